@@ -58,6 +58,9 @@ class Limit(BaseModel):
     key: anyTypeUnion
     pairwith: typing.Any
     tags: Optional[List[StrictStr]]
+    algorithm: Optional[StrictStr]
+    burst: Optional[StrictInt]
+    unit: Optional[StrictStr]
 
 
 # securitypolicy
@@ -165,6 +168,8 @@ class FlowControl(BaseModel):
     exclude: Optional[List[StrictStr]]
     description: Optional[StrictStr]
     active: StrictBool
+    step_ttl: Optional[StrictInt]
+    max_duration: Optional[StrictInt]
 
 
 # Action
@@ -29,6 +29,8 @@ end
 -- dynamic metadata filter name
 local DMFN = "com.reblaze.curiefense"
 local LOG_KEY = "request.info"
+local INFLIGHT_KEY = "request.inflight"
+local RESPONSE_HEADERS_KEY = "response.headers"
 
 local function log_request(handle, inspection_result)
   handle:streamInfo():dynamicMetadata():set(DMFN, LOG_KEY, inspection_result:request_map(nil))
@@ -68,6 +70,10 @@ local function custom_response(handle, action_params)
     response["headers"][":status"] = response["status"]
 
     if block_mode then
+        if action_params["delay"] then
+            -- the envoy lua filter can't wait without blocking the worker, tarpits answer right away
+            handle:logDebug("tarpit delay ignored")
+        end
         handle:logDebug(cjson.encode(response))
         handle:respond( response["headers"], response["content"])
     end
@@ -75,6 +81,21 @@ end
 
 function session_rust_envoy.on_response(handle)
     handle:logDebug("todo, capture return code")
+    local meta = handle:streamInfo():dynamicMetadata():get(DMFN)
+    local inflight = meta and meta[INFLIGHT_KEY]
+    if inflight then
+        local err = curiefense.release_inflight(cjson.decode(inflight))
+        if err then
+            handle:logErr(sfmt("curiefense.release_inflight error %s", err))
+        end
+    end
+    local response_headers = meta and meta[RESPONSE_HEADERS_KEY]
+    if response_headers then
+        local headers = handle:headers()
+        for k, v in pairs(cjson.decode(response_headers)) do
+            headers:replace(k, v)
+        end
+    end
 end
 
 function session_rust_envoy.inspect(handle)
@@ -109,6 +130,10 @@ function session_rust_envoy.inspect(handle)
     )
 
     log_request(handle, res)
+    local inflight = res.inflight
+    if inflight and not rawequal(next(inflight), nil) then
+        handle:streamInfo():dynamicMetadata():set(DMFN, INFLIGHT_KEY, cjson.encode(inflight))
+    end
 
     if res.error then
         handle:logErr(sfmt("curiefense.inspect_request_map error %s", res.error))
@@ -135,6 +160,22 @@ function session_rust_envoy.inspect(handle)
                         headers_handle:replace(k, v)
                     end
                 end
+                if type(analyser_response.transform) == "table" then
+                    local transform = analyser_response.transform
+                    if type(transform.remove_headers) == "table" then
+                        for _, h in ipairs(transform.remove_headers) do
+                            headers_handle:remove(h)
+                        end
+                    end
+                    if transform.path then headers_handle:replace(":path", transform.path) end
+                    -- routing hint, the routes can match on this header
+                    if transform.upstream then headers_handle:replace("x-curiefense-upstream", transform.upstream) end
+                end
+                -- rate limit headers are added to the server response
+                if type(analyser_response.response_headers) == "table" then
+                    handle:streamInfo():dynamicMetadata():set(DMFN, RESPONSE_HEADERS_KEY,
+                        cjson.encode(analyser_response.response_headers))
+                end
             end
         end
     end
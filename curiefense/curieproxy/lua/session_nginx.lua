@@ -39,12 +39,35 @@ local function custom_response(handle, action_params)
     handle.log(handle.DEBUG, cjson.encode(action_params))
 
     if block_mode then
+        -- tarpits answer late, ngx.sleep does not block the worker
+        local delay = tonumber(action_params["delay"])
+        if delay and delay > 0 then
+            handle.sleep(delay / 1000)
+        end
         if action_params["content"] then handle.say(action_params["content"]) end
         handle.exit(handle.HTTP_OK)
     end
 
 end
 
+-- applies the changes of transform actions to the forwarded request
+local function transform_request(handle, transform)
+    if type(transform.remove_headers) == "table" then
+        for _, h in ipairs(transform.remove_headers) do
+            handle.req.clear_header(h)
+        end
+    end
+    if transform.path then
+        local path, args = transform.path:match("^([^?]*)%??(.*)$")
+        handle.req.set_uri(path)
+        if args ~= "" then handle.req.set_uri_args(args) end
+    end
+    if transform.upstream then
+        handle.req.set_header("x-curiefense-upstream", transform.upstream)
+        handle.ctx.upstream = transform.upstream
+    end
+end
+
 local function make_safe_headers(rheaders)
     local headers = {}
 
@@ -120,6 +143,11 @@ function session_rust_nginx.inspect(handle, loglevel, secpolid, plugins)
             red:init_pipeline()
             for _, flow in pairs(flows) do
                 red:llen(flow.key)
+                if flow.step_ttl or flow.max_duration then
+                    -- timestamps of the latest and first steps
+                    red:lindex(flow.key, 0)
+                    red:lindex(flow.key, -1)
+                end
             end
             local results, redis_err = red:commit_pipeline()
             if redis_err or not results then
@@ -128,10 +156,29 @@ function session_rust_nginx.inspect(handle, loglevel, secpolid, plugins)
             end
 
             local result_idx = 1
+            local now = handle.now() * 1000
 
             for _, flow in pairs(flows) do
                 local len = results[result_idx]
                 result_idx = result_idx + 1
+                local expiry = nil
+                if flow.step_ttl or flow.max_duration then
+                    local latest = tonumber(results[result_idx])
+                    local first = tonumber(results[result_idx + 1])
+                    result_idx = result_idx + 2
+                    if len > 0 then
+                        if flow.step_ttl and latest and now - latest > flow.step_ttl * 1000 then
+                            expiry = "step"
+                        elseif flow.max_duration and first and now - first > flow.max_duration * 1000 then
+                            expiry = "sequence"
+                        end
+                    end
+                    if expiry then
+                        -- the sequence starts over
+                        red:del(flow.key)
+                        len = 0
+                    end
+                end
                 local step = flow.step
                 local flowtype = "nonlast"
                 if flow.is_last then
@@ -143,14 +190,14 @@ function session_rust_nginx.inspect(handle, loglevel, secpolid, plugins)
                 else
                     if step == len then
                         local key = flow.key
-                        red:lpush(key, "foo")
+                        red:lpush(key, math.floor(now))
                         local ttl = red:ttl(key)
                         if ttl == nil or ttl < 0 then
                             red:expire(key, flow.timeframe)
                         end
                     end
                 end
-                table.insert(rflows, flow:result(flowtype))
+                table.insert(rflows, flow:result(flowtype, expiry))
             end
         end
 
@@ -171,6 +218,13 @@ function session_rust_nginx.inspect(handle, loglevel, secpolid, plugins)
                         red:sadd(key, pw)
                         red:scard(key)
                         red:ttl(key)
+                    elseif limit.inflight then
+                        -- one lease per request, released on completion or expired after the timeframe
+                        red:eval(curiefense.INFLIGHT_SCRIPT, 1, key, math.floor(handle.now() * 1000),
+                            limit.timeframe * 1000, limit.member)
+                    elseif limit.cost ~= 1 then
+                        red:incrby(key, limit.cost)
+                        red:ttl(key)
                     else
                         red:incr(key)
                         red:ttl(key)
@@ -196,13 +250,15 @@ function session_rust_nginx.inspect(handle, loglevel, secpolid, plugins)
                     end
                     curcount = results[result_idx]
                     result_idx = result_idx + 1
-                    local expire = results[result_idx]
-                    result_idx = result_idx + 1
                     if curcount == nil then
                         curcount = 0
                     end
-                    if expire == nil or expire < 0 then
-                        red:expire(key, limit.timeframe)
+                    if not limit.inflight then
+                        local expire = results[result_idx]
+                        result_idx = result_idx + 1
+                        if expire == nil or expire < 0 then
+                            red:expire(key, limit.timeframe)
+                        end
                     end
                 end
                 table.insert(rlimits, limit:result(curcount))
@@ -242,14 +298,86 @@ function session_rust_nginx.inspect(handle, loglevel, secpolid, plugins)
                     handle.req.set_header(k, v)
                 end
             end
+            -- rate limit headers are added to the server response
+            if analyser_response ~= cjson.null and type(analyser_response["response_headers"]) == "table" then
+                handle.ctx.response_headers = analyser_response["response_headers"]
+            end
+            if analyser_response ~= cjson.null and type(analyser_response["transform"]) == "table" then
+                transform_request(handle, analyser_response["transform"])
+            end
+        end
+    end
+end
+
+-- appends a header value, keeping the values already set
+local function append_header(handle, name, value)
+    local current = handle.header[name]
+    if current == nil then
+        handle.header[name] = value
+    elseif type(current) == "table" then
+        table.insert(current, value)
+        handle.header[name] = current
+    else
+        handle.header[name] = { current, value }
+    end
+end
+
+-- adds the headers of passing actions to the server response, and inspects it
+function session_rust_nginx.header_filter(handle)
+    local headers = handle.ctx.response_headers
+    if headers then
+        for k, v in pairs(headers) do
+            handle.header[k] = v
         end
     end
+
+    local res = handle.ctx.res
+    if not res or res.blocking then
+        return
+    end
+    local rres = res:inspect_response(handle.status, make_safe_headers(handle.resp.get_headers()), nil)
+    if not rres then
+        return
+    end
+    for _, log in ipairs(rres.logs) do
+        handle.log(handle.DEBUG, log)
+    end
+    for k, v in pairs(rres.headers) do
+        handle.header[k] = v
+    end
+    -- such as the CSRF token cookie
+    for _, h in ipairs(rres.added_headers) do
+        append_header(handle, h[1], h[2])
+    end
+    if rres.blocking then
+        local action = cjson.decode(rres.response)["response"]
+        handle.status = tonumber(action["status"]) or handle.status
+        handle.header["content-length"] = nil
+        handle.ctx.response_body = action["content"] or ""
+    end
+end
+
+-- replaces the body of the responses blocked by the response filters
+function session_rust_nginx.body_filter(handle)
+    local body = handle.ctx.response_body
+    if body then
+        handle.arg[1] = body
+        handle.arg[2] = true
+        handle.ctx.response_body = ""
+    end
 end
 
 -- log block stage processing
 function session_rust_nginx.log(handle, extra)
     local res = handle.ctx.res
     handle.ctx.res = nil
+    local inflight = res.inflight
+    if inflight and not rawequal(next(inflight), nil) then
+        local err = curiefense.release_inflight(inflight)
+        if err then
+            handle.log(handle.ERR, sfmt("curiefense.release_inflight error %s", err))
+        end
+    end
     handle.var.request_map = res:request_map(extra)
 end
 
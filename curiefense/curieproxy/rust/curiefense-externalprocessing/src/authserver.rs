@@ -0,0 +1,420 @@
+//! An HTTP authorization server, for nginx auth_request, traefik ForwardAuth or haproxy.
+//!
+//! The proxy sends a subrequest carrying the headers of the original request, its method and uri being passed in
+//! the standard forwarded headers (`x-forwarded-method`, `x-forwarded-uri` and `x-forwarded-host` for traefik,
+//! `x-original-method` and `x-original-uri` for the usual nginx configurations). As the client headers are also
+//! forwarded, the header family is selected with `--proxy`, and subrequests carrying headers of both families, or
+//! missing the original uri, are rejected with a 403. The request is analyzed, and the server answers with
+//! an empty 200 when it passes, or a 401 or 403 when it is blocked, as these are the only codes auth_request
+//! understands. The actual decision is described in the `x-curiefense-*` response headers, so that the proxy can
+//! forward them, or build its own response.
+//!
+//! The request body is not analyzed, and in-flight limits are released as soon as the decision is taken, as the
+//! end of the original request is not known.
+use curiefense::{
+    accesslog::should_log,
+    challenge::ConfiguredChallenge,
+    config::with_config,
+    counters::release_inflight,
+    incremental::{add_headers, finalize, inspect_init, IPInfo},
+    interface::{jsonlog, AnalyzeResult},
+    logs::{LogLevel, Logs},
+    utils::RequestMeta,
+};
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use log::{debug, error, info, warn, LevelFilter};
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, time::Duration};
+use structopt::StructOpt;
+use syslog::Facility;
+
+/// routing hint for the proxy, as in the external processing server
+const UPSTREAM_HEADER: &str = "x-curiefense-upstream";
+
+/// the proxy sending the subrequests, which selects the headers holding the original request attributes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProxyKind {
+    Nginx,
+    Traefik,
+}
+
+/// method, uri and host headers of a proxy
+struct HeaderFamily {
+    method: &'static str,
+    uri: &'static str,
+    host: Option<&'static str>,
+}
+
+const NGINX_HEADERS: HeaderFamily = HeaderFamily {
+    method: "x-original-method",
+    uri: "x-original-uri",
+    host: None,
+};
+
+const TRAEFIK_HEADERS: HeaderFamily = HeaderFamily {
+    method: "x-forwarded-method",
+    uri: "x-forwarded-uri",
+    host: Some("x-forwarded-host"),
+};
+
+impl HeaderFamily {
+    fn names(&self) -> impl Iterator<Item = &'static str> {
+        [self.method, self.uri].into_iter().chain(self.host)
+    }
+}
+
+impl ProxyKind {
+    /// the headers of this proxy, and those of the other one
+    fn families(self) -> (HeaderFamily, HeaderFamily) {
+        match self {
+            ProxyKind::Nginx => (NGINX_HEADERS, TRAEFIK_HEADERS),
+            ProxyKind::Traefik => (TRAEFIK_HEADERS, NGINX_HEADERS),
+        }
+    }
+}
+
+impl std::str::FromStr for ProxyKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nginx" => Ok(ProxyKind::Nginx),
+            "traefik" => Ok(ProxyKind::Traefik),
+            _ => Err(format!("unknown proxy {}, expected nginx or traefik", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Settings {
+    loglevel: LogLevel,
+    trustedhops: usize,
+    fail_open: bool,
+    proxy: ProxyKind,
+}
+
+/// request attributes, or headers, by name
+type Attributes = HashMap<String, String>;
+
+/// rebuilds the original request attributes and headers from the authorization subrequest, the subrequest is
+/// rejected when it is ambiguous
+fn original_request(
+    proxy: ProxyKind,
+    method: &str,
+    headers: HashMap<String, String>,
+) -> Result<(Attributes, Attributes), String> {
+    let (family, other) = proxy.families();
+    if let Some(h) = other.names().find(|h| headers.contains_key(*h)) {
+        return Err(format!("Rejected a {:?} subrequest carrying the {} header", proxy, h));
+    }
+    let mut headers = headers;
+    let mut meta = HashMap::new();
+    let path = headers
+        .remove(family.uri)
+        .ok_or_else(|| format!("Rejected a subrequest without the {} header", family.uri))?;
+    meta.insert("path".to_string(), path);
+    meta.insert(
+        "method".to_string(),
+        headers.remove(family.method).unwrap_or_else(|| method.to_string()),
+    );
+    if let Some(host) = family.host.and_then(|h| headers.remove(h)) {
+        headers.insert("host".to_string(), host);
+    }
+    if let Some(authority) = headers.get("host") {
+        meta.insert("authority".to_string(), authority.clone());
+    }
+    if let Some(requestid) = headers.remove("x-request-id") {
+        meta.insert("x-request-id".to_string(), requestid);
+    }
+    Ok((meta, headers))
+}
+
+/// an empty answer
+fn empty_answer(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+/// the status of the authorization answer, auth_request only accepts 2xx, 401 and 403
+fn answer_status(result: &AnalyzeResult) -> StatusCode {
+    match &result.decision.maction {
+        Some(a) if a.block_mode && a.status == 401 => StatusCode::UNAUTHORIZED,
+        Some(a) if a.block_mode => StatusCode::FORBIDDEN,
+        _ => StatusCode::OK,
+    }
+}
+
+/// the decision headers, and the action headers
+fn answer_headers(result: &AnalyzeResult) -> Vec<(String, String)> {
+    let mut out = Vec::new();
+    let action = match &result.decision.maction {
+        None => {
+            out.push(("x-curiefense-action".to_string(), "pass".to_string()));
+            return out;
+        }
+        Some(a) => a,
+    };
+    let atype = serde_json::to_value(action.atype)
+        .ok()
+        .and_then(|v| v.as_str().map(|s| s.to_string()))
+        .unwrap_or_default();
+    out.push(("x-curiefense-action".to_string(), atype));
+    out.push(("x-curiefense-status".to_string(), action.status.to_string()));
+    let reasons = result
+        .decision
+        .reasons
+        .iter()
+        .map(|r| r.id.as_str())
+        .collect::<Vec<_>>()
+        .join(",");
+    if !reasons.is_empty() {
+        out.push(("x-curiefense-reasons".to_string(), reasons));
+    }
+    for (k, v) in action.headers.iter().flatten() {
+        out.push((k.clone(), v.clone()));
+    }
+    if let Some(upstream) = result.decision.transform().and_then(|t| t.upstream.as_ref()) {
+        out.push((UPSTREAM_HEADER.to_string(), upstream.clone()));
+    }
+    out
+}
+
+async fn analyze(
+    settings: Settings,
+    meta: HashMap<String, String>,
+    headers: HashMap<String, String>,
+) -> Result<(AnalyzeResult, Logs), String> {
+    let meta = RequestMeta::from_map(meta).map_err(|rr| format!("Could not get request meta: {}", rr))?;
+
+    let mut logs = Logs::new(settings.loglevel);
+    let (idata, globalfilters, flows, vtags) = with_config(&mut logs, |_, cfg| {
+        inspect_init(
+            cfg,
+            settings.loglevel,
+            meta,
+            IPInfo::Hops(settings.trustedhops),
+            None,
+            None,
+            HashMap::new(),
+        )
+        .map(|o| {
+            (
+                o,
+                cfg.globalfilters.clone(),
+                cfg.flows.clone(),
+                cfg.virtual_tags.clone(),
+            )
+        })
+    })
+    .ok_or_else(|| "No configuration is loaded".to_string())??;
+    show_logs(logs);
+
+    let idata = match add_headers(idata, headers) {
+        Ok(i) => i,
+        Err((logs, result)) => return Ok((result, logs)),
+    };
+    let challenge = ConfiguredChallenge::current();
+    Ok(finalize(idata, challenge.as_ref(), &globalfilters, &flows, None, vtags).await)
+}
+
+async fn log_result(result: &AnalyzeResult, logs: &Logs) {
+    let block_code = result.decision.maction.as_ref().map(|a| a.status);
+    let (v, _) = jsonlog(
+        &result.decision,
+        Some(&result.rinfo),
+        block_code,
+        &result.tags,
+        &result.stats,
+        logs,
+        HashMap::new(),
+    )
+    .await;
+    for l in logs.to_stringvec() {
+        debug!("{}", l);
+    }
+    if should_log(&result.decision, &result.rinfo, &result.tags) {
+        info!("CFLOG {}", String::from_utf8_lossy(&v));
+    }
+}
+
+async fn authorize(settings: Settings, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let headers = req
+        .headers()
+        .iter()
+        .filter_map(|(k, v)| v.to_str().ok().map(|v| (k.as_str().to_string(), v.to_string())))
+        .collect();
+    // ambiguous subrequests are rejected, even in fail open mode
+    let (meta, headers) = match original_request(settings.proxy, req.method().as_str(), headers) {
+        Ok(r) => r,
+        Err(rr) => {
+            warn!("{}", rr);
+            return Ok(empty_answer(StatusCode::FORBIDDEN));
+        }
+    };
+    let (result, logs) = match analyze(settings, meta, headers).await {
+        Ok(r) => r,
+        Err(rr) => {
+            error!("{}", rr);
+            let status = if settings.fail_open {
+                StatusCode::OK
+            } else {
+                StatusCode::FORBIDDEN
+            };
+            return Ok(empty_answer(status));
+        }
+    };
+    log_result(&result, &logs).await;
+    if let Err(rr) = release_inflight(&result.rinfo.inflight).await {
+        error!("Could not release in-flight counters: {}", rr);
+    }
+
+    let status = answer_status(&result);
+    let body = match &result.decision.maction {
+        Some(a) if a.block_mode => {
+            // tarpits answer late, without blocking a thread
+            if let Some(delay) = a.delay {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+            }
+            Body::from(a.content.clone())
+        }
+        _ => Body::empty(),
+    };
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    for (k, v) in answer_headers(&result) {
+        match (HeaderName::from_bytes(k.as_bytes()), HeaderValue::from_str(&v)) {
+            (Ok(name), Ok(value)) => {
+                response.headers_mut().insert(name, value);
+            }
+            _ => warn!("Invalid decision header {}: {}", k, v),
+        }
+    }
+    Ok(response)
+}
+
+fn show_logs(logs: Logs) {
+    let vlogs = logs.to_stringvec();
+    if !vlogs.is_empty() {
+        warn!("CONFIGURATION LOGS:");
+        for l in vlogs {
+            warn!("{}", l);
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "cf-authserver",
+    about = "An HTTP authorization server for curiefense (nginx auth_request, traefik ForwardAuth)."
+)]
+struct Opt {
+    #[structopt(long, default_value = "0.0.0.0:8081")]
+    listen: String,
+    #[structopt(long)]
+    configpath: String,
+    #[structopt(long, default_value = "info")]
+    loglevel: String,
+    #[structopt(long, default_value = "1")]
+    trustedhops: usize,
+    /// the proxy sending the subrequests, nginx or traefik
+    #[structopt(long, default_value = "nginx")]
+    proxy: ProxyKind,
+    /// let the requests through when they can't be analyzed, instead of answering with a 403
+    #[structopt(long)]
+    fail_open: bool,
+    #[structopt(long)]
+    syslog: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let opt = Opt::from_args();
+    let addr: SocketAddr = opt.listen.parse()?;
+    let loglevel: LogLevel = opt.loglevel.parse()?;
+    let level_filter = match &loglevel {
+        LogLevel::Debug => LevelFilter::Debug,
+        _ => LevelFilter::Info,
+    };
+    // initial configuration loading
+    let mut logs = Logs::new(loglevel);
+    with_config(&mut logs, |_, _| {});
+    show_logs(logs);
+
+    if opt.syslog {
+        syslog::init_unix(Facility::LOG_USER, level_filter)?;
+    } else {
+        simplelog::TermLogger::init(
+            level_filter,
+            simplelog::Config::default(),
+            simplelog::TerminalMode::Stdout,
+            simplelog::ColorChoice::Auto,
+        )?;
+    };
+
+    let settings = Settings {
+        loglevel,
+        trustedhops: opt.trustedhops,
+        fail_open: opt.fail_open,
+        proxy: opt.proxy,
+    };
+    let service =
+        make_service_fn(move |_| async move { Ok::<_, Infallible>(service_fn(move |req| authorize(settings, req))) });
+    info!("Listening on {}, configuration in {}", addr, opt.configpath);
+    Server::bind(&addr).serve(service).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mk_headers(headers: &[(&str, &str)]) -> HashMap<String, String> {
+        headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn forwarded_request() {
+        let headers = mk_headers(&[
+            ("host", "auth.local"),
+            ("x-forwarded-host", "shop.example.com"),
+            ("x-forwarded-method", "POST"),
+            ("x-forwarded-uri", "/cart?item=1"),
+            ("x-forwarded-for", "1.2.3.4"),
+            ("x-request-id", "abc"),
+        ]);
+        let (meta, headers) = original_request(ProxyKind::Traefik, "GET", headers).unwrap();
+        assert_eq!(meta.get("method").map(|s| s.as_str()), Some("POST"));
+        assert_eq!(meta.get("path").map(|s| s.as_str()), Some("/cart?item=1"));
+        assert_eq!(meta.get("authority").map(|s| s.as_str()), Some("shop.example.com"));
+        assert_eq!(meta.get("x-request-id").map(|s| s.as_str()), Some("abc"));
+        assert_eq!(headers.get("host").map(|s| s.as_str()), Some("shop.example.com"));
+        assert_eq!(headers.get("x-forwarded-for").map(|s| s.as_str()), Some("1.2.3.4"));
+        assert!(!headers.contains_key("x-forwarded-uri"));
+
+        let nginx = mk_headers(&[("host", "shop.example.com"), ("x-original-uri", "/admin")]);
+        let (meta, _) = original_request(ProxyKind::Nginx, "GET", nginx).unwrap();
+        assert_eq!(meta.get("path").map(|s| s.as_str()), Some("/admin"));
+        assert_eq!(meta.get("method").map(|s| s.as_str()), Some("GET"));
+        assert_eq!(meta.get("authority").map(|s| s.as_str()), Some("shop.example.com"));
+    }
+
+    #[test]
+    fn ambiguous_requests() {
+        // a client sending its own x-forwarded-uri through nginx
+        let spoofed = mk_headers(&[("x-original-uri", "/admin"), ("x-forwarded-uri", "/harmless")]);
+        assert!(original_request(ProxyKind::Nginx, "GET", spoofed.clone()).is_err());
+        assert!(original_request(ProxyKind::Traefik, "GET", spoofed).is_err());
+        let host_only = mk_headers(&[
+            ("x-original-uri", "/admin"),
+            ("x-forwarded-host", "lenient.example.com"),
+        ]);
+        assert!(original_request(ProxyKind::Nginx, "GET", host_only).is_err());
+        assert!(original_request(ProxyKind::Nginx, "GET", HashMap::new()).is_err());
+        assert_eq!("traefik".parse(), Ok(ProxyKind::Traefik));
+        assert!("haproxy".parse::<ProxyKind>().is_err());
+    }
+}
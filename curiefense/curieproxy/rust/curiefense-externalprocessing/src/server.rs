@@ -1,16 +1,24 @@
-use chrono::{DateTime, Utc};
 use curiefense::{
+    accesslog::should_log,
+    analyze::ResponseAnalyzeResult,
+    challenge::ConfiguredChallenge,
     config::{flow::FlowMap, globalfilter::GlobalFilterSection, virtualtags::VirtualTags, with_config},
-    grasshopper::DynGrasshopper,
-    incremental::{add_body, add_headers, finalize, inspect_init, IData, IPInfo},
-    interface::{jsonlog, AnalyzeResult},
+    counters::release_inflight,
+    incremental::{add_headers, analyze_body_chunk, finalize, inspect_init, IData, IPInfo},
+    interface::{jsonlog, Action, AnalyzeResult, RequestTransform},
     logs::{LogLevel, Logs},
+    logsink::{BoundedSink, DropPolicy, LogRecord, LogSink},
+    siem::{siem_record, LogFormat},
     utils::RequestMeta,
 };
 use elasticsearch::{http::transport::Transport, Elasticsearch};
 use lazy_static::lazy_static;
 use log::{debug, error, info, warn, LevelFilter};
-use std::{collections::HashMap, sync::RwLock};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 use structopt::StructOpt;
 use syslog::{Facility, Formatter3164, LoggerBackend};
 use tokio::{
@@ -24,8 +32,8 @@ mod ext_proc;
 
 use ext_proc::{
     external_processor_server::{ExternalProcessor, ExternalProcessorServer},
-    processing_response, BodyResponse, HeaderMutation, HeaderValue, HeaderValueOption, HeadersResponse, HttpStatus,
-    ImmediateResponse, ProcessingRequest, ProcessingResponse,
+    processing_response, BodyResponse, CommonResponse, HeaderMutation, HeaderValue, HeaderValueOption, HeadersResponse,
+    HttpStatus, ImmediateResponse, ProcessingRequest, ProcessingResponse,
 };
 
 lazy_static! {
@@ -35,8 +43,10 @@ lazy_static! {
 #[derive(Clone)]
 pub struct MyEP {
     handle_replies: bool,
+    fail_open: bool,
     reqchannel: Sender<CfgRequest>,
-    logsender: Option<Sender<(Vec<u8>, DateTime<Utc>)>>,
+    logsink: Option<Arc<BoundedSink>>,
+    log_format: LogFormat,
 }
 
 type CfgRequest = (
@@ -91,30 +101,22 @@ async fn configloop(rx: Receiver<CfgRequest>, configpath: &str, loglevel: LogLev
     }
 }
 
-async fn logloop(rx: Receiver<(Vec<u8>, DateTime<Utc>)>, client: Elasticsearch) {
-    let mut mrx = rx;
+/// consumes the log sink, this runs in its own thread so that a slow elasticsearch never
+/// blocks the request path: the sink drops records instead when its queue is full
+fn logloop(sink: Arc<BoundedSink>, client: Elasticsearch, rt: tokio::runtime::Handle) {
     loop {
-        match mrx.recv().await {
-            None => {
-                error!("should not happen, logging channel closed?");
-                break;
-            }
-            Some((v, now)) => {
-                let idx = now.format("curieaccesslog-%Y.%m.%d-000001").to_string();
-                match client
-                    .index(elasticsearch::IndexParts::Index(&idx))
-                    .body(v)
-                    .send()
-                    .await
-                {
-                    Err(rr) => error!("When logging to ES: {}", rr),
-                    Ok(response) => {
-                        if !response.status_code().is_success() {
-                            error!("When logging to ES: {:?}", response);
-                        } else {
-                            info!("{:?}", response);
-                        }
-                    }
+        let LogRecord { data, timestamp } = match sink.recv_timeout(Duration::from_secs(1)) {
+            None => continue,
+            Some(r) => r,
+        };
+        let idx = timestamp.format("curieaccesslog-%Y.%m.%d-000001").to_string();
+        match rt.block_on(client.index(elasticsearch::IndexParts::Index(&idx)).body(data).send()) {
+            Err(rr) => error!("When logging to ES: {}", rr),
+            Ok(response) => {
+                if !response.status_code().is_success() {
+                    error!("When logging to ES: {:?}", response);
+                } else {
+                    info!("{:?}", response);
                 }
             }
         }
@@ -125,20 +127,25 @@ impl MyEP {
     fn new(
         reqchannel: Sender<CfgRequest>,
         handle_replies: bool,
-        logsender: Option<Sender<(Vec<u8>, DateTime<Utc>)>>,
+        fail_open: bool,
+        logsink: Option<Arc<BoundedSink>>,
+        log_format: LogFormat,
     ) -> Self {
         MyEP {
             handle_replies,
+            fail_open,
             reqchannel,
-            logsender,
+            logsink,
+            log_format,
         }
     }
 
-    // the main content handling loop
+    // the main content handling loop, `stage` is the processing stage envoy is waiting an answer for
     async fn handle(
         self,
         tx: &mut Sender<Result<ProcessingResponse, Status>>,
         msg: &mut tonic::Streaming<ProcessingRequest>,
+        stage: &mut ProcessingStage,
     ) -> Result<(), String> {
         // currently, the first request is for headers, and then we might get body parts
         async fn next_message(m: &mut tonic::Streaming<ProcessingRequest>) -> Result<ProcessingRequest, String> {
@@ -180,20 +187,19 @@ impl MyEP {
             something_else => return Err(format!("Expected a RequestHeaders, but got {:?}", something_else)),
         };
 
-        let meta = match RequestMeta::from_map(meta) {
-            Ok(m) => m,
-            Err(rr) => {
-                error!("Could not get request meta: {}", rr);
-                return Ok(());
-            }
-        };
+        let meta = RequestMeta::from_map(meta).map_err(|rr| format!("Could not get request meta: {}", rr))?;
 
         // get configuration data from the dedicated task
         let (rtx, mut rrx) = mpsc::channel(1);
-        self.reqchannel.send((meta, rtx)).await.unwrap();
-        let midata = rrx.recv().await;
-
-        let (idata, globalfilters, flows, vtags) = midata.unwrap().unwrap().unwrap();
+        self.reqchannel
+            .send((meta, rtx))
+            .await
+            .map_err(|_| "The configuration task is gone".to_string())?;
+        let (idata, globalfilters, flows, vtags) = rrx
+            .recv()
+            .await
+            .flatten()
+            .ok_or_else(|| "No configuration is loaded".to_string())??;
 
         let mut idata = match add_headers(idata, mheaders) {
             Ok(i) => i,
@@ -205,10 +211,11 @@ impl MyEP {
 
         if !headers_only {
             stage_pass(ProcessingStage::Headers, tx).await;
+            *stage = ProcessingStage::Body;
             loop {
                 match next_message(msg).await?.request {
                     Some(ext_proc::processing_request::Request::RequestBody(bdy)) => {
-                        idata = match add_body(idata, &bdy.body) {
+                        idata = match analyze_body_chunk(idata, &bdy.body, &globalfilters, &vtags) {
                             Ok(i) => i,
                             Err((logs, dec)) => {
                                 self.send_action(ProcessingStage::Body, tx, &dec, &logs, None).await;
@@ -224,7 +231,8 @@ impl MyEP {
             }
         }
 
-        let (dec, logs) = finalize(idata, Some(&DynGrasshopper {}), &globalfilters, &flows, None, vtags).await;
+        let challenge = ConfiguredChallenge::current();
+        let (dec, logs) = finalize(idata, challenge.as_ref(), &globalfilters, &flows, None, vtags).await;
 
         let stage = if headers_only {
             ProcessingStage::Headers
@@ -237,19 +245,64 @@ impl MyEP {
                 let code: Option<u32> = match next_message(msg).await {
                     Ok(nmsg) => match nmsg.request {
                         Some(ext_proc::processing_request::Request::ResponseHeaders(hdrs)) => {
-                            stage_pass(ProcessingStage::RHeaders, tx).await;
-
-                            hdrs.headers
-                                .iter()
-                                .flat_map(|hm| hm.headers.iter())
-                                .filter_map(|hv| {
-                                    if hv.key == ":status" {
-                                        hv.value.parse().ok()
-                                    } else {
-                                        Some(0)
+                            let mut status = 0;
+                            let mut rheaders = HashMap::new();
+                            for hv in hdrs.headers.into_iter().flat_map(|hm| hm.headers) {
+                                if hv.key == ":status" {
+                                    status = hv.value.parse().unwrap_or(0);
+                                } else if !hv.key.starts_with(':') {
+                                    rheaders.insert(hv.key, hv.value);
+                                }
+                            }
+                            let mut rlogs = Logs::new(logs.level);
+                            let response = dec.inspect_response(&mut rlogs, status, rheaders, None);
+                            for l in rlogs.to_stringvec() {
+                                debug!("{}", l);
+                            }
+                            let sent = match response.decision.maction.as_ref().filter(|a| a.block_mode) {
+                                Some(a) => {
+                                    status = a.status;
+                                    send_response(
+                                        tx,
+                                        processing_response::Response::ImmediateResponse(ImmediateResponse {
+                                            status: Some(HttpStatus { code: a.status as i32 }),
+                                            details: serde_json::to_string(&response.decision.reasons)
+                                                .unwrap_or_default(),
+                                            body: a.content.clone(),
+                                            headers: a.headers.clone().map(mutate_headers),
+                                            grpc_status: None,
+                                        }),
+                                    )
+                                    .await
+                                }
+                                None => {
+                                    // rate limit headers and the CSRF token cookie are added to the server response
+                                    let action_headers =
+                                        dec.decision.maction.as_ref().and_then(|a| a.response_headers.clone());
+                                    match response_mutation(action_headers, &response) {
+                                        None => {
+                                            stage_pass(ProcessingStage::RHeaders, tx).await;
+                                            Ok(())
+                                        }
+                                        Some(mutation) => {
+                                            send_response(
+                                                tx,
+                                                processing_response::Response::ResponseHeaders(HeadersResponse {
+                                                    response: Some(CommonResponse {
+                                                        header_mutation: Some(mutation),
+                                                        ..Default::default()
+                                                    }),
+                                                }),
+                                            )
+                                            .await
+                                        }
                                     }
-                                })
-                                .next()
+                                }
+                            };
+                            if let Err(rr) = sent {
+                                error!("Could not answer the response headers: {}", rr);
+                            }
+                            Some(status)
                         }
 
                         something_else => {
@@ -268,6 +321,10 @@ impl MyEP {
             };
             self.send_action(ProcessingStage::Reply, tx, &dec, &logs, code).await;
         }
+        // the stream is over, the request no longer counts towards in-flight limits
+        if let Err(rr) = release_inflight(&dec.rinfo.inflight).await {
+            error!("Could not release in-flight counters: {}", rr);
+        }
         Ok(())
     }
 
@@ -286,23 +343,30 @@ impl MyEP {
             }
             Some(a) => {
                 if a.block_mode {
-                    tx.send(Ok(ProcessingResponse {
-                        response: Some(ext_proc::processing_response::Response::ImmediateResponse(
-                            ImmediateResponse {
-                                status: Some(HttpStatus { code: a.status as i32 }),
-                                details: serde_json::to_string(&result.decision.reasons).unwrap(),
-                                body: a.content.clone(),
-                                headers: a.headers.clone().map(mutate_headers),
-                                grpc_status: None,
-                            },
-                        )),
-                        ..Default::default()
-                    }))
-                    .await
-                    .unwrap();
+                    // tarpits answer late, without blocking a thread
+                    if let Some(delay) = a.delay {
+                        tokio::time::sleep(Duration::from_millis(delay)).await;
+                    }
+                    let sent = send_response(
+                        tx,
+                        processing_response::Response::ImmediateResponse(ImmediateResponse {
+                            status: Some(HttpStatus { code: a.status as i32 }),
+                            details: serde_json::to_string(&result.decision.reasons).unwrap_or_default(),
+                            body: a.content.clone(),
+                            headers: a.headers.clone().map(mutate_headers),
+                            grpc_status: None,
+                        }),
+                    )
+                    .await;
+                    if let Err(rr) = sent {
+                        warn!("Could not block the request: {}", rr);
+                    }
                     true
                 } else {
-                    stage_pass(stage, tx).await;
+                    match result.decision.transform() {
+                        Some(transform) => transform_pass(stage, tx, transform_headers(a, transform)).await,
+                        None => stage_pass(stage, tx).await,
+                    }
                     false
                 }
             }
@@ -323,10 +387,21 @@ impl MyEP {
             for l in logs.to_stringvec() {
                 debug!("{}", l);
             }
-            info!("CFLOG {}", String::from_utf8_lossy(&v));
-            if let Some(tx) = &self.logsender {
-                if let Err(rr) = tx.send((v, now)).await {
-                    error!("Could not log: {}", rr);
+            // the statistics are aggregated by jsonlog, sampled out requests are only left out of the logs
+            if should_log(&result.decision, &result.rinfo, &result.tags) {
+                info!("CFLOG {}", String::from_utf8_lossy(&v));
+                if let Some(sink) = &self.logsink {
+                    let data = siem_record(
+                        self.log_format,
+                        &result.decision,
+                        &result.rinfo,
+                        &result.tags,
+                        block_code,
+                    )
+                    .unwrap_or(v);
+                    if !sink.submit(LogRecord { data, timestamp: now }) {
+                        warn!("Log sink {} is full, dropped a log entry", sink.name());
+                    }
                 }
             }
         }
@@ -349,6 +424,74 @@ fn mutate_headers(headers: HashMap<String, String>) -> HeaderMutation {
     }
 }
 
+/// header mutation for the server response: the headers of the passing action and the ones replaced by the response
+/// inspection are set, the headers it adds, such as the CSRF token cookie, are appended
+fn response_mutation(
+    action_headers: Option<HashMap<String, String>>,
+    response: &ResponseAnalyzeResult,
+) -> Option<HeaderMutation> {
+    let mut headers = action_headers.unwrap_or_default();
+    headers.extend(response.headers.clone());
+    let mut mutation = mutate_headers(headers);
+    mutation
+        .set_headers
+        .extend(response.added_headers.iter().map(|(key, value)| HeaderValueOption {
+            header: Some(HeaderValue {
+                key: key.clone(),
+                value: value.clone(),
+            }),
+            append: Some(true),
+            append_action: 0,
+        }));
+    if mutation.set_headers.is_empty() {
+        None
+    } else {
+        Some(mutation)
+    }
+}
+
+/// routing hint for envoy, the routes can match on this header
+const UPSTREAM_HEADER: &str = "x-curiefense-upstream";
+
+/// header mutation applying a request transform
+fn transform_headers(action: &Action, transform: &RequestTransform) -> CommonResponse {
+    let mut headers = action.headers.clone().unwrap_or_default();
+    if let Some(path) = &transform.path {
+        headers.insert(":path".to_string(), path.clone());
+    }
+    if let Some(upstream) = &transform.upstream {
+        headers.insert(UPSTREAM_HEADER.to_string(), upstream.clone());
+    }
+    let mut mutation = mutate_headers(headers);
+    mutation.remove_headers = transform.remove_headers.clone();
+    CommonResponse {
+        header_mutation: Some(mutation),
+        clear_route_cache: transform.path.is_some() || transform.upstream.is_some(),
+        ..Default::default()
+    }
+}
+
+/// passes the request, with a header mutation
+async fn transform_pass(
+    stage: ProcessingStage,
+    tx: &mut Sender<Result<ProcessingResponse, Status>>,
+    response: CommonResponse,
+) {
+    let r = match stage {
+        ProcessingStage::Headers => processing_response::Response::RequestHeaders(HeadersResponse {
+            response: Some(response),
+        }),
+        ProcessingStage::Body => processing_response::Response::RequestBody(BodyResponse {
+            response: Some(response),
+        }),
+        // the request has already been forwarded
+        ProcessingStage::RHeaders | ProcessingStage::Reply => return stage_pass(stage, tx).await,
+    };
+    if let Err(rr) = send_response(tx, r).await {
+        warn!("Could not transform the request: {}", rr);
+    }
+}
+
 async fn send_response(
     tx: &mut Sender<Result<ProcessingResponse, Status>>,
     r: processing_response::Response,
@@ -368,22 +511,44 @@ enum ProcessingStage {
     Reply,
 }
 
+/// the answer letting the request through a stage, None when envoy does not wait for an answer
+fn pass_response(stage: ProcessingStage) -> Option<processing_response::Response> {
+    match stage {
+        ProcessingStage::Headers => Some(processing_response::Response::RequestHeaders(HeadersResponse {
+            response: None,
+        })),
+        ProcessingStage::Body => Some(processing_response::Response::RequestBody(BodyResponse {
+            response: None,
+        })),
+        ProcessingStage::RHeaders => Some(processing_response::Response::ResponseHeaders(
+            ext_proc::HeadersResponse { response: None },
+        )),
+        ProcessingStage::Reply => None,
+    }
+}
+
+/// the answer when the request could not be analyzed
+fn failure_response(fail_open: bool, stage: ProcessingStage, msg: String) -> Option<processing_response::Response> {
+    if fail_open {
+        pass_response(stage)
+    } else {
+        Some(processing_response::Response::ImmediateResponse(ImmediateResponse {
+            status: Some(HttpStatus { code: 403 }),
+            headers: None,
+            body: String::new(),
+            grpc_status: None,
+            details: msg,
+        }))
+    }
+}
+
+/// lets the request through a stage, the stream might have been reset by envoy
 async fn stage_pass(stage: ProcessingStage, tx: &mut Sender<Result<ProcessingResponse, Status>>) {
-    send_response(
-        tx,
-        match stage {
-            ProcessingStage::Headers => {
-                processing_response::Response::RequestHeaders(HeadersResponse { response: None })
-            }
-            ProcessingStage::Body => processing_response::Response::RequestBody(BodyResponse { response: None }),
-            ProcessingStage::RHeaders => {
-                processing_response::Response::ResponseHeaders(ext_proc::HeadersResponse { response: None })
-            }
-            ProcessingStage::Reply => return,
-        },
-    )
-    .await
-    .unwrap();
+    if let Some(r) = pass_response(stage) {
+        if let Err(rr) = send_response(tx, r).await {
+            warn!("Could not let the request through: {}", rr);
+        }
+    }
 }
 
 fn show_logs(logs: Logs) {
@@ -409,22 +574,20 @@ impl ExternalProcessor for MyEP {
         let cep = self.clone();
 
         spawn(async move {
-            if let Err(msg) = cep.handle(&mut tx, &mut message).await {
+            let mut stage = ProcessingStage::Headers;
+            let fail_open = cep.fail_open;
+            if let Err(msg) = cep.handle(&mut tx, &mut message, &mut stage).await {
                 error!("{}", msg);
-                send_response(
-                    &mut tx,
-                    processing_response::Response::ImmediateResponse(ext_proc::ImmediateResponse {
-                        status: Some(ext_proc::HttpStatus { code: 403 }),
-                        headers: None,
-                        body: String::new(),
-                        grpc_status: None,
-                        details: msg,
-                    }),
-                )
-                .await
-                .unwrap()
+                if let Some(r) = failure_response(fail_open, stage, msg) {
+                    if let Err(rr) = send_response(&mut tx, r).await {
+                        warn!("Could not answer the failed request: {}", rr);
+                    }
+                }
+            }
+            // fails when the stream was reset, there is nothing left to answer then
+            if let Err(rr) = message.trailers().await {
+                debug!("Could not read the stream trailers: {}", rr);
             }
-            message.trailers().await.unwrap();
         });
 
         Ok(tonic::Response::new(ReceiverStream::new(rx)))
@@ -448,10 +611,22 @@ struct Opt {
     trustedhops: u32,
     #[structopt(long)]
     handle_replies: bool,
+    /// let the requests through when they can't be analyzed, instead of answering with a 403
+    #[structopt(long)]
+    fail_open: bool,
     #[structopt(long)]
     syslog: bool,
     #[structopt(long)]
     elasticsearch: Option<String>,
+    /// maximum number of log entries waiting to be sent to elasticsearch
+    #[structopt(long, default_value = "500")]
+    log_queue_size: usize,
+    /// what to do when the log queue is full, drop_newest or drop_oldest
+    #[structopt(long, default_value = "drop_newest")]
+    log_drop_policy: DropPolicy,
+    /// format of the entries sent to elasticsearch, curiefense, cef or ecs
+    #[structopt(long, default_value = "curiefense")]
+    log_format: LogFormat,
 }
 
 #[tokio::main]
@@ -486,17 +661,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let _ = spawn(async move { configloop(crx, &opt.configpath, loglevel, opt.trustedhops).await });
 
-    let mut logsender: Option<Sender<(Vec<u8>, DateTime<Utc>)>> = None;
+    let mut logsink: Option<Arc<BoundedSink>> = None;
 
     if let Some(esurl) = opt.elasticsearch {
-        let (logtx, logrx) = mpsc::channel(500);
         let transport = Transport::single_node(&esurl)?;
         let client = Elasticsearch::new(transport);
-        logsender = Some(logtx);
-        let _ = spawn(async move { logloop(logrx, client).await });
+        let sink = BoundedSink::new("elasticsearch", opt.log_queue_size, opt.log_drop_policy);
+        logsink = Some(sink.clone());
+        let rt = tokio::runtime::Handle::current();
+        let _ = std::thread::spawn(move || logloop(sink, client, rt));
     }
 
-    let ep = MyEP::new(ctx, opt.handle_replies, logsender);
+    let ep = MyEP::new(ctx, opt.handle_replies, opt.fail_open, logsink, opt.log_format);
     Server::builder()
         .accept_http1(true)
         .add_service(ExternalProcessorServer::new(ep))
@@ -505,3 +681,151 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use curiefense::config::virtualtags::VirtualTags;
+    use curiefense::interface::{Decision, Tags};
+
+    fn response(headers: &[(&str, &str)], added: &[(&str, &str)]) -> ResponseAnalyzeResult {
+        ResponseAnalyzeResult {
+            decision: Decision::pass(Vec::new()),
+            tags: Tags::new(&VirtualTags::default()),
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            body: None,
+            added_headers: added.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        }
+    }
+
+    fn set_headers(mutation: &HeaderMutation) -> Vec<(String, String, Option<bool>)> {
+        let mut out: Vec<_> = mutation
+            .set_headers
+            .iter()
+            .map(|h| {
+                let hv = h.header.as_ref().unwrap();
+                (hv.key.clone(), hv.value.clone(), h.append)
+            })
+            .collect();
+        out.sort();
+        out
+    }
+
+    #[test]
+    fn failure_answers() {
+        for stage in [
+            ProcessingStage::Headers,
+            ProcessingStage::Body,
+            ProcessingStage::RHeaders,
+        ] {
+            assert_eq!(
+                failure_response(true, stage, "failed".to_string()),
+                pass_response(stage)
+            );
+            assert!(pass_response(stage).is_some());
+        }
+        assert_eq!(
+            failure_response(true, ProcessingStage::Reply, "failed".to_string()),
+            None
+        );
+        match failure_response(false, ProcessingStage::Body, "failed".to_string()) {
+            Some(processing_response::Response::ImmediateResponse(r)) => {
+                assert_eq!(r.status, Some(HttpStatus { code: 403 }));
+                assert_eq!(r.details, "failed");
+            }
+            other => panic!("expected a 403, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_stream() {
+        let (mut tx, rx) = mpsc::channel(1);
+        drop(rx);
+        // the stream was reset, the answers are dropped without panicking
+        stage_pass(ProcessingStage::Headers, &mut tx).await;
+        transform_pass(ProcessingStage::Body, &mut tx, CommonResponse::default()).await;
+    }
+
+    fn transform_response(headers: &[(&str, &str)], transform: &RequestTransform) -> CommonResponse {
+        let action = Action {
+            headers: Some(headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()),
+            ..Action::default()
+        };
+        transform_headers(&action, transform)
+    }
+
+    #[test]
+    fn transform_set_headers() {
+        let transform = RequestTransform {
+            remove_headers: Vec::new(),
+            path: Some("/v2/users".to_string()),
+            upstream: Some("pool-b".to_string()),
+        };
+        let response = transform_response(&[("x-user", "alice")], &transform);
+        assert!(response.clear_route_cache);
+        assert_eq!(
+            set_headers(response.header_mutation.as_ref().unwrap()),
+            vec![
+                (":path".to_string(), "/v2/users".to_string(), None),
+                (UPSTREAM_HEADER.to_string(), "pool-b".to_string(), None),
+                ("x-user".to_string(), "alice".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn transform_path_overrides_action_header() {
+        let transform = RequestTransform {
+            path: Some("/rewritten".to_string()),
+            ..RequestTransform::default()
+        };
+        let response = transform_response(&[(":path", "/original")], &transform);
+        assert_eq!(
+            set_headers(response.header_mutation.as_ref().unwrap()),
+            vec![(":path".to_string(), "/rewritten".to_string(), None)]
+        );
+    }
+
+    #[test]
+    fn transform_remove_headers() {
+        let transform = RequestTransform {
+            remove_headers: vec!["cookie".to_string(), "authorization".to_string()],
+            ..RequestTransform::default()
+        };
+        let response = transform_response(&[], &transform);
+        // the route does not change, envoy keeps its cached route
+        assert!(!response.clear_route_cache);
+        let mutation = response.header_mutation.unwrap();
+        assert!(mutation.set_headers.is_empty());
+        assert_eq!(mutation.remove_headers, vec!["cookie", "authorization"]);
+    }
+
+    #[test]
+    fn response_mutation_nothing() {
+        assert_eq!(response_mutation(None, &response(&[], &[])), None);
+    }
+
+    #[test]
+    fn response_mutation_csrf_cookie() {
+        let action = [("x-ratelimit-remaining".to_string(), "3".to_string())]
+            .into_iter()
+            .collect();
+        let mutation = response_mutation(
+            Some(action),
+            &response(
+                &[("server", "redacted")],
+                &[("set-cookie", "cf_csrf=token"), ("x-csrf-token", "token")],
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            set_headers(&mutation),
+            vec![
+                ("server".to_string(), "redacted".to_string(), None),
+                ("set-cookie".to_string(), "cf_csrf=token".to_string(), Some(true)),
+                ("x-csrf-token".to_string(), "token".to_string(), Some(true)),
+                ("x-ratelimit-remaining".to_string(), "3".to_string(), None),
+            ]
+        );
+    }
+}
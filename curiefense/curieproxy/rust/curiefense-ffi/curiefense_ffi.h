@@ -77,6 +77,13 @@ bool curiefense_cfr_is_blocking(const struct CFResult *ptr);
  */
 uint32_t curiefense_cfr_block_status(const struct CFResult *ptr);
 
+/**
+ * # Safety
+ *
+ * Returns the number of milliseconds to wait before sending a blocking response, for tarpits.
+ */
+uint64_t curiefense_cfr_block_delay(const struct CFResult *ptr);
+
 /**
  * # Safety
  *
@@ -92,6 +99,29 @@ uintptr_t curiefense_cfr_block_contentlength(const struct CFResult *ptr);
  */
 void curiefense_cfr_block_content(const struct CFResult *ptr, unsigned char *tgt);
 
+/**
+ * # Safety
+ *
+ * Returns false when the request is sampled out of the access logs.
+ */
+bool curiefense_cfr_should_log(const struct CFResult *ptr);
+
+/**
+ * # Safety
+ *
+ * Returns the structured access log record, json encoded, without consuming the result. A status of 0 means it
+ * is not known. Can be freed with curiefense_str_free.
+ */
+char *curiefense_cfr_access_log(const struct CFResult *ptr, uint32_t status, uintptr_t *ln);
+
+/**
+ * # Safety
+ *
+ * Returns the result in a SIEM format, "cef" or "ecs", without consuming the result. A status of 0 means it is not
+ * known. Returns NULL for an unknown format. Can be freed with curiefense_str_free.
+ */
+char *curiefense_cfr_siem(const struct CFResult *ptr, const char *format, uint32_t status, uintptr_t *ln);
+
 /**
  * # Safety
  *
@@ -115,6 +145,14 @@ void curiefense_cfr_logs(struct CFResult *ptr,
  */
 char *curiefense_cfr_error(const struct CFResult *ptr);
 
+/**
+ * # Safety
+ *
+ * Releases the in-flight limit counters taken by the request. Must be called once, when the request completes,
+ * before the result is consumed by curiefense_cfr_log. Returns false if the counters could not be released.
+ */
+bool curiefense_cfr_release_inflight(const struct CFResult *ptr);
+
 /**
  * # Safety
  *
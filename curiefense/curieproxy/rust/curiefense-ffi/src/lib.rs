@@ -1,11 +1,16 @@
 use core::ffi::c_void;
+use curiefense::accesslog::{access_log_json, should_log};
+use curiefense::analyze::ResponseAnalyzeResult;
+use curiefense::challenge::ChallengeProvider;
 use curiefense::config::contentfilter::ContentFilterRules;
 use curiefense::config::Config;
-use curiefense::grasshopper::{DummyGrasshopper, Grasshopper};
+use curiefense::counters::release_inflight_block;
+use curiefense::grasshopper::DummyGrasshopper;
 use curiefense::incremental::{add_body, add_header, finalize, inspect_init, IData, IPInfo};
 use curiefense::inspect_generic_request_map_async;
 use curiefense::interface::{jsonlog_block, AnalyzeResult};
 use curiefense::logs::{LogLevel, Logs};
+use curiefense::siem::{siem_record, LogFormat};
 use curiefense::simple_executor::{new_executor_and_spawner, Executor, Progress, TaskCB};
 use curiefense::utils::{RawRequest, RequestMeta};
 use std::collections::HashMap;
@@ -109,6 +114,18 @@ pub unsafe extern "C" fn curiefense_cfr_block_status(ptr: *const CFResult) -> u3
     }
 }
 
+/// # Safety
+///
+/// Returns the number of milliseconds to wait before sending a blocking response, for tarpits.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_cfr_block_delay(ptr: *const CFResult) -> u64 {
+    match ptr.as_ref() {
+        None => 0,
+        Some(CFResult::RR(_)) => 0,
+        Some(CFResult::OK(r)) => r.result.decision.maction.as_ref().and_then(|a| a.delay).unwrap_or(0),
+    }
+}
+
 /// # Safety
 ///
 /// Returns the content length of a blocking action.
@@ -137,6 +154,88 @@ pub unsafe extern "C" fn curiefense_cfr_block_content(ptr: *const CFResult, tgt:
     }
 }
 
+/// # Safety
+///
+/// Returns false when the request is sampled out of the access logs.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_cfr_should_log(ptr: *const CFResult) -> bool {
+    match ptr.as_ref() {
+        Some(CFResult::OK(dec)) => should_log(&dec.result.decision, &dec.result.rinfo, &dec.result.tags),
+        _ => true,
+    }
+}
+
+/// # Safety
+///
+/// Returns the structured access log record, json encoded, without consuming the result. A status of 0 means it
+/// is not known. Can be freed with curiefense_str_free.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_cfr_access_log(ptr: *const CFResult, status: u32, ln: *mut usize) -> *mut c_char {
+    if ptr.is_null() {
+        *ln = 0;
+        return std::ptr::null_mut();
+    }
+    let out: Vec<u8> = match &*ptr {
+        CFResult::OK(dec) => access_log_json(
+            &dec.result.decision,
+            &dec.result.rinfo,
+            &dec.result.tags,
+            &dec.result.stats,
+            Some(status).filter(|s| *s != 0),
+        ),
+        CFResult::RR(rr) => rr.as_bytes().to_vec(),
+    };
+    *ln = out.len();
+    match CString::new(out) {
+        Err(_) => {
+            *ln = 0;
+            std::ptr::null_mut()
+        }
+        Ok(cs) => cs.into_raw(),
+    }
+}
+
+/// # Safety
+///
+/// Returns the result in a SIEM format, "cef" or "ecs", without consuming the result. A status of 0 means it is not
+/// known. Returns NULL for an unknown format. Can be freed with curiefense_str_free.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_cfr_siem(
+    ptr: *const CFResult,
+    format: *const c_char,
+    status: u32,
+    ln: *mut usize,
+) -> *mut c_char {
+    *ln = 0;
+    if ptr.is_null() || format.is_null() {
+        return std::ptr::null_mut();
+    }
+    let format: LogFormat = match CStr::from_ptr(format).to_str().ok().and_then(|s| s.parse().ok()) {
+        Some(f) => f,
+        None => return std::ptr::null_mut(),
+    };
+    let out: Vec<u8> = match &*ptr {
+        CFResult::OK(dec) => match siem_record(
+            format,
+            &dec.result.decision,
+            &dec.result.rinfo,
+            &dec.result.tags,
+            Some(status).filter(|s| *s != 0),
+        ) {
+            Some(v) => v,
+            None => return std::ptr::null_mut(),
+        },
+        CFResult::RR(rr) => rr.as_bytes().to_vec(),
+    };
+    match CString::new(out) {
+        Err(_) => std::ptr::null_mut(),
+        Ok(cs) => {
+            *ln = cs.as_bytes().len();
+            cs.into_raw()
+        }
+    }
+}
+
 /// # Safety
 ///
 /// Returns the log string, json encoded. Can be freed with curiefense_str_free.
@@ -221,6 +320,174 @@ pub unsafe extern "C" fn curiefense_cfr_error(ptr: *const CFResult) -> *mut c_ch
     out.into_raw()
 }
 
+/// # Safety
+///
+/// Releases the in-flight limit counters taken by the request. Must be called once, when the request completes,
+/// before the result is consumed by curiefense_cfr_log. Returns false if the counters could not be released.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_cfr_release_inflight(ptr: *const CFResult) -> bool {
+    match ptr.as_ref() {
+        Some(CFResult::OK(r)) => release_inflight_block(&r.result.rinfo.inflight).is_ok(),
+        _ => true,
+    }
+}
+
+/// Result of the inspection of the upstream server response
+#[derive(Debug)]
+pub struct CFResponse {
+    result: ResponseAnalyzeResult,
+    logs: Logs,
+}
+
+/// # Safety
+///
+/// Inspects the response of the upstream server, for a request that was let through, without consuming the result.
+/// Returns NULL when the request could not be inspected. The returned object is freed with curiefense_resp_free.
+///
+/// Note that the hashmap raw_headers is consumed and freed by this function.
+///
+/// Arguments
+///
+/// status: the response status code
+/// raw_headers: hashmap containing the response headers
+/// mbody: body as a single buffer, or NULL if it is not inspected
+/// mbody_len: length of the body. It MUST be 0 if mbody is NULL.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_cfr_inspect_response(
+    ptr: *const CFResult,
+    status: u32,
+    raw_headers: *mut CFHashmap,
+    mbody: *const c_uchar,
+    mbody_len: usize,
+) -> *mut CFResponse {
+    let headers = match raw_headers.as_mut() {
+        None => return std::ptr::null_mut(),
+        Some(rf) => Box::from_raw(rf).inner,
+    };
+    let dec = match ptr.as_ref() {
+        Some(CFResult::OK(dec)) => dec,
+        _ => return std::ptr::null_mut(),
+    };
+    let body = if mbody_len == 0 {
+        None
+    } else {
+        Some(std::slice::from_raw_parts(mbody, mbody_len).to_vec())
+    };
+    let mut logs = Logs::new(dec.logs.level);
+    let result = dec.result.inspect_response(&mut logs, status, headers, body);
+    Box::into_raw(Box::new(CFResponse { result, logs }))
+}
+
+/// # Safety
+///
+/// Returns true when the response must be replaced by the one of the blocking action, see
+/// curiefense_resp_block_status and curiefense_resp_block_content.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_resp_is_blocking(ptr: *const CFResponse) -> bool {
+    match ptr.as_ref() {
+        None => false,
+        Some(r) => r.result.decision.is_blocking(),
+    }
+}
+
+/// # Safety
+///
+/// Returns the status code of a blocking action.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_resp_block_status(ptr: *const CFResponse) -> u32 {
+    match ptr.as_ref() {
+        None => 0,
+        Some(r) => r.result.decision.maction.as_ref().map(|a| a.status).unwrap_or(0),
+    }
+}
+
+/// # Safety
+///
+/// Returns the headers to set on the response, as a json object, followed by the headers to append, such as the
+/// CSRF token cookie, as a json list of [name, value] pairs, in a json object with the "set" and "append" keys.
+/// Can be freed with curiefense_str_free.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_resp_headers(ptr: *const CFResponse, ln: *mut usize) -> *mut c_char {
+    *ln = 0;
+    let r = match ptr.as_ref() {
+        None => return std::ptr::null_mut(),
+        Some(r) => r,
+    };
+    let out = serde_json::json!({
+        "set": r.result.headers,
+        "append": r.result.added_headers,
+    })
+    .to_string();
+    match CString::new(out) {
+        Err(_) => std::ptr::null_mut(),
+        Ok(cs) => {
+            *ln = cs.as_bytes().len();
+            cs.into_raw()
+        }
+    }
+}
+
+/// # Safety
+///
+/// Returns the replacement body of the response, or NULL when it was not rewritten. The pointer is valid until the
+/// response object is freed, and must not be freed.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_resp_body(ptr: *const CFResponse, ln: *mut usize) -> *const c_uchar {
+    *ln = 0;
+    match ptr.as_ref().and_then(|r| r.result.body.as_ref()) {
+        None => std::ptr::null(),
+        Some(body) => {
+            *ln = body.len();
+            body.as_ptr()
+        }
+    }
+}
+
+/// # Safety
+///
+/// Returns the content of a blocking action, or NULL. The pointer is valid until the response object is freed,
+/// and must not be freed.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_resp_block_content(ptr: *const CFResponse, ln: *mut usize) -> *const c_uchar {
+    *ln = 0;
+    match ptr.as_ref().and_then(|r| r.result.decision.maction.as_ref()) {
+        None => std::ptr::null(),
+        Some(a) => {
+            *ln = a.content.len();
+            a.content.as_ptr()
+        }
+    }
+}
+
+/// # Safety
+///
+/// Populates the logs of the response inspection, see curiefense_cfr_logs.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_resp_logs(
+    ptr: *const CFResponse,
+    cb: unsafe extern "C" fn(u8, *const c_char, *mut c_void),
+    cb_data: *mut c_void,
+) {
+    if let Some(r) = ptr.as_ref() {
+        for log in &r.logs.logs {
+            let msg_str = format!("{}µs - {}", log.elapsed_micros, log.message);
+            let msg = match CString::new(msg_str) {
+                Err(_) => CString::new("Irrepresentable log".to_string()).unwrap(),
+                Ok(lgmsg) => lgmsg,
+            };
+            cb(log.level as u8, msg.as_ptr(), cb_data)
+        }
+    }
+}
+
+/// # Safety
+///
+/// Frees the result of curiefense_cfr_inspect_response.
+#[no_mangle]
+pub unsafe extern "C" fn curiefense_resp_free(ptr: *mut CFResponse) {
+    c_free(ptr);
+}
+
 /// # Safety
 ///
 /// Frees a string that has been returned by this API.
@@ -233,7 +500,7 @@ pub unsafe extern "C" fn curiefense_str_free(ptr: *mut c_char) {
 }
 
 /// Simple wrapper to return the reqinfo data
-pub async fn inspect_wrapper<GH: Grasshopper>(logs: Logs, raw: RawRequest<'_>, mgh: Option<&GH>) -> CFDecision {
+pub async fn inspect_wrapper<GH: ChallengeProvider>(logs: Logs, raw: RawRequest<'_>, mgh: Option<&GH>) -> CFDecision {
     let mut mlogs = logs;
     let result = inspect_generic_request_map_async(mgh, raw, &mut mlogs, None, HashMap::new()).await;
     CFDecision { result, logs: mlogs }
@@ -580,7 +847,7 @@ pub unsafe extern "C" fn curiefense_stream_add_body(
 }
 
 /// Simple wrapper to return the reqinfo data
-pub async fn stream_wrapper<GH: Grasshopper>(
+pub async fn stream_wrapper<GH: ChallengeProvider>(
     config: &CFStreamConfig,
     data: Result<Box<IData>, Box<(AnalyzeResult, Logs)>>,
     mgh: Option<&GH>,
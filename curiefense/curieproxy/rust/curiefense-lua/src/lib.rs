@@ -1,27 +1,39 @@
 pub mod userdata;
 
+use curiefense::abtest::drain_diff_records;
+use curiefense::analyze::analyze_client_state_block;
 use curiefense::analyze::analyze_finish;
 use curiefense::analyze::analyze_flows;
 use curiefense::analyze::analyze_init;
+use curiefense::analyze::end_request_span;
+use curiefense::analyze::record_client_state_block;
 use curiefense::analyze::APhase1;
 use curiefense::analyze::APhase2I;
 use curiefense::analyze::APhase2O;
 use curiefense::analyze::APhase3;
 use curiefense::analyze::CfRulesArg;
 use curiefense::analyze::InitResult;
-use curiefense::config::reload_config;
+use curiefense::ban::{list_bans_block, remove_ban_block};
+use curiefense::challenge::ChallengeProvider;
+use curiefense::challenge::ConfiguredChallenge;
+use curiefense::config::validate::validate_config;
+use curiefense::config::{active_revision, reload_config};
+use curiefense::counters::release_inflight_block;
 use curiefense::grasshopper::DynGrasshopper;
 use curiefense::grasshopper::GHMode;
 use curiefense::grasshopper::GHQuery;
 use curiefense::grasshopper::GHResponse;
-use curiefense::grasshopper::Grasshopper;
 use curiefense::grasshopper::PrecisionLevel;
 use curiefense::inspect_generic_request_map;
 use curiefense::inspect_generic_request_map_init;
-use curiefense::interface::aggregator::aggregated_values_block;
+use curiefense::interface::aggregator::{aggregated_values_block, anomaly_snapshot_block};
+use curiefense::limit::INFLIGHT_SCRIPT;
 use curiefense::logs::LogLevel;
 use curiefense::logs::Logs;
+use curiefense::otel::Span;
+use curiefense::quota::quota_usage_block;
 use curiefense::requestfields::RequestField;
+use curiefense::support::support_bundle_block;
 use curiefense::utils::RequestMeta;
 use curiefense::utils::{InspectionResult, RawRequest};
 use mlua::prelude::*;
@@ -153,13 +165,13 @@ fn lua_convert_args<'l>(lua: &'l Lua, args: LuaTable<'l>) -> Result<LuaArgs<'l>,
 fn lua_inspect_request(lua: &Lua, args: LuaTable) -> LuaResult<LuaInspectionResult> {
     match lua_convert_args(lua, args) {
         Ok(lua_args) => {
-            let grasshopper = &DynGrasshopper {};
+            let grasshopper = ConfiguredChallenge::current();
             let res = inspect_request(
                 lua_args.meta,
                 lua_args.headers,
                 lua_args.lua_body.as_ref().map(|b| b.as_bytes()),
                 lua_args.str_ip,
-                Some(grasshopper),
+                grasshopper.as_ref(),
                 lua_args.secpolid,
                 lua_args.plugins,
             );
@@ -175,14 +187,14 @@ fn lua_inspect_request(lua: &Lua, args: LuaTable) -> LuaResult<LuaInspectionResu
 fn lua_inspect_init(lua: &Lua, args: LuaTable) -> LuaResult<LInitResult<APhase1>> {
     match lua_convert_args(lua, args) {
         Ok(lua_args) => {
-            let grasshopper = &DynGrasshopper {};
+            let grasshopper = ConfiguredChallenge::current();
             let res = inspect_init(
                 lua_args.loglevel,
                 lua_args.meta,
                 lua_args.headers,
                 lua_args.lua_body.as_ref().map(|b| b.as_bytes()),
                 lua_args.str_ip,
-                Some(grasshopper),
+                grasshopper.as_ref(),
                 lua_args.secpolid,
                 lua_args.plugins,
             );
@@ -236,8 +248,12 @@ fn lua_inspect_process(lua: &Lua, args: (LuaValue, LuaValue)) -> LuaResult<LuaIn
         LInitResult::P1(logs, p2) => (logs, p2),
     };
     let p3 = APhase3::from_phase2(*p2, limit_results);
-    let grasshopper = &DynGrasshopper {};
-    let res = analyze_finish(&mut logs, Some(grasshopper), CfRulesArg::Global, p3);
+    let grasshopper = ConfiguredChallenge::current();
+    let span = Span::start(logs.trace.as_ref(), "curiefense.analyze_finish");
+    let res = analyze_finish(&mut logs, grasshopper.as_ref(), CfRulesArg::Global, p3);
+    span.end();
+    record_client_state_block(&mut logs, &res);
+    end_request_span(&mut logs, &res);
     Ok(LuaInspectionResult(Ok(InspectionResult::from_analyze(logs, res))))
 }
 
@@ -277,7 +293,7 @@ struct DummyGrasshopper {
     humanity: PrecisionLevel,
 }
 
-impl Grasshopper for DummyGrasshopper {
+impl ChallengeProvider for DummyGrasshopper {
     fn is_human(&self, _input: GHQuery) -> Result<PrecisionLevel, String> {
         Ok(self.humanity)
     }
@@ -330,7 +346,7 @@ fn lua_test_inspect_request(lua: &Lua, args: LuaTable) -> LuaResult<LuaInspectio
 
 /// Rust-native inspection top level function
 #[allow(clippy::too_many_arguments)]
-fn inspect_request<GH: Grasshopper>(
+fn inspect_request<GH: ChallengeProvider>(
     meta: HashMap<String, String>,
     headers: HashMap<String, String>,
     mbody: Option<&[u8]>,
@@ -355,7 +371,7 @@ fn inspect_request<GH: Grasshopper>(
 }
 /// Rust-native functions for the dialog system
 #[allow(clippy::too_many_arguments)]
-fn inspect_init<GH: Grasshopper>(
+fn inspect_init<GH: ChallengeProvider>(
     loglevel: LogLevel,
     meta: HashMap<String, String>,
     headers: HashMap<String, String>,
@@ -381,7 +397,15 @@ fn inspect_init<GH: Grasshopper>(
         Ok(p0) => p0,
     };
 
+    let ctx = p0.reqinfo.trace.clone();
+    let p0 = analyze_client_state_block(&mut logs, p0);
+    let span = Span::start(ctx.as_ref(), "curiefense.analyze_init");
     let r = analyze_init(&mut logs, grasshopper, p0);
+    span.end();
+    if let InitResult::Res(res) = &r {
+        record_client_state_block(&mut logs, res);
+        end_request_span(&mut logs, res);
+    }
     Ok((r, logs))
 }
 
@@ -400,7 +424,66 @@ fn curiefense(lua: &Lua) -> LuaResult<LuaTable> {
         "aggregated_values",
         lua.create_function(|_, ()| Ok(aggregated_values_block()))?,
     )?;
+    exports.set(
+        "anomaly_snapshot",
+        lua.create_function(|_, ()| Ok(anomaly_snapshot_block()))?,
+    )?;
+    exports.set(
+        "support_bundle",
+        lua.create_function(|_, ()| Ok(support_bundle_block(Some(&DynGrasshopper {}))))?,
+    )?;
     exports.set("lua_reload_conf", lua.create_function(lua_reload_conf)?)?;
+    // revision and hash of the configuration currently in use, as JSON
+    exports.set(
+        "config_revision",
+        lua.create_function(|_, ()| Ok(serde_json::to_string(&active_revision()).unwrap_or_else(|rr| rr.to_string())))?,
+    )?;
+    // dry-run validation of a configuration directory, returns the diagnostics as JSON
+    exports.set(
+        "validate_config",
+        lua.create_function(|_, path: String| {
+            Ok(serde_json::to_string(&validate_config(&path)).unwrap_or_else(|rr| rr.to_string()))
+        })?,
+    )?;
+    // diff records of the candidate profiles, as JSON lines
+    exports.set(
+        "ab_diff_records",
+        lua.create_function(|_, max: usize| Ok(drain_diff_records(max)))?,
+    )?;
+    // script taking an in-flight lease, called with the key, the time and lease duration in ms, and the member
+    exports.set("INFLIGHT_SCRIPT", INFLIGHT_SCRIPT)?;
+    // to be called when a request completes, with the leases from the inflight field of the result
+    exports.set(
+        "release_inflight",
+        lua.create_function(|_, keys: Vec<String>| Ok(release_inflight_block(&keys).err().map(|rr| rr.to_string())))?,
+    )?;
+    // usage of a quota, from the concatenated key selector values and an optional unix timestamp, as JSON
+    exports.set(
+        "quota_usage",
+        lua.create_function(
+            |_, (namespace, limit_id, key, at): (String, String, String, Option<i64>)| {
+                Ok(match quota_usage_block(&namespace, &limit_id, &key, at) {
+                    Ok(usage) => serde_json::to_string(&usage).unwrap_or_else(|rr| rr.to_string()),
+                    Err(rr) => serde_json::json!({ "error": rr.to_string() }).to_string(),
+                })
+            },
+        )?,
+    )?;
+    // active bans, as JSON
+    exports.set(
+        "list_bans",
+        lua.create_function(|_, ()| {
+            Ok(match list_bans_block() {
+                Ok(bans) => serde_json::to_string(&bans).unwrap_or_else(|rr| rr.to_string()),
+                Err(rr) => serde_json::json!({ "error": rr.to_string() }).to_string(),
+            })
+        })?,
+    )?;
+    // lifts the ban of an address, returns an error message on failure
+    exports.set(
+        "remove_ban",
+        lua.create_function(|_, ip: String| Ok(remove_ban_block(&ip).err().map(|rr| rr.to_string())))?,
+    )?;
     // end-to-end inspection (test)
     exports.set("test_inspect_request", lua.create_function(lua_test_inspect_request)?)?;
 
@@ -1,10 +1,12 @@
 use std::collections::HashMap;
 
-use curiefense::analyze::{APhase1, APhase2I};
-use curiefense::flow::{FlowCheck, FlowResult, FlowResultType};
+use curiefense::analyze::{APhase1, APhase2I, ResponseAnalyzeResult};
+use curiefense::config::limit::LimitAlgorithm;
+use curiefense::flow::{FlowCheck, FlowExpiry, FlowResult, FlowResultType};
 use curiefense::interface::Tags;
 use curiefense::limit::{LimitCheck, LimitResult};
 use curiefense::logs::Logs;
+use curiefense::siem::LogFormat;
 use curiefense::utils::InspectionResult;
 use mlua::prelude::*;
 
@@ -50,6 +52,14 @@ impl mlua::UserData for LuaInspectionResult {
         });
         fields.add_field_method_get("logs", |_, this| this.get_with(|r| r.logs.to_stringvec()));
         fields.add_field_method_get("response", |_, this| this.get_with(|r| r.decision.response_json()));
+        // false when the request is sampled out of the access logs
+        fields.add_field_method_get("should_log", |_, this| this.get_with(|r| r.should_log()));
+        fields.add_field_method_get("inflight", |_, this| {
+            this.get_with(|r| r.rinfo.as_ref().map(|i| i.inflight.clone()).unwrap_or_default())
+        });
+        fields.add_field_method_get("request_id", |_, this| {
+            this.get_with_o(|r| r.rinfo.as_ref().and_then(|i| i.rinfo.meta.requestid.clone()))
+        });
     }
 
     fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
@@ -64,6 +74,64 @@ impl mlua::UserData for LuaInspectionResult {
                 Ok(Some(v)) => Ok(Some(lua.create_string(&v)?)),
             }
         });
+        // structured access log record, with the response status when known
+        methods.add_method("access_log", |lua, this, status: Option<u32>| {
+            match this.get_with(|r| r.access_log_json(status))? {
+                None => Ok(None),
+                Some(v) => Ok(Some(lua.create_string(&v)?)),
+            }
+        });
+        // the result as a CEF line or an ECS document
+        methods.add_method("siem", |lua, this, (format, status): (String, Option<u32>)| {
+            let format: LogFormat = format.parse().map_err(|rr| LuaError::RuntimeError(format!("{}", rr)))?;
+            match this.get_with_o(|r| r.siem_record(format, status))? {
+                None => Ok(None),
+                Some(v) => Ok(Some(lua.create_string(&v)?)),
+            }
+        });
+        // inspects the response of the upstream server, nil when the request could not be inspected
+        methods.add_method(
+            "inspect_response",
+            |_, this, (status, headers, body): (u32, HashMap<String, String>, Option<LuaString>)| {
+                Ok(match &this.0 {
+                    Err(_) => None,
+                    Ok(r) => {
+                        let mut logs = Logs::new(r.logs.level);
+                        let body = body.map(|b| b.as_bytes().to_vec());
+                        r.inspect_response(&mut logs, status, headers, body)
+                            .map(|res| LuaResponseResult(res, logs))
+                    }
+                })
+            },
+        );
+    }
+}
+
+/// Data type for the response inspection
+pub struct LuaResponseResult(pub ResponseAnalyzeResult, pub Logs);
+impl mlua::UserData for LuaResponseResult {
+    fn add_fields<'lua, F: mlua::UserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("blocking", |_, this| Ok(this.0.decision.is_blocking()));
+        fields.add_field_method_get("response", |_, this| Ok(this.0.decision.response_json()));
+        fields.add_field_method_get("tags", |_, this| {
+            Ok(this.0.tags.as_hash_ref().keys().cloned().collect::<Vec<_>>())
+        });
+        fields.add_field_method_get("logs", |_, this| Ok(this.1.to_stringvec()));
+        // response headers that must be replaced
+        fields.add_field_method_get("headers", |_, this| Ok(this.0.headers.clone()));
+        // response headers that must be appended, as a list of {name, value} pairs
+        fields.add_field_method_get("added_headers", |_, this| {
+            Ok(this
+                .0
+                .added_headers
+                .iter()
+                .map(|(k, v)| vec![k.clone(), v.clone()])
+                .collect::<Vec<_>>())
+        });
+        // the replacement body, nil when the body was not rewritten
+        fields.add_field_method_get("body", |lua, this| {
+            this.0.body.as_ref().map(|b| lua.create_string(b)).transpose()
+        });
     }
 }
 
@@ -150,6 +218,18 @@ impl mlua::UserData for LInitResult<APhase1> {
                 Ok(Some(v)) => Ok(Some(lua.create_string(&v)?)),
             }
         });
+        // same as the inspection result method, for requests decided in the first phase
+        methods.add_method(
+            "inspect_response",
+            |_, this, (status, headers, body): (u32, HashMap<String, String>, Option<LuaString>)| {
+                this.get_with_o(|r| {
+                    let mut logs = Logs::new(r.logs.level);
+                    let body = body.map(|b| b.as_bytes().to_vec());
+                    r.inspect_response(&mut logs, status, headers, body)
+                        .map(|res| LuaResponseResult(res, logs))
+                })
+            },
+        );
     }
 }
 
@@ -222,13 +302,13 @@ impl mlua::UserData for LuaLimitCheck {
         fields.add_field_method_get("pairwith", |_, this| Ok(this.0.pairwith.clone()));
         fields.add_field_method_get("zero_limits", |_, this| Ok(this.0.zero_limits()));
         fields.add_field_method_get("timeframe", |_, this| Ok(this.0.limit.timeframe));
+        fields.add_field_method_get("cost", |_, this| Ok(this.0.cost));
+        fields.add_field_method_get("inflight", |_, this| Ok(this.0.algorithm() == LimitAlgorithm::InFlight));
+        fields.add_field_method_get("member", |_, this| Ok(this.0.member.clone()));
     }
     fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method("result", |_, this, curcount| {
-            Ok(LuaLimitResult(LimitResult {
-                limit: this.0.limit.clone(),
-                curcount,
-            }))
+        methods.add_method("result", |_, this, (curcount, ttl): (i64, Option<i64>)| {
+            Ok(LuaLimitResult(this.0.clone().result(curcount, ttl.unwrap_or(-1))))
         });
     }
 }
@@ -249,10 +329,13 @@ impl mlua::UserData for LuaFlowCheck {
         fields.add_field_method_get("name", |_, this| Ok(this.0.name.clone()));
         fields.add_field_method_get("tags", |_, this| Ok(this.0.tags.clone()));
         fields.add_field_method_get("timeframe", |_, this| Ok(this.0.timeframe));
+        fields.add_field_method_get("step_ttl", |_, this| Ok(this.0.step_ttl));
+        fields.add_field_method_get("max_duration", |_, this| Ok(this.0.max_duration));
     }
 
     fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method("result", |_, this, tp: String| {
+        // the optional expiry is "step" or "sequence", when the sequence in progress timed out
+        methods.add_method("result", |_, this, (tp, expiry): (String, Option<String>)| {
             let tp = match tp.as_str() {
                 "lastok" => FlowResultType::LastOk,
                 "lastblock" => FlowResultType::LastBlock,
@@ -270,6 +353,7 @@ impl mlua::UserData for LuaFlowCheck {
                 id: this.0.id.clone(),
                 name: this.0.name.clone(),
                 tags: this.0.tags.clone(),
+                expired: expiry.as_deref().and_then(FlowExpiry::parse),
             }))
         });
     }
@@ -3,6 +3,7 @@ use pyo3::exceptions::PyTypeError;
 use pyo3::prelude::*;
 use std::collections::HashMap;
 
+use curiefense::challenge::ConfiguredChallenge;
 use curiefense::grasshopper::DynGrasshopper;
 use curiefense::inspect_generic_request_map;
 use curiefense::logs::{LogLevel, Logs};
@@ -43,8 +44,8 @@ fn py_inspect_request(
         mbody,
     };
 
-    let grasshopper = DynGrasshopper {};
-    let dec = inspect_generic_request_map(Some(&grasshopper), raw, &mut logs, None, plugins.unwrap_or_default());
+    let grasshopper = ConfiguredChallenge::current();
+    let dec = inspect_generic_request_map(grasshopper.as_ref(), raw, &mut logs, None, plugins.unwrap_or_default());
     let res = InspectionResult {
         decision: dec.decision,
         tags: Some(dec.tags),
@@ -119,11 +120,23 @@ fn aggregated_data() -> PyResult<String> {
     Ok(curiefense::interface::aggregator::aggregated_values_block())
 }
 
+#[pyfunction]
+fn anomaly_snapshot() -> PyResult<String> {
+    Ok(curiefense::interface::aggregator::anomaly_snapshot_block())
+}
+
+#[pyfunction]
+fn support_bundle() -> PyResult<String> {
+    Ok(curiefense::support::support_bundle_block(Some(&DynGrasshopper {})))
+}
+
 #[pymodule]
 fn curiefense(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(py_inspect_request, m)?)?;
     m.add_function(wrap_pyfunction!(rust_match, m)?)?;
     m.add_function(wrap_pyfunction!(hyperscan_match, m)?)?;
     m.add_function(wrap_pyfunction!(aggregated_data, m)?)?;
+    m.add_function(wrap_pyfunction!(anomaly_snapshot, m)?)?;
+    m.add_function(wrap_pyfunction!(support_bundle, m)?)?;
     Ok(())
 }
@@ -9,7 +9,18 @@ use std::fmt::Write;
 fn body_test(mcontent_type: Option<&str>, body: &[u8], expected_size: Option<usize>) {
     let mut logs = Logs::default();
     let mut args = RequestField::new(&[]);
-    parse_body(&mut logs, &mut args, 500, mcontent_type, &[], body).unwrap();
+    parse_body(
+        &mut logs,
+        &mut args,
+        500,
+        usize::MAX,
+        Default::default(),
+        &Default::default(),
+        mcontent_type,
+        &[],
+        body,
+    )
+    .unwrap();
     if let Some(sz) = expected_size {
         assert_eq!(args.len(), sz);
     }
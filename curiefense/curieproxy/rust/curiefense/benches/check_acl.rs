@@ -1,10 +1,10 @@
 use criterion::*;
 use curiefense::config::virtualtags::VirtualTags;
 use rand::{distributions::Alphanumeric, Rng};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use curiefense::acl::check_acl;
-use curiefense::config::raw::AclProfile;
+use curiefense::config::raw::{AclExpressions, AclProfile};
 use curiefense::interface::{Location, SimpleAction, Tags};
 
 fn tags_vec(sz: usize) -> Vec<(String, Location)> {
@@ -38,6 +38,8 @@ fn gen_profile(sz: usize) -> AclProfile {
         force_deny: tags_vec(sz).into_iter().map(|p| p.0).collect(),
         action: SimpleAction::default(),
         tags: HashSet::new(),
+        expires: HashMap::new(),
+        expressions: AclExpressions::default(),
     }
 }
 
@@ -1,10 +1,11 @@
 use criterion::*;
 use curiefense::analyze::{analyze, APhase0, CfRulesArg};
 use curiefense::config::contentfilter::{ContentFilterProfile, ContentFilterRules};
-use curiefense::config::hostmap::{PolicyId, SecurityPolicy};
+use curiefense::config::hostmap::{CounterSettings, PolicyId, SecurityPolicy};
 use curiefense::config::raw::AclProfile;
 use curiefense::config::virtualtags::VirtualTags;
 use curiefense::grasshopper::{DummyGrasshopper, PrecisionLevel};
+use curiefense::hooks::Hooks;
 use curiefense::interface::{SecpolStats, SimpleDecision, StatsCollect};
 use curiefense::logs::{LogLevel, Logs};
 use curiefense::tagging::tag_request;
@@ -25,6 +26,11 @@ fn logging_empty(c: &mut Criterion) {
             requestid: None,
             extra: HashMap::new(),
             protocol: None,
+            tls_fingerprint: None,
+            header_order: None,
+            proxy_source: None,
+            sni: None,
+            client_cert: None,
         },
         mbody: Some(b"{\"zzz\":45}"),
     };
@@ -45,6 +51,34 @@ fn logging_empty(c: &mut Criterion) {
         limits: Vec::new(),
         session: Vec::new(),
         session_ids: Vec::new(),
+        response_filter_active: false,
+        response_filter_profile: None,
+        counters: CounterSettings::default(),
+        jwt: None,
+        session_tracking: None,
+        decision_cache: None,
+        data_leak: None,
+        csrf: None,
+        protocol: None,
+        websocket: None,
+        bot_score: None,
+        captcha: None,
+        failure_policy: Default::default(),
+        canary: None,
+        candidate: None,
+        access_log: None,
+        path_template: None,
+        methods: None,
+        api_key: None,
+        honeypot: None,
+        offender_tracking: None,
+        bypass: None,
+        cadence: None,
+        login_protection: None,
+        checkout_protection: None,
+        scraping: None,
+        replay: None,
+        signature: None,
     });
     let mut logs = Logs::new(LogLevel::Debug);
     let stats =
@@ -55,10 +89,13 @@ fn logging_empty(c: &mut Criterion) {
     let p0 = APhase0 {
         flows: HashMap::new(),
         globalfilter_dec,
+        session_filters: Vec::new(),
+        hooks: Hooks::default(),
         precision_level: PrecisionLevel::Invalid,
         itags,
         reqinfo,
         stats,
+        cache: None,
     };
     let rules = ContentFilterRules::empty();
     let result = async_std::task::block_on(analyze(
@@ -1,14 +1,15 @@
 use curiefense::config::contentfilter::ContentFilterProfile;
 use curiefense::config::hostmap::*;
 use curiefense::config::matchers::Matching;
-use curiefense::config::raw::AclProfile;
+use curiefense::config::pathtrie::PathTrie;
+use curiefense::config::raw::{AclExpressions, AclProfile};
 use curiefense::config::Config;
 use curiefense::interface::SimpleAction;
 use curiefense::logs::Logs;
 use curiefense::securitypolicy::match_securitypolicy;
 
 use criterion::*;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 fn gen_bogus_config(sz: usize) -> Config {
@@ -20,6 +21,7 @@ fn gen_bogus_config(sz: usize) -> Config {
                 HostMap {
                     name: format!("Dummy hostmap {}", i),
                     entries: Vec::new(),
+                    templates: PathTrie::default(),
                     default: None,
                 },
             )
@@ -38,6 +40,8 @@ fn gen_bogus_config(sz: usize) -> Config {
         force_deny: HashSet::new(),
         action: SimpleAction::default(),
         tags: HashSet::new(),
+        expires: HashMap::new(),
+        expressions: AclExpressions::default(),
     };
 
     let dummy_entries: Vec<Matching<Arc<SecurityPolicy>>> = (0..sz)
@@ -60,6 +64,34 @@ fn gen_bogus_config(sz: usize) -> Config {
                     content_filter_profile: ContentFilterProfile::default_from_seed("seed"),
                     session: Vec::new(),
                     session_ids: Vec::new(),
+                    response_filter_active: false,
+                    response_filter_profile: None,
+                    counters: CounterSettings::default(),
+                    jwt: None,
+                    session_tracking: None,
+                    decision_cache: None,
+                    data_leak: None,
+                    csrf: None,
+                    protocol: None,
+                    websocket: None,
+                    bot_score: None,
+                    captcha: None,
+                    failure_policy: Default::default(),
+                    canary: None,
+                    candidate: None,
+                    access_log: None,
+                    path_template: None,
+                    methods: None,
+                    api_key: None,
+                    honeypot: None,
+                    offender_tracking: None,
+                    bypass: None,
+                    cadence: None,
+                    login_protection: None,
+                    checkout_protection: None,
+                    scraping: None,
+                    replay: None,
+                    signature: None,
                     limits: Vec::new(),
                 }),
             )
@@ -70,6 +102,7 @@ fn gen_bogus_config(sz: usize) -> Config {
     def.default = Some(HostMap {
         name: "__default__".into(),
         entries: dummy_entries,
+        templates: PathTrie::default(),
         default: Some(Arc::new(SecurityPolicy {
             policy: PolicyId {
                 id: "__default__".into(),
@@ -86,6 +119,34 @@ fn gen_bogus_config(sz: usize) -> Config {
             content_filter_profile: ContentFilterProfile::default_from_seed("seed"),
             session: Vec::new(),
             session_ids: Vec::new(),
+            response_filter_active: false,
+            response_filter_profile: None,
+            counters: CounterSettings::default(),
+            jwt: None,
+            session_tracking: None,
+            decision_cache: None,
+            data_leak: None,
+            csrf: None,
+            protocol: None,
+            websocket: None,
+            bot_score: None,
+            captcha: None,
+            failure_policy: Default::default(),
+            canary: None,
+            candidate: None,
+            access_log: None,
+            path_template: None,
+            methods: None,
+            api_key: None,
+            honeypot: None,
+            offender_tracking: None,
+            bypass: None,
+            cadence: None,
+            login_protection: None,
+            checkout_protection: None,
+            scraping: None,
+            replay: None,
+            signature: None,
             limits: Vec::new(),
         })),
     });
@@ -0,0 +1,200 @@
+//! A/B comparison of candidate profiles.
+//!
+//! A security policy entry can name candidate ACL and content filter profiles. Every request matching the entry is
+//! evaluated with both the active and the candidate profiles, in shadow, and a diff record is queued in the ab_diff
+//! log stream when the decisions or the block reasons differ. The stream is drained by the integration, and the
+//! comparisons are counted with the aggregated statistics. The candidate never changes the decision.
+use lazy_static::lazy_static;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::canary::{shadow_evaluate, ShadowDecision, ShadowProfiles};
+use crate::config::contentfilter::ContentFilterProfile;
+use crate::config::raw::{AclProfile, RawCandidate};
+use crate::grasshopper::PrecisionLevel;
+use crate::interface::{BlockReason, Location, Tags};
+use crate::logs::Logs;
+use crate::logsink::{BoundedSink, DropPolicy, LogRecord, LogSink};
+use crate::utils::RequestInfo;
+
+/// candidate profiles of a security policy entry
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub acl_profile: AclProfile,
+    pub content_filter_profile: ContentFilterProfile,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ComparisonStats {
+    pub evaluated: u64,
+    pub decision_changed: u64,
+    pub reasons_changed: u64,
+}
+
+lazy_static! {
+    static ref DIFFS: Arc<BoundedSink> = {
+        let capacity = std::env::var("AB_DIFF_QUEUE_SIZE")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(1024);
+        BoundedSink::new("ab_diff", capacity, DropPolicy::DropOldest)
+    };
+    static ref STATS: Mutex<HashMap<String, ComparisonStats>> = Mutex::new(HashMap::new());
+}
+
+impl Candidate {
+    /// candidate profiles, the profiles that are not named are the active ones
+    pub fn resolve(
+        logs: &mut Logs,
+        raw: RawCandidate,
+        acls: &HashMap<String, AclProfile>,
+        content_filter_profiles: &HashMap<String, ContentFilterProfile>,
+        acl_profile: &AclProfile,
+        content_filter_profile: &ContentFilterProfile,
+    ) -> Option<Self> {
+        let acl_profile = match raw.acl_profile {
+            None => acl_profile.clone(),
+            Some(id) => match acls.get(&id) {
+                Some(p) => p.clone(),
+                None => {
+                    logs.error(|| format!("Unknown candidate ACL profile {}", id));
+                    return None;
+                }
+            },
+        };
+        let content_filter_profile = match raw.content_filter_profile {
+            None => content_filter_profile.clone(),
+            Some(id) => match content_filter_profiles.get(&id) {
+                Some(p) => p.clone(),
+                None => {
+                    logs.error(|| format!("Unknown candidate Content Filter profile {}", id));
+                    return None;
+                }
+            },
+        };
+        Some(Candidate {
+            acl_profile,
+            content_filter_profile,
+        })
+    }
+}
+
+/// reasons of the first decision that are not in the second one
+fn missing_reasons<'a>(from: &'a ShadowDecision, to: &ShadowDecision) -> Vec<&'a BlockReason> {
+    from.reasons.iter().filter(|r| !to.reasons.contains(r)).collect()
+}
+
+/// compares the active and candidate profiles of the security policy entry, if it has candidates
+pub fn compare_candidate(logs: &mut Logs, reqinfo: &RequestInfo, tags: &mut Tags, precision_level: PrecisionLevel) {
+    let secpol = &reqinfo.rinfo.secpolicy;
+    let candidate = match &secpol.candidate {
+        Some(c) => c,
+        None => return,
+    };
+    let active = shadow_evaluate(
+        logs,
+        ShadowProfiles::from_policy(secpol),
+        reqinfo,
+        tags,
+        precision_level,
+    );
+    let profiles = ShadowProfiles {
+        acl_profile: &candidate.acl_profile,
+        content_filter_profile: &candidate.content_filter_profile,
+        ..ShadowProfiles::from_policy(secpol)
+    };
+    let shadow = shadow_evaluate(logs, profiles, reqinfo, tags, precision_level);
+
+    let decision_changed = active.blocking != shadow.blocking;
+    let added = missing_reasons(&shadow, &active);
+    let removed = missing_reasons(&active, &shadow);
+    let reasons_changed = !added.is_empty() || !removed.is_empty();
+    {
+        let mut stats = STATS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let entry = stats.entry(secpol.entry.id.clone()).or_default();
+        entry.evaluated += 1;
+        entry.decision_changed += decision_changed as u64;
+        entry.reasons_changed += reasons_changed as u64;
+    }
+    if !decision_changed && !reasons_changed {
+        return;
+    }
+
+    tags.insert("ab-diff", Location::Request);
+    logs.debug(|| format!("candidate profiles of {} differ", secpol.entry.id));
+    let record = json!({
+        "timestamp": reqinfo.timestamp,
+        "secpolid": secpol.policy.id,
+        "secpolentryid": secpol.entry.id,
+        "ip": reqinfo.rinfo.geoip.ipstr,
+        "method": reqinfo.rinfo.meta.method,
+        "path": reqinfo.rinfo.qinfo.qpath,
+        "decision_changed": decision_changed,
+        "active": active,
+        "candidate": shadow,
+        "added_reasons": added,
+        "removed_reasons": removed,
+    });
+    DIFFS.submit(LogRecord {
+        data: record.to_string().into_bytes(),
+        timestamp: reqinfo.timestamp,
+    });
+}
+
+/// takes up to `max` diff records from the ab_diff stream, as JSON lines
+pub fn drain_diff_records(max: usize) -> Vec<String> {
+    std::iter::from_fn(|| DIFFS.try_recv())
+        .take(max)
+        .map(|r| String::from_utf8_lossy(&r.data).into_owned())
+        .collect()
+}
+
+/// comparison statistics, by security policy entry id
+pub fn comparison_stats() -> HashMap<String, ComparisonStats> {
+    STATS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candidate_defaults_to_active() {
+        let mut logs = Logs::default();
+        let mut acls = HashMap::new();
+        let strict = AclProfile {
+            id: "strict".to_string(),
+            ..AclProfile::default()
+        };
+        acls.insert("strict".to_string(), strict);
+        let active_acl = AclProfile::default();
+        let active_cf = ContentFilterProfile::default_from_seed("seed");
+        let raw = |acl: Option<&str>, cf: Option<&str>| RawCandidate {
+            acl_profile: acl.map(|s| s.to_string()),
+            content_filter_profile: cf.map(|s| s.to_string()),
+        };
+
+        let candidate = Candidate::resolve(
+            &mut logs,
+            raw(Some("strict"), None),
+            &acls,
+            &HashMap::new(),
+            &active_acl,
+            &active_cf,
+        )
+        .unwrap();
+        assert_eq!(candidate.acl_profile.id, "strict");
+        assert_eq!(candidate.content_filter_profile.id, active_cf.id);
+        assert!(Candidate::resolve(
+            &mut logs,
+            raw(None, Some("missing")),
+            &acls,
+            &HashMap::new(),
+            &active_acl,
+            &active_cf
+        )
+        .is_none());
+    }
+}
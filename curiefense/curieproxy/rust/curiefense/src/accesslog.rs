@@ -0,0 +1,321 @@
+//! Structured access log records.
+//!
+//! The record is a flat, versioned JSON object built from the analysis result. Its fields can be selected per
+//! security policy entry, with the access_log section:
+//!
+//!  * include: fields to keep, all fields when empty,
+//!  * exclude: fields to remove,
+//!  * headers: request headers copied to the record,
+//!  * max_field_size: strings are truncated to this size, in bytes,
+//!  * max_record_size: the bulky fields (headers first) are removed until the record fits, and listed in `truncated`,
+//!  * pass_sample_percent: share of the passed requests that are logged,
+//!  * always_log_tags: passed requests with one of these tags are always logged.
+//!
+//! Final decisions (blocks, challenges) and monitored requests are always logged, sampling only applies to the
+//! requests that were passed without any reason.
+use rand::Rng;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashSet;
+
+use crate::bypass::BYPASS_TAG;
+use crate::config::raw::{RawAccessLog, RawActionType};
+use crate::interface::stats::Stats;
+use crate::interface::{BlockReason, Decision, Tags};
+use crate::utils::RequestInfo;
+
+/// version of the record layout, bumped when fields are renamed or removed
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// all the fields of a record
+pub const FIELDS: [&str; 18] = [
+    "timestamp",
+    "request_id",
+    "session",
+    "ip",
+    "method",
+    "authority",
+    "path",
+    "query",
+    "status",
+    "action",
+    "blocked",
+    "reason",
+    "block_reasons",
+    "tags",
+    "headers",
+    "geo",
+    "timings",
+    "security_config",
+];
+
+/// fields removed, in that order, when a record is too large
+const DROP_ORDER: [&str; 5] = ["headers", "block_reasons", "tags", "geo", "timings"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessLogSettings {
+    pub fields: HashSet<String>,
+    pub headers: Vec<String>,
+    pub max_field_size: usize,
+    pub max_record_size: usize,
+    pub pass_sample_percent: f64,
+    pub always_log_tags: Vec<String>,
+}
+
+impl Default for AccessLogSettings {
+    fn default() -> Self {
+        AccessLogSettings {
+            fields: FIELDS.iter().map(|s| s.to_string()).collect(),
+            headers: ["user-agent", "referer", "content-type"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            max_field_size: 1024,
+            max_record_size: 16384,
+            pass_sample_percent: 100.0,
+            always_log_tags: Vec::new(),
+        }
+    }
+}
+
+impl AccessLogSettings {
+    pub fn resolve(raw: RawAccessLog) -> Self {
+        let default = AccessLogSettings::default();
+        let mut fields: HashSet<String> = if raw.include.is_empty() {
+            default.fields
+        } else {
+            raw.include
+                .into_iter()
+                .filter(|f| FIELDS.contains(&f.as_str()))
+                .collect()
+        };
+        for f in &raw.exclude {
+            fields.remove(f);
+        }
+        AccessLogSettings {
+            fields,
+            headers: raw
+                .headers
+                .map(|hs| hs.into_iter().map(|h| h.to_lowercase()).collect())
+                .unwrap_or(default.headers),
+            max_field_size: raw.max_field_size.unwrap_or(default.max_field_size),
+            max_record_size: raw.max_record_size.unwrap_or(default.max_record_size),
+            pass_sample_percent: raw
+                .pass_sample_percent
+                .map(|p| p.clamp(0.0, 100.0))
+                .unwrap_or(default.pass_sample_percent),
+            always_log_tags: raw.always_log_tags,
+        }
+    }
+
+    /// should the request be logged, `draw` being a random number between 0 and 100
+    fn sampled(&self, decision: &Decision, tags: &Tags, draw: f64) -> bool {
+        decision.is_final()
+            || decision.reasons.iter().any(|r| r.action == RawActionType::Monitor)
+            || self.always_log_tags.iter().any(|t| tags.contains(t))
+            || draw < self.pass_sample_percent
+    }
+}
+
+/// log sampling decision for an analyzed request
+pub fn should_log(decision: &Decision, rinfo: &RequestInfo, tags: &Tags) -> bool {
+    let secpolicy = &rinfo.rinfo.secpolicy;
+    if tags.contains(BYPASS_TAG) && !secpolicy.bypass.as_ref().map(|b| b.log).unwrap_or(false) {
+        return false;
+    }
+    match &secpolicy.access_log {
+        None => true,
+        Some(settings) => settings.sampled(decision, tags, rand::thread_rng().gen_range(0.0..100.0)),
+    }
+}
+
+fn truncate_strings(v: &mut Value, max: usize) {
+    match v {
+        Value::String(s) if s.len() > max => {
+            let mut end = max;
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+            s.truncate(end);
+        }
+        Value::Array(a) => a.iter_mut().for_each(|i| truncate_strings(i, max)),
+        Value::Object(o) => o.values_mut().for_each(|i| truncate_strings(i, max)),
+        _ => (),
+    }
+}
+
+fn record_size(record: &Map<String, Value>) -> usize {
+    serde_json::to_vec(record).map(|v| v.len()).unwrap_or(0)
+}
+
+#[derive(Serialize)]
+struct Geo<'t> {
+    country: &'t Option<String>,
+    region: &'t Option<String>,
+    city: &'t Option<String>,
+    continent: &'t Option<String>,
+    location: &'t Option<(f64, f64)>,
+    asn: &'t Option<u32>,
+    company: &'t Option<String>,
+}
+
+/// builds the access log record of an analyzed request
+pub fn access_log_record(
+    decision: &Decision,
+    rinfo: &RequestInfo,
+    tags: &Tags,
+    stats: &Stats,
+    status: Option<u32>,
+) -> Value {
+    let default_settings = AccessLogSettings::default();
+    let secpol = &rinfo.rinfo.secpolicy;
+    let settings = secpol.access_log.as_ref().unwrap_or(&default_settings);
+    let geoip = &rinfo.rinfo.geoip;
+
+    let mut record = Map::new();
+    let mut add = |name: &str, value: Value| {
+        if settings.fields.contains(name) {
+            record.insert(name.to_string(), value);
+        }
+    };
+    add("timestamp", json!(rinfo.timestamp));
+    add("request_id", json!(rinfo.rinfo.meta.requestid));
+    add("session", json!(rinfo.session));
+    add("ip", json!(geoip.ipstr));
+    add("method", json!(rinfo.rinfo.meta.method));
+    add("authority", json!(rinfo.rinfo.host));
+    add("path", json!(rinfo.rinfo.qinfo.qpath));
+    add("query", json!(rinfo.rinfo.qinfo.query));
+    add(
+        "status",
+        json!(status.or_else(|| decision.maction.as_ref().map(|a| a.status))),
+    );
+    let action = if decision.is_blocking() {
+        "block"
+    } else if decision.reasons.iter().any(|r| r.action == RawActionType::Monitor) {
+        "monitor"
+    } else {
+        "pass"
+    };
+    add("action", json!(action));
+    add("blocked", json!(decision.is_blocking()));
+    add(
+        "reason",
+        json!(BlockReason::block_reason_desc(&decision.reasons).filter(|_| decision.is_final())),
+    );
+    add("block_reasons", json!(decision.reasons));
+    let mut tag_names: Vec<&String> = tags.inner().keys().collect();
+    tag_names.sort();
+    add("tags", json!(tag_names));
+    let headers: Map<String, Value> = settings
+        .headers
+        .iter()
+        .filter_map(|h| rinfo.headers.get(h).map(|v| (h.clone(), json!(v))))
+        .collect();
+    add("headers", Value::Object(headers));
+    add(
+        "geo",
+        json!(Geo {
+            country: &geoip.country_iso,
+            region: &geoip.region,
+            city: &geoip.city_name,
+            continent: &geoip.continent_code,
+            location: &geoip.location,
+            asn: &geoip.asn,
+            company: &geoip.company,
+        }),
+    );
+    add("timings", json!(stats.timing));
+    add(
+        "security_config",
+        json!({
+            "revision": stats.revision,
+            "config_hash": stats.config_hash,
+            "secpolid": secpol.policy.id,
+            "secpolentryid": secpol.entry.id,
+        }),
+    );
+
+    for v in record.values_mut() {
+        truncate_strings(v, settings.max_field_size);
+    }
+    record.insert("schema_version".to_string(), json!(SCHEMA_VERSION));
+    let mut dropped = Vec::new();
+    for field in DROP_ORDER.iter() {
+        if record_size(&record) <= settings.max_record_size {
+            break;
+        }
+        if record.remove(*field).is_some() {
+            dropped.push(*field);
+        }
+    }
+    if !dropped.is_empty() {
+        record.insert("truncated".to_string(), json!(dropped));
+    }
+    Value::Object(record)
+}
+
+/// the access log record, serialized
+pub fn access_log_json(
+    decision: &Decision,
+    rinfo: &RequestInfo,
+    tags: &Tags,
+    stats: &Stats,
+    status: Option<u32>,
+) -> Vec<u8> {
+    serde_json::to_vec(&access_log_record(decision, rinfo, tags, stats, status)).unwrap_or_else(|_| b"{}".to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn field_selection() {
+        let settings = AccessLogSettings::resolve(RawAccessLog {
+            include: vec![
+                "ip".to_string(),
+                "path".to_string(),
+                "headers".to_string(),
+                "bogus".to_string(),
+            ],
+            exclude: vec!["path".to_string()],
+            headers: Some(vec!["X-Api-Key".to_string()]),
+            max_field_size: None,
+            max_record_size: Some(512),
+            ..RawAccessLog::default()
+        });
+        let expected: HashSet<String> = ["ip", "headers"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(settings.fields, expected);
+        assert_eq!(settings.headers, vec!["x-api-key".to_string()]);
+        assert_eq!(settings.max_field_size, 1024);
+        assert_eq!(settings.max_record_size, 512);
+    }
+
+    #[test]
+    fn sampling() {
+        use crate::config::virtualtags::VirtualTags;
+        use crate::interface::{Action, Location};
+
+        let settings = AccessLogSettings::resolve(RawAccessLog {
+            pass_sample_percent: Some(10.0),
+            always_log_tags: vec!["suspicious".to_string()],
+            ..RawAccessLog::default()
+        });
+        let mut tags = Tags::new(&VirtualTags::default());
+        let pass = Decision::pass(Vec::new());
+        assert!(settings.sampled(&pass, &tags, 5.0));
+        assert!(!settings.sampled(&pass, &tags, 50.0));
+        let block = Decision::action(Action::default(), Vec::new());
+        assert!(settings.sampled(&block, &tags, 50.0));
+        tags.insert("suspicious", Location::Request);
+        assert!(settings.sampled(&pass, &tags, 50.0));
+    }
+
+    #[test]
+    fn truncation() {
+        let mut v = json!({"a": "ééé", "b": ["abcdef"]});
+        truncate_strings(&mut v, 3);
+        assert_eq!(v, json!({"a": "é", "b": ["abc"]}));
+    }
+}
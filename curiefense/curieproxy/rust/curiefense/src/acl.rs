@@ -1,4 +1,5 @@
 use crate::config::raw::AclProfile;
+use crate::config::tagexpr::TagExpression;
 use crate::interface::{AclStage, Tags};
 
 use std::collections::HashSet;
@@ -51,20 +52,26 @@ impl std::fmt::Display for AclResult {
 }
 
 pub fn check_acl(tags: &Tags, acl: &AclProfile) -> AclResult {
-    let subcheck = |checks: &HashSet<String>, allowed: bool| {
-        let tags = tags.intersect_tags(checks);
-        if tags.is_empty() {
+    let exprs = &acl.expressions;
+    let subcheck = |checks: &HashSet<String>, exprs: &[TagExpression], allowed: bool| {
+        let mut matched = tags.intersect_tags(checks);
+        for m in exprs.iter().filter_map(|e| e.matching(tags)) {
+            matched.merge(m);
+        }
+        if matched.is_empty() {
             None
         } else {
-            Some((allowed, tags))
+            Some((allowed, matched))
         }
     };
-    subcheck(&acl.force_deny, false)
+    subcheck(&acl.force_deny, &exprs.force_deny, false)
         .map(AclResult::Passthrough)
-        .or_else(|| subcheck(&acl.passthrough, true).map(AclResult::Passthrough))
+        .or_else(|| subcheck(&acl.passthrough, &exprs.passthrough, true).map(AclResult::Passthrough))
         .unwrap_or_else(|| {
-            let botresult = subcheck(&acl.allow_bot, true).or_else(|| subcheck(&acl.deny_bot, false));
-            let humanresult = subcheck(&acl.allow, true).or_else(|| subcheck(&acl.deny, false));
+            let botresult = subcheck(&acl.allow_bot, &exprs.allow_bot, true)
+                .or_else(|| subcheck(&acl.deny_bot, &exprs.deny_bot, false));
+            let humanresult =
+                subcheck(&acl.allow, &exprs.allow, true).or_else(|| subcheck(&acl.deny, &exprs.deny, false));
 
             AclResult::Match {
                 bot: botresult,
@@ -1,27 +1,75 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
+use crate::abtest::compare_candidate;
 use crate::acl::check_acl;
-use crate::config::contentfilter::ContentFilterRules;
+use crate::ban::record_ban;
+use crate::botscore::{bot_score, BotAction};
+use crate::cadence::{apply_cadence, query_cadence};
+use crate::canary::canary_check;
+use crate::captcha::analyze_captcha;
+use crate::challenge::ChallengeProvider;
+use crate::checkout::{apply_checkout, query_checkout};
+use crate::clearance::clearance_decision;
+use crate::clientstate::StateQuery;
+use crate::config::contentfilter::{ContentFilterMode, ContentFilterRules};
+use crate::config::expiry::EXPIRING_TAG;
 use crate::config::flow::FlowMap;
+use crate::config::globalfilter::GlobalFilterSection;
+use crate::config::responsefilter::ResponseFilterProfile;
 use crate::config::CONFIGS;
-use crate::contentfilter::{content_filter_check, masking};
-use crate::flow::{flow_build_query, flow_info, flow_process, flow_resolve_query, FlowCheck, FlowResult};
+use crate::contentfilter::schema::schema_check;
+use crate::contentfilter::{content_filter_check, learning, masking, record_shadow};
+use crate::counters::{counter_backend, counter_backend_name, fallback_backend, CounterBackend};
+use crate::csrf::{check_csrf, csrf_response_headers};
+use crate::dataleak::data_leak_check;
+use crate::decisioncache::CacheLookup;
+use crate::failure::{dependency_failed, failed_dependencies, failure_decision, Dependency};
+use crate::flow::{flow_info, flow_process, FlowCheck, FlowResult};
 use crate::grasshopper::{
-    challenge_phase01, challenge_phase02, check_app_sig, handle_bio_reports, GHMode, Grasshopper, PrecisionLevel,
+    challenge_phase01, challenge_phase02, check_app_sig, handle_bio_reports, DummyGrasshopper, GHMode, PrecisionLevel,
 };
+use crate::honeypot::{apply_honeypot, query_honeypot};
+use crate::hooks::Hooks;
 use crate::interface::stats::{BStageMapped, StatsCollect};
 use crate::interface::{
-    merge_decisions, AclStage, AnalyzeResult, BStageFlow, BlockReason, Decision, Location, SimpleDecision, Tags,
+    merge_decisions, AclStage, AnalyzeResult, BStageFlow, BlockReason, Decision, Location, SimpleAction,
+    SimpleDecision, Tags,
 };
-use crate::limit::{limit_build_query, limit_info, limit_process, limit_resolve_query, LimitCheck, LimitResult};
+use crate::limit::{inflight_leases, limit_info, limit_process, LimitCheck, LimitResult};
+use crate::login::{apply_login, query_login};
 use crate::logs::Logs;
-use crate::redis::redis_async_conn;
-use crate::utils::{eat_errors, BodyDecodingResult, BodyProblem, RequestInfo};
+use crate::offender::{apply_offender, query_offender, record_offender};
+use crate::otel::Span;
+use crate::protocol::check_protocol;
+use crate::replay::analyze_replay;
+use crate::responsefilter::response_filter_check;
+use crate::scraping::{apply_scraping, query_scraping};
+use crate::session::{analyze_session, record_session};
+use crate::topn::record_topn;
+use crate::utils::{eat_errors, now_ms, BodyDecodingResult, BodyProblem, RequestInfo};
+use crate::webhook::notify_webhooks;
 
 /*
 
   Scanning advances using the following steps:
 
+  APhase0
+    |
+    | analyze_client_state
+    |   honeypot
+    |   offender
+    |   cadence
+    |   login
+    |   checkout
+    |   scraping
+    |   analyze_replay
+    |   analyze_session
+    |   analyze_captcha
+    v
+  APhase0
+    |
+    | analyze_init
+    v
   APhase1
     |
     | analyze_query_flow
@@ -39,6 +87,18 @@ use crate::utils::{eat_errors, BodyDecodingResult, BodyProblem, RequestInfo};
     | analyse_finish
     v
   Done
+    |
+    | record_client_state
+    v
+  Done
+
+  Responses are inspected separately, once the upstream server replied:
+
+  APhaseResp
+    |
+    | analyze_response
+    v
+  Done
 */
 
 pub enum CfRulesArg<'t> {
@@ -49,10 +109,15 @@ pub enum CfRulesArg<'t> {
 pub struct APhase0 {
     pub flows: FlowMap,
     pub globalfilter_dec: SimpleDecision,
+    /// global filters that depend on the session state
+    pub session_filters: Vec<GlobalFilterSection>,
+    pub hooks: Hooks,
     pub precision_level: PrecisionLevel,
     pub itags: Tags,
     pub reqinfo: RequestInfo,
     pub stats: StatsCollect<BStageMapped>,
+    /// set by analyze_client_state, when the decision cache is enabled
+    pub cache: Option<CacheLookup>,
 }
 
 #[derive(Clone)]
@@ -62,6 +127,8 @@ pub struct AnalysisInfo {
     reqinfo: RequestInfo,
     stats: StatsCollect<BStageMapped>,
     tags: Tags,
+    hooks: Hooks,
+    cache: Option<CacheLookup>,
 }
 
 #[derive(Clone)]
@@ -91,17 +158,122 @@ pub enum InitResult {
     Phase1(APhase1),
 }
 
-#[allow(clippy::too_many_arguments)]
-pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APhase0) -> InitResult {
+fn simple_reasons(decision: &SimpleDecision) -> &[BlockReason] {
+    match decision {
+        SimpleDecision::Pass => &[],
+        SimpleDecision::Action(_, reasons) => reasons,
+    }
+}
+
+/// the stages that load and update the client state kept in redis, and the CAPTCHA verification
+///
+/// they run for every request, even when the decision is served from the cache
+pub async fn analyze_client_state(logs: &mut Logs, mut p0: APhase0) -> APhase0 {
+    let mut cache = p0
+        .reqinfo
+        .rinfo
+        .secpolicy
+        .decision_cache
+        .clone()
+        .map(|cache| CacheLookup::new(cache, &p0.reqinfo));
+    let previous_reasons = simple_reasons(&p0.globalfilter_dec).to_vec();
+
+    let span = Span::start(p0.reqinfo.trace.as_ref(), "curiefense.analyze_session");
+    // the stages share a single redis round trip
+    let mut query = StateQuery::default();
+    let honeypot = query_honeypot(&mut p0, &mut query);
+    let offender = query_offender(&p0, &mut query);
+    let cadence = query_cadence(&p0, &mut query);
+    let login = query_login(&mut p0, &mut query);
+    let checkout = query_checkout(&mut p0, &mut query);
+    let scraping = query_scraping(&p0, &mut query);
+    let replies = query.run().await;
+    if let Some(q) = honeypot {
+        apply_honeypot(logs, &mut p0, q, &replies);
+    }
+    if let Some(q) = offender {
+        apply_offender(logs, &mut p0, q, &replies);
+    }
+    if let Some(q) = cadence {
+        apply_cadence(logs, &mut p0, q, &replies);
+    }
+    if let Some(q) = login {
+        apply_login(logs, &mut p0, q, &replies);
+    }
+    if let Some(q) = checkout {
+        apply_checkout(logs, &mut p0, q, &replies);
+    }
+    if let Some(q) = scraping {
+        apply_scraping(logs, &mut p0, q, &replies);
+    }
+    let p0 = analyze_replay(logs, p0).await;
+    let p0 = analyze_session(logs, p0).await;
+    let mut p0 = analyze_captcha(logs, p0).await;
+    span.end();
+
+    if let Some(lookup) = cache.as_mut() {
+        let stage_reasons = simple_reasons(&p0.globalfilter_dec)
+            .iter()
+            .filter(|r| !previous_reasons.contains(r))
+            .cloned()
+            .collect();
+        if lookup.lookup(stage_reasons) {
+            logs.debug("decision cache hit");
+        }
+    }
+    p0.cache = cache;
+    p0
+}
+
+pub fn analyze_client_state_block(logs: &mut Logs, p0: APhase0) -> APhase0 {
+    async_std::task::block_on(analyze_client_state(logs, p0))
+}
+
+/// updates the client state with the outcome of the analysis
+pub async fn record_client_state(logs: &mut Logs, result: &AnalyzeResult) {
+    record_session(logs, result).await;
+    record_offender(logs, result).await;
+    record_ban(logs, result).await;
+}
+
+pub fn record_client_state_block(logs: &mut Logs, result: &AnalyzeResult) {
+    async_std::task::block_on(record_client_state(logs, result))
+}
+
+fn cache_decision(logs: &mut Logs, cache: Option<&CacheLookup>, result: &AnalyzeResult) {
+    if cache.map(|c| c.insert(&result.decision, &result.tags)).unwrap_or(false) {
+        logs.debug("decision cached");
+    }
+}
+
+pub fn analyze_init<GH: ChallengeProvider>(logs: &mut Logs, mgh: Option<&GH>, mut p0: APhase0) -> InitResult {
+    let cache = p0.cache.take();
+    match init_checks(logs, mgh, p0) {
+        InitResult::Res(result) => {
+            cache_decision(logs, cache.as_ref(), &result);
+            InitResult::Res(result)
+        }
+        InitResult::Phase1(mut p1) => {
+            p1.info.cache = cache;
+            InitResult::Phase1(p1)
+        }
+    }
+}
+
+fn init_checks<GH: ChallengeProvider>(logs: &mut Logs, mgh: Option<&GH>, p0: APhase0) -> InitResult {
     let stats = p0.stats;
     let mut tags = p0.itags;
     let reqinfo = p0.reqinfo;
     let securitypolicy = &reqinfo.rinfo.secpolicy;
     let precision_level = p0.precision_level;
     let globalfilter_dec = p0.globalfilter_dec;
+    let hooks = p0.hooks;
 
     tags.insert_qualified("securitypolicy", &securitypolicy.policy.name, Location::Request);
     tags.insert_qualified("securitypolicy-entry", &securitypolicy.entry.name, Location::Request);
+    if let Some(methods) = &securitypolicy.methods {
+        tags.insert_qualified("securitypolicy-entry-methods", &methods.join("-"), Location::Request);
+    }
     tags.insert_qualified("aclid", &securitypolicy.acl_profile.id, Location::Request);
     tags.insert_qualified("aclname", &securitypolicy.acl_profile.name, Location::Request);
     tags.insert_qualified(
@@ -115,6 +287,20 @@ pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APha
         Location::Request,
     );
 
+    // a decision that is not final, such as monitor, is kept along with the decisions of the next stages
+    let mut cumulated_decision = Decision::pass(Vec::new());
+    if let Some(decision) = hooks.on_init(logs, &reqinfo, &mut tags) {
+        cumulated_decision = merge_decisions(cumulated_decision, decision);
+        if cumulated_decision.is_final() {
+            return InitResult::Res(AnalyzeResult {
+                decision: cumulated_decision,
+                tags,
+                rinfo: masking(reqinfo),
+                stats: stats.mapped_stage_build(),
+            });
+        }
+    }
+
     //if /c365 then call gh phase01 with mode passive
     if reqinfo.rinfo.qinfo.uri.starts_with("/c3650cdf") {
         if let Some(gh) = mgh {
@@ -148,6 +334,20 @@ pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APha
                     securitypolicy.content_filter_profile.action.atype.to_raw(),
                     securitypolicy.content_filter_profile.max_body_depth,
                 ),
+                BodyProblem::ForbiddenUpload(reason, actual, expected) => BlockReason::body_upload(
+                    securitypolicy.content_filter_profile.id.clone(),
+                    securitypolicy.content_filter_profile.name.clone(),
+                    securitypolicy.content_filter_profile.action.atype.to_raw(),
+                    reason,
+                    actual.clone(),
+                    expected.clone(),
+                ),
+                BodyProblem::TooComplex(max) => BlockReason::body_too_complex(
+                    securitypolicy.content_filter_profile.id.clone(),
+                    securitypolicy.content_filter_profile.name.clone(),
+                    securitypolicy.content_filter_profile.action.atype.to_raw(),
+                    *max,
+                ),
             };
             // we expect the body to be properly decoded
             let decision = securitypolicy.content_filter_profile.action.to_decision(
@@ -163,7 +363,7 @@ pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APha
                 tags.insert(t, Location::Body);
             }
             return InitResult::Res(AnalyzeResult {
-                decision,
+                decision: merge_decisions(cumulated_decision, decision),
                 tags,
                 rinfo: masking(reqinfo),
                 stats: stats.mapped_stage_build(),
@@ -228,6 +428,7 @@ pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APha
     let decision = if let SimpleDecision::Action(action, reason) = globalfilter_dec {
         logs.debug(|| format!("Global filter decision {:?}", reason));
         let decision = action.to_decision(logs, precision_level, mgh, &reqinfo, &mut tags, reason);
+        let decision = merge_decisions(cumulated_decision, decision);
         if decision.is_final() {
             return InitResult::Res(AnalyzeResult {
                 decision,
@@ -240,7 +441,7 @@ pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APha
         // (this is because we passed it to action.to_decision)
         decision
     } else {
-        Decision::pass(Vec::new())
+        cumulated_decision
     };
 
     let flow_checks = flow_info(logs, &p0.flows, &reqinfo, &tags);
@@ -250,6 +451,8 @@ pub fn analyze_init<GH: Grasshopper>(logs: &mut Logs, mgh: Option<&GH>, p0: APha
         reqinfo,
         stats,
         tags,
+        hooks,
+        cache: None,
     };
     InitResult::Phase1(APhase1::new(flow_checks, (), info))
 }
@@ -272,10 +475,51 @@ impl APhase2O {
 
 impl APhase3 {
     pub fn from_phase2(p2: APhase2I, limit_results: Vec<LimitResult>) -> Self {
+        let mut info = p2.info;
+        info.reqinfo.inflight.extend(inflight_leases(&p2.limits));
         Self {
             flows: p2.flows,
             limits: limit_results,
-            info: p2.info,
+            info,
+        }
+    }
+}
+
+/// switches to the local store, when the configured counter backend is not available
+fn degraded_backend(logs: &mut Logs, tags: &mut Tags, rr: anyhow::Error) -> Box<dyn CounterBackend> {
+    logs.error(|| format!("counter backend: {}", rr));
+    logs.warning("using the local store for flow and limit checks");
+    tags.insert("limit-degraded", Location::Request);
+    dependency_failed(tags, Dependency::Redis);
+    fallback_backend()
+}
+
+async fn query_flows(logs: &mut Logs, tags: &mut Tags, checks: Vec<FlowCheck>) -> Vec<FlowResult> {
+    let res = match counter_backend().await {
+        Ok(mut backend) => backend.resolve_flows(logs, checks.clone()).await,
+        Err(rr) => Err(rr),
+    };
+    match res {
+        Ok(r) => r,
+        Err(rr) => {
+            let mut backend = degraded_backend(logs, tags, rr);
+            let res = backend.resolve_flows(logs, checks).await;
+            eat_errors(logs, res)
+        }
+    }
+}
+
+async fn query_limits(logs: &mut Logs, tags: &mut Tags, checks: Vec<LimitCheck>) -> Vec<LimitResult> {
+    let res = match counter_backend().await {
+        Ok(mut backend) => backend.resolve_limits(logs, checks.clone()).await,
+        Err(rr) => Err(rr),
+    };
+    match res {
+        Ok(r) => r,
+        Err(rr) => {
+            let mut backend = degraded_backend(logs, tags, rr);
+            let res = backend.resolve_limits(logs, checks).await;
+            eat_errors(logs, res)
         }
     }
 }
@@ -287,31 +531,12 @@ pub async fn analyze_query_flows<'t>(logs: &mut Logs, p1: APhase1) -> APhase2O {
         info,
     };
 
-    let info = p1.info;
+    let mut info = p1.info;
     if p1.flows.is_empty() {
         return empty(info);
     }
 
-    let mut redis = match redis_async_conn().await {
-        Ok(c) => c,
-        Err(rr) => {
-            logs.error(|| format!("Could not connect to the redis server {}", rr));
-            return empty(info);
-        }
-    };
-
-    let mut pipe = redis::pipe();
-    flow_build_query(&mut pipe, &p1.flows);
-    let res: Result<Vec<Option<i64>>, _> = pipe.query_async(&mut redis).await;
-    let mut lst = match res {
-        Ok(l) => l.into_iter(),
-        Err(rr) => {
-            logs.error(|| format!("{}", rr));
-            return empty(info);
-        }
-    };
-
-    let flow_results = eat_errors(logs, flow_resolve_query(&mut redis, &mut lst, p1.flows).await);
+    let flow_results = query_flows(logs, &mut info.tags, p1.flows).await;
     logs.debug("query - flow checks done");
 
     AnalysisPhase {
@@ -341,32 +566,13 @@ pub async fn analyze_query_limits<'t>(logs: &mut Logs, p2: APhase2I) -> APhase3
 
     let flows = p2.flows;
 
-    let info = p2.info;
+    let mut info = p2.info;
     if p2.limits.is_empty() {
         return empty(info, flows);
     }
 
-    let mut redis = match redis_async_conn().await {
-        Ok(c) => c,
-        Err(rr) => {
-            logs.error(|| format!("Could not connect to the redis server {}", rr));
-            return empty(info, flows);
-        }
-    };
-
-    let mut pipe = redis::pipe();
-    limit_build_query(&mut pipe, &p2.limits);
-    let res: Result<Vec<Option<i64>>, _> = pipe.query_async(&mut redis).await;
-    let mut lst = match res {
-        Ok(l) => l.into_iter(),
-        Err(rr) => {
-            logs.error(|| format!("{}", rr));
-            return empty(info, flows);
-        }
-    };
-
-    let limit_results_err = limit_resolve_query(logs, &mut redis, &mut lst, p2.limits).await;
-    let limit_results = eat_errors(logs, limit_results_err);
+    info.reqinfo.inflight.extend(inflight_leases(&p2.limits));
+    let limit_results = query_limits(logs, &mut info.tags, p2.limits).await;
     logs.debug("query - limit checks done");
 
     AnalysisPhase {
@@ -376,7 +582,41 @@ pub async fn analyze_query_limits<'t>(logs: &mut Logs, p2: APhase2I) -> APhase3
     }
 }
 
-pub fn analyze_finish<GH: Grasshopper>(
+pub fn analyze_finish<GH: ChallengeProvider>(
+    logs: &mut Logs,
+    mgh: Option<&GH>,
+    cfrules: CfRulesArg<'_>,
+    mut p3: APhase3,
+) -> AnalyzeResult {
+    let cache = p3.info.cache.take();
+    let result = match cache.as_ref().and_then(|c| c.hit.clone()) {
+        // flows and limits were counted, only the remaining checks are skipped
+        Some(hit) => {
+            let (decision, cached_tags) = *hit;
+            let info = p3.info;
+            let mut tags = info.tags;
+            tags.extend(cached_tags);
+            tags.insert("decision-cache-hit", Location::Request);
+            AnalyzeResult {
+                decision: merge_decisions(decision, info.p0_decision),
+                tags,
+                rinfo: masking(info.reqinfo),
+                stats: info.stats.mapped_stage_build(),
+            }
+        }
+        None => {
+            let result = finish_checks(logs, mgh, cfrules, p3);
+            cache_decision(logs, cache.as_ref(), &result);
+            result
+        }
+    };
+    record_topn(&result);
+    notify_webhooks(&result);
+    result
+}
+
+/// limits, ACL and content filter checks
+fn finish_checks<GH: ChallengeProvider>(
     logs: &mut Logs,
     mgh: Option<&GH>,
     cfrules: CfRulesArg<'_>,
@@ -391,6 +631,9 @@ pub fn analyze_finish<GH: Grasshopper>(
     let reqinfo = info.reqinfo;
     let secpol = &reqinfo.rinfo.secpolicy;
 
+    canary_check(logs, &reqinfo, &mut tags, precision_level);
+    compare_candidate(logs, &reqinfo, &mut tags, precision_level);
+
     let (limit_check, stats) = limit_process(p3.flows, 0, &p3.limits, &mut tags);
 
     if let SimpleDecision::Action(action, curbrs) = limit_check {
@@ -407,13 +650,119 @@ pub fn analyze_finish<GH: Grasshopper>(
     }
     logs.debug("limit checks done");
 
-    let acl_result = check_acl(&tags, &secpol.acl_profile);
+    // redis and the challenge provider failures are known at this point
+    let failed = failed_dependencies(&tags);
+    if let Some(decision) = failure_decision(secpol, &failed) {
+        logs.warning(|| format!("failed dependencies: {:?}", failed));
+        cumulated_decision = merge_decisions(cumulated_decision, decision);
+        if cumulated_decision.is_final() {
+            return AnalyzeResult {
+                decision: cumulated_decision,
+                tags,
+                rinfo: masking(reqinfo),
+                stats: stats.limit_stage_build(),
+            };
+        }
+    }
+
+    if let Some(settings) = &secpol.protocol {
+        let action = SimpleAction::default();
+        let mut reasons = Vec::new();
+        for anomaly in check_protocol(settings, &reqinfo) {
+            logs.debug(|| format!("protocol anomaly {}: {}", anomaly.check, anomaly.actual));
+            tags.insert_qualified("protocol-anomaly", anomaly.check, anomaly.location.clone());
+            let mut reason = BlockReason::protocol(
+                secpol.entry.id.clone(),
+                secpol.entry.name.clone(),
+                action.atype.to_raw(),
+                anomaly,
+            );
+            if !settings.enforce {
+                reason.action.inactive();
+            }
+            reasons.push(reason);
+        }
+        if settings.enforce && !reasons.is_empty() {
+            let decision = action.to_decision(logs, precision_level, mgh, &reqinfo, &mut tags, reasons);
+            cumulated_decision = merge_decisions(cumulated_decision, decision);
+            return AnalyzeResult {
+                decision: cumulated_decision,
+                tags,
+                rinfo: masking(reqinfo),
+                stats: stats.limit_stage_build(),
+            };
+        }
+        if !reasons.is_empty() {
+            cumulated_decision = merge_decisions(cumulated_decision, Decision::pass(reasons));
+        }
+    }
+
+    if let Some(settings) = &secpol.bot_score {
+        let bs = bot_score(settings, precision_level, &reqinfo, &tags, now_ms());
+        logs.debug(|| format!("bot score {} ({:?})", bs.score, bs.signals));
+        tags.insert_qualified("botscore", &bs.score.to_string(), Location::Request);
+        for signal in &bs.signals {
+            tags.insert_qualified("bot-signal", signal, Location::Request);
+        }
+        if let Some((bot_action, threshold)) = settings.action(bs.score) {
+            let action = SimpleAction::default();
+            let mut reason = BlockReason::bot_score(
+                secpol.entry.id.clone(),
+                secpol.entry.name.clone(),
+                action.atype.to_raw(),
+                bs.score,
+                threshold,
+            );
+            let decision = match (bot_action, mgh) {
+                (BotAction::Monitor, _) => {
+                    reason.action.inactive();
+                    Decision::pass(vec![reason])
+                }
+                (BotAction::Challenge, Some(gh)) => {
+                    logs.debug("Call challenge phase01 with mode: Active (bot score)");
+                    challenge_phase01(gh, logs, &reqinfo, vec![reason], GHMode::Active)
+                }
+                (BotAction::Challenge, None) | (BotAction::Block, _) => {
+                    action.to_decision(logs, precision_level, mgh, &reqinfo, &mut tags, vec![reason])
+                }
+            };
+            cumulated_decision = merge_decisions(cumulated_decision, decision);
+            if bot_action != BotAction::Monitor {
+                return AnalyzeResult {
+                    decision: cumulated_decision,
+                    tags,
+                    rinfo: masking(reqinfo),
+                    stats: stats.limit_stage_build(),
+                };
+            }
+        }
+    }
+
+    if let Some(decision) = info.hooks.on_pre_acl(logs, &reqinfo, &mut tags) {
+        cumulated_decision = merge_decisions(cumulated_decision, decision);
+        if cumulated_decision.is_final() {
+            return AnalyzeResult {
+                decision: cumulated_decision,
+                tags,
+                rinfo: masking(reqinfo),
+                stats: stats.limit_stage_build(),
+            };
+        }
+    }
+
+    // entries past their expiry date are ignored
+    let acl_profile = secpol.acl_profile.active_at(reqinfo.timestamp);
+    let acl_result = check_acl(&tags, &acl_profile);
     logs.debug(|| format!("ACL result: {}", acl_result));
 
     let acl_decision = acl_result.decision(precision_level.is_human());
     let stats = stats.acl(usize::from(acl_decision.is_some()));
     if let Some(decision) = acl_decision {
         let bypass = decision.stage == AclStage::Bypass;
+        if acl_profile.expires_soon(decision.tags.inner().keys(), reqinfo.timestamp) {
+            tags.insert(EXPIRING_TAG, Location::Request);
+            tags.insert_qualified(EXPIRING_TAG, &acl_profile.id, Location::Request);
+        }
         let mut br = BlockReason::acl(
             reqinfo.rinfo.secpolicy.acl_profile.id.clone(),
             reqinfo.rinfo.secpolicy.acl_profile.name.clone(),
@@ -460,7 +809,14 @@ pub fn analyze_finish<GH: Grasshopper>(
 
         // Send challenge, even if the acl is inactive in sec_pol.
         if decision.challenge {
-            let decision = if let Some(gh) = mgh {
+            let captcha = secpol
+                .captcha
+                .as_ref()
+                .filter(|c| c.serves(precision_level, mgh.is_some()));
+            let decision = if let Some(captcha) = captcha {
+                logs.debug("Serve a CAPTCHA (acl)");
+                captcha.decision(Vec::new())
+            } else if let Some(gh) = mgh {
                 logs.debug("Call challenge phase01 with mode: Active (acl)");
                 challenge_phase01(gh, logs, &reqinfo, Vec::new(), GHMode::Active)
             } else {
@@ -489,23 +845,97 @@ pub fn analyze_finish<GH: Grasshopper>(
         }
     };
 
-    let mut cfcheck =
-        |stats, mrls| content_filter_check(logs, stats, &mut tags, &reqinfo, &secpol.content_filter_profile, mrls);
+    if let Some(settings) = &secpol.csrf {
+        if let Err(problem) = check_csrf(settings, &reqinfo) {
+            logs.debug(|| format!("CSRF check failed: {}", problem));
+            tags.insert("csrf-failed", Location::Header(settings.header.clone()));
+            let action = SimpleAction::default();
+            let mut reason = BlockReason::csrf(
+                secpol.entry.id.clone(),
+                secpol.entry.name.clone(),
+                action.atype.to_raw(),
+                settings.header.clone(),
+                problem,
+            );
+            if settings.enforce {
+                let decision = action.to_decision(logs, precision_level, mgh, &reqinfo, &mut tags, vec![reason]);
+                cumulated_decision = merge_decisions(cumulated_decision, decision);
+                return AnalyzeResult {
+                    decision: cumulated_decision,
+                    tags,
+                    rinfo: masking(reqinfo),
+                    stats: stats.acl_stage_build(),
+                };
+            }
+            reason.action.inactive();
+            cumulated_decision = merge_decisions(cumulated_decision, Decision::pass(vec![reason]));
+        }
+    }
+
+    if let Some(decision) = info.hooks.on_pre_contentfilter(logs, &reqinfo, &mut tags) {
+        cumulated_decision = merge_decisions(cumulated_decision, decision);
+        if cumulated_decision.is_final() {
+            return AnalyzeResult {
+                decision: cumulated_decision,
+                tags,
+                rinfo: masking(reqinfo),
+                stats: stats.acl_stage_build(),
+            };
+        }
+    }
+
+    // the content filter scratch data is charged on what is left of the request memory budget
+    let mut memory = reqinfo.memory;
+    let mut cfcheck = |stats, mrls| {
+        memory.track(|| content_filter_check(logs, stats, &mut tags, &reqinfo, &secpol.content_filter_profile, mrls))
+    };
     // otherwise, run content_filter_check
     let (content_filter_result, stats) = match cfrules {
-        CfRulesArg::Global => match CONFIGS.hsdb.read() {
-            Ok(rd) => cfcheck(stats, rd.get(&secpol.content_filter_profile.id)),
-            Err(rr) => {
-                logs.error(|| format!("Could not get lock on HSDB: {}", rr));
-                (Ok(()), stats.no_content_filter())
-            }
-        },
+        CfRulesArg::Global => {
+            let cfg = CONFIGS.config.load();
+            cfcheck(stats, cfg.hsdb.get(&secpol.content_filter_profile.id))
+        }
         CfRulesArg::Get(r) => cfcheck(stats, r),
     };
     logs.debug("Content Filter checks done");
 
+    let content_filter_result = match (
+        content_filter_result,
+        schema_check(&secpol.content_filter_profile, &reqinfo, &mut tags),
+    ) {
+        (r, Ok(())) => r,
+        (Ok(()), Err(schema_block)) => Err(schema_block),
+        (Err(mut cfblock), Err(schema_block)) => {
+            cfblock.blocking |= schema_block.blocking;
+            cfblock.reasons.extend(schema_block.reasons);
+            Err(cfblock)
+        }
+    };
+    let cfmode = secpol.content_filter_profile.mode;
+    // requests that would be blocked are not learned, so that attacks do not end up in the model
+    let learnable = match &content_filter_result {
+        Ok(()) => true,
+        Err(cfblock) => !cfblock.blocking,
+    };
+    if cfmode == ContentFilterMode::Learning && learnable {
+        learning::observe(&secpol.content_filter_profile.id, &reqinfo);
+    }
     let content_filter_decision = match content_filter_result {
         Ok(()) => Decision::pass(Vec::new()),
+        Err(cfblock) if cfmode.is_passive() => {
+            record_shadow(logs, &secpol.content_filter_profile, &reqinfo, &cfblock);
+            tags.insert("cf-shadow", Location::Request);
+            Decision::pass(
+                cfblock
+                    .reasons
+                    .into_iter()
+                    .map(|mut reason| {
+                        reason.action.inactive();
+                        reason
+                    })
+                    .collect(),
+            )
+        }
         Err(cfblock) => {
             // insert extra tags
             if !secpol.content_filter_profile.tags.is_empty() {
@@ -549,6 +979,22 @@ pub fn analyze_finish<GH: Grasshopper>(
     };
 
     cumulated_decision = merge_decisions(cumulated_decision, content_filter_decision);
+    let cf_failed: Vec<Dependency> = failed_dependencies(&tags)
+        .into_iter()
+        .filter(|d| !failed.contains(d))
+        .collect();
+    if let Some(decision) = failure_decision(secpol, &cf_failed) {
+        logs.warning(|| format!("failed dependencies: {:?}", cf_failed));
+        cumulated_decision = merge_decisions(cumulated_decision, decision);
+    }
+    if let Some(decision) = info.hooks.on_finish(logs, &reqinfo, &mut tags, &cumulated_decision) {
+        cumulated_decision = merge_decisions(cumulated_decision, decision);
+    }
+    if let Some(cookie) = &reqinfo.clearance {
+        if !cumulated_decision.is_final() {
+            cumulated_decision = merge_decisions(cumulated_decision, clearance_decision(cookie.clone()));
+        }
+    }
     AnalyzeResult {
         decision: cumulated_decision,
         tags,
@@ -558,20 +1004,193 @@ pub fn analyze_finish<GH: Grasshopper>(
 }
 
 #[allow(clippy::too_many_arguments)]
-pub async fn analyze<GH: Grasshopper>(
+pub async fn analyze<GH: ChallengeProvider>(
     logs: &mut Logs,
     mgh: Option<&GH>,
     p0: APhase0,
     cfrules: CfRulesArg<'_>,
 ) -> AnalyzeResult {
+    let ctx = p0.reqinfo.trace.clone();
+    let p0 = analyze_client_state(logs, p0).await;
+    let span = Span::start(ctx.as_ref(), "curiefense.analyze_init");
     let init_result = analyze_init(logs, mgh, p0);
-    match init_result {
+    span.end();
+    let result = match init_result {
         InitResult::Res(result) => result,
         InitResult::Phase1(p1) => {
+            let mut span = Span::start(ctx.as_ref(), "curiefense.analyze_query_flows");
+            span.set_attribute("db.system", counter_backend_name().into());
+            span.set_attribute("curiefense.checks", p1.flows.len().into());
             let p2i = analyze_query_flows(logs, p1).await;
+            span.end();
             let p2o = analyze_flows(logs, p2i);
+            let mut span = Span::start(ctx.as_ref(), "curiefense.analyze_query_limits");
+            span.set_attribute("db.system", counter_backend_name().into());
+            span.set_attribute("curiefense.checks", p2o.limits.len().into());
             let p3 = analyze_query_limits(logs, p2o).await;
-            analyze_finish(logs, mgh, cfrules, p3)
+            span.end();
+            let span = Span::start(ctx.as_ref(), "curiefense.analyze_finish");
+            let result = analyze_finish(logs, mgh, cfrules, p3);
+            span.end();
+            result
+        }
+    };
+    let mut result = result;
+    if let Some(gh) = mgh {
+        result.stats.challenge_latency(gh.latency().as_micros() as u64);
+    }
+    record_client_state(logs, &result).await;
+    end_request_span(logs, &result);
+    result
+}
+
+/// exports the span of the request, from the time it was received, and reports the errors of the background tasks
+pub fn end_request_span(logs: &mut Logs, result: &AnalyzeResult) {
+    logs.background_errors();
+    let mut span = Span::request(
+        result.rinfo.trace.as_ref(),
+        "curiefense.analyze",
+        result.rinfo.timestamp.into(),
+    );
+    span.set_attribute("curiefense.blocked", result.decision.is_final().into());
+    span.set_attribute("curiefense.tags", result.tags.inner().len().into());
+    span.end();
+}
+
+/// data required to inspect the response sent by the upstream server
+pub struct APhaseResp {
+    pub reqinfo: RequestInfo,
+    pub tags: Tags,
+    pub status: u32,
+    pub headers: HashMap<String, String>,
+    pub body: Option<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub struct ResponseAnalyzeResult {
+    pub decision: Decision,
+    pub tags: Tags,
+    /// response headers that must be replaced
+    pub headers: HashMap<String, String>,
+    /// replacement response body, when it was rewritten
+    pub body: Option<Vec<u8>>,
+    /// headers appended to the response, such as the CSRF token cookie
+    pub added_headers: Vec<(String, String)>,
+}
+
+pub fn analyze_response(logs: &mut Logs, presp: APhaseResp) -> ResponseAnalyzeResult {
+    let mut tags = presp.tags;
+    let reqinfo = presp.reqinfo;
+    let secpol = &reqinfo.rinfo.secpolicy;
+
+    let (mut decision, headers, mut body) = match &secpol.response_filter_profile {
+        None => {
+            logs.debug("no response filter profile");
+            (Decision::pass(Vec::new()), HashMap::new(), None)
+        }
+        Some(profile) => response_filter_phase(
+            logs,
+            &reqinfo,
+            &mut tags,
+            profile,
+            presp.status,
+            &presp.headers,
+            presp.body.as_deref(),
+        ),
+    };
+
+    if let (Some(settings), Some(rawbody)) = (&secpol.data_leak, presp.body.as_deref()) {
+        // data leak rules run on the body as rewritten by the response filter
+        let scanned = body.as_deref().unwrap_or(rawbody);
+        let dlresult = data_leak_check(logs, &mut tags, settings, scanned);
+        logs.debug("Data leak checks done");
+        let mut reasons = dlresult.reasons;
+        if !settings.active {
+            for reason in reasons.iter_mut() {
+                reason.action.inactive();
+            }
+        }
+        match dlresult.action {
+            Some(action) if decision.maction.is_none() => {
+                reasons.extend(decision.reasons);
+                decision = action.to_decision::<DummyGrasshopper>(
+                    logs,
+                    PrecisionLevel::Invalid,
+                    None,
+                    &reqinfo,
+                    &mut tags,
+                    reasons,
+                );
+                if let Some(action) = decision.maction.as_mut() {
+                    action.block_mode &= settings.active;
+                }
+            }
+            _ => decision.reasons.extend(reasons),
+        }
+        if settings.active && dlresult.body.is_some() {
+            body = dlresult.body;
+        }
+    }
+
+    let added_headers = secpol
+        .csrf
+        .as_ref()
+        .map(|settings| csrf_response_headers(settings, &reqinfo).into_iter().collect())
+        .unwrap_or_default();
+
+    ResponseAnalyzeResult {
+        decision,
+        tags,
+        headers,
+        body,
+        added_headers,
+    }
+}
+
+/// response filter checks, returning the decision and the rewritten headers and body
+fn response_filter_phase(
+    logs: &mut Logs,
+    reqinfo: &RequestInfo,
+    tags: &mut Tags,
+    profile: &ResponseFilterProfile,
+    status: u32,
+    headers: &HashMap<String, String>,
+    mbody: Option<&[u8]>,
+) -> (Decision, HashMap<String, String>, Option<Vec<u8>>) {
+    let secpol = &reqinfo.rinfo.secpolicy;
+    tags.insert_qualified("responsefilterid", &profile.id, Location::Response);
+    tags.insert_qualified("responsefiltername", &profile.name, Location::Response);
+
+    let rfresult = response_filter_check(logs, tags, profile, status, headers, mbody);
+    logs.debug("Response Filter checks done");
+
+    let reasons = rfresult
+        .reasons
+        .into_iter()
+        .map(|mut reason| {
+            if !secpol.response_filter_active {
+                reason.action.inactive();
+            }
+            reason
+        })
+        .collect();
+    let decision = if rfresult.blocking {
+        let mut dec =
+            profile
+                .action
+                .to_decision::<DummyGrasshopper>(logs, PrecisionLevel::Invalid, None, reqinfo, tags, reasons);
+        if let Some(action) = dec.maction.as_mut() {
+            action.block_mode &= secpol.response_filter_active;
         }
+        dec
+    } else {
+        Decision::pass(reasons)
+    };
+
+    // rewrites are only performed when the response filter is active
+    if secpol.response_filter_active {
+        (decision, rfresult.headers, rfresult.body)
+    } else {
+        (decision, HashMap::new(), None)
     }
 }
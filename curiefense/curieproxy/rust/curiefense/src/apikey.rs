@@ -0,0 +1,122 @@
+//! API key extraction.
+//!
+//! Security policy entries can extract an API key, or application id, from a header, a query argument or a JWT
+//! claim, the first source that is present being used. The key is exposed as the `apikey` attribute, that can be
+//! used in limit keys, session selectors and global filters. Requests carrying a key are tagged with
+//! `apikey:FINGERPRINT`, the fingerprint being the beginning of the SHA-256 hash of the key, so that keys do not
+//! leak in the logs.
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::config::raw::RawApiKeySettings;
+use crate::jwt::{bearer_token, decode_part};
+use crate::logs::Logs;
+use crate::requestfields::RequestField;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiKeySource {
+    /// lowercase header name
+    Header(String),
+    Arg(String),
+    /// claim of the JWT carried by the header, that is not verified at this stage
+    JwtClaim {
+        header: String,
+        claim: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeySettings {
+    pub sources: Vec<ApiKeySource>,
+}
+
+impl ApiKeySettings {
+    pub fn resolve(logs: &mut Logs, raw: RawApiKeySettings) -> Option<Self> {
+        let mut sources = Vec::new();
+        for s in raw.sources {
+            let jwt_header = s.jwt_header.as_deref().unwrap_or("authorization").to_ascii_lowercase();
+            match (s.header, s.arg, s.jwt_claim) {
+                (Some(h), None, None) => sources.push(ApiKeySource::Header(h.to_ascii_lowercase())),
+                (None, Some(a), None) => sources.push(ApiKeySource::Arg(a)),
+                (None, None, Some(claim)) => sources.push(ApiKeySource::JwtClaim {
+                    header: jwt_header,
+                    claim,
+                }),
+                _ => logs.error("an API key source must have exactly one of header, arg or jwt_claim"),
+            }
+        }
+        if sources.is_empty() {
+            logs.error("no valid API key source, the extraction is disabled");
+            return None;
+        }
+        Some(ApiKeySettings { sources })
+    }
+
+    /// the API key of the request, from the first source that is present
+    pub fn extract(&self, headers: &RequestField, args: &RequestField) -> Option<String> {
+        self.sources
+            .iter()
+            .filter_map(|source| match source {
+                ApiKeySource::Header(h) => headers.get_str(h).map(|v| v.trim().to_string()),
+                ApiKeySource::Arg(a) => args.get_str(a).map(|v| v.to_string()),
+                ApiKeySource::JwtClaim { header, claim } => {
+                    let token = bearer_token(headers.get_str(header)?)?;
+                    let claims = decode_part(token.split('.').nth(1)?)?;
+                    match claims.get(claim)? {
+                        Value::String(s) => Some(s.clone()),
+                        Value::Number(n) => Some(n.to_string()),
+                        _ => None,
+                    }
+                }
+            })
+            .find(|k| !k.is_empty())
+    }
+}
+
+/// short identifier of a key, used in tags
+pub fn fingerprint(key: &str) -> String {
+    let digest = Sha256::digest(key.as_bytes());
+    digest.iter().take(8).map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::Location;
+
+    fn field(kvs: &[(&str, &str)]) -> RequestField {
+        let mut out = RequestField::new(&[]);
+        for (k, v) in kvs {
+            out.add(k.to_string(), Location::Request, v.to_string());
+        }
+        out
+    }
+
+    #[test]
+    fn source_order() {
+        let settings = ApiKeySettings {
+            sources: vec![
+                ApiKeySource::Header("x-api-key".to_string()),
+                ApiKeySource::JwtClaim {
+                    header: "authorization".to_string(),
+                    claim: "client_id".to_string(),
+                },
+                ApiKeySource::Arg("api_key".to_string()),
+            ],
+        };
+        let args = field(&[("api_key", "from-arg")]);
+        assert_eq!(
+            settings.extract(&field(&[("x-api-key", " k1 ")]), &args),
+            Some("k1".to_string())
+        );
+        // {"client_id":"app-7"}
+        let jwt = "Bearer eyJhbGciOiJub25lIn0.eyJjbGllbnRfaWQiOiJhcHAtNyJ9.";
+        assert_eq!(
+            settings.extract(&field(&[("authorization", jwt)]), &args),
+            Some("app-7".to_string())
+        );
+        assert_eq!(settings.extract(&field(&[]), &args), Some("from-arg".to_string()));
+        assert_eq!(settings.extract(&field(&[]), &field(&[])), None);
+        assert_eq!(fingerprint("k1").len(), 16);
+    }
+}
@@ -0,0 +1,330 @@
+//! Temporary bans.
+//!
+//! Actions can carry a `ban` parameter, a duration in seconds. When the decision of a request is blocking and its
+//! action has one, the client address is banned for that duration. Bans are stored in a redis hash, mapping the
+//! addresses to their expiry date and reason, and mirrored in a local table that is refreshed every BAN_REFRESH
+//! seconds (5 by default), so that checking a request does not wait on redis.
+//!
+//! The expiry dates are also kept in a sorted set, so that expired bans are removed without reading the hash, and
+//! every change bumps a version counter: the hash is only read again when the version changed. All the updates are
+//! done by scripts, so that a ban is never lost to a concurrent update.
+//!
+//! Banned clients are blocked as soon as their security policy is known, before their body is decoded, with the
+//! `banned` tag. Bans can be listed and lifted with `list_bans` and `remove_ban`.
+use chrono::{TimeZone, Utc};
+use lazy_static::lazy_static;
+use redis::Script;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::interface::{AnalyzeResult, BlockReason, SimpleAction, SimpleActionT};
+use crate::logs::Logs;
+use crate::redis::{redis_async_conn, REDIS_KEY_PREFIX};
+
+lazy_static! {
+    // the keys share a hash tag, as they are updated by the same scripts
+    static ref BAN_KEY: String = format!("{{{}bans}}", *REDIS_KEY_PREFIX);
+    static ref BAN_EXPIRY_KEY: String = format!("{}:expiry", *BAN_KEY);
+    static ref BAN_VERSION_KEY: String = format!("{}:version", *BAN_KEY);
+    static ref BAN_REFRESH: Duration = Duration::from_secs(
+        std::env::var("BAN_REFRESH")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5)
+    );
+    static ref BANS: Mutex<LocalBans> = Mutex::new(LocalBans::default());
+    /// KEYS: hash, expiry set, version - ARGV: ip, expiry, encoded ban
+    ///
+    /// keeps the current ban when it is longer, returns the encoded ban
+    static ref ADD_BAN_SCRIPT: Script = Script::new(
+        r#"
+local current = redis.call('ZSCORE', KEYS[2], ARGV[1])
+if current and tonumber(current) >= tonumber(ARGV[2]) then
+  local value = redis.call('HGET', KEYS[1], ARGV[1])
+  if value then
+    return value
+  end
+end
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[3])
+redis.call('ZADD', KEYS[2], ARGV[2], ARGV[1])
+redis.call('INCR', KEYS[3])
+return ARGV[3]
+"#
+    );
+    /// KEYS: hash, expiry set, version - ARGV: ip
+    static ref REMOVE_BAN_SCRIPT: Script = Script::new(
+        r#"
+redis.call('ZREM', KEYS[2], ARGV[1])
+local removed = redis.call('HDEL', KEYS[1], ARGV[1])
+if removed > 0 then
+  redis.call('INCR', KEYS[3])
+end
+return removed
+"#
+    );
+    /// KEYS: hash, expiry set, version - ARGV: now, known version
+    ///
+    /// removes the expired bans, returns the version, followed by the content of the hash when the version differs
+    static ref FETCH_BANS_SCRIPT: Script = Script::new(
+        r#"
+local expired = redis.call('ZRANGEBYSCORE', KEYS[2], '-inf', ARGV[1], 'LIMIT', 0, 1000)
+if #expired > 0 then
+  redis.call('ZREM', KEYS[2], unpack(expired))
+  redis.call('HDEL', KEYS[1], unpack(expired))
+end
+local version = redis.call('GET', KEYS[3]) or '0'
+if version == ARGV[2] then
+  return {version}
+end
+local out = redis.call('HGETALL', KEYS[1])
+table.insert(out, 1, version)
+return out
+"#
+    );
+}
+
+static REFRESHER: std::sync::Once = std::sync::Once::new();
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Ban {
+    pub ip: String,
+    /// unix timestamp
+    pub expires: i64,
+    pub reason: String,
+}
+
+impl Ban {
+    fn encode(&self) -> String {
+        format!("{}|{}", self.expires, self.reason)
+    }
+
+    fn decode(ip: String, value: &str) -> Option<Self> {
+        let (expires, reason) = value.split_once('|')?;
+        Some(Ban {
+            ip,
+            expires: expires.parse().ok()?,
+            reason: reason.to_string(),
+        })
+    }
+
+    pub fn active(&self, now: i64) -> bool {
+        now < self.expires
+    }
+}
+
+/// the local copy of the bans, and the version it was read at
+#[derive(Debug, Default)]
+struct LocalBans {
+    version: Option<String>,
+    bans: HashMap<String, Ban>,
+}
+
+fn local_bans() -> std::sync::MutexGuard<'static, LocalBans> {
+    BANS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// the expiry date of a ban starting now
+fn ban_expiry(now: i64, duration: u64) -> i64 {
+    now.saturating_add(duration.min(i64::MAX as u64) as i64)
+}
+
+/// parses the answer of FETCH_BANS_SCRIPT, the bans are None when the version did not change
+fn parse_fetch(mut raw: Vec<String>, now: i64) -> anyhow::Result<(String, Option<Vec<Ban>>)> {
+    if raw.is_empty() {
+        anyhow::bail!("empty answer when fetching the bans");
+    }
+    let version = raw.remove(0);
+    if raw.is_empty() {
+        return Ok((version, None));
+    }
+    let mut active = Vec::new();
+    let mut it = raw.into_iter();
+    while let (Some(ip), Some(value)) = (it.next(), it.next()) {
+        // expired or invalid entries are skipped
+        if let Some(ban) = Ban::decode(ip, &value).filter(|b| b.active(now)) {
+            active.push(ban);
+        }
+    }
+    Ok((version, Some(active)))
+}
+
+/// the expired bans are removed, and the active ones are returned unless the version is `known`
+async fn fetch_bans(now: i64, known: Option<&str>) -> anyhow::Result<(String, Option<Vec<Ban>>)> {
+    let mut conn = redis_async_conn().await?;
+    let raw: Vec<String> = FETCH_BANS_SCRIPT
+        .key(&*BAN_KEY)
+        .key(&*BAN_EXPIRY_KEY)
+        .key(&*BAN_VERSION_KEY)
+        .arg(now)
+        .arg(known.unwrap_or_default())
+        .invoke_async(&mut conn)
+        .await?;
+    parse_fetch(raw, now)
+}
+
+fn refresh_bans() {
+    let now = Utc::now().timestamp();
+    let known = local_bans().version.clone();
+    // the previous table is kept when redis can't be reached
+    if let Ok((version, fetched)) = async_std::task::block_on(fetch_bans(now, known.as_deref())) {
+        let mut local = local_bans();
+        match fetched {
+            Some(bans) => local.bans = bans.into_iter().map(|b| (b.ip.clone(), b)).collect(),
+            None => local.bans.retain(|_, b| b.active(now)),
+        }
+        local.version = Some(version);
+    }
+}
+
+/// the ban of the address, if it is active
+pub fn banned(ip: &str, now: i64) -> Option<Ban> {
+    REFRESHER.call_once(|| {
+        std::thread::spawn(|| loop {
+            refresh_bans();
+            std::thread::sleep(*BAN_REFRESH);
+        });
+    });
+    local_bans().bans.get(ip).filter(|b| b.active(now)).cloned()
+}
+
+/// bans an address, extending its current ban if it is longer
+pub async fn add_ban(ip: &str, duration: u64, reason: &str) -> anyhow::Result<Ban> {
+    let ban = Ban {
+        ip: ip.to_string(),
+        expires: ban_expiry(Utc::now().timestamp(), duration),
+        reason: reason.to_string(),
+    };
+    let mut conn = redis_async_conn().await?;
+    let encoded: String = ADD_BAN_SCRIPT
+        .key(&*BAN_KEY)
+        .key(&*BAN_EXPIRY_KEY)
+        .key(&*BAN_VERSION_KEY)
+        .arg(ip)
+        .arg(ban.expires)
+        .arg(ban.encode())
+        .invoke_async(&mut conn)
+        .await?;
+    let ban = Ban::decode(ip.to_string(), &encoded).ok_or_else(|| anyhow::anyhow!("invalid ban {}", encoded))?;
+    local_bans().bans.insert(ban.ip.clone(), ban.clone());
+    Ok(ban)
+}
+
+/// lifts a ban, returns false if the address was not banned
+pub async fn remove_ban(ip: &str) -> anyhow::Result<bool> {
+    local_bans().bans.remove(ip);
+    let mut conn = redis_async_conn().await?;
+    let removed: u64 = REMOVE_BAN_SCRIPT
+        .key(&*BAN_KEY)
+        .key(&*BAN_EXPIRY_KEY)
+        .key(&*BAN_VERSION_KEY)
+        .arg(ip)
+        .invoke_async(&mut conn)
+        .await?;
+    Ok(removed > 0)
+}
+
+pub async fn list_bans() -> anyhow::Result<Vec<Ban>> {
+    let (_, bans) = fetch_bans(Utc::now().timestamp(), None).await?;
+    let mut bans = bans.unwrap_or_default();
+    bans.sort_by(|a, b| a.ip.cmp(&b.ip));
+    Ok(bans)
+}
+
+pub fn remove_ban_block(ip: &str) -> anyhow::Result<bool> {
+    async_std::task::block_on(remove_ban(ip))
+}
+
+pub fn list_bans_block() -> anyhow::Result<Vec<Ban>> {
+    async_std::task::block_on(list_bans())
+}
+
+/// bans the client when the decision is blocking and its action has a ban duration
+pub async fn record_ban(logs: &mut Logs, result: &AnalyzeResult) {
+    let duration = match result.decision.maction.as_ref().and_then(|a| a.ban) {
+        Some(d) if result.decision.is_blocking() => d,
+        _ => return,
+    };
+    let ip = &result.rinfo.rinfo.geoip.ipstr;
+    let reason = BlockReason::block_reason_desc(&result.decision.reasons).unwrap_or_default();
+    match add_ban(ip, duration, &reason).await {
+        Ok(ban) => logs.debug(|| format!("{} banned until {}", ip, ban.expires)),
+        Err(rr) => logs.error(|| format!("could not ban {}: {}", ip, rr)),
+    }
+}
+
+/// the action and reason of the requests of banned clients
+pub fn ban_action(ban: &Ban) -> (SimpleAction, BlockReason) {
+    let action = SimpleAction {
+        atype: SimpleActionT::Custom {
+            content: "access denied".to_string(),
+        },
+        status: 403,
+        ..SimpleAction::default()
+    };
+    let until = Utc
+        .timestamp_opt(ban.expires, 0)
+        .single()
+        .map(|d| d.to_rfc3339())
+        .unwrap_or_default();
+    let reason = BlockReason::banned(action.atype.to_raw(), until, ban.reason.clone());
+    (action, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding() {
+        let ban = Ban {
+            ip: "1.2.3.4".to_string(),
+            expires: 1700000000,
+            reason: "global filter|scanner".to_string(),
+        };
+        assert_eq!(Ban::decode(ban.ip.clone(), &ban.encode()), Some(ban.clone()));
+        assert_eq!(Ban::decode("x".to_string(), "soon|x"), None);
+        assert!(ban.active(1699999999));
+        assert!(!ban.active(1700000000));
+        let (action, reason) = ban_action(&ban);
+        assert_eq!(action.status, 403);
+        assert_eq!(reason.id, "ban");
+    }
+
+    #[test]
+    fn expiry() {
+        assert_eq!(ban_expiry(1700000000, 60), 1700000060);
+        assert_eq!(ban_expiry(1700000000, u64::MAX), i64::MAX);
+        assert_eq!(ban_expiry(1700000000, i64::MAX as u64), i64::MAX);
+    }
+
+    #[test]
+    fn fetched_bans() {
+        let raw = |items: &[&str]| items.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(parse_fetch(raw(&["3"]), 100).unwrap(), ("3".to_string(), None));
+        let (version, bans) = parse_fetch(
+            raw(&[
+                "4",
+                "1.2.3.4",
+                "200|scanner",
+                "5.6.7.8",
+                "50|expired",
+                "9.9.9.9",
+                "garbage",
+            ]),
+            100,
+        )
+        .unwrap();
+        assert_eq!(version, "4");
+        assert_eq!(
+            bans,
+            Some(vec![Ban {
+                ip: "1.2.3.4".to_string(),
+                expires: 200,
+                reason: "scanner".to_string()
+            }])
+        );
+        assert!(parse_fetch(Vec::new(), 100).is_err());
+    }
+}
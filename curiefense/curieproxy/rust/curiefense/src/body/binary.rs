@@ -0,0 +1,148 @@
+/// MessagePack and CBOR bodies
+///
+/// Both are flattened like JSON bodies: key names are built by joining map keys and list indices with "_".
+/// Scalar map keys are converted to strings, other keys are replaced with their position in the map.
+/// Binary strings are decoded as (lossy) UTF-8, so that they can be inspected too.
+use serde_cbor::Value as CValue;
+
+use super::json_path;
+use crate::interface::Location;
+use crate::requestfields::RequestField;
+use crate::utils::BodyProblem;
+
+/// the decoder counts two levels per container, this is the nesting limit of the JSON parser (128 levels)
+const MSGPACK_MAX_DECODER_DEPTH: usize = 2 * 128;
+
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+fn msgpack_scalar(value: &rmpv::Value) -> Option<String> {
+    use rmpv::Value::*;
+    match value {
+        Nil => Some("null".to_string()),
+        Boolean(b) => Some(b.to_string()),
+        Integer(i) => Some(i.to_string()),
+        F32(f) => Some(f.to_string()),
+        F64(f) => Some(f.to_string()),
+        String(s) => Some(lossy(s.as_bytes())),
+        Binary(b) => Some(lossy(b)),
+        Ext(_, b) => Some(lossy(b)),
+        Array(_) | Map(_) => None,
+    }
+}
+
+fn flatten_msgpack(
+    depth_budget: usize,
+    args: &mut RequestField,
+    prefix: &mut Vec<String>,
+    value: rmpv::Value,
+) -> Result<(), ()> {
+    if depth_budget == 0 {
+        return Err(());
+    }
+    match value {
+        rmpv::Value::Array(array) => {
+            prefix.push(String::new());
+            let idx = prefix.len() - 1;
+            for (i, v) in array.into_iter().enumerate() {
+                prefix[idx] = i.to_string();
+                flatten_msgpack(depth_budget - 1, args, prefix, v)?;
+            }
+            prefix.pop();
+        }
+        rmpv::Value::Map(mp) => {
+            prefix.push(String::new());
+            let idx = prefix.len() - 1;
+            for (i, (k, v)) in mp.into_iter().enumerate() {
+                prefix[idx] = msgpack_scalar(&k).unwrap_or_else(|| i.to_string());
+                flatten_msgpack(depth_budget - 1, args, prefix, v)?;
+            }
+            prefix.pop();
+        }
+        scalar => {
+            let v = msgpack_scalar(&scalar).unwrap_or_default();
+            args.add(json_path(prefix), Location::Body, v);
+        }
+    }
+    Ok(())
+}
+
+pub fn msgpack_body(mxdepth: usize, args: &mut RequestField, body: &[u8]) -> Result<(), BodyProblem> {
+    let mut rd = body;
+    // the decoder recursion is bounded too, as it happens before flattening
+    // it counts two levels per container, and is capped to protect the stack
+    let decoder_depth = mxdepth
+        .saturating_mul(2)
+        .saturating_add(2)
+        .min(MSGPACK_MAX_DECODER_DEPTH);
+    let value = rmpv::decode::read_value_with_max_depth(&mut rd, decoder_depth).map_err(|rr| match rr {
+        rmpv::decode::Error::DepthLimitExceeded => BodyProblem::TooDeep,
+        _ => BodyProblem::DecodingError(rr.to_string(), None),
+    })?;
+    if !rd.is_empty() {
+        return Err(BodyProblem::DecodingError(
+            format!("{} trailing bytes after the MessagePack value", rd.len()),
+            None,
+        ));
+    }
+    let mut prefix = Vec::new();
+    flatten_msgpack(mxdepth, args, &mut prefix, value).map_err(|()| BodyProblem::TooDeep)
+}
+
+fn cbor_scalar(value: &CValue) -> Option<String> {
+    match value {
+        CValue::Null => Some("null".to_string()),
+        CValue::Bool(b) => Some(b.to_string()),
+        CValue::Integer(i) => Some(i.to_string()),
+        CValue::Float(f) => Some(f.to_string()),
+        CValue::Text(s) => Some(s.clone()),
+        CValue::Bytes(b) => Some(lossy(b)),
+        CValue::Tag(_, v) => cbor_scalar(v),
+        _ => None,
+    }
+}
+
+fn flatten_cbor(
+    depth_budget: usize,
+    args: &mut RequestField,
+    prefix: &mut Vec<String>,
+    value: CValue,
+) -> Result<(), ()> {
+    if depth_budget == 0 {
+        return Err(());
+    }
+    match value {
+        CValue::Array(array) => {
+            prefix.push(String::new());
+            let idx = prefix.len() - 1;
+            for (i, v) in array.into_iter().enumerate() {
+                prefix[idx] = i.to_string();
+                flatten_cbor(depth_budget - 1, args, prefix, v)?;
+            }
+            prefix.pop();
+        }
+        CValue::Map(mp) => {
+            prefix.push(String::new());
+            let idx = prefix.len() - 1;
+            for (i, (k, v)) in mp.into_iter().enumerate() {
+                prefix[idx] = cbor_scalar(&k).unwrap_or_else(|| i.to_string());
+                flatten_cbor(depth_budget - 1, args, prefix, v)?;
+            }
+            prefix.pop();
+        }
+        // tags (dates, bignums ...) do not change the path, but still count as a nesting level
+        CValue::Tag(_, v) => flatten_cbor(depth_budget - 1, args, prefix, *v)?,
+        scalar => {
+            let v = cbor_scalar(&scalar).unwrap_or_default();
+            args.add(json_path(prefix), Location::Body, v);
+        }
+    }
+    Ok(())
+}
+
+pub fn cbor_body(mxdepth: usize, args: &mut RequestField, body: &[u8]) -> Result<(), BodyProblem> {
+    let value: CValue = serde_cbor::from_slice(body).map_err(|rr| BodyProblem::DecodingError(rr.to_string(), None))?;
+    let mut prefix = Vec::new();
+    flatten_cbor(mxdepth, args, &mut prefix, value).map_err(|()| BodyProblem::TooDeep)
+}
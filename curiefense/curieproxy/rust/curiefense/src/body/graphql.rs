@@ -1,11 +1,41 @@
 use async_graphql_parser::{
     parse_query,
-    types::{Directive, DocumentOperations, OperationDefinition, Selection, SelectionSet},
+    types::{Directive, DocumentOperations, ExecutableDocument, OperationDefinition, Selection, SelectionSet},
     Positioned,
 };
+use serde_json::Value;
 
+use super::flatten_json;
 use crate::{interface::Location, requestfields::RequestField, utils::BodyProblem};
 
+/// document traversal state, shared by all the definitions of a query document
+struct GraphqlCtx<'a> {
+    args: &'a mut RequestField,
+    /// number of fields that can still be visited
+    complexity_budget: usize,
+    max_complexity: usize,
+}
+
+impl<'a> GraphqlCtx<'a> {
+    /// accounts for a visited field, failing when the document has too many fields
+    fn visit_field(&mut self, path: String) -> Result<(), BodyProblem> {
+        if self.complexity_budget == 0 {
+            return Err(BodyProblem::TooComplex(self.max_complexity));
+        }
+        self.complexity_budget -= 1;
+        self.args.add("gpath".to_string(), Location::Body, path);
+        Ok(())
+    }
+}
+
+fn sub_path(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        path.to_string() + "." + name
+    }
+}
+
 fn insert_directive(args: &mut RequestField, prefix: String, dir: Directive) {
     for (n, v) in dir.arguments {
         let prefix = prefix.clone() + "-" + &dir.name.node + "-" + &n.node;
@@ -17,55 +47,74 @@ fn insert_directive(args: &mut RequestField, prefix: String, dir: Directive) {
 
 fn insert_dirsels(
     max_depth: usize,
-    args: &mut RequestField,
+    ctx: &mut GraphqlCtx,
     prefix: &str,
+    path: &str,
     directives: Vec<Positioned<Directive>>,
     mselections: Option<Positioned<SelectionSet>>,
-) -> Result<bool, ()> {
+) -> Result<bool, BodyProblem> {
     if max_depth == 0 {
-        return Err(());
+        return Err(BodyProblem::TooDeep);
     }
     let mut o = false;
     for (n, d) in directives.into_iter().enumerate() {
         o = true;
-        insert_directive(args, format!("{}-d{}", prefix, n), d.node);
+        insert_directive(ctx.args, format!("{}-d{}", prefix, n), d.node);
     }
     if let Some(selections) = mselections {
         for (n, s) in selections.node.items.into_iter().enumerate() {
             o = true;
-            insert_selection(max_depth - 1, args, format!("{}-s{}", prefix, n), s.node)?;
+            insert_selection(max_depth - 1, ctx, format!("{}-s{}", prefix, n), path, s.node)?;
         }
     }
     Ok(o)
 }
 
-fn insert_selection(max_depth: usize, args: &mut RequestField, prefix: String, sel: Selection) -> Result<(), ()> {
+fn insert_selection(
+    max_depth: usize,
+    ctx: &mut GraphqlCtx,
+    prefix: String,
+    path: &str,
+    sel: Selection,
+) -> Result<(), BodyProblem> {
     if max_depth == 0 {
-        return Err(());
+        return Err(BodyProblem::TooDeep);
     }
     match sel {
         Selection::Field(pfield) => {
             let field = pfield.node;
             let mut traced = false;
             let nprefix = prefix.to_string() + "-" + &field.name.node;
+            let npath = sub_path(path, &field.name.node);
+            ctx.visit_field(npath.clone())?;
             if let Some(alias) = field.alias {
                 traced = true;
-                args.add(nprefix.to_string() + "-alias", Location::Body, alias.node.to_string());
+                ctx.args
+                    .add(nprefix.to_string() + "-alias", Location::Body, alias.node.to_string());
             }
             for (k, v) in field.arguments {
                 traced = true;
-                args.add(nprefix.to_string() + "-" + &k.node, Location::Body, v.node.to_string());
+                ctx.args
+                    .add(nprefix.to_string() + "-" + &k.node, Location::Body, v.node.to_string());
             }
-            traced |= insert_dirsels(max_depth, args, &nprefix, field.directives, Some(field.selection_set))?;
+            traced |= insert_dirsels(
+                max_depth,
+                ctx,
+                &nprefix,
+                &npath,
+                field.directives,
+                Some(field.selection_set),
+            )?;
             if !traced {
-                args.add(prefix.clone(), Location::Body, field.name.node.to_string());
+                ctx.args
+                    .add(prefix.clone(), Location::Body, field.name.node.to_string());
             }
         }
         Selection::FragmentSpread(fsp) => {
             let frag = fsp.node;
-            let traced = insert_dirsels(max_depth, args, &prefix, frag.directives, None)?;
+            let traced = insert_dirsels(max_depth, ctx, &prefix, path, frag.directives, None)?;
             if !traced {
-                args.add(
+                ctx.args.add(
                     prefix.to_string() + "-frag",
                     Location::Body,
                     frag.fragment_name.node.to_string(),
@@ -74,7 +123,14 @@ fn insert_selection(max_depth: usize, args: &mut RequestField, prefix: String, s
         }
         Selection::InlineFragment(pinline) => {
             let inline = pinline.node;
-            insert_dirsels(max_depth, args, &prefix, inline.directives, Some(inline.selection_set))?;
+            insert_dirsels(
+                max_depth,
+                ctx,
+                &prefix,
+                path,
+                inline.directives,
+                Some(inline.selection_set),
+            )?;
         }
     }
     Ok(())
@@ -82,52 +138,117 @@ fn insert_selection(max_depth: usize, args: &mut RequestField, prefix: String, s
 
 fn insert_operation(
     max_depth: usize,
-    args: &mut RequestField,
+    ctx: &mut GraphqlCtx,
     mprefix: Option<&str>,
     pod: Positioned<OperationDefinition>,
-) -> Result<(), ()> {
+) -> Result<(), BodyProblem> {
     if max_depth == 0 {
-        return Err(());
+        return Err(BodyProblem::TooDeep);
     }
     let mut prefix = "gdir".to_string();
     let od = pod.node;
+    ctx.args.add("gop-type".to_string(), Location::Body, od.ty.to_string());
     if let Some(p) = mprefix {
         prefix += "-";
         prefix += p;
+        ctx.args.add("gop-name".to_string(), Location::Body, p.to_string());
     }
     for pvardef in od.variable_definitions {
         let vardef = pvardef.node;
         let varprefix = prefix.clone() + "-" + &vardef.name.node;
         if let Some(cval) = vardef.default_value {
-            args.add(varprefix.clone() + "-defvalue", Location::Body, cval.to_string());
+            ctx.args
+                .add(varprefix.clone() + "-defvalue", Location::Body, cval.to_string());
         }
-        insert_dirsels(max_depth, args, &varprefix, vardef.directives, None)?;
+        insert_dirsels(max_depth, ctx, &varprefix, "", vardef.directives, None)?;
     }
-    insert_dirsels(max_depth, args, &prefix, od.directives, Some(od.selection_set))?;
-    Ok(())
+    insert_dirsels(max_depth, ctx, &prefix, "", od.directives, Some(od.selection_set)).map(|_| ())
 }
 
+/// decodes a graphql query document
+///
+/// Besides the directives and selections, the following fields are extracted:
+///  * gop-type and gop-name, the operation types and names,
+///  * gpath, the dotted paths of all selected fields, fragment fields being rooted at `...fragmentName`.
+///
+/// The document is rejected when it is nested deeper than max_depth, or when it has more than
+/// max_complexity fields.
 // invariant, max_depth > 0
-pub fn graphql_body(max_depth: usize, args: &mut RequestField, body: &[u8]) -> Result<(), BodyProblem> {
+pub fn graphql_body(
+    max_depth: usize,
+    max_complexity: usize,
+    args: &mut RequestField,
+    body: &[u8],
+) -> Result<(), BodyProblem> {
     let body_utf8 = std::str::from_utf8(body).map_err(|rr| BodyProblem::DecodingError(rr.to_string(), None))?;
     let document = parse_query(body_utf8).map_err(|rr| BodyProblem::DecodingError(rr.to_string(), None))?;
+    insert_document(max_depth, max_complexity, args, document)
+}
+
+fn insert_document(
+    max_depth: usize,
+    max_complexity: usize,
+    args: &mut RequestField,
+    document: ExecutableDocument,
+) -> Result<(), BodyProblem> {
+    let mut ctx = GraphqlCtx {
+        args,
+        complexity_budget: max_complexity,
+        max_complexity,
+    };
     for (nm, pdef) in document.fragments {
         let basename = "gfrag-".to_string() + &nm;
         insert_dirsels(
             max_depth,
-            args,
+            &mut ctx,
             &basename,
+            &format!("...{}", nm),
             pdef.node.directives,
             Some(pdef.node.selection_set),
-        )
-        .map_err(|_| BodyProblem::TooDeep)?;
+        )?;
     }
 
-    let rs = match document.operations {
-        DocumentOperations::Single(opdef) => insert_operation(max_depth, args, None, opdef),
+    match document.operations {
+        DocumentOperations::Single(opdef) => insert_operation(max_depth, &mut ctx, None, opdef),
         DocumentOperations::Multiple(opdefs) => opdefs
             .into_iter()
-            .try_for_each(|(n, op)| insert_operation(max_depth, args, Some(&n), op)),
+            .try_for_each(|(n, op)| insert_operation(max_depth, &mut ctx, Some(&n), op)),
+    }
+}
+
+/// decodes a graphql request sent as JSON, with its query, operationName and variables members
+///
+/// The whole body is flattened like any JSON body, the graphql entries are added on top of it: variables are
+/// also stored as gvar-name entries. Returns None when the body is not a valid graphql request, so that it can be
+/// decoded as plain JSON.
+pub fn graphql_json_body(
+    max_depth: usize,
+    max_complexity: usize,
+    args: &mut RequestField,
+    body: &[u8],
+) -> Option<Result<(), BodyProblem>> {
+    let request = match serde_json::from_slice(body) {
+        Ok(Value::Object(mp)) => mp,
+        _ => return None,
     };
-    rs.map_err(|_| BodyProblem::TooDeep)
+    let document = match request.get("query") {
+        Some(Value::String(q)) => parse_query(q).ok()?,
+        _ => return None,
+    };
+    if let Some(Value::String(opname)) = request.get("operationName") {
+        args.add("gop-selected".to_string(), Location::Body, opname.clone());
+    }
+    if let Some(Value::Object(variables)) = request.get("variables") {
+        for (name, value) in variables {
+            let value = match value {
+                Value::String(s) => s.clone(),
+                v => v.to_string(),
+            };
+            args.add(format!("gvar-{}", name), Location::Body, value);
+        }
+    }
+    if flatten_json(max_depth, args, &mut Vec::new(), Value::Object(request)).is_err() {
+        return Some(Err(BodyProblem::TooDeep));
+    }
+    Some(insert_document(max_depth, max_complexity, args, document))
 }
@@ -6,6 +6,9 @@
 ///  * xml
 ///  * multipart/form-data
 ///  * urlencoded forms
+///  * graphql, either as a query document or as a JSON request
+///  * protobuf and gRPC
+///  * MessagePack and CBOR
 ///
 /// The main function, parse_body, is the only exported function.
 ///
@@ -21,7 +24,20 @@ use crate::requestfields::RequestField;
 use crate::utils::decoders::parse_urlencoded_params_bytes;
 use crate::utils::BodyProblem;
 
+mod binary;
 mod graphql;
+mod protobuf;
+mod uploads;
+
+pub use protobuf::ProtobufSchema;
+pub use uploads::UploadPolicy;
+
+/// what is needed to decode protobuf bodies: the optional schema, and the request path for gRPC methods
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProtoContext<'a> {
+    pub schema: Option<&'a ProtobufSchema>,
+    pub path: &'a str,
+}
 
 fn json_path(prefix: &[String]) -> String {
     if prefix.is_empty() {
@@ -269,24 +285,62 @@ fn forms_body(args: &mut RequestField, body: &[u8]) -> Result<(), BodyProblem> {
 /// reuses the multipart crate to parse these bodies
 ///
 /// will not work properly with binary data
-fn multipart_form_encoded(boundary: &str, args: &mut RequestField, body: &[u8]) -> Result<(), BodyProblem> {
+///
+/// for uploaded files, the file name, declared and sniffed types, and size are stored as `name:filename`,
+/// `name:content-type`, `name:sniffed-type` and `name:size`, and the content is stored as `name`, unless the
+/// policy says otherwise
+fn multipart_form_encoded(
+    boundary: &str,
+    uploads: &UploadPolicy,
+    args: &mut RequestField,
+    body: &[u8],
+) -> Result<(), BodyProblem> {
     let mut multipart = Multipart::with_body(body, boundary);
+    let mut rejected = None;
     multipart
         .foreach_entry(|mut entry| {
             let mut content = Vec::new();
             let _ = entry.data.read_to_end(&mut content);
             let name = entry.headers.name.to_string();
-            let scontent = String::from_utf8_lossy(&content);
-            args.add(name, Location::Body, scontent.to_string());
+            match &entry.headers.filename {
+                None => {
+                    let scontent = String::from_utf8_lossy(&content);
+                    args.add(name, Location::Body, scontent.to_string());
+                }
+                Some(filename) => {
+                    let declared = entry.headers.content_type.as_ref().map(|m| m.to_string());
+                    let sniffed = uploads::sniff_type(&content);
+                    args.add(name.clone() + ":filename", Location::Body, filename.clone());
+                    if let Some(d) = &declared {
+                        args.add(name.clone() + ":content-type", Location::Body, d.clone());
+                    }
+                    args.add(name.clone() + ":sniffed-type", Location::Body, sniffed.to_string());
+                    args.add(name.clone() + ":size", Location::Body, content.len().to_string());
+                    if rejected.is_none() {
+                        rejected = uploads.check(content.len(), declared.as_deref(), sniffed).err();
+                    }
+                    if !uploads.skip_contents {
+                        args.add(name, Location::Body, String::from_utf8_lossy(&content).to_string());
+                    }
+                }
+            }
         })
-        .map_err(|rr| BodyProblem::DecodingError(rr.to_string(), None))
+        .map_err(|rr| BodyProblem::DecodingError(rr.to_string(), None))?;
+    match rejected {
+        Some(rr) => Err(rr),
+        None => Ok(()),
+    }
 }
 
 /// body parsing function, returns an error when the body can't be decoded
+#[allow(clippy::too_many_arguments)]
 pub fn parse_body(
     logs: &mut Logs,
     args: &mut RequestField,
     max_depth: usize,
+    max_complexity: usize,
+    proto: ProtoContext,
+    uploads: &UploadPolicy,
     mcontent_type: Option<&str>,
     accepted_types: &[ContentType],
     body: &[u8],
@@ -308,17 +362,28 @@ pub fn parse_body(
             match t {
                 ContentType::Graphql => {
                     if content_type == "application/graphql" {
-                        return graphql::graphql_body(max_depth, args, body);
+                        return graphql::graphql_body(max_depth, max_complexity, args, body);
+                    }
+                    if content_type.ends_with("/json") {
+                        if let Some(res) = graphql::graphql_json_body(max_depth, max_complexity, args, body) {
+                            return res;
+                        }
                     }
                 }
                 ContentType::Json => {
                     if content_type.ends_with("/json") {
+                        // graphql requests are usually sent as JSON
+                        if accepted_types.iter().any(|t| matches!(t, ContentType::Graphql)) {
+                            if let Some(res) = graphql::graphql_json_body(max_depth, max_complexity, args, body) {
+                                return res;
+                            }
+                        }
                         return json_body(max_depth, args, body);
                     }
                 }
                 ContentType::MultipartForm => {
                     if let Some(boundary) = content_type.strip_prefix("multipart/form-data; boundary=") {
-                        return multipart_form_encoded(boundary, args, body);
+                        return multipart_form_encoded(boundary, uploads, args, body);
                     }
                 }
                 ContentType::Xml => {
@@ -326,6 +391,27 @@ pub fn parse_body(
                         return xml_body(max_depth, args, body);
                     }
                 }
+                ContentType::Protobuf => {
+                    if content_type.starts_with("application/grpc") {
+                        return protobuf::protobuf_body(max_depth, args, proto.schema, proto.path, true, body);
+                    }
+                    if content_type == "application/x-protobuf" || content_type == "application/protobuf" {
+                        return protobuf::protobuf_body(max_depth, args, proto.schema, proto.path, false, body);
+                    }
+                }
+                ContentType::MessagePack => {
+                    if content_type.ends_with("/msgpack")
+                        || content_type.ends_with("/x-msgpack")
+                        || content_type.ends_with("/vnd.msgpack")
+                    {
+                        return binary::msgpack_body(max_depth, args, body);
+                    }
+                }
+                ContentType::Cbor => {
+                    if content_type.ends_with("/cbor") {
+                        return binary::cbor_body(max_depth, args, body);
+                    }
+                }
                 ContentType::UrlEncoded => {
                     if content_type == "application/x-www-form-urlencoded" {
                         return forms_body(args, body);
@@ -368,7 +454,18 @@ mod tests {
     ) -> RequestField {
         let mut logs = Logs::default();
         let mut args = RequestField::new(dec);
-        parse_body(&mut logs, &mut args, max_depth, mcontent_type, accepted_types, body).unwrap();
+        parse_body(
+            &mut logs,
+            &mut args,
+            max_depth,
+            usize::MAX,
+            ProtoContext::default(),
+            &UploadPolicy::default(),
+            mcontent_type,
+            accepted_types,
+            body,
+        )
+        .unwrap();
         for lg in logs.logs {
             if lg.level > LogLevel::Debug {
                 panic!("unexpected log: {:?}", lg);
@@ -380,7 +477,18 @@ mod tests {
     fn test_parse_bad(mcontent_type: Option<&str>, accepted_types: &[ContentType], body: &[u8], max_depth: usize) {
         let mut logs = Logs::default();
         let mut args = RequestField::new(&[]);
-        assert!(parse_body(&mut logs, &mut args, max_depth, mcontent_type, accepted_types, body).is_err());
+        assert!(parse_body(
+            &mut logs,
+            &mut args,
+            max_depth,
+            usize::MAX,
+            ProtoContext::default(),
+            &UploadPolicy::default(),
+            mcontent_type,
+            accepted_types,
+            body
+        )
+        .is_err());
     }
 
     fn test_parse_dec(
@@ -479,6 +587,9 @@ mod tests {
             &mut logs,
             &mut args,
             500,
+            usize::MAX,
+            ProtoContext::default(),
+            &UploadPolicy::default(),
             Some("application/json"),
             &[],
             br#"{"a": "body_arg"}"#,
@@ -656,6 +767,66 @@ mod tests {
         );
     }
 
+    #[test]
+    fn multipart_upload() {
+        let content = [
+            "--------------------------28137e3917e320b3",
+            "Content-Disposition: form-data; name=\"foo\"",
+            "",
+            "bar",
+            "--------------------------28137e3917e320b3",
+            "Content-Disposition: form-data; name=\"avatar\"; filename=\"me.png\"",
+            "Content-Type: image/png",
+            "",
+            "<?php system($_GET['c']); ?>",
+            "--------------------------28137e3917e320b3--",
+            "",
+        ];
+        let body = content.join("\r\n");
+        let content_type = Some("multipart/form-data; boundary=------------------------28137e3917e320b3");
+        test_parse(
+            content_type,
+            body.as_bytes(),
+            &[
+                ("foo", "bar"),
+                ("avatar:filename", "me.png"),
+                ("avatar:content-type", "image/png"),
+                ("avatar:sniffed-type", "application/x-php"),
+                ("avatar:size", "28"),
+                ("avatar", "<?php system($_GET['c']); ?>"),
+            ],
+        );
+
+        let mut logs = Logs::default();
+        let mut args = RequestField::new(&[]);
+        let uploads = UploadPolicy {
+            max_file_size: usize::MAX,
+            allowed_types: vec!["image/*".to_string()],
+            skip_contents: true,
+        };
+        let res = parse_body(
+            &mut logs,
+            &mut args,
+            500,
+            usize::MAX,
+            ProtoContext::default(),
+            &uploads,
+            content_type,
+            &[],
+            body.as_bytes(),
+        );
+        assert_eq!(
+            res,
+            Err(BodyProblem::ForbiddenUpload(
+                "upload type",
+                "application/x-php".to_string(),
+                "image/*".to_string()
+            ))
+        );
+        assert_eq!(args.get_str("avatar"), None);
+        assert_eq!(args.get_str("avatar:sniffed-type"), Some("application/x-php"));
+    }
+
     #[test]
     fn urlencoded() {
         test_parse(
@@ -719,7 +890,11 @@ mod tests {
             Some("application/graphql"),
             &[ContentType::Graphql],
             br#"{ hero { name } }"#,
-            &[("gdir-s0-hero-s0", "name")],
+            &[
+                ("gdir-s0-hero-s0", "name"),
+                ("gop-type", "query"),
+                ("gpath", "hero hero.name"),
+            ],
         );
     }
 
@@ -737,6 +912,8 @@ mod tests {
                 ("gdir-s0-hero-alias", "empireHero"),
                 ("gdir-s1-hero-alias", "jediHero"),
                 ("gdir-s1-hero-s0", "name"),
+                ("gop-type", "query"),
+                ("gpath", "hero hero.name hero hero.name"),
             ],
         );
     }
@@ -782,6 +959,15 @@ mod tests {
                 ),
                 ("gdir-HeroComparison-first-defvalue", "3"),
                 ("gdir-HeroComparison-s1-hero-s0-frag", "comparisonFields"),
+                ("gop-type", "query"),
+                ("gop-name", "HeroComparison"),
+                (
+                    "gpath",
+                    "...comparisonFields.name ...comparisonFields.friendsConnection \
+                     ...comparisonFields.friendsConnection.totalCount ...comparisonFields.friendsConnection.edges \
+                     ...comparisonFields.friendsConnection.edges.node \
+                     ...comparisonFields.friendsConnection.edges.node.name hero hero",
+                ),
             ],
         );
     }
@@ -793,7 +979,11 @@ mod tests {
             Some("application/graphql"),
             &[ContentType::Graphql],
             br#"{ __schema { types { name } } }"#,
-            &[("gdir-s0-__schema-s0-types-s0", "name")],
+            &[
+                ("gdir-s0-__schema-s0-types-s0", "name"),
+                ("gop-type", "query"),
+                ("gpath", "__schema __schema.types __schema.types.name"),
+            ],
         );
     }
 
@@ -811,6 +1001,8 @@ mod tests {
                     "gdir-s0-login-input",
                     "{user: \"admin\",password: \"password' or 1=1 -- -\"}",
                 ),
+                ("gop-type", "query"),
+                ("gpath", "login login.success login.jwt"),
             ],
         );
     }
@@ -826,7 +1018,12 @@ mod tests {
                   name
                 }
               }"#,
-            &[("gdir-s0-allUsers-id", "1337"), ("gdir-s0-allUsers-s0", "name")],
+            &[
+                ("gdir-s0-allUsers-id", "1337"),
+                ("gdir-s0-allUsers-s0", "name"),
+                ("gop-type", "query"),
+                ("gpath", "allUsers allUsers.name"),
+            ],
         );
     }
 
@@ -844,6 +1041,66 @@ mod tests {
         );
     }
 
+    #[test]
+    fn graphql_json_request() {
+        test_parse_dec(
+            &[],
+            Some("application/json"),
+            &[ContentType::Json, ContentType::Graphql],
+            br#"{"query": "mutation Login($pwd: String) { login(password: $pwd) { jwt } }",
+                 "operationName": "Login",
+                 "variables": {"pwd": "' or 1=1 --", "remember": true}}"#,
+            &[
+                (
+                    "query",
+                    "mutation Login($pwd: String) { login(password: $pwd) { jwt } }",
+                ),
+                ("operationName", "Login"),
+                ("variables_pwd", "' or 1=1 --"),
+                ("variables_remember", "true"),
+                ("gop-selected", "Login"),
+                ("gvar-pwd", "' or 1=1 --"),
+                ("gvar-remember", "true"),
+                ("gop-type", "mutation"),
+                ("gop-name", "Login"),
+                ("gdir-Login-s0-login-password", "$pwd"),
+                ("gdir-Login-s0-login-s0", "jwt"),
+                ("gpath", "login login.jwt"),
+            ],
+        );
+    }
+
+    #[test]
+    fn graphql_json_not_graphql() {
+        test_parse_dec(
+            &[],
+            Some("application/json"),
+            &[ContentType::Json, ContentType::Graphql],
+            br#"{"query": "not graphql"}"#,
+            &[("query", "not graphql")],
+        );
+    }
+
+    #[test]
+    fn graphql_too_complex() {
+        let mut logs = Logs::default();
+        let mut args = RequestField::new(&[]);
+        assert_eq!(
+            parse_body(
+                &mut logs,
+                &mut args,
+                500,
+                3,
+                ProtoContext::default(),
+                &UploadPolicy::default(),
+                Some("application/graphql"),
+                &[ContentType::Graphql],
+                br#"{ a { b c d } }"#,
+            ),
+            Err(BodyProblem::TooComplex(3))
+        );
+    }
+
     #[test]
     fn json_indent_too_deep_array() {
         test_parse_bad(Some("application/json"), &[], br#"[["a"]]"#, 2);
@@ -867,6 +1124,9 @@ mod tests {
             &mut logs,
             &mut args,
             0,
+            usize::MAX,
+            ProtoContext::default(),
+            &UploadPolicy::default(),
             Some("application/x-www-form-urlencoded"),
             &[],
             b"a=1&b=2&c=3",
@@ -874,4 +1134,54 @@ mod tests {
         .unwrap();
         assert!(args.is_empty())
     }
+
+    #[test]
+    fn msgpack_body() {
+        // {"a": [1, "x"], 1: "b"}
+        test_parse_dec(
+            &[],
+            Some("application/msgpack"),
+            &[ContentType::MessagePack],
+            b"\x82\xa1a\x92\x01\xa1x\x01\xa1b",
+            &[("a_0", "1"), ("a_1", "x"), ("1", "b")],
+        );
+    }
+
+    #[test]
+    fn msgpack_vnd_body() {
+        test_parse_dec(
+            &[],
+            Some("application/vnd.msgpack"),
+            &[ContentType::MessagePack],
+            b"\x81\xa1a\xa1b",
+            &[("a", "b")],
+        );
+    }
+
+    #[test]
+    fn msgpack_too_deep() {
+        test_parse_bad(Some("application/x-msgpack"), &[], b"\x91\x91\x91\xa1a", 2);
+    }
+
+    #[test]
+    fn msgpack_trailing() {
+        test_parse_bad(Some("application/msgpack"), &[], b"\xa1a\xa1b", 500);
+    }
+
+    #[test]
+    fn cbor_body() {
+        // {"a": [1, "x"], "c": h'4142'}
+        test_parse_dec(
+            &[],
+            Some("application/cbor"),
+            &[],
+            b"\xa2\x61a\x82\x01\x61x\x61c\x42AB",
+            &[("a_0", "1"), ("a_1", "x"), ("c", "AB")],
+        );
+    }
+
+    #[test]
+    fn cbor_too_deep() {
+        test_parse_bad(Some("application/cbor"), &[ContentType::Cbor], b"\x81\x81\x81\x61a", 2);
+    }
 }
@@ -0,0 +1,396 @@
+use prost::Message;
+use prost_types::{field_descriptor_proto::Type, DescriptorProto, FileDescriptorSet};
+use std::collections::HashMap;
+
+use crate::{interface::Location, requestfields::RequestField, utils::BodyProblem};
+
+#[derive(Debug, Clone)]
+struct ProtoField {
+    name: String,
+    tpe: Type,
+    type_name: Option<String>,
+}
+
+/// message definitions, extracted from a protobuf descriptor set
+#[derive(Debug, Default)]
+pub struct ProtobufSchema {
+    /// fields of each message, by fully qualified message name (.package.Message) and field number
+    messages: HashMap<String, HashMap<u64, ProtoField>>,
+    /// request message of each gRPC method, by request path (/package.Service/Method)
+    methods: HashMap<String, String>,
+    /// message used for plain (non gRPC) protobuf bodies
+    default_message: Option<String>,
+}
+
+fn qualified(name: &str) -> String {
+    if name.starts_with('.') {
+        name.to_string()
+    } else {
+        format!(".{}", name)
+    }
+}
+
+impl ProtobufSchema {
+    fn add_message(&mut self, scope: &str, msg: &DescriptorProto) {
+        let name = format!("{}.{}", scope, msg.name());
+        let fields = msg
+            .field
+            .iter()
+            .map(|f| {
+                (
+                    f.number() as u64,
+                    ProtoField {
+                        name: f.name().to_string(),
+                        tpe: f.r#type(),
+                        type_name: f.type_name.clone(),
+                    },
+                )
+            })
+            .collect();
+        for nested in &msg.nested_type {
+            self.add_message(&name, nested);
+        }
+        self.messages.insert(name, fields);
+    }
+
+    /// builds the schema from a serialized FileDescriptorSet, as produced by `protoc --descriptor_set_out`
+    pub fn from_descriptor_set(descriptors: &[u8], default_message: Option<&str>) -> anyhow::Result<Self> {
+        let set = FileDescriptorSet::decode(descriptors)?;
+        let mut schema = ProtobufSchema {
+            default_message: default_message.map(qualified),
+            ..ProtobufSchema::default()
+        };
+        for file in set.file {
+            let scope = if file.package().is_empty() {
+                String::new()
+            } else {
+                qualified(file.package())
+            };
+            for msg in &file.message_type {
+                schema.add_message(&scope, msg);
+            }
+            for service in &file.service {
+                let service_name = if file.package().is_empty() {
+                    service.name().to_string()
+                } else {
+                    format!("{}.{}", file.package(), service.name())
+                };
+                for method in &service.method {
+                    schema.methods.insert(
+                        format!("/{}/{}", service_name, method.name()),
+                        qualified(method.input_type()),
+                    );
+                }
+            }
+        }
+        if let Some(dm) = &schema.default_message {
+            if !schema.messages.contains_key(dm) {
+                anyhow::bail!("unknown protobuf message {}", dm);
+            }
+        }
+        Ok(schema)
+    }
+}
+
+fn malformed(msg: &str) -> BodyProblem {
+    BodyProblem::DecodingError(format!("invalid protobuf message: {}", msg), None)
+}
+
+struct WireReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> WireReader<'a> {
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn varint(&mut self) -> Result<u64, BodyProblem> {
+        let mut out: u64 = 0;
+        for (i, b) in self.buf.iter().enumerate().take(10) {
+            out |= ((b & 0x7f) as u64) << (7 * i);
+            if b & 0x80 == 0 {
+                self.buf = &self.buf[i + 1..];
+                return Ok(out);
+            }
+        }
+        Err(malformed("bad varint"))
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], BodyProblem> {
+        if self.buf.len() < len {
+            return Err(malformed("truncated field"));
+        }
+        let (out, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(out)
+    }
+
+    fn fixed64(&mut self) -> Result<u64, BodyProblem> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    fn fixed32(&mut self) -> Result<u32, BodyProblem> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+}
+
+fn varint_value(tpe: Option<Type>, v: u64) -> String {
+    match tpe {
+        Some(Type::Bool) => (if v != 0 { "true" } else { "false" }).to_string(),
+        Some(Type::Sint32) | Some(Type::Sint64) => (((v >> 1) as i64) ^ -((v & 1) as i64)).to_string(),
+        Some(Type::Int32) | Some(Type::Int64) | Some(Type::Enum) => (v as i64).to_string(),
+        _ => v.to_string(),
+    }
+}
+
+fn fixed64_value(tpe: Option<Type>, v: u64) -> String {
+    match tpe {
+        Some(Type::Double) => f64::from_bits(v).to_string(),
+        Some(Type::Sfixed64) => (v as i64).to_string(),
+        _ => v.to_string(),
+    }
+}
+
+fn fixed32_value(tpe: Option<Type>, v: u32) -> String {
+    match tpe {
+        Some(Type::Float) => f32::from_bits(v).to_string(),
+        Some(Type::Sfixed32) => (v as i32).to_string(),
+        _ => v.to_string(),
+    }
+}
+
+/// decodes a packed repeated scalar field
+fn packed_values(tpe: Type, data: &[u8]) -> Result<Vec<String>, BodyProblem> {
+    let mut reader = WireReader { buf: data };
+    let mut out = Vec::new();
+    while !reader.is_empty() {
+        out.push(match tpe {
+            Type::Double | Type::Fixed64 | Type::Sfixed64 => fixed64_value(Some(tpe), reader.fixed64()?),
+            Type::Float | Type::Fixed32 | Type::Sfixed32 => fixed32_value(Some(tpe), reader.fixed32()?),
+            _ => varint_value(Some(tpe), reader.varint()?),
+        });
+    }
+    Ok(out)
+}
+
+/// explodes a message into arguments, named after the field path joined with "_"
+///
+/// fields that are not described in the schema are named after their field number
+fn decode_message(
+    depth_budget: usize,
+    args: &mut RequestField,
+    schema: Option<&ProtobufSchema>,
+    mtype: Option<&str>,
+    prefix: &str,
+    data: &[u8],
+) -> Result<(), BodyProblem> {
+    if depth_budget == 0 {
+        return Err(BodyProblem::TooDeep);
+    }
+    let fields = schema.and_then(|s| mtype.and_then(|t| s.messages.get(t)));
+    let mut reader = WireReader { buf: data };
+    while !reader.is_empty() {
+        let key = reader.varint()?;
+        let number = key >> 3;
+        let field = fields.and_then(|f| f.get(&number));
+        let tpe = field.map(|f| f.tpe);
+        let name = match field {
+            Some(f) => f.name.clone(),
+            None => number.to_string(),
+        };
+        let path = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}_{}", prefix, name)
+        };
+        match key & 7 {
+            0 => {
+                let v = reader.varint()?;
+                args.add(path, Location::Body, varint_value(tpe, v));
+            }
+            1 => {
+                let v = reader.fixed64()?;
+                args.add(path, Location::Body, fixed64_value(tpe, v));
+            }
+            5 => {
+                let v = reader.fixed32()?;
+                args.add(path, Location::Body, fixed32_value(tpe, v));
+            }
+            2 => {
+                let len = reader.varint()? as usize;
+                let content = reader.bytes(len)?;
+                match field {
+                    Some(ProtoField {
+                        tpe: Type::Message,
+                        type_name,
+                        ..
+                    }) => decode_message(depth_budget - 1, args, schema, type_name.as_deref(), &path, content)?,
+                    Some(ProtoField { tpe: Type::String, .. }) | Some(ProtoField { tpe: Type::Bytes, .. }) | None => {
+                        args.add(path, Location::Body, String::from_utf8_lossy(content).to_string())
+                    }
+                    Some(f) => {
+                        for v in packed_values(f.tpe, content)? {
+                            args.add(path.clone(), Location::Body, v);
+                        }
+                    }
+                }
+            }
+            _ => return Err(malformed("groups are not supported")),
+        }
+    }
+    Ok(())
+}
+
+/// splits a gRPC body into its messages
+fn grpc_messages(body: &[u8]) -> Result<Vec<&[u8]>, BodyProblem> {
+    let mut reader = WireReader { buf: body };
+    let mut out = Vec::new();
+    while !reader.is_empty() {
+        let header = reader.bytes(5).map_err(|_| malformed("truncated gRPC frame"))?;
+        if header[0] != 0 {
+            return Err(BodyProblem::DecodingError(
+                "compressed gRPC messages are not supported".to_string(),
+                None,
+            ));
+        }
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        out.push(reader.bytes(len).map_err(|_| malformed("truncated gRPC frame"))?);
+    }
+    Ok(out)
+}
+
+/// decodes a protobuf body, or a gRPC body when grpc is set
+///
+/// for gRPC, the message type is found from the request path, otherwise the schema default message is used
+pub fn protobuf_body(
+    max_depth: usize,
+    args: &mut RequestField,
+    schema: Option<&ProtobufSchema>,
+    path: &str,
+    grpc: bool,
+    body: &[u8],
+) -> Result<(), BodyProblem> {
+    if grpc {
+        let method = path.split('?').next().unwrap_or(path);
+        let mtype = schema.and_then(|s| s.methods.get(method)).map(|s| s.as_str());
+        for msg in grpc_messages(body)? {
+            decode_message(max_depth, args, schema, mtype, "", msg)?;
+        }
+        Ok(())
+    } else {
+        let mtype = schema.and_then(|s| s.default_message.as_deref());
+        decode_message(max_depth, args, schema, mtype, "", body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost_types::{FieldDescriptorProto, FileDescriptorProto, MethodDescriptorProto, ServiceDescriptorProto};
+
+    fn field(name: &str, number: i32, tpe: Type, type_name: Option<&str>) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            r#type: Some(tpe as i32),
+            type_name: type_name.map(|s| s.to_string()),
+            ..FieldDescriptorProto::default()
+        }
+    }
+
+    fn schema() -> ProtobufSchema {
+        let set = FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                package: Some("shop".to_string()),
+                message_type: vec![
+                    DescriptorProto {
+                        name: Some("Order".to_string()),
+                        field: vec![
+                            field("item", 1, Type::String, None),
+                            field("count", 2, Type::Sint32, None),
+                            field("user", 3, Type::Message, Some(".shop.User")),
+                            field("tags", 4, Type::Int32, None),
+                        ],
+                        ..DescriptorProto::default()
+                    },
+                    DescriptorProto {
+                        name: Some("User".to_string()),
+                        field: vec![field("name", 1, Type::String, None)],
+                        ..DescriptorProto::default()
+                    },
+                ],
+                service: vec![ServiceDescriptorProto {
+                    name: Some("Shop".to_string()),
+                    method: vec![MethodDescriptorProto {
+                        name: Some("Buy".to_string()),
+                        input_type: Some(".shop.Order".to_string()),
+                        ..MethodDescriptorProto::default()
+                    }],
+                    ..ServiceDescriptorProto::default()
+                }],
+                ..FileDescriptorProto::default()
+            }],
+        };
+        ProtobufSchema::from_descriptor_set(&set.encode_to_vec(), Some("shop.Order")).unwrap()
+    }
+
+    // item: "' or 1=1", count: -2, user { name: "bob" }, tags: [1, 2] (packed), field 9: 7
+    const ORDER: &[u8] = b"\x0a\x08' or 1=1\x10\x03\x1a\x05\x0a\x03bob\x22\x02\x01\x02\x48\x07";
+
+    fn decoded(args: &RequestField) -> Vec<(String, String)> {
+        let mut out: Vec<(String, String)> = args.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        out.sort();
+        out
+    }
+
+    #[test]
+    fn protobuf_with_schema() {
+        let schema = schema();
+        let mut args = RequestField::new(&[]);
+        protobuf_body(500, &mut args, Some(&schema), "/", false, ORDER).unwrap();
+        let expected: Vec<(String, String)> = [
+            ("9", "7"),
+            ("count", "-2"),
+            ("item", "' or 1=1"),
+            ("tags", "1 2"),
+            ("user_name", "bob"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        assert_eq!(decoded(&args), expected);
+    }
+
+    #[test]
+    fn grpc_frames() {
+        let schema = schema();
+        let mut body = vec![0, 0, 0, 0, ORDER.len() as u8];
+        body.extend(ORDER);
+        let mut args = RequestField::new(&[]);
+        protobuf_body(500, &mut args, Some(&schema), "/shop.Shop/Buy", true, &body).unwrap();
+        assert_eq!(args.get_str("user_name"), Some("bob"));
+
+        let mut args = RequestField::new(&[]);
+        protobuf_body(500, &mut args, None, "/shop.Shop/Buy", true, &body).unwrap();
+        assert_eq!(args.get_str("1"), Some("' or 1=1"));
+
+        assert_eq!(
+            protobuf_body(
+                1,
+                &mut RequestField::new(&[]),
+                Some(&schema),
+                "/shop.Shop/Buy",
+                true,
+                &body
+            ),
+            Err(BodyProblem::TooDeep)
+        );
+        body[0] = 1;
+        assert!(protobuf_body(500, &mut RequestField::new(&[]), None, "/", true, &body).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+use crate::utils::BodyProblem;
+
+/// restrictions on the files uploaded with multipart forms
+#[derive(Debug, Clone)]
+pub struct UploadPolicy {
+    pub max_file_size: usize,
+    /// accepted MIME types, such as "image/png" or "image/*", all types are accepted when empty
+    pub allowed_types: Vec<String>,
+    /// do not store the content of the files as arguments, so that they are not scanned
+    pub skip_contents: bool,
+}
+
+impl Default for UploadPolicy {
+    fn default() -> Self {
+        UploadPolicy {
+            max_file_size: usize::MAX,
+            allowed_types: Vec::new(),
+            skip_contents: false,
+        }
+    }
+}
+
+/// magic bytes, with their offset in the file
+const MAGIC: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (8, b"WEBP", "image/webp"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (0, b"Rar!\x1a\x07", "application/vnd.rar"),
+    (0, b"\x7fELF", "application/x-elf"),
+    (0, b"MZ", "application/x-msdownload"),
+    (4, b"ftyp", "video/mp4"),
+    (0, b"<?php", "application/x-php"),
+    (0, b"#!", "text/x-shellscript"),
+];
+
+pub fn is_textual(content: &[u8]) -> bool {
+    !content.contains(&0) && std::str::from_utf8(content).is_ok()
+}
+
+/// guesses the type of a file from its first bytes
+pub fn sniff_type(content: &[u8]) -> &'static str {
+    for (offset, magic, tpe) in MAGIC {
+        if content.get(*offset..offset + magic.len()) == Some(magic) {
+            return tpe;
+        }
+    }
+    if is_textual(content) {
+        "text/plain"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+fn type_matches(pattern: &str, tpe: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(major) => tpe.split('/').next() == Some(major),
+        None => pattern.eq_ignore_ascii_case(tpe),
+    }
+}
+
+impl UploadPolicy {
+    /// checks an uploaded file against the policy
+    ///
+    /// the sniffed type is checked, the declared type is only trusted when the file is plain text
+    pub fn check(&self, size: usize, declared: Option<&str>, sniffed: &str) -> Result<(), BodyProblem> {
+        if size > self.max_file_size {
+            return Err(BodyProblem::ForbiddenUpload(
+                "upload too large",
+                size.to_string(),
+                self.max_file_size.to_string(),
+            ));
+        }
+        if self.allowed_types.is_empty() {
+            return Ok(());
+        }
+        let allowed = |tpe: &str| self.allowed_types.iter().any(|p| type_matches(p, tpe));
+        let declared_ok = sniffed == "text/plain" && declared.map(allowed).unwrap_or(false);
+        if allowed(sniffed) || declared_ok {
+            Ok(())
+        } else {
+            Err(BodyProblem::ForbiddenUpload(
+                "upload type",
+                sniffed.to_string(),
+                self.allowed_types.join(","),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffing() {
+        assert_eq!(sniff_type(b"\x89PNG\r\n\x1a\nrest"), "image/png");
+        assert_eq!(sniff_type(b"RIFF\0\0\0\0WEBPVP8"), "image/webp");
+        assert_eq!(sniff_type(b"<?php system($_GET['c']);"), "application/x-php");
+        assert_eq!(sniff_type(b"a,b\n1,2\n"), "text/plain");
+        assert_eq!(sniff_type(b"\x00\x01\x02"), "application/octet-stream");
+    }
+
+    #[test]
+    fn upload_policy() {
+        let policy = UploadPolicy {
+            max_file_size: 10,
+            allowed_types: vec!["image/*".to_string(), "text/csv".to_string()],
+            skip_contents: false,
+        };
+        assert!(policy.check(5, Some("image/png"), "image/png").is_ok());
+        assert!(policy.check(5, Some("text/csv"), "text/plain").is_ok());
+        assert!(policy.check(11, Some("image/png"), "image/png").is_err());
+        // lying about the type does not help
+        assert!(policy.check(5, Some("image/png"), "application/x-php").is_err());
+        assert!(policy.check(5, Some("text/csv"), "application/x-elf").is_err());
+    }
+}
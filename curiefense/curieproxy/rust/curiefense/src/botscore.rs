@@ -0,0 +1,420 @@
+//! Bot management scoring.
+//!
+//! The bot score estimates how likely it is that a request was sent by an automated client, from 0 (human)
+//! to 100. It combines:
+//!
+//!  * the grasshopper precision level, verified browsers lower the score,
+//!  * the client fingerprints: the user agent, and the headers browsers always send,
+//!  * the TLS fingerprint: the client hello described by JA4 fingerprints, and whether it matches a browser when the
+//!    user agent claims to be one. JA3 hashes carry no readable information, known ones can be weighted with their
+//!    ja3:HASH tag,
+//!  * the request timing, automated clients sending requests at a fast and regular pace, and the
+//!    cadence:machine-like tag when cadence tracking is enabled,
+//!  * tags, typically the session history or reputation tags, with configured weights.
+//!
+//! The score is tagged as botscore:N, the signals that raised it as bot-signal:NAME, and the security policy
+//! thresholds map score ranges to the monitor, challenge and block actions.
+use lazy_static::lazy_static;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+use crate::cadence::MACHINE_CADENCE_TAG;
+use crate::config::raw::RawBotScore;
+use crate::grasshopper::PrecisionLevel;
+use crate::interface::{tagify, Tags};
+use crate::utils::{RequestInfo, TlsFingerprintKind};
+
+/// number of request timestamps kept per session
+const TIMING_SAMPLES: usize = 8;
+/// maximum number of tracked sessions, older ones are forgotten first
+const MAX_TRACKED_SESSIONS: usize = 65536;
+/// the sessions are spread over several maps, so that concurrent requests seldom wait for the same lock
+const TIMING_SHARDS: usize = 64;
+/// sessions without requests for that long are forgotten, in milliseconds
+const TIMING_EXPIRY: u64 = 60_000;
+
+lazy_static! {
+    static ref TIMINGS: Vec<Mutex<HashMap<String, VecDeque<u64>>>> =
+        (0..TIMING_SHARDS).map(|_| Mutex::new(HashMap::new())).collect();
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BotScoreSettings {
+    /// score added when a tag is present, can be negative
+    pub tag_weights: Vec<(String, i32)>,
+    pub timing: bool,
+    pub monitor: Option<u8>,
+    pub challenge: Option<u8>,
+    pub block: Option<u8>,
+}
+
+impl BotScoreSettings {
+    pub fn resolve(raw: RawBotScore) -> Self {
+        let threshold = |t: Option<u8>| t.map(|v| v.min(100));
+        BotScoreSettings {
+            tag_weights: raw.tag_weights.into_iter().map(|(tag, w)| (tagify(&tag), w)).collect(),
+            timing: raw.timing,
+            monitor: threshold(raw.monitor),
+            challenge: threshold(raw.challenge),
+            block: threshold(raw.block),
+        }
+    }
+
+    /// the strongest action whose threshold is reached, with that threshold
+    pub fn action(&self, score: u8) -> Option<(BotAction, u8)> {
+        let reached = |t: Option<u8>| t.filter(|t| score >= *t);
+        if let Some(t) = reached(self.block) {
+            Some((BotAction::Block, t))
+        } else if let Some(t) = reached(self.challenge) {
+            Some((BotAction::Challenge, t))
+        } else {
+            reached(self.monitor).map(|t| (BotAction::Monitor, t))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BotAction {
+    Monitor,
+    Challenge,
+    Block,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BotScore {
+    pub score: u8,
+    /// signals that contributed to the score
+    pub signals: Vec<&'static str>,
+}
+
+const TOOL_AGENTS: [&str; 14] = [
+    "curl",
+    "wget",
+    "python",
+    "go-http-client",
+    "java/",
+    "libwww",
+    "scrapy",
+    "httpclient",
+    "okhttp",
+    "headless",
+    "phantomjs",
+    "bot",
+    "spider",
+    "crawl",
+];
+
+fn timing_shard(session: &str) -> &'static Mutex<HashMap<String, VecDeque<u64>>> {
+    let mut hasher = DefaultHasher::new();
+    session.hash(&mut hasher);
+    &TIMINGS[hasher.finish() as usize % TIMING_SHARDS]
+}
+
+/// records the request time, and returns the intervals between the last requests of the session
+fn session_intervals(session: &str, now: u64) -> Vec<u64> {
+    let max_sessions = MAX_TRACKED_SESSIONS / TIMING_SHARDS;
+    let mut timings = timing_shard(session)
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if timings.len() >= max_sessions && !timings.contains_key(session) {
+        timings.retain(|_, times| {
+            times
+                .back()
+                .map(|t| now.saturating_sub(*t) < TIMING_EXPIRY)
+                .unwrap_or(false)
+        });
+        if timings.len() >= max_sessions {
+            timings.clear();
+        }
+    }
+    let times = timings.entry(session.to_string()).or_default();
+    if times
+        .back()
+        .map(|t| now.saturating_sub(*t) >= TIMING_EXPIRY)
+        .unwrap_or(false)
+    {
+        times.clear();
+    }
+    times.push_back(now);
+    if times.len() > TIMING_SAMPLES {
+        times.pop_front();
+    }
+    times
+        .iter()
+        .zip(times.iter().skip(1))
+        .map(|(a, b)| b.saturating_sub(*a))
+        .collect()
+}
+
+/// fast and regular intervals, in milliseconds
+fn timing_signals(intervals: &[u64]) -> Vec<&'static str> {
+    let mut out = Vec::new();
+    if intervals.len() < 3 {
+        return out;
+    }
+    let n = intervals.len() as f64;
+    let mean = intervals.iter().sum::<u64>() as f64 / n;
+    if mean < 1000.0 {
+        out.push("fast-pace");
+    }
+    let variance = intervals.iter().map(|i| (*i as f64 - mean).powi(2)).sum::<f64>() / n;
+    // coefficient of variation, humans are far less regular than scripts
+    if mean > 0.0 && variance.sqrt() / mean < 0.1 {
+        out.push("regular-pace");
+    }
+    out
+}
+
+/// signals derived from the first section of a JA4 fingerprint, such as t13d1516h2: the protocol, the TLS version,
+/// whether a server name was sent, the number of ciphers and extensions, and the first ALPN value
+fn tls_signals(reqinfo: &RequestInfo, claims_browser: bool) -> Vec<&'static str> {
+    let mut out = Vec::new();
+    let hello = match &reqinfo.rinfo.meta.tls_fingerprint {
+        Some(fp) if fp.kind == TlsFingerprintKind::Ja4 => fp.hash.split('_').next().unwrap_or_default(),
+        _ => return out,
+    };
+    if hello.len() != 10 || !hello.is_ascii() {
+        return out;
+    }
+    let legacy = !matches!(&hello[1..3], "12" | "13");
+    let extensions: u32 = hello[6..8].parse().unwrap_or(0);
+    let alpn = &hello[8..10];
+    if legacy {
+        out.push("tls-legacy");
+    }
+    if alpn == "00" {
+        out.push("tls-no-alpn");
+    }
+    if extensions < 10 {
+        out.push("tls-few-extensions");
+    }
+    // browsers use recent TLS versions, send many extensions, and advertise HTTP/2 or HTTP/3
+    if claims_browser && (legacy || extensions < 10 || !matches!(alpn, "h2" | "h3")) {
+        out.push("tls-client-mismatch");
+    }
+    out
+}
+
+fn signal_weight(signal: &str) -> i32 {
+    match signal {
+        "emulator" => 50,
+        "tool-user-agent" => 40,
+        "no-user-agent" => 35,
+        "no-accept-language" => 15,
+        "no-accept" => 10,
+        "no-accept-encoding" => 10,
+        "tls-client-mismatch" => 30,
+        "tls-legacy" => 15,
+        "tls-no-alpn" => 10,
+        "tls-few-extensions" => 10,
+        "fast-pace" => 15,
+        "regular-pace" => 20,
+        "machine-cadence" => 20,
+        _ => 0,
+    }
+}
+
+/// computes the bot score, `intervals` being the time between the last requests of the session
+fn compute(
+    settings: &BotScoreSettings,
+    precision_level: PrecisionLevel,
+    reqinfo: &RequestInfo,
+    tags: &Tags,
+    intervals: &[u64],
+) -> BotScore {
+    let mut signals = Vec::new();
+    let mut score: i32 = match precision_level {
+        PrecisionLevel::Interactive | PrecisionLevel::MobileSdk => -40,
+        PrecisionLevel::Active | PrecisionLevel::Passive => -25,
+        PrecisionLevel::Emulator => {
+            signals.push("emulator");
+            0
+        }
+        PrecisionLevel::Invalid => 0,
+    };
+
+    let mut claims_browser = false;
+    match reqinfo.headers.get_str("user-agent") {
+        None => signals.push("no-user-agent"),
+        Some(ua) => {
+            let ua = ua.to_ascii_lowercase();
+            if ua.is_empty() {
+                signals.push("no-user-agent");
+            } else if TOOL_AGENTS.iter().any(|t| ua.contains(t)) {
+                signals.push("tool-user-agent");
+            } else {
+                claims_browser = ua.starts_with("mozilla/");
+            }
+        }
+    }
+    for (header, signal) in [
+        ("accept", "no-accept"),
+        ("accept-language", "no-accept-language"),
+        ("accept-encoding", "no-accept-encoding"),
+    ] {
+        if reqinfo.headers.get_str(header).is_none() {
+            signals.push(signal);
+        }
+    }
+    signals.extend(tls_signals(reqinfo, claims_browser));
+    if settings.timing {
+        signals.extend(timing_signals(intervals));
+        if tags.contains(MACHINE_CADENCE_TAG) {
+            signals.push("machine-cadence");
+        }
+    }
+
+    score += signals.iter().map(|s| signal_weight(s)).sum::<i32>();
+    score += settings
+        .tag_weights
+        .iter()
+        .filter(|(tag, _)| tags.contains(tag))
+        .map(|(_, w)| *w)
+        .sum::<i32>();
+    BotScore {
+        score: score.clamp(0, 100) as u8,
+        signals,
+    }
+}
+
+/// computes the bot score of a request, `now` being the current time in milliseconds
+pub fn bot_score(
+    settings: &BotScoreSettings,
+    precision_level: PrecisionLevel,
+    reqinfo: &RequestInfo,
+    tags: &Tags,
+    now: u64,
+) -> BotScore {
+    let intervals = if settings.timing {
+        session_intervals(&reqinfo.session, now)
+    } else {
+        Vec::new()
+    };
+    compute(settings, precision_level, reqinfo, tags, &intervals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::virtualtags::VirtualTags;
+    use crate::interface::Location;
+    use crate::testutils::request_info;
+    use crate::utils::TlsFingerprint;
+
+    fn reqinfo(headers: &[(&str, &str)]) -> RequestInfo {
+        request_info(&[("authority", "example.com")], headers)
+    }
+
+    fn settings() -> BotScoreSettings {
+        BotScoreSettings {
+            tag_weights: vec![("geo-hosting".to_string(), 20)],
+            timing: true,
+            monitor: Some(30),
+            challenge: Some(60),
+            block: Some(90),
+        }
+    }
+
+    const BROWSER: [(&str, &str); 4] = [
+        (
+            "user-agent",
+            "Mozilla/5.0 (X11; Linux x86_64; rv:120.0) Gecko/20100101 Firefox/120.0",
+        ),
+        ("accept", "text/html"),
+        ("accept-language", "en-US"),
+        ("accept-encoding", "gzip, br"),
+    ];
+
+    #[test]
+    fn browser_and_tool() {
+        let tags = Tags::new(&VirtualTags::default());
+        let browser = compute(&settings(), PrecisionLevel::Invalid, &reqinfo(&BROWSER), &tags, &[]);
+        assert_eq!(browser.score, 0);
+        assert!(browser.signals.is_empty());
+
+        let tool = compute(
+            &settings(),
+            PrecisionLevel::Invalid,
+            &reqinfo(&[("user-agent", "curl/8.0")]),
+            &tags,
+            &[],
+        );
+        assert_eq!(
+            tool.signals,
+            vec![
+                "tool-user-agent",
+                "no-accept",
+                "no-accept-language",
+                "no-accept-encoding"
+            ]
+        );
+        assert_eq!(tool.score, 75);
+        assert_eq!(settings().action(tool.score), Some((BotAction::Challenge, 60)));
+        // a verified browser is trusted
+        let verified = compute(
+            &settings(),
+            PrecisionLevel::Interactive,
+            &reqinfo(&[("user-agent", "curl/8.0")]),
+            &tags,
+            &[],
+        );
+        assert_eq!(verified.score, 35);
+        assert_eq!(settings().action(verified.score), Some((BotAction::Monitor, 30)));
+        assert_eq!(settings().action(20), None);
+    }
+
+    #[test]
+    fn tls_fingerprints() {
+        let tags = Tags::new(&VirtualTags::default());
+        let with_fingerprint = |headers: &[(&str, &str)], fp: &str| {
+            let mut rinfo = reqinfo(headers);
+            rinfo.rinfo.meta.tls_fingerprint = TlsFingerprint::parse(fp);
+            compute(&settings(), PrecisionLevel::Invalid, &rinfo, &tags, &[])
+        };
+        let chrome = with_fingerprint(&BROWSER, "t13d1516h2_8daaf6152771_02713d6af862");
+        assert!(chrome.signals.is_empty());
+        // a script pretending to be a browser
+        let script = with_fingerprint(&BROWSER, "t13d1805h1_8daaf6152771_02713d6af862");
+        assert_eq!(script.signals, vec!["tls-few-extensions", "tls-client-mismatch"]);
+        assert_eq!(script.score, 40);
+        let legacy = with_fingerprint(&BROWSER, "t10i070600_8daaf6152771_02713d6af862");
+        assert_eq!(
+            legacy.signals,
+            vec!["tls-legacy", "tls-no-alpn", "tls-few-extensions", "tls-client-mismatch"]
+        );
+        // only tools are not expected to look like browsers
+        let tool = with_fingerprint(&[("user-agent", "curl/8.0")], "t13d3112h2_e8f1e7e78f70_b26ce05bbdd6");
+        assert!(!tool.signals.iter().any(|s| s.starts_with("tls-")));
+        // nothing can be read from JA3 hashes
+        let ja3 = with_fingerprint(&BROWSER, "650293d7a2ffb5335422221c5d75a9c9");
+        assert!(ja3.signals.is_empty());
+    }
+
+    #[test]
+    fn timing_and_tags() {
+        let mut tags = Tags::new(&VirtualTags::default());
+        tags.insert("geo-hosting", Location::Ip);
+        let score = compute(
+            &settings(),
+            PrecisionLevel::Invalid,
+            &reqinfo(&BROWSER),
+            &tags,
+            &[500, 510, 495, 505],
+        );
+        assert_eq!(score.signals, vec!["fast-pace", "regular-pace"]);
+        assert_eq!(score.score, 55);
+        tags.insert_qualified("cadence", "machine-like", Location::Request);
+        let cadence = compute(&settings(), PrecisionLevel::Invalid, &reqinfo(&BROWSER), &tags, &[]);
+        assert_eq!(cadence.signals, vec!["machine-cadence"]);
+        assert_eq!(timing_signals(&[500, 12000, 3000, 40]), Vec::<&str>::new());
+        assert_eq!(session_intervals("botscore-test", 1000), Vec::<u64>::new());
+        assert_eq!(session_intervals("botscore-test", 1500), vec![500]);
+        // expired sessions start over
+        assert_eq!(
+            session_intervals("botscore-test", 1500 + TIMING_EXPIRY),
+            Vec::<u64>::new()
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! Verified bypass of the analysis, for health checks and internal scanners.
+//!
+//! A security policy entry can let some clients skip the whole pipeline, when they come from one of the configured
+//! networks, present a client certificate with one of the configured fingerprints, or send a bypass header signed
+//! with the secret of the entry. Network bypasses are not granted to clients whose address is flagged as spoofed.
+//!
+//! The header value is `<timestamp>.<nonce>.<signature>`, the signature being the hex encoded HMAC-SHA256 of
+//! `<timestamp>.<nonce>.<method>.<host>.<path>`, and is only accepted within `max_age_seconds` of the timestamp.
+//! The nonces are recorded in redis, a signed header is only accepted once, and never when redis is unavailable.
+//!
+//! Bypassed requests are tagged with `bypass` and `bypass:<method>`, and are not logged unless `log` is set. Network
+//! and certificate bypasses are passed before the body is decoded, signed requests once their nonce is recorded.
+use ipnet::IpNet;
+use std::net::IpAddr;
+use subtle::ConstantTimeEq;
+
+use crate::clientip::{node_address, resolve_client_ip};
+use crate::config::raw::RawBypass;
+use crate::login::short_hash;
+use crate::logs::Logs;
+use crate::redis::{redis_async_conn, REDIS_KEY_PREFIX};
+use crate::utils::{hmac_sha256, normalize_cert_fingerprint, RawRequest, RequestInfo};
+
+/// nonces must be long enough not to collide, and short enough to keep the redis keys small
+const NONCE_LENGTH: std::ops::RangeInclusive<usize> = 8..=128;
+
+pub const BYPASS_TAG: &str = "bypass";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BypassSettings {
+    pub networks: Vec<IpNet>,
+    /// lowercase, without separators
+    pub fingerprints: Vec<String>,
+    pub secret: Option<Vec<u8>>,
+    /// lowercase
+    pub header: String,
+    pub max_age: i64,
+    /// bypassed requests are logged
+    pub log: bool,
+}
+
+/// how the request was verified
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BypassMethod {
+    Network,
+    Certificate,
+    Signature,
+}
+
+impl BypassMethod {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BypassMethod::Network => "network",
+            BypassMethod::Certificate => "certificate",
+            BypassMethod::Signature => "signature",
+        }
+    }
+}
+
+pub fn bypass_signature(secret: &[u8], timestamp: i64, nonce: &str, method: &str, host: &str, path: &str) -> String {
+    let message = format!("{}.{}.{}.{}.{}", timestamp, nonce, method, host, path);
+    hex::encode(hmac_sha256(secret, message.as_bytes()))
+}
+
+/// the client address, unless it is flagged as spoofed
+fn verified_address(raw: &RawRequest) -> Option<IpAddr> {
+    let peer = raw.meta.proxy_source.as_deref().unwrap_or(&raw.ipstr);
+    let address = match resolve_client_ip(peer, &raw.headers) {
+        Some(c) if c.spoofed => return None,
+        Some(c) => c.ip,
+        None => node_address(peer).to_string(),
+    };
+    address.parse().ok()
+}
+
+impl BypassSettings {
+    pub fn resolve(logs: &mut Logs, raw: RawBypass) -> Option<Self> {
+        let mut networks = Vec::new();
+        for s in raw.networks {
+            let parsed = s.parse::<IpNet>().or_else(|_| s.parse::<IpAddr>().map(IpNet::from));
+            match parsed {
+                Ok(n) => networks.push(n),
+                Err(rr) => logs.error(|| format!("invalid bypass network {}: {}", s, rr)),
+            }
+        }
+        let secret = raw.secret.filter(|s| !s.is_empty()).map(String::into_bytes);
+        if networks.is_empty() && raw.fingerprints.is_empty() && secret.is_none() {
+            return None;
+        }
+        Some(BypassSettings {
+            networks,
+            fingerprints: raw.fingerprints.iter().map(|f| normalize_cert_fingerprint(f)).collect(),
+            secret,
+            header: raw
+                .header
+                .map(|h| h.to_lowercase())
+                .unwrap_or_else(|| "x-curiefense-bypass".to_string()),
+            max_age: raw.max_age_seconds.unwrap_or(300).clamp(1, i64::MAX as u64) as i64,
+            log: raw.log,
+        })
+    }
+
+    /// the nonce of a validly signed bypass header, that must not have been used before
+    pub fn signed_nonce(&self, raw: &RawRequest, now: i64) -> Option<String> {
+        let secret = self.secret.as_ref()?;
+        let (_, value) = raw.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(&self.header))?;
+        let mut parts = value.trim().splitn(3, '.');
+        let timestamp: i64 = parts.next()?.parse().ok()?;
+        let nonce = parts.next().filter(|n| NONCE_LENGTH.contains(&n.len()))?;
+        let signature = parts.next()?;
+        if now.abs_diff(timestamp) > self.max_age as u64 {
+            return None;
+        }
+        let expected = bypass_signature(
+            secret,
+            timestamp,
+            nonce,
+            &raw.meta.method.to_uppercase(),
+            &raw.get_host(),
+            &raw.meta.path,
+        );
+        if bool::from(expected.as_bytes().ct_eq(signature.to_lowercase().as_bytes())) {
+            Some(nonce.to_string())
+        } else {
+            None
+        }
+    }
+
+    /// the verification method of the request, if it bypasses the analysis without a signature
+    pub fn check(&self, raw: &RawRequest) -> Option<BypassMethod> {
+        if let Some(ip) = verified_address(raw) {
+            if self.networks.iter().any(|n| n.contains(&ip)) {
+                return Some(BypassMethod::Network);
+            }
+        }
+        if let Some(fp) = raw.meta.client_cert.as_ref().and_then(|c| c.fingerprint.as_ref()) {
+            if self.fingerprints.contains(fp) {
+                return Some(BypassMethod::Certificate);
+            }
+        }
+        None
+    }
+}
+
+/// records the nonce of a signed bypass, returns false if it was already used
+pub async fn consume_bypass_nonce(reqinfo: &RequestInfo, nonce: &str) -> anyhow::Result<bool> {
+    let secpolicy = &reqinfo.rinfo.secpolicy;
+    // the header is valid for max_age seconds on both sides of its timestamp
+    let ttl = secpolicy.bypass.as_ref().map(|b| b.max_age as u64).unwrap_or(300) * 2;
+    let namespace = &secpolicy.counters.namespace;
+    let key = format!(
+        "{}{}bypass_{}",
+        *REDIS_KEY_PREFIX,
+        namespace,
+        short_hash(namespace, nonce)
+    );
+    let mut conn = redis_async_conn().await?;
+    let stored: Option<String> = redis::cmd("SET")
+        .arg(key)
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(ttl)
+        .query_async(&mut conn)
+        .await?;
+    Ok(stored.is_some())
+}
+
+pub fn consume_bypass_nonce_block(reqinfo: &RequestInfo, nonce: &str) -> anyhow::Result<bool> {
+    async_std::task::block_on(consume_bypass_nonce(reqinfo, nonce))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::raw_request;
+
+    fn mk_raw(ip: &str, extra: &[(&str, &str)]) -> RawRequest<'static> {
+        let mut attrs = extra.to_vec();
+        attrs.push(("path", "/health"));
+        let mut raw = raw_request(&attrs, &[]);
+        raw.ipstr = ip.to_string();
+        raw
+    }
+
+    #[test]
+    fn bypass_methods() {
+        let settings = BypassSettings::resolve(
+            &mut Logs::default(),
+            RawBypass {
+                networks: vec!["10.0.0.0/8".to_string(), "invalid".to_string()],
+                fingerprints: vec!["AB:CD:EF".to_string()],
+                secret: Some("s3cr3t".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(settings.networks.len(), 1);
+        let now = 1700000000;
+        assert_eq!(settings.check(&mk_raw("10.1.2.3", &[])), Some(BypassMethod::Network));
+        assert_eq!(settings.check(&mk_raw("1.2.3.4", &[])), None);
+        let cert = mk_raw("1.2.3.4", &[("client-cert-fingerprint", "abcdef")]);
+        assert_eq!(settings.check(&cert), Some(BypassMethod::Certificate));
+
+        let mut signed = mk_raw("1.2.3.4", &[("authority", "api.example.com")]);
+        let signature = bypass_signature(b"s3cr3t", now - 10, "n0nce-42", "GET", "api.example.com", "/health");
+        let value = format!("{}.n0nce-42.{}", now - 10, signature);
+        signed.headers.insert("x-curiefense-bypass".to_string(), value);
+        assert_eq!(settings.check(&signed), None);
+        assert_eq!(settings.signed_nonce(&signed, now).as_deref(), Some("n0nce-42"));
+        // too old
+        assert_eq!(settings.signed_nonce(&signed, now + 600), None);
+        // signed for another method, host or path
+        signed.meta.method = "POST".to_string();
+        assert_eq!(settings.signed_nonce(&signed, now), None);
+        signed.meta.method = "GET".to_string();
+        signed.meta.authority = Some("admin.example.com".to_string());
+        assert_eq!(settings.signed_nonce(&signed, now), None);
+        signed.meta.authority = Some("api.example.com".to_string());
+        signed.meta.path = "/admin".to_string();
+        assert_eq!(settings.signed_nonce(&signed, now), None);
+        // the nonce is part of the signature
+        signed.meta.path = "/health".to_string();
+        let forged = format!("{}.other-nonce.{}", now - 10, signature);
+        signed.headers.insert("x-curiefense-bypass".to_string(), forged);
+        assert_eq!(settings.signed_nonce(&signed, now), None);
+        let overflow = format!("{}.n0nce-42.{}", i64::MIN, signature);
+        signed.headers.insert("x-curiefense-bypass".to_string(), overflow);
+        assert_eq!(settings.signed_nonce(&signed, now), None);
+
+        assert_eq!(
+            BypassSettings::resolve(&mut Logs::default(), RawBypass::default()),
+            None
+        );
+    }
+}
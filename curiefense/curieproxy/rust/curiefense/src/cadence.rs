@@ -0,0 +1,241 @@
+//! Request cadence tracking.
+//!
+//! When enabled on a security policy entry, the time between consecutive requests of the same key (the client IP by
+//! default) is tracked in redis, as exponentially weighted mean and variance. Two features are derived from them:
+//!
+//!  * the burstiness, (σ - μ) / (σ + μ), from -1 for perfectly periodic requests to 1 for bursts,
+//!  * the periodicity score, 1 - σ / μ, that gets close to 1 when requests are evenly spaced.
+//!
+//! Once `min_samples` intervals are known, keys whose periodicity score reaches `machine_periodicity` are tagged
+//! `cadence:machine-like`, and keys whose burstiness reaches `bursty` are tagged `cadence:bursty`. These tags are set
+//! before the session stage, so that global filters, limits and the bot score can use them.
+use crate::analyze::APhase0;
+use crate::clientstate::{Pending, StateQuery, StateReplies};
+use crate::config::matchers::RequestSelector;
+use crate::config::raw::RawCadence;
+use crate::failure::{dependency_failed, Dependency};
+use crate::interface::Location;
+use crate::logs::Logs;
+use crate::redis::REDIS_KEY_PREFIX;
+use crate::utils::{select_string, RequestInfo};
+
+/// tag of the request cadence of the key, qualified with machine-like or bursty
+pub const CADENCE_TAG: &str = "cadence";
+pub const MACHINE_CADENCE_TAG: &str = "cadence:machine-like";
+
+/// KEYS: state hash, ARGV: now (ms), smoothing factor, expiration (s)
+pub const CADENCE_SCRIPT: &str = r#"
+local now = tonumber(ARGV[1])
+local state = redis.call('HMGET', KEYS[1], 'last', 'count', 'mean', 'var')
+local last = tonumber(state[1])
+local count = tonumber(state[2]) or 0
+local mean = tonumber(state[3]) or 0
+local var = tonumber(state[4]) or 0
+if last then
+  local dt = math.max(0, now - last)
+  if count == 0 then
+    mean = dt
+    var = 0
+  else
+    local alpha = tonumber(ARGV[2])
+    local diff = dt - mean
+    mean = mean + alpha * diff
+    var = (1 - alpha) * (var + alpha * diff * diff)
+  end
+  count = count + 1
+end
+redis.call('HSET', KEYS[1], 'last', now, 'count', count, 'mean', tostring(mean), 'var', tostring(var))
+redis.call('EXPIRE', KEYS[1], ARGV[3])
+return {count, tostring(mean), tostring(var)}
+"#;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CadenceSettings {
+    pub keys: Vec<RequestSelector>,
+    /// weight of the last interval in the moving averages
+    pub smoothing: f64,
+    pub min_samples: u64,
+    pub machine_periodicity: f64,
+    pub bursty: f64,
+    /// seconds without requests after which the state is forgotten
+    pub ttl: u64,
+}
+
+impl CadenceSettings {
+    pub fn resolve(logs: &mut Logs, raw: RawCadence) -> Self {
+        let mut keys = Vec::new();
+        for k in raw.keys {
+            match RequestSelector::resolve_selector_map(k) {
+                Ok(sel) => keys.push(sel),
+                Err(rr) => logs.error(|| format!("cadence key: {}", rr)),
+            }
+        }
+        if keys.is_empty() {
+            keys.push(RequestSelector::Ip);
+        }
+        CadenceSettings {
+            keys,
+            smoothing: raw.smoothing.filter(|s| *s > 0.0 && *s <= 1.0).unwrap_or(0.2),
+            min_samples: raw.min_samples.unwrap_or(5).max(2),
+            machine_periodicity: raw.machine_periodicity.unwrap_or(0.9),
+            bursty: raw.bursty.unwrap_or(0.3),
+            ttl: raw.ttl_seconds.unwrap_or(3600).max(1),
+        }
+    }
+
+    /// the cadence tags of a key
+    pub fn labels(&self, stats: &CadenceStats) -> Vec<&'static str> {
+        let mut out = Vec::new();
+        if stats.count < self.min_samples {
+            return out;
+        }
+        if stats.periodicity() >= self.machine_periodicity {
+            out.push("machine-like");
+        }
+        if stats.burstiness() >= self.bursty {
+            out.push("bursty");
+        }
+        out
+    }
+}
+
+/// moving statistics of the intervals between requests, in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CadenceStats {
+    pub count: u64,
+    pub mean: f64,
+    pub var: f64,
+}
+
+impl CadenceStats {
+    /// adds an interval, as done by CADENCE_SCRIPT
+    pub fn update(&mut self, dt: f64, smoothing: f64) {
+        if self.count == 0 {
+            self.mean = dt;
+            self.var = 0.0;
+        } else {
+            let diff = dt - self.mean;
+            self.mean += smoothing * diff;
+            self.var = (1.0 - smoothing) * (self.var + smoothing * diff * diff);
+        }
+        self.count += 1;
+    }
+
+    pub fn burstiness(&self) -> f64 {
+        let sd = self.var.max(0.0).sqrt();
+        if sd + self.mean <= 0.0 {
+            // simultaneous requests
+            1.0
+        } else {
+            (sd - self.mean) / (sd + self.mean)
+        }
+    }
+
+    pub fn periodicity(&self) -> f64 {
+        if self.mean <= 0.0 {
+            0.0
+        } else {
+            (1.0 - self.var.max(0.0).sqrt() / self.mean).max(0.0)
+        }
+    }
+}
+
+fn cadence_key(settings: &CadenceSettings, reqinfo: &RequestInfo) -> Option<String> {
+    let mut parts = String::new();
+    for sel in &settings.keys {
+        parts += &select_string(reqinfo, sel, None)?;
+    }
+    Some(format!(
+        "{}{}cadence_{:X}",
+        *REDIS_KEY_PREFIX,
+        reqinfo.rinfo.secpolicy.counters.namespace,
+        md5::compute(parts)
+    ))
+}
+
+/// adds the command that records the request time, and returns the statistics of its key
+pub fn query_cadence(p0: &APhase0, query: &mut StateQuery) -> Option<Pending> {
+    let settings = p0.reqinfo.rinfo.secpolicy.cadence.as_ref()?;
+    let key = cadence_key(settings, &p0.reqinfo)?;
+    Some(query.add(|pipe| {
+        pipe.cmd("EVAL")
+            .arg(CADENCE_SCRIPT)
+            .arg(1)
+            .arg(key)
+            .arg(p0.reqinfo.timestamp.timestamp_millis())
+            .arg(settings.smoothing)
+            .arg(settings.ttl);
+        1
+    }))
+}
+
+fn cadence_stats(replies: &StateReplies, pending: Pending) -> anyhow::Result<CadenceStats> {
+    let ((count, mean, var),): ((u64, String, String),) = replies.get(pending)?;
+    Ok(CadenceStats {
+        count,
+        mean: mean.parse()?,
+        var: var.parse()?,
+    })
+}
+
+/// tags the request with the cadence of its key
+pub fn apply_cadence(logs: &mut Logs, p0: &mut APhase0, pending: Pending, replies: &StateReplies) {
+    let secpolicy = p0.reqinfo.rinfo.secpolicy.clone();
+    let settings = match &secpolicy.cadence {
+        None => return,
+        Some(s) => s,
+    };
+    match cadence_stats(replies, pending) {
+        Ok(stats) => {
+            logs.debug(|| {
+                format!(
+                    "cadence: {} intervals, burstiness {:.2}, periodicity {:.2}",
+                    stats.count,
+                    stats.burstiness(),
+                    stats.periodicity()
+                )
+            });
+            for label in settings.labels(&stats) {
+                p0.itags.insert_qualified(CADENCE_TAG, label, Location::Request);
+            }
+        }
+        Err(rr) => {
+            logs.error(|| format!("cadence tracking: {}", rr));
+            p0.itags.insert("cadence-degraded", Location::Request);
+            dependency_failed(&mut p0.itags, Dependency::Redis);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(intervals: &[f64]) -> CadenceStats {
+        let mut stats = CadenceStats::default();
+        for dt in intervals {
+            stats.update(*dt, 0.2);
+        }
+        stats
+    }
+
+    #[test]
+    fn features() {
+        let settings = CadenceSettings::resolve(&mut Logs::default(), RawCadence::default());
+        assert_eq!(settings.keys, vec![RequestSelector::Ip]);
+
+        let periodic = run(&[1000.0; 10]);
+        assert_eq!(periodic.periodicity(), 1.0);
+        assert_eq!(periodic.burstiness(), -1.0);
+        assert_eq!(settings.labels(&periodic), vec!["machine-like"]);
+        // not enough samples
+        assert!(settings.labels(&run(&[1000.0; 3])).is_empty());
+
+        let bursts = run(&[10.0, 10.0, 10.0, 10.0, 300000.0, 10.0, 10.0, 10.0]);
+        assert!(bursts.periodicity() < 0.5);
+        assert_eq!(settings.labels(&bursts), vec!["bursty"]);
+
+        let human = run(&[3000.0, 12000.0, 5000.0, 8000.0, 2000.0, 15000.0]);
+        assert!(settings.labels(&human).is_empty());
+    }
+}
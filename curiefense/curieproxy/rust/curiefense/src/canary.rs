@@ -0,0 +1,233 @@
+//! Canary versions of security policies.
+//!
+//! A security policy with a `canary` section is not matched by itself: it is a new version of the primary policy
+//! it names. A share of the traffic of the primary policy, selected by hashing the client IP or session, is also
+//! evaluated with the matching entry of the canary version, in shadow. The ACL and content filter checks of the
+//! canary are run, the limits and flows are not, as they would update the counters a second time. The would-be
+//! decisions are tagged, logged and counted, the canary never changes the decision.
+use lazy_static::lazy_static;
+use serde::Serialize;
+use sha2::{Digest, Sha224};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::acl::check_acl;
+use crate::config::contentfilter::ContentFilterProfile;
+use crate::config::hostmap::{HostMap, SecurityPolicy};
+use crate::config::raw::{AclProfile, RawCanary};
+use crate::config::CONFIGS;
+use crate::contentfilter::content_filter_check;
+use crate::grasshopper::PrecisionLevel;
+use crate::interface::stats::StatsCollect;
+use crate::interface::{AclStage, BlockReason, Location, Tags};
+use crate::logs::Logs;
+use crate::utils::RequestInfo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanarySplit {
+    Ip,
+    Session,
+}
+
+/// a canary version of a security policy
+#[derive(Debug, Clone)]
+pub struct Canary {
+    /// id of the primary security policy
+    pub primary: String,
+    pub percent: u8,
+    pub split: CanarySplit,
+    pub hostmap: HostMap,
+}
+
+/// the canary version of a security policy entry
+#[derive(Debug)]
+pub struct CanaryEntry {
+    pub percent: u8,
+    pub split: CanarySplit,
+    pub policy: Arc<SecurityPolicy>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CanaryStats {
+    pub evaluated: u64,
+    pub would_block: u64,
+}
+
+lazy_static! {
+    static ref STATS: Mutex<HashMap<String, CanaryStats>> = Mutex::new(HashMap::new());
+}
+
+impl Canary {
+    pub fn resolve(logs: &mut Logs, raw: RawCanary, hostmap: HostMap) -> Self {
+        let split = match raw.split.as_deref() {
+            None | Some("ip") => CanarySplit::Ip,
+            Some("session") => CanarySplit::Session,
+            Some(unknown) => {
+                logs.warning(|| format!("unknown canary split {}, using ip", unknown));
+                CanarySplit::Ip
+            }
+        };
+        if raw.percent > 100 {
+            logs.warning(|| format!("canary percentage {} of {} is capped to 100", raw.percent, hostmap.name));
+        }
+        Canary {
+            primary: raw.primary,
+            percent: raw.percent.min(100),
+            split,
+            hostmap,
+        }
+    }
+
+    /// the canary version of an entry of the primary policy, with the same id, or the default entry
+    pub fn entry(&self, entry_id: &str) -> Option<Arc<CanaryEntry>> {
+        let policy = self
+            .hostmap
+            .all_entries()
+            .find(|p| p.entry.id == entry_id)
+            .or(self.hostmap.default.as_ref())?;
+        Some(Arc::new(CanaryEntry {
+            percent: self.percent,
+            split: self.split,
+            policy: policy.clone(),
+        }))
+    }
+}
+
+/// stable bucket, between 0 and 99
+fn bucket(key: &str) -> u8 {
+    let digest = Sha224::digest(key.as_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    (u64::from_be_bytes(bytes) % 100) as u8
+}
+
+fn selected(canary: &CanaryEntry, reqinfo: &RequestInfo) -> bool {
+    let key = match canary.split {
+        CanarySplit::Ip => &reqinfo.rinfo.geoip.ipstr,
+        CanarySplit::Session => &reqinfo.session,
+    };
+    bucket(key) < canary.percent
+}
+
+/// what a set of profiles would decide for the request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ShadowDecision {
+    pub blocking: bool,
+    pub reasons: Vec<BlockReason>,
+}
+
+/// the profiles run by a shadow evaluation
+#[derive(Debug, Clone, Copy)]
+pub struct ShadowProfiles<'a> {
+    pub acl_active: bool,
+    pub acl_profile: &'a AclProfile,
+    pub content_filter_active: bool,
+    pub content_filter_profile: &'a ContentFilterProfile,
+}
+
+impl<'a> ShadowProfiles<'a> {
+    pub fn from_policy(policy: &'a SecurityPolicy) -> Self {
+        ShadowProfiles {
+            acl_active: policy.acl_active,
+            acl_profile: &policy.acl_profile,
+            content_filter_active: policy.content_filter_active,
+            content_filter_profile: &policy.content_filter_profile,
+        }
+    }
+}
+
+/// runs the ACL and content filter profiles, without changing the tags or any counter
+pub fn shadow_evaluate(
+    logs: &mut Logs,
+    profiles: ShadowProfiles<'_>,
+    reqinfo: &RequestInfo,
+    tags: &Tags,
+    precision_level: PrecisionLevel,
+) -> ShadowDecision {
+    let mut blocking = false;
+    let mut reasons = Vec::new();
+
+    let acl = profiles.acl_profile;
+    if let Some(decision) = check_acl(tags, acl).decision(precision_level.is_human()) {
+        let bypass = decision.stage == AclStage::Bypass;
+        let challenge = decision.challenge;
+        let mut br = BlockReason::acl(acl.id.clone(), acl.name.clone(), decision.tags, decision.stage);
+        if !profiles.acl_active && !challenge {
+            br.action.inactive();
+        }
+        blocking |= challenge || (!bypass && br.action.is_final());
+        reasons.push(br);
+    }
+
+    let cfg = CONFIGS.config.load();
+    let stats = StatsCollect::new(logs.start, String::new()).content_filter_only();
+    let mut shadow_tags = tags.clone();
+    let profile = profiles.content_filter_profile;
+    let (result, _) = content_filter_check(
+        logs,
+        stats,
+        &mut shadow_tags,
+        reqinfo,
+        profile,
+        cfg.hsdb.get(&profile.id),
+    );
+    if let Err(cfblock) = result {
+        blocking |= cfblock.blocking && profiles.content_filter_active && !profile.mode.is_passive();
+        reasons.extend(cfblock.reasons);
+    }
+    ShadowDecision { blocking, reasons }
+}
+
+/// evaluates the canary version of the security policy, for the selected share of the traffic
+pub fn canary_check(logs: &mut Logs, reqinfo: &RequestInfo, tags: &mut Tags, precision_level: PrecisionLevel) {
+    let secpol = &reqinfo.rinfo.secpolicy;
+    let canary = match &secpol.canary {
+        Some(c) if selected(c, reqinfo) => c,
+        _ => return,
+    };
+    let shadow = shadow_evaluate(
+        logs,
+        ShadowProfiles::from_policy(&canary.policy),
+        reqinfo,
+        tags,
+        precision_level,
+    );
+    logs.info(|| {
+        format!(
+            "canary {} would {}: {:?}",
+            canary.policy.policy.id,
+            if shadow.blocking { "block" } else { "pass" },
+            shadow.reasons
+        )
+    });
+    tags.insert("canary", Location::Request);
+    if shadow.blocking {
+        tags.insert("canary-would-block", Location::Request);
+    }
+    let mut stats = STATS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let entry = stats.entry(secpol.policy.id.clone()).or_default();
+    entry.evaluated += 1;
+    if shadow.blocking {
+        entry.would_block += 1;
+    }
+}
+
+/// canary statistics, by primary security policy id
+pub fn canary_stats() -> HashMap<String, CanaryStats> {
+    STATS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        assert_eq!(bucket("1.2.3.4"), bucket("1.2.3.4"));
+        assert!(bucket("1.2.3.4") < 100);
+        let share = (0..1000)
+            .filter(|i| bucket(&format!("10.0.{}.{}", i / 256, i % 256)) < 20)
+            .count();
+        assert!((120..280).contains(&share), "{}", share);
+    }
+}
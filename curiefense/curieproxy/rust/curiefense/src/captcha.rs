@@ -0,0 +1,372 @@
+//! CAPTCHA challenges.
+//!
+//! When the ACL requires a challenge, a CAPTCHA page from hCaptcha, reCAPTCHA or Turnstile can be served
+//! instead of, or as an escalation of, the challenge provider. Once solved, the widget token is stored in a
+//! cookie, and verified with the vendor on the next request. The verification results are cached, as tokens
+//! can only be verified once, and verified clients are considered interactive humans.
+//!
+//! Cached tokens raise the precision level before the request is tagged, so that the global filters see the
+//! client as human. Tokens that fail the verification, including when the vendor can't be reached, are cached too,
+//! and each client IP can only trigger a limited number of verifications per minute.
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::analyze::APhase0;
+use crate::config::raw::RawCaptchaSettings;
+use crate::grasshopper::PrecisionLevel;
+use crate::interface::{Action, ActionType, BlockReason, Decision, Location};
+use crate::logs::Logs;
+use crate::utils::{now_ms, RequestInfo};
+
+/// maximum number of cached verification results, and of rate limited client IPs
+const MAX_CACHED_TOKENS: usize = 65536;
+/// how long a verification error is cached, in milliseconds
+const FAILED_TTL_MS: u64 = 60_000;
+/// verifications allowed per client IP and per minute
+const MAX_VERIFICATIONS: u32 = 10;
+
+lazy_static! {
+    /// verification results, by ip and token, with their expiration time in milliseconds
+    static ref VERIFIED: Mutex<HashMap<String, (bool, u64)>> = Mutex::new(HashMap::new());
+    /// verifications by client IP, with the start of their one minute window in milliseconds
+    static ref VERIFICATIONS: Mutex<HashMap<String, (u32, u64)>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptchaVendor {
+    HCaptcha,
+    ReCaptcha,
+    Turnstile,
+}
+
+impl CaptchaVendor {
+    fn script(&self) -> &'static str {
+        match self {
+            CaptchaVendor::HCaptcha => "https://js.hcaptcha.com/1/api.js",
+            CaptchaVendor::ReCaptcha => "https://www.google.com/recaptcha/api.js",
+            CaptchaVendor::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/api.js",
+        }
+    }
+
+    /// class of the widget element
+    fn widget(&self) -> &'static str {
+        match self {
+            CaptchaVendor::HCaptcha => "h-captcha",
+            CaptchaVendor::ReCaptcha => "g-recaptcha",
+            CaptchaVendor::Turnstile => "cf-turnstile",
+        }
+    }
+
+    fn verify_url(&self) -> &'static str {
+        match self {
+            CaptchaVendor::HCaptcha => "https://api.hcaptcha.com/siteverify",
+            CaptchaVendor::ReCaptcha => "https://www.google.com/recaptcha/api/siteverify",
+            CaptchaVendor::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptchaMode {
+    /// replaces the challenge provider
+    Alternative,
+    /// only served when there is no challenge provider, or when it flagged the client as an emulator
+    Escalation,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptchaSettings {
+    pub vendor: CaptchaVendor,
+    pub site_key: String,
+    pub secret: String,
+    pub mode: CaptchaMode,
+    pub cookie: String,
+    /// in seconds
+    pub ttl: u64,
+    pub timeout: Duration,
+}
+
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+impl CaptchaSettings {
+    pub fn resolve(logs: &mut Logs, raw: RawCaptchaSettings) -> Option<Self> {
+        let vendor = match raw.vendor.to_lowercase().as_str() {
+            "hcaptcha" => CaptchaVendor::HCaptcha,
+            "recaptcha" => CaptchaVendor::ReCaptcha,
+            "turnstile" => CaptchaVendor::Turnstile,
+            unknown => {
+                logs.error(|| format!("unknown CAPTCHA vendor {}, the CAPTCHA is disabled", unknown));
+                return None;
+            }
+        };
+        if raw.secret.is_empty() || raw.site_key.is_empty() || !raw.site_key.chars().all(is_token_char) {
+            logs.error("the CAPTCHA requires a secret and a valid site key, it is disabled");
+            return None;
+        }
+        let mode = match raw.mode.as_deref() {
+            None | Some("alternative") => CaptchaMode::Alternative,
+            Some("escalation") => CaptchaMode::Escalation,
+            Some(unknown) => {
+                logs.warning(|| format!("unknown CAPTCHA mode {}, using alternative", unknown));
+                CaptchaMode::Alternative
+            }
+        };
+        let cookie = raw
+            .cookie
+            .filter(|c| !c.is_empty() && c.chars().all(is_token_char))
+            .unwrap_or_else(|| "cf_captcha".to_string());
+        Some(CaptchaSettings {
+            vendor,
+            site_key: raw.site_key,
+            secret: raw.secret,
+            mode,
+            cookie,
+            ttl: raw.ttl_seconds.unwrap_or(1800).max(1),
+            timeout: Duration::from_millis(raw.timeout_ms.unwrap_or(2000).max(1)),
+        })
+    }
+
+    /// true when the CAPTCHA should be served in place of the challenge
+    pub fn serves(&self, precision_level: PrecisionLevel, has_provider: bool) -> bool {
+        match self.mode {
+            CaptchaMode::Alternative => true,
+            CaptchaMode::Escalation => !has_provider || precision_level == PrecisionLevel::Emulator,
+        }
+    }
+
+    fn page(&self) -> String {
+        format!(
+            r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>Verification required</title>
+<script src="{script}" async defer></script>
+</head>
+<body><p>Please complete the verification to continue.</p>
+<div class="{widget}" data-sitekey="{site_key}" data-callback="onCaptchaSolved"></div>
+<script>
+function onCaptchaSolved(token) {{
+  document.cookie = "{cookie}=" + token + "; path=/; max-age={ttl}; SameSite=Lax";
+  location.reload();
+}}
+</script>
+</body></html>
+"#,
+            script = self.vendor.script(),
+            widget = self.vendor.widget(),
+            site_key = self.site_key,
+            cookie = self.cookie,
+            ttl = self.ttl
+        )
+    }
+
+    /// the cache key of the token of the request
+    fn token_key(&self, reqinfo: &RequestInfo) -> Option<(String, String)> {
+        let token = reqinfo.cookies.get_str(&self.cookie).filter(|t| !t.is_empty())?;
+        Some((format!("{}|{}", reqinfo.rinfo.geoip.ipstr, token), token.to_string()))
+    }
+
+    /// the precision level of a request carrying a token that was already verified
+    pub fn cached_level(&self, reqinfo: &RequestInfo) -> Option<PrecisionLevel> {
+        let (key, _) = self.token_key(reqinfo)?;
+        cached(&key, now_ms())
+            .filter(|verified| *verified)
+            .map(|_| PrecisionLevel::Interactive)
+    }
+
+    /// the CAPTCHA page
+    pub fn decision(&self, reasons: Vec<BlockReason>) -> Decision {
+        let mut headers = HashMap::new();
+        headers.insert("content-type".to_string(), "text/html; charset=utf-8".to_string());
+        headers.insert("cache-control".to_string(), "no-store".to_string());
+        Decision::action(
+            Action {
+                atype: ActionType::Block,
+                block_mode: true,
+                headers: Some(headers),
+                status: 403,
+                content: self.page(),
+                extra_tags: Some(std::iter::once("captcha".to_string()).collect()),
+                response_headers: None,
+                delay: None,
+                transform: None,
+                ban: None,
+            },
+            reasons,
+        )
+    }
+}
+
+fn cached(key: &str, now: u64) -> Option<bool> {
+    let cache = VERIFIED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    cache
+        .get(key)
+        .filter(|(_, expires)| *expires > now)
+        .map(|(verified, _)| *verified)
+}
+
+fn cache(key: String, verified: bool, expires: u64, now: u64) {
+    let mut cache = VERIFIED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if cache.len() >= MAX_CACHED_TOKENS {
+        cache.retain(|_, (_, e)| *e > now);
+        if cache.len() >= MAX_CACHED_TOKENS {
+            cache.clear();
+        }
+    }
+    cache.insert(key, (verified, expires));
+}
+
+/// counts a verification for the client IP, false when it exceeded its limit
+fn allow_verification(ip: &str, now: u64) -> bool {
+    let mut verifications = VERIFICATIONS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if verifications.len() >= MAX_CACHED_TOKENS {
+        verifications.retain(|_, (_, start)| *start + 60_000 > now);
+        if verifications.len() >= MAX_CACHED_TOKENS {
+            verifications.clear();
+        }
+    }
+    let (count, start) = verifications.entry(ip.to_string()).or_insert((0, now));
+    if *start + 60_000 <= now {
+        *count = 0;
+        *start = now;
+    }
+    *count += 1;
+    *count <= MAX_VERIFICATIONS
+}
+
+#[derive(Deserialize)]
+struct SiteVerify {
+    success: bool,
+}
+
+/// asks the vendor whether the token is valid, on the blocking thread pool
+async fn site_verify(settings: &CaptchaSettings, token: &str, ip: &str) -> anyhow::Result<bool> {
+    let url = settings.vendor.verify_url();
+    let timeout = settings.timeout;
+    let form = [
+        ("secret".to_string(), settings.secret.clone()),
+        ("response".to_string(), token.to_string()),
+        ("remoteip".to_string(), ip.to_string()),
+    ];
+    async_std::task::spawn_blocking(move || {
+        let connector = native_tls::TlsConnector::new()?;
+        let agent = ureq::AgentBuilder::new()
+            .timeout(timeout)
+            .tls_connector(Arc::new(connector))
+            .build();
+        let form: Vec<(&str, &str)> = form.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        let reply = agent.post(url).send_form(&form)?.into_string()?;
+        let parsed: SiteVerify = serde_json::from_str(&reply)?;
+        Ok(parsed.success)
+    })
+    .await
+}
+
+/// verifies the CAPTCHA token of the request, raising the precision level of verified clients
+///
+/// the level of cached tokens was already raised when the request was tagged
+pub async fn analyze_captcha(logs: &mut Logs, mut p0: APhase0) -> APhase0 {
+    let secpolicy = p0.reqinfo.rinfo.secpolicy.clone();
+    let settings = match &secpolicy.captcha {
+        Some(s) => s,
+        None => return p0,
+    };
+    let (key, token) = match settings.token_key(&p0.reqinfo) {
+        Some(kt) => kt,
+        None => return p0,
+    };
+    let ip = p0.reqinfo.rinfo.geoip.ipstr.clone();
+    let now = now_ms();
+    let verified = match cached(&key, now) {
+        Some(v) => v,
+        None if p0.precision_level.is_interactive() => return p0,
+        None if !allow_verification(&ip, now) => {
+            logs.debug(|| format!("CAPTCHA verifications rate limited for {}", ip));
+            p0.itags.insert("captcha-rate-limited", Location::Ip);
+            false
+        }
+        None => match site_verify(settings, &token, &ip).await {
+            Ok(v) => {
+                cache(key, v, now + settings.ttl * 1000, now);
+                v
+            }
+            Err(rr) => {
+                logs.error(|| format!("CAPTCHA verification: {}", rr));
+                cache(key, false, now + FAILED_TTL_MS, now);
+                false
+            }
+        },
+    };
+    if verified {
+        p0.precision_level = PrecisionLevel::Interactive;
+        p0.itags.insert("captcha-verified", Location::Request);
+    } else {
+        p0.itags.insert("captcha-failed", Location::Request);
+    }
+    p0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(vendor: &str, mode: Option<&str>) -> RawCaptchaSettings {
+        RawCaptchaSettings {
+            vendor: vendor.to_string(),
+            site_key: "site-key_1".to_string(),
+            secret: "secret".to_string(),
+            mode: mode.map(|m| m.to_string()),
+            cookie: None,
+            ttl_seconds: None,
+            timeout_ms: None,
+        }
+    }
+
+    #[test]
+    fn settings() {
+        let mut logs = Logs::default();
+        let turnstile = CaptchaSettings::resolve(&mut logs, raw("Turnstile", None)).unwrap();
+        assert_eq!(turnstile.vendor, CaptchaVendor::Turnstile);
+        assert_eq!(turnstile.cookie, "cf_captcha");
+        assert!(turnstile.serves(PrecisionLevel::Invalid, true));
+        let page = turnstile.decision(Vec::new());
+        let action = page.maction.unwrap();
+        assert_eq!(action.status, 403);
+        assert!(action
+            .content
+            .contains(r#"class="cf-turnstile" data-sitekey="site-key_1""#));
+
+        let escalation = CaptchaSettings::resolve(&mut logs, raw("hcaptcha", Some("escalation"))).unwrap();
+        assert!(!escalation.serves(PrecisionLevel::Invalid, true));
+        assert!(escalation.serves(PrecisionLevel::Emulator, true));
+        assert!(escalation.serves(PrecisionLevel::Invalid, false));
+
+        assert!(CaptchaSettings::resolve(&mut logs, raw("unknown", None)).is_none());
+        let mut invalid_key = raw("recaptcha", None);
+        invalid_key.site_key = "\"><script>".to_string();
+        assert!(CaptchaSettings::resolve(&mut logs, invalid_key).is_none());
+    }
+
+    #[test]
+    fn verification_cache() {
+        assert_eq!(cached("1.2.3.4|token", 1000), None);
+        cache("1.2.3.4|token".to_string(), true, 2000, 1000);
+        assert_eq!(cached("1.2.3.4|token", 1500), Some(true));
+        assert_eq!(cached("5.6.7.8|token", 1500), None);
+        assert_eq!(cached("1.2.3.4|token", 2000), None);
+    }
+
+    #[test]
+    fn verification_rate_limit() {
+        for _ in 0..MAX_VERIFICATIONS {
+            assert!(allow_verification("192.0.2.1", 1000));
+        }
+        assert!(!allow_verification("192.0.2.1", 30_000));
+        assert!(allow_verification("192.0.2.2", 30_000));
+        // a new window starts a minute after the first verification
+        assert!(allow_verification("192.0.2.1", 61_000));
+    }
+}
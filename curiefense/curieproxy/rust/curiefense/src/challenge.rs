@@ -0,0 +1,346 @@
+//! Challenge providers.
+//!
+//! The bot detection challenges are delegated to a provider, selected in challenge.json:
+//!
+//!  * grasshopper, the default, through its FFI, guarded by a timeout,
+//!  * pow, the native proof-of-work challenge,
+//!  * http, a sidecar listening on the loopback interface, so that other vendors can be integrated, its calls are
+//!    guarded like the grasshopper ones,
+//!  * none, requests that should be challenged are then handled with the configured action.
+//!
+//! The sidecar receives JSON POST requests on `URL/METHOD`, METHOD being the name of a `ChallengeProvider`
+//! method. The queries hold the request headers, cookies, ip and protocol. It answers with
+//! `{"precision_level": LEVEL}` for `is_human`, `{"cookie": VALUE}` for `verify_challenge`, and
+//! `{"precision_level", "str_response", "headers", "status_code"}` objects otherwise.
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::raw::RawChallengeSettings;
+use crate::config::{Config, CONFIGS};
+use crate::grasshopper::{
+    run_guarded, GHMode, GHQuery, GHResponse, GrasshopperSettings, GuardedGrasshopper, PrecisionLevel,
+};
+use crate::logs::Logs;
+use crate::pow::{PowSettings, ProofOfWork};
+
+/// a bot detection vendor, issuing challenges and verifying their results
+pub trait ChallengeProvider {
+    /// the precision level of a request, from the proofs it holds
+    fn is_human(&self, input: GHQuery) -> Result<PrecisionLevel, String>;
+    /// the challenge page
+    fn init_challenge(&self, input: GHQuery, mode: GHMode) -> Result<GHResponse, String>;
+    /// verifies a challenge result, returning the cookie value proving it
+    fn verify_challenge(&self, headers: HashMap<&str, &str>) -> Result<String, String>;
+    fn should_provide_app_sig(&self, headers: HashMap<&str, &str>) -> Result<GHResponse, String>;
+    fn handle_bio_report(&self, input: GHQuery, precision_level: PrecisionLevel) -> Result<GHResponse, String>;
+
+    /// time spent waiting for the provider
+    fn latency(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SidecarSettings {
+    /// without the trailing slash
+    pub url: String,
+    pub agent: ureq::Agent,
+    /// also bounds the wait of the request path, when the sidecar does not answer
+    pub timeout: Duration,
+}
+
+/// true for URLs of the loopback interface, the queries hold the request cookies
+fn is_loopback_url(url: &str) -> bool {
+    let authority = match url.strip_prefix("http://") {
+        None => return false,
+        Some(rest) => rest.split('/').next().unwrap_or(rest),
+    };
+    let host = match authority.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or(bracketed),
+        None => authority.split(':').next().unwrap_or(authority),
+    };
+    host == "localhost" || host.parse::<IpAddr>().map(|ip| ip.is_loopback()).unwrap_or(false)
+}
+
+impl SidecarSettings {
+    pub fn resolve(logs: &mut Logs, raw: RawChallengeSettings) -> Option<Self> {
+        let url = match raw.url {
+            None => {
+                logs.error("the http challenge provider requires an url");
+                return None;
+            }
+            Some(url) => url.trim_end_matches('/').to_string(),
+        };
+        if !is_loopback_url(&url) {
+            logs.error(|| {
+                format!(
+                    "the challenge sidecar must listen on the loopback interface, not {}",
+                    url
+                )
+            });
+            return None;
+        }
+        let timeout = Duration::from_millis(raw.timeout_ms.unwrap_or(500).max(1));
+        let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+        Some(SidecarSettings { url, agent, timeout })
+    }
+}
+
+/// the challenge provider settings, from challenge.json
+#[derive(Debug, Clone)]
+pub enum ChallengeSettings {
+    Grasshopper(GrasshopperSettings),
+    ProofOfWork(Arc<PowSettings>),
+    Sidecar(Arc<SidecarSettings>),
+    Disabled,
+}
+
+impl Default for ChallengeSettings {
+    fn default() -> Self {
+        ChallengeSettings::Grasshopper(GrasshopperSettings::default())
+    }
+}
+
+fn grasshopper_settings(raw: &RawChallengeSettings) -> GrasshopperSettings {
+    let default = GrasshopperSettings::default();
+    GrasshopperSettings {
+        timeout: raw
+            .timeout_ms
+            .map(|t| Duration::from_millis(t.max(1)))
+            .unwrap_or(default.timeout),
+    }
+}
+
+impl ChallengeSettings {
+    /// falls back to grasshopper when the provider can't be used
+    pub fn resolve(logs: &mut Logs, raw: RawChallengeSettings) -> Self {
+        let resolved = match raw.provider.as_str() {
+            "grasshopper" => Some(ChallengeSettings::Grasshopper(grasshopper_settings(&raw))),
+            "pow" => PowSettings::resolve(logs, raw).map(|s| ChallengeSettings::ProofOfWork(Arc::new(s))),
+            "http" => SidecarSettings::resolve(logs, raw).map(|s| ChallengeSettings::Sidecar(Arc::new(s))),
+            "none" => Some(ChallengeSettings::Disabled),
+            unknown => {
+                logs.error(|| format!("unknown challenge provider {}", unknown));
+                None
+            }
+        };
+        resolved.unwrap_or_else(|| {
+            logs.warning("using the grasshopper challenge provider");
+            ChallengeSettings::default()
+        })
+    }
+}
+
+/// a challenge sidecar, queried with JSON over HTTP
+#[derive(Debug, Clone)]
+pub struct HttpChallenge {
+    pub settings: Arc<SidecarSettings>,
+}
+
+#[derive(Deserialize)]
+struct SidecarPrecision {
+    precision_level: PrecisionLevel,
+}
+
+#[derive(Deserialize)]
+struct SidecarCookie {
+    cookie: String,
+}
+
+impl HttpChallenge {
+    fn call<T: DeserializeOwned>(&self, method: &'static str, query: Value) -> Result<T, String> {
+        let url = format!("{}/{}", self.settings.url, method);
+        let agent = self.settings.agent.clone();
+        let target = url.clone();
+        let reply = run_guarded(method, self.settings.timeout, move || {
+            agent
+                .post(&target)
+                .set("content-type", "application/json")
+                .send_string(&query.to_string())
+                .map_err(|rr| rr.to_string())?
+                .into_string()
+                .map_err(|rr| rr.to_string())
+        })
+        .map_err(|rr| format!("{}: {}", url, rr))?;
+        serde_json::from_str(&reply).map_err(|rr| format!("{}: invalid reply, {}", url, rr))
+    }
+}
+
+impl ChallengeProvider for HttpChallenge {
+    fn is_human(&self, input: GHQuery) -> Result<PrecisionLevel, String> {
+        let reply: SidecarPrecision = self.call("is_human", json!(input))?;
+        Ok(reply.precision_level)
+    }
+
+    fn init_challenge(&self, input: GHQuery, mode: GHMode) -> Result<GHResponse, String> {
+        self.call("init_challenge", json!({ "query": input, "mode": mode }))
+    }
+
+    fn verify_challenge(&self, headers: HashMap<&str, &str>) -> Result<String, String> {
+        let reply: SidecarCookie = self.call("verify_challenge", json!({ "headers": headers }))?;
+        Ok(reply.cookie)
+    }
+
+    fn should_provide_app_sig(&self, headers: HashMap<&str, &str>) -> Result<GHResponse, String> {
+        self.call("should_provide_app_sig", json!({ "headers": headers }))
+    }
+
+    fn handle_bio_report(&self, input: GHQuery, precision_level: PrecisionLevel) -> Result<GHResponse, String> {
+        self.call(
+            "handle_bio_report",
+            json!({ "query": input, "precision_level": precision_level }),
+        )
+    }
+}
+
+/// the challenge provider selected in challenge.json
+pub enum ConfiguredChallenge {
+    Grasshopper(GuardedGrasshopper),
+    ProofOfWork(ProofOfWork),
+    Sidecar(HttpChallenge),
+}
+
+impl ConfiguredChallenge {
+    /// None when challenges are disabled
+    pub fn from_config(config: &Config) -> Option<Self> {
+        match &config.challenge {
+            ChallengeSettings::Grasshopper(settings) => {
+                Some(ConfiguredChallenge::Grasshopper(GuardedGrasshopper::new(*settings)))
+            }
+            ChallengeSettings::ProofOfWork(settings) => Some(ConfiguredChallenge::ProofOfWork(ProofOfWork {
+                settings: settings.clone(),
+            })),
+            ChallengeSettings::Sidecar(settings) => Some(ConfiguredChallenge::Sidecar(HttpChallenge {
+                settings: settings.clone(),
+            })),
+            ChallengeSettings::Disabled => None,
+        }
+    }
+
+    /// the provider of the current configuration
+    pub fn current() -> Option<Self> {
+        Self::from_config(&CONFIGS.config.load())
+    }
+
+    fn provider(&self) -> &dyn ChallengeProvider {
+        match self {
+            ConfiguredChallenge::Grasshopper(gh) => gh,
+            ConfiguredChallenge::ProofOfWork(pow) => pow,
+            ConfiguredChallenge::Sidecar(sidecar) => sidecar,
+        }
+    }
+}
+
+impl ChallengeProvider for ConfiguredChallenge {
+    fn is_human(&self, input: GHQuery) -> Result<PrecisionLevel, String> {
+        self.provider().is_human(input)
+    }
+
+    fn init_challenge(&self, input: GHQuery, mode: GHMode) -> Result<GHResponse, String> {
+        self.provider().init_challenge(input, mode)
+    }
+
+    fn verify_challenge(&self, headers: HashMap<&str, &str>) -> Result<String, String> {
+        self.provider().verify_challenge(headers)
+    }
+
+    fn should_provide_app_sig(&self, headers: HashMap<&str, &str>) -> Result<GHResponse, String> {
+        self.provider().should_provide_app_sig(headers)
+    }
+
+    fn handle_bio_report(&self, input: GHQuery, precision_level: PrecisionLevel) -> Result<GHResponse, String> {
+        self.provider().handle_bio_report(input, precision_level)
+    }
+
+    fn latency(&self) -> Duration {
+        self.provider().latency()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(provider: &str, url: Option<&str>) -> RawChallengeSettings {
+        RawChallengeSettings {
+            provider: provider.to_string(),
+            secret: "s3cr3t".to_string(),
+            difficulty: None,
+            ttl_seconds: None,
+            cookie: None,
+            url: url.map(|u| u.to_string()),
+            timeout_ms: None,
+            clearance: None,
+        }
+    }
+
+    #[test]
+    fn loopback_urls() {
+        assert!(is_loopback_url("http://localhost:8080/challenge"));
+        assert!(is_loopback_url("http://127.0.0.1:9000"));
+        assert!(is_loopback_url("http://[::1]:9000/"));
+        assert!(!is_loopback_url("http://10.0.0.1:9000"));
+        assert!(!is_loopback_url("http://localhost.example.com"));
+        assert!(!is_loopback_url("https://localhost"));
+    }
+
+    #[test]
+    fn silent_sidecar() {
+        // the connection is accepted, but never answered
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let settings = SidecarSettings::resolve(
+            &mut Logs::default(),
+            RawChallengeSettings {
+                timeout_ms: Some(50),
+                ..raw("http", Some(&url))
+            },
+        )
+        .unwrap();
+        let sidecar = HttpChallenge {
+            settings: Arc::new(settings),
+        };
+        let start = std::time::Instant::now();
+        assert!(sidecar.verify_challenge(HashMap::new()).is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn provider_selection() {
+        let mut logs = Logs::default();
+        let resolve = |logs: &mut Logs, provider, url| ChallengeSettings::resolve(logs, raw(provider, url));
+        assert!(matches!(
+            resolve(&mut logs, "pow", None),
+            ChallengeSettings::ProofOfWork(_)
+        ));
+        match resolve(&mut logs, "http", Some("http://127.0.0.1:9000/")) {
+            ChallengeSettings::Sidecar(s) => assert_eq!(s.url, "http://127.0.0.1:9000"),
+            other => panic!("unexpected settings {:?}", other),
+        }
+        assert!(matches!(resolve(&mut logs, "none", None), ChallengeSettings::Disabled));
+        let guarded = RawChallengeSettings {
+            timeout_ms: Some(50),
+            ..raw("grasshopper", None)
+        };
+        match ChallengeSettings::resolve(&mut logs, guarded) {
+            ChallengeSettings::Grasshopper(s) => {
+                assert_eq!(s.timeout, Duration::from_millis(50));
+            }
+            other => panic!("unexpected settings {:?}", other),
+        }
+        // unusable providers fall back to grasshopper
+        assert!(matches!(
+            resolve(&mut logs, "http", Some("http://10.0.0.1:9000")),
+            ChallengeSettings::Grasshopper(_)
+        ));
+        assert!(matches!(
+            resolve(&mut logs, "vendor", None),
+            ChallengeSettings::Grasshopper(_)
+        ));
+    }
+}
@@ -0,0 +1,309 @@
+//! Checkout protection, against card testing.
+//!
+//! A security policy entry can designate its payment endpoints (path templates, and methods, POST by default), and the
+//! argument holding the card number. For each payment attempt, the card number is masked in the request, keeping its
+//! BIN (issuer prefix) and last four digits, and three distinct card counts are kept in redis, as HyperLogLogs over
+//! fixed windows of `window_seconds`:
+//!
+//!  * the cards tried from the client IP,
+//!  * the cards tried in the session,
+//!  * the cards tried with the same BIN, that grows when generated card numbers are tested.
+//!
+//! Card numbers are only stored as truncated hashes. Payment attempts are tagged `checkout-attempt` and
+//! `checkout-bin:<bin>`, and each threshold that is exceeded adds a `checkout-anomaly:<signal>` tag and a reason, with
+//! the configured action (a challenge by default).
+use crate::analyze::APhase0;
+use crate::clientstate::{Pending, StateQuery, StateReplies};
+use crate::config::pathtrie::PathTemplate;
+use crate::config::raw::RawCheckoutProtection;
+use crate::failure::{dependency_failed, Dependency};
+use crate::interface::{stronger_decision, BlockReason, Location, SimpleAction, SimpleDecision};
+use crate::login::{protection_action, short_hash};
+use crate::logs::Logs;
+use crate::redis::REDIS_KEY_PREFIX;
+use crate::utils::RequestInfo;
+
+/// prefix of the tags of payment attempts, of their card BIN, and of the anomalies of their counts
+pub const CHECKOUT_TAG_PREFIX: &str = "checkout-";
+
+#[derive(Debug, Clone)]
+pub struct CheckoutProtectionSettings {
+    pub paths: Vec<PathTemplate>,
+    /// uppercase
+    pub methods: Vec<String>,
+    pub card_field: String,
+    /// number of leading digits identifying the issuer
+    pub bin_length: usize,
+    /// seconds
+    pub window: u64,
+    pub max_cards_per_ip: u64,
+    pub max_cards_per_session: u64,
+    pub max_cards_per_bin: u64,
+    pub action: SimpleAction,
+}
+
+/// a card number, with its separators removed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CardAttempt {
+    /// the value of the card argument, as sent
+    pub raw: String,
+    pub digits: String,
+    pub bin: String,
+}
+
+impl CardAttempt {
+    /// the card number, with all digits but the BIN and the last four replaced by `*`
+    pub fn masked(&self) -> String {
+        let len = self.digits.len();
+        format!(
+            "{}{}{}",
+            self.bin,
+            "*".repeat(len - self.bin.len() - 4),
+            &self.digits[len - 4..]
+        )
+    }
+}
+
+/// distinct card counts of the current window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CardCounts {
+    pub cards_per_ip: u64,
+    pub cards_per_session: u64,
+    pub cards_per_bin: u64,
+}
+
+/// parses a card number, made of 12 to 19 digits, optionally separated by spaces or dashes
+pub fn parse_card(value: &str, bin_length: usize) -> Option<CardAttempt> {
+    let mut digits = String::new();
+    for c in value.trim().chars() {
+        match c {
+            '0'..='9' => digits.push(c),
+            ' ' | '-' => (),
+            _ => return None,
+        }
+    }
+    if !(12..=19).contains(&digits.len()) {
+        return None;
+    }
+    Some(CardAttempt {
+        raw: value.to_string(),
+        bin: digits[..bin_length].to_string(),
+        digits,
+    })
+}
+
+impl CheckoutProtectionSettings {
+    pub fn resolve(logs: &mut Logs, raw: RawCheckoutProtection) -> Option<Self> {
+        let mut paths = Vec::new();
+        for p in raw.paths {
+            match p.parse() {
+                Ok(tpl) => paths.push(tpl),
+                Err(rr) => logs.error(|| format!("checkout protection path: {}", rr)),
+            }
+        }
+        if paths.is_empty() {
+            logs.error("checkout protection requires payment paths, it is disabled");
+            return None;
+        }
+        let methods = if raw.methods.is_empty() {
+            vec!["POST".to_string()]
+        } else {
+            raw.methods.iter().map(|m| m.to_uppercase()).collect()
+        };
+        Some(CheckoutProtectionSettings {
+            paths,
+            methods,
+            card_field: raw.card_field.unwrap_or_else(|| "card_number".to_string()),
+            // the BIN and last four digits must not overlap, even for the shortest card numbers
+            bin_length: raw.bin_length.unwrap_or(6).clamp(4, 8),
+            window: raw.window_seconds.unwrap_or(3600).max(1),
+            max_cards_per_ip: raw.max_cards_per_ip.unwrap_or(3),
+            max_cards_per_session: raw.max_cards_per_session.unwrap_or(3),
+            max_cards_per_bin: raw.max_cards_per_bin.unwrap_or(20),
+            action: protection_action(logs, "checkout protection", raw.action),
+        })
+    }
+
+    /// the card number of the request, if it is a payment attempt
+    pub fn attempt(&self, reqinfo: &RequestInfo) -> Option<CardAttempt> {
+        if !self.methods.contains(&reqinfo.rinfo.meta.method.to_uppercase()) {
+            return None;
+        }
+        let path = &reqinfo.rinfo.qinfo.normalized.path;
+        if !self.paths.iter().any(|tpl| tpl.captures(path).is_some()) {
+            return None;
+        }
+        parse_card(reqinfo.rinfo.qinfo.args.get_str(&self.card_field)?, self.bin_length)
+    }
+
+    /// the exceeded thresholds, as (signal, count, threshold)
+    pub fn anomalies(&self, counts: &CardCounts) -> Vec<(&'static str, u64, u64)> {
+        [
+            ("cards-per-ip", counts.cards_per_ip, self.max_cards_per_ip),
+            (
+                "cards-per-session",
+                counts.cards_per_session,
+                self.max_cards_per_session,
+            ),
+            ("cards-per-bin", counts.cards_per_bin, self.max_cards_per_bin),
+        ]
+        .iter()
+        .filter(|(_, count, max)| count > max)
+        .cloned()
+        .collect()
+    }
+}
+
+/// replaces the card number in the arguments, the query string and the raw body
+fn mask_card(reqinfo: &mut RequestInfo, field: &str, card: &CardAttempt) {
+    let masked = card.masked();
+    let locations = reqinfo.rinfo.qinfo.args.mask_with(field, |_| masked.clone());
+    for location in locations {
+        match location {
+            Location::UriArgumentValue(_, _) => {
+                reqinfo.rinfo.meta.path = reqinfo.rinfo.meta.path.replace(&card.raw, &masked);
+                if let Some(q) = &reqinfo.rinfo.qinfo.query {
+                    reqinfo.rinfo.qinfo.query = Some(q.replace(&card.raw, &masked));
+                }
+            }
+            Location::Body | Location::BodyArgumentValue(_, _) => {
+                reqinfo
+                    .rinfo
+                    .qinfo
+                    .args
+                    .alter("RAW_BODY", |b| b.replace(&card.raw, &masked));
+            }
+            _ => (),
+        }
+    }
+}
+
+/// the HyperLogLogs the card is added to
+fn card_sets(reqinfo: &RequestInfo, settings: &CheckoutProtectionSettings, card: &CardAttempt) -> [String; 3] {
+    let namespace = &reqinfo.rinfo.secpolicy.counters.namespace;
+    let bucket = reqinfo.timestamp.timestamp().max(0) as u64 / settings.window;
+    let prefix = format!("{}{}checkout_", *REDIS_KEY_PREFIX, namespace);
+    [
+        format!("{}ip_{}_{}", prefix, reqinfo.rinfo.geoip.ipstr, bucket),
+        format!(
+            "{}session_{}_{}",
+            prefix,
+            short_hash(namespace, &reqinfo.session),
+            bucket
+        ),
+        format!("{}bin_{}_{}", prefix, card.bin, bucket),
+    ]
+}
+
+/// masks the card number of payment attempts, tags them, and adds the commands that count them
+pub fn query_checkout(p0: &mut APhase0, query: &mut StateQuery) -> Option<Pending> {
+    let secpolicy = p0.reqinfo.rinfo.secpolicy.clone();
+    let settings = secpolicy.checkout_protection.as_ref()?;
+    let card = settings.attempt(&p0.reqinfo)?;
+    mask_card(&mut p0.reqinfo, &settings.card_field, &card);
+    p0.itags.insert("checkout-attempt", Location::Request);
+    p0.itags.insert_qualified("checkout-bin", &card.bin, Location::Request);
+    let sets = card_sets(&p0.reqinfo, settings, &card);
+    let hcard = short_hash(&secpolicy.counters.namespace, &card.digits);
+    Some(query.add(|pipe| {
+        for key in &sets {
+            pipe.cmd("PFADD").arg(key).arg(&hcard).ignore();
+            pipe.cmd("EXPIRE").arg(key).arg(settings.window).ignore();
+            pipe.cmd("PFCOUNT").arg(key);
+        }
+        sets.len()
+    }))
+}
+
+/// sets the checkout protection action when a threshold is exceeded
+pub fn apply_checkout(logs: &mut Logs, p0: &mut APhase0, pending: Pending, replies: &StateReplies) {
+    let secpolicy = p0.reqinfo.rinfo.secpolicy.clone();
+    let settings = match &secpolicy.checkout_protection {
+        None => return,
+        Some(s) => s,
+    };
+    let counts = match replies.get::<Vec<u64>>(pending) {
+        Ok(counts) => CardCounts {
+            cards_per_ip: counts.first().copied().unwrap_or(0),
+            cards_per_session: counts.get(1).copied().unwrap_or(0),
+            cards_per_bin: counts.get(2).copied().unwrap_or(0),
+        },
+        Err(rr) => {
+            logs.error(|| format!("checkout protection: {}", rr));
+            p0.itags.insert("checkout-protection-degraded", Location::Request);
+            dependency_failed(&mut p0.itags, Dependency::Redis);
+            return;
+        }
+    };
+    logs.debug(|| format!("payment attempt counts: {:?}", counts));
+    let mut reasons = Vec::new();
+    for (signal, count, max) in settings.anomalies(&counts) {
+        let location = if signal == "cards-per-ip" {
+            Location::Ip
+        } else {
+            Location::Request
+        };
+        p0.itags.insert_qualified("checkout-anomaly", signal, location.clone());
+        reasons.push(BlockReason::card_testing(
+            secpolicy.entry.id.clone(),
+            secpolicy.entry.name.clone(),
+            settings.action.atype.to_raw(),
+            signal,
+            location,
+            count,
+            max,
+        ));
+    }
+    if !reasons.is_empty() {
+        let decision = SimpleDecision::Action(settings.action.clone(), reasons);
+        let previous = std::mem::replace(&mut p0.globalfilter_dec, SimpleDecision::Pass);
+        p0.globalfilter_dec = stronger_decision(previous, decision);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::request_info;
+
+    fn mk_reqinfo(method: &str, path: &str) -> RequestInfo {
+        request_info(&[("method", method), ("path", path)], &[])
+    }
+
+    #[test]
+    fn cards_and_anomalies() {
+        let settings = CheckoutProtectionSettings::resolve(
+            &mut Logs::default(),
+            RawCheckoutProtection {
+                paths: vec!["/pay".to_string()],
+                card_field: Some("cc".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let card = parse_card("4111-1111-1111-1111", 6).unwrap();
+        assert_eq!(card.bin, "411111");
+        assert_eq!(card.masked(), "411111******1111");
+        assert_eq!(parse_card("4111 1111", 6), None);
+        assert_eq!(parse_card("4111x1111111111111", 6), None);
+
+        let mut reqinfo = mk_reqinfo("POST", "/pay?cc=4111111111111111&amount=3");
+        let attempt = settings.attempt(&reqinfo).unwrap();
+        assert_eq!(attempt.digits, "4111111111111111");
+        mask_card(&mut reqinfo, &settings.card_field, &attempt);
+        assert_eq!(reqinfo.rinfo.qinfo.args.get_str("cc"), Some("411111******1111"));
+        assert_eq!(reqinfo.rinfo.meta.path, "/pay?cc=411111******1111&amount=3");
+        assert_eq!(settings.attempt(&mk_reqinfo("GET", "/pay?cc=4111111111111111")), None);
+        assert_eq!(settings.attempt(&mk_reqinfo("POST", "/cart?cc=4111111111111111")), None);
+
+        let counts = CardCounts {
+            cards_per_ip: 2,
+            cards_per_session: 4,
+            cards_per_bin: 25,
+        };
+        assert_eq!(
+            settings.anomalies(&counts),
+            vec![("cards-per-session", 4, 3), ("cards-per-bin", 25, 20)]
+        );
+    }
+}
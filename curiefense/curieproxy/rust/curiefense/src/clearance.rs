@@ -0,0 +1,180 @@
+//! Human clearance cookies.
+//!
+//! Once the challenge provider recognized a client as human, a clearance cookie holding the precision level and
+//! an expiration timestamp is issued, signed with HMAC-SHA256 along with the client IP and user agent. Requests
+//! holding a valid clearance get their precision level from the cookie, without querying the provider again.
+use std::collections::HashMap;
+use subtle::ConstantTimeEq;
+
+use crate::config::raw::RawClearanceSettings;
+use crate::grasshopper::PrecisionLevel;
+use crate::interface::{Action, ActionType, Decision};
+use crate::logs::Logs;
+use crate::utils::{hmac_sha256, RequestInfo};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClearanceSettings {
+    pub secret: Vec<u8>,
+    pub cookie: String,
+    /// in seconds
+    pub ttl: i64,
+}
+
+impl ClearanceSettings {
+    pub fn resolve(logs: &mut Logs, raw: RawClearanceSettings) -> Option<Self> {
+        if raw.secret.is_empty() {
+            logs.error("clearance cookies require a secret, they are disabled");
+            return None;
+        }
+        let cookie = match raw.cookie {
+            Some(c) if !c.is_empty() && c.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') => c,
+            Some(c) => {
+                logs.warning(|| format!("invalid clearance cookie name {:?}, using cf_clearance", c));
+                "cf_clearance".to_string()
+            }
+            None => "cf_clearance".to_string(),
+        };
+        Some(ClearanceSettings {
+            secret: raw.secret.into_bytes(),
+            cookie,
+            ttl: raw.ttl_seconds.unwrap_or(1800).clamp(1, i64::MAX as u64) as i64,
+        })
+    }
+}
+
+fn level_from_code(code: &str) -> Option<PrecisionLevel> {
+    match code {
+        "0" => Some(PrecisionLevel::Active),
+        "1" => Some(PrecisionLevel::Passive),
+        "2" => Some(PrecisionLevel::Interactive),
+        "3" => Some(PrecisionLevel::MobileSdk),
+        _ => None,
+    }
+}
+
+fn sign(settings: &ClearanceSettings, payload: &str, ip: &str, user_agent: &str) -> String {
+    hex::encode(hmac_sha256(
+        &settings.secret,
+        format!("{}|{}|{}", payload, ip, user_agent).as_bytes(),
+    ))
+}
+
+/// mints a clearance for a human precision level, valid until now + ttl
+pub fn mint_clearance(
+    settings: &ClearanceSettings,
+    level: PrecisionLevel,
+    ip: &str,
+    user_agent: &str,
+    now: i64,
+) -> String {
+    let payload = format!("{}.{}", now.saturating_add(settings.ttl), level as u8);
+    let signature = sign(settings, &payload, ip, user_agent);
+    format!("{}.{}", payload, signature)
+}
+
+/// the precision level of a valid clearance, issued to this IP and user agent
+pub fn check_clearance(
+    settings: &ClearanceSettings,
+    token: &str,
+    ip: &str,
+    user_agent: &str,
+    now: i64,
+) -> Option<PrecisionLevel> {
+    let (payload, signature) = token.rsplit_once('.')?;
+    if !bool::from(
+        sign(settings, payload, ip, user_agent)
+            .as_bytes()
+            .ct_eq(signature.as_bytes()),
+    ) {
+        return None;
+    }
+    let (expires, code) = payload.split_once('.')?;
+    if expires.parse::<i64>().ok()? < now {
+        return None;
+    }
+    level_from_code(code)
+}
+
+/// checks the clearance cookie of the request
+pub fn request_clearance(settings: &ClearanceSettings, reqinfo: &RequestInfo) -> Option<PrecisionLevel> {
+    let token = reqinfo.cookies.get_str(&settings.cookie)?;
+    check_clearance(
+        settings,
+        token,
+        &reqinfo.rinfo.geoip.ipstr,
+        reqinfo.headers.get_str("user-agent").unwrap_or_default(),
+        reqinfo.timestamp.timestamp(),
+    )
+}
+
+/// the Set-Cookie header issuing a clearance to the client of the request
+pub fn clearance_cookie(settings: &ClearanceSettings, level: PrecisionLevel, reqinfo: &RequestInfo) -> String {
+    let token = mint_clearance(
+        settings,
+        level,
+        &reqinfo.rinfo.geoip.ipstr,
+        reqinfo.headers.get_str("user-agent").unwrap_or_default(),
+        reqinfo.timestamp.timestamp(),
+    );
+    format!(
+        "{}={}; Max-Age={}; Path=/; Secure; HttpOnly; SameSite=Lax",
+        settings.cookie, token, settings.ttl
+    )
+}
+
+/// a monitor decision, adding the clearance cookie to the response of the server
+pub fn clearance_decision(cookie: String) -> Decision {
+    let mut headers = HashMap::new();
+    headers.insert("set-cookie".to_string(), cookie);
+    Decision::action(
+        Action {
+            atype: ActionType::Monitor,
+            block_mode: false,
+            status: 200,
+            headers: None,
+            content: String::new(),
+            extra_tags: None,
+            response_headers: Some(headers),
+            delay: None,
+            transform: None,
+            ban: None,
+        },
+        Vec::new(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> ClearanceSettings {
+        ClearanceSettings::resolve(
+            &mut Logs::default(),
+            RawClearanceSettings {
+                secret: "s3cr3t".to_string(),
+                cookie: None,
+                ttl_seconds: Some(60),
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn clearances() {
+        let settings = settings();
+        assert_eq!(settings.cookie, "cf_clearance");
+        let token = mint_clearance(&settings, PrecisionLevel::Interactive, "1.2.3.4", "firefox", 1000);
+        assert_eq!(
+            check_clearance(&settings, &token, "1.2.3.4", "firefox", 1000),
+            Some(PrecisionLevel::Interactive)
+        );
+        // bound to the IP and user agent
+        assert_eq!(check_clearance(&settings, &token, "5.6.7.8", "firefox", 1000), None);
+        assert_eq!(check_clearance(&settings, &token, "1.2.3.4", "curl", 1000), None);
+        assert_eq!(check_clearance(&settings, &token, "1.2.3.4", "firefox", 1061), None);
+        assert_eq!(check_clearance(&settings, "garbage", "1.2.3.4", "firefox", 1000), None);
+        // the level can't be raised
+        let forged = token.replacen(".2.", ".3.", 1);
+        assert_eq!(check_clearance(&settings, &forged, "1.2.3.4", "firefox", 1000), None);
+    }
+}
@@ -0,0 +1,389 @@
+//! Client IP resolution.
+//!
+//! When trusted-proxies.json is present, the client address is derived from the forwarding chain, taken from the
+//! RFC 7239 Forwarded header, or from X-Forwarded-For when it is absent. The connecting address, or the source
+//! address of the PROXY protocol when the integration provides it, is appended as the last hop, unless it already
+//! is the last entry. The Forwarded header is only used when the connecting address is a trusted proxy, and only its
+//! elements added by trusted proxies are kept: the elements on the left of the rightmost untrusted node were written
+//! by that node. The strategies are:
+//!
+//!  * rightmost_untrusted: the rightmost address that is not a trusted proxy,
+//!  * leftmost: the leftmost address, as long as the connecting address is trusted,
+//!  * hop_count: the address found `hops` hops before the connecting address.
+//!
+//! The selected hop is tagged as client-ip-hop:N, 0 being the connecting address. Requests relayed by untrusted
+//! addresses, including forwarding headers sent by an untrusted client, or holding invalid addresses, are tagged as
+//! client-ip-spoofed.
+use arc_swap::ArcSwap;
+use ipnet::IpNet;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::config::raw::RawClientIpSettings;
+use crate::logs::Logs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientIpStrategy {
+    RightmostUntrusted,
+    Leftmost,
+    HopCount(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIpSettings {
+    pub trusted: Vec<IpNet>,
+    pub strategy: ClientIpStrategy,
+}
+
+/// the resolved client address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIp {
+    pub ip: String,
+    /// hops from the connecting address
+    pub hop: usize,
+    pub spoofed: bool,
+    /// protocol and host requested by the client, from the Forwarded header
+    pub proto: Option<String>,
+    pub host: Option<String>,
+}
+
+/// a forwarded-element of the Forwarded header, or an entry of X-Forwarded-For
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForwardedElement {
+    /// the address of the for node
+    pub node: String,
+    pub by: Option<String>,
+    pub proto: Option<String>,
+    pub host: Option<String>,
+}
+
+lazy_static! {
+    static ref SETTINGS: ArcSwap<Option<ClientIpSettings>> = ArcSwap::from_pointee(None);
+}
+
+impl ClientIpSettings {
+    pub fn resolve(logs: &mut Logs, raw: RawClientIpSettings) -> Option<Self> {
+        let trusted = raw
+            .trusted_proxies
+            .iter()
+            .filter_map(|s| {
+                let s = s.trim();
+                let parsed = s
+                    .parse::<IpNet>()
+                    .ok()
+                    .or_else(|| s.parse::<IpAddr>().ok().map(IpNet::from));
+                if parsed.is_none() {
+                    logs.warning(|| format!("invalid trusted proxy {}", s));
+                }
+                parsed.map(|n| n.trunc())
+            })
+            .collect();
+        let strategy = match raw.strategy.as_str() {
+            "rightmost_untrusted" => ClientIpStrategy::RightmostUntrusted,
+            "leftmost" => ClientIpStrategy::Leftmost,
+            "hop_count" => ClientIpStrategy::HopCount(raw.hops.unwrap_or(1)),
+            unknown => {
+                logs.error(|| format!("unknown client ip strategy {}, the client ip is not resolved", unknown));
+                return None;
+            }
+        };
+        Some(ClientIpSettings { trusted, strategy })
+    }
+
+    fn is_trusted(&self, ip: Option<IpAddr>) -> bool {
+        ip.map(|ip| self.trusted.iter().any(|n| n.contains(&ip)))
+            .unwrap_or(false)
+    }
+
+    /// selects the client address in the chain, the connecting address being last
+    pub fn select(&self, chain: &[&str]) -> ClientIp {
+        let parsed: Vec<Option<IpAddr>> = chain.iter().map(|s| s.parse().ok()).collect();
+        let last = chain.len() - 1;
+        let peer_trusted = self.is_trusted(parsed[last]);
+        let idx = match self.strategy {
+            ClientIpStrategy::RightmostUntrusted => {
+                (0..=last).rev().find(|&i| !self.is_trusted(parsed[i])).unwrap_or(0)
+            }
+            ClientIpStrategy::Leftmost if peer_trusted => 0,
+            ClientIpStrategy::Leftmost => last,
+            ClientIpStrategy::HopCount(hops) => last.saturating_sub(hops),
+        };
+        // forwarded addresses can only be trusted when they were added by trusted proxies
+        let untrusted_relay = (last > 0 && !peer_trusted) || (idx + 1..=last).any(|i| !self.is_trusted(parsed[i]));
+        match parsed[idx] {
+            Some(ip) => ClientIp {
+                ip: ip.to_string(),
+                hop: last - idx,
+                spoofed: untrusted_relay,
+                proto: None,
+                host: None,
+            },
+            None => ClientIp {
+                ip: chain[last].to_string(),
+                hop: 0,
+                spoofed: true,
+                proto: None,
+                host: None,
+            },
+        }
+    }
+}
+
+pub fn configure_client_ip(settings: Option<ClientIpSettings>) {
+    SETTINGS.store(Arc::new(settings));
+}
+
+/// true when the client address is resolved from the forwarding headers
+pub fn client_ip_configured() -> bool {
+    SETTINGS.load().is_some()
+}
+
+/// splits on the separator, outside of quoted strings
+fn split_unquoted(s: &str, separator: char) -> Vec<&str> {
+    let mut out = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                out.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    out.push(&s[start..]);
+    out
+}
+
+fn unquote(s: &str) -> &str {
+    let s = s.trim();
+    s.strip_prefix('"').and_then(|s| s.strip_suffix('"')).unwrap_or(s)
+}
+
+/// the address of a node, without its port: 192.0.2.43:47011, "[2001:db8::1]:4711", unknown or _hidden
+pub fn node_address(node: &str) -> &str {
+    let node = unquote(node);
+    if let Some(bracketed) = node.strip_prefix('[') {
+        return bracketed.split(']').next().unwrap_or(bracketed);
+    }
+    match node.split_once(':') {
+        Some((ip, port)) if !port.contains(':') => ip,
+        _ => node,
+    }
+}
+
+/// parses the RFC 7239 Forwarded header, skipping the elements without a for parameter
+pub fn parse_forwarded(value: &str) -> Vec<ForwardedElement> {
+    let mut out = Vec::new();
+    for element in split_unquoted(value, ',') {
+        let mut parsed = ForwardedElement::default();
+        let mut has_node = false;
+        for pair in split_unquoted(element, ';') {
+            let (k, v) = match pair.split_once('=') {
+                Some(kv) => kv,
+                None => continue,
+            };
+            match k.trim().to_ascii_lowercase().as_str() {
+                "for" => {
+                    parsed.node = node_address(v).to_string();
+                    has_node = true;
+                }
+                "by" => parsed.by = Some(node_address(v).to_string()),
+                "proto" => parsed.proto = Some(unquote(v).to_ascii_lowercase()),
+                "host" => parsed.host = Some(unquote(v).to_string()),
+                _ => (),
+            }
+        }
+        if has_node {
+            out.push(parsed);
+        }
+    }
+    out
+}
+
+/// the entries of the X-Forwarded-For header
+fn xff_chain(headers: &HashMap<String, String>) -> Vec<ForwardedElement> {
+    headers
+        .get("x-forwarded-for")
+        .map(|xff| {
+            xff.split(',')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .map(|s| ForwardedElement {
+                    node: node_address(s).to_string(),
+                    ..ForwardedElement::default()
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// the address appended to X-Forwarded-For by the proxy in front of curiefense, that is the connecting address
+pub fn xff_peer(headers: &HashMap<String, String>) -> Option<String> {
+    headers
+        .get("x-forwarded-for")
+        .and_then(|xff| xff.rsplit(',').next())
+        .map(|s| node_address(s).to_string())
+        .filter(|s| !s.is_empty())
+}
+
+impl ClientIpSettings {
+    /// the forwarding chain, from the Forwarded header when it was written by trusted proxies, or from
+    /// X-Forwarded-For, the boolean being true when an untrusted peer sent a Forwarded header
+    pub fn forwarded_chain(&self, peer: &str, headers: &HashMap<String, String>) -> (Vec<ForwardedElement>, bool) {
+        let forwarded = match headers.get("forwarded") {
+            None => return (xff_chain(headers), false),
+            Some(f) => f,
+        };
+        if !self.is_trusted(peer.parse().ok()) {
+            return (xff_chain(headers), true);
+        }
+        let mut chain = parse_forwarded(forwarded);
+        if let Some(untrusted) = chain.iter().rposition(|e| !self.is_trusted(e.node.parse().ok())) {
+            chain.drain(..untrusted);
+        }
+        if chain.is_empty() {
+            (xff_chain(headers), false)
+        } else {
+            (chain, false)
+        }
+    }
+}
+
+/// resolves the client address of a request, None when no strategy is configured
+pub fn resolve_client_ip(peer: &str, headers: &HashMap<String, String>) -> Option<ClientIp> {
+    let settings = SETTINGS.load();
+    let settings = settings.as_ref().as_ref()?;
+    let peer = node_address(peer);
+    let (elements, injected) = settings.forwarded_chain(peer, headers);
+    let mut chain: Vec<&str> = elements.iter().map(|e| e.node.as_str()).collect();
+    if chain.last() != Some(&peer) {
+        chain.push(peer);
+    }
+    let mut client = settings.select(&chain);
+    client.spoofed |= injected;
+    if let Some(element) = elements.get(chain.len() - 1 - client.hop) {
+        client.proto = element.proto.clone();
+        client.host = element.host.clone();
+    }
+    Some(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(strategy: &str, hops: Option<usize>) -> ClientIpSettings {
+        ClientIpSettings::resolve(
+            &mut Logs::default(),
+            RawClientIpSettings {
+                trusted_proxies: vec!["10.0.0.0/8".to_string(), "192.0.2.1".to_string()],
+                strategy: strategy.to_string(),
+                hops,
+            },
+        )
+        .unwrap()
+    }
+
+    fn client(ip: &str, hop: usize, spoofed: bool) -> ClientIp {
+        ClientIp {
+            ip: ip.to_string(),
+            hop,
+            spoofed,
+            proto: None,
+            host: None,
+        }
+    }
+
+    #[test]
+    fn strategies() {
+        let chain = ["1.2.3.4", "5.6.7.8", "10.1.1.1", "192.0.2.1"];
+        let rightmost = settings("rightmost_untrusted", None);
+        assert_eq!(rightmost.select(&chain), client("5.6.7.8", 2, false));
+        assert_eq!(rightmost.select(&["10.0.0.1"]), client("10.0.0.1", 0, false));
+        // a client pretending to be behind a proxy
+        assert_eq!(rightmost.select(&["10.0.0.1", "8.8.8.8"]), client("8.8.8.8", 0, true));
+
+        let leftmost = settings("leftmost", None);
+        assert_eq!(leftmost.select(&chain), client("1.2.3.4", 3, true));
+        assert_eq!(leftmost.select(&["1.2.3.4", "8.8.8.8"]), client("8.8.8.8", 0, true));
+
+        let hops = settings("hop_count", Some(2));
+        assert_eq!(hops.select(&chain), client("5.6.7.8", 2, false));
+        assert_eq!(hops.select(&["192.0.2.1"]), client("192.0.2.1", 0, false));
+        assert_eq!(
+            hops.select(&["garbage", "10.0.0.2", "192.0.2.1"]),
+            client("192.0.2.1", 0, true)
+        );
+
+        assert!(ClientIpSettings::resolve(
+            &mut Logs::default(),
+            RawClientIpSettings {
+                trusted_proxies: Vec::new(),
+                strategy: "random".to_string(),
+                hops: None,
+            }
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn forwarded() {
+        let chain = parse_forwarded(
+            r#"for=192.0.2.43:47011;proto=HTTPS;host="example.com", For="[2001:db8:cafe::17]:4711";by=_hidden, by=10.0.0.1, for=unknown"#,
+        );
+        let nodes: Vec<&str> = chain.iter().map(|e| e.node.as_str()).collect();
+        assert_eq!(nodes, vec!["192.0.2.43", "2001:db8:cafe::17", "unknown"]);
+        assert_eq!(chain[0].proto.as_deref(), Some("https"));
+        assert_eq!(chain[0].host.as_deref(), Some("example.com"));
+        assert_eq!(chain[1].by.as_deref(), Some("_hidden"));
+        assert_eq!(node_address("2001:db8::1"), "2001:db8::1");
+        assert_eq!(node_address(" 192.0.2.1:80"), "192.0.2.1");
+
+        let trusted = settings("rightmost_untrusted", None);
+        let mut headers = HashMap::new();
+        headers.insert("x-forwarded-for".to_string(), "1.2.3.4, 5.6.7.8".to_string());
+        assert_eq!(trusted.forwarded_chain("10.0.0.1", &headers).0.len(), 2);
+        assert_eq!(xff_peer(&headers).as_deref(), Some("5.6.7.8"));
+        headers.insert("forwarded".to_string(), "for=9.9.9.9".to_string());
+        let (chain, injected) = trusted.forwarded_chain("10.0.0.1", &headers);
+        assert_eq!(chain[0].node, "9.9.9.9");
+        assert!(!injected);
+    }
+
+    #[test]
+    fn injected_forwarded() {
+        let trusted = settings("rightmost_untrusted", None);
+        // a client talking directly to the proxy, which only appends to X-Forwarded-For
+        let mut headers = HashMap::new();
+        headers.insert("forwarded".to_string(), "for=8.8.8.8".to_string());
+        headers.insert("x-forwarded-for".to_string(), "1.2.3.4".to_string());
+        let peer = xff_peer(&headers).unwrap();
+        assert_eq!(peer, "1.2.3.4");
+        let (chain, injected) = trusted.forwarded_chain(&peer, &headers);
+        assert_eq!(
+            chain.iter().map(|e| e.node.as_str()).collect::<Vec<_>>(),
+            vec!["1.2.3.4"]
+        );
+        assert!(injected);
+
+        // elements written by an untrusted node are dropped
+        headers.insert(
+            "forwarded".to_string(),
+            "for=8.8.8.8, for=5.6.7.8, for=10.1.1.1".to_string(),
+        );
+        let (chain, injected) = trusted.forwarded_chain("10.0.0.1", &headers);
+        assert_eq!(
+            chain.iter().map(|e| e.node.as_str()).collect::<Vec<_>>(),
+            vec!["5.6.7.8", "10.1.1.1"]
+        );
+        assert!(!injected);
+    }
+}
@@ -0,0 +1,93 @@
+//! Shared redis round trip of the client state stages.
+//!
+//! Several stages read, and update, some state of the client that is kept in redis. Instead of querying redis one
+//! after the other, each stage adds its commands to a single pipeline, that is sent once per request, and reads its
+//! replies back once it is done.
+use redis::{FromRedisValue, Value};
+
+use crate::redis::redis_async_conn;
+
+/// the position of the replies of a stage in the pipeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pending {
+    start: usize,
+    len: usize,
+}
+
+/// the commands of all the stages
+#[derive(Default)]
+pub struct StateQuery {
+    pipe: redis::Pipeline,
+    replies: usize,
+}
+
+impl StateQuery {
+    /// adds the commands of a stage, `f` returns the number of commands whose reply is not ignored
+    pub fn add<F: FnOnce(&mut redis::Pipeline) -> usize>(&mut self, f: F) -> Pending {
+        let len = f(&mut self.pipe);
+        let start = self.replies;
+        self.replies += len;
+        Pending { start, len }
+    }
+
+    pub async fn run(self) -> StateReplies {
+        if self.pipe.cmd_iter().next().is_none() {
+            return StateReplies(Ok(Vec::new()));
+        }
+        let res: anyhow::Result<Vec<Value>> = async {
+            let mut conn = redis_async_conn().await?;
+            Ok(self.pipe.query_async(&mut conn).await?)
+        }
+        .await;
+        StateReplies(res.map_err(|rr| rr.to_string()))
+    }
+}
+
+/// the replies of all the stages, or the error of the round trip
+#[derive(Debug)]
+pub struct StateReplies(Result<Vec<Value>, String>);
+
+impl StateReplies {
+    /// the replies of a stage, parsed as a tuple or a vector
+    pub fn get<T: FromRedisValue>(&self, pending: Pending) -> anyhow::Result<T> {
+        let values = self.0.as_ref().map_err(|rr| anyhow::anyhow!("{}", rr))?;
+        let replies = values
+            .get(pending.start..pending.start + pending.len)
+            .ok_or_else(|| anyhow::anyhow!("missing replies, got {} of them", values.len()))?;
+        Ok(T::from_redis_value(&Value::Bulk(replies.to_vec()))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stage_replies() {
+        let mut query = StateQuery::default();
+        let first = query.add(|pipe| {
+            pipe.cmd("SET").arg("a").arg(1).ignore();
+            pipe.cmd("EXISTS").arg("a");
+            1
+        });
+        let second = query.add(|pipe| {
+            pipe.cmd("HMGET").arg("b").arg("x").arg("y");
+            pipe.cmd("HMGET").arg("c").arg("x").arg("y");
+            2
+        });
+        let replies = StateReplies(Ok(vec![
+            Value::Int(1),
+            Value::Bulk(vec![Value::Data(b"1.5".to_vec()), Value::Nil]),
+            Value::Bulk(vec![Value::Nil, Value::Nil]),
+        ]));
+        let (found,): (u64,) = replies.get(first).unwrap();
+        assert_eq!(found, 1);
+        let states: Vec<(Option<f64>, Option<i64>)> = replies.get(second).unwrap();
+        assert_eq!(states, vec![(Some(1.5), None), (None, None)]);
+
+        let failed = StateReplies(Err("connection refused".to_string()));
+        assert!(failed.get::<(u64,)>(first).is_err());
+        let truncated = StateReplies(Ok(vec![Value::Int(1)]));
+        assert!(truncated.get::<Vec<(Option<f64>, Option<i64>)>>(second).is_err());
+    }
+}
@@ -1,16 +1,23 @@
+use crate::body::{ProtobufSchema, UploadPolicy};
 use crate::config::matchers::Matching;
+use crate::config::prefilter::LiteralPrefilter;
 use crate::config::raw::{
-    ContentType, RawContentFilterEntryMatch, RawContentFilterProfile, RawContentFilterProperties, RawContentFilterRule,
+    ContentType, RawArgumentLimits, RawContentFilterEntryMatch, RawContentFilterProfile, RawContentFilterProperties,
+    RawContentFilterRule, RawPathSchema, RawRuleExclusion,
 };
-use crate::interface::{RawTags, SimpleAction};
+use crate::contentfilter::learning::ValueShape;
+use crate::interface::{Location, RawTags, SimpleAction};
 use crate::logs::Logs;
+use crate::masking::MaskingPolicy;
+use crate::utils::decoders::base64dec_all;
 
-use hyperscan::prelude::{pattern, Builder, CompileFlags, Pattern, Patterns, VectoredDatabase};
-use hyperscan::Vectored;
+use hyperscan::prelude::{pattern, Builder, CompileFlags, Pattern, Patterns, StreamingDatabase, VectoredDatabase};
+use hyperscan::{StreamingMode, Vectored};
 use regex::{Regex, RegexBuilder};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct Section<A> {
@@ -31,14 +38,214 @@ pub struct ContentFilterProfile {
     pub ignore_alphanum: bool,
     pub sections: Section<ContentFilterSection>,
     pub decoding: Vec<Transformation>,
+    /// maximum number of times the decoding chain is applied to a value
+    pub decoding_depth: usize,
     pub masking_seed: Vec<u8>,
     pub content_type: Vec<ContentType>,
     pub ignore_body: bool,
     pub max_body_size: usize,
+    pub max_body_scan_size: usize,
     pub max_body_depth: usize,
+    pub max_graphql_complexity: usize,
+    pub max_request_memory: usize,
+    pub protobuf: Option<Arc<ProtobufSchema>>,
+    pub uploads: UploadPolicy,
     pub referer_as_uri: bool,
+    pub case_insensitive_names: bool,
     pub action: SimpleAction,
     pub tags: HashSet<String>,
+    pub mode: ContentFilterMode,
+    pub schema: Vec<PathSchema>,
+    /// anomaly scoring mode, with its blocking threshold
+    pub anomaly_threshold: Option<u32>,
+    pub rule_exclusions: Vec<RuleExclusion>,
+    pub lexical: LexicalAnalysis,
+    pub limits: ArgumentLimits,
+    /// the raw body chunks are scanned as they are received
+    pub stream_body: bool,
+    pub masking: MaskingPolicy,
+}
+
+/// request wide hard limits, usize::MAX when unlimited
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArgumentLimits {
+    pub max_args: usize,
+    pub max_headers: usize,
+    pub max_value_length: usize,
+    pub max_decoded_body_size: usize,
+}
+
+impl Default for ArgumentLimits {
+    fn default() -> Self {
+        ArgumentLimits {
+            max_args: usize::MAX,
+            max_headers: usize::MAX,
+            max_value_length: usize::MAX,
+            max_decoded_body_size: usize::MAX,
+        }
+    }
+}
+
+impl ArgumentLimits {
+    pub fn resolve(raw: RawArgumentLimits) -> Self {
+        let limit = |v: Option<usize>| nonzero(v.unwrap_or(usize::MAX));
+        ArgumentLimits {
+            max_args: limit(raw.max_args),
+            max_headers: limit(raw.max_headers),
+            max_value_length: limit(raw.max_value_length),
+            max_decoded_body_size: limit(raw.max_decoded_body_size),
+        }
+    }
+}
+
+/// selects the lexical analyzers, and the sections they analyze
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LexicalAnalysis {
+    #[serde(default)]
+    pub sqli: bool,
+    #[serde(default)]
+    pub xss: bool,
+    /// all sections are analyzed when empty
+    #[serde(default)]
+    pub sections: Vec<SectionIdx>,
+}
+
+impl LexicalAnalysis {
+    pub fn analyzes(&self, idx: SectionIdx) -> bool {
+        self.sections.is_empty() || self.sections.contains(&idx)
+    }
+}
+
+/// the expected arguments of the paths matching a regex
+#[derive(Debug, Clone)]
+pub struct PathSchema {
+    pub path: Regex,
+    /// uppercase, empty for all methods
+    pub methods: Vec<String>,
+    pub params: Vec<ParamSchema>,
+    pub allow_unknown: bool,
+    pub block: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ParamSchema {
+    /// the configured name regex, used in the block reasons
+    pub name_src: String,
+    pub name: Regex,
+    pub shape: Option<ValueShape>,
+    pub min_length: usize,
+    pub max_length: usize,
+    pub required: bool,
+}
+
+impl PathSchema {
+    pub fn resolve(raw: RawPathSchema, case_insensitive: bool) -> anyhow::Result<Self> {
+        let params = raw
+            .params
+            .into_iter()
+            .map(|p| {
+                Ok(ParamSchema {
+                    name: RegexBuilder::new(&format!("^(?:{})$", p.name))
+                        .case_insensitive(case_insensitive)
+                        .build()?,
+                    name_src: p.name,
+                    shape: p.shape,
+                    min_length: p.min_length.unwrap_or(0),
+                    max_length: p.max_length.unwrap_or(usize::MAX),
+                    required: p.required,
+                })
+            })
+            .collect::<anyhow::Result<Vec<ParamSchema>>>()?;
+        Ok(PathSchema {
+            path: Regex::new(&raw.path)?,
+            methods: raw.methods.iter().map(|m| m.to_uppercase()).collect(),
+            params,
+            allow_unknown: raw.allow_unknown,
+            block: raw.block,
+        })
+    }
+}
+
+/// a content filter rule that is not applied on some paths or arguments
+#[derive(Debug, Clone)]
+pub struct RuleExclusion {
+    pub rule: String,
+    pub path: Option<Regex>,
+    pub arg: Option<String>,
+}
+
+/// converts a path glob to an anchored regular expression
+fn glob_regex(glob: &str) -> String {
+    let mut out = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                out.push_str(".*");
+            }
+            '*' => out.push_str("[^/]*"),
+            '?' => out.push_str("[^/]"),
+            _ => out.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    out.push('$');
+    out
+}
+
+impl RuleExclusion {
+    pub fn resolve(raw: RawRuleExclusion) -> anyhow::Result<Self> {
+        Ok(RuleExclusion {
+            path: raw.path.map(|glob| Regex::new(&glob_regex(&glob))).transpose()?,
+            rule: raw.rule,
+            arg: raw.arg,
+        })
+    }
+
+    /// libinjection and lexical matches are excluded by their rule family, such as "sqli" or "lexical-xss"
+    pub fn matches(&self, ruleid: &str, path: &str, location: &Location, case_insensitive: bool) -> bool {
+        let rule_matches = ruleid == self.rule || ruleid.split(':').next() == Some(self.rule.as_str());
+        let path_matches = self.path.as_ref().map(|re| re.is_match(path)).unwrap_or(true);
+        let arg_matches = match (&self.arg, location.name()) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(arg), Some(name)) if case_insensitive => arg.eq_ignore_ascii_case(name),
+            (Some(arg), Some(name)) => arg == name,
+        };
+        rule_matches && path_matches && arg_matches
+    }
+}
+
+/// how the content filter results are used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentFilterMode {
+    /// the results are part of the decision
+    Enforce,
+    /// everything is evaluated and reported, but the decision is never affected
+    Shadow,
+    /// like shadow, and the observed arguments are aggregated into a suggested positive security model
+    Learning,
+}
+
+impl ContentFilterMode {
+    /// true when the results must not affect the decision
+    pub fn is_passive(&self) -> bool {
+        !matches!(self, ContentFilterMode::Enforce)
+    }
+}
+
+impl std::str::FromStr for ContentFilterMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "enforce" => Ok(ContentFilterMode::Enforce),
+            "shadow" => Ok(ContentFilterMode::Shadow),
+            "learning" => Ok(ContentFilterMode::Learning),
+            _ => Err(anyhow::anyhow!("invalid content filter mode {}", s)),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -50,17 +257,70 @@ pub struct ContentFilterRule {
     pub subcategory: String,
     pub tags: HashSet<String>,
     pub pattern: Pattern,
+    pub score: u32,
 }
 
+/// how much of the request body is analyzed, depending on its size
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyAnalysisDepth {
+    /// the body is decoded and scanned
+    Full,
+    /// the body is not decoded, only the request metadata is analyzed
+    MetadataOnly,
+    /// the body is larger than the hard limit, and the request is blocked
+    TooLarge,
+    /// the profile ignores bodies
+    Ignored,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub enum Transformation {
+    #[serde(rename = "base64")]
     Base64Decode,
+    #[serde(rename = "html")]
     HtmlEntitiesDecode,
+    #[serde(rename = "unicode")]
     UnicodeDecode,
+    #[serde(rename = "url")]
     UrlDecode,
+    /// url decoding, applied twice
+    #[serde(rename = "double_url")]
+    DoubleUrlDecode,
+    /// base64 encoded gzip data
+    #[serde(rename = "gzip")]
+    GzipDecode,
+}
+
+/// upper bound on the number of times the decoding chain is applied
+pub const MAX_DECODING_DEPTH: usize = 16;
+
+impl Transformation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transformation::Base64Decode => "base64",
+            Transformation::HtmlEntitiesDecode => "html",
+            Transformation::UnicodeDecode => "unicode",
+            Transformation::UrlDecode => "url",
+            Transformation::DoubleUrlDecode => "double_url",
+            Transformation::GzipDecode => "gzip",
+        }
+    }
 }
 
 impl ContentFilterProfile {
+    /// selects the analysis depth for a body of the given size
+    pub fn body_depth(&self, size: usize) -> BodyAnalysisDepth {
+        if self.ignore_body {
+            BodyAnalysisDepth::Ignored
+        } else if size > self.max_body_size {
+            BodyAnalysisDepth::TooLarge
+        } else if size > self.max_body_scan_size {
+            BodyAnalysisDepth::MetadataOnly
+        } else {
+            BodyAnalysisDepth::Full
+        }
+    }
+
     pub fn default_from_seed(seed: &str) -> Self {
         ContentFilterProfile {
             id: "__default__".to_string(),
@@ -99,6 +359,7 @@ impl ContentFilterProfile {
                 },
             },
             decoding: vec![Transformation::Base64Decode, Transformation::UrlDecode],
+            decoding_depth: 1,
             masking_seed: seed.as_bytes().to_vec(),
             active: HashSet::default(),
             ignore: HashSet::default(),
@@ -106,10 +367,24 @@ impl ContentFilterProfile {
             content_type: Vec::new(),
             ignore_body: false,
             max_body_size: usize::MAX,
+            max_body_scan_size: usize::MAX,
             max_body_depth: usize::MAX,
+            max_graphql_complexity: usize::MAX,
+            max_request_memory: usize::MAX,
+            protobuf: None,
+            uploads: UploadPolicy::default(),
             referer_as_uri: false,
+            case_insensitive_names: false,
             action: SimpleAction::default(),
             tags: HashSet::new(),
+            mode: ContentFilterMode::Enforce,
+            schema: Vec::new(),
+            anomaly_threshold: None,
+            rule_exclusions: Vec::new(),
+            lexical: LexicalAnalysis::default(),
+            limits: ArgumentLimits::default(),
+            stream_body: false,
+            masking: MaskingPolicy::default(),
         }
     }
 }
@@ -130,7 +405,7 @@ pub struct ContentFilterEntryMatch {
     pub exclusions: HashSet<String>,
 }
 
-#[derive(Debug, Clone, Eq, Serialize, PartialEq, Copy)]
+#[derive(Debug, Clone, Eq, Serialize, Deserialize, PartialEq, Copy)]
 #[serde(rename_all = "snake_case")]
 pub enum SectionIdx {
     Headers,
@@ -194,7 +469,22 @@ where
 
 pub struct ContentFilterRules {
     pub db: VectoredDatabase,
+    /// the same rules, compiled for body streaming when the profile requires it
+    pub stream_db: Option<StreamingDatabase>,
     pub ids: Vec<ContentFilterRule>,
+    /// when set, data that does not match this prefilter can't match any rule
+    pub prefilter: Option<LiteralPrefilter>,
+}
+
+// the compiled databases are not printable
+impl std::fmt::Debug for ContentFilterRules {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ContentFilterRules")
+            .field("rules", &self.ids.len())
+            .field("streaming", &self.stream_db.is_some())
+            .field("prefilter", &self.prefilter.is_some())
+            .finish()
+    }
 }
 
 impl ContentFilterRules {
@@ -202,7 +492,9 @@ impl ContentFilterRules {
         let pattern: Pattern = pattern! { "^TEST$" };
         ContentFilterRules {
             db: pattern.build().unwrap(),
+            stream_db: None,
             ids: Vec::new(),
+            prefilter: None,
         }
     }
 }
@@ -245,7 +537,7 @@ fn mk_entry_match(
     ))
 }
 
-fn mk_section(
+pub(crate) fn mk_section(
     allsections: &RawContentFilterProperties,
     props: RawContentFilterProperties,
     lowercase_key: bool,
@@ -284,21 +576,45 @@ fn convert_entry(
     entry: RawContentFilterProfile,
 ) -> anyhow::Result<(String, ContentFilterProfile)> {
     let mut decoding = Vec::new();
-    // default order
-    if entry.decoding.base64 {
-        decoding.push(Transformation::Base64Decode)
-    }
-    if entry.decoding.dual {
-        decoding.push(Transformation::UrlDecode)
-    }
-    if entry.decoding.html {
-        decoding.push(Transformation::HtmlEntitiesDecode)
-    }
-    if entry.decoding.unicode {
-        decoding.push(Transformation::UnicodeDecode)
+    if !entry.decoding.chain.is_empty() {
+        decoding = entry.decoding.chain.clone();
+    } else {
+        // default order
+        if entry.decoding.gzip {
+            decoding.push(Transformation::GzipDecode)
+        }
+        if entry.decoding.base64 {
+            decoding.push(Transformation::Base64Decode)
+        }
+        if entry.decoding.dual {
+            decoding.push(Transformation::UrlDecode)
+        }
+        if entry.decoding.double_url {
+            decoding.push(Transformation::DoubleUrlDecode)
+        }
+        if entry.decoding.html {
+            decoding.push(Transformation::HtmlEntitiesDecode)
+        }
+        if entry.decoding.unicode {
+            decoding.push(Transformation::UnicodeDecode)
+        }
     }
+    let decoding_depth = entry.decoding.max_depth.unwrap_or(1).clamp(1, MAX_DECODING_DEPTH);
     let max_body_size = nonzero(entry.max_body_size.unwrap_or(usize::MAX));
+    let max_body_scan_size = nonzero(entry.max_body_scan_size.unwrap_or(usize::MAX));
     let max_body_depth = nonzero(entry.max_body_depth.unwrap_or(usize::MAX));
+    let max_graphql_complexity = nonzero(entry.max_graphql_complexity.unwrap_or(usize::MAX));
+    let max_request_memory = nonzero(entry.max_request_memory.unwrap_or(usize::MAX));
+    let protobuf = match &entry.protobuf_descriptors {
+        None => None,
+        Some(encoded) => {
+            let descriptors = base64dec_all(encoded).map_err(|rr| anyhow::anyhow!("protobuf descriptors: {}", rr))?;
+            Some(Arc::new(ProtobufSchema::from_descriptor_set(
+                &descriptors,
+                entry.protobuf_message.as_deref(),
+            )?))
+        }
+    };
     let id = entry.id;
     let action = match entry.action {
         None => SimpleAction::default(),
@@ -312,6 +628,33 @@ fn convert_entry(
             SimpleAction::default()
         }),
     };
+    let case_insensitive_names = entry.case_insensitive_names;
+    // an invalid schema is dropped, the other ones are still enforced
+    let schema = entry
+        .schema
+        .into_iter()
+        .filter_map(|raw| {
+            let path = raw.path.clone();
+            PathSchema::resolve(raw, case_insensitive_names)
+                .map_err(|rr| logs.error(|| format!("content filter entry {}, schema {}: {}", id, path, rr)))
+                .ok()
+        })
+        .collect();
+    let rule_exclusions = entry
+        .rule_exclusions
+        .into_iter()
+        .map(RuleExclusion::resolve)
+        .collect::<anyhow::Result<Vec<RuleExclusion>>>()
+        .map_err(|rr| anyhow::anyhow!("rule exclusions: {}", rr))?;
+    let masking = MaskingPolicy::resolve(logs, entry.masking, &entry.masking_seed)
+        .map_err(|rr| anyhow::anyhow!("masking policy: {}", rr))?;
+    let mode = match entry.mode.as_deref() {
+        None => ContentFilterMode::Enforce,
+        Some(m) => m.parse().unwrap_or_else(|rr: anyhow::Error| {
+            logs.error(|| format!("{} in content filter profile {}", rr, id));
+            ContentFilterMode::Enforce
+        }),
+    };
     Ok((
         id.clone(),
         ContentFilterProfile {
@@ -320,12 +663,13 @@ fn convert_entry(
             ignore_alphanum: entry.ignore_alphanum,
             sections: Section {
                 headers: mk_section(&entry.allsections, entry.headers, true)?,
-                cookies: mk_section(&entry.allsections, entry.cookies, false)?,
-                args: mk_section(&entry.allsections, entry.args, false)?,
+                cookies: mk_section(&entry.allsections, entry.cookies, entry.case_insensitive_names)?,
+                args: mk_section(&entry.allsections, entry.args, entry.case_insensitive_names)?,
                 path: mk_section(&entry.allsections, entry.path, false)?,
-                plugins: mk_section(&entry.allsections, entry.plugins, false)?,
+                plugins: mk_section(&entry.allsections, entry.plugins, entry.case_insensitive_names)?,
             },
             decoding,
+            decoding_depth,
             masking_seed: entry.masking_seed.as_bytes().to_vec(),
             active: entry.active.into_iter().collect(),
             ignore: entry.ignore.into_iter().collect(),
@@ -333,10 +677,28 @@ fn convert_entry(
             content_type: entry.content_type,
             ignore_body: entry.ignore_body,
             max_body_size,
+            max_body_scan_size,
             max_body_depth,
+            max_graphql_complexity,
+            max_request_memory,
+            protobuf,
+            uploads: UploadPolicy {
+                max_file_size: nonzero(entry.max_upload_size.unwrap_or(usize::MAX)),
+                allowed_types: entry.allowed_upload_types,
+                skip_contents: entry.skip_upload_contents,
+            },
             referer_as_uri: entry.referer_as_uri,
+            case_insensitive_names: entry.case_insensitive_names,
             action,
             tags: entry.tags.into_iter().collect(),
+            mode,
+            schema,
+            anomaly_threshold: entry.anomaly_threshold,
+            rule_exclusions,
+            lexical: entry.lexical,
+            limits: ArgumentLimits::resolve(entry.limits),
+            stream_body: entry.stream_body,
+            masking,
         },
     ))
 }
@@ -388,6 +750,7 @@ pub fn convert_rule(entry: RawContentFilterRule) -> anyhow::Result<ContentFilter
     Ok(ContentFilterRule {
         id: entry.id,
         operand: entry.operand,
+        score: entry.score.unwrap_or(entry.risk as u32),
         risk: entry.risk,
         category: entry.category,
         subcategory: entry.subcategory,
@@ -441,22 +804,52 @@ pub fn resolve_rules(
         false
     };
 
-    let build_from_profile = |prof: &ContentFilterProfile| -> anyhow::Result<ContentFilterRules> {
+    let build_from_profile = |logs: &mut Logs, prof: &ContentFilterProfile| -> anyhow::Result<ContentFilterRules> {
         let ids: Vec<ContentFilterRule> = rules.iter().filter(|r| rule_kept(r, prof)).cloned().collect();
         if ids.is_empty() {
             return Err(anyhow::anyhow!("no rules were selected, empty profile"));
         }
-        Patterns::from_iter(ids.iter().map(|i| i.pattern.clone()))
-            .build::<Vectored>()
-            .map(|db| ContentFilterRules { db, ids })
+        let prefilter = LiteralPrefilter::build(ids.iter().map(|i| i.operand.as_str()));
+        let patterns = Patterns::from_iter(ids.iter().map(|i| i.pattern.clone()));
+        let db = patterns.build::<Vectored>()?;
+        // bodies are buffered when the rules can't be compiled for streaming
+        let stream_db = if prof.stream_body {
+            match patterns.build::<StreamingMode>() {
+                Ok(db) => Some(db),
+                Err(rr) => {
+                    logs.error(|| {
+                        format!(
+                            "When building the streaming rules of profile {}, error: {}",
+                            prof.id, rr
+                        )
+                    });
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        Ok(ContentFilterRules {
+            db,
+            stream_db,
+            ids,
+            prefilter,
+        })
     };
 
     let mut out: HashMap<String, ContentFilterRules> = HashMap::new();
 
     for v in profiles.values() {
-        match build_from_profile(v) {
+        match build_from_profile(logs, v) {
             Ok(p) => {
-                logs.debug(|| format!("Loaded profile {} with {} rules", v.id, p.ids.len()));
+                logs.debug(|| {
+                    format!(
+                        "Loaded profile {} with {} rules, prefilter: {}",
+                        v.id,
+                        p.ids.len(),
+                        p.prefilter.as_ref().map(|pf| pf.literals.len()).unwrap_or(0)
+                    )
+                });
                 out.insert(v.id.to_string(), p);
             }
             Err(rr) => logs.warning(|| format!("When building profile {}, error: {}", v.id, rr)),
@@ -465,3 +858,46 @@ pub fn resolve_rules(
 
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn body_depth_tiers() {
+        let mut profile = ContentFilterProfile::default_from_seed("seed");
+        assert_eq!(profile.body_depth(1 << 30), BodyAnalysisDepth::Full);
+        profile.max_body_scan_size = 128 * 1024;
+        profile.max_body_size = 1024 * 1024;
+        assert_eq!(profile.body_depth(1000), BodyAnalysisDepth::Full);
+        assert_eq!(profile.body_depth(128 * 1024 + 1), BodyAnalysisDepth::MetadataOnly);
+        assert_eq!(profile.body_depth(1024 * 1024 + 1), BodyAnalysisDepth::TooLarge);
+        profile.ignore_body = true;
+        assert_eq!(profile.body_depth(1024 * 1024 + 1), BodyAnalysisDepth::Ignored);
+    }
+
+    #[test]
+    fn rule_exclusions() {
+        let exclusion = RuleExclusion::resolve(RawRuleExclusion {
+            rule: "100".to_string(),
+            path: Some("/api/*/comments/**".to_string()),
+            arg: Some("Body".to_string()),
+        })
+        .unwrap();
+        let arg = Location::UriArgumentValue("body".to_string(), "x".to_string());
+        assert!(exclusion.matches("100", "/api/v1/comments/3/edit", &arg, true));
+        assert!(!exclusion.matches("100", "/api/v1/comments/3/edit", &arg, false));
+        assert!(!exclusion.matches("101", "/api/v1/comments/3", &arg, true));
+        assert!(!exclusion.matches("100", "/api/v1/v2/comments/3", &arg, true));
+        assert!(!exclusion.matches("100", "/api/v1/comments/3", &Location::Uri, true));
+
+        let sqli = RuleExclusion::resolve(RawRuleExclusion {
+            rule: "sqli".to_string(),
+            path: None,
+            arg: None,
+        })
+        .unwrap();
+        assert!(sqli.matches("sqli:s&1", "/", &Location::Body, false));
+        assert!(!sqli.matches("xss", "/", &Location::Body, false));
+    }
+}
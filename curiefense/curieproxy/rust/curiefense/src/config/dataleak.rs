@@ -0,0 +1,254 @@
+//! Data leak rules, from dataleak-rules.json.
+//!
+//! All the rules are compiled in a single Hyperscan database, that finds which rules match a response
+//! body. The matching spans are then found with the regex of each matching rule, and validated
+//! according to the rule kind (checksum for credit card numbers, reserved ranges for SSNs).
+use hyperscan::prelude::{Builder, CompileFlags, Pattern, Patterns};
+use hyperscan::{Block, BlockDatabase};
+use regex::bytes::Regex;
+use std::collections::{HashMap, HashSet};
+use std::iter::FromIterator;
+use std::sync::Arc;
+
+use crate::config::raw::{RawDataLeakRule, RawDataLeakSettings};
+use crate::interface::SimpleAction;
+use crate::logs::Logs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataLeakKind {
+    CreditCard,
+    Ssn,
+    ApiKey,
+    Regex,
+}
+
+impl DataLeakKind {
+    fn parse(kind: &str) -> anyhow::Result<Self> {
+        match kind {
+            "credit_card" => Ok(DataLeakKind::CreditCard),
+            "ssn" => Ok(DataLeakKind::Ssn),
+            "api_key" => Ok(DataLeakKind::ApiKey),
+            "regex" => Ok(DataLeakKind::Regex),
+            _ => Err(anyhow::anyhow!("unknown data leak rule kind {}", kind)),
+        }
+    }
+
+    /// the builtin pattern of this kind
+    fn pattern(&self) -> Option<&'static str> {
+        match self {
+            DataLeakKind::CreditCard => Some(r"\b[0-9](?:[ -]?[0-9]){12,18}\b"),
+            DataLeakKind::Ssn => Some(r"\b[0-9]{3}-[0-9]{2}-[0-9]{4}\b"),
+            DataLeakKind::ApiKey => Some(
+                r"\b(?:AKIA[0-9A-Z]{16}|AIza[0-9A-Za-z_-]{35}|[sr]k_live_[0-9A-Za-z]{24,}|gh[pousr]_[0-9A-Za-z]{36}|xox[abprs]-[0-9A-Za-z-]{10,})\b",
+            ),
+            DataLeakKind::Regex => None,
+        }
+    }
+
+    /// checks that a span matched by the rule pattern is really sensitive data
+    pub fn validate(&self, matched: &str) -> bool {
+        match self {
+            DataLeakKind::CreditCard => luhn(matched),
+            DataLeakKind::Ssn => {
+                let mut groups = matched.split('-');
+                let area = groups.next().unwrap_or("000");
+                let group = groups.next().unwrap_or("00");
+                let serial = groups.next().unwrap_or("0000");
+                area != "000" && area != "666" && !area.starts_with('9') && group != "00" && serial != "0000"
+            }
+            DataLeakKind::ApiKey | DataLeakKind::Regex => true,
+        }
+    }
+}
+
+/// Luhn checksum of the digits of a string
+fn luhn(value: &str) -> bool {
+    let digits: Vec<u32> = value.chars().filter_map(|c| c.to_digit(10)).collect();
+    if digits.len() < 13 || digits.len() > 19 {
+        return false;
+    }
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, d)| {
+            if i % 2 == 1 {
+                let doubled = d * 2;
+                if doubled > 9 {
+                    doubled - 9
+                } else {
+                    doubled
+                }
+            } else {
+                *d
+            }
+        })
+        .sum();
+    // the check digit makes the sum a multiple of 10
+    let remainder = sum % 10;
+    remainder == 0
+}
+
+#[derive(Debug, Clone)]
+pub struct DataLeakRule {
+    pub id: String,
+    pub name: String,
+    pub kind: DataLeakKind,
+    pub re: Regex,
+    pub risk: u8,
+    pub mask: bool,
+    pub action: SimpleAction,
+    pub tags: HashSet<String>,
+}
+
+pub struct DataLeakRules {
+    /// None when there are no rules
+    pub db: Option<BlockDatabase>,
+    pub rules: Vec<DataLeakRule>,
+}
+
+impl std::fmt::Debug for DataLeakRules {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DataLeakRules").field("rules", &self.rules).finish()
+    }
+}
+
+fn convert_rule(
+    logs: &mut Logs,
+    actions: &HashMap<String, SimpleAction>,
+    raw: RawDataLeakRule,
+) -> anyhow::Result<(DataLeakRule, Pattern)> {
+    let kind = DataLeakKind::parse(&raw.kind)?;
+    let operand = match (kind.pattern(), raw.operand) {
+        (Some(builtin), _) => builtin.to_string(),
+        (None, Some(operand)) => operand,
+        (None, None) => return Err(anyhow::anyhow!("regex rule without an operand")),
+    };
+    let pattern = Pattern::with_flags(&operand, CompileFlags::SINGLEMATCH)?;
+    // make sure the pattern compiles on its own, so that a bad rule does not prevent the others from loading
+    Patterns::from_iter(std::iter::once(pattern.clone())).build::<Block>()?;
+    let rule_id = &raw.id;
+    let action = match &raw.action {
+        None => SimpleAction::default(),
+        Some(aid) => actions.get(aid).cloned().unwrap_or_else(|| {
+            logs.error(|| {
+                format!(
+                    "Could not resolve action {} when resolving data leak rule {}",
+                    aid, rule_id
+                )
+            });
+            SimpleAction::default()
+        }),
+    };
+    let rule = DataLeakRule {
+        id: raw.id,
+        name: raw.name,
+        kind,
+        re: Regex::new(&operand)?,
+        risk: raw.risk,
+        mask: raw.mask,
+        action,
+        tags: raw.tags.into_iter().collect(),
+    };
+    Ok((rule, pattern))
+}
+
+impl DataLeakRules {
+    pub fn empty() -> Self {
+        DataLeakRules {
+            db: None,
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn resolve(
+        logs: &mut Logs,
+        actions: &HashMap<String, SimpleAction>,
+        raw: Vec<RawDataLeakRule>,
+    ) -> DataLeakRules {
+        let mut rules = Vec::new();
+        let mut patterns = Vec::new();
+        for rawrule in raw {
+            let id = rawrule.id.clone();
+            match convert_rule(logs, actions, rawrule) {
+                Ok((rule, pattern)) => {
+                    rules.push(rule);
+                    patterns.push(pattern);
+                }
+                Err(rr) => logs.error(|| format!("data leak rule {}: {}", id, rr)),
+            }
+        }
+        if rules.is_empty() {
+            return DataLeakRules::empty();
+        }
+        match Patterns::from_iter(patterns).build::<Block>() {
+            Ok(db) => DataLeakRules { db: Some(db), rules },
+            Err(rr) => {
+                logs.error(|| format!("when building the data leak rules database: {}", rr));
+                DataLeakRules::empty()
+            }
+        }
+    }
+}
+
+/// data leak detection settings of a security policy entry
+#[derive(Debug, Clone)]
+pub struct DataLeakSettings {
+    pub active: bool,
+    pub rules: Arc<DataLeakRules>,
+    /// indices of the enabled rules, all of them when None
+    pub enabled: Option<HashSet<usize>>,
+    pub mask_char: char,
+    pub keep_last: usize,
+}
+
+impl DataLeakSettings {
+    pub fn resolve(logs: &mut Logs, rules: &Arc<DataLeakRules>, raw: RawDataLeakSettings) -> Self {
+        let enabled = if raw.rules.is_empty() {
+            None
+        } else {
+            for id in &raw.rules {
+                if !rules.rules.iter().any(|r| &r.id == id) {
+                    logs.warning(|| format!("Unknown data leak rule {}", id));
+                }
+            }
+            Some(
+                rules
+                    .rules
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, r)| raw.rules.contains(&r.id))
+                    .map(|(idx, _)| idx)
+                    .collect(),
+            )
+        };
+        DataLeakSettings {
+            active: raw.active,
+            rules: rules.clone(),
+            enabled,
+            mask_char: raw.mask_char.unwrap_or('*'),
+            keep_last: raw.keep_last,
+        }
+    }
+
+    pub fn is_enabled(&self, idx: usize) -> bool {
+        self.enabled.as_ref().map(|e| e.contains(&idx)).unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validation() {
+        assert!(DataLeakKind::CreditCard.validate("4111 1111 1111 1111"));
+        assert!(DataLeakKind::CreditCard.validate("4111-1111-1111-1111"));
+        assert!(!DataLeakKind::CreditCard.validate("4111 1111 1111 1112"));
+        assert!(!DataLeakKind::CreditCard.validate("0000"));
+        assert!(DataLeakKind::Ssn.validate("123-45-6789"));
+        assert!(!DataLeakKind::Ssn.validate("666-45-6789"));
+        assert!(!DataLeakKind::Ssn.validate("123-00-6789"));
+        assert!(!DataLeakKind::Ssn.validate("923-45-6789"));
+    }
+}
@@ -0,0 +1,63 @@
+//! Expiry of emergency rules.
+//!
+//! Global filter sections and ACL entries can carry an `expires` date, either RFC 3339 or a unix timestamp. Expired
+//! rules are ignored at match time, so that emergency blocks revert without a configuration push. Decisions made by
+//! rules expiring within RULE_EXPIRY_WARNING seconds (1 hour by default) are tagged with `expiring-rule`.
+use anyhow::Context;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use lazy_static::lazy_static;
+
+/// tag added to requests matched by a rule that is about to expire
+pub const EXPIRING_TAG: &str = "expiring-rule";
+
+lazy_static! {
+    static ref WARNING: Duration = Duration::seconds(
+        std::env::var("RULE_EXPIRY_WARNING")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(3600)
+    );
+}
+
+/// parses an expiry date, either RFC 3339 or a unix timestamp
+pub fn parse_expiry(s: &str) -> anyhow::Result<DateTime<Utc>> {
+    if let Ok(ts) = s.parse::<i64>() {
+        return Utc
+            .timestamp_opt(ts, 0)
+            .single()
+            .with_context(|| format!("invalid expiry timestamp {}", s));
+    }
+    DateTime::parse_from_rfc3339(s)
+        .map(|d| d.with_timezone(&Utc))
+        .with_context(|| format!("invalid expiry date {}", s))
+}
+
+pub fn is_expired(expires: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+    expires.map(|e| now >= e).unwrap_or(false)
+}
+
+/// the rule is still active, but expires within the warning window
+pub fn expires_soon(expires: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+    expires.map(|e| now < e && e - now <= *WARNING).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry_dates() {
+        let rfc = parse_expiry("2024-11-29T12:00:00+01:00").unwrap();
+        let ts = parse_expiry("1732878000").unwrap();
+        assert_eq!(rfc, ts);
+        assert!(parse_expiry("tomorrow").is_err());
+
+        let before = rfc - Duration::minutes(10);
+        assert!(!is_expired(Some(rfc), before));
+        assert!(expires_soon(Some(rfc), before));
+        assert!(!expires_soon(Some(rfc), rfc - Duration::days(1)));
+        assert!(is_expired(Some(rfc), rfc));
+        assert!(!expires_soon(Some(rfc), rfc));
+        assert!(!is_expired(None, rfc));
+    }
+}
@@ -8,6 +8,21 @@ use crate::logs::Logs;
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct SequenceKey(pub String);
 
+impl SequenceKey {
+    /// the key of the steps that are not bound to a method, host and uri
+    pub fn any() -> Self {
+        SequenceKey(String::new())
+    }
+}
+
+/// the request attributes of a step that is not indexed by its sequence key, missing parts match anything
+#[derive(Debug, Clone, Default)]
+pub struct StepRoute {
+    pub method: Option<String>,
+    pub host: Option<String>,
+    pub path: Option<String>,
+}
+
 #[derive(Debug, Clone)]
 struct FlowEntry {
     id: String,
@@ -18,11 +33,15 @@ struct FlowEntry {
     timeframe: u64,
     tags: Vec<String>,
     sequence: Vec<FlowStep>,
+    step_ttl: Option<u64>,
+    max_duration: Option<u64>,
 }
 
 #[derive(Debug, Clone)]
 struct FlowStep {
     sequence_key: SequenceKey,
+    route: StepRoute,
+    tags: Vec<String>,
     select: Vec<RequestSelectorCondition>,
 }
 
@@ -48,8 +67,16 @@ pub struct FlowElement {
     pub tags: Vec<String>,
     /// the step selector
     pub select: Vec<RequestSelectorCondition>,
+    /// the step route, for steps stored under the SequenceKey::any key
+    pub route: StepRoute,
+    /// tags the request must have to match the step
+    pub step_tags: Vec<String>,
     /// marker for the last step
     pub is_last: bool,
+    /// maximum time between two consecutive steps, in seconds
+    pub step_ttl: Option<u64>,
+    /// maximum time between the first and the last step, in seconds
+    pub max_duration: Option<u64>,
 }
 
 impl FlowEntry {
@@ -72,6 +99,8 @@ impl FlowEntry {
             tags: rawentry.tags,
             key: mkey?,
             sequence,
+            step_ttl: rawentry.step_ttl.filter(|t| *t > 0),
+            max_duration: rawentry.max_duration.filter(|t| *t > 0),
         })
     }
 }
@@ -83,10 +112,12 @@ impl FlowStep {
             .into_iter()
             .map(|(hname, hvalue)| (hname.to_ascii_lowercase(), hvalue))
             .collect();
-        let sequence_key = SequenceKey(
-            rawstep.method + headers.get("host").map(|s| s.as_str()).unwrap_or("Missing host field") + &rawstep.uri,
-        );
-        headers.remove("host");
+        let host = headers.remove("host").filter(|h| !h.is_empty());
+        let nonempty = |s: String| if s.is_empty() { None } else { Some(s) };
+        let (sequence_key, route) = match (nonempty(rawstep.method), host, nonempty(rawstep.uri)) {
+            (Some(method), Some(host), Some(uri)) => (SequenceKey(method + &host + &uri), StepRoute::default()),
+            (method, host, path) => (SequenceKey::any(), StepRoute { method, host, path }),
+        };
         let fake_selector = RawLimitSelector {
             args: rawstep.args,
             cookies: rawstep.cookies,
@@ -96,6 +127,8 @@ impl FlowStep {
 
         Ok(FlowStep {
             sequence_key,
+            route,
+            tags: rawstep.tags,
             select: resolve_selectors(fake_selector)?,
         })
     }
@@ -126,8 +159,12 @@ pub fn flow_resolve(logs: &mut Logs, rawentries: Vec<RawFlowEntry>) -> FlowMap {
                         name: entry.name.clone(),
                         timeframe: entry.timeframe,
                         select: step.select,
+                        route: step.route,
+                        step_tags: step.tags,
                         step: stepid as u32,
                         is_last: stepid + 1 == nsteps,
+                        step_ttl: entry.step_ttl,
+                        max_duration: entry.max_duration,
                     })
                 }
             }
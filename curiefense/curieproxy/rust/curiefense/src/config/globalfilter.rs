@@ -1,4 +1,5 @@
 use anyhow::Context;
+use chrono::{DateTime, Utc};
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use iprange::IpRange;
 use regex::{Regex, RegexBuilder};
@@ -6,9 +7,36 @@ use serde_json::{from_value, Value};
 use std::collections::HashMap;
 use std::net::IpAddr;
 
-use crate::config::raw::{GlobalFilterEntryType, RawGlobalFilterRule, RawGlobalFilterSection, Relation};
+use crate::cadence::CADENCE_TAG;
+use crate::checkout::CHECKOUT_TAG_PREFIX;
+use crate::config::expiry::{is_expired, parse_expiry};
+use crate::config::raw::{
+    GlobalFilterEntryType, RawGlobalFilterEntry, RawGlobalFilterRelation, RawGlobalFilterRule, RawGlobalFilterSection,
+    RawHttpFingerprint, RawIpList, RawTlsFingerprint, Relation,
+};
+use crate::config::timewindow::TimeWindow;
+use crate::honeypot::HONEYPOT_TAG;
 use crate::interface::{RawTags, SimpleAction};
+use crate::login::LOGIN_TAG_PREFIX;
 use crate::logs::Logs;
+use crate::offender::OFFENDER_TAG;
+use crate::scraping::SCRAPER_TAG;
+use crate::session::SESSION_TAG_PREFIX;
+
+/// prefixes of the tags set from the client state kept in redis, by the session tracking, honeypot, offender, cadence,
+/// login, checkout and scraping stages
+///
+/// these stages run after the global filters are evaluated, so the filters matching on these tags are deferred to the
+/// session stage, the last of them
+pub const SESSION_STAGE_TAGS: [&str; 7] = [
+    SESSION_TAG_PREFIX,
+    HONEYPOT_TAG,
+    OFFENDER_TAG,
+    CADENCE_TAG,
+    LOGIN_TAG_PREFIX,
+    CHECKOUT_TAG_PREFIX,
+    SCRAPER_TAG,
+];
 
 #[derive(Debug, Clone)]
 pub struct GlobalFilterSection {
@@ -17,6 +45,10 @@ pub struct GlobalFilterSection {
     pub tags: RawTags,
     pub rule: GlobalFilterRule,
     pub action: Option<SimpleAction>,
+    /// the rule refers to one of the SESSION_STAGE_TAGS, and is evaluated by the session stage
+    pub session: bool,
+    pub window: Option<TimeWindow>,
+    pub expires: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone)]
@@ -25,6 +57,20 @@ pub enum GlobalFilterRule {
     Entry(GlobalFilterEntry),
 }
 
+impl GlobalFilterRule {
+    /// true if the rule matches on one of the SESSION_STAGE_TAGS
+    pub fn uses_session_tags(&self) -> bool {
+        match self {
+            GlobalFilterRule::Rel(rel) => rel.entries.iter().any(|e| e.uses_session_tags()),
+            GlobalFilterRule::Entry(GlobalFilterEntry {
+                entry: GlobalFilterEntryE::Tag(tag),
+                ..
+            }) => SESSION_STAGE_TAGS.iter().any(|p| tag.exact.starts_with(p)),
+            GlobalFilterRule::Entry(_) => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct GlobalFilterRelation {
     pub relation: Relation,
@@ -50,6 +96,13 @@ pub struct PairEntry {
     pub re: Option<Regex>,
 }
 
+/// matches the values of the headers whose name match
+#[derive(Debug, Clone)]
+pub struct RegexPairEntry {
+    pub key: Regex,
+    pub value: Regex,
+}
+
 #[derive(Debug, Clone)]
 pub enum GlobalFilterEntryE {
     // internal usage for the optimizer
@@ -60,6 +113,11 @@ pub enum GlobalFilterEntryE {
     Cookies(PairEntry),
     Header(PairEntry),
     Plugins(PairEntry),
+    HeaderRegex(RegexPairEntry),
+
+    // presence of an argument or a cookie
+    ArgExists(String),
+    CookieExists(String),
 
     // ip/iprange
     Ip(IpAddr),
@@ -78,9 +136,13 @@ pub enum GlobalFilterEntryE {
     Asn(u32),
     Company(SingleEntry),
     Authority(SingleEntry),
+    TlsFingerprint(SingleEntry),
     Tag(SingleEntry),
     SecurityPolicyId(String),
     SecurityPolicyEntryId(String),
+    ApiKey(SingleEntry),
+    ClientCertSubject(SingleEntry),
+    ClientCertSan(SingleEntry),
 }
 
 /// tries to aggregate ip ranges
@@ -203,6 +265,105 @@ pub fn optimize_ipranges(rel: Relation, unoptimized: Vec<GlobalFilterRule>) -> V
     other
 }
 
+/// converts the TLS fingerprint reputation list into global filter sections, matching the exact fingerprints
+pub fn tls_fingerprint_sections(raw: Vec<RawTlsFingerprint>) -> Vec<RawGlobalFilterSection> {
+    raw.into_iter()
+        .map(|fp| RawGlobalFilterSection {
+            id: format!("tls-fingerprint-{}", fp.fingerprint),
+            rule: RawGlobalFilterRule::Entry(RawGlobalFilterEntry {
+                tp: GlobalFilterEntryType::TlsFingerprint,
+                vl: Value::String(format!("^{}$", regex::escape(&fp.fingerprint.to_ascii_lowercase()))),
+                comment: None,
+            }),
+            name: fp.name,
+            active: true,
+            tags: fp.tags,
+            action: fp.action,
+            window: None,
+            expires: None,
+        })
+        .collect()
+}
+
+/// replaces the references to IP lists with the entries of these lists
+///
+/// a reference matches when one of the entries matches, a negated reference when none of them do
+pub fn expand_ip_lists(raw: Vec<RawGlobalFilterSection>, lists: &[RawIpList]) -> Vec<RawGlobalFilterSection> {
+    fn expand(rule: RawGlobalFilterRule, lists: &HashMap<&str, &RawIpList>) -> RawGlobalFilterRule {
+        match rule {
+            RawGlobalFilterRule::Rel(rel) => RawGlobalFilterRule::Rel(RawGlobalFilterRelation {
+                relation: rel.relation,
+                entries: rel.entries.into_iter().map(|e| expand(e, lists)).collect(),
+            }),
+            RawGlobalFilterRule::Entry(RawGlobalFilterEntry {
+                tp: GlobalFilterEntryType::IpList,
+                vl: Value::String(id),
+                comment,
+            }) => {
+                let (negated, lid) = match id.strip_prefix('!') {
+                    Some(lid) => (true, lid),
+                    None => (false, id.as_str()),
+                };
+                match lists.get(lid) {
+                    // left as is, and reported when the entry is converted
+                    None => RawGlobalFilterRule::Entry(RawGlobalFilterEntry {
+                        tp: GlobalFilterEntryType::IpList,
+                        vl: Value::String(id),
+                        comment,
+                    }),
+                    Some(list) => RawGlobalFilterRule::Rel(RawGlobalFilterRelation {
+                        relation: if negated { Relation::And } else { Relation::Or },
+                        entries: list
+                            .entries
+                            .iter()
+                            .map(|ip| {
+                                RawGlobalFilterRule::Entry(RawGlobalFilterEntry {
+                                    tp: GlobalFilterEntryType::Ip,
+                                    vl: Value::String(if negated { format!("!{}", ip) } else { ip.clone() }),
+                                    comment: None,
+                                })
+                            })
+                            .collect(),
+                    }),
+                }
+            }
+            entry => entry,
+        }
+    }
+
+    let lists: HashMap<&str, &RawIpList> = lists.iter().map(|l| (l.id.as_str(), l)).collect();
+    raw.into_iter()
+        .map(|s| RawGlobalFilterSection {
+            rule: expand(s.rule, &lists),
+            ..s
+        })
+        .collect()
+}
+
+/// converts the known HTTP client fingerprints into global filter sections, tagging the client name
+pub fn http_fingerprint_sections(raw: Vec<RawHttpFingerprint>) -> Vec<RawGlobalFilterSection> {
+    raw.into_iter()
+        .map(|fp| {
+            let mut tags = fp.tags;
+            tags.push(format!("http-client:{}", fp.name));
+            RawGlobalFilterSection {
+                id: format!("http-fingerprint-{}", fp.fingerprint),
+                rule: RawGlobalFilterRule::Entry(RawGlobalFilterEntry {
+                    tp: GlobalFilterEntryType::Tag,
+                    vl: Value::String(format!("http-fp:{}", fp.fingerprint.to_ascii_lowercase())),
+                    comment: None,
+                }),
+                name: fp.name,
+                active: true,
+                tags,
+                action: None,
+                window: None,
+                expires: None,
+            }
+        })
+        .collect()
+}
+
 impl GlobalFilterSection {
     // what an ugly function :(
     pub fn resolve(
@@ -292,6 +453,28 @@ impl GlobalFilterSection {
             })
         }
 
+        /// build a global filter entry matching header names and values with regexes
+        fn header_regex(val: Value) -> anyhow::Result<GlobalFilterEntry> {
+            let (k, v): (String, String) = from_value(val)?;
+            let (negated, v) = match v.strip_prefix('!') {
+                Some(nval) => (true, nval),
+                None => (false, v.as_str()),
+            };
+            let build = |s: &str| {
+                RegexBuilder::new(s)
+                    .case_insensitive(true)
+                    .build()
+                    .with_context(|| format!("Bad regex {}", s))
+            };
+            Ok(GlobalFilterEntry {
+                negated,
+                entry: GlobalFilterEntryE::HeaderRegex(RegexPairEntry {
+                    key: build(&k)?,
+                    value: build(v)?,
+                }),
+            })
+        }
+
         // convert a json value
         fn convert_entry(logs: &mut Logs, tp: GlobalFilterEntryType, val: Value) -> anyhow::Result<GlobalFilterEntry> {
             match tp {
@@ -319,6 +502,10 @@ impl GlobalFilterSection {
                 GlobalFilterEntryType::Asn => single(|rawasn| Ok(GlobalFilterEntryE::Asn(rawasn.parse()?)), val),
                 GlobalFilterEntryType::Company => single_re(logs, GlobalFilterEntryE::Company, val),
                 GlobalFilterEntryType::Authority => single_re(logs, GlobalFilterEntryE::Authority, val),
+                GlobalFilterEntryType::ApiKey => single_re(logs, GlobalFilterEntryE::ApiKey, val),
+                GlobalFilterEntryType::ClientCertSubject => single_re(logs, GlobalFilterEntryE::ClientCertSubject, val),
+                GlobalFilterEntryType::ClientCertSan => single_re(logs, GlobalFilterEntryE::ClientCertSan, val),
+                GlobalFilterEntryType::TlsFingerprint => single_re(logs, GlobalFilterEntryE::TlsFingerprint, val),
                 GlobalFilterEntryType::Tag => single(
                     |s| {
                         Ok(GlobalFilterEntryE::Tag(SingleEntry {
@@ -334,6 +521,13 @@ impl GlobalFilterSection {
                 GlobalFilterEntryType::SecurityPolicyEntryId => {
                     single(|id| Ok(GlobalFilterEntryE::SecurityPolicyEntryId(id.to_string())), val)
                 }
+                GlobalFilterEntryType::HeaderRegex => header_regex(val),
+                GlobalFilterEntryType::ArgExists => single(|n| Ok(GlobalFilterEntryE::ArgExists(n.to_string())), val),
+                GlobalFilterEntryType::CookieExists => {
+                    single(|n| Ok(GlobalFilterEntryE::CookieExists(n.to_string())), val)
+                }
+                // known lists have been expanded by expand_ip_lists
+                GlobalFilterEntryType::IpList => single(|id| Err(anyhow::anyhow!("unknown IP list {}", id)), val),
             }
         }
 
@@ -363,12 +557,30 @@ impl GlobalFilterSection {
             let sid = &s.id;
             let rule = convert_rule(logs, s.rule).with_context(|| format!("in section {}, sid={}", sname, sid))?;
             let action = s.action.as_ref().and_then(|r| actions.get(r)).cloned();
+            let session = rule.uses_session_tags();
+            let window = s
+                .window
+                .map(TimeWindow::resolve)
+                .transpose()
+                .with_context(|| format!("in the time window of section {}, sid={}", sname, sid))?;
+            let expires = s
+                .expires
+                .as_deref()
+                .map(parse_expiry)
+                .transpose()
+                .with_context(|| format!("in section {}, sid={}", sname, sid))?;
+            if is_expired(expires, Utc::now()) {
+                logs.info(|| format!("section {}, sid={} has expired", sname, sid));
+            }
             Ok(GlobalFilterSection {
+                window,
+                expires,
                 id: s.id,
                 tags: s.tags.iter().cloned().collect(),
                 rule,
                 action,
                 name: s.name,
+                session,
             })
         }
 
@@ -1,9 +1,36 @@
+use serde_json::Value;
 use std::sync::Arc;
 
+use crate::abtest::Candidate;
+use crate::accesslog::AccessLogSettings;
+use crate::apikey::ApiKeySettings;
+use crate::botscore::BotScoreSettings;
+use crate::bypass::BypassSettings;
+use crate::cadence::CadenceSettings;
+use crate::canary::CanaryEntry;
+use crate::captcha::CaptchaSettings;
+use crate::checkout::CheckoutProtectionSettings;
 use crate::config::contentfilter::ContentFilterProfile;
+use crate::config::dataleak::DataLeakSettings;
 use crate::config::limit::Limit;
 use crate::config::matchers::Matching;
-use crate::config::raw::AclProfile;
+use crate::config::pathtrie::{PathTemplate, PathTrie};
+use crate::config::raw::{AclProfile, RawCounterSettings};
+use crate::config::responsefilter::ResponseFilterProfile;
+use crate::csrf::CsrfSettings;
+use crate::decisioncache::DecisionCache;
+use crate::failure::FailurePolicy;
+use crate::honeypot::HoneypotSettings;
+use crate::jwt::JwtSettings;
+use crate::login::LoginProtectionSettings;
+use crate::logs::Logs;
+use crate::offender::OffenderSettings;
+use crate::protocol::ProtocolSettings;
+use crate::replay::ReplaySettings;
+use crate::scraping::ScrapingSettings;
+use crate::session::SessionSettings;
+use crate::signature::SignatureSettings;
+use crate::websocket::WebSocketSettings;
 
 use super::matchers::RequestSelector;
 
@@ -12,9 +39,30 @@ use super::matchers::RequestSelector;
 pub struct HostMap {
     pub name: String,
     pub entries: Vec<Matching<Arc<SecurityPolicy>>>,
+    /// entries matched with a path template, several entries sharing a template when they are scoped to different
+    /// methods
+    pub templates: PathTrie<Vec<TemplateEntry>>,
     pub default: Option<Arc<SecurityPolicy>>,
 }
 
+/// an entry matched with a path template
+#[derive(Debug, Clone)]
+pub struct TemplateEntry {
+    /// number of regular expression entries that come before this one, and are tried first
+    pub rank: usize,
+    pub policy: Arc<SecurityPolicy>,
+}
+
+impl HostMap {
+    /// all the entries, except the default one
+    pub fn all_entries(&self) -> impl Iterator<Item = &Arc<SecurityPolicy>> {
+        self.templates
+            .values()
+            .flat_map(|v| v.iter().map(|t| &t.policy))
+            .chain(self.entries.iter().map(|m| &m.inner))
+    }
+}
+
 #[derive(Debug)]
 pub struct PolicyId {
     pub id: String,
@@ -34,6 +82,93 @@ pub struct SecurityPolicy {
     pub limits: Vec<Limit>,
     pub session: Vec<RequestSelector>,
     pub session_ids: Vec<RequestSelector>,
+    pub response_filter_active: bool,
+    pub response_filter_profile: Option<ResponseFilterProfile>,
+    pub counters: CounterSettings,
+    pub jwt: Option<JwtSettings>,
+    pub session_tracking: Option<SessionSettings>,
+    pub decision_cache: Option<Arc<DecisionCache>>,
+    pub data_leak: Option<DataLeakSettings>,
+    pub csrf: Option<CsrfSettings>,
+    pub protocol: Option<ProtocolSettings>,
+    pub websocket: Option<WebSocketSettings>,
+    pub bot_score: Option<BotScoreSettings>,
+    pub captcha: Option<CaptchaSettings>,
+    pub failure_policy: FailurePolicy,
+    /// canary version of this entry, evaluated in shadow
+    pub canary: Option<Arc<CanaryEntry>>,
+    /// candidate profiles, compared with the active ones
+    pub candidate: Option<Candidate>,
+    pub access_log: Option<AccessLogSettings>,
+    /// path template of the entry, its captures are added to the request arguments
+    pub path_template: Option<PathTemplate>,
+    /// uppercase and sorted HTTP methods the entry is restricted to, all methods when None
+    pub methods: Option<Vec<String>>,
+    pub api_key: Option<ApiKeySettings>,
+    pub honeypot: Option<HoneypotSettings>,
+    pub offender_tracking: Option<OffenderSettings>,
+    pub bypass: Option<BypassSettings>,
+    pub cadence: Option<CadenceSettings>,
+    pub login_protection: Option<LoginProtectionSettings>,
+    pub checkout_protection: Option<CheckoutProtectionSettings>,
+    pub scraping: Option<ScrapingSettings>,
+    pub replay: Option<ReplaySettings>,
+    pub signature: Option<SignatureSettings>,
+}
+
+/// flow and limit counter settings of a security policy
+#[derive(Debug, Clone, PartialEq)]
+pub struct CounterSettings {
+    /// prefix for the counter keys, empty or ending with an underscore
+    pub namespace: String,
+    pub limit_ttl_multiplier: f64,
+    pub flow_ttl_multiplier: f64,
+}
+
+impl Default for CounterSettings {
+    fn default() -> Self {
+        CounterSettings {
+            namespace: String::new(),
+            limit_ttl_multiplier: 1.0,
+            flow_ttl_multiplier: 1.0,
+        }
+    }
+}
+
+fn valid_multiplier(logs: &mut Logs, name: &str, m: Option<f64>) -> f64 {
+    match m {
+        None => 1.0,
+        Some(v) if v.is_finite() && v > 0.0 => v,
+        Some(v) => {
+            logs.error(|| format!("invalid {} {}, should be a positive number", name, v));
+            1.0
+        }
+    }
+}
+
+impl CounterSettings {
+    pub fn resolve(logs: &mut Logs, raw: RawCounterSettings) -> Self {
+        let namespace = match raw.namespace {
+            None => String::new(),
+            Some(ns) if ns.is_empty() => String::new(),
+            // keys must stay valid for all counter backends
+            Some(ns) if ns.chars().all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c)) => ns + "_",
+            Some(ns) => {
+                logs.error(|| format!("invalid counter namespace {:?}", ns));
+                String::new()
+            }
+        };
+        CounterSettings {
+            namespace,
+            limit_ttl_multiplier: valid_multiplier(logs, "limit_ttl_multiplier", raw.limit_ttl_multiplier),
+            flow_ttl_multiplier: valid_multiplier(logs, "flow_ttl_multiplier", raw.flow_ttl_multiplier),
+        }
+    }
+
+    /// scales a timeframe, making sure it is at least one second long
+    pub fn scaled_ttl(timeframe: u64, multiplier: f64) -> u64 {
+        ((timeframe as f64) * multiplier).round().max(1.0) as u64
+    }
 }
 
 impl Default for SecurityPolicy {
@@ -55,11 +190,46 @@ impl Default for SecurityPolicy {
             limits: Vec::new(),
             session: Vec::new(),
             session_ids: Vec::new(),
+            response_filter_active: false,
+            response_filter_profile: None,
+            jwt: None,
+            session_tracking: None,
+            decision_cache: None,
+            data_leak: None,
+            csrf: None,
+            protocol: None,
+            websocket: None,
+            bot_score: None,
+            captcha: None,
+            failure_policy: FailurePolicy::default(),
+            canary: None,
+            candidate: None,
+            access_log: None,
+            path_template: None,
+            methods: None,
+            api_key: None,
+            honeypot: None,
+            offender_tracking: None,
+            bypass: None,
+            cadence: None,
+            login_protection: None,
+            checkout_protection: None,
+            scraping: None,
+            replay: None,
+            signature: None,
+            counters: CounterSettings::default(),
         }
     }
 }
 
 impl SecurityPolicy {
+    pub fn accepts_method(&self, method: Option<&str>) -> bool {
+        match (&self.methods, method) {
+            (None, _) | (_, None) => true,
+            (Some(methods), Some(m)) => methods.iter().any(|sm| sm.eq_ignore_ascii_case(m)),
+        }
+    }
+
     pub fn empty() -> Self {
         let mut out = Self {
             policy: PolicyId {
@@ -78,9 +248,130 @@ impl SecurityPolicy {
             limits: Vec::new(),
             session: Vec::new(),
             session_ids: Vec::new(),
+            response_filter_active: false,
+            response_filter_profile: None,
+            jwt: None,
+            session_tracking: None,
+            decision_cache: None,
+            data_leak: None,
+            csrf: None,
+            protocol: None,
+            websocket: None,
+            bot_score: None,
+            captcha: None,
+            failure_policy: FailurePolicy::default(),
+            canary: None,
+            candidate: None,
+            access_log: None,
+            path_template: None,
+            methods: None,
+            api_key: None,
+            honeypot: None,
+            offender_tracking: None,
+            bypass: None,
+            cadence: None,
+            login_protection: None,
+            checkout_protection: None,
+            scraping: None,
+            replay: None,
+            signature: None,
+            counters: CounterSettings::default(),
         };
         out.content_filter_profile.content_type = Vec::new();
         out.content_filter_profile.decoding = Vec::new();
         out
     }
 }
+
+/// applies the site level settings of a raw security policy, its `defaults` object, to the entries of its map
+///
+/// Entries only override the sections they define (such as `limit_ids`, `acl_profile` or `content_filter_profile`),
+/// the other ones being inherited, so that the effective entries are computed before being deserialized.
+pub fn inherit_policy_defaults(rawmap: &mut Value) {
+    let defaults = match rawmap.as_object_mut().and_then(|o| o.remove("defaults")) {
+        Some(Value::Object(d)) => d,
+        _ => return,
+    };
+    let entries = match rawmap.get_mut("map").and_then(|m| m.as_array_mut()) {
+        Some(e) => e,
+        None => return,
+    };
+    for entry in entries.iter_mut().filter_map(|e| e.as_object_mut()) {
+        for (k, v) in &defaults {
+            if entry.get(k).map(|ev| ev.is_null()).unwrap_or(true) {
+                entry.insert(k.clone(), v.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::raw::RawHostMap;
+
+    #[test]
+    fn method_scope() {
+        let mut secpol = SecurityPolicy::empty();
+        assert!(secpol.accepts_method(Some("DELETE")));
+        secpol.methods = Some(vec!["POST".to_string(), "PUT".to_string()]);
+        assert!(secpol.accepts_method(Some("post")));
+        assert!(!secpol.accepts_method(Some("GET")));
+        assert!(secpol.accepts_method(None));
+    }
+
+    #[test]
+    fn policy_inheritance() {
+        let mut raw = serde_json::json!({
+            "match": "example.com",
+            "id": "site",
+            "name": "site",
+            "tags": [],
+            "defaults": {
+                "acl_profile": "acl-site",
+                "content_filter_profile": "cf-site",
+                "acl_active": true,
+                "content_filter_active": true,
+                "limit_ids": ["l1"]
+            },
+            "map": [
+                {"match": "/", "name": "default"},
+                {"match": "/api", "name": "api", "limit_ids": ["l2", "l3"], "acl_active": false}
+            ]
+        });
+        inherit_policy_defaults(&mut raw);
+        let hostmap: RawHostMap = serde_json::from_value(raw).unwrap();
+        let root = &hostmap.map[0];
+        assert_eq!(root.acl_profile, "acl-site");
+        assert_eq!(root.limit_ids, vec!["l1".to_string()]);
+        let api = &hostmap.map[1];
+        assert_eq!(api.content_filter_profile, "cf-site");
+        assert!(!api.acl_active);
+        assert_eq!(api.limit_ids, vec!["l2".to_string(), "l3".to_string()]);
+    }
+
+    #[test]
+    fn counter_settings() {
+        let mut logs = Logs::default();
+        let settings = CounterSettings::resolve(
+            &mut logs,
+            RawCounterSettings {
+                namespace: Some("tenant-1".to_string()),
+                limit_ttl_multiplier: Some(2.5),
+                flow_ttl_multiplier: Some(-1.0),
+            },
+        );
+        assert_eq!(settings.namespace, "tenant-1_");
+        assert_eq!(settings.flow_ttl_multiplier, 1.0);
+        assert_eq!(CounterSettings::scaled_ttl(60, settings.limit_ttl_multiplier), 150);
+        assert_eq!(CounterSettings::scaled_ttl(1, 0.1), 1);
+        let bad = CounterSettings::resolve(
+            &mut logs,
+            RawCounterSettings {
+                namespace: Some("has space".to_string()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(bad, CounterSettings::default());
+    }
+}
@@ -0,0 +1,141 @@
+//! Host routing rules of security policies.
+//!
+//! Besides its `match` regular expression, a security policy can list `hosts` rules, that are evaluated first:
+//!
+//!  * exact names, such as `www.example.com`,
+//!  * wildcards, such as `*.example.com`, with the semantics of wildcard certificates: the star matches exactly one
+//!    label, so that `a.example.com` matches, but neither `example.com` nor `a.b.example.com`,
+//!  * regular expressions, prefixed with `~`.
+//!
+//! Exact rules have priority over wildcards, and wildcards over regular expressions. Hosts are compared without
+//! their port, case insensitively. When the host header matches no rule and no `match` expression, the host rules
+//! are tried with the TLS SNI. A security policy marked as `fallback` is used for the hosts that match nothing,
+//! instead of the `__default__` one.
+use regex::{Regex, RegexBuilder};
+use std::collections::HashMap;
+
+/// how the security policy of a request was selected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostMatch {
+    Exact,
+    Wildcard,
+    Regex,
+    /// the `match` regular expression of the security policy
+    Pattern,
+    Fallback,
+    Default,
+    /// explicitly selected by the proxy
+    Selected,
+}
+
+impl HostMatch {
+    /// the tag value
+    pub fn name(&self) -> &'static str {
+        match self {
+            HostMatch::Exact => "exact",
+            HostMatch::Wildcard => "wildcard",
+            HostMatch::Regex => "regex",
+            HostMatch::Pattern => "pattern",
+            HostMatch::Fallback => "fallback",
+            HostMatch::Default => "default",
+            HostMatch::Selected => "selected",
+        }
+    }
+}
+
+/// lowercase host, without its port and trailing dot
+pub fn normalize_host(host: &str) -> String {
+    let without_port = if host.starts_with('[') {
+        // ipv6 literal
+        host.split_once(']').map(|(h, _)| &h[1..]).unwrap_or(host)
+    } else {
+        match host.rsplit_once(':') {
+            Some((h, port)) if !h.contains(':') && port.chars().all(|c| c.is_ascii_digit()) => h,
+            _ => host,
+        }
+    };
+    without_port.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// host rules, resolved to security policy ids
+#[derive(Debug, Clone, Default)]
+pub struct HostRules {
+    exact: HashMap<String, String>,
+    /// by parent domain
+    wildcard: HashMap<String, String>,
+    regex: Vec<(Regex, String)>,
+    /// id of the security policy used for unknown hosts
+    pub fallback: Option<String>,
+}
+
+impl HostRules {
+    pub fn add(&mut self, rule: &str, id: &str) -> anyhow::Result<()> {
+        let previous = if let Some(re) = rule.strip_prefix('~') {
+            let regex = RegexBuilder::new(re).case_insensitive(true).build()?;
+            self.regex.push((regex, id.to_string()));
+            None
+        } else if let Some(parent) = rule.strip_prefix("*.") {
+            if parent.is_empty() || parent.contains('*') {
+                anyhow::bail!("invalid wildcard host rule {}", rule);
+            }
+            self.wildcard.insert(normalize_host(parent), id.to_string())
+        } else {
+            if rule.is_empty() || rule.contains('*') {
+                anyhow::bail!("invalid host rule {}", rule);
+            }
+            self.exact.insert(normalize_host(rule), id.to_string())
+        };
+        match previous {
+            Some(other) if other != id => anyhow::bail!("host rule {} is also used by {}", rule, other),
+            _ => Ok(()),
+        }
+    }
+
+    /// the id of the security policy of the host, along with the kind of the matching rule
+    pub fn route(&self, host: &str) -> Option<(&str, HostMatch)> {
+        let host = normalize_host(host);
+        if let Some(id) = self.exact.get(&host) {
+            return Some((id, HostMatch::Exact));
+        }
+        if let Some(id) = host
+            .split_once('.')
+            .filter(|(label, _)| !label.is_empty())
+            .and_then(|(_, parent)| self.wildcard.get(parent))
+        {
+            return Some((id, HostMatch::Wildcard));
+        }
+        self.regex
+            .iter()
+            .find(|(re, _)| re.is_match(&host))
+            .map(|(_, id)| (id.as_str(), HostMatch::Regex))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routing() {
+        let mut rules = HostRules::default();
+        rules.add("www.example.com", "www").unwrap();
+        rules.add("*.example.com", "sub").unwrap();
+        rules.add("~^api[0-9]+\\.", "api").unwrap();
+        assert!(rules.add("www.example.com", "other").is_err());
+        assert!(rules.add("*.*.example.com", "other").is_err());
+
+        assert_eq!(rules.route("WWW.example.com:8443"), Some(("www", HostMatch::Exact)));
+        assert_eq!(rules.route("shop.example.com."), Some(("sub", HostMatch::Wildcard)));
+        assert_eq!(rules.route("api2.example.org"), Some(("api", HostMatch::Regex)));
+        // a wildcard matches a single label
+        assert_eq!(rules.route("example.com"), None);
+        assert_eq!(rules.route("a.b.example.com"), None);
+    }
+
+    #[test]
+    fn host_normalization() {
+        assert_eq!(normalize_host("[::1]:8080"), "::1");
+        assert_eq!(normalize_host("::1"), "::1");
+        assert_eq!(normalize_host("Example.COM:80"), "example.com");
+    }
+}
@@ -1,4 +1,5 @@
 use anyhow::Context;
+use chrono::{DateTime, Datelike, TimeZone, Utc};
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::collections::HashSet;
@@ -7,6 +8,7 @@ use crate::config::matchers::{
     decode_request_selector_condition, RequestSelector, RequestSelectorCondition, SelectorType,
 };
 use crate::config::raw::{RawLimit, RawLimitSelector};
+use crate::config::timewindow::TimeWindow;
 use crate::interface::SimpleAction;
 use crate::logs::Logs;
 
@@ -21,6 +23,115 @@ pub struct Limit {
     pub pairwith: Option<RequestSelector>,
     pub key: Vec<RequestSelector>,
     pub tags: Vec<String>,
+    pub algorithm: LimitAlgorithm,
+    pub unit: LimitUnit,
+    pub window: Option<TimeWindow>,
+}
+
+/// what a limit counts, thresholds and bursts are expressed in this unit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LimitUnit {
+    #[default]
+    Requests,
+    /// the size of the request bodies, to throttle uploads independently from the request rate
+    BodyBytes,
+}
+
+impl LimitUnit {
+    pub fn parse(unit: Option<&str>) -> anyhow::Result<Self> {
+        match unit.unwrap_or("requests") {
+            "requests" => Ok(LimitUnit::Requests),
+            "body-bytes" => Ok(LimitUnit::BodyBytes),
+            other => Err(anyhow::anyhow!("unknown limit unit {}", other)),
+        }
+    }
+}
+
+/// how requests are counted for a limit
+///
+/// The alternative algorithms are implemented as redis scripts, and only apply to plain counters: limits with
+/// a pairwith selector, and the memcached backend, always use fixed windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LimitAlgorithm {
+    /// a counter that is reset every timeframe
+    #[default]
+    FixedWindow,
+    /// the exact number of requests during the last timeframe
+    SlidingLog,
+    /// the current window counter, plus the previous one weighted by the time it still overlaps the timeframe
+    SlidingCounter,
+    /// a bucket that refills at the rate of the lowest threshold, and accepts bursts of up to `burst` requests
+    TokenBucket { burst: u64 },
+    /// the number of requests currently being processed, released by the proxy when they complete
+    ///
+    /// The timeframe is a lease: counters that are not released expire after the timeframe.
+    InFlight,
+    /// a counter for the current calendar day or month, the timeframe is ignored
+    Quota(QuotaPeriod),
+}
+
+/// calendar period of a quota, in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaPeriod {
+    Daily,
+    Monthly,
+}
+
+impl QuotaPeriod {
+    fn start(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let (day, month) = match self {
+            QuotaPeriod::Daily => (now.day(), now.month()),
+            QuotaPeriod::Monthly => (1, now.month()),
+        };
+        Utc.with_ymd_and_hms(now.year(), month, day, 0, 0, 0).unwrap()
+    }
+
+    /// the end of the period containing the date
+    pub fn end(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let start = self.start(now);
+        match self {
+            QuotaPeriod::Daily => start + chrono::Duration::days(1),
+            QuotaPeriod::Monthly if start.month() == 12 => {
+                Utc.with_ymd_and_hms(start.year() + 1, 1, 1, 0, 0, 0).unwrap()
+            }
+            QuotaPeriod::Monthly => Utc
+                .with_ymd_and_hms(start.year(), start.month() + 1, 1, 0, 0, 0)
+                .unwrap(),
+        }
+    }
+
+    /// name of the period containing the date, part of the counter keys
+    pub fn label(&self, now: DateTime<Utc>) -> String {
+        match self {
+            QuotaPeriod::Daily => now.format("%Y-%m-%d").to_string(),
+            QuotaPeriod::Monthly => now.format("%Y-%m").to_string(),
+        }
+    }
+
+    /// seconds until the end of the period
+    pub fn remaining(&self, now: DateTime<Utc>) -> u64 {
+        (self.end(now) - now).num_seconds().max(1) as u64
+    }
+}
+
+impl LimitAlgorithm {
+    /// body sizes are throttled with a leaky bucket unless another algorithm is selected
+    pub fn parse(algorithm: Option<&str>, burst: u64, unit: LimitUnit) -> anyhow::Result<Self> {
+        let default = match unit {
+            LimitUnit::Requests => "fixed-window",
+            LimitUnit::BodyBytes => "token-bucket",
+        };
+        match algorithm.unwrap_or(default) {
+            "fixed-window" => Ok(LimitAlgorithm::FixedWindow),
+            "sliding-log" => Ok(LimitAlgorithm::SlidingLog),
+            "sliding-counter" => Ok(LimitAlgorithm::SlidingCounter),
+            "token-bucket" => Ok(LimitAlgorithm::TokenBucket { burst }),
+            "in-flight" => Ok(LimitAlgorithm::InFlight),
+            "daily-quota" => Ok(LimitAlgorithm::Quota(QuotaPeriod::Daily)),
+            "monthly-quota" => Ok(LimitAlgorithm::Quota(QuotaPeriod::Monthly)),
+            other => Err(anyhow::anyhow!("unknown limit algorithm {}", other)),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -55,6 +166,28 @@ impl Limit {
             .collect();
         let key = mkey.with_context(|| "when converting the key entry")?;
         let pairwith = RequestSelector::resolve_selector_map(rawlimit.pairwith).ok();
+        let unit = LimitUnit::parse(rawlimit.unit.as_deref())?;
+        let algorithm = LimitAlgorithm::parse(
+            rawlimit.algorithm.as_deref(),
+            rawlimit.burst.map(|b| b.inner).unwrap_or(0),
+            unit,
+        )?;
+        if pairwith.is_some() && matches!(algorithm, LimitAlgorithm::Quota(_)) {
+            anyhow::bail!("quotas can't have a pairwith selector");
+        }
+        if unit == LimitUnit::BodyBytes {
+            if pairwith.is_some() {
+                anyhow::bail!("body-bytes limits can't have a pairwith selector");
+            }
+            if matches!(algorithm, LimitAlgorithm::SlidingLog | LimitAlgorithm::InFlight) {
+                anyhow::bail!("body-bytes limits can't use the {:?} algorithm", algorithm);
+            }
+        }
+        let window = rawlimit
+            .window
+            .map(TimeWindow::resolve)
+            .transpose()
+            .with_context(|| "when converting the time window")?;
         let mut thresholds: Vec<LimitThreshold> = Vec::new();
         let id = rawlimit.id;
 
@@ -95,6 +228,9 @@ impl Limit {
                 pairwith,
                 key,
                 tags: rawlimit.tags,
+                algorithm,
+                unit,
+                window,
             },
             rawlimit.active,
         ))
@@ -153,6 +289,9 @@ mod tests {
                     headers: None,
                     status: v as u32,
                     extra_tags: None,
+                    template: None,
+                    quota: None,
+                    ban: None,
                 },
             }
         }
@@ -166,4 +305,48 @@ mod tests {
         let expected: Vec<u64> = vec![8, 4, 1, 0];
         assert_eq!(status, expected);
     }
+
+    #[test]
+    fn test_limit_algorithms() {
+        let requests = LimitUnit::Requests;
+        assert_eq!(
+            LimitAlgorithm::parse(None, 5, requests).unwrap(),
+            LimitAlgorithm::FixedWindow
+        );
+        assert_eq!(
+            LimitAlgorithm::parse(Some("sliding-log"), 0, requests).unwrap(),
+            LimitAlgorithm::SlidingLog
+        );
+        assert_eq!(
+            LimitAlgorithm::parse(Some("token-bucket"), 5, requests).unwrap(),
+            LimitAlgorithm::TokenBucket { burst: 5 }
+        );
+        assert_eq!(
+            LimitAlgorithm::parse(Some("in-flight"), 0, requests).unwrap(),
+            LimitAlgorithm::InFlight
+        );
+        assert_eq!(
+            LimitAlgorithm::parse(Some("monthly-quota"), 0, requests).unwrap(),
+            LimitAlgorithm::Quota(QuotaPeriod::Monthly)
+        );
+        assert!(LimitAlgorithm::parse(Some("leaky"), 0, requests).is_err());
+        assert_eq!(
+            LimitAlgorithm::parse(None, 1024, LimitUnit::BodyBytes).unwrap(),
+            LimitAlgorithm::TokenBucket { burst: 1024 }
+        );
+        assert_eq!(LimitUnit::parse(None).unwrap(), LimitUnit::Requests);
+        assert_eq!(LimitUnit::parse(Some("body-bytes")).unwrap(), LimitUnit::BodyBytes);
+        assert!(LimitUnit::parse(Some("bits")).is_err());
+    }
+
+    #[test]
+    fn test_quota_periods() {
+        let now = Utc.with_ymd_and_hms(2024, 12, 31, 23, 0, 0).unwrap();
+        assert_eq!(QuotaPeriod::Daily.label(now), "2024-12-31");
+        assert_eq!(QuotaPeriod::Monthly.label(now), "2024-12");
+        assert_eq!(QuotaPeriod::Daily.remaining(now), 3600);
+        assert_eq!(QuotaPeriod::Monthly.remaining(now), 3600);
+        let mid = Utc.with_ymd_and_hms(2024, 2, 10, 0, 0, 0).unwrap();
+        assert_eq!(QuotaPeriod::Monthly.remaining(mid), 20 * 86400);
+    }
 }
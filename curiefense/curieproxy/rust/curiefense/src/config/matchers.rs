@@ -9,6 +9,7 @@ pub enum RequestSelector {
     Query,
     Uri,
     Country,
+    Continent,
     Region,
     SubRegion,
     Method,
@@ -23,6 +24,7 @@ pub enum RequestSelector {
     Session,
     SecpolId,
     SecpolEntryId,
+    ApiKey,
 }
 
 #[derive(Debug, Clone)]
@@ -63,6 +65,7 @@ impl RequestSelector {
             "query" => Some(RequestSelector::Query),
             "uri" => Some(RequestSelector::Uri),
             "country" => Some(RequestSelector::Country),
+            "continent" => Some(RequestSelector::Continent),
             "region" => Some(RequestSelector::Region),
             "subregion" => Some(RequestSelector::SubRegion),
             "method" => Some(RequestSelector::Method),
@@ -73,10 +76,25 @@ impl RequestSelector {
             "session" => Some(RequestSelector::Session),
             "secpolid" | "securitypolicyid" | "securitypolicy" => Some(RequestSelector::SecpolId),
             "secpolentryid" | "securitypolicyentryid" | "securitypolicyentry" => Some(RequestSelector::SecpolEntryId),
+            "apikey" => Some(RequestSelector::ApiKey),
             _ => None,
         }
     }
 
+    /// attributes coming from the geoip databases, that can be unknown for any request
+    pub fn is_geo(&self) -> bool {
+        matches!(
+            self,
+            RequestSelector::Network
+                | RequestSelector::Country
+                | RequestSelector::Continent
+                | RequestSelector::Region
+                | RequestSelector::SubRegion
+                | RequestSelector::Asn
+                | RequestSelector::Company
+        )
+    }
+
     pub fn resolve_selector_raw(k: &str, v: &str) -> anyhow::Result<Self> {
         let st = resolve_selector_type(k)?;
         Self::resolve_selector(st, v)
@@ -110,6 +128,7 @@ impl std::fmt::Display for RequestSelector {
             RequestSelector::Query => write!(f, "query"),
             RequestSelector::Uri => write!(f, "uri"),
             RequestSelector::Country => write!(f, "country"),
+            RequestSelector::Continent => write!(f, "continent"),
             RequestSelector::Method => write!(f, "method"),
             RequestSelector::Asn => write!(f, "asn"),
             RequestSelector::Args(a) => write!(f, "argument_{}", a),
@@ -124,6 +143,7 @@ impl std::fmt::Display for RequestSelector {
             RequestSelector::SubRegion => write!(f, "subregion"),
             RequestSelector::Session => write!(f, "session"),
             RequestSelector::Plugins(n) => write!(f, "plugins_{}", n),
+            RequestSelector::ApiKey => write!(f, "apikey"),
         }
     }
 }
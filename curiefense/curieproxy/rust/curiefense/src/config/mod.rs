@@ -1,37 +1,92 @@
 pub mod contentfilter;
+pub mod dataleak;
+pub mod expiry;
 pub mod flow;
 pub mod globalfilter;
 pub mod hostmap;
+pub mod hostrules;
 pub mod limit;
 pub mod matchers;
+pub mod openapi;
+pub mod pathtrie;
+pub mod prefilter;
 pub mod raw;
+pub mod responsefilter;
+pub mod responsetemplate;
+pub mod tagexpr;
+pub mod timewindow;
+pub mod validate;
 pub mod virtualtags;
+pub mod watcher;
 
+use arc_swap::ArcSwap;
 use lazy_static::lazy_static;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::sync::RwLock;
+use std::sync::Mutex;
+use std::time::Instant;
 
+use crate::abtest::Candidate;
+use crate::accesslog::AccessLogSettings;
+use crate::apikey::ApiKeySettings;
+use crate::botscore::BotScoreSettings;
+use crate::bypass::BypassSettings;
+use crate::cadence::CadenceSettings;
+use crate::canary::Canary;
+use crate::captcha::CaptchaSettings;
+use crate::challenge::ChallengeSettings;
+use crate::checkout::CheckoutProtectionSettings;
+use crate::clearance::ClearanceSettings;
+use crate::clientip::{configure_client_ip, ClientIpSettings};
 use crate::config::limit::Limit;
+use crate::csrf::CsrfSettings;
+use crate::decisioncache::{DecisionCache, DecisionCacheSettings};
+use crate::geoprovider::configure_geo;
+use crate::honeypot::HoneypotSettings;
+use crate::hooks::Hooks;
 use crate::interface::SimpleAction;
+use crate::jwt::JwtSettings;
+use crate::login::LoginProtectionSettings;
 use crate::logs::Logs;
-use contentfilter::{resolve_rules, ContentFilterProfile, ContentFilterRules};
+use crate::offender::OffenderSettings;
+use crate::protocol::ProtocolSettings;
+use crate::replay::ReplaySettings;
+use crate::reputation::{configure_feeds, ReputationFeed};
+use crate::scraping::ScrapingSettings;
+use crate::session::SessionSettings;
+use crate::signature::SignatureSettings;
+use crate::wasm::load_plugins;
+use crate::webhook::{configure_webhooks, WebhookSettings};
+use crate::websocket::WebSocketSettings;
+use contentfilter::{resolve_rules, ContentFilterProfile, ContentFilterRules, PathSchema};
+use dataleak::{DataLeakRules, DataLeakSettings};
 use flow::flow_resolve;
-use globalfilter::GlobalFilterSection;
-use hostmap::{HostMap, PolicyId, SecurityPolicy};
+use globalfilter::{expand_ip_lists, http_fingerprint_sections, tls_fingerprint_sections, GlobalFilterSection};
+use hostmap::{inherit_policy_defaults, CounterSettings, HostMap, PolicyId, SecurityPolicy, TemplateEntry};
+use hostrules::HostRules;
 use matchers::Matching;
-use raw::{AclProfile, RawFlowEntry, RawGlobalFilterSection, RawHostMap, RawLimit, RawSecurityPolicy, RawVirtualTag};
+use pathtrie::{PathTemplate, PathTrie, TEMPLATE_PREFIX};
+use raw::{
+    AclProfile, RawChallengeSettings, RawClientIpSettings, RawFlowEntry, RawGeoSettings, RawGlobalFilterSection,
+    RawHostMap, RawLimit, RawSecurityPolicy, RawVirtualTag,
+};
+use responsefilter::ResponseFilterProfile;
+use responsetemplate::ResponseTemplate;
 use virtualtags::{vtags_resolve, VirtualTags};
 
 use self::flow::FlowMap;
 use self::matchers::RequestSelector;
 use self::raw::RawAclProfile;
+use self::raw::RawIpList;
 use self::raw::RawManifest;
+use self::raw::RawWebhook;
 
-static ALL_CONFIG_FILES: [&str; 10] = [
+static ALL_CONFIG_FILES: [&str; 21] = [
     "actions.json",
     "acl-profiles.json",
     "contentfilter-profiles.json",
@@ -42,27 +97,41 @@ static ALL_CONFIG_FILES: [&str; 10] = [
     "securitypolicy.json",
     "flow-control.json",
     "virtual-tags.json",
+    "responsefilter-profiles.json",
+    "dataleak-rules.json",
+    "tls-fingerprints.json",
+    "http-fingerprints.json",
+    "reputation-feeds.json",
+    "response-templates.json",
+    "challenge.json",
+    "geo.json",
+    "trusted-proxies.json",
+    "ip-lists.json",
+    "webhooks.json",
 ];
 
+/// the current configuration, readers get a consistent snapshot while a new one is being built
 pub struct LockedConfig {
-    pub config: RwLock<Config>,
-    pub hsdb: RwLock<HashMap<String, ContentFilterRules>>,
+    pub config: ArcSwap<Config>,
 }
 
 impl LockedConfig {
     fn initial() -> Self {
-        let mut config = Config::load(Logs::default(), "/cf-config/current/config");
+        let basepath = "/cf-config/current/config";
+        let mut config = Config::load(Logs::default(), basepath);
         let path = Path::new("/cf-config/current/config/json");
-        let hsdb = load_hsdb(&mut config.logs, path, &config.content_filter_profiles);
+        config.hsdb = Arc::new(load_hsdb(&mut config.logs, path, &config.content_filter_profiles));
+        watcher::start_config_watcher(basepath, &config.revision);
         LockedConfig {
-            config: RwLock::new(config),
-            hsdb: RwLock::new(hsdb),
+            config: ArcSwap::from_pointee(config),
         }
     }
 }
 
 lazy_static! {
     pub static ref CONFIGS: LockedConfig = LockedConfig::initial();
+    /// reloads start from the current configuration, they must not run concurrently
+    static ref RELOAD_LOCK: Mutex<()> = Mutex::new(());
     static ref CONFIG_DEPENDENCIES: HashMap<&'static str, Vec<String>> = {
         let mut map = HashMap::new();
 
@@ -75,9 +144,15 @@ lazy_static! {
                 "globalfilter-lists.json".to_string(),
                 "limits.json".to_string(),
                 "securitypolicy.json".to_string(),
+                "responsefilter-profiles.json".to_string(),
+                "dataleak-rules.json".to_string(),
                 "manifest.json".to_string(),
             ],
         );
+        // actions are resolved with the templates
+        let mut template_deps = map["actions.json"].clone();
+        template_deps.push("actions.json".to_string());
+        map.insert("response-templates.json", template_deps);
         map.insert(
             "contentfilter-profiles.json",
             vec![
@@ -94,6 +169,14 @@ lazy_static! {
             "acl-profiles.json",
             vec!["securitypolicy.json".to_string(), "manifest.json".to_string()],
         );
+        map.insert(
+            "responsefilter-profiles.json",
+            vec!["securitypolicy.json".to_string(), "manifest.json".to_string()],
+        );
+        map.insert(
+            "dataleak-rules.json",
+            vec!["securitypolicy.json".to_string(), "manifest.json".to_string()],
+        );
 
         // add generic dependency to the manifest
         for f in ALL_CONFIG_FILES {
@@ -122,21 +205,57 @@ pub fn with_config<R, F>(logs: &mut Logs, f: F) -> Option<R>
 where
     F: FnOnce(&mut Logs, &Config) -> R,
 {
-    match CONFIGS.config.read() {
-        Ok(cfg) => {
-            config_logs(logs, &cfg);
-            Some(f(logs, &cfg))
-        }
-        Err(rr) =>
-        // read failed :(
-        {
-            logs.error(|| rr.to_string());
-            None
+    let cfg = CONFIGS.config.load();
+    config_logs(logs, &cfg);
+    Some(f(logs, &cfg))
+}
+
+/// SHA-256 of the names and contents of the configuration files, identifying the configuration that is loaded
+fn config_hash(configpath: &Path) -> String {
+    let mut hasher = Sha256::new();
+    for fname in ALL_CONFIG_FILES.iter().chain(std::iter::once(&"wasm-plugins.json")) {
+        if let Ok(content) = std::fs::read(configpath.join(fname)) {
+            hasher.update(fname.as_bytes());
+            hasher.update([0]);
+            hasher.update(&content);
+            hasher.update([0]);
         }
     }
+    hex::encode(hasher.finalize())
+}
+
+/// the configuration requests are currently analyzed with
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ActiveRevision {
+    pub revision: String,
+    pub config_hash: String,
+}
+
+pub fn active_revision() -> ActiveRevision {
+    let cfg = CONFIGS.config.load();
+    ActiveRevision {
+        revision: cfg.revision.clone(),
+        config_hash: cfg.config_hash.clone(),
+    }
+}
+
+/// version of the configuration, from the manifest.json file of the parent directory
+fn manifest_version(basepath: &str) -> Result<String, String> {
+    let manifest: RawManifest = PathBuf::from(basepath)
+        .parent()
+        .ok_or_else(|| "could not get parent directory?".to_string())
+        .and_then(|x| {
+            let mut pth = x.to_owned();
+            pth.push("manifest.json");
+            std::fs::File::open(pth).map_err(|rr| rr.to_string())
+        })
+        .and_then(|file| serde_json::from_reader(file).map_err(|rr| rr.to_string()))?;
+    Ok(manifest.meta.version)
 }
 
 pub fn reload_config(basepath: &str, filenames: Vec<String>) {
+    let _reloading = RELOAD_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let start = Instant::now();
     let mut logs = Logs::default();
 
     let mut bjson = PathBuf::from(basepath);
@@ -155,38 +274,24 @@ pub fn reload_config(basepath: &str, filenames: Vec<String>) {
         files_to_reload.extend(filenames);
     }
 
-    let mut config = match CONFIGS.config.read() {
-        Ok(cfg) => cfg.clone(),
-        Err(rr) => {
-            logs.error(|| rr.to_string());
-            return;
-        }
-    };
-    let mut hsdb: Option<_> = None;
+    crate::geo::reload_maxmind(&mut logs);
+
+    let mut config = Config::clone(&CONFIGS.config.load());
 
     if files_to_reload.contains("manifest.json") {
-        let mmanifest: Result<RawManifest, String> = PathBuf::from(basepath)
-            .parent()
-            .ok_or_else(|| "could not get parent directory?".to_string())
-            .and_then(|x| {
-                let mut pth = x.to_owned();
-                pth.push("manifest.json");
-                std::fs::File::open(pth).map_err(|rr| rr.to_string())
-            })
-            .and_then(|file| serde_json::from_reader(file).map_err(|rr| rr.to_string()));
-
-        let revision = match mmanifest {
+        let revision = match manifest_version(basepath) {
             Err(rr) => {
                 logs.error(move || format!("When loading manifest.json: {}", rr));
                 "unknown".to_string()
             }
-            Ok(manifest) => manifest.meta.version,
+            Ok(version) => version,
         };
         config.revision = revision;
     }
     if files_to_reload.contains("actions.json") {
+        let templates = load_response_templates(&mut logs, &bjson);
         let rawactions = Config::load_config_file(&mut logs, &bjson, "actions.json");
-        let actions = SimpleAction::resolve_actions(&mut logs, rawactions);
+        let actions = SimpleAction::resolve_actions(&mut logs, &templates, rawactions);
         config.actions = actions;
     }
     if files_to_reload.contains("acl-profiles.json") {
@@ -203,11 +308,46 @@ pub fn reload_config(basepath: &str, filenames: Vec<String>) {
             ContentFilterProfile::resolve(&mut logs, &config.actions, raw_content_filter_profiles);
         config.content_filter_profiles = content_filter_profiles;
     }
+    if files_to_reload.contains("responsefilter-profiles.json") {
+        let raw_response_filter_profiles = Config::load_config_file(&mut logs, &bjson, "responsefilter-profiles.json");
+        config.response_filter_profiles =
+            ResponseFilterProfile::resolve(&mut logs, &config.actions, raw_response_filter_profiles);
+    }
+    if files_to_reload.contains("dataleak-rules.json") {
+        config.data_leak_rules = load_data_leak_rules(&mut logs, &bjson, &config.actions);
+    }
+    if files_to_reload.contains("reputation-feeds.json") {
+        let feeds = load_reputation_feeds(&mut logs, &bjson);
+        configure_feeds(&mut logs, feeds);
+    }
+    if files_to_reload.contains("geo.json") {
+        let geo = load_geo(&mut logs, &bjson);
+        configure_geo(&mut logs, geo);
+    }
+    if files_to_reload.contains("trusted-proxies.json") {
+        configure_client_ip(load_client_ip(&mut logs, &bjson));
+    }
+    if files_to_reload.contains("webhooks.json") {
+        configure_webhooks(load_webhooks(&mut logs, &bjson));
+    }
+    if files_to_reload.contains("challenge.json") {
+        let (challenge, clearance) = load_challenge(&mut logs, &bjson);
+        config.challenge = challenge;
+        config.clearance = clearance;
+    }
     if files_to_reload.contains("contentfilter-rules.json") {
-        hsdb = Some(load_hsdb(&mut logs, &bjson, &config.content_filter_profiles));
+        config.hsdb = Arc::new(load_hsdb(&mut logs, &bjson, &config.content_filter_profiles));
     }
-    if files_to_reload.contains("globalfilter-lists.json") {
-        let raw_global_filters = Config::load_config_file(&mut logs, &bjson, "globalfilter-lists.json");
+    if [
+        "globalfilter-lists.json",
+        "tls-fingerprints.json",
+        "http-fingerprints.json",
+        "ip-lists.json",
+    ]
+    .iter()
+    .any(|f| files_to_reload.contains(*f))
+    {
+        let raw_global_filters = load_global_filters(&mut logs, &bjson);
         let globalfilters = GlobalFilterSection::resolve(&mut logs, &config.actions, raw_global_filters);
         config.globalfilters = globalfilters;
     }
@@ -219,8 +359,9 @@ pub fn reload_config(basepath: &str, filenames: Vec<String>) {
         config.inactive_limits = inactive_limits;
     }
     if files_to_reload.contains("securitypolicy.json") {
-        let raw_sec_pol = Config::load_config_file(&mut logs, &bjson, "securitypolicy.json");
-        let (securitypolicies_map, securitypolicies, default) = sec_pol_resolve(
+        let raw_sec_pol =
+            Config::load_config_file_with(&mut logs, &bjson, "securitypolicy.json", inherit_policy_defaults);
+        let (securitypolicies_map, securitypolicies, default, host_rules) = sec_pol_resolve(
             &mut logs,
             raw_sec_pol,
             &config.limits,
@@ -228,10 +369,13 @@ pub fn reload_config(basepath: &str, filenames: Vec<String>) {
             &config.inactive_limits,
             &config.acls,
             &config.content_filter_profiles,
+            &config.response_filter_profiles,
+            &config.data_leak_rules,
         );
         config.securitypolicies_map = securitypolicies_map;
         config.securitypolicies = securitypolicies;
         config.default = default;
+        config.host_rules = host_rules;
     }
     if files_to_reload.contains("flow-control.json") {
         let raw_flows = Config::load_config_file(&mut logs, &bjson, "flow-control.json");
@@ -244,31 +388,46 @@ pub fn reload_config(basepath: &str, filenames: Vec<String>) {
         config.virtual_tags = virtual_tags;
     }
 
+    if files_to_reload.contains("wasm-plugins.json") {
+        let raw_plugins = Config::load_config_file(&mut logs, &bjson, "wasm-plugins.json");
+        config.plugin_hooks = load_plugins(&mut logs, raw_plugins);
+    }
+
+    // hooks registered since the last load are picked up on every reload
+    config.hooks = Hooks::registered().with(&config.plugin_hooks);
+    config.config_hash = config_hash(&bjson);
     config.logs = logs.clone();
 
-    match CONFIGS.config.write() {
-        Ok(mut w) => *w = config,
-        Err(rr) => logs.error(|| rr.to_string()),
-    };
-    if let Some(hsdb) = hsdb {
-        match CONFIGS.hsdb.write() {
-            Ok(mut dbw) => *dbw = hsdb,
-            Err(rr) => logs.error(|| rr.to_string()),
-        };
-    }
+    let revision = config.revision.clone();
+
+    // the rules are part of the snapshot, so that requests using the new configuration also use the new rules
+    crate::jwt::retain_key_sets(&config);
+    CONFIGS.config.store(Arc::new(config));
+    watcher::record_reload(&logs, &revision, start.elapsed());
 }
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub revision: String,
+    /// hash of the contents of the configuration files
+    pub config_hash: String,
     pub securitypolicies_map: HashMap<String, HostMap>, // used when the security policy is set
     pub securitypolicies: Vec<Matching<HostMap>>,
     pub globalfilters: Vec<GlobalFilterSection>,
     pub default: Option<HostMap>,
+    /// host rules of the security policies, evaluated before their match patterns
+    pub host_rules: HostRules,
     pub container_name: Option<String>,
     pub flows: FlowMap,
     pub content_filter_profiles: HashMap<String, ContentFilterProfile>,
+    pub response_filter_profiles: HashMap<String, ResponseFilterProfile>,
+    pub data_leak_rules: Arc<DataLeakRules>,
+    /// compiled content filter rules, by content filter profile id
+    pub hsdb: Arc<HashMap<String, ContentFilterRules>>,
     pub virtual_tags: VirtualTags,
+    pub challenge: ChallengeSettings,
+    /// clearance cookies, issued once the challenge provider recognized a human
+    pub clearance: Option<Arc<ClearanceSettings>>,
     pub logs: Logs,
 
     // Not used when processing request, but to optimize reloading config
@@ -277,6 +436,10 @@ pub struct Config {
     pub global_limits: Vec<Limit>,
     pub inactive_limits: HashSet<String>,
     pub acls: HashMap<String, AclProfile>,
+
+    /// registered hooks, followed by the plugins
+    pub hooks: Hooks,
+    pub plugin_hooks: Hooks,
 }
 
 fn from_map<V: Clone>(mp: &HashMap<String, V>, k: &str) -> Result<V, String> {
@@ -286,6 +449,31 @@ fn from_map<V: Clone>(mp: &HashMap<String, V>, k: &str) -> Result<V, String> {
     })
 }
 
+/// regular expression entries, path template entries, and the default entry of a security policy
+type ResolvedEntries = (
+    Vec<Matching<Arc<SecurityPolicy>>>,
+    PathTrie<Vec<TemplateEntry>>,
+    Option<Arc<SecurityPolicy>>,
+);
+
+/// a security policy entry, before the entries are ordered
+enum OrderedEntry {
+    Regex(Matching<Arc<SecurityPolicy>>),
+    /// the template, its text and the entry
+    Template(PathTemplate, String, Arc<SecurityPolicy>),
+}
+
+impl OrderedEntry {
+    /// longest patterns first, method scoped entries before the others for the same pattern length
+    fn order(&self) -> (usize, bool) {
+        let (len, policy) = match self {
+            OrderedEntry::Regex(m) => (m.matcher_len(), &m.inner),
+            OrderedEntry::Template(_, text, policy) => (text.len(), policy),
+        };
+        (usize::MAX - len, policy.methods.is_none())
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 impl Config {
     fn resolve_security_policies(
@@ -299,11 +487,15 @@ impl Config {
         inactive_limits: &HashSet<String>,
         acls: &HashMap<String, AclProfile>,
         contentfilterprofiles: &HashMap<String, ContentFilterProfile>,
+        responsefilterprofiles: &HashMap<String, ResponseFilterProfile>,
+        dataleakrules: &Arc<DataLeakRules>,
         session: Vec<RequestSelector>,
         session_ids: Vec<RequestSelector>,
-    ) -> (Vec<Matching<Arc<SecurityPolicy>>>, Option<Arc<SecurityPolicy>>) {
+        counters: CounterSettings,
+        canary: Option<&Canary>,
+    ) -> ResolvedEntries {
         let mut default: Option<Arc<SecurityPolicy>> = None;
-        let mut entries: Vec<Matching<Arc<SecurityPolicy>>> = Vec::new();
+        let mut ordered: Vec<OrderedEntry> = Vec::new();
         for rawmap in rawmaps {
             let mapname = rawmap.name.clone();
             let acl_profile: AclProfile = match acls.get(&rawmap.acl_profile) {
@@ -313,7 +505,7 @@ impl Config {
                     AclProfile::default()
                 }
             };
-            let content_filter_profile: ContentFilterProfile =
+            let mut content_filter_profile: ContentFilterProfile =
                 match contentfilterprofiles.get(&rawmap.content_filter_profile) {
                     Some(p) => p.clone(),
                     None => {
@@ -321,6 +513,31 @@ impl Config {
                         continue;
                     }
                 };
+            if let Some(rawopenapi) = &rawmap.openapi {
+                let case_insensitive = content_filter_profile.case_insensitive_names;
+                match openapi::compile(rawopenapi).and_then(|schemas| {
+                    schemas
+                        .into_iter()
+                        .map(|raw| PathSchema::resolve(raw, case_insensitive))
+                        .collect::<anyhow::Result<Vec<PathSchema>>>()
+                }) {
+                    Ok(mut schemas) => {
+                        schemas.append(&mut content_filter_profile.schema);
+                        content_filter_profile.schema = schemas;
+                    }
+                    Err(rr) => logs.error(|| format!("OpenAPI document of security policy entry {}: {}", mapname, rr)),
+                }
+            }
+            let response_filter_profile = match &rawmap.response_filter_profile {
+                None => None,
+                Some(rfid) => {
+                    let p = responsefilterprofiles.get(rfid).cloned();
+                    if p.is_none() {
+                        logs.warning(|| format!("Unknown Response Filter profile {}", rfid));
+                    }
+                    p
+                }
+            };
             let mut olimits: Vec<Limit> = Vec::new();
             for gl in global_limits {
                 if !rawmap.limit_ids.contains(&gl.id) {
@@ -337,13 +554,47 @@ impl Config {
                     logs.debug(|| format!("Trying to add inactive limit {} in map {}", lid, mapname))
                 }
             }
+            let candidate = rawmap.candidate.and_then(|raw| {
+                Candidate::resolve(
+                    logs,
+                    raw,
+                    acls,
+                    contentfilterprofiles,
+                    &acl_profile,
+                    &content_filter_profile,
+                )
+            });
+            let path_template = match rawmap.match_.strip_prefix(TEMPLATE_PREFIX) {
+                None => None,
+                Some(t) => match t.parse::<PathTemplate>() {
+                    Ok(tpl) => Some(tpl),
+                    Err(rr) => {
+                        logs.error(|| format!("Invalid path template in entry {}: {}", mapname, rr));
+                        continue;
+                    }
+                },
+            };
+            let methods = if rawmap.methods.is_empty() {
+                None
+            } else {
+                let mut methods: Vec<String> = rawmap.methods.iter().map(|m| m.to_ascii_uppercase()).collect();
+                methods.sort();
+                methods.dedup();
+                Some(methods)
+            };
+            let entry_id = rawmap.id.unwrap_or_else(|| mapname.clone());
+            // the replay protection relies on the signed timestamp
+            let signature = rawmap.signature.and_then(|raw| SignatureSettings::resolve(logs, raw));
             let securitypolicy = SecurityPolicy {
                 policy: PolicyId {
                     id: policyid.to_string(),
                     name: policyname.to_string(),
                 },
+                canary: canary.and_then(|c| c.entry(&entry_id)),
+                candidate,
+                access_log: rawmap.access_log.map(AccessLogSettings::resolve),
                 entry: PolicyId {
-                    id: rawmap.id.unwrap_or_else(|| mapname.clone()),
+                    id: entry_id,
                     name: rawmap.name,
                 },
                 tags: tags.clone(),
@@ -354,6 +605,41 @@ impl Config {
                 content_filter_active: rawmap.content_filter_active,
                 content_filter_profile,
                 limits: olimits,
+                response_filter_active: rawmap.response_filter_active,
+                response_filter_profile,
+                counters: counters.clone(),
+                jwt: rawmap.jwt.map(JwtSettings::resolve),
+                session_tracking: rawmap.session_tracking.map(|raw| SessionSettings::resolve(logs, raw)),
+                decision_cache: rawmap
+                    .decision_cache
+                    .map(|raw| Arc::new(DecisionCache::new(DecisionCacheSettings::resolve(logs, raw)))),
+                data_leak: rawmap
+                    .data_leak
+                    .map(|raw| DataLeakSettings::resolve(logs, dataleakrules, raw)),
+                csrf: rawmap.csrf.and_then(|raw| CsrfSettings::resolve(logs, raw)),
+                protocol: rawmap.protocol.map(ProtocolSettings::resolve),
+                websocket: rawmap.websocket.map(WebSocketSettings::resolve),
+                bot_score: rawmap.bot_score.map(BotScoreSettings::resolve),
+                captcha: rawmap.captcha.and_then(|raw| CaptchaSettings::resolve(logs, raw)),
+                failure_policy: rawmap.failure_policy,
+                path_template,
+                methods,
+                api_key: rawmap.api_key.and_then(|raw| ApiKeySettings::resolve(logs, raw)),
+                honeypot: rawmap.honeypot.and_then(|raw| HoneypotSettings::resolve(logs, raw)),
+                offender_tracking: rawmap.offender_tracking.map(|raw| OffenderSettings::resolve(logs, raw)),
+                bypass: rawmap.bypass.and_then(|raw| BypassSettings::resolve(logs, raw)),
+                cadence: rawmap.cadence.map(|raw| CadenceSettings::resolve(logs, raw)),
+                login_protection: rawmap
+                    .login_protection
+                    .and_then(|raw| LoginProtectionSettings::resolve(logs, raw)),
+                checkout_protection: rawmap
+                    .checkout_protection
+                    .and_then(|raw| CheckoutProtectionSettings::resolve(logs, raw)),
+                scraping: rawmap.scraping.map(ScrapingSettings::resolve),
+                replay: rawmap
+                    .replay
+                    .and_then(|raw| ReplaySettings::resolve(logs, raw, signature.as_ref())),
+                signature,
             };
             if rawmap.match_ == "__default__"
                 || securitypolicy.entry.id == "__default__"
@@ -366,17 +652,42 @@ impl Config {
                     logs.warning("Multiple __default__ maps");
                 }
                 default = Some(Arc::new(securitypolicy));
+            } else if let Some(tpl) = securitypolicy.path_template.clone() {
+                let text = rawmap.match_[TEMPLATE_PREFIX.len()..].to_string();
+                ordered.push(OrderedEntry::Template(tpl, text, Arc::new(securitypolicy)));
             } else {
                 match Matching::from_str(&rawmap.match_, Arc::new(securitypolicy)) {
                     Err(rr) => {
                         logs.warning(format!("Invalid regex {} in entry {}: {}", &rawmap.match_, &mapname, rr).as_str())
                     }
-                    Ok(matcher) => entries.push(matcher),
+                    Ok(matcher) => ordered.push(OrderedEntry::Regex(matcher)),
                 }
             }
         }
-        entries.sort_by_key(|x: &Matching<Arc<SecurityPolicy>>| usize::MAX - x.matcher_len());
-        (entries, default)
+        // the sort is stable, entries of the same length are kept in their configured order
+        ordered.sort_by_key(OrderedEntry::order);
+        let mut entries: Vec<Matching<Arc<SecurityPolicy>>> = Vec::new();
+        let mut templates: PathTrie<Vec<TemplateEntry>> = PathTrie::default();
+        for entry in ordered {
+            match entry {
+                OrderedEntry::Regex(matcher) => entries.push(matcher),
+                OrderedEntry::Template(tpl, text, policy) => {
+                    let shared = templates.get_or_insert_with(&tpl, Vec::new);
+                    if shared.iter().any(|t| t.policy.methods == policy.methods) {
+                        logs.warning(
+                            format!("Duplicate path template {} in entry {}", text, policy.entry.name).as_str(),
+                        );
+                    }
+                    shared.push(TemplateEntry {
+                        rank: entries.len(),
+                        policy,
+                    });
+                    // method scoped entries are tried first
+                    shared.sort_by_key(|t| t.policy.methods.is_none());
+                }
+            }
+        }
+        (entries, templates, default)
     }
 
     fn resolve(
@@ -388,6 +699,8 @@ impl Config {
         rawglobalfilters: Vec<RawGlobalFilterSection>,
         rawacls: Vec<RawAclProfile>,
         content_filter_profiles: HashMap<String, ContentFilterProfile>,
+        response_filter_profiles: HashMap<String, ResponseFilterProfile>,
+        data_leak_rules: Arc<DataLeakRules>,
         container_name: Option<String>,
         rawflows: Vec<RawFlowEntry>,
         rawvirtualtags: Vec<RawVirtualTag>,
@@ -400,7 +713,7 @@ impl Config {
             .map(|a| (a.id.clone(), AclProfile::resolve(&mut logs, &actions, a)))
             .collect();
 
-        let (securitypolicies_map, securitypolicies, default) = sec_pol_resolve(
+        let (securitypolicies_map, securitypolicies, default, host_rules) = sec_pol_resolve(
             &mut logs,
             rawmaps,
             &limits,
@@ -408,6 +721,8 @@ impl Config {
             &inactive_limits,
             &acls,
             &content_filter_profiles,
+            &response_filter_profiles,
+            &data_leak_rules,
         );
 
         let globalfilters = GlobalFilterSection::resolve(&mut logs, &actions, rawglobalfilters);
@@ -418,24 +733,43 @@ impl Config {
 
         Config {
             revision,
+            config_hash: String::new(),
             securitypolicies_map,
             securitypolicies,
             globalfilters,
             default,
+            host_rules,
             container_name,
             flows,
             content_filter_profiles,
+            response_filter_profiles,
+            data_leak_rules,
+            hsdb: Arc::new(HashMap::new()),
             logs,
             virtual_tags,
+            challenge: ChallengeSettings::default(),
+            clearance: None,
             actions,
             limits,
             global_limits,
             inactive_limits,
             acls,
+            hooks: Hooks::registered(),
+            plugin_hooks: Hooks::default(),
         }
     }
 
     fn load_config_file<A: serde::de::DeserializeOwned>(logs: &mut Logs, base: &Path, fname: &str) -> Vec<A> {
+        Config::load_config_file_with(logs, base, fname, |_| ())
+    }
+
+    /// loads a configuration file, preparing each raw entry before it is deserialized
+    fn load_config_file_with<A: serde::de::DeserializeOwned>(
+        logs: &mut Logs,
+        base: &Path,
+        fname: &str,
+        prepare: fn(&mut serde_json::Value),
+    ) -> Vec<A> {
         let mut path = base.to_path_buf();
         path.push(fname);
         let fullpath = path.to_str().unwrap_or(fname).to_string();
@@ -455,7 +789,8 @@ impl Config {
             }
         };
         let mut out = Vec::new();
-        for value in values {
+        for mut value in values {
+            prepare(&mut value);
             // for each entry, try to resolve it as a raw configuration value, failing otherwise
             match serde_json::from_value(value) {
                 Err(rr) => logs.error(|| format!("when resolving entry from {}: {}", fullpath, rr)),
@@ -465,45 +800,53 @@ impl Config {
         out
     }
 
-    fn load(mut logs: Logs, basepath: &str) -> Config {
+    fn load(logs: Logs, basepath: &str) -> Config {
+        let (mut config, globals) = Config::load_candidate(logs, basepath);
+        globals.apply(&mut config.logs);
+        config
+    }
+
+    /// loads a configuration, without touching the global settings
+    fn load_candidate(mut logs: Logs, basepath: &str) -> (Config, GlobalSettings) {
         let mut bjson = PathBuf::from(basepath);
         bjson.push("json");
 
         logs.debug(|| format!("Loading configuration from {}", basepath));
 
-        let mmanifest: Result<RawManifest, String> = PathBuf::from(basepath)
-            .parent()
-            .ok_or_else(|| "could not get parent directory?".to_string())
-            .and_then(|x| {
-                let mut pth = x.to_owned();
-                pth.push("manifest.json");
-                std::fs::File::open(pth).map_err(|rr| rr.to_string())
-            })
-            .and_then(|file| serde_json::from_reader(file).map_err(|rr| rr.to_string()));
-
-        let revision = match mmanifest {
+        let revision = match manifest_version(basepath) {
             Err(rr) => {
                 logs.error(move || format!("When loading manifest.json: {}", rr));
                 "unknown".to_string()
             }
-            Ok(manifest) => manifest.meta.version,
+            Ok(version) => version,
         };
 
         let rawactions = Config::load_config_file(&mut logs, &bjson, "actions.json");
-        let securitypolicy = Config::load_config_file(&mut logs, &bjson, "securitypolicy.json");
-        let globalfilters = Config::load_config_file(&mut logs, &bjson, "globalfilter-lists.json");
+        let securitypolicy =
+            Config::load_config_file_with(&mut logs, &bjson, "securitypolicy.json", inherit_policy_defaults);
+        let globalfilters = load_global_filters(&mut logs, &bjson);
         let limits = Config::load_config_file(&mut logs, &bjson, "limits.json");
         let acls = Config::load_config_file(&mut logs, &bjson, "acl-profiles.json");
         let rawcontentfilterprofiles = Config::load_config_file(&mut logs, &bjson, "contentfilter-profiles.json");
         let flows = Config::load_config_file(&mut logs, &bjson, "flow-control.json");
         let virtualtags = Config::load_config_file(&mut logs, &bjson, "virtual-tags.json");
+        let rawresponsefilterprofiles = Config::load_config_file(&mut logs, &bjson, "responsefilter-profiles.json");
 
         let container_name = container_name();
 
-        let actions = SimpleAction::resolve_actions(&mut logs, rawactions);
+        let templates = load_response_templates(&mut logs, &bjson);
+        let actions = SimpleAction::resolve_actions(&mut logs, &templates, rawactions);
         let content_filter_profiles = ContentFilterProfile::resolve(&mut logs, &actions, rawcontentfilterprofiles);
+        let response_filter_profiles = ResponseFilterProfile::resolve(&mut logs, &actions, rawresponsefilterprofiles);
+        let data_leak_rules = load_data_leak_rules(&mut logs, &bjson, &actions);
+        let globals = GlobalSettings {
+            feeds: load_reputation_feeds(&mut logs, &bjson),
+            geo: load_geo(&mut logs, &bjson),
+            client_ip: load_client_ip(&mut logs, &bjson),
+            webhooks: load_webhooks(&mut logs, &bjson),
+        };
 
-        Config::resolve(
+        let mut config = Config::resolve(
             logs,
             revision,
             actions,
@@ -512,33 +855,182 @@ impl Config {
             globalfilters,
             acls,
             content_filter_profiles,
+            response_filter_profiles,
+            data_leak_rules,
             container_name,
             flows,
             virtualtags,
-        )
+        );
+
+        let (challenge, clearance) = load_challenge(&mut config.logs, &bjson);
+        config.challenge = challenge;
+        config.clearance = clearance;
+
+        // plugins are optional
+        if bjson.join("wasm-plugins.json").exists() {
+            let raw_plugins = Config::load_config_file(&mut config.logs, &bjson, "wasm-plugins.json");
+            config.plugin_hooks = load_plugins(&mut config.logs, raw_plugins);
+            config.hooks = config.hooks.with(&config.plugin_hooks);
+        }
+        config.config_hash = config_hash(&bjson);
+        (config, globals)
     }
 
     pub fn empty() -> Config {
         Config {
             revision: "dummy".to_string(),
+            config_hash: String::new(),
             securitypolicies_map: HashMap::new(),
             securitypolicies: Vec::new(),
             globalfilters: Vec::new(),
             default: None,
+            host_rules: HostRules::default(),
             container_name: container_name(),
             flows: HashMap::new(),
             content_filter_profiles: HashMap::new(),
+            response_filter_profiles: HashMap::new(),
+            data_leak_rules: Arc::new(DataLeakRules::empty()),
+            hsdb: Arc::new(HashMap::new()),
             logs: Logs::default(),
             virtual_tags: Arc::new(HashMap::new()),
+            challenge: ChallengeSettings::default(),
+            clearance: None,
             actions: HashMap::new(),
             limits: HashMap::new(),
             global_limits: Vec::new(),
             inactive_limits: HashSet::new(),
             acls: HashMap::new(),
+            hooks: Hooks::registered(),
+            plugin_hooks: Hooks::default(),
+        }
+    }
+}
+
+/// global filters, with the sections built from the optional fingerprint lists
+///
+/// known HTTP clients are tagged first, so that the other sections can use their tags
+fn load_global_filters(logs: &mut Logs, configpath: &Path) -> Vec<RawGlobalFilterSection> {
+    let mut out = Vec::new();
+    if configpath.join("http-fingerprints.json").exists() {
+        let raw_fingerprints = Config::load_config_file(logs, configpath, "http-fingerprints.json");
+        out.extend(http_fingerprint_sections(raw_fingerprints));
+    }
+    out.extend(Config::load_config_file(logs, configpath, "globalfilter-lists.json"));
+    if configpath.join("tls-fingerprints.json").exists() {
+        let raw_fingerprints = Config::load_config_file(logs, configpath, "tls-fingerprints.json");
+        out.extend(tls_fingerprint_sections(raw_fingerprints));
+    }
+    if configpath.join("ip-lists.json").exists() {
+        let ip_lists: Vec<RawIpList> = Config::load_config_file(logs, configpath, "ip-lists.json");
+        out = expand_ip_lists(out, &ip_lists);
+    }
+    out
+}
+
+/// settings that are not part of the configuration, but held in globals
+struct GlobalSettings {
+    feeds: Vec<ReputationFeed>,
+    geo: Option<RawGeoSettings>,
+    client_ip: Option<ClientIpSettings>,
+    webhooks: Vec<WebhookSettings>,
+}
+
+impl GlobalSettings {
+    fn apply(self, logs: &mut Logs) {
+        configure_feeds(logs, self.feeds);
+        configure_geo(logs, self.geo);
+        configure_client_ip(self.client_ip);
+        configure_webhooks(self.webhooks);
+    }
+}
+
+/// the reputation feeds are optional, their lists are loaded in the background
+fn load_reputation_feeds(logs: &mut Logs, configpath: &Path) -> Vec<ReputationFeed> {
+    let raw_feeds = if configpath.join("reputation-feeds.json").exists() {
+        Config::load_config_file(logs, configpath, "reputation-feeds.json")
+    } else {
+        Vec::new()
+    };
+    ReputationFeed::resolve(raw_feeds)
+}
+
+/// the geographic provider is optional, MaxMind or ipinfo being used by default
+fn load_geo(logs: &mut Logs, configpath: &Path) -> Option<RawGeoSettings> {
+    let raw_settings: Vec<RawGeoSettings> = if configpath.join("geo.json").exists() {
+        Config::load_config_file(logs, configpath, "geo.json")
+    } else {
+        Vec::new()
+    };
+    raw_settings.into_iter().next()
+}
+
+/// without trusted proxies, the address provided by the integration is the client address
+fn load_client_ip(logs: &mut Logs, configpath: &Path) -> Option<ClientIpSettings> {
+    let raw_settings: Vec<RawClientIpSettings> = if configpath.join("trusted-proxies.json").exists() {
+        Config::load_config_file(logs, configpath, "trusted-proxies.json")
+    } else {
+        Vec::new()
+    };
+    raw_settings
+        .into_iter()
+        .next()
+        .and_then(|raw| ClientIpSettings::resolve(logs, raw))
+}
+
+/// webhooks are optional
+fn load_webhooks(logs: &mut Logs, configpath: &Path) -> Vec<WebhookSettings> {
+    let raw_webhooks: Vec<RawWebhook> = if configpath.join("webhooks.json").exists() {
+        Config::load_config_file(logs, configpath, "webhooks.json")
+    } else {
+        Vec::new()
+    };
+    raw_webhooks
+        .into_iter()
+        .filter_map(|raw| WebhookSettings::resolve(logs, raw))
+        .collect()
+}
+
+/// the challenge provider is optional, grasshopper being used by default
+fn load_challenge(logs: &mut Logs, configpath: &Path) -> (ChallengeSettings, Option<Arc<ClearanceSettings>>) {
+    if !configpath.join("challenge.json").exists() {
+        return (ChallengeSettings::default(), None);
+    }
+    let raw_settings: Vec<RawChallengeSettings> = Config::load_config_file(logs, configpath, "challenge.json");
+    match raw_settings.into_iter().next() {
+        None => (ChallengeSettings::default(), None),
+        Some(mut raw) => {
+            let clearance = raw
+                .clearance
+                .take()
+                .and_then(|c| ClearanceSettings::resolve(logs, c))
+                .map(Arc::new);
+            (ChallengeSettings::resolve(logs, raw), clearance)
         }
     }
 }
 
+/// the response templates are optional
+fn load_response_templates(logs: &mut Logs, configpath: &Path) -> HashMap<String, Arc<ResponseTemplate>> {
+    if !configpath.join("response-templates.json").exists() {
+        return HashMap::new();
+    }
+    let raw_templates = Config::load_config_file(logs, configpath, "response-templates.json");
+    ResponseTemplate::resolve(logs, raw_templates)
+}
+
+/// the data leak rules are optional
+fn load_data_leak_rules(
+    logs: &mut Logs,
+    configpath: &Path,
+    actions: &HashMap<String, SimpleAction>,
+) -> Arc<DataLeakRules> {
+    if !configpath.join("dataleak-rules.json").exists() {
+        return Arc::new(DataLeakRules::empty());
+    }
+    let raw_rules = Config::load_config_file(logs, configpath, "dataleak-rules.json");
+    Arc::new(DataLeakRules::resolve(logs, actions, raw_rules))
+}
+
 pub fn load_hsdb(
     logs: &mut Logs,
     configpath: &Path,
@@ -556,7 +1048,15 @@ pub fn load_hsdb(
     resolve_rules(logs, profiles, contentfilterrules)
 }
 
-// securitypolicies_map, securitypolicies, default
+/// securitypolicies_map, securitypolicies, default, host_rules
+type ResolvedPolicies = (
+    HashMap<String, HostMap>,
+    Vec<Matching<HostMap>>,
+    Option<HostMap>,
+    HostRules,
+);
+
+#[allow(clippy::too_many_arguments)]
 fn sec_pol_resolve(
     logs: &mut Logs,
     rawmaps: Vec<RawHostMap>,
@@ -565,10 +1065,18 @@ fn sec_pol_resolve(
     inactive_limits: &HashSet<String>,
     acls: &HashMap<String, AclProfile>,
     content_filter_profiles: &HashMap<String, ContentFilterProfile>,
-) -> (HashMap<String, HostMap>, Vec<Matching<HostMap>>, Option<HostMap>) {
+    response_filter_profiles: &HashMap<String, ResponseFilterProfile>,
+    data_leak_rules: &Arc<DataLeakRules>,
+) -> ResolvedPolicies {
     let mut default: Option<HostMap> = None;
+    let mut host_rules = HostRules::default();
     let mut securitypolicies: Vec<Matching<HostMap>> = Vec::new();
     let mut securitypolicies_map = HashMap::new();
+    let mut canaries: HashMap<String, Canary> = HashMap::new();
+
+    // canary versions are built first, so that they can be attached to their primary security policies
+    let mut rawmaps = rawmaps;
+    rawmaps.sort_by_key(|rawmap| rawmap.canary.is_none());
 
     // build the entries while looking for the default entry
     for rawmap in rawmaps {
@@ -596,7 +1104,8 @@ fn sec_pol_resolve(
             logs.error(|| format!("error when decoding session_ids in {}, {}", &mapname, rr));
             Vec::new()
         });
-        let (entries, default_entry) = Config::resolve_security_policies(
+        let counters = CounterSettings::resolve(logs, rawmap.counters);
+        let (entries, templates, default_entry) = Config::resolve_security_policies(
             logs,
             &rawmap.id,
             &rawmap.name,
@@ -607,8 +1116,12 @@ fn sec_pol_resolve(
             inactive_limits,
             acls,
             content_filter_profiles,
+            response_filter_profiles,
+            data_leak_rules,
             session,
             session_ids,
+            counters,
+            canaries.get(&rawmap.id),
         );
         if default_entry.is_none() {
             logs.warning(format!("HostMap entry '{}' does not have a default entry", &rawmap.name).as_str());
@@ -616,8 +1129,30 @@ fn sec_pol_resolve(
         let hostmap = HostMap {
             name: rawmap.name,
             entries,
+            templates,
             default: default_entry,
         };
+        if let Some(rawcanary) = rawmap.canary {
+            // canary versions are never matched directly
+            let canary = Canary::resolve(logs, rawcanary, hostmap);
+            if canaries.contains_key(&canary.primary) {
+                logs.error(|| format!("security policy {} has several canary versions", canary.primary));
+            }
+            canaries.insert(canary.primary.clone(), canary);
+            continue;
+        }
+        for rule in &rawmap.hosts {
+            if let Err(rr) = host_rules.add(rule, &rawmap.id) {
+                logs.error(|| format!("In security policy {}: {}", hostmap.name, rr));
+            }
+        }
+        if rawmap.fallback {
+            if let Some(other) = &host_rules.fallback {
+                let id = &rawmap.id;
+                logs.error(|| format!("Security policies {} and {} are both fallbacks", other, id));
+            }
+            host_rules.fallback = Some(rawmap.id.clone());
+        }
         securitypolicies_map.insert(rawmap.id, hostmap.clone());
         if rawmap.match_ == "__default__" {
             if default.is_some() {
@@ -637,5 +1172,24 @@ fn sec_pol_resolve(
     // order by decreasing matcher length, so that more specific rules are matched first
     securitypolicies.sort_by_key(|b| std::cmp::Reverse(b.matcher_len()));
 
-    (securitypolicies_map, securitypolicies, default)
+    (securitypolicies_map, securitypolicies, default, host_rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_follows_contents() {
+        let dir = std::env::temp_dir().join(format!("cf-config-hash-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let empty = config_hash(&dir);
+        std::fs::write(dir.join("limits.json"), "[]").unwrap();
+        let limits = config_hash(&dir);
+        assert_ne!(empty, limits);
+        assert_eq!(limits, config_hash(&dir));
+        std::fs::write(dir.join("limits.json"), "[ ]").unwrap();
+        assert_ne!(limits, config_hash(&dir));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,450 @@
+//! OpenAPI 3 documents.
+//!
+//! A security policy entry can embed the OpenAPI document of the API it protects. Each operation is
+//! compiled into a content filter schema (see contentfilter/schema.rs) for its path and method:
+//!  * path templates become regexes, integer path parameters only matching digits ;
+//!  * query parameters become expected arguments ;
+//!  * JSON request bodies become expected arguments, named like the flattened body arguments (object keys
+//!    and array indices joined with "_").
+//!
+//! Header and cookie parameters, as well as non JSON bodies, are not checked. The compiled schemas take
+//! precedence over the ones of the content filter profile.
+use anyhow::{anyhow, bail};
+use serde_json::Value;
+
+use super::raw::{RawOpenApi, RawParamSchema, RawPathSchema};
+use crate::contentfilter::learning::ValueShape;
+
+const METHODS: [&str; 8] = ["get", "put", "post", "delete", "options", "head", "patch", "trace"];
+
+/// bounds the number of followed references, and the nesting of body schemas
+const MAX_DEPTH: usize = 32;
+
+/// follows local references, such as #/components/schemas/Item
+fn deref<'a>(root: &'a Value, mut value: &'a Value) -> anyhow::Result<&'a Value> {
+    for _ in 0..MAX_DEPTH {
+        let reference = match value.get("$ref").and_then(Value::as_str) {
+            None => return Ok(value),
+            Some(r) => r,
+        };
+        let pointer = reference
+            .strip_prefix('#')
+            .ok_or_else(|| anyhow!("only local references are supported, not {}", reference))?;
+        value = root
+            .pointer(pointer)
+            .ok_or_else(|| anyhow!("unresolved reference {}", reference))?;
+    }
+    bail!("too many nested references")
+}
+
+/// the path prefix of the first server, with its variables set to their default values
+fn base_path(root: &Value) -> String {
+    let server = match root.get("servers").and_then(|s| s.get(0)) {
+        None => return String::new(),
+        Some(s) => s,
+    };
+    let mut url = server.get("url").and_then(Value::as_str).unwrap_or("").to_string();
+    if let Some(variables) = server.get("variables").and_then(Value::as_object) {
+        for (name, var) in variables {
+            if let Some(default) = var.get("default").and_then(Value::as_str) {
+                url = url.replace(&format!("{{{}}}", name), default);
+            }
+        }
+    }
+    let path = match url.find("://") {
+        None => url.as_str(),
+        Some(idx) => {
+            let rest = &url[idx + 3..];
+            rest.find('/').map(|p| &rest[p..]).unwrap_or("")
+        }
+    };
+    path.trim_end_matches('/').to_string()
+}
+
+fn shape(schema: &Value) -> Option<ValueShape> {
+    match schema.get("type").and_then(Value::as_str) {
+        Some("integer") => Some(ValueShape::Integer),
+        Some("number") => Some(ValueShape::Decimal),
+        Some("boolean") => Some(ValueShape::Boolean),
+        Some("string") if schema.get("format").and_then(Value::as_str) == Some("uuid") => Some(ValueShape::Uuid),
+        _ => None,
+    }
+}
+
+fn length(schema: &Value, key: &str) -> Option<usize> {
+    schema.get(key).and_then(Value::as_u64).map(|l| l as usize)
+}
+
+fn param_schema(name: String, schema: &Value, required: bool) -> RawParamSchema {
+    RawParamSchema {
+        name,
+        shape: shape(schema),
+        min_length: length(schema, "minLength"),
+        max_length: length(schema, "maxLength"),
+        required,
+    }
+}
+
+/// adds a parameter, merging it with a previous one with the same name, as only the first matching
+/// parameter of a schema is checked
+fn push_param(params: &mut Vec<RawParamSchema>, param: RawParamSchema) {
+    match params.iter_mut().find(|p| p.name == param.name) {
+        None => params.push(param),
+        Some(prev) => {
+            if prev.shape != param.shape {
+                prev.shape = match (prev.shape, param.shape) {
+                    (Some(a), Some(b)) => Some(a.merge(b)),
+                    _ => None,
+                };
+            }
+            prev.min_length = prev.min_length.min(param.min_length);
+            prev.max_length = match (prev.max_length, param.max_length) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                _ => None,
+            };
+            prev.required &= param.required;
+        }
+    }
+}
+
+fn child_name(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}_{}", prefix, name)
+    }
+}
+
+/// flattens a JSON body schema into arguments, prefix being the regex of the current position
+///
+/// catchall receives the arguments matching free form objects, they must be checked last
+fn body_params(
+    root: &Value,
+    schema: &Value,
+    prefix: &str,
+    required: bool,
+    depth: usize,
+    params: &mut Vec<RawParamSchema>,
+    catchall: &mut Vec<RawParamSchema>,
+) -> anyhow::Result<()> {
+    if depth == 0 {
+        bail!("request body schema is too deep");
+    }
+    let schema = deref(root, schema)?;
+    if let Some(all) = schema.get("allOf").and_then(Value::as_array) {
+        for sub in all {
+            body_params(root, sub, prefix, required, depth - 1, params, catchall)?;
+        }
+    }
+    for key in &["oneOf", "anyOf"] {
+        if let Some(alternatives) = schema.get(key).and_then(Value::as_array) {
+            for sub in alternatives {
+                body_params(root, sub, prefix, false, depth - 1, params, catchall)?;
+            }
+        }
+    }
+    let tp = schema.get("type").and_then(Value::as_str).or_else(|| {
+        if schema.get("properties").is_some() {
+            Some("object")
+        } else {
+            None
+        }
+    });
+    match tp {
+        Some("object") => {
+            let required_props: Vec<&str> = schema
+                .get("required")
+                .and_then(Value::as_array)
+                .map(|r| r.iter().filter_map(Value::as_str).collect())
+                .unwrap_or_default();
+            if let Some(properties) = schema.get("properties").and_then(Value::as_object) {
+                for (name, sub) in properties {
+                    body_params(
+                        root,
+                        sub,
+                        &child_name(prefix, &regex::escape(name)),
+                        required && required_props.contains(&name.as_str()),
+                        depth - 1,
+                        params,
+                        catchall,
+                    )?;
+                }
+            }
+            let free_form = match schema.get("additionalProperties") {
+                Some(Value::Bool(b)) => *b,
+                Some(Value::Object(_)) => true,
+                _ => false,
+            };
+            if free_form {
+                push_param(catchall, param_schema(child_name(prefix, ".+"), &Value::Null, false));
+            }
+        }
+        Some("array") => {
+            let items = schema.get("items").unwrap_or(&Value::Null);
+            body_params(
+                root,
+                items,
+                &child_name(prefix, "[0-9]+"),
+                false,
+                depth - 1,
+                params,
+                catchall,
+            )?;
+        }
+        // compositions without a type do not describe a value by themselves
+        None if schema.get("allOf").is_some() || schema.get("oneOf").is_some() || schema.get("anyOf").is_some() => (),
+        _ => {
+            let name = if prefix.is_empty() { "JSON_ROOT" } else { prefix };
+            push_param(params, param_schema(name.to_string(), schema, required));
+        }
+    }
+    Ok(())
+}
+
+/// the JSON schema of a request body, if there is one
+fn json_body<'a>(root: &'a Value, operation: &'a Value) -> anyhow::Result<Option<(&'a Value, bool)>> {
+    let body = match operation.get("requestBody") {
+        None => return Ok(None),
+        Some(b) => deref(root, b)?,
+    };
+    let required = body.get("required").and_then(Value::as_bool).unwrap_or(false);
+    let content = match body.get("content").and_then(Value::as_object) {
+        None => return Ok(None),
+        Some(c) => c,
+    };
+    Ok(content
+        .iter()
+        .find(|(ct, _)| ct.starts_with("application/") && ct.ends_with("json"))
+        .and_then(|(_, media)| media.get("schema"))
+        .map(|schema| (schema, required)))
+}
+
+/// path and operation parameters, the operation ones taking precedence
+fn parameters<'a>(root: &'a Value, path_item: &'a Value, operation: &'a Value) -> anyhow::Result<Vec<&'a Value>> {
+    let mut out: Vec<&Value> = Vec::new();
+    for source in &[path_item, operation] {
+        if let Some(params) = source.get("parameters").and_then(Value::as_array) {
+            for param in params {
+                let param = deref(root, param)?;
+                let key = (param.get("name"), param.get("in"));
+                out.retain(|p| (p.get("name"), p.get("in")) != key);
+                out.push(param);
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// converts a path template to a regex
+fn path_regex(root: &Value, base: &str, template: &str, params: &[&Value]) -> anyhow::Result<String> {
+    let mut out = format!("^{}", regex::escape(base));
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("unterminated parameter in path {}", template))?
+            + start;
+        out += &regex::escape(&rest[..start]);
+        let name = &rest[start + 1..end];
+        let param_shape = match params
+            .iter()
+            .find(|p| {
+                p.get("in").and_then(Value::as_str) == Some("path")
+                    && p.get("name").and_then(Value::as_str) == Some(name)
+            })
+            .and_then(|p| p.get("schema"))
+        {
+            None => None,
+            Some(schema) => shape(deref(root, schema)?),
+        };
+        out += if param_shape == Some(ValueShape::Integer) {
+            "-?[0-9]+"
+        } else {
+            "[^/]+"
+        };
+        rest = &rest[end + 1..];
+    }
+    out += &regex::escape(rest);
+    out.push('$');
+    Ok(out)
+}
+
+fn compile_operation(
+    root: &Value,
+    base: &str,
+    template: &str,
+    method: &str,
+    path_item: &Value,
+    operation: &Value,
+    settings: &RawOpenApi,
+) -> anyhow::Result<RawPathSchema> {
+    let params = parameters(root, path_item, operation)?;
+    let mut schema_params = Vec::new();
+    for param in &params {
+        if param.get("in").and_then(Value::as_str) != Some("query") {
+            continue;
+        }
+        let name = param
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("query parameter without a name"))?;
+        let required = param.get("required").and_then(Value::as_bool).unwrap_or(false);
+        let schema = match param.get("schema") {
+            None => &Value::Null,
+            Some(s) => deref(root, s)?,
+        };
+        // exploded arrays are repeated arguments
+        let schema = if schema.get("type").and_then(Value::as_str) == Some("array") {
+            match schema.get("items") {
+                None => &Value::Null,
+                Some(items) => deref(root, items)?,
+            }
+        } else {
+            schema
+        };
+        push_param(&mut schema_params, param_schema(regex::escape(name), schema, required));
+    }
+    if let Some((body, required)) = json_body(root, operation)? {
+        let mut catchall = Vec::new();
+        body_params(root, body, "", required, MAX_DEPTH, &mut schema_params, &mut catchall)?;
+        for param in catchall {
+            push_param(&mut schema_params, param);
+        }
+    }
+    Ok(RawPathSchema {
+        path: path_regex(root, base, template, &params)?,
+        methods: vec![method.to_uppercase()],
+        params: schema_params,
+        allow_unknown: settings.allow_unknown,
+        block: settings.block,
+    })
+}
+
+/// compiles an OpenAPI 3 document into content filter schemas, one per operation
+pub fn compile(settings: &RawOpenApi) -> anyhow::Result<Vec<RawPathSchema>> {
+    let root = &settings.document;
+    match root.get("openapi").and_then(Value::as_str) {
+        Some(v) if v.starts_with("3.") => (),
+        Some(v) => bail!("unsupported OpenAPI version {}", v),
+        None => bail!("not an OpenAPI 3 document"),
+    }
+    let base = base_path(root);
+    let paths = root
+        .get("paths")
+        .and_then(Value::as_object)
+        .ok_or_else(|| anyhow!("no paths in OpenAPI document"))?;
+    let mut out = Vec::new();
+    for (template, path_item) in paths {
+        let path_item = deref(root, path_item)?;
+        for method in METHODS.iter() {
+            if let Some(operation) = path_item.get(method) {
+                let schema = compile_operation(root, &base, template, method, path_item, operation, settings)
+                    .map_err(|rr| anyhow!("{} {}: {}", method.to_uppercase(), template, rr))?;
+                out.push((template.matches('{').count(), schema));
+            }
+        }
+    }
+    // concrete paths are matched before templated ones, as in /items/mine and /items/{id}
+    out.sort_by_key(|(templated, _)| *templated);
+    Ok(out.into_iter().map(|(_, schema)| schema).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document() -> Value {
+        serde_json::json!({
+            "openapi": "3.0.3",
+            "servers": [{"url": "https://api.example.com/{version}", "variables": {"version": {"default": "v1"}}}],
+            "paths": {
+                "/items/{id}": {
+                    "parameters": [{"name": "id", "in": "path", "required": true, "schema": {"type": "integer"}}],
+                    "get": {
+                        "parameters": [{"$ref": "#/components/parameters/Fields"}]
+                    },
+                    "put": {
+                        "requestBody": {
+                            "required": true,
+                            "content": {"application/json": {"schema": {"$ref": "#/components/schemas/Item"}}}
+                        }
+                    }
+                },
+                "/items/mine": {
+                    "get": {}
+                }
+            },
+            "components": {
+                "parameters": {
+                    "Fields": {"name": "fields", "in": "query", "schema": {"type": "string", "maxLength": 64}}
+                },
+                "schemas": {
+                    "Item": {
+                        "type": "object",
+                        "required": ["name"],
+                        "properties": {
+                            "name": {"type": "string", "minLength": 1},
+                            "uid": {"type": "string", "format": "uuid"},
+                            "tags": {"type": "array", "items": {"type": "string"}},
+                            "extra": {"type": "object", "additionalProperties": true}
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    fn param<'a>(schema: &'a RawPathSchema, name: &str) -> &'a RawParamSchema {
+        schema.params.iter().find(|p| p.name == name).unwrap()
+    }
+
+    #[test]
+    fn compile_document() {
+        let schemas = compile(&RawOpenApi {
+            document: document(),
+            allow_unknown: false,
+            block: true,
+        })
+        .unwrap();
+        let summary: Vec<(&str, &str)> = schemas
+            .iter()
+            .map(|s| (s.path.as_str(), s.methods[0].as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("^/v1/items/mine$", "GET"),
+                ("^/v1/items/-?[0-9]+$", "GET"),
+                ("^/v1/items/-?[0-9]+$", "PUT"),
+            ]
+        );
+
+        let get = &schemas[1];
+        assert_eq!(get.params.len(), 1);
+        assert_eq!(param(get, "fields").max_length, Some(64));
+        assert!(!param(get, "fields").required);
+
+        let put = &schemas[2];
+        assert!(param(put, "name").required);
+        assert_eq!(param(put, "name").min_length, Some(1));
+        assert_eq!(param(put, "uid").shape, Some(ValueShape::Uuid));
+        assert!(!param(put, "tags_[0-9]+").required);
+        assert_eq!(put.params.last().unwrap().name, "extra_.+");
+    }
+
+    #[test]
+    fn bad_documents() {
+        let compile_doc = |document| {
+            compile(&RawOpenApi {
+                document,
+                allow_unknown: false,
+                block: false,
+            })
+        };
+        assert!(compile_doc(serde_json::json!({"swagger": "2.0", "paths": {}})).is_err());
+        assert!(compile_doc(
+            serde_json::json!({"openapi": "3.1.0", "paths": {"/a": {"get": {"parameters": [{"$ref": "#/nope"}]}}}})
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,316 @@
+//! Path templates, compiled in a prefix trie to select security policy entries.
+//!
+//! Entries whose match starts with `path:` are path templates instead of regular expressions, such as
+//! `path:/users/{id}/orders` or `path:/static/**`. Each segment is either:
+//!
+//!  * a literal,
+//!  * a `{name}` capture, matching a non empty segment, exposed as the `name` argument of the request,
+//!  * a glob, such as `*` or `*.png`, matching a single segment,
+//!  * `**`, as the last segment, matching all the remaining ones.
+//!
+//! Templates are ordered with the regular expression entries, by decreasing length, and a template is only selected
+//! when no regular expression entry coming before it matches. When several templates match, literal segments have
+//! priority over globs, globs over captures, and captures over `**`, and the less specific templates are still tried
+//! when the entries of the most specific one do not accept the request method.
+use std::collections::HashMap;
+
+pub const TEMPLATE_PREFIX: &str = "path:";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Capture(String),
+    Glob(String),
+    Rest,
+}
+
+/// does the glob, where `*` matches any sequence of characters, match the segment
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if parts.len() == 1 {
+        return pattern == s;
+    }
+    if s.len() < first.len() + last.len() || !s.starts_with(first) || !s.ends_with(last) {
+        return false;
+    }
+    let mut rest = &s[first.len()..s.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            None => return false,
+            Some(p) => rest = &rest[p + part.len()..],
+        }
+    }
+    true
+}
+
+/// the path segments, without the query string
+fn path_segments(path: &str) -> Vec<&str> {
+    let qpath = path.split('?').next().unwrap_or(path);
+    qpath.strip_prefix('/').unwrap_or(qpath).split('/').collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathTemplate {
+    segments: Vec<Segment>,
+}
+
+impl std::str::FromStr for PathTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.starts_with('/') {
+            anyhow::bail!("path template {} does not start with /", s);
+        }
+        let raw = path_segments(s);
+        let mut segments = Vec::new();
+        for (i, seg) in raw.iter().enumerate() {
+            let segment = if *seg == "**" {
+                if i + 1 != raw.len() {
+                    anyhow::bail!("** is not the last segment of {}", s);
+                }
+                Segment::Rest
+            } else if let Some(name) = seg.strip_prefix('{').and_then(|n| n.strip_suffix('}')) {
+                if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                    anyhow::bail!("invalid capture name {} in {}", name, s);
+                }
+                Segment::Capture(name.to_string())
+            } else if seg.contains(['{', '}']) {
+                anyhow::bail!("invalid segment {} in {}", seg, s);
+            } else if seg.contains('*') {
+                Segment::Glob(seg.to_string())
+            } else {
+                Segment::Literal(seg.to_string())
+            };
+            segments.push(segment);
+        }
+        Ok(PathTemplate { segments })
+    }
+}
+
+impl PathTemplate {
+    /// the named captures of the path, None if the template does not match
+    pub fn captures(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let segs = path_segments(path);
+        let mut out = Vec::new();
+        for (i, segment) in self.segments.iter().enumerate() {
+            if *segment == Segment::Rest {
+                return Some(out);
+            }
+            let s = segs.get(i)?;
+            match segment {
+                Segment::Literal(l) if l == s => (),
+                Segment::Glob(g) if glob_match(g, s) => (),
+                Segment::Capture(name) if !s.is_empty() => out.push((name.clone(), s.to_string())),
+                _ => return None,
+            }
+        }
+        if segs.len() == self.segments.len() {
+            Some(out)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    literals: HashMap<String, Node>,
+    globs: Vec<(String, Node)>,
+    capture: Option<Box<Node>>,
+    /// value index for paths ending here
+    value: Option<usize>,
+    /// value index of a `**` segment
+    rest: Option<usize>,
+}
+
+impl Node {
+    fn new() -> Self {
+        Node {
+            literals: HashMap::new(),
+            globs: Vec::new(),
+            capture: None,
+            value: None,
+            rest: None,
+        }
+    }
+
+    /// pushes the value indices of all the matching templates, most specific first
+    fn lookup(&self, segs: &[&str], out: &mut Vec<usize>) {
+        let (head, tail) = match segs.split_first() {
+            None => {
+                // ** also matches no segment at all
+                out.extend(self.value.iter().chain(self.rest.iter()));
+                return;
+            }
+            Some(ht) => ht,
+        };
+        if let Some(n) = self.literals.get(*head) {
+            n.lookup(tail, out);
+        }
+        for (_, n) in self.globs.iter().filter(|(g, _)| glob_match(g, head)) {
+            n.lookup(tail, out);
+        }
+        if let Some(n) = self.capture.as_ref().filter(|_| !head.is_empty()) {
+            n.lookup(tail, out);
+        }
+        out.extend(self.rest);
+    }
+}
+
+/// path templates, compiled in a prefix trie
+#[derive(Debug, Clone)]
+pub struct PathTrie<A> {
+    root: Node,
+    values: Vec<A>,
+}
+
+impl<A> Default for PathTrie<A> {
+    fn default() -> Self {
+        PathTrie {
+            root: Node::new(),
+            values: Vec::new(),
+        }
+    }
+}
+
+impl<A> PathTrie<A> {
+    /// the value index slot of a template
+    fn slot<'n>(root: &'n mut Node, template: &PathTemplate) -> &'n mut Option<usize> {
+        let mut node = root;
+        for segment in &template.segments {
+            node = match segment {
+                Segment::Literal(l) => node.literals.entry(l.clone()).or_insert_with(Node::new),
+                Segment::Capture(_) => node.capture.get_or_insert_with(|| Box::new(Node::new())),
+                Segment::Glob(g) => {
+                    let pos = match node.globs.iter().position(|(pg, _)| pg == g) {
+                        Some(p) => p,
+                        None => {
+                            node.globs.push((g.clone(), Node::new()));
+                            node.globs.len() - 1
+                        }
+                    };
+                    &mut node.globs[pos].1
+                }
+                Segment::Rest => return &mut node.rest,
+            };
+        }
+        &mut node.value
+    }
+
+    /// adds a template, returns false when an equivalent template was already present
+    pub fn insert(&mut self, template: &PathTemplate, value: A) -> bool {
+        let slot = Self::slot(&mut self.root, template);
+        if slot.is_some() {
+            return false;
+        }
+        *slot = Some(self.values.len());
+        self.values.push(value);
+        true
+    }
+
+    /// the value of a template, inserted when absent
+    pub fn get_or_insert_with<F: FnOnce() -> A>(&mut self, template: &PathTemplate, f: F) -> &mut A {
+        let slot = Self::slot(&mut self.root, template);
+        let idx = match slot {
+            Some(i) => *i,
+            None => {
+                *slot = Some(self.values.len());
+                self.values.push(f());
+                self.values.len() - 1
+            }
+        };
+        &mut self.values[idx]
+    }
+
+    /// the value of the best matching template
+    pub fn get(&self, path: &str) -> Option<&A> {
+        self.get_all(path).next()
+    }
+
+    /// the values of all the matching templates, most specific first
+    pub fn get_all(&self, path: &str) -> impl Iterator<Item = &A> {
+        let mut out = Vec::new();
+        self.root.lookup(&path_segments(path), &mut out);
+        out.into_iter().map(move |i| &self.values[i])
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &A> {
+        self.values.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trie(templates: &[&str]) -> PathTrie<usize> {
+        let mut out = PathTrie::default();
+        for (i, t) in templates.iter().enumerate() {
+            assert!(out.insert(&t.parse().unwrap(), i), "{}", t);
+        }
+        out
+    }
+
+    #[test]
+    fn priorities() {
+        let t = trie(&[
+            "/users/{id}",
+            "/users/me",
+            "/users/{id}/orders/**",
+            "/static/*.png",
+            "/static/**",
+            "/",
+        ]);
+        assert_eq!(t.get("/users/me"), Some(&1));
+        assert_eq!(t.get("/users/42?x=y"), Some(&0));
+        assert_eq!(t.get("/users/42/orders"), Some(&2));
+        assert_eq!(t.get("/users/42/orders/7/items"), Some(&2));
+        assert_eq!(t.get("/static/logo.png"), Some(&3));
+        assert_eq!(t.get("/static/css/main.css"), Some(&4));
+        assert_eq!(t.get("/"), Some(&5));
+        assert_eq!(t.get("/users/"), None);
+        assert_eq!(t.get("/other"), None);
+        assert_eq!(t.get_all("/users/42/orders").collect::<Vec<_>>(), vec![&2]);
+        assert_eq!(t.get_all("/static/logo.png").collect::<Vec<_>>(), vec![&3, &4]);
+        let nested = trie(&["/**", "/api/{v}/**", "/api/v1/users", "/api/*/users"]);
+        assert_eq!(
+            nested.get_all("/api/v1/users").collect::<Vec<_>>(),
+            vec![&2, &3, &1, &0]
+        );
+        // capture names do not make templates different
+        let mut dup = trie(&["/a/{x}"]);
+        assert!(!dup.insert(&"/a/{y}".parse().unwrap(), 1));
+        assert_eq!(dup.len(), 1);
+        dup.get_or_insert_with(&"/a/{z}".parse().unwrap(), || 2);
+        *dup.get_or_insert_with(&"/b".parse().unwrap(), || 3) += 1;
+        assert_eq!(dup.get("/a/1"), Some(&0));
+        assert_eq!(dup.get("/b"), Some(&4));
+    }
+
+    #[test]
+    fn captures() {
+        let tpl: PathTemplate = "/users/{id}/files/{file}/**".parse().unwrap();
+        assert_eq!(
+            tpl.captures("/users/42/files/a.txt/raw"),
+            Some(vec![
+                ("id".to_string(), "42".to_string()),
+                ("file".to_string(), "a.txt".to_string())
+            ])
+        );
+        assert_eq!(tpl.captures("/users/42"), None);
+        assert!(glob_match("*.tar.*", "a.tar.gz"));
+        assert!(!glob_match("*.png", "a.jpg"));
+        assert!("/a/**/b".parse::<PathTemplate>().is_err());
+        assert!("/a/{}".parse::<PathTemplate>().is_err());
+        assert!("a/b".parse::<PathTemplate>().is_err());
+    }
+}
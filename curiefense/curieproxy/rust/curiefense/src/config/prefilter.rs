@@ -0,0 +1,194 @@
+use aho_corasick::{AhoCorasick, AhoCorasickBuilder};
+use regex_syntax::hir::{Hir, HirKind};
+use regex_syntax::ParserBuilder;
+
+/// literals shorter than this are not selective enough to be useful
+const MIN_LITERAL_LEN: usize = 3;
+
+/// A literal pre-filter, that is used to skip the hyperscan step when none of the
+/// literals required by the rules of a profile are present in the scanned data.
+#[derive(Debug, Clone)]
+pub struct LiteralPrefilter {
+    ac: AhoCorasick,
+    pub literals: Vec<Vec<u8>>,
+}
+
+impl LiteralPrefilter {
+    /// builds a prefilter from a list of patterns
+    ///
+    /// returns None when a pattern does not contain a required literal, as it could then
+    /// match data that the prefilter would reject
+    pub fn build<'t, I: Iterator<Item = &'t str>>(patterns: I) -> Option<Self> {
+        let mut literals = Vec::new();
+        for p in patterns {
+            literals.push(required_literal(p)?);
+        }
+        if literals.is_empty() {
+            return None;
+        }
+        literals.sort();
+        literals.dedup();
+        let ac = AhoCorasickBuilder::new()
+            .ascii_case_insensitive(true)
+            .build(&literals)
+            .ok()?;
+        Some(LiteralPrefilter { ac, literals })
+    }
+
+    /// is it possible that one of the rules matches this input?
+    pub fn may_match(&self, input: &str) -> bool {
+        self.ac.is_match(input)
+    }
+}
+
+/// the exact string matched by the expression, when it is fixed
+fn exact_literal(hir: &Hir) -> Option<Vec<u8>> {
+    match hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => Some(Vec::new()),
+        HirKind::Literal(l) => Some(l.0.to_vec()),
+        HirKind::Capture(c) => exact_literal(&c.sub),
+        HirKind::Repetition(r) if r.max == Some(r.min) => Some(exact_literal(&r.sub)?.repeat(r.min as usize)),
+        HirKind::Concat(subs) => {
+            let mut out = Vec::new();
+            for sub in subs {
+                out.extend(exact_literal(sub)?);
+            }
+            Some(out)
+        }
+        _ => None,
+    }
+}
+
+fn end_run(cur: &mut Vec<u8>, best: &mut Vec<u8>) {
+    if cur.len() > best.len() {
+        *best = cur.clone();
+    }
+    cur.clear();
+}
+
+/// the longest literal that must be present in any string matching the expression, possibly empty
+fn longest_required(hir: &Hir) -> Vec<u8> {
+    if let Some(exact) = exact_literal(hir) {
+        return exact;
+    }
+    match hir.kind() {
+        HirKind::Capture(c) => longest_required(&c.sub),
+        HirKind::Repetition(r) if r.min > 0 => longest_required(&r.sub),
+        HirKind::Concat(subs) => {
+            let mut best = Vec::new();
+            let mut cur = Vec::new();
+            for sub in subs {
+                if let Some(exact) = exact_literal(sub) {
+                    cur.extend(exact);
+                    continue;
+                }
+                match sub.kind() {
+                    // the mandatory repetitions are adjacent to the literals around them
+                    HirKind::Repetition(r) if r.min > 0 => match exact_literal(&r.sub) {
+                        Some(exact) => {
+                            let mandatory = exact.repeat(r.min as usize);
+                            cur.extend(&mandatory);
+                            end_run(&mut cur, &mut best);
+                            cur = mandatory;
+                        }
+                        None => {
+                            end_run(&mut cur, &mut best);
+                            end_run(&mut longest_required(&r.sub), &mut best);
+                        }
+                    },
+                    _ => {
+                        end_run(&mut cur, &mut best);
+                        end_run(&mut longest_required(sub), &mut best);
+                    }
+                }
+            }
+            end_run(&mut cur, &mut best);
+            best
+        }
+        // alternations, classes and optional expressions
+        _ => Vec::new(),
+    }
+}
+
+/// extracts the longest literal that must be present in any string matching the pattern
+///
+/// the pattern is parsed as hyperscan compiles it, matching bytes, and patterns that can't be parsed, such as those
+/// using back-references or \Q..\E quoting, have no required literal
+fn required_literal(pattern: &str) -> Option<Vec<u8>> {
+    let hir = ParserBuilder::new()
+        .unicode(false)
+        .utf8(false)
+        .multi_line(true)
+        .dot_matches_new_line(true)
+        .build()
+        .parse(pattern)
+        .ok()?;
+    let best = longest_required(&hir);
+    if best.len() >= MIN_LITERAL_LEN {
+        Some(best)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lit(s: &str) -> Option<Vec<u8>> {
+        Some(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn simple_literals() {
+        assert_eq!(required_literal("union\\s+select"), lit("select"));
+        assert_eq!(required_literal("<script"), lit("<script"));
+        assert_eq!(required_literal("\\.\\./\\.\\./"), lit("../../"));
+    }
+
+    #[test]
+    fn no_literals() {
+        assert_eq!(required_literal("a|bcdef"), None);
+        assert_eq!(required_literal("(abc|def)"), None);
+        assert_eq!(required_literal("[abc]+"), None);
+        assert_eq!(required_literal("ab"), None);
+    }
+
+    #[test]
+    fn quantifiers() {
+        assert_eq!(required_literal("abcd?ef"), lit("abc"));
+        assert_eq!(required_literal("xyzw+"), lit("xyzw"));
+        assert_eq!(required_literal("abc{2,3}defg"), lit("ccdefg"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(required_literal("\\x3cscript>"), lit("<script>"));
+        assert_eq!(required_literal("\\x{3c}script"), lit("<script"));
+        assert_eq!(required_literal("on\\x65rror="), lit("onerror="));
+        assert_eq!(required_literal("\\xffab"), Some(vec![0xff, b'a', b'b']));
+        assert_eq!(required_literal("\\u{3c}script"), lit("<script"));
+        // hyperscan syntax that is not understood is not prefiltered
+        assert_eq!(required_literal("\\Q<script>\\E"), None);
+        assert_eq!(required_literal("\\cAabcd"), None);
+        assert_eq!(required_literal("\\074script"), None);
+        assert_eq!(required_literal("(abc)\\1def"), None);
+    }
+
+    #[test]
+    fn groups() {
+        assert_eq!(required_literal("(?i)select\\s+from"), None);
+        assert_eq!(required_literal("(?:union)\\s+(all\\s+)?select"), lit("select"));
+        assert_eq!(required_literal("(java)script:"), lit("javascript:"));
+        assert_eq!(required_literal("\\bxyz\\b"), lit("xyz"));
+    }
+
+    #[test]
+    fn prefilter() {
+        let pf = LiteralPrefilter::build(["union\\s+select", "<script"].iter().copied()).unwrap();
+        assert!(pf.may_match("1 UNION SELECT 2"));
+        assert!(pf.may_match("<SCRIPT>"));
+        assert!(!pf.may_match("hello world"));
+        assert!(LiteralPrefilter::build(["union\\s+select", "a|b"].iter().copied()).is_none());
+    }
+}
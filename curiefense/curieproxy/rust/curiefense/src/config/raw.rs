@@ -1,8 +1,15 @@
-use serde::de::{self, Deserializer, SeqAccess, Visitor};
 /// this module contains types that map to the the JSON configuration format of curiefense configuration files
+use chrono::{DateTime, Utc};
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 
+use crate::config::contentfilter::{LexicalAnalysis, Transformation};
+use crate::config::expiry::{expires_soon, is_expired, parse_expiry};
+use crate::config::tagexpr::{is_expression, TagExpression};
+use crate::contentfilter::learning::ValueShape;
+use crate::failure::FailurePolicy;
 use crate::interface::SimpleAction;
 use crate::logs::Logs;
 
@@ -59,6 +66,7 @@ impl Serialize for Repru64 {
 
 /// a mapping of the configuration file for security policy entries
 /// it is called "securitypolicy" in the lua code
+/// its optional `defaults` object is merged into the map entries when loading, see `inherit_policy_defaults`
 #[derive(Debug, Deserialize, Clone)]
 pub struct RawHostMap {
     #[serde(rename = "match")]
@@ -71,6 +79,36 @@ pub struct RawHostMap {
     pub session: Vec<HashMap<String, String>>,
     #[serde(default)]
     pub session_ids: Vec<HashMap<String, String>>,
+    #[serde(default)]
+    pub counters: RawCounterSettings,
+    /// makes this security policy a canary version of another one
+    pub canary: Option<RawCanary>,
+    /// exact, wildcard or regex host rules, see the hostrules module
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// used for the hosts that match no security policy
+    #[serde(default)]
+    pub fallback: bool,
+}
+
+/// canary settings of a security policy
+#[derive(Debug, Deserialize, Clone)]
+pub struct RawCanary {
+    /// id of the primary security policy
+    pub primary: String,
+    /// share of the traffic that is also evaluated with the canary version
+    pub percent: u8,
+    /// ip or session
+    pub split: Option<String>,
+}
+
+/// per security policy settings for the flow and limit counters
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RawCounterSettings {
+    /// namespace added to the counter keys, so that several tenants can share a redis server
+    pub namespace: Option<String>,
+    pub limit_ttl_multiplier: Option<f64>,
+    pub flow_ttl_multiplier: Option<f64>,
 }
 
 /// a mapping of the configuration file for security policies
@@ -86,6 +124,473 @@ pub struct RawSecurityPolicy {
     pub acl_active: bool,
     pub content_filter_active: bool,
     pub limit_ids: Vec<String>,
+    #[serde(default)]
+    pub response_filter_profile: Option<String>,
+    #[serde(default)]
+    pub response_filter_active: bool,
+    #[serde(default)]
+    pub jwt: Option<RawJwtSettings>,
+    #[serde(default)]
+    pub session_tracking: Option<RawSessionTracking>,
+    #[serde(default)]
+    pub decision_cache: Option<RawDecisionCache>,
+    #[serde(default)]
+    pub openapi: Option<RawOpenApi>,
+    #[serde(default)]
+    pub data_leak: Option<RawDataLeakSettings>,
+    #[serde(default)]
+    pub csrf: Option<RawCsrfSettings>,
+    #[serde(default)]
+    pub protocol: Option<RawProtocolSettings>,
+    #[serde(default)]
+    pub websocket: Option<RawWebSocketSettings>,
+    #[serde(default)]
+    pub bot_score: Option<RawBotScore>,
+    #[serde(default)]
+    pub captcha: Option<RawCaptchaSettings>,
+    /// what happens when redis, the challenge provider or hyperscan are unavailable
+    #[serde(default)]
+    pub failure_policy: FailurePolicy,
+    /// profiles compared with the active ones, without enforcement
+    #[serde(default)]
+    pub candidate: Option<RawCandidate>,
+    /// fields and size caps of the access log records
+    #[serde(default)]
+    pub access_log: Option<RawAccessLog>,
+    /// HTTP methods the entry is restricted to, all of them when empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub api_key: Option<RawApiKeySettings>,
+    #[serde(default)]
+    pub honeypot: Option<RawHoneypot>,
+    #[serde(default)]
+    pub offender_tracking: Option<RawOffenderTracking>,
+    #[serde(default)]
+    pub bypass: Option<RawBypass>,
+    #[serde(default)]
+    pub cadence: Option<RawCadence>,
+    #[serde(default)]
+    pub login_protection: Option<RawLoginProtection>,
+    #[serde(default)]
+    pub checkout_protection: Option<RawCheckoutProtection>,
+    #[serde(default)]
+    pub scraping: Option<RawScraping>,
+    #[serde(default)]
+    pub replay: Option<RawReplay>,
+    #[serde(default)]
+    pub signature: Option<RawSignature>,
+}
+
+/// access log record settings of a security policy entry
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RawAccessLog {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    pub headers: Option<Vec<String>>,
+    pub max_field_size: Option<usize>,
+    pub max_record_size: Option<usize>,
+    pub pass_sample_percent: Option<f64>,
+    #[serde(default)]
+    pub always_log_tags: Vec<String>,
+}
+
+/// candidate profiles of a security policy entry, the active profiles are used when not set
+#[derive(Debug, Deserialize, Clone)]
+pub struct RawCandidate {
+    pub acl_profile: Option<String>,
+    pub content_filter_profile: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// protocol anomaly checks of a security policy entry, all checks are enabled by default
+#[derive(Debug, Deserialize, Clone)]
+pub struct RawProtocolSettings {
+    #[serde(default = "default_true")]
+    pub duplicate_content_length: bool,
+    /// transfer-encoding headers that are obfuscated or sent along with a content-length
+    #[serde(default = "default_true")]
+    pub transfer_encoding: bool,
+    /// invalid characters in the method, path or header names
+    #[serde(default = "default_true")]
+    pub request_line: bool,
+    /// NUL bytes in argument names or values
+    #[serde(default = "default_true")]
+    pub nul_bytes: bool,
+    /// path length limit, in bytes
+    #[serde(default)]
+    pub max_path_length: Option<usize>,
+    /// HTTP/2 and HTTP/3 pseudo headers, and forbidden connection specific headers
+    #[serde(default = "default_true")]
+    pub pseudo_headers: bool,
+    /// streams opened per second on the connection, passed by the proxy in the stream-rate attribute
+    #[serde(default)]
+    pub max_stream_rate: Option<f64>,
+    /// streams reset by the client on the connection, passed by the proxy in the stream-resets attribute
+    #[serde(default)]
+    pub max_stream_resets: Option<u64>,
+    /// decoded size of the HTTP/2 and HTTP/3 header lists, in bytes
+    #[serde(default)]
+    pub max_header_list_size: Option<usize>,
+    /// ratio between the decoded and compressed header sizes, the compressed size being passed by the proxy in the
+    /// header-wire-size attribute
+    #[serde(default)]
+    pub max_header_compression_ratio: Option<f64>,
+    /// anomalous requests are rejected, they are only tagged otherwise
+    #[serde(default)]
+    pub enforce: bool,
+}
+
+/// bot score of the requests matching a security policy entry, and the actions taken from it
+///
+/// thresholds are scores between 0 (human) and 100 (automated), the strongest reached threshold is applied
+#[derive(Debug, Deserialize, Clone)]
+pub struct RawBotScore {
+    /// score added to the requests having a given tag, can be negative
+    #[serde(default)]
+    pub tag_weights: HashMap<String, i32>,
+    /// fast and regular request patterns raise the score
+    #[serde(default = "default_true")]
+    pub timing: bool,
+    #[serde(default)]
+    pub monitor: Option<u8>,
+    #[serde(default)]
+    pub challenge: Option<u8>,
+    #[serde(default)]
+    pub block: Option<u8>,
+}
+
+/// inspection of the WebSocket connections opened through a security policy entry
+#[derive(Debug, Deserialize, Clone)]
+pub struct RawWebSocketSettings {
+    /// messages are analyzed with the content filter profile
+    #[serde(default = "default_true")]
+    pub inspect: bool,
+    /// maximum number of messages per connection, during each period
+    #[serde(default)]
+    pub max_messages: Option<u64>,
+    /// in seconds, defaults to 1
+    #[serde(default)]
+    pub period: Option<u64>,
+    /// in bytes
+    #[serde(default)]
+    pub max_message_size: Option<usize>,
+}
+
+/// API key extraction settings of a security policy entry
+#[derive(Debug, Deserialize, Clone)]
+pub struct RawApiKeySettings {
+    /// tried in order, the first key that is found is used
+    pub sources: Vec<RawApiKeySource>,
+}
+
+/// exactly one of header, arg and jwt_claim must be set
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RawApiKeySource {
+    pub header: Option<String>,
+    pub arg: Option<String>,
+    pub jwt_claim: Option<String>,
+    /// header carrying the JWT, defaults to authorization
+    pub jwt_header: Option<String>,
+}
+
+/// CSRF protection settings of a security policy entry
+#[derive(Debug, Deserialize, Clone)]
+pub struct RawCsrfSettings {
+    /// key signing the tokens
+    pub secret: String,
+    /// defaults to cf_csrf
+    pub cookie: Option<String>,
+    /// defaults to x-csrf-token
+    pub header: Option<String>,
+    pub ttl_seconds: Option<u64>,
+    /// methods that must carry a token, defaults to POST, PUT, PATCH and DELETE
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// requests without a valid token are rejected, they are only tagged otherwise
+    #[serde(default)]
+    pub enforce: bool,
+}
+
+/// CAPTCHA challenge of a security policy entry
+#[derive(Debug, Deserialize, Clone)]
+pub struct RawCaptchaSettings {
+    /// hcaptcha, recaptcha or turnstile
+    pub vendor: String,
+    pub site_key: String,
+    pub secret: String,
+    /// alternative replaces the challenge provider, escalation only serves the CAPTCHA to clients that
+    /// already failed the challenge, or when there is no provider
+    pub mode: Option<String>,
+    /// defaults to cf_captcha
+    pub cookie: Option<String>,
+    /// how long a verified token is accepted, defaults to 1800
+    pub ttl_seconds: Option<u64>,
+    /// timeout of the verification call, defaults to 2000
+    pub timeout_ms: Option<u64>,
+}
+
+/// response data leak detection settings of a security policy entry
+#[derive(Debug, Deserialize, Clone)]
+pub struct RawDataLeakSettings {
+    /// when unset, matches are only reported
+    #[serde(default)]
+    pub active: bool,
+    /// ids of the data leak rules to run, all of them when empty
+    #[serde(default)]
+    pub rules: Vec<String>,
+    /// character replacing masked data, defaults to *
+    pub mask_char: Option<char>,
+    /// number of trailing characters left unmasked
+    #[serde(default)]
+    pub keep_last: usize,
+}
+
+/// API contract of a security policy entry, compiled into content filter schemas, see config/openapi.rs
+#[derive(Debug, Deserialize, Clone)]
+pub struct RawOpenApi {
+    /// OpenAPI 3 document, in its JSON form
+    pub document: serde_json::Value,
+    /// arguments that are not in the document are accepted
+    #[serde(default)]
+    pub allow_unknown: bool,
+    /// violations block the request with the content filter profile action
+    #[serde(default)]
+    pub block: bool,
+}
+
+/// bearer token inspection settings of a security policy entry
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RawJwtSettings {
+    /// header carrying the token, defaults to authorization
+    pub header: Option<String>,
+    /// signatures are checked against this key set, a http(s) URL or a local path
+    pub jwks_url: Option<String>,
+    pub jwks_refresh_seconds: Option<u64>,
+    /// accepted issuers and audiences, anything is accepted when empty
+    #[serde(default)]
+    pub issuers: Vec<String>,
+    #[serde(default)]
+    pub audiences: Vec<String>,
+    /// tolerance for the exp and nbf claims
+    #[serde(default)]
+    pub leeway_seconds: u64,
+    /// requests without a token are rejected
+    #[serde(default)]
+    pub required: bool,
+    /// problems only produce tags unless set, in which case the request is blocked
+    #[serde(default)]
+    pub enforce: bool,
+}
+
+/// session tracking settings of a security policy entry
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RawSessionTracking {
+    /// where the session identifier is taken from, the first available one is used:
+    /// "cookie:<name>", "header:<name>", "jwt-sub" or "ip-ua"
+    #[serde(default)]
+    pub sources: Vec<String>,
+    /// lifetime of an idle session
+    pub ttl_seconds: Option<u64>,
+    /// tags that are remembered for the session, and their maximum number
+    #[serde(default)]
+    pub tracked_tags: Vec<String>,
+    pub max_recent_tags: Option<usize>,
+    /// thresholds producing the session-age-over-*, session-requests-over-* and session-cf-hits-over-* tags
+    #[serde(default)]
+    pub age_thresholds: Vec<u64>,
+    #[serde(default)]
+    pub request_thresholds: Vec<u64>,
+    #[serde(default)]
+    pub cf_hit_thresholds: Vec<u64>,
+}
+
+/// decoy paths and form fields of a security policy entry, see the honeypot module
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RawHoneypot {
+    /// path templates, such as /wp-admin/**
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// hidden form fields, that trigger when they are not empty
+    #[serde(default)]
+    pub fields: Vec<String>,
+    /// how long trapped clients stay marked
+    pub ttl_seconds: Option<u64>,
+}
+
+/// decaying offender scores of a security policy entry, see the offender module
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RawOffenderTracking {
+    pub half_life_seconds: Option<u64>,
+    /// score added by blocking decisions, and by monitored decisions with reasons
+    pub block_weight: Option<f64>,
+    pub suspicious_weight: Option<f64>,
+    /// lowest scores of the offender:low, offender:medium and offender:high buckets
+    pub low: Option<f64>,
+    pub medium: Option<f64>,
+    pub high: Option<f64>,
+}
+
+/// credential stuffing detection of a security policy entry, see the login module
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RawLoginProtection {
+    /// path templates of the login endpoints
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// defaults to POST
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// defaults to username
+    pub username_field: Option<String>,
+    /// defaults to password
+    pub password_field: Option<String>,
+    pub window_seconds: Option<u64>,
+    pub max_usernames_per_ip: Option<u64>,
+    pub max_ips_per_username: Option<u64>,
+    pub max_usernames_per_password: Option<u64>,
+    /// monitor, challenge (default), ichallenge or custom (block)
+    pub action: Option<RawActionType>,
+}
+
+/// card testing detection of a security policy entry, see the checkout module
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RawCheckoutProtection {
+    /// path templates of the payment endpoints
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// defaults to POST
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// defaults to card_number
+    pub card_field: Option<String>,
+    /// defaults to 6
+    pub bin_length: Option<usize>,
+    pub window_seconds: Option<u64>,
+    pub max_cards_per_ip: Option<u64>,
+    pub max_cards_per_session: Option<u64>,
+    pub max_cards_per_bin: Option<u64>,
+    /// monitor, challenge (default), ichallenge or custom (block)
+    pub action: Option<RawActionType>,
+}
+
+/// HMAC request signatures of a security policy entry, see the signature module
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RawSignature {
+    /// path templates of the signed endpoints, all the paths of the entry when empty
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// defaults to x-signature
+    pub header: Option<String>,
+    /// stripped from the header value, such as sha256=
+    pub prefix: Option<String>,
+    /// sha256 (default), sha384 or sha512
+    pub algorithm: Option<String>,
+    /// hex (default) or base64
+    pub encoding: Option<String>,
+    pub secret: Option<String>,
+    /// environment variable holding the secret, when not set
+    pub secret_env: Option<String>,
+    /// header holding the unix timestamp, that is then signed along with the body
+    pub timestamp_header: Option<String>,
+    pub tolerance_seconds: Option<u64>,
+    /// requests with an invalid signature are rejected, they are only tagged otherwise
+    #[serde(default)]
+    pub enforce: bool,
+}
+
+/// replay protection of a security policy entry, see the replay module
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RawReplay {
+    /// path templates of the protected endpoints, all the paths of the entry when empty
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// defaults to POST, PUT, PATCH and DELETE
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// headers carrying the nonce or signature, defaults to x-nonce
+    #[serde(default)]
+    pub headers: Vec<String>,
+    /// how long nonces are remembered, also bounds the tolerance of the signed timestamp
+    pub ttl_seconds: Option<u64>,
+    /// requests without a nonce are accepted
+    #[serde(default)]
+    pub allow_missing: bool,
+    /// replayed requests are rejected, they are only tagged otherwise
+    #[serde(default)]
+    pub enforce: bool,
+}
+
+/// scraping detection of a security policy entry, see the scraping module
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RawScraping {
+    pub window_seconds: Option<u64>,
+    /// requests required before computing the signals
+    pub min_requests: Option<u64>,
+    /// ratio of distinct paths to requests
+    pub max_path_diversity: Option<f64>,
+    pub max_pagination_depth: Option<u64>,
+    /// ratio of static resources to requests
+    pub min_static_ratio: Option<f64>,
+    /// signals required for the scraper:likely tag
+    pub min_signals: Option<usize>,
+    /// arguments holding page numbers, page and p when empty
+    #[serde(default)]
+    pub page_args: Vec<String>,
+    /// extensions of the static resources, common assets when empty
+    #[serde(default)]
+    pub static_extensions: Vec<String>,
+}
+
+/// inter-arrival tracking of a security policy entry, see the cadence module
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RawCadence {
+    /// selectors of the tracked key, the client IP when empty
+    #[serde(default)]
+    pub keys: Vec<HashMap<String, String>>,
+    /// weight of the last interval in the moving averages, between 0 and 1
+    pub smoothing: Option<f64>,
+    /// intervals required before tagging
+    pub min_samples: Option<u64>,
+    /// periodicity score of the cadence:machine-like tag
+    pub machine_periodicity: Option<f64>,
+    /// burstiness of the cadence:bursty tag
+    pub bursty: Option<f64>,
+    pub ttl_seconds: Option<u64>,
+}
+
+/// verified bypass of a security policy entry, see the bypass module
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RawBypass {
+    /// IP addresses and networks
+    #[serde(default)]
+    pub networks: Vec<String>,
+    /// client certificate fingerprints
+    #[serde(default)]
+    pub fingerprints: Vec<String>,
+    /// key signing the bypass header
+    pub secret: Option<String>,
+    /// defaults to x-curiefense-bypass
+    pub header: Option<String>,
+    pub max_age_seconds: Option<u64>,
+    /// bypassed requests are not logged by default
+    #[serde(default)]
+    pub log: bool,
+}
+
+/// blocking decision cache settings of a security policy entry
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RawDecisionCache {
+    pub ttl_seconds: Option<u64>,
+    pub max_entries: Option<usize>,
+    /// decisions are cached when they are triggered by one of these: "acl", "content_filter",
+    /// "global_filter", "restriction" or "schema", defaults to acl, content_filter and restriction
+    #[serde(default)]
+    pub kinds: Vec<String>,
 }
 
 #[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
@@ -95,6 +600,22 @@ pub enum Relation {
     Or,
 }
 
+/// a WASM plugin, from wasm-plugins.json
+#[derive(Debug, Deserialize, Clone)]
+pub struct RawWasmPlugin {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub active: bool,
+    /// path to the module, either binary or text format
+    pub path: String,
+    /// phases the plugin is called in: init, pre_acl, pre_contentfilter and finish
+    pub phases: Vec<String>,
+    /// execution budget of a single call, in wasmtime fuel units
+    pub fuel: Option<u64>,
+    pub max_memory: Option<usize>,
+}
+
 /// this is partial, as a ton of data is not needed
 #[derive(Debug, Deserialize, Clone)]
 pub struct RawGlobalFilterSection {
@@ -104,6 +625,148 @@ pub struct RawGlobalFilterSection {
     pub tags: Vec<String>,
     pub rule: RawGlobalFilterRule,
     pub action: Option<String>,
+    /// the section only applies during this window
+    #[serde(default)]
+    pub window: Option<RawTimeWindow>,
+    /// RFC 3339 date or unix timestamp, after which the section is ignored
+    #[serde(default)]
+    pub expires: Option<String>,
+}
+
+/// when a global filter section or a limit applies, see the timewindow module
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RawTimeWindow {
+    /// RFC 3339 date
+    #[serde(default)]
+    pub start: Option<String>,
+    #[serde(default)]
+    pub end: Option<String>,
+    /// cron-like schedule, such as "* 9-17 * * mon-fri"
+    #[serde(default)]
+    pub schedule: Option<String>,
+    /// fixed offset, such as +02:00
+    #[serde(default)]
+    pub timezone: Option<String>,
+}
+
+/// an IP reputation list, from reputation-feeds.json
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawReputationFeed {
+    pub id: String,
+    pub name: String,
+    pub active: bool,
+    /// requests from listed addresses are tagged with reputation:category
+    pub category: String,
+    /// a file path, an http(s) URL, or redis:key for a Redis set
+    pub source: String,
+    pub refresh_seconds: Option<u64>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// the challenge provider, from challenge.json
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawChallengeSettings {
+    /// grasshopper, pow for the native proof-of-work challenge, http for a sidecar, or none
+    pub provider: String,
+    /// key signing the proof-of-work puzzles
+    #[serde(default)]
+    pub secret: String,
+    /// number of leading zero bits of the solution hash, defaults to 16
+    pub difficulty: Option<u8>,
+    pub ttl_seconds: Option<u64>,
+    /// defaults to cf_pow
+    pub cookie: Option<String>,
+    /// base URL of the sidecar, on the loopback interface
+    pub url: Option<String>,
+    /// timeout of the grasshopper and sidecar calls, defaults to 500, provider failures are handled according to
+    /// the failure policy of the security policy
+    pub timeout_ms: Option<u64>,
+    /// cookies remembering clients recognized as humans
+    pub clearance: Option<RawClearanceSettings>,
+}
+
+/// the clearance cookies, from challenge.json
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawClearanceSettings {
+    /// key signing the clearance cookies
+    #[serde(default)]
+    pub secret: String,
+    /// defaults to cf_clearance
+    pub cookie: Option<String>,
+    /// defaults to 1800
+    pub ttl_seconds: Option<u64>,
+}
+
+/// the geographic lookup provider, from geo.json
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawGeoSettings {
+    /// maxmind, ipinfo, ip2location or cidr
+    pub provider: String,
+    /// the IP2Location CSV database, or the CIDR to country CSV file
+    pub path: Option<String>,
+}
+
+/// an outbound webhook, from webhooks.json, see the webhook module
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RawWebhook {
+    pub id: String,
+    pub url: String,
+    /// signs the batches with HMAC-SHA256
+    pub secret: Option<String>,
+    /// environment variable holding the secret, when not set
+    pub secret_env: Option<String>,
+    /// only the requests with one of these tags are notified, all blocking decisions when empty
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub batch_size: Option<usize>,
+    pub flush_seconds: Option<u64>,
+    pub retries: Option<u32>,
+    pub timeout_seconds: Option<u64>,
+    /// events queued before the oldest ones are dropped
+    pub queue_size: Option<usize>,
+}
+
+/// the client address resolution, from trusted-proxies.json
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawClientIpSettings {
+    /// addresses or networks of the proxies in front of curiefense
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// rightmost_untrusted, leftmost or hop_count
+    pub strategy: String,
+    /// number of proxies, for the hop_count strategy, defaults to 1
+    pub hops: Option<usize>,
+}
+
+/// a known TLS client fingerprint, from tls-fingerprints.json
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawTlsFingerprint {
+    /// JA3 hash or JA4 fingerprint
+    pub fingerprint: String,
+    pub name: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub action: Option<String>,
+}
+
+/// a named list of IPs and networks, from ip-lists.json
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawIpList {
+    pub id: String,
+    pub name: String,
+    pub entries: Vec<String>,
+}
+
+/// a known HTTP client fingerprint, from http-fingerprints.json
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawHttpFingerprint {
+    /// as found in the http-fp tag
+    pub fingerprint: String,
+    /// the client name, such as curl or python-requests
+    pub name: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -137,9 +800,21 @@ pub enum GlobalFilterEntryType {
     Ip,
     Company,
     Authority,
+    TlsFingerprint,
     Tag,
     SecurityPolicyId,
     SecurityPolicyEntryId,
+    /// [name regex, value regex], matched against all the headers
+    HeaderRegex,
+    ArgExists,
+    CookieExists,
+    /// reference to a list from ip-lists.json
+    IpList,
+    ApiKey,
+    /// distinguished name of the mutual TLS client certificate
+    ClientCertSubject,
+    /// matched against all the subject alternative names of the client certificate
+    ClientCertSan,
 }
 
 /// a special datatype for deserializing tuples with 2 elements, and optional extra elements
@@ -201,6 +876,19 @@ pub struct RawLimit {
     pub active: bool,
     #[serde(default)]
     pub tags: Vec<String>,
+    /// one of fixed-window (default), sliding-log, sliding-counter, token-bucket, in-flight, daily-quota or
+    /// monthly-quota
+    #[serde(default)]
+    pub algorithm: Option<String>,
+    /// extra requests a token bucket accepts on top of the threshold
+    #[serde(default)]
+    pub burst: Option<Repru64>,
+    /// what is counted, requests (default) or body-bytes
+    #[serde(default)]
+    pub unit: Option<String>,
+    /// the limit only applies during this window
+    #[serde(default)]
+    pub window: Option<RawTimeWindow>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -240,11 +928,18 @@ pub enum RawActionType {
     Custom,
     Challenge,
     Ichallenge,
+    Redirect,
+    Tarpit,
+    RateLimitHeaders,
+    Transform,
 }
 
 impl RawActionType {
     pub fn is_final(&self) -> bool {
-        *self != RawActionType::Monitor
+        !matches!(
+            self,
+            RawActionType::Monitor | RawActionType::RateLimitHeaders | RawActionType::Transform
+        )
     }
 
     pub fn inactive(&mut self) {
@@ -264,6 +959,32 @@ pub struct RawActionParams {
     #[serde(default)]
     pub headers: Option<HashMap<String, String>>,
     pub content: Option<String>,
+    /// id of a response template, replacing the content
+    pub template: Option<String>,
+    /// target of redirections, can refer to the request attributes
+    pub location: Option<String>,
+    /// tarpit delay, in milliseconds
+    pub delay: Option<u64>,
+    /// headers removed from forwarded requests
+    #[serde(default)]
+    pub remove_headers: Vec<String>,
+    /// new path of forwarded requests, can refer to the request attributes
+    pub path: Option<String>,
+    /// routing hint for the proxy, can refer to the request attributes
+    pub upstream: Option<String>,
+    /// seconds the client address is banned for, when the decision is blocking
+    pub ban: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawResponseTemplate {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    pub html: Option<String>,
+    pub json: Option<String>,
+    #[serde(default)]
+    pub support: String,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -279,6 +1000,9 @@ pub struct RawAclProfile {
     pub action: Option<String>,
     #[serde(default)]
     pub tags: Vec<String>,
+    /// expiry dates of entries, by entry tag
+    #[serde(default)]
+    pub expires: HashMap<String, String>,
 }
 
 #[derive(Debug, Clone)]
@@ -293,6 +1017,47 @@ pub struct AclProfile {
     pub force_deny: HashSet<String>,
     pub action: SimpleAction,
     pub tags: HashSet<String>,
+    pub expires: HashMap<String, DateTime<Utc>>,
+    /// entries that are tag expressions, and not single tags
+    pub expressions: AclExpressions,
+}
+
+/// tag expressions of an ACL profile, by column
+#[derive(Debug, Clone, Default)]
+pub struct AclExpressions {
+    pub allow: Vec<TagExpression>,
+    pub allow_bot: Vec<TagExpression>,
+    pub deny: Vec<TagExpression>,
+    pub deny_bot: Vec<TagExpression>,
+    pub passthrough: Vec<TagExpression>,
+    pub force_deny: Vec<TagExpression>,
+}
+
+impl AclExpressions {
+    fn columns_mut(&mut self) -> [&mut Vec<TagExpression>; 6] {
+        [
+            &mut self.allow,
+            &mut self.allow_bot,
+            &mut self.deny,
+            &mut self.deny_bot,
+            &mut self.passthrough,
+            &mut self.force_deny,
+        ]
+    }
+}
+
+/// moves the tag expressions out of an ACL column
+fn split_expressions(logs: &mut Logs, id: &str, column: &mut HashSet<String>) -> Vec<TagExpression> {
+    let sources: Vec<String> = column.iter().filter(|e| is_expression(e)).cloned().collect();
+    let mut out = Vec::new();
+    for source in sources {
+        column.remove(&source);
+        match source.parse() {
+            Ok(e) => out.push(e),
+            Err(rr) => logs.error(|| format!("in acl profile {}, invalid expression {}: {}", id, source, rr)),
+        }
+    }
+    out
 }
 
 impl Default for AclProfile {
@@ -308,6 +1073,8 @@ impl Default for AclProfile {
             force_deny: HashSet::new(),
             action: SimpleAction::default(),
             tags: HashSet::new(),
+            expires: HashMap::new(),
+            expressions: AclExpressions::default(),
         }
     }
 }
@@ -325,19 +1092,77 @@ impl AclProfile {
                 SimpleAction::default()
             }),
         };
+        let mut expires = HashMap::new();
+        for (entry, date) in acl.expires {
+            match parse_expiry(&date) {
+                Ok(d) => {
+                    expires.insert(entry, d);
+                }
+                Err(rr) => logs.error(|| format!("in acl profile {}, entry {}: {}", id, entry, rr)),
+            }
+        }
+        let mut allow = acl.allow;
+        let mut allow_bot = acl.allow_bot;
+        let mut deny = acl.deny;
+        let mut deny_bot = acl.deny_bot;
+        let mut passthrough = acl.passthrough;
+        let mut force_deny = acl.force_deny;
+        let expressions = AclExpressions {
+            allow: split_expressions(logs, &id, &mut allow),
+            allow_bot: split_expressions(logs, &id, &mut allow_bot),
+            deny: split_expressions(logs, &id, &mut deny),
+            deny_bot: split_expressions(logs, &id, &mut deny_bot),
+            passthrough: split_expressions(logs, &id, &mut passthrough),
+            force_deny: split_expressions(logs, &id, &mut force_deny),
+        };
         AclProfile {
             id,
             name: acl.name,
-            allow: acl.allow,
-            allow_bot: acl.allow_bot,
-            deny: acl.deny,
-            deny_bot: acl.deny_bot,
-            passthrough: acl.passthrough,
-            force_deny: acl.force_deny,
+            allow,
+            allow_bot,
+            deny,
+            deny_bot,
+            passthrough,
+            force_deny,
             action,
             tags: acl.tags.into_iter().collect(),
+            expires,
+            expressions,
         }
     }
+
+    /// the profile without its entries expired at this date
+    pub fn active_at(&self, now: DateTime<Utc>) -> Cow<'_, AclProfile> {
+        if !self.expires.values().any(|e| is_expired(Some(*e), now)) {
+            return Cow::Borrowed(self);
+        }
+        let mut out = self.clone();
+        for (entry, e) in &self.expires {
+            if is_expired(Some(*e), now) {
+                for set in [
+                    &mut out.allow,
+                    &mut out.allow_bot,
+                    &mut out.deny,
+                    &mut out.deny_bot,
+                    &mut out.passthrough,
+                    &mut out.force_deny,
+                ] {
+                    set.remove(entry);
+                }
+                for column in out.expressions.columns_mut() {
+                    column.retain(|e| &e.source != entry);
+                }
+            }
+        }
+        Cow::Owned(out)
+    }
+
+    /// the matched entries expire soon
+    pub fn expires_soon<'a, I: IntoIterator<Item = &'a String>>(&self, entries: I, now: DateTime<Utc>) -> bool {
+        entries
+            .into_iter()
+            .any(|t| expires_soon(self.expires.get(t).copied(), now))
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -347,16 +1172,23 @@ pub enum ContentType {
     UrlEncoded,    // application/x-www-form-urlencoded
     Json,
     Xml,
-    Graphql, // application/graphql
+    Graphql,  // application/graphql
+    Protobuf, // application/grpc, application/x-protobuf
+    #[serde(alias = "msgpack")]
+    MessagePack, // application/msgpack, application/vnd.msgpack
+    Cbor,     // application/cbor
 }
 
 impl ContentType {
-    pub const VALUES: [ContentType; 5] = [
+    pub const VALUES: [ContentType; 8] = [
         ContentType::Json,
         ContentType::MultipartForm,
         ContentType::UrlEncoded,
         ContentType::Xml,
         ContentType::Graphql,
+        ContentType::Protobuf,
+        ContentType::MessagePack,
+        ContentType::Cbor,
     ];
 }
 
@@ -388,12 +1220,164 @@ pub struct RawContentFilterProfile {
     #[serde(default)]
     pub ignore_body: bool,
     pub max_body_size: Option<usize>,
+    /// bodies larger than this are not decoded, only the request metadata is analyzed
+    #[serde(default)]
+    pub max_body_scan_size: Option<usize>,
     pub max_body_depth: Option<usize>,
+    /// maximum number of fields in a graphql document
+    #[serde(default)]
+    pub max_graphql_complexity: Option<usize>,
+    /// memory budget, in bytes, for the data extracted from a request during analysis
+    #[serde(default)]
+    pub max_request_memory: Option<usize>,
+    /// base64 encoded protobuf FileDescriptorSet, used to name the fields of protobuf and gRPC bodies
+    #[serde(default)]
+    pub protobuf_descriptors: Option<String>,
+    /// message type of the protobuf (non gRPC) bodies
+    #[serde(default)]
+    pub protobuf_message: Option<String>,
+    /// maximum size of files uploaded with multipart forms
+    #[serde(default)]
+    pub max_upload_size: Option<usize>,
+    /// accepted MIME types for uploaded files, such as "image/*", all types are accepted when empty
+    #[serde(default)]
+    pub allowed_upload_types: Vec<String>,
+    /// the content of the uploaded files is not scanned with the content filter rules
+    #[serde(default)]
+    pub skip_upload_contents: bool,
     #[serde(default)]
     pub referer_as_uri: bool,
+    /// cookie, argument and plugin names are matched case insensitively
+    #[serde(default)]
+    pub case_insensitive_names: bool,
     pub action: Option<String>,
     #[serde(default)]
     pub tags: Vec<String>,
+    /// "enforce" (default), "shadow" or "learning"
+    #[serde(default)]
+    pub mode: Option<String>,
+    /// expected arguments, by path
+    #[serde(default)]
+    pub schema: Vec<RawPathSchema>,
+    /// when set, the scores of the matched rules are summed, and the request is only blocked when the total
+    /// reaches this threshold
+    #[serde(default)]
+    pub anomaly_threshold: Option<u32>,
+    /// content filter rules that are not applied to some paths or arguments
+    #[serde(default)]
+    pub rule_exclusions: Vec<RawRuleExclusion>,
+    /// native SQLi and XSS analyzers, run along with the signatures
+    #[serde(default)]
+    pub lexical: LexicalAnalysis,
+    /// hard limits, checked before any other analysis
+    #[serde(default)]
+    pub limits: RawArgumentLimits,
+    /// the raw body is scanned as its chunks are received, and only buffered up to max_body_scan_size
+    #[serde(default)]
+    pub stream_body: bool,
+    /// classes of sensitive values, and how they are masked
+    #[serde(default)]
+    pub masking: RawMaskingPolicy,
+}
+
+/// masking policy of a content filter profile
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RawMaskingPolicy {
+    /// custom classes, in addition to the email, phone and credit_card built-in classes
+    #[serde(default)]
+    pub classes: Vec<RawMaskingClass>,
+    #[serde(default)]
+    pub rules: Vec<RawMaskingRule>,
+    /// salt of the hash mode, the masking seed when not set
+    #[serde(default)]
+    pub salt: Option<String>,
+    /// masked values are replaced by HMAC pseudonyms
+    #[serde(default)]
+    pub pseudonymize: bool,
+    #[serde(default)]
+    pub pseudonym_key: Option<String>,
+    /// environment variable holding the key, when pseudonym_key is not set
+    #[serde(default)]
+    pub pseudonym_key_env: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RawMaskingClass {
+    pub name: String,
+    pub regex: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RawMaskingRule {
+    /// args, headers, cookies or client_cert, all sections when empty
+    #[serde(default)]
+    pub sections: Vec<String>,
+    /// name of the field, all fields when not set
+    #[serde(default)]
+    pub name: Option<String>,
+    /// classes of values to mask, the whole value is masked when empty
+    #[serde(default)]
+    pub classes: Vec<String>,
+    /// full (default), partial or hash
+    #[serde(default)]
+    pub mode: Option<String>,
+}
+
+/// request wide limits, unlimited when not set or set to 0
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RawArgumentLimits {
+    /// number of arguments, from the query string and the body
+    #[serde(default)]
+    pub max_args: Option<usize>,
+    #[serde(default)]
+    pub max_headers: Option<usize>,
+    /// length of any argument, header, cookie or path part value
+    #[serde(default)]
+    pub max_value_length: Option<usize>,
+    /// total size of the values extracted from the body, after decoding
+    #[serde(default)]
+    pub max_decoded_body_size: Option<usize>,
+}
+
+/// suppresses the matches of a content filter rule, all paths and arguments are excluded when not set
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RawRuleExclusion {
+    pub rule: String,
+    /// path glob, "*" matches inside a path segment and "**" across segments
+    #[serde(default)]
+    pub path: Option<String>,
+    /// name of the argument, header, cookie or plugin entry
+    #[serde(default)]
+    pub arg: Option<String>,
+}
+
+/// the expected arguments of the paths matching a regex, the first matching schema is used
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RawPathSchema {
+    pub path: String,
+    /// the schema only applies to these methods, or to all of them when empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub params: Vec<RawParamSchema>,
+    /// arguments that are not listed are accepted
+    #[serde(default)]
+    pub allow_unknown: bool,
+    /// violations block the request with the profile action, they are only reported otherwise
+    #[serde(default)]
+    pub block: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RawParamSchema {
+    /// regex matching the whole argument name
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub shape: Option<ValueShape>,
+    pub min_length: Option<usize>,
+    pub max_length: Option<usize>,
+    #[serde(default)]
+    pub required: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -424,7 +1408,7 @@ pub struct RawContentFilterProperties {
     pub max_length: MaxLength,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct ContentFilterDecoding {
     #[serde(default)]
     pub base64: bool,
@@ -434,6 +1418,17 @@ pub struct ContentFilterDecoding {
     pub html: bool,
     #[serde(default)]
     pub unicode: bool,
+    #[serde(default)]
+    pub double_url: bool,
+    /// base64 encoded gzip data
+    #[serde(default)]
+    pub gzip: bool,
+    /// explicit decoding order, replaces the flags above when not empty
+    #[serde(default)]
+    pub chain: Vec<Transformation>,
+    /// the chain is applied again on decoded values, up to this number of times
+    #[serde(default)]
+    pub max_depth: Option<usize>,
 }
 
 impl Default for ContentFilterDecoding {
@@ -443,6 +1438,10 @@ impl Default for ContentFilterDecoding {
             dual: true,
             html: false,
             unicode: false,
+            double_url: false,
+            gzip: false,
+            chain: Vec::new(),
+            max_depth: None,
         }
     }
 }
@@ -466,6 +1465,58 @@ pub struct RawContentFilterRule {
     pub subcategory: String,
     #[serde(default)]
     pub tags: HashSet<String>,
+    /// anomaly score added by a match, defaults to the risk level
+    #[serde(default)]
+    pub score: Option<u32>,
+}
+
+/// response filter profiles, used when inspecting the upstream server responses
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawResponseFilterProfile {
+    pub id: String,
+    pub name: String,
+    /// status codes that should not be sent back to the client
+    #[serde(default)]
+    pub status: Vec<u32>,
+    #[serde(default)]
+    pub headers: RawContentFilterProperties,
+    #[serde(default)]
+    pub signatures: Vec<RawResponseSignature>,
+    #[serde(default)]
+    pub masking_seed: String,
+    pub max_body_size: Option<usize>,
+    pub action: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawResponseSignature {
+    pub id: String,
+    pub operand: String,
+    pub risk: u8,
+    /// when set, matching data is masked instead of blocking the response
+    #[serde(default)]
+    pub mask: bool,
+}
+
+/// a data leak rule, from dataleak-rules.json
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RawDataLeakRule {
+    pub id: String,
+    pub name: String,
+    /// "credit_card", "ssn", "api_key" or "regex"
+    pub kind: String,
+    /// the pattern of regex rules
+    #[serde(default)]
+    pub operand: Option<String>,
+    pub risk: u8,
+    /// when set, matching data is masked, otherwise the response is blocked with the action
+    #[serde(default)]
+    pub mask: bool,
+    pub action: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -480,12 +1531,24 @@ pub struct RawFlowEntry {
     pub timeframe: u64,
     pub tags: Vec<String>,
     pub sequence: Vec<RawFlowStep>,
+    /// maximum time between two consecutive steps, in seconds
+    #[serde(default)]
+    pub step_ttl: Option<u64>,
+    /// maximum time between the first and the last step, in seconds
+    #[serde(default)]
+    pub max_duration: Option<u64>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct RawFlowStep {
+    /// steps without a method, host header or uri match any value, and are tested on every request
+    #[serde(default)]
     pub method: String,
+    #[serde(default)]
     pub uri: String,
+    /// tags the request must have to match the step
+    #[serde(default)]
+    pub tags: Vec<String>,
     #[serde(default)]
     pub cookies: HashMap<String, String>,
     #[serde(default)]
@@ -0,0 +1,98 @@
+use crate::config::contentfilter::{mk_section, ContentFilterSection};
+use crate::config::raw::{RawContentFilterProperties, RawResponseFilterProfile, RawResponseSignature};
+use crate::interface::SimpleAction;
+use crate::logs::Logs;
+
+use regex::bytes::{Regex, RegexBuilder};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone)]
+pub struct ResponseFilterProfile {
+    pub id: String,
+    pub name: String,
+    pub status: HashSet<u32>,
+    pub headers: ContentFilterSection,
+    pub signatures: Vec<ResponseSignature>,
+    pub masking_seed: Vec<u8>,
+    pub max_body_size: usize,
+    pub action: SimpleAction,
+    pub tags: HashSet<String>,
+}
+
+/// a body signature, matched on the raw bytes of the response
+#[derive(Debug, Clone)]
+pub struct ResponseSignature {
+    pub id: String,
+    pub risk: u8,
+    pub mask: bool,
+    pub re: Regex,
+}
+
+fn convert_signature(sig: RawResponseSignature) -> anyhow::Result<ResponseSignature> {
+    let re = RegexBuilder::new(&sig.operand)
+        .case_insensitive(true)
+        .build()
+        .map_err(|rr| anyhow::anyhow!("signature {}, pattern {:?}: {}", sig.id, sig.operand, rr))?;
+    Ok(ResponseSignature {
+        id: sig.id,
+        risk: sig.risk,
+        mask: sig.mask,
+        re,
+    })
+}
+
+fn convert_entry(
+    logs: &mut Logs,
+    actions: &HashMap<String, SimpleAction>,
+    entry: RawResponseFilterProfile,
+) -> anyhow::Result<ResponseFilterProfile> {
+    let id = entry.id;
+    let action = match entry.action {
+        None => SimpleAction::default(),
+        Some(aid) => actions.get(&aid).cloned().unwrap_or_else(|| {
+            logs.error(|| {
+                format!(
+                    "Could not resolve action {} when resolving response filter entry {}",
+                    aid, id
+                )
+            });
+            SimpleAction::default()
+        }),
+    };
+    let signatures = entry
+        .signatures
+        .into_iter()
+        .map(convert_signature)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(ResponseFilterProfile {
+        id,
+        name: entry.name,
+        status: entry.status.into_iter().collect(),
+        headers: mk_section(&RawContentFilterProperties::default(), entry.headers, true)?,
+        signatures,
+        masking_seed: entry.masking_seed.into_bytes(),
+        max_body_size: entry.max_body_size.filter(|&s| s > 0).unwrap_or(usize::MAX),
+        action,
+        tags: entry.tags.into_iter().collect(),
+    })
+}
+
+impl ResponseFilterProfile {
+    pub fn resolve(
+        logs: &mut Logs,
+        actions: &HashMap<String, SimpleAction>,
+        raw: Vec<RawResponseFilterProfile>,
+    ) -> HashMap<String, ResponseFilterProfile> {
+        let mut out = HashMap::new();
+        for rp in raw {
+            let id = rp.id.clone();
+            match convert_entry(logs, actions, rp) {
+                Ok(p) => {
+                    out.insert(id, p);
+                }
+                Err(rr) => logs.error(|| format!("response filter id {}: {:?}", id, rr)),
+            }
+        }
+        out
+    }
+}
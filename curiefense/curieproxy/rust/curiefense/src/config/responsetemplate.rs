@@ -0,0 +1,144 @@
+//! Response templates, used by the blocking actions instead of a fixed content.
+//!
+//! A template has an HTML and a JSON variant, the one that is served is chosen with the `Accept` header of the
+//! request. The templates can refer to the request attributes and tags, the request id, the block reasons, and
+//! a support contact.
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::config::raw::RawResponseTemplate;
+use crate::logs::Logs;
+use crate::utils::templating::{parse_response_template, ResponseBodyTemplate};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Html,
+    Json,
+}
+
+impl ResponseFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ResponseFormat::Html => "text/html; charset=utf-8",
+            ResponseFormat::Json => "application/json",
+        }
+    }
+
+    /// the preferred format according to an Accept header, HTML when there is no preference
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        match accept {
+            Some(a) if quality(a, "application/json") > quality(a, "text/html") => ResponseFormat::Json,
+            _ => ResponseFormat::Html,
+        }
+    }
+}
+
+/// quality of a media type in an Accept header, the most specific matching range wins
+fn quality(accept: &str, mtype: &str) -> f32 {
+    let wildcard = format!("{}/*", mtype.split('/').next().unwrap_or(mtype));
+    let mut best: Option<(u8, f32)> = None;
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let media = params.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let specificity = if media == mtype {
+            2
+        } else if media == wildcard {
+            1
+        } else if media == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        let q = params
+            .filter_map(|p| p.split_once('='))
+            .find(|(k, _)| k.trim() == "q")
+            .and_then(|(_, v)| v.trim().parse().ok())
+            .unwrap_or(1.0);
+        if best.map(|(s, _)| specificity > s).unwrap_or(true) {
+            best = Some((specificity, q));
+        }
+    }
+    best.map(|(_, q)| q).unwrap_or(0.0)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseTemplate {
+    pub id: String,
+    pub html: Option<ResponseBodyTemplate>,
+    pub json: Option<ResponseBodyTemplate>,
+    pub support: String,
+}
+
+impl ResponseTemplate {
+    pub fn resolve(logs: &mut Logs, rawtemplates: Vec<RawResponseTemplate>) -> HashMap<String, Arc<Self>> {
+        let mut out = HashMap::new();
+        for raw in rawtemplates {
+            if raw.html.is_none() && raw.json.is_none() {
+                logs.error(|| format!("Response template {} has neither an html nor a json body", raw.id));
+                continue;
+            }
+            let template = ResponseTemplate {
+                id: raw.id.clone(),
+                html: raw.html.as_deref().map(parse_response_template),
+                json: raw.json.as_deref().map(parse_response_template),
+                support: raw.support,
+            };
+            out.insert(raw.id, Arc::new(template));
+        }
+        out
+    }
+
+    /// the body matching the Accept header, or the only one that is defined
+    pub fn select(&self, accept: Option<&str>) -> Option<(ResponseFormat, &ResponseBodyTemplate)> {
+        let html = self.html.as_ref().map(|t| (ResponseFormat::Html, t));
+        let json = self.json.as_ref().map(|t| (ResponseFormat::Json, t));
+        match ResponseFormat::negotiate(accept) {
+            ResponseFormat::Html => html.or(json),
+            ResponseFormat::Json => json.or(html),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation() {
+        use ResponseFormat::*;
+        assert_eq!(ResponseFormat::negotiate(None), Html);
+        assert_eq!(ResponseFormat::negotiate(Some("application/json")), Json);
+        assert_eq!(ResponseFormat::negotiate(Some("text/html,application/json")), Html);
+        assert_eq!(
+            ResponseFormat::negotiate(Some("text/html;q=0.9, application/json")),
+            Json
+        );
+        assert_eq!(ResponseFormat::negotiate(Some("application/*, text/*;q=0.5")), Json);
+        assert_eq!(
+            ResponseFormat::negotiate(Some("*/*;q=0.8, application/json;q=0.1")),
+            Html
+        );
+        assert_eq!(ResponseFormat::negotiate(Some("image/png")), Html);
+    }
+
+    #[test]
+    fn selection() {
+        let mut logs = Logs::default();
+        let raw = |html: Option<&str>, json: Option<&str>| RawResponseTemplate {
+            id: "t".to_string(),
+            name: String::new(),
+            html: html.map(|s| s.to_string()),
+            json: json.map(|s| s.to_string()),
+            support: String::new(),
+        };
+        let both = ResponseTemplate::resolve(&mut logs, vec![raw(Some("h"), Some("j"))]);
+        assert_eq!(
+            both["t"].select(Some("application/json")).unwrap().0,
+            ResponseFormat::Json
+        );
+        assert_eq!(both["t"].select(None).unwrap().0, ResponseFormat::Html);
+        let json_only = ResponseTemplate::resolve(&mut logs, vec![raw(None, Some("j"))]);
+        assert_eq!(json_only["t"].select(None).unwrap().0, ResponseFormat::Json);
+        assert!(ResponseTemplate::resolve(&mut logs, vec![raw(None, None)]).is_empty());
+    }
+}
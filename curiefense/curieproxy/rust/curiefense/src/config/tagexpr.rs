@@ -0,0 +1,224 @@
+//! Boolean tag expressions, used in ACL profile entries.
+//!
+//! An expression combines tags with the `and`, `or` and `not` keywords (case insensitive) and parentheses, `not`
+//! binding tighter than `and`, itself binding tighter than `or`:
+//!
+//!     (country:ru or country:kp) and not partner-api
+//!
+//! Tags never contain spaces or parentheses, so any ACL entry containing them is parsed as an expression.
+use std::collections::HashSet;
+
+use crate::interface::{tagify, Location, Tags};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagExpr {
+    Tag(String),
+    Not(Box<TagExpr>),
+    And(Vec<TagExpr>),
+    Or(Vec<TagExpr>),
+}
+
+#[derive(Debug, Clone)]
+pub struct TagExpression {
+    /// the entry, as written in the profile
+    pub source: String,
+    pub expr: TagExpr,
+    /// tags that are not negated, reported as the matching tags
+    pub positive: HashSet<String>,
+}
+
+/// the ACL entry is an expression, and not a single tag
+pub fn is_expression(entry: &str) -> bool {
+    entry.contains(|c: char| c.is_whitespace() || c == '(' || c == ')')
+}
+
+fn tokenize(s: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut cur = String::new();
+    for c in s.chars() {
+        if c.is_whitespace() || c == '(' || c == ')' {
+            if !cur.is_empty() {
+                out.push(std::mem::take(&mut cur));
+            }
+            if !c.is_whitespace() {
+                out.push(c.to_string());
+            }
+        } else {
+            cur.push(c);
+        }
+    }
+    if !cur.is_empty() {
+        out.push(cur);
+    }
+    out
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek_keyword(&self, kw: &str) -> bool {
+        self.tokens
+            .get(self.pos)
+            .map(|t| t.eq_ignore_ascii_case(kw))
+            .unwrap_or(false)
+    }
+
+    fn binary(
+        &mut self,
+        kw: &str,
+        operand: fn(&mut Self) -> anyhow::Result<TagExpr>,
+        build: fn(Vec<TagExpr>) -> TagExpr,
+    ) -> anyhow::Result<TagExpr> {
+        let mut operands = vec![operand(self)?];
+        while self.peek_keyword(kw) {
+            self.pos += 1;
+            operands.push(operand(self)?);
+        }
+        Ok(if operands.len() == 1 {
+            operands.pop().unwrap()
+        } else {
+            build(operands)
+        })
+    }
+
+    fn or(&mut self) -> anyhow::Result<TagExpr> {
+        self.binary("or", Self::and, TagExpr::Or)
+    }
+
+    fn and(&mut self) -> anyhow::Result<TagExpr> {
+        self.binary("and", Self::not, TagExpr::And)
+    }
+
+    fn not(&mut self) -> anyhow::Result<TagExpr> {
+        if self.peek_keyword("not") {
+            self.pos += 1;
+            return Ok(TagExpr::Not(Box::new(self.not()?)));
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> anyhow::Result<TagExpr> {
+        let token = match self.tokens.get(self.pos) {
+            None => anyhow::bail!("unexpected end of expression"),
+            Some(t) => t.clone(),
+        };
+        self.pos += 1;
+        match token.as_str() {
+            "(" => {
+                let inner = self.or()?;
+                match self.tokens.get(self.pos).map(|t| t.as_str()) {
+                    Some(")") => {
+                        self.pos += 1;
+                        Ok(inner)
+                    }
+                    _ => anyhow::bail!("missing closing parenthesis"),
+                }
+            }
+            ")" => anyhow::bail!("unexpected closing parenthesis"),
+            t if ["and", "or", "not"].iter().any(|kw| t.eq_ignore_ascii_case(kw)) => {
+                anyhow::bail!("unexpected keyword {}", t)
+            }
+            t => Ok(TagExpr::Tag(tagify(t))),
+        }
+    }
+}
+
+impl TagExpr {
+    pub fn eval(&self, tags: &Tags) -> bool {
+        match self {
+            TagExpr::Tag(t) => tags.contains(t),
+            TagExpr::Not(e) => !e.eval(tags),
+            TagExpr::And(es) => es.iter().all(|e| e.eval(tags)),
+            TagExpr::Or(es) => es.iter().any(|e| e.eval(tags)),
+        }
+    }
+
+    fn positive_tags(&self, negated: bool, out: &mut HashSet<String>) {
+        match self {
+            TagExpr::Tag(t) if !negated => {
+                out.insert(t.clone());
+            }
+            TagExpr::Tag(_) => (),
+            TagExpr::Not(e) => e.positive_tags(!negated, out),
+            TagExpr::And(es) | TagExpr::Or(es) => es.iter().for_each(|e| e.positive_tags(negated, out)),
+        }
+    }
+}
+
+impl std::str::FromStr for TagExpression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s),
+            pos: 0,
+        };
+        let expr = parser.or()?;
+        if let Some(t) = parser.tokens.get(parser.pos) {
+            anyhow::bail!("unexpected {} in expression {}", t, s);
+        }
+        let mut positive = HashSet::new();
+        expr.positive_tags(false, &mut positive);
+        Ok(TagExpression {
+            source: s.to_string(),
+            expr,
+            positive,
+        })
+    }
+}
+
+impl TagExpression {
+    /// the tags explaining the match, None if the expression does not match
+    pub fn matching(&self, tags: &Tags) -> Option<Tags> {
+        if !self.expr.eval(tags) {
+            return None;
+        }
+        let mut out = tags.intersect_tags(&self.positive);
+        if out.is_empty() {
+            // only negations, the expression itself is reported
+            out.insert(&self.source, Location::Request);
+        }
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::virtualtags::VirtualTags;
+
+    fn tags(names: &[&str]) -> Tags {
+        let slice: Vec<(String, Location)> = names.iter().map(|n| (n.to_string(), Location::Request)).collect();
+        Tags::from_slice(&slice, VirtualTags::default())
+    }
+
+    #[test]
+    fn precedence_and_matching() {
+        let e: TagExpression = "(country:ru OR country:kp) AND NOT partner-api".parse().unwrap();
+        assert!(e.matching(&tags(&["country:ru"])).is_some());
+        assert!(e.matching(&tags(&["country:kp", "partner-api"])).is_none());
+        assert!(e.matching(&tags(&["country:fr"])).is_none());
+        let m = e.matching(&tags(&["country:ru", "all"])).unwrap();
+        assert_eq!(m.inner().keys().collect::<Vec<_>>(), vec!["country:ru"]);
+
+        // and binds tighter than or
+        let e: TagExpression = "a or b and c".parse().unwrap();
+        assert!(e.matching(&tags(&["a"])).is_some());
+        assert!(e.matching(&tags(&["b"])).is_none());
+
+        let e: TagExpression = "not bot".parse().unwrap();
+        assert!(e.matching(&tags(&[])).is_some());
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        for s in ["(a or b", "a and", "a b", "or a", "a)"] {
+            assert!(s.parse::<TagExpression>().is_err(), "{}", s);
+        }
+        assert!(is_expression("not a"));
+        assert!(!is_expression("country:ru"));
+    }
+}
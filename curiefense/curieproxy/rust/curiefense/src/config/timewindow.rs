@@ -0,0 +1,226 @@
+//! Time windows, restricting when global filter sections and limits apply.
+//!
+//! A window has optional absolute bounds (RFC 3339 `start` and `end` timestamps), and an optional cron-like
+//! schedule (`minute hour day-of-month month day-of-week`, with `*`, lists, ranges and steps), evaluated in the
+//! window timezone. Timezones are fixed offsets, such as `+02:00`, or `UTC` (the default).
+use anyhow::Context;
+use chrono::{DateTime, Datelike, FixedOffset, Timelike, Utc};
+
+use crate::config::raw::RawTimeWindow;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CronField {
+    /// bit i is set when value i is accepted
+    accepted: u64,
+    /// the field is a wildcard
+    any: bool,
+}
+
+impl CronField {
+    fn parse(field: &str, min: u32, max: u32, names: &[&str]) -> anyhow::Result<Self> {
+        let value = |s: &str| -> anyhow::Result<u32> {
+            let lower = s.to_ascii_lowercase();
+            let v = match names.iter().position(|n| *n == lower) {
+                Some(p) => p as u32 + min,
+                None => s.parse().with_context(|| format!("invalid value {}", s))?,
+            };
+            if v < min || v > max {
+                anyhow::bail!("value {} is out of the {}-{} range", v, min, max);
+            }
+            Ok(v)
+        };
+        let mut accepted = 0;
+        for part in field.split(',') {
+            let (range, step) = match part.split_once('/') {
+                None => (part, 1),
+                Some((r, s)) => (r, s.parse().with_context(|| format!("invalid step {}", s))?),
+            };
+            if step == 0 {
+                anyhow::bail!("invalid step 0");
+            }
+            let (from, to) = match range {
+                "*" => (min, max),
+                r => match r.split_once('-') {
+                    Some((a, b)) => (value(a)?, value(b)?),
+                    None if step > 1 => (value(r)?, max),
+                    None => (value(r)?, value(r)?),
+                },
+            };
+            for v in (from..=to).step_by(step as usize) {
+                accepted |= 1 << v;
+            }
+        }
+        Ok(CronField {
+            accepted,
+            any: field == "*",
+        })
+    }
+
+    fn matches(&self, v: u32) -> bool {
+        self.accepted & (1 << v) != 0
+    }
+}
+
+/// a cron-like schedule, with a minute resolution
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    minutes: CronField,
+    hours: CronField,
+    days: CronField,
+    months: CronField,
+    weekdays: CronField,
+}
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+impl std::str::FromStr for CronSchedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            anyhow::bail!("a schedule has 5 fields, not {}: {}", fields.len(), s);
+        }
+        let mut weekdays = CronField::parse(fields[4], 0, 7, &WEEKDAYS)?;
+        // 7 is sunday too
+        if weekdays.matches(7) {
+            weekdays.accepted |= 1;
+        }
+        Ok(CronSchedule {
+            minutes: CronField::parse(fields[0], 0, 59, &[])?,
+            hours: CronField::parse(fields[1], 0, 23, &[])?,
+            days: CronField::parse(fields[2], 1, 31, &[])?,
+            months: CronField::parse(fields[3], 1, 12, &MONTHS)?,
+            weekdays,
+        })
+    }
+}
+
+impl CronSchedule {
+    fn matches<T: Datelike + Timelike>(&self, t: &T) -> bool {
+        // as in cron, when both the day of month and the day of week are restricted, either can match
+        let day = match (self.days.any, self.weekdays.any) {
+            (false, false) => self.days.matches(t.day()) || self.weekdays.matches(t.weekday().num_days_from_sunday()),
+            _ => self.days.matches(t.day()) && self.weekdays.matches(t.weekday().num_days_from_sunday()),
+        };
+        day && self.minutes.matches(t.minute()) && self.hours.matches(t.hour()) && self.months.matches(t.month())
+    }
+}
+
+fn parse_offset(tz: &str) -> anyhow::Result<FixedOffset> {
+    if tz.eq_ignore_ascii_case("utc") || tz == "Z" {
+        return Ok(FixedOffset::east_opt(0).unwrap());
+    }
+    let (sign, rest) = match tz.split_at(1) {
+        ("+", r) => (1, r),
+        ("-", r) => (-1, r),
+        _ => anyhow::bail!("invalid timezone {}, expected an offset such as +02:00", tz),
+    };
+    let digits: String = rest.chars().filter(|c| *c != ':').collect();
+    if digits.len() != 4 {
+        anyhow::bail!("invalid timezone {}, expected an offset such as +02:00", tz);
+    }
+    let hours: i32 = digits[..2].parse()?;
+    let minutes: i32 = digits[2..].parse()?;
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).with_context(|| format!("invalid timezone {}", tz))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeWindow {
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+    pub schedule: Option<CronSchedule>,
+    pub offset: FixedOffset,
+}
+
+impl TimeWindow {
+    pub fn resolve(raw: RawTimeWindow) -> anyhow::Result<Self> {
+        let date = |s: Option<String>| -> anyhow::Result<Option<DateTime<Utc>>> {
+            s.map(|d| {
+                DateTime::parse_from_rfc3339(&d)
+                    .map(|d| d.with_timezone(&Utc))
+                    .with_context(|| format!("invalid date {}", d))
+            })
+            .transpose()
+        };
+        let window = TimeWindow {
+            start: date(raw.start)?,
+            end: date(raw.end)?,
+            schedule: raw.schedule.as_deref().map(str::parse).transpose()?,
+            offset: parse_offset(raw.timezone.as_deref().unwrap_or("UTC"))?,
+        };
+        if let (Some(start), Some(end)) = (window.start, window.end) {
+            if end <= start {
+                anyhow::bail!("the time window ends before it starts");
+            }
+        }
+        Ok(window)
+    }
+
+    /// is the date inside the window
+    pub fn contains(&self, t: DateTime<Utc>) -> bool {
+        self.start.map(|s| t >= s).unwrap_or(true)
+            && self.end.map(|e| t < e).unwrap_or(true)
+            && self
+                .schedule
+                .as_ref()
+                .map(|s| s.matches(&t.with_timezone(&self.offset)))
+                .unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(start: Option<&str>, end: Option<&str>, schedule: Option<&str>, tz: Option<&str>) -> TimeWindow {
+        TimeWindow::resolve(RawTimeWindow {
+            start: start.map(|s| s.to_string()),
+            end: end.map(|s| s.to_string()),
+            schedule: schedule.map(|s| s.to_string()),
+            timezone: tz.map(|s| s.to_string()),
+        })
+        .unwrap()
+    }
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn absolute_bounds() {
+        let w = window(Some("2024-11-29T00:00:00Z"), Some("2024-11-30T00:00:00Z"), None, None);
+        assert!(w.contains(at("2024-11-29T12:00:00Z")));
+        assert!(!w.contains(at("2024-11-30T00:00:00Z")));
+        assert!(!w.contains(at("2024-11-28T23:59:59Z")));
+    }
+
+    #[test]
+    fn schedule_with_offset() {
+        // office hours, monday to friday, in UTC+2
+        let w = window(None, None, Some("* 9-17 * * mon-fri"), Some("+02:00"));
+        // 2024-11-29 is a friday
+        assert!(w.contains(at("2024-11-29T07:30:00Z")));
+        assert!(!w.contains(at("2024-11-29T06:30:00Z")));
+        assert!(!w.contains(at("2024-11-30T10:00:00Z")));
+        let every_quarter = window(None, None, Some("*/15 * * * *"), None);
+        assert!(every_quarter.contains(at("2024-11-29T07:45:10Z")));
+        assert!(!every_quarter.contains(at("2024-11-29T07:46:00Z")));
+    }
+
+    #[test]
+    fn invalid_windows() {
+        let raw = |schedule: &str, tz: &str| RawTimeWindow {
+            start: None,
+            end: None,
+            schedule: Some(schedule.to_string()),
+            timezone: Some(tz.to_string()),
+        };
+        assert!(TimeWindow::resolve(raw("* * * *", "UTC")).is_err());
+        assert!(TimeWindow::resolve(raw("61 * * * *", "UTC")).is_err());
+        assert!(TimeWindow::resolve(raw("* * * * *", "Europe/Paris")).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+//! Dry-run validation of a configuration directory.
+//!
+//! The candidate configuration is loaded and compiled like a live one, but neither the current configuration
+//! nor the global settings (reputation feeds, geo provider, trusted proxies) are replaced. This is meant for CI
+//! and the confserver, before publishing.
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use super::contentfilter::{convert_rule, resolve_rules};
+use super::hostmap::SecurityPolicy;
+use super::raw::RawContentFilterRule;
+use super::Config;
+use crate::logs::{LogLevel, Logs};
+
+/// a content filter rule that could not be compiled
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct RuleFailure {
+    pub id: String,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Validation {
+    /// true when there are no errors and all rules were compiled
+    pub valid: bool,
+    pub revision: String,
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+    /// entities that are not referenced by any security policy, as kind:id
+    pub unused: Vec<String>,
+    pub rule_failures: Vec<RuleFailure>,
+}
+
+/// entities defined in the configuration, but not used by any security policy
+fn unused_entities(config: &Config) -> Vec<String> {
+    let policies: Vec<&SecurityPolicy> = config
+        .securitypolicies
+        .iter()
+        .map(|m| &m.inner)
+        .chain(config.default.iter())
+        .flat_map(|hostmap| {
+            hostmap
+                .all_entries()
+                .map(|p| p.as_ref())
+                .chain(hostmap.default.iter().map(|d| d.as_ref()))
+        })
+        .collect();
+    let acls: HashSet<&str> = policies.iter().map(|p| p.acl_profile.id.as_str()).collect();
+    let content_filters: HashSet<&str> = policies.iter().map(|p| p.content_filter_profile.id.as_str()).collect();
+    let limits: HashSet<&str> = policies
+        .iter()
+        .flat_map(|p| p.limits.iter().map(|l| l.id.as_str()))
+        .chain(config.global_limits.iter().map(|l| l.id.as_str()))
+        .collect();
+
+    let mut out: Vec<String> = config
+        .acls
+        .keys()
+        .filter(|id| !acls.contains(id.as_str()))
+        .map(|id| format!("acl-profile:{}", id))
+        .chain(
+            config
+                .content_filter_profiles
+                .keys()
+                .filter(|id| !content_filters.contains(id.as_str()))
+                .map(|id| format!("contentfilter-profile:{}", id)),
+        )
+        .chain(
+            config
+                .limits
+                .keys()
+                .filter(|id| !limits.contains(id.as_str()) && !config.inactive_limits.contains(*id))
+                .map(|id| format!("limit:{}", id)),
+        )
+        .collect();
+    out.sort();
+    out
+}
+
+/// loads and compiles the configuration found at `basepath`, without activating it
+pub fn validate_config(basepath: &str) -> Validation {
+    let (config, _) = Config::load_candidate(Logs::new(LogLevel::Warning), basepath);
+    let mut logs = config.logs.clone();
+
+    // the rules are compiled one by one, so that failures are reported with their ids
+    let mut bjson = PathBuf::from(basepath);
+    bjson.push("json");
+    let raw_rules: Vec<RawContentFilterRule> = Config::load_config_file(&mut logs, &bjson, "contentfilter-rules.json");
+    let mut rule_failures = Vec::new();
+    let mut rules = Vec::new();
+    for raw in raw_rules {
+        let id = raw.id.clone();
+        match convert_rule(raw) {
+            Ok(rule) => rules.push(rule),
+            Err(rr) => rule_failures.push(RuleFailure {
+                id,
+                error: rr.to_string(),
+            }),
+        }
+    }
+    resolve_rules(&mut logs, &config.content_filter_profiles, rules);
+
+    let messages = |level: LogLevel| -> Vec<String> {
+        logs.logs
+            .iter()
+            .filter(|l| l.level == level)
+            .map(|l| l.message.clone())
+            .collect()
+    };
+    let errors = messages(LogLevel::Error);
+    let warnings = messages(LogLevel::Warning);
+    Validation {
+        valid: errors.is_empty() && rule_failures.is_empty(),
+        revision: config.revision.clone(),
+        errors,
+        warnings,
+        unused: unused_entities(&config),
+        rule_failures,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::hostmap::HostMap;
+    use crate::config::pathtrie::PathTrie;
+    use crate::config::raw::AclProfile;
+    use std::sync::Arc;
+
+    #[test]
+    fn unused() {
+        let mut config = Config::empty();
+        config.acls.insert("__default__".to_string(), AclProfile::default());
+        config.acls.insert(
+            "orphan".to_string(),
+            AclProfile {
+                id: "orphan".to_string(),
+                ..AclProfile::default()
+            },
+        );
+        config.default = Some(HostMap {
+            name: "default".to_string(),
+            entries: Vec::new(),
+            templates: PathTrie::default(),
+            default: Some(Arc::new(SecurityPolicy::default())),
+        });
+        assert_eq!(unused_entities(&config), vec!["acl-profile:orphan".to_string()]);
+    }
+
+    #[test]
+    fn missing_directory() {
+        let validation = validate_config("/nonexistent/config");
+        assert!(!validation.valid);
+        assert!(!validation.errors.is_empty());
+    }
+}
@@ -0,0 +1,103 @@
+//! Configuration watcher.
+//!
+//! When CONFIG_WATCH_SECONDS is set, a thread polls the version of the configuration, as found in the
+//! manifest.json file next to the configuration directory, and reloads the whole configuration when it
+//! changes. The new configuration, including the content filter databases, is fully built before being
+//! swapped in, so requests are never analyzed with a partially loaded configuration.
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::{manifest_version, reload_config};
+use crate::logs::{LogLevel, Logs};
+
+lazy_static! {
+    static ref CONFIG_WATCH_PERIOD: Option<Duration> = std::env::var("CONFIG_WATCH_SECONDS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|s| *s > 0)
+        .map(Duration::from_secs);
+    static ref RELOAD_STATS: Mutex<ReloadStats> = Mutex::new(ReloadStats::default());
+}
+
+static CONFIG_WATCHER: std::sync::Once = std::sync::Once::new();
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReloadStats {
+    pub reloads: u64,
+    /// reloads that logged errors, the configuration is still swapped in
+    pub failed: u64,
+    pub last_reload: Option<DateTime<Utc>>,
+    pub last_duration_micros: u64,
+    pub last_revision: Option<String>,
+    pub last_errors: Vec<String>,
+}
+
+pub fn reload_stats() -> ReloadStats {
+    RELOAD_STATS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+}
+
+pub(super) fn record_reload(logs: &Logs, revision: &str, duration: Duration) {
+    let errors: Vec<String> = logs
+        .logs
+        .iter()
+        .filter(|l| l.level >= LogLevel::Error)
+        .map(|l| l.message.clone())
+        .collect();
+    let mut stats = RELOAD_STATS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    stats.reloads += 1;
+    if !errors.is_empty() {
+        stats.failed += 1;
+    }
+    stats.last_reload = Some(Utc::now());
+    stats.last_duration_micros = duration.as_micros() as u64;
+    stats.last_revision = Some(revision.to_string());
+    stats.last_errors = errors;
+}
+
+/// starts the thread watching the configuration version, if CONFIG_WATCH_SECONDS is set
+pub fn start_config_watcher(basepath: &str, revision: &str) {
+    let period = match *CONFIG_WATCH_PERIOD {
+        None => return,
+        Some(p) => p,
+    };
+    let basepath = basepath.to_string();
+    let mut last_revision = revision.to_string();
+    CONFIG_WATCHER.call_once(move || {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(period);
+            // an unreadable manifest is probably being written, it will be read on the next poll
+            if let Ok(revision) = manifest_version(&basepath) {
+                if revision != last_revision {
+                    reload_config(&basepath, Vec::new());
+                    last_revision = revision;
+                }
+            }
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reload_statistics() {
+        let before = reload_stats();
+        let mut logs = Logs::default();
+        record_reload(&logs, "rev1", Duration::from_millis(3));
+        logs.error("could not parse limits.json");
+        record_reload(&logs, "rev2", Duration::from_millis(5));
+        let after = reload_stats();
+        assert!(after.reloads >= before.reloads + 2);
+        assert!(after.failed > before.failed);
+        assert_eq!(after.last_revision.as_deref(), Some("rev2"));
+        assert_eq!(after.last_duration_micros, 5000);
+        assert_eq!(after.last_errors, vec!["could not parse limits.json".to_string()]);
+    }
+}
@@ -1,17 +1,26 @@
+pub mod learning;
+pub mod lexical;
+pub mod schema;
+pub mod stream;
+
+use chrono::{DateTime, Utc};
 use hyperscan::Matching;
 use lazy_static::lazy_static;
 use libinjection::{sqli, xss};
-use std::collections::{HashMap, HashSet};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
 
 use crate::config::contentfilter::{
-    rule_tags, ContentFilterEntryMatch, ContentFilterProfile, ContentFilterRules, ContentFilterSection, Section,
-    SectionIdx, ALL_SECTION_IDX, ALL_SECTION_IDX_NO_PLUGINS,
+    rule_tags, ContentFilterEntryMatch, ContentFilterMode, ContentFilterProfile, ContentFilterRules,
+    ContentFilterSection, Section, SectionIdx, ALL_SECTION_IDX, ALL_SECTION_IDX_NO_PLUGINS,
 };
 use crate::config::raw::RawActionType;
+use crate::failure::{dependency_failed, Dependency};
 use crate::interface::stats::{BStageAcl, BStageContentFilter, StatsCollect};
 use crate::interface::{BlockReason, Initiator, Location, Tags};
 use crate::requestfields::RequestField;
-use crate::utils::{masker, RequestInfo};
+use crate::utils::RequestInfo;
 use crate::Logs;
 
 lazy_static! {
@@ -33,6 +42,29 @@ lazy_static! {
     .iter()
     .map(|s| s.to_string())
     .collect();
+    pub static ref LEXICAL_SQLI_TAGS: HashSet<String> = [
+        "cf-rule-id:lexical-sqli",
+        "cf-rule-category:lexical",
+        "cf-rule-subcategory:lexical-sqli",
+        "cf-rule-risk:lexical",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect();
+    pub static ref LEXICAL_XSS_TAGS: HashSet<String> = [
+        "cf-rule-id:lexical-xss",
+        "cf-rule-category:lexical",
+        "cf-rule-subcategory:lexical-xss",
+        "cf-rule-risk:lexical",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect();
+    static ref SHADOW_REPORTS_SIZE: usize = std::env::var("SHADOW_REPORTS_SIZE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(100);
+    static ref SHADOW_REPORTS: Mutex<VecDeque<ShadowReport>> = Mutex::new(VecDeque::new());
 }
 
 #[derive(Default)]
@@ -56,12 +88,137 @@ fn is_blocking(reasons: &[BlockReason]) -> bool {
     reasons.iter().any(|r| r.action >= RawActionType::Custom)
 }
 
+/// exclusions match the normalized path, so that dot segments can't move a request under an excluded prefix
+fn exclusion_path(rinfo: &RequestInfo) -> &str {
+    &rinfo.rinfo.qinfo.normalized.path
+}
+
+/// removes the matches suppressed by the profile rule exclusions, tagging them for auditing
+fn apply_exclusions(
+    logs: &mut Logs,
+    profile: &ContentFilterProfile,
+    path: &str,
+    tags: &mut Tags,
+    reasons: &mut Vec<BlockReason>,
+) {
+    if profile.rule_exclusions.is_empty() {
+        return;
+    }
+    reasons.retain(|reason| {
+        let ruleid = match &reason.initiator {
+            Initiator::ContentFilter { ruleid, .. } => ruleid,
+            _ => return true,
+        };
+        let excluded = profile
+            .rule_exclusions
+            .iter()
+            .any(|ex| ex.matches(ruleid, path, &reason.location, profile.case_insensitive_names));
+        if excluded {
+            logs.debug(|| format!("content filter rule {} excluded at {}", ruleid, reason.location));
+            tags.insert_qualified("cf-excluded", ruleid, reason.location.clone());
+        }
+        !excluded
+    });
+}
+
+/// anomaly score of a content filter match, the rule score when set, its risk level otherwise
+fn reason_score(reason: &BlockReason) -> u32 {
+    match &reason.initiator {
+        Initiator::ContentFilter { risk_level, .. } => reason
+            .extra
+            .get("score")
+            .and_then(|s| s.as_u64())
+            .map(|s| s as u32)
+            .unwrap_or(*risk_level as u32),
+        _ => 0,
+    }
+}
+
+/// sums the scores of the active matches, that only block when the total reaches the threshold
+/// the other matches are downgraded to monitoring
+fn anomaly_scoring(threshold: u32, tags: &mut Tags, reasons: &mut [BlockReason]) -> (bool, u32) {
+    let score: u32 = reasons
+        .iter()
+        .filter(|r| r.action >= RawActionType::Custom)
+        .map(reason_score)
+        .sum();
+    let blocking = score >= threshold;
+    if !blocking {
+        for reason in reasons.iter_mut().filter(|r| r.action >= RawActionType::Custom) {
+            reason.action = RawActionType::Monitor;
+        }
+    }
+    tags.insert_qualified("cf-score", &score.to_string(), Location::Request);
+    (blocking, score)
+}
+
 #[derive(Debug)]
 pub struct CfBlock {
     pub blocking: bool,
     pub reasons: Vec<BlockReason>,
 }
 
+/// what the content filter would have done, for profiles in shadow or learning mode
+#[derive(Debug, Clone, Serialize)]
+pub struct ShadowReport {
+    pub timestamp: DateTime<Utc>,
+    pub profile_id: String,
+    pub profile_name: String,
+    pub mode: ContentFilterMode,
+    pub security_policy: String,
+    pub security_policy_entry: String,
+    pub request_id: Option<String>,
+    pub method: String,
+    pub path: String,
+    /// the request would have been blocked
+    pub blocking: bool,
+    pub reasons: Vec<BlockReason>,
+}
+
+/// records the results of a profile that does not affect the decision
+pub fn record_shadow(logs: &mut Logs, profile: &ContentFilterProfile, rinfo: &RequestInfo, cfblock: &CfBlock) {
+    logs.info(|| {
+        format!(
+            "content filter profile {} in {:?} mode, would have {} the request ({} reasons)",
+            profile.id,
+            profile.mode,
+            if cfblock.blocking { "blocked" } else { "reported" },
+            cfblock.reasons.len()
+        )
+    });
+    if *SHADOW_REPORTS_SIZE == 0 {
+        return;
+    }
+    let report = ShadowReport {
+        timestamp: rinfo.timestamp,
+        profile_id: profile.id.clone(),
+        profile_name: profile.name.clone(),
+        mode: profile.mode,
+        security_policy: rinfo.rinfo.secpolicy.policy.id.clone(),
+        security_policy_entry: rinfo.rinfo.secpolicy.entry.id.clone(),
+        request_id: rinfo.rinfo.meta.requestid.clone(),
+        method: rinfo.rinfo.meta.method.clone(),
+        path: rinfo.rinfo.qinfo.qpath.clone(),
+        blocking: cfblock.blocking,
+        reasons: cfblock.reasons.clone(),
+    };
+    let mut reports = SHADOW_REPORTS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if reports.len() >= *SHADOW_REPORTS_SIZE {
+        reports.pop_front();
+    }
+    reports.push_back(report);
+}
+
+/// the most recent shadow reports, oldest first
+pub fn shadow_reports() -> Vec<ShadowReport> {
+    SHADOW_REPORTS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .iter()
+        .cloned()
+        .collect()
+}
+
 /// Runs the Content Filter part of curiefense
 /// in case of matches, returns a pair (is_blocking, reasons)
 pub fn content_filter_check(
@@ -74,12 +231,30 @@ pub fn content_filter_check(
 ) -> (Result<(), CfBlock>, StatsCollect<BStageContentFilter>) {
     let mut omit = Default::default();
 
+    // note the decodings that changed the analyzed values
+    for idx in &ALL_SECTION_IDX_NO_PLUGINS {
+        for (tr, locs) in &get_section(*idx, rinfo).decoded {
+            tags.insert_qualified_locs("cf-decoded", tr.name(), locs.clone());
+        }
+    }
+
     // directly exit if omitted profile
     if tags.has_intersection(&profile.ignore) {
         logs.debug("content filter bypass because of global ignore");
         return (Ok(()), stats.no_content_filter());
     }
 
+    // hard limits, checked first so that enormous requests are not scanned
+    if let Some(reason) = limits_check(profile, rinfo) {
+        return (
+            Err(CfBlock {
+                blocking: true,
+                reasons: vec![reason],
+            }),
+            stats.no_content_filter(),
+        );
+    }
+
     // check section profiles
     for idx in &ALL_SECTION_IDX {
         if let Err(reason) = section_check(
@@ -118,10 +293,26 @@ pub fn content_filter_check(
             .iter()
             .filter(|(name, _)| !omit.entries.get(*idx).contains(*name))
             .map(|(name, value)| (value.to_string(), (*idx, name.to_string())));
-        hca_keys.extend(section_content);
+        for (value, key) in section_content {
+            if !crate::memory::charge(value.len() + key.1.len()) {
+                return (
+                    Err(CfBlock {
+                        blocking: profile.action.atype.to_raw() >= RawActionType::Custom,
+                        reasons: vec![BlockReason::memory_exceeded(
+                            profile.id.clone(),
+                            profile.name.clone(),
+                            profile.action.atype.to_raw(),
+                            profile.max_request_memory,
+                        )],
+                    }),
+                    stats.no_content_filter(),
+                );
+            }
+            hca_keys.insert(value, key);
+        }
     }
 
-    let iblock = if cfg!(fuzzing) {
+    let mut iblock = if cfg!(fuzzing) {
         Vec::new()
     } else {
         injection_check(
@@ -135,7 +326,10 @@ pub fn content_filter_check(
             test_sqli,
         )
     };
-    if is_blocking(&iblock) {
+    iblock.extend(lexical_check(profile, tags, &hca_keys, &omit));
+    apply_exclusions(logs, profile, exclusion_path(rinfo), tags, &mut iblock);
+    // in anomaly scoring mode, injections are scored along with the signatures
+    if profile.anomaly_threshold.is_none() && is_blocking(&iblock) {
         return (
             Err(CfBlock {
                 blocking: true,
@@ -148,7 +342,7 @@ pub fn content_filter_check(
     let mut specific_tags = tags.new_with_vtags();
 
     // finally, hyperscan check
-    match mhsdb {
+    let (mut reasons, stats) = match mhsdb {
         Some(hsdb) => {
             let (scanresult, stats) = hyperscan(
                 logs,
@@ -164,29 +358,122 @@ pub fn content_filter_check(
             match scanresult {
                 Err(rr) => {
                     logs.error(|| rr.to_string());
-                    (Ok(()), stats)
+                    dependency_failed(tags, Dependency::Hyperscan);
+                    (Vec::new(), stats)
                 }
                 Ok(reasons) => {
                     tags.extend(specific_tags);
-                    if reasons.is_empty() {
-                        (Ok(()), stats)
-                    } else {
-                        (
-                            Err(CfBlock {
-                                blocking: is_blocking(&reasons),
-                                reasons,
-                            }),
-                            stats,
-                        )
-                    }
+                    (reasons, stats)
                 }
             }
         }
         None => {
             logs.warning(||format!("no hsdb found for profile {}, it probably means that no rules were matched by the active/report/ignore", profile.id));
-            (Ok(()), stats.no_content_filter())
+            (Vec::new(), stats.no_content_filter())
+        }
+    };
+
+    // matches found while streaming the body, unless the same rules already matched the parsed body
+    for reason in &rinfo.streamed_body {
+        if let Initiator::ContentFilter { ruleid, .. } = &reason.initiator {
+            let known = reasons
+                .iter()
+                .any(|r| matches!(&r.initiator, Initiator::ContentFilter { ruleid: rid, .. } if rid == ruleid));
+            if !known {
+                tags.insert_qualified("cf-rule-id", ruleid, Location::Body);
+                reasons.push(reason.clone());
+            }
         }
     }
+
+    apply_exclusions(logs, profile, exclusion_path(rinfo), tags, &mut reasons);
+    let (blocking, stats) = match profile.anomaly_threshold {
+        None => (is_blocking(&reasons), stats),
+        Some(threshold) => {
+            reasons.extend(iblock);
+            let (blocking, score) = anomaly_scoring(threshold, tags, &mut reasons);
+            logs.debug(|| format!("content filter anomaly score {}/{}", score, threshold));
+            (blocking, stats.cf_score(score))
+        }
+    };
+    if reasons.is_empty() {
+        (Ok(()), stats)
+    } else {
+        (Err(CfBlock { blocking, reasons }), stats)
+    }
+}
+
+/// checks the request wide limits of the profile
+fn limits_check(profile: &ContentFilterProfile, rinfo: &RequestInfo) -> Option<BlockReason> {
+    let limits = &profile.limits;
+    let action = profile.action.atype.to_raw();
+    let count = |field: &RequestField| field.iter().filter(|(name, _)| !name.ends_with(":decoded")).count();
+
+    let args = count(&rinfo.rinfo.qinfo.args);
+    if args > limits.max_args {
+        return Some(BlockReason::too_many_args(
+            profile.id.clone(),
+            profile.name.clone(),
+            action,
+            args,
+            limits.max_args,
+        ));
+    }
+    let headers = count(&rinfo.headers);
+    if headers > limits.max_headers {
+        return Some(BlockReason::too_many_headers(
+            profile.id.clone(),
+            profile.name.clone(),
+            action,
+            headers,
+            limits.max_headers,
+        ));
+    }
+
+    if limits.max_value_length < usize::MAX {
+        for idx in &ALL_SECTION_IDX_NO_PLUGINS {
+            for (name, value) in get_section(*idx, rinfo).iter() {
+                if !name.ends_with(":decoded") && value.len() > limits.max_value_length {
+                    return Some(BlockReason::value_too_long(
+                        profile.id.clone(),
+                        profile.name.clone(),
+                        action,
+                        Location::from_name(*idx, name),
+                        value.len(),
+                        limits.max_value_length,
+                    ));
+                }
+            }
+        }
+    }
+
+    if limits.max_decoded_body_size < usize::MAX {
+        let body_size: usize = rinfo
+            .rinfo
+            .qinfo
+            .args
+            .iter_locations()
+            .filter(|(_, _, locs)| {
+                locs.iter().any(|l| {
+                    matches!(
+                        l,
+                        Location::Body | Location::BodyArgument(_) | Location::BodyArgumentValue(_, _)
+                    )
+                })
+            })
+            .map(|(_, value, _)| value.len())
+            .sum();
+        if body_size > limits.max_decoded_body_size {
+            return Some(BlockReason::decoded_body_too_large(
+                profile.id.clone(),
+                profile.name.clone(),
+                action,
+                body_size,
+                limits.max_decoded_body_size,
+            ));
+        }
+    }
+    None
 }
 
 /// checks a section (headers, args, cookies) against the policy
@@ -269,7 +556,7 @@ fn section_check(
         };
 
         // check name rules
-        if let Some(entry) = section.names.get(name) {
+        if let Some(entry) = section.names.get(params.lookup_name(name).as_ref()) {
             check_entry(entry)?;
             // if an argument was matched by exact check, we do not try to match it against regex rules
             continue;
@@ -345,6 +632,58 @@ fn injection_check(
     out
 }
 
+type LexicalAnalyzer = fn(&str) -> Option<&'static str>;
+
+/// runs the native lexical analyzers selected by the profile
+fn lexical_check(
+    profile: &ContentFilterProfile,
+    tags: &mut Tags,
+    hca_keys: &HashMap<String, (SectionIdx, String)>,
+    omit: &Omitted,
+) -> Vec<BlockReason> {
+    let mut out = Vec::new();
+    let engines: [(&str, bool, &HashSet<String>, LexicalAnalyzer); 2] = [
+        ("sqli", profile.lexical.sqli, &LEXICAL_SQLI_TAGS, lexical::sqli),
+        ("xss", profile.lexical.xss, &LEXICAL_XSS_TAGS, lexical::xss),
+    ];
+    for (engine, enabled, engine_tags, analyzer) in engines {
+        if !enabled || engine_tags.intersection(&profile.ignore).next().is_some() {
+            continue;
+        }
+        for (value, (idx, name)) in hca_keys.iter() {
+            if !profile.lexical.analyzes(*idx) {
+                continue;
+            }
+            let omitted = omit
+                .exclusions
+                .get(*idx)
+                .get(name)
+                .map(|tgs| engine_tags.intersection(tgs).next().is_some())
+                .unwrap_or(false);
+            if omitted {
+                continue;
+            }
+            if let Some(finding) = analyzer(value) {
+                let locs = Location::from_value(*idx, name, value);
+                let subcategory = format!("lexical-{}", engine);
+                tags.insert_qualified("cf-rule-id", &subcategory, locs.clone());
+                tags.insert_qualified("cf-rule-category", "lexical", locs.clone());
+                tags.insert_qualified("cf-rule-subcategory", &subcategory, locs.clone());
+                tags.insert_qualified("cf-rule-risk", "lexical", locs.clone());
+                out.push(BlockReason::lexical(
+                    profile.id.clone(),
+                    profile.name.clone(),
+                    profile.action.atype.to_raw(),
+                    locs,
+                    engine,
+                    finding,
+                ));
+            }
+        }
+    }
+    out
+}
+
 #[allow(clippy::too_many_arguments)]
 fn hyperscan(
     logs: &mut Logs,
@@ -357,12 +696,18 @@ fn hyperscan(
     global_kept: &HashSet<String>,
     exclusions: &Section<HashMap<String, HashSet<String>>>,
 ) -> (anyhow::Result<Vec<BlockReason>>, StatsCollect<BStageContentFilter>) {
+    // TODO: use `intersperse` when this stabilizes
+    let to_scan = hca_keys.keys().cloned().collect::<Vec<_>>().join("\n");
+    if let Some(prefilter) = &sigs.prefilter {
+        if !prefilter.may_match(&to_scan) {
+            logs.debug("content filter literal prefilter: no match");
+            return (Ok(Vec::new()), stats.cf_no_match(sigs.ids.len()));
+        }
+    }
     let scratch = match sigs.db.alloc_scratch() {
         Err(rr) => return (Err(rr), stats.no_content_filter()),
         Ok(s) => s,
     };
-    // TODO: use `intersperse` when this stabilizes
-    let to_scan = hca_keys.keys().cloned().collect::<Vec<_>>().join("\n");
     let mut found = false;
     #[allow(clippy::needless_borrow)]
     if let Err(rr) = sigs.db.scan(&[to_scan], &scratch, |_, _, _, _| {
@@ -377,12 +722,15 @@ fn hyperscan(
         return (Ok(Vec::new()), stats.cf_no_match(sigs.ids.len()));
     }
 
-    let mut founds: HashSet<(&str, Location, RawActionType, u8)> = HashSet::new();
+    let mut founds: HashSet<(&str, Location, RawActionType, u8, u32)> = HashSet::new();
 
     let mut matches = 0;
     let mut nactive = 0;
     // something matched! but what?
     for (k, (sid, name)) in hca_keys {
+        if !sigs.prefilter.as_ref().map(|pf| pf.may_match(&k)).unwrap_or(true) {
+            continue;
+        }
         // for some reason, from is always set to 0 in my tests, so we can't accurately capture substrings
         #[allow(clippy::needless_borrow)]
         let scanr = sigs.db.scan(&[k.as_bytes()], &scratch, |id, from, to, _flags| {
@@ -418,7 +766,7 @@ fn hyperscan(
                         } else {
                             RawActionType::Monitor
                         };
-                        founds.insert((&sig.id, location, decision, sig.risk));
+                        founds.insert((&sig.id, location, decision, sig.risk, sig.score));
                     }
                 }
             }
@@ -431,7 +779,7 @@ fn hyperscan(
     (
         Ok(founds
             .into_iter()
-            .map(|(sigid, location, action, risk_level)| BlockReason {
+            .map(|(sigid, location, action, risk_level, score)| BlockReason {
                 id: profile.id.clone(),
                 name: profile.name.clone(),
                 initiator: Initiator::ContentFilter {
@@ -441,18 +789,25 @@ fn hyperscan(
                 location,
                 action,
                 extra_locations: Vec::new(),
-                extra: serde_json::Value::Null,
+                extra: if profile.anomaly_threshold.is_some() {
+                    serde_json::json!({ "score": score })
+                } else {
+                    serde_json::Value::Null
+                },
             })
             .collect()),
         stats.cf_matches(sigs.ids.len(), matches, nactive),
     )
 }
 
-fn mask_section(masking_seed: &[u8], sec: &mut RequestField, section: &ContentFilterSection) -> HashSet<Location> {
+fn mask_section<F>(mask: &F, sec: &mut RequestField, section: &ContentFilterSection) -> HashSet<Location>
+where
+    F: Fn(&str) -> String,
+{
     let to_mask: Vec<String> = sec
         .iter()
         .filter(|&(name, _)| {
-            if let Some(e) = section.names.get(name) {
+            if let Some(e) = section.names.get(sec.lookup_name(name).as_ref()) {
                 e.mask
             } else {
                 section.regex.iter().any(|(re, e)| e.mask && re.is_match(name))
@@ -460,32 +815,33 @@ fn mask_section(masking_seed: &[u8], sec: &mut RequestField, section: &ContentFi
         })
         .map(|(name, _)| name.to_string())
         .collect();
-    to_mask.iter().flat_map(|n| sec.mask(masking_seed, n)).collect()
+    to_mask.iter().flat_map(|n| sec.mask_with(n, mask)).collect()
 }
 
 pub fn masking(req: RequestInfo) -> RequestInfo {
     let mut ri = req;
     let mut to_mask = HashSet::new();
-    let masking_seed = &ri.rinfo.secpolicy.content_filter_profile.masking_seed;
-    let profile = &ri.rinfo.secpolicy.content_filter_profile;
+    let secpolicy = ri.rinfo.secpolicy.clone();
+    let profile = &secpolicy.content_filter_profile;
+    let mask = |v: &str| profile.masking.mask_value(&profile.masking_seed, v);
 
     to_mask.extend(mask_section(
-        masking_seed,
+        &mask,
         &mut ri.cookies,
         profile.sections.get(SectionIdx::Cookies),
     ));
     to_mask.extend(mask_section(
-        masking_seed,
+        &mask,
         &mut ri.rinfo.qinfo.args,
         profile.sections.get(SectionIdx::Args),
     ));
     to_mask.extend(mask_section(
-        masking_seed,
+        &mask,
         &mut ri.rinfo.qinfo.path_as_map,
         profile.sections.get(SectionIdx::Path),
     ));
     to_mask.extend(mask_section(
-        masking_seed,
+        &mask,
         &mut ri.headers,
         profile.sections.get(SectionIdx::Headers),
     ));
@@ -494,7 +850,7 @@ pub fn masking(req: RequestInfo) -> RequestInfo {
         use Location::*;
         match extra_mask {
             UriArgumentValue(_, v) => {
-                let target = masker(masking_seed, &v);
+                let target = mask(&v);
                 let npath = ri.rinfo.meta.path.replace(&v, &target);
                 ri.rinfo.meta.path = npath;
                 if let Some(q) = ri.rinfo.qinfo.query {
@@ -503,15 +859,16 @@ pub fn masking(req: RequestInfo) -> RequestInfo {
                 }
             }
             RefererArgumentValue(_, v) => {
-                let target = masker(masking_seed, &v);
+                let target = mask(&v);
                 ri.headers.alter("referer", |r| r.replace(&v, &target));
             }
             Body => {
-                ri.rinfo.qinfo.args.mask(masking_seed, "RAW_BODY");
+                ri.rinfo.qinfo.args.mask_with("RAW_BODY", mask);
             }
             _ => (),
         }
     }
+    profile.masking.apply(&mut ri);
 
     ri
 }
@@ -521,6 +878,7 @@ mod test {
     use std::sync::Arc;
 
     use super::*;
+    use crate::config::contentfilter::{ArgumentLimits, RuleExclusion};
     use crate::config::hostmap::SecurityPolicy;
     use crate::config::virtualtags::VirtualTags;
     use crate::interface::stats::Stats;
@@ -529,13 +887,22 @@ mod test {
     use crate::{Logs, RawRequest};
 
     fn test_request_info(profile: ContentFilterProfile) -> RequestInfo {
+        test_request_info_path(profile, "/foo?arg1=avalue1&arg2=a%20value2")
+    }
+
+    fn test_request_info_path(profile: ContentFilterProfile, path: &str) -> RequestInfo {
         let meta = RequestMeta {
             authority: Some("myhost".to_string()),
             method: "GET".to_string(),
-            path: "/foo?arg1=avalue1&arg2=a%20value2".to_string(),
+            path: path.to_string(),
             extra: HashMap::default(),
             requestid: None,
             protocol: None,
+            tls_fingerprint: None,
+            header_order: None,
+            proxy_source: None,
+            sni: None,
+            client_cert: None,
         };
         let mut logs = Logs::default();
         let headers = [("h1", "value1"), ("h2", "value2")]
@@ -553,6 +920,21 @@ mod test {
         map_request(&mut logs, Arc::new(secpol), None, &raw_request, None, HashMap::new())
     }
 
+    #[test]
+    fn exclusions_on_normalized_path() {
+        let exclusion = RuleExclusion {
+            rule: "100".to_string(),
+            path: Some(regex::Regex::new("^/api/[^/]*/comments/").unwrap()),
+            arg: None,
+        };
+        let profile = ContentFilterProfile::default_from_seed("test");
+        let traversal = test_request_info_path(profile.clone(), "/api/v1/comments/../../admin");
+        assert_eq!(exclusion_path(&traversal), "/api/admin");
+        assert!(!exclusion.matches("100", exclusion_path(&traversal), &Location::Request, false));
+        let comments = test_request_info_path(profile, "/api/v1/comments/42");
+        assert!(exclusion.matches("100", exclusion_path(&comments), &Location::Request, false));
+    }
+
     #[test]
     fn no_masking() {
         let profile = ContentFilterProfile::default_from_seed("test");
@@ -728,6 +1110,11 @@ mod test {
             authority: Some("myhost".to_string()),
             method: "GET".to_string(),
             protocol: None,
+            tls_fingerprint: None,
+            header_order: None,
+            proxy_source: None,
+            sni: None,
+            client_cert: None,
             path: "/foo/pth/ddd?arg1=SECRETa1&arg2=U0VDUkVUYTI%3D".to_string(),
             extra: HashMap::default(),
             requestid: None,
@@ -780,4 +1167,81 @@ mod test {
             panic!("U0VDU found in {}", log_string);
         }
     }
+
+    #[test]
+    fn anomaly_scores() {
+        let reasons = || {
+            let mut scored = BlockReason::xss("p".to_string(), "p".to_string(), RawActionType::Custom, Location::Body);
+            scored.extra = serde_json::json!({ "score": 5 });
+            vec![
+                scored,
+                BlockReason::xss(
+                    "p".to_string(),
+                    "p".to_string(),
+                    RawActionType::Custom,
+                    Location::Request,
+                ),
+                BlockReason::xss(
+                    "p".to_string(),
+                    "p".to_string(),
+                    RawActionType::Monitor,
+                    Location::Request,
+                ),
+            ]
+        };
+
+        let mut tags = Tags::new(&VirtualTags::default());
+        let mut below = reasons();
+        assert_eq!(anomaly_scoring(9, &mut tags, &mut below), (false, 8));
+        assert!(tags.contains("cf-score:8"));
+        assert!(!is_blocking(&below));
+
+        let mut tags = Tags::new(&VirtualTags::default());
+        let mut above = reasons();
+        assert_eq!(anomaly_scoring(8, &mut tags, &mut above), (true, 8));
+        assert!(is_blocking(&above));
+    }
+
+    #[test]
+    fn argument_limits() {
+        let check = |limits: ArgumentLimits| {
+            let mut profile = ContentFilterProfile::default_from_seed("test");
+            profile.limits = limits;
+            let rinfo = test_request_info(profile.clone());
+            limits_check(&profile, &rinfo).map(|r| match r.initiator {
+                Initiator::Restriction { tpe, .. } => tpe,
+                _ => panic!("unexpected initiator {:?}", r.initiator),
+            })
+        };
+        assert_eq!(check(ArgumentLimits::default()), None);
+        assert_eq!(
+            check(ArgumentLimits {
+                max_args: 1,
+                ..ArgumentLimits::default()
+            }),
+            Some("args count")
+        );
+        assert_eq!(
+            check(ArgumentLimits {
+                max_headers: 1,
+                ..ArgumentLimits::default()
+            }),
+            Some("headers count")
+        );
+        assert_eq!(
+            check(ArgumentLimits {
+                max_value_length: 6,
+                ..ArgumentLimits::default()
+            }),
+            Some("value length")
+        );
+        // the request has no body
+        assert_eq!(
+            check(ArgumentLimits {
+                max_decoded_body_size: 1,
+                ..ArgumentLimits::default()
+            }),
+            None
+        );
+    }
 }
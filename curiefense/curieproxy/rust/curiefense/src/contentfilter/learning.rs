@@ -0,0 +1,283 @@
+//! Learning mode.
+//!
+//! Content filter profiles in learning mode record the arguments of the requests that were not blocked by
+//! the content filter: for each path, the argument names, the shape of their values and their lengths.
+//! The observations are exported as a suggested positive security model, listing the expected arguments
+//! of each path.
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::utils::RequestInfo;
+
+/// bounds on the amount of observations kept for a profile
+const MAX_LEARNED_PATHS: usize = 1000;
+const MAX_LEARNED_PARAMS: usize = 100;
+
+lazy_static! {
+    static ref OBSERVATIONS: Mutex<HashMap<String, ProfileObservations>> = Mutex::new(HashMap::new());
+}
+
+/// the kind of values an argument holds, from the most specific to the most generic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueShape {
+    Integer,
+    Decimal,
+    Boolean,
+    Uuid,
+    Hex,
+    Alpha,
+    Alphanumeric,
+    Text,
+}
+
+fn is_uuid(value: &str) -> bool {
+    let groups: Vec<&str> = value.split('-').collect();
+    groups.len() == 5
+        && groups
+            .iter()
+            .zip([8, 4, 4, 4, 12].iter())
+            .all(|(g, l)| g.len() == *l && g.bytes().all(|c| c.is_ascii_hexdigit()))
+}
+
+impl ValueShape {
+    pub fn of(value: &str) -> Self {
+        let digits = value.strip_prefix('-').unwrap_or(value);
+        if !digits.is_empty() && digits.bytes().all(|c| c.is_ascii_digit()) {
+            ValueShape::Integer
+        } else if value.parse::<f64>().map(|f| f.is_finite()).unwrap_or(false) {
+            ValueShape::Decimal
+        } else if value == "true" || value == "false" {
+            ValueShape::Boolean
+        } else if is_uuid(value) {
+            ValueShape::Uuid
+        } else if value.len() >= 8 && value.bytes().all(|c| c.is_ascii_hexdigit()) {
+            ValueShape::Hex
+        } else if !value.is_empty() && value.bytes().all(|c| c.is_ascii_alphabetic()) {
+            ValueShape::Alpha
+        } else if !value.is_empty()
+            && value
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'-')
+        {
+            ValueShape::Alphanumeric
+        } else {
+            ValueShape::Text
+        }
+    }
+
+    /// shapes that accept all the values of this one, most specific first
+    fn generalizations(&self) -> &'static [ValueShape] {
+        use ValueShape::*;
+        match self {
+            Integer => &[Integer, Hex, Decimal, Alphanumeric, Text],
+            Decimal => &[Decimal, Text],
+            Boolean => &[Boolean, Alpha, Alphanumeric, Text],
+            Uuid => &[Uuid, Alphanumeric, Text],
+            Hex => &[Hex, Alphanumeric, Text],
+            Alpha => &[Alpha, Alphanumeric, Text],
+            Alphanumeric => &[Alphanumeric, Text],
+            Text => &[Text],
+        }
+    }
+
+    /// the most specific shape accepting the values of both shapes
+    pub fn merge(self, other: ValueShape) -> ValueShape {
+        let others = other.generalizations();
+        self.generalizations()
+            .iter()
+            .find(|s| others.contains(s))
+            .copied()
+            .unwrap_or(ValueShape::Text)
+    }
+
+    /// does the value have this shape, or a more specific one
+    pub fn accepts(&self, value: &str) -> bool {
+        ValueShape::of(value).generalizations().contains(self)
+    }
+}
+
+#[derive(Debug, Default)]
+struct ParamObservations {
+    seen: u64,
+    shape: Option<ValueShape>,
+    min_length: usize,
+    max_length: usize,
+}
+
+#[derive(Debug, Default)]
+struct PathObservations {
+    requests: u64,
+    params: HashMap<String, ParamObservations>,
+}
+
+#[derive(Debug, Default)]
+struct ProfileObservations {
+    paths: HashMap<String, PathObservations>,
+}
+
+/// records the arguments of a request
+pub fn observe(profile_id: &str, reqinfo: &RequestInfo) {
+    let mut observations = OBSERVATIONS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let profile = observations.entry(profile_id.to_string()).or_default();
+    let path = &reqinfo.rinfo.qinfo.qpath;
+    if !profile.paths.contains_key(path) && profile.paths.len() >= MAX_LEARNED_PATHS {
+        return;
+    }
+    let pathobs = profile.paths.entry(path.clone()).or_default();
+    pathobs.requests += 1;
+    for (name, value) in reqinfo.rinfo.qinfo.args.iter() {
+        if !pathobs.params.contains_key(name) && pathobs.params.len() >= MAX_LEARNED_PARAMS {
+            continue;
+        }
+        let param = pathobs.params.entry(name.to_string()).or_default();
+        let length = value.chars().count();
+        if param.seen == 0 {
+            param.min_length = length;
+        }
+        param.seen += 1;
+        param.min_length = param.min_length.min(length);
+        param.max_length = param.max_length.max(length);
+        // empty values do not say anything about the shape
+        if !value.is_empty() {
+            let shape = ValueShape::of(value);
+            param.shape = Some(param.shape.map(|s| s.merge(shape)).unwrap_or(shape));
+        }
+    }
+}
+
+/// an expected argument
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParamModel {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub shape: ValueShape,
+    pub min_length: usize,
+    pub max_length: usize,
+    /// the argument was present in all observed requests
+    pub required: bool,
+}
+
+/// the expected arguments of a path
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathModel {
+    pub path: String,
+    pub requests: u64,
+    pub params: Vec<ParamModel>,
+}
+
+/// the suggested positive security model of a profile, built from the observed requests
+pub fn learned_model(profile_id: &str) -> Vec<PathModel> {
+    let observations = OBSERVATIONS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let profile = match observations.get(profile_id) {
+        None => return Vec::new(),
+        Some(p) => p,
+    };
+    let mut out: Vec<PathModel> = profile
+        .paths
+        .iter()
+        .map(|(path, pathobs)| {
+            let mut params: Vec<ParamModel> = pathobs
+                .params
+                .iter()
+                .map(|(name, p)| ParamModel {
+                    name: name.clone(),
+                    shape: p.shape.unwrap_or(ValueShape::Text),
+                    min_length: p.min_length,
+                    max_length: p.max_length,
+                    required: p.seen >= pathobs.requests,
+                })
+                .collect();
+            params.sort_by(|a, b| a.name.cmp(&b.name));
+            PathModel {
+                path: path.clone(),
+                requests: pathobs.requests,
+                params,
+            }
+        })
+        .collect();
+    out.sort_by(|a, b| a.path.cmp(&b.path));
+    out
+}
+
+/// profiles with observations
+pub fn learning_profiles() -> Vec<String> {
+    let observations = OBSERVATIONS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut out: Vec<String> = observations.keys().cloned().collect();
+    out.sort();
+    out
+}
+
+/// drops the observations of a profile, returns false if there were none
+pub fn reset_learning(profile_id: &str) -> bool {
+    OBSERVATIONS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .remove(profile_id)
+        .is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::request_info;
+
+    fn mk_reqinfo(path: &str) -> RequestInfo {
+        request_info(&[("path", path)], &[])
+    }
+
+    #[test]
+    fn shapes() {
+        use ValueShape::*;
+        assert_eq!(ValueShape::of("-42"), Integer);
+        assert_eq!(ValueShape::of("4.2"), Decimal);
+        assert_eq!(ValueShape::of("true"), Boolean);
+        assert_eq!(ValueShape::of("123e4567-e89b-12d3-a456-426614174000"), Uuid);
+        assert_eq!(ValueShape::of("deadbeef"), Hex);
+        assert_eq!(ValueShape::of("hello"), Alpha);
+        assert_eq!(ValueShape::of("user_12"), Alphanumeric);
+        assert_eq!(ValueShape::of("hello world"), Text);
+        assert_eq!(Integer.merge(Decimal), Decimal);
+        assert_eq!(Integer.merge(Alpha), Alphanumeric);
+        assert_eq!(Boolean.merge(Alpha), Alpha);
+        assert_eq!(Decimal.merge(Alpha), Text);
+        assert!(Alphanumeric.accepts("42"));
+        assert!(!Integer.accepts("4.2"));
+    }
+
+    #[test]
+    fn learned_paths() {
+        let profile = "learning-test-profile";
+        observe(profile, &mk_reqinfo("/items?id=12&q=shoes"));
+        observe(profile, &mk_reqinfo("/items?id=1234&q=red+shoes"));
+        observe(profile, &mk_reqinfo("/items?id=7"));
+        let model = learned_model(profile);
+        assert_eq!(model.len(), 1);
+        assert_eq!(model[0].path, "/items");
+        assert_eq!(model[0].requests, 3);
+        assert_eq!(
+            model[0].params,
+            vec![
+                ParamModel {
+                    name: "id".to_string(),
+                    shape: ValueShape::Integer,
+                    min_length: 1,
+                    max_length: 4,
+                    required: true,
+                },
+                ParamModel {
+                    name: "q".to_string(),
+                    shape: ValueShape::Text,
+                    min_length: 5,
+                    max_length: 9,
+                    required: false,
+                },
+            ]
+        );
+        assert!(learning_profiles().contains(&profile.to_string()));
+        assert!(reset_learning(profile));
+        assert!(learned_model(profile).is_empty());
+    }
+}
@@ -0,0 +1,488 @@
+//! Lexical SQL injection and XSS detection.
+//!
+//! The signatures miss obfuscated payloads, such as `UN/**/ION SELECT` or `<img/src=x onerror=...>`.
+//! These analyzers tokenize the values, like libinjection, and look for token sequences that only make
+//! sense in an injection: a string that is closed and followed by a boolean operator, a `UNION` followed by a
+//! statement, stacked queries, event handler attributes in a tag, `javascript:` URIs...
+//!
+//! Values are analyzed in several contexts, as the injection point can be inside a quoted string.
+
+/// SQL token classes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Sql {
+    Str,
+    Number,
+    /// bare identifier
+    Ident,
+    /// dangerous function, followed by a parenthesis
+    Func,
+    Union,
+    /// select, insert, drop...
+    Statement,
+    /// from, where, into...
+    Keyword,
+    /// and, or, &&, ||
+    Logic,
+    Operator,
+    Comment,
+    Open,
+    Close,
+    Semicolon,
+    Comma,
+}
+
+const SQL_STATEMENTS: [&str; 11] = [
+    "select", "insert", "update", "delete", "drop", "alter", "create", "truncate", "exec", "execute", "declare",
+];
+const SQL_KEYWORDS: [&str; 11] = [
+    "from",
+    "where",
+    "into",
+    "table",
+    "values",
+    "having",
+    "order",
+    "group",
+    "limit",
+    "waitfor",
+    "procedure",
+];
+const SQL_OPERATORS: [&str; 8] = ["like", "in", "is", "not", "between", "regexp", "rlike", "div"];
+const SQL_FUNCTIONS: [&str; 14] = [
+    "sleep",
+    "benchmark",
+    "pg_sleep",
+    "load_file",
+    "extractvalue",
+    "updatexml",
+    "char",
+    "chr",
+    "concat",
+    "version",
+    "user",
+    "database",
+    "substring",
+    "ascii",
+];
+
+/// the context in which the value is inserted in the query
+#[derive(Debug, Clone, Copy)]
+enum SqlContext {
+    Bare,
+    Quoted(char),
+}
+
+fn sql_word(word: &str, next: Option<char>) -> Sql {
+    let lword = word.to_ascii_lowercase();
+    let lword = lword.as_str();
+    if lword == "union" {
+        Sql::Union
+    } else if lword == "and" || lword == "or" || lword == "xor" {
+        Sql::Logic
+    } else if SQL_STATEMENTS.contains(&lword) {
+        Sql::Statement
+    } else if SQL_KEYWORDS.contains(&lword) {
+        Sql::Keyword
+    } else if SQL_OPERATORS.contains(&lword) {
+        Sql::Operator
+    } else if next == Some('(') && SQL_FUNCTIONS.contains(&lword) {
+        Sql::Func
+    } else {
+        Sql::Ident
+    }
+}
+
+/// skips a quoted string, starting after the opening quote, returns the index after the closing quote
+fn skip_string(chars: &[char], mut i: usize, quote: char) -> usize {
+    while i < chars.len() {
+        if chars[i] == '\\' {
+            i += 2;
+        } else if chars[i] == quote {
+            // doubled quotes are escaped quotes
+            if chars.get(i + 1) == Some(&quote) {
+                i += 2;
+            } else {
+                return i + 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    chars.len()
+}
+
+fn sql_tokens(value: &str, context: SqlContext) -> Vec<Sql> {
+    let chars: Vec<char> = value.chars().collect();
+    let mut out = Vec::new();
+    let mut i = 0;
+    if let SqlContext::Quoted(q) = context {
+        i = skip_string(&chars, 0, q);
+        out.push(Sql::Str);
+    }
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '\'' || c == '"' {
+            i = skip_string(&chars, i + 1, c);
+            out.push(Sql::Str);
+        } else if c == '`' {
+            i = skip_string(&chars, i + 1, c);
+            out.push(Sql::Ident);
+        } else if c == '/' && next == Some('*') {
+            // mysql versioned comments are executed
+            if chars.get(i + 2) == Some(&'!') {
+                i += 3;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+                continue;
+            }
+            let mut j = i + 2;
+            while j + 1 < chars.len() && !(chars[j] == '*' && chars[j + 1] == '/') {
+                j += 1;
+            }
+            i = (j + 2).min(chars.len());
+            out.push(Sql::Comment);
+        } else if c == '*' && next == Some('/') {
+            // end of a versioned comment
+            i += 2;
+        } else if (c == '-' && next == Some('-')) || c == '#' {
+            out.push(Sql::Comment);
+            break;
+        } else if c.is_ascii_digit() || (c == '.' && next.map(|n| n.is_ascii_digit()).unwrap_or(false)) {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                i += 1;
+            }
+            out.push(Sql::Number);
+        } else if c.is_alphabetic() || c == '_' || c == '@' || c == '$' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || matches!(chars[i], '_' | '@' | '$' | '.')) {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            let mut j = i;
+            while j < chars.len() && chars[j].is_whitespace() {
+                j += 1;
+            }
+            out.push(sql_word(&word, chars.get(j).copied()));
+        } else if (c == '|' && next == Some('|')) || (c == '&' && next == Some('&')) {
+            i += 2;
+            out.push(Sql::Logic);
+        } else {
+            i += 1;
+            out.push(match c {
+                '(' => Sql::Open,
+                ')' => Sql::Close,
+                ';' => Sql::Semicolon,
+                ',' => Sql::Comma,
+                _ => Sql::Operator,
+            });
+        }
+    }
+    out
+}
+
+fn sql_finding(tokens: &[Sql], context: SqlContext) -> Option<&'static str> {
+    use Sql::*;
+    // comments between tokens are used to obfuscate keywords, only a trailing comment is meaningful
+    let last = tokens.len().saturating_sub(1);
+    let tokens: Vec<Sql> = tokens
+        .iter()
+        .enumerate()
+        .filter(|(i, t)| **t != Comment || *i == last)
+        .map(|(_, t)| *t)
+        .collect();
+    if let SqlContext::Quoted(_) = context {
+        // the first token is the end of the quoted string the value was inserted in
+        match tokens.get(1..) {
+            Some([Logic, Str | Number | Ident | Func | Open, ..]) => return Some("string-break"),
+            Some([Union | Semicolon, ..]) => return Some("string-break"),
+            Some([Close, Logic | Union | Semicolon, ..]) => return Some("string-break"),
+            Some([Comment]) | Some([Close, Comment]) => return Some("comment-truncation"),
+            _ => (),
+        }
+    }
+    for (i, w) in tokens.windows(2).enumerate() {
+        match (w[0], w[1]) {
+            (Union, Statement) => return Some("union"),
+            (Union, Ident) if tokens.get(i + 2) == Some(&Statement) => return Some("union"),
+            (Semicolon, Statement) => return Some("stacked"),
+            (Open, Statement) if tokens[i + 2..].contains(&Keyword) => return Some("subquery"),
+            (Logic | Operator | Semicolon | Statement, Func) if tokens.get(i + 2) == Some(&Open) => {
+                return Some("function")
+            }
+            _ => (),
+        }
+    }
+    for w in tokens.windows(4) {
+        if let [Logic, a, Operator, b] = w {
+            if a == b && matches!(a, Number | Str) {
+                return Some("tautology");
+            }
+        }
+    }
+    None
+}
+
+/// looks for a SQL injection, returns the name of the finding
+pub fn sqli(value: &str) -> Option<&'static str> {
+    if value.len() < 3 {
+        return None;
+    }
+    let mut contexts = vec![SqlContext::Bare];
+    for q in ['\'', '"'] {
+        if value.contains(q) {
+            contexts.push(SqlContext::Quoted(q));
+        }
+    }
+    contexts
+        .into_iter()
+        .find_map(|ctx| sql_finding(&sql_tokens(value, ctx), ctx))
+}
+
+const XSS_TAGS: [&str; 13] = [
+    "script", "iframe", "frame", "frameset", "object", "embed", "applet", "base", "import", "xml", "meta", "svg",
+    "math",
+];
+const XSS_URI_ATTRIBUTES: [&str; 11] = [
+    "href",
+    "src",
+    "action",
+    "formaction",
+    "data",
+    "xlink:href",
+    "background",
+    "lowsrc",
+    "dynsrc",
+    "poster",
+    "content",
+];
+
+/// decodes the numeric and the most common named html entities, drops the whitespace and control characters
+/// that browsers ignore in URI schemes
+fn normalize_uri(value: &str) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(c) = rest.chars().next() {
+        if c == '&' {
+            if let Some(end) = rest.find(';').filter(|e| *e < 12) {
+                let entity = &rest[1..end];
+                let decoded = if let Some(hex) = entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                    u32::from_str_radix(hex, 16).ok().and_then(char::from_u32)
+                } else if let Some(dec) = entity.strip_prefix('#') {
+                    dec.parse().ok().and_then(char::from_u32)
+                } else {
+                    match entity {
+                        "colon" => Some(':'),
+                        "tab" => Some('\t'),
+                        "newline" => Some('\n'),
+                        _ => None,
+                    }
+                };
+                if let Some(d) = decoded {
+                    out.push(d);
+                    rest = &rest[end + 1..];
+                    continue;
+                }
+            }
+        }
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    out.chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+fn dangerous_uri(value: &str) -> bool {
+    let uri = normalize_uri(value);
+    uri.starts_with("javascript:") || uri.starts_with("vbscript:") || uri.starts_with("data:text/html")
+}
+
+fn is_event_handler(name: &str) -> bool {
+    name.len() > 4 && name.starts_with("on") && name.chars().all(|c| c.is_ascii_alphabetic())
+}
+
+/// parses the attributes of a tag, starting at `i`, returns the finding and the index after the tag
+fn xss_attributes(chars: &[char], mut i: usize) -> (Option<&'static str>, usize) {
+    loop {
+        while i < chars.len() && (chars[i].is_whitespace() || chars[i] == '/') {
+            i += 1;
+        }
+        if i >= chars.len() {
+            return (None, i);
+        }
+        if chars[i] == '>' {
+            return (None, i + 1);
+        }
+        let start = i;
+        while i < chars.len() && !chars[i].is_whitespace() && !matches!(chars[i], '=' | '>' | '/') {
+            i += 1;
+        }
+        let name: String = chars[start..i].iter().collect::<String>().to_ascii_lowercase();
+        while i < chars.len() && chars[i].is_whitespace() {
+            i += 1;
+        }
+        let value = if chars.get(i) == Some(&'=') {
+            i += 1;
+            while i < chars.len() && chars[i].is_whitespace() {
+                i += 1;
+            }
+            let vstart;
+            let vend;
+            if let Some(q) = chars.get(i).copied().filter(|c| *c == '"' || *c == '\'' || *c == '`') {
+                vstart = i + 1;
+                i = vstart;
+                while i < chars.len() && chars[i] != q {
+                    i += 1;
+                }
+                vend = i;
+                i = (i + 1).min(chars.len());
+            } else {
+                vstart = i;
+                while i < chars.len() && !chars[i].is_whitespace() && chars[i] != '>' {
+                    i += 1;
+                }
+                vend = i;
+            }
+            Some(chars[vstart..vend].iter().collect::<String>())
+        } else {
+            None
+        };
+        if name.is_empty() {
+            i += 1;
+            continue;
+        }
+        if is_event_handler(&name) && value.is_some() {
+            return (Some("event-handler"), i);
+        }
+        if let Some(v) = &value {
+            if XSS_URI_ATTRIBUTES.contains(&name.as_str()) && dangerous_uri(v) {
+                return (Some("uri"), i);
+            }
+            if name == "style" {
+                let style = normalize_uri(v);
+                if style.contains("expression(") || style.contains("javascript:") {
+                    return (Some("style"), i);
+                }
+            }
+            if name == "srcdoc" {
+                return (Some("srcdoc"), i);
+            }
+        }
+    }
+}
+
+fn xss_html(chars: &[char], mut i: usize) -> Option<&'static str> {
+    while i < chars.len() {
+        if chars[i] != '<' {
+            i += 1;
+            continue;
+        }
+        i += 1;
+        if chars.get(i) == Some(&'/') {
+            i += 1;
+        }
+        let start = i;
+        while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == ':' || chars[i] == '-') {
+            i += 1;
+        }
+        // "<" followed by something else than a tag name is text
+        if i == start || !chars[start].is_ascii_alphabetic() {
+            continue;
+        }
+        let tag = chars[start..i].iter().collect::<String>().to_ascii_lowercase();
+        if XSS_TAGS.contains(&tag.as_str()) {
+            return Some("tag");
+        }
+        let (finding, next) = xss_attributes(chars, i);
+        if finding.is_some() {
+            return finding;
+        }
+        i = next;
+    }
+    None
+}
+
+/// looks for a cross site scripting payload, returns the name of the finding
+pub fn xss(value: &str) -> Option<&'static str> {
+    if value.len() < 3 {
+        return None;
+    }
+    if dangerous_uri(value) {
+        return Some("uri");
+    }
+    let chars: Vec<char> = value.chars().collect();
+    if let Some(f) = xss_html(&chars, 0) {
+        return Some(f);
+    }
+    // the value is inserted in a quoted attribute, and closes it
+    for q in ['"', '\''] {
+        if let Some(pos) = chars.iter().position(|c| *c == q) {
+            if let (Some(f), _) = xss_attributes(&chars, pos + 1) {
+                return Some(f);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sql_injections() {
+        assert_eq!(sqli("1' or '1'='1"), Some("string-break"));
+        assert_eq!(sqli("admin'--"), Some("comment-truncation"));
+        assert_eq!(sqli("1 UNION/**/ALL/**/SELECT password FROM users"), Some("union"));
+        assert_eq!(sqli("1; DROP TABLE users"), Some("stacked"));
+        assert_eq!(sqli("1 or 1=1"), Some("tautology"));
+        assert_eq!(sqli("1 and sleep (5)"), Some("function"));
+        assert_eq!(sqli("1 /*!50000union*/ /*!50000select*/ 1"), Some("union"));
+        assert_eq!(sqli("x\") or (\"a\"=\"a"), Some("string-break"));
+    }
+
+    #[test]
+    fn sql_legit() {
+        for v in [
+            "hello world",
+            "O'Reilly",
+            "select your plan",
+            "rock'n'roll",
+            "1-2",
+            "john.doe@example.com",
+            "it's a test, isn't it?",
+            "sort by name (ascending)",
+        ] {
+            assert_eq!(sqli(v), None, "{}", v);
+        }
+    }
+
+    #[test]
+    fn xss_payloads() {
+        assert_eq!(xss("<script>alert(1)</script>"), Some("tag"));
+        assert_eq!(xss("<img/src=x onerror=alert(1)>"), Some("event-handler"));
+        assert_eq!(xss("<a href=\"jav&#x09;ascript&colon;alert(1)\">x</a>"), Some("uri"));
+        assert_eq!(xss("\" onmouseover=\"alert(1)"), Some("event-handler"));
+        assert_eq!(xss("  JaVaScRiPt:alert(1)"), Some("uri"));
+        assert_eq!(xss("<div style=\"width: expression(alert(1))\">"), Some("style"));
+        assert_eq!(xss("<SVG onload=alert(1)>"), Some("tag"));
+    }
+
+    #[test]
+    fn xss_legit() {
+        for v in [
+            "a < b and c > d",
+            "<b>bold</b> text",
+            "say \"hello\" to everyone",
+            "<a href=\"https://example.com\">link</a>",
+            "online=yes",
+        ] {
+            assert_eq!(xss(v), None, "{}", v);
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Positive security model.
+//!
+//! Content filter profiles can list the expected arguments of some paths. The first schema whose path
+//! regex matches the request path, and that applies to its method, is used: unknown arguments, arguments
+//! that do not have the expected type or length, and missing required arguments produce block reasons.
+//! They only block the request when the schema is set to, using the profile action.
+//!
+//! The bearer token header and claims, that are added as arguments by the token inspection, are not part
+//! of the schema.
+use std::collections::HashSet;
+
+use crate::config::contentfilter::{ContentFilterProfile, ParamSchema};
+use crate::config::raw::RawActionType;
+use crate::interface::{BlockReason, Location, Tags};
+use crate::utils::RequestInfo;
+
+use super::CfBlock;
+
+fn violation(
+    profile: &ContentFilterProfile,
+    action: RawActionType,
+    path: &str,
+    param: &str,
+    problem: &'static str,
+    locs: &HashSet<Location>,
+) -> BlockReason {
+    BlockReason::schema(
+        profile.id.clone(),
+        profile.name.clone(),
+        action,
+        path.to_string(),
+        param.to_string(),
+        problem,
+        locs,
+    )
+}
+
+fn param_problem(param: &ParamSchema, value: &str) -> Option<&'static str> {
+    let length = value.chars().count();
+    if length < param.min_length {
+        Some("too short")
+    } else if length > param.max_length {
+        Some("too long")
+    } else if param
+        .shape
+        .map(|s| !value.is_empty() && !s.accepts(value))
+        .unwrap_or(false)
+    {
+        Some("unexpected type")
+    } else {
+        None
+    }
+}
+
+/// arguments added by the token inspection, client arguments with the same names are still checked
+fn jwt_argument(name: &str, locs: &HashSet<Location>) -> bool {
+    (name.starts_with("jwt-header-") || name.starts_with("jwt-claim-"))
+        && locs.iter().all(|l| matches!(l, Location::Header(_)))
+}
+
+/// checks the request arguments against the schema of its path
+pub fn schema_check(profile: &ContentFilterProfile, reqinfo: &RequestInfo, tags: &mut Tags) -> Result<(), CfBlock> {
+    let path = &reqinfo.rinfo.qinfo.qpath;
+    let method = &reqinfo.rinfo.meta.method;
+    let schema = match profile.schema.iter().find(|s| {
+        s.path.is_match(path) && (s.methods.is_empty() || s.methods.iter().any(|m| m.eq_ignore_ascii_case(method)))
+    }) {
+        None => return Ok(()),
+        Some(s) => s,
+    };
+    let action = if schema.block {
+        profile.action.atype.to_raw()
+    } else {
+        RawActionType::Monitor
+    };
+
+    let mut reasons = Vec::new();
+    let mut seen = vec![false; schema.params.len()];
+    for (name, value, locs) in reqinfo.rinfo.qinfo.args.iter_locations() {
+        if jwt_argument(name, locs) {
+            continue;
+        }
+        let problem = match schema.params.iter().position(|p| p.name.is_match(name)) {
+            None if schema.allow_unknown => None,
+            None => Some("unknown argument"),
+            Some(idx) => {
+                seen[idx] = true;
+                param_problem(&schema.params[idx], value)
+            }
+        };
+        if let Some(problem) = problem {
+            reasons.push(violation(profile, action, path, name, problem, locs));
+        }
+    }
+    let request_loc: HashSet<Location> = std::iter::once(Location::Request).collect();
+    for (param, _) in schema.params.iter().zip(seen).filter(|(p, seen)| p.required && !seen) {
+        reasons.push(violation(
+            profile,
+            action,
+            path,
+            &param.name_src,
+            "missing argument",
+            &request_loc,
+        ));
+    }
+
+    if reasons.is_empty() {
+        return Ok(());
+    }
+    for r in &reasons {
+        tags.insert_locs(
+            "schema-violation",
+            std::iter::once(&r.location)
+                .chain(r.extra_locations.iter())
+                .cloned()
+                .collect(),
+        );
+    }
+    Err(CfBlock {
+        blocking: schema.block,
+        reasons,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::contentfilter::PathSchema;
+    use crate::config::virtualtags::VirtualTags;
+    use crate::contentfilter::learning::ValueShape;
+    use crate::interface::Initiator;
+    use crate::testutils::request_info;
+    use regex::Regex;
+
+    fn mk_profile() -> ContentFilterProfile {
+        let mut profile = ContentFilterProfile::default_from_seed("seed");
+        profile.schema = vec![PathSchema {
+            path: Regex::new("^/items$").unwrap(),
+            methods: Vec::new(),
+            params: vec![
+                ParamSchema {
+                    name_src: "id".to_string(),
+                    name: Regex::new("^(?:id)$").unwrap(),
+                    shape: Some(ValueShape::Integer),
+                    min_length: 1,
+                    max_length: 6,
+                    required: true,
+                },
+                ParamSchema {
+                    name_src: "q".to_string(),
+                    name: Regex::new("^(?:q)$").unwrap(),
+                    shape: None,
+                    min_length: 0,
+                    max_length: 10,
+                    required: false,
+                },
+            ],
+            allow_unknown: false,
+            block: true,
+        }];
+        profile
+    }
+
+    fn mk_reqinfo(path: &str) -> RequestInfo {
+        request_info(&[("path", path)], &[])
+    }
+
+    fn problems(path: &str) -> Option<Vec<(String, &'static str)>> {
+        check(&mk_reqinfo(path))
+    }
+
+    fn check(reqinfo: &RequestInfo) -> Option<Vec<(String, &'static str)>> {
+        let mut tags = Tags::new(&VirtualTags::default());
+        match schema_check(&mk_profile(), reqinfo, &mut tags) {
+            Ok(()) => None,
+            Err(block) => {
+                assert!(block.blocking);
+                assert!(tags.contains("schema-violation"));
+                let mut out: Vec<(String, &'static str)> = block
+                    .reasons
+                    .into_iter()
+                    .map(|r| match r.initiator {
+                        Initiator::Schema { param, problem, .. } => (param, problem),
+                        other => panic!("unexpected initiator {}", other),
+                    })
+                    .collect();
+                out.sort();
+                Some(out)
+            }
+        }
+    }
+
+    #[test]
+    fn schema_violations() {
+        assert_eq!(problems("/items?id=12&q=shoes"), None);
+        assert_eq!(problems("/other?anything=goes"), None);
+        assert_eq!(
+            problems("/items?id=abc&q=very+long+query&debug=1"),
+            Some(vec![
+                ("debug".to_string(), "unknown argument"),
+                ("id".to_string(), "unexpected type"),
+                ("q".to_string(), "too long"),
+            ])
+        );
+        assert_eq!(
+            problems("/items?q=shoes"),
+            Some(vec![("id".to_string(), "missing argument")])
+        );
+    }
+
+    #[test]
+    fn token_arguments() {
+        let mut reqinfo = mk_reqinfo("/items?id=12");
+        let loc = Location::Header("authorization".to_string());
+        reqinfo
+            .rinfo
+            .qinfo
+            .args
+            .add("jwt-claim-sub".to_string(), loc, "user".to_string());
+        assert_eq!(check(&reqinfo), None);
+        assert_eq!(
+            problems("/items?id=12&jwt-claim-sub=user"),
+            Some(vec![("jwt-claim-sub".to_string(), "unknown argument")])
+        );
+    }
+}
@@ -0,0 +1,255 @@
+//! Streaming analysis of request bodies.
+//!
+//! The raw body chunks are scanned with the streaming version of the profile rules, so that matches
+//! spanning chunk boundaries are found without buffering the whole body. The scan can produce a verdict
+//! as soon as the matches would block the request, or when the stream ends.
+use hyperscan::prelude::{Matching, Scratch, Stream};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+use super::{apply_exclusions, is_blocking, reason_score};
+use crate::config::contentfilter::{rule_tags, ContentFilterProfile, ContentFilterRules};
+use crate::config::raw::RawActionType;
+use crate::interface::{BlockReason, Initiator, Location, Tags};
+use crate::logs::Logs;
+
+pub struct BodyStream {
+    /// keeps the database alive while the stream is open
+    rules: Arc<HashMap<String, ContentFilterRules>>,
+    profile_id: String,
+    stream: Option<Stream>,
+    scratch: Scratch,
+    /// indices of the matched rules
+    matched: BTreeSet<u32>,
+    pub scanned: usize,
+}
+
+// hyperscan streams and scratch spaces can be moved across threads, they just can't be used concurrently
+unsafe impl Send for BodyStream {}
+
+impl BodyStream {
+    /// opens a stream when the profile rules were compiled for streaming
+    pub fn open(
+        rules: Arc<HashMap<String, ContentFilterRules>>,
+        profile: &ContentFilterProfile,
+    ) -> anyhow::Result<Option<Self>> {
+        let db = match rules.get(&profile.id).and_then(|r| r.stream_db.as_ref()) {
+            None => return Ok(None),
+            Some(db) => db,
+        };
+        let scratch = db.alloc_scratch()?;
+        let stream = db.open_stream()?;
+        Ok(Some(BodyStream {
+            profile_id: profile.id.clone(),
+            rules,
+            stream: Some(stream),
+            scratch,
+            matched: BTreeSet::new(),
+            scanned: 0,
+        }))
+    }
+
+    /// scans a body chunk, returning the matches when they are enough to block the request
+    pub fn scan(
+        &mut self,
+        logs: &mut Logs,
+        profile: &ContentFilterProfile,
+        chunk: &[u8],
+    ) -> anyhow::Result<Option<Vec<BlockReason>>> {
+        let stream = match &self.stream {
+            None => return Ok(None),
+            Some(s) => s,
+        };
+        let matched = &mut self.matched;
+        stream.scan(chunk, &self.scratch, |id, _, _, _| {
+            matched.insert(id);
+            Matching::Continue
+        })?;
+        self.scanned += chunk.len();
+        let reasons = self.reasons(logs, profile);
+        Ok(if exceeds_threshold(profile, &reasons) {
+            Some(reasons)
+        } else {
+            None
+        })
+    }
+
+    /// closes the stream, returning all the matches
+    pub fn finish(mut self, logs: &mut Logs, profile: &ContentFilterProfile) -> anyhow::Result<Vec<BlockReason>> {
+        if let Some(stream) = self.stream.take() {
+            let matched = &mut self.matched;
+            // matches anchored at the end of the data are only reported when closing the stream
+            stream.close(&self.scratch, |id, _, _, _| {
+                matched.insert(id);
+                Matching::Continue
+            })?;
+        }
+        Ok(self.reasons(logs, profile))
+    }
+
+    fn reasons(&self, logs: &mut Logs, profile: &ContentFilterProfile) -> Vec<BlockReason> {
+        let rules = match self.rules.get(&self.profile_id) {
+            None => return Vec::new(),
+            Some(r) => r,
+        };
+        let mut out = Vec::new();
+        for id in &self.matched {
+            let sig = match rules.ids.get(*id as usize) {
+                None => {
+                    logs.error(|| format!("Should not happen, invalid hyperscan index {}", id));
+                    continue;
+                }
+                Some(sig) => sig,
+            };
+            let (specific_tags, all_tags) = rule_tags(sig);
+            if specific_tags.has_intersection(&profile.ignore) || all_tags.has_intersection(&profile.ignore) {
+                continue;
+            }
+            let action = if specific_tags.has_intersection(&profile.active) {
+                RawActionType::Custom
+            } else if specific_tags.has_intersection(&profile.report) {
+                RawActionType::Monitor
+            } else if all_tags.has_intersection(&profile.active) {
+                RawActionType::Custom
+            } else if all_tags.has_intersection(&profile.report) {
+                RawActionType::Monitor
+            } else {
+                continue;
+            };
+            out.push(BlockReason {
+                id: profile.id.clone(),
+                name: profile.name.clone(),
+                initiator: Initiator::ContentFilter {
+                    ruleid: sig.id.clone(),
+                    risk_level: sig.risk,
+                },
+                location: Location::Body,
+                action,
+                extra_locations: Vec::new(),
+                extra: if profile.anomaly_threshold.is_some() {
+                    serde_json::json!({ "score": sig.score })
+                } else {
+                    serde_json::Value::Null
+                },
+            });
+        }
+        out
+    }
+}
+
+impl Drop for BodyStream {
+    fn drop(&mut self) {
+        // streams are not freed when dropped, they must be closed
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.close(&self.scratch, Matching::Terminate);
+        }
+    }
+}
+
+/// the matches that still block the request once the rule exclusions of the profile are applied
+pub fn early_verdict(
+    logs: &mut Logs,
+    profile: &ContentFilterProfile,
+    path: &str,
+    tags: &mut Tags,
+    reasons: Vec<BlockReason>,
+) -> Option<Vec<BlockReason>> {
+    let mut reasons = reasons;
+    apply_exclusions(logs, profile, path, tags, &mut reasons);
+    if exceeds_threshold(profile, &reasons) {
+        Some(reasons)
+    } else {
+        None
+    }
+}
+
+/// true when the matches are enough to block the request, before the end of the stream
+fn exceeds_threshold(profile: &ContentFilterProfile, reasons: &[BlockReason]) -> bool {
+    if profile.mode.is_passive() {
+        return false;
+    }
+    match profile.anomaly_threshold {
+        None => is_blocking(reasons),
+        Some(threshold) => {
+            let score: u32 = reasons
+                .iter()
+                .filter(|r| r.action >= RawActionType::Custom)
+                .map(reason_score)
+                .sum();
+            score >= threshold
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::contentfilter::{convert_rule, resolve_rules, RuleExclusion};
+    use crate::config::raw::{RawContentFilterRule, RawRuleExclusion};
+
+    fn rules(profile: &ContentFilterProfile) -> Arc<HashMap<String, ContentFilterRules>> {
+        let rule = convert_rule(RawContentFilterRule {
+            id: "100".to_string(),
+            operand: "union\\s+select".to_string(),
+            risk: 5,
+            category: "sqli".to_string(),
+            subcategory: "union".to_string(),
+            tags: Default::default(),
+            score: None,
+        })
+        .unwrap();
+        let profiles = std::iter::once((profile.id.clone(), profile.clone())).collect();
+        Arc::new(resolve_rules(&mut Logs::default(), &profiles, vec![rule]))
+    }
+
+    #[test]
+    fn match_across_chunks() {
+        let mut profile = ContentFilterProfile::default_from_seed("seed");
+        profile.stream_body = true;
+        profile.active = std::iter::once("cf-rule-category:sqli".to_string()).collect();
+        let mut logs = Logs::default();
+        let mut stream = BodyStream::open(rules(&profile), &profile).unwrap().unwrap();
+        assert!(stream.scan(&mut logs, &profile, b"a=1 UNI").unwrap().is_none());
+        let reasons = stream.scan(&mut logs, &profile, b"ON SELECT 2").unwrap().unwrap();
+        assert_eq!(reasons.len(), 1);
+        assert_eq!(stream.scanned, 18);
+    }
+
+    #[test]
+    fn threshold_at_end() {
+        let mut profile = ContentFilterProfile::default_from_seed("seed");
+        profile.stream_body = true;
+        profile.active = std::iter::once("cf-rule-category:sqli".to_string()).collect();
+        profile.anomaly_threshold = Some(8);
+        let mut logs = Logs::default();
+        let mut stream = BodyStream::open(rules(&profile), &profile).unwrap().unwrap();
+        assert!(stream.scan(&mut logs, &profile, b"union select").unwrap().is_none());
+        let reasons = stream.finish(&mut logs, &profile).unwrap();
+        assert_eq!(reasons.len(), 1);
+        assert_eq!(reasons[0].extra["score"], 5);
+
+        profile.stream_body = false;
+        assert!(BodyStream::open(rules(&profile), &profile).unwrap().is_none());
+    }
+
+    #[test]
+    fn excluded_early_match() {
+        let mut profile = ContentFilterProfile::default_from_seed("seed");
+        profile.stream_body = true;
+        profile.active = std::iter::once("cf-rule-category:sqli".to_string()).collect();
+        let mut logs = Logs::default();
+        let mut stream = BodyStream::open(rules(&profile), &profile).unwrap().unwrap();
+        let reasons = stream.scan(&mut logs, &profile, b"union select").unwrap().unwrap();
+        let mut tags = Tags::new(&Default::default());
+        assert!(early_verdict(&mut logs, &profile, "/search", &mut tags, reasons.clone()).is_some());
+
+        profile.rule_exclusions = vec![RuleExclusion::resolve(RawRuleExclusion {
+            rule: "100".to_string(),
+            path: Some("/search".to_string()),
+            arg: None,
+        })
+        .unwrap()];
+        assert!(early_verdict(&mut logs, &profile, "/search", &mut tags, reasons).is_none());
+        assert!(tags.contains("cf-excluded:100"));
+    }
+}
@@ -0,0 +1,105 @@
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+
+use crate::flow::{flow_build_query, flow_resolve_query, FlowCheck, FlowResult};
+use crate::limit::{limit_build_query, limit_resolve_query, load_limit_scripts, parse_lease, LimitCheck, LimitResult};
+use crate::logs::Logs;
+use crate::memcached::memcached_backend;
+use crate::redis::{local_fallback_conn, redis_async_conn, RedisConn};
+
+lazy_static! {
+    /// selects the counter backend, one of "redis" (default), "memcached" or "memory"
+    static ref COUNTER_BACKEND: String = std::env::var("COUNTER_BACKEND").unwrap_or_else(|_| "redis".to_string());
+}
+
+/// A storage backend for the flow and limit counters
+#[async_trait]
+pub trait CounterBackend: Send {
+    /// increments the limit counters, and returns their current values
+    async fn resolve_limits(&mut self, logs: &mut Logs, checks: Vec<LimitCheck>) -> anyhow::Result<Vec<LimitResult>>;
+
+    /// checks the flow sequences, and advances them when the current step matches
+    async fn resolve_flows(&mut self, logs: &mut Logs, checks: Vec<FlowCheck>) -> anyhow::Result<Vec<FlowResult>>;
+
+    /// releases the in-flight leases taken by a request
+    async fn release_inflight(&mut self, leases: &[String]) -> anyhow::Result<()>;
+
+    /// current value of a counter, 0 when it does not exist
+    async fn counter_value(&mut self, key: &str) -> anyhow::Result<i64>;
+}
+
+/// the redis backend, also used for the in-memory store as it understands the same commands
+#[async_trait]
+impl CounterBackend for RedisConn {
+    async fn resolve_limits(&mut self, logs: &mut Logs, checks: Vec<LimitCheck>) -> anyhow::Result<Vec<LimitResult>> {
+        let mut pipe = redis::pipe();
+        limit_build_query(&mut pipe, &checks);
+        let res: Vec<Option<i64>> = match pipe.query_async(self).await {
+            // the script cache is empty after a restart, that also lost the counters, so counting this request
+            // twice in fixed windows does not matter
+            Err(rr) if rr.kind() == redis::ErrorKind::NoScriptError => {
+                logs.debug("loading the limit scripts");
+                load_limit_scripts(self).await?;
+                pipe.query_async(self).await?
+            }
+            r => r?,
+        };
+        limit_resolve_query(logs, self, &mut res.into_iter(), checks).await
+    }
+
+    async fn resolve_flows(&mut self, _logs: &mut Logs, checks: Vec<FlowCheck>) -> anyhow::Result<Vec<FlowResult>> {
+        let mut pipe = redis::pipe();
+        flow_build_query(&mut pipe, &checks);
+        // step timestamps pushed by older versions are not numbers, they are ignored
+        let res: Vec<redis::Value> = pipe.query_async(self).await?;
+        let mut values = res.iter().map(|v| redis::from_redis_value::<i64>(v).ok());
+        flow_resolve_query(self, &mut values, checks).await
+    }
+
+    async fn release_inflight(&mut self, leases: &[String]) -> anyhow::Result<()> {
+        let mut pipe = redis::pipe();
+        for lease in leases {
+            let (key, member) = parse_lease(lease);
+            pipe.cmd("ZREM").arg(key).arg(member).ignore();
+        }
+        pipe.query_async::<_, ()>(self).await?;
+        Ok(())
+    }
+
+    async fn counter_value(&mut self, key: &str) -> anyhow::Result<i64> {
+        let value: Option<i64> = redis::cmd("GET").arg(key).query_async(self).await?;
+        Ok(value.unwrap_or(0))
+    }
+}
+
+/// returns the configured counter backend
+pub async fn counter_backend() -> anyhow::Result<Box<dyn CounterBackend>> {
+    match COUNTER_BACKEND.as_str() {
+        "memcached" => Ok(Box::new(memcached_backend())),
+        "memory" => Ok(Box::new(local_fallback_conn())),
+        "redis" => Ok(Box::new(redis_async_conn().await?)),
+        other => Err(anyhow::anyhow!("unknown counter backend {}", other)),
+    }
+}
+
+/// releases the in-flight leases of a request, to be called by the proxy when the request completes
+pub async fn release_inflight(leases: &[String]) -> anyhow::Result<()> {
+    if leases.is_empty() {
+        return Ok(());
+    }
+    counter_backend().await?.release_inflight(leases).await
+}
+
+pub fn release_inflight_block(leases: &[String]) -> anyhow::Result<()> {
+    async_std::task::block_on(release_inflight(leases))
+}
+
+/// name of the configured counter backend
+pub fn counter_backend_name() -> &'static str {
+    COUNTER_BACKEND.as_str()
+}
+
+/// the backend used when the configured one is not available
+pub fn fallback_backend() -> Box<dyn CounterBackend> {
+    Box::new(local_fallback_conn())
+}
@@ -0,0 +1,201 @@
+//! CSRF protection.
+//!
+//! Tokens are issued in a cookie, along with a response header carrying the same value, that the
+//! application must echo back in a request header (double submit). A token is made of its expiration
+//! timestamp and a random nonce, signed with HMAC-SHA256, so that tokens can't be forged without the
+//! secret of the security policy entry.
+//!
+//! Requests using one of the protected methods must carry the same valid token in the cookie and the
+//! header.
+use rand::RngCore;
+use std::collections::{HashMap, HashSet};
+use subtle::ConstantTimeEq;
+
+use crate::config::raw::RawCsrfSettings;
+use crate::logs::Logs;
+use crate::utils::{hmac_sha256, RequestInfo};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsrfSettings {
+    pub secret: Vec<u8>,
+    pub cookie: String,
+    /// lowercase
+    pub header: String,
+    pub ttl: i64,
+    /// uppercase
+    pub methods: HashSet<String>,
+    pub enforce: bool,
+}
+
+impl CsrfSettings {
+    pub fn resolve(logs: &mut Logs, raw: RawCsrfSettings) -> Option<Self> {
+        if raw.secret.is_empty() {
+            logs.error("CSRF protection requires a secret, it is disabled");
+            return None;
+        }
+        let methods = if raw.methods.is_empty() {
+            ["POST", "PUT", "PATCH", "DELETE"]
+                .iter()
+                .map(|m| m.to_string())
+                .collect()
+        } else {
+            raw.methods.iter().map(|m| m.to_uppercase()).collect()
+        };
+        Some(CsrfSettings {
+            secret: raw.secret.into_bytes(),
+            cookie: raw.cookie.unwrap_or_else(|| "cf_csrf".to_string()),
+            header: raw
+                .header
+                .map(|h| h.to_lowercase())
+                .unwrap_or_else(|| "x-csrf-token".to_string()),
+            ttl: raw.ttl_seconds.unwrap_or(43200).clamp(1, i64::MAX as u64) as i64,
+            methods,
+            enforce: raw.enforce,
+        })
+    }
+}
+
+/// a token problem, used as the block reason
+pub type CsrfProblem = &'static str;
+
+fn sign(settings: &CsrfSettings, payload: &str) -> String {
+    hex::encode(hmac_sha256(&settings.secret, payload.as_bytes()))
+}
+
+/// mints a token, valid until now + ttl
+pub fn mint_token(settings: &CsrfSettings, now: i64) -> String {
+    let mut nonce = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let payload = format!("{}.{}", now.saturating_add(settings.ttl), hex::encode(nonce));
+    let signature = sign(settings, &payload);
+    format!("{}.{}", payload, signature)
+}
+
+/// checks the signature and expiration of a token, returning its expiration timestamp
+fn check_token(settings: &CsrfSettings, token: &str, now: i64) -> Result<i64, CsrfProblem> {
+    let (payload, signature) = token.rsplit_once('.').ok_or("malformed token")?;
+    if !bool::from(sign(settings, payload).as_bytes().ct_eq(signature.as_bytes())) {
+        return Err("invalid signature");
+    }
+    let expires: i64 = payload
+        .split('.')
+        .next()
+        .and_then(|e| e.parse().ok())
+        .ok_or("malformed token")?;
+    if expires < now {
+        return Err("expired token");
+    }
+    Ok(expires)
+}
+
+/// validates the token of a request, if its method is protected
+pub fn check_csrf(settings: &CsrfSettings, reqinfo: &RequestInfo) -> Result<(), CsrfProblem> {
+    if !settings.methods.contains(&reqinfo.rinfo.meta.method.to_uppercase()) {
+        return Ok(());
+    }
+    let cookie = reqinfo.cookies.get_str(&settings.cookie).ok_or("missing cookie")?;
+    let header = reqinfo.headers.get_str(&settings.header).ok_or("missing header")?;
+    if !bool::from(cookie.as_bytes().ct_eq(header.as_bytes())) {
+        return Err("token mismatch");
+    }
+    check_token(settings, cookie, reqinfo.timestamp.timestamp()).map(|_| ())
+}
+
+/// headers issuing a new token, unless the request cookie holds a token valid for more than half its lifetime
+///
+/// the proxy adds them to the response
+pub fn csrf_response_headers(settings: &CsrfSettings, reqinfo: &RequestInfo) -> HashMap<String, String> {
+    let now = reqinfo.timestamp.timestamp();
+    let current = reqinfo
+        .cookies
+        .get_str(&settings.cookie)
+        .and_then(|token| check_token(settings, token, now).ok());
+    let mut out = HashMap::new();
+    if current.map(|expires| expires - now > settings.ttl / 2).unwrap_or(false) {
+        return out;
+    }
+    let token = mint_token(settings, now);
+    out.insert(
+        "set-cookie".to_string(),
+        format!(
+            "{}={}; Max-Age={}; Path=/; Secure; SameSite=Strict",
+            settings.cookie, token, settings.ttl
+        ),
+    );
+    out.insert(settings.header.clone(), token);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze::{analyze_response, APhaseResp};
+    use crate::config::hostmap::SecurityPolicy;
+    use crate::config::virtualtags::VirtualTags;
+    use crate::interface::Tags;
+    use crate::testutils::{map_raw, raw_request};
+
+    fn settings() -> CsrfSettings {
+        CsrfSettings::resolve(
+            &mut Logs::default(),
+            RawCsrfSettings {
+                secret: "s3cr3t".to_string(),
+                cookie: None,
+                header: None,
+                ttl_seconds: Some(600),
+                methods: Vec::new(),
+                enforce: true,
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn tokens() {
+        let settings = settings();
+        let token = mint_token(&settings, 1000);
+        assert_eq!(check_token(&settings, &token, 1000), Ok(1600));
+        assert_eq!(check_token(&settings, &token, 1601), Err("expired token"));
+        let forged = token.replacen("1600", "9600", 1);
+        assert_eq!(check_token(&settings, &forged, 1000), Err("invalid signature"));
+        assert_eq!(check_token(&settings, "garbage", 1000), Err("malformed token"));
+        let mut other = settings.clone();
+        other.secret = b"other".to_vec();
+        assert_eq!(check_token(&other, &token, 1000), Err("invalid signature"));
+    }
+
+    fn response_headers(cookie: Option<String>) -> Vec<(String, String)> {
+        let mut secpol = SecurityPolicy::empty();
+        secpol.csrf = Some(settings());
+        let headers: Vec<(&str, &str)> = cookie.iter().map(|c| ("cookie", c.as_str())).collect();
+        let reqinfo = map_raw(secpol, &raw_request(&[], &headers));
+        let presp = APhaseResp {
+            reqinfo,
+            tags: Tags::new(&VirtualTags::default()),
+            status: 200,
+            headers: HashMap::new(),
+            body: None,
+        };
+        let mut added = analyze_response(&mut Logs::default(), presp).added_headers;
+        added.sort();
+        added
+    }
+
+    #[test]
+    fn response_issues_token() {
+        let added = response_headers(None);
+        assert_eq!(added.len(), 2);
+        let (cookie_name, cookie) = &added[0];
+        let (header_name, token) = &added[1];
+        assert_eq!(cookie_name, "set-cookie");
+        assert_eq!(header_name, "x-csrf-token");
+        assert!(cookie.starts_with(&format!("cf_csrf={};", token)));
+        assert!(check_token(&settings(), token, chrono::Utc::now().timestamp()).is_ok());
+    }
+
+    #[test]
+    fn response_keeps_fresh_token() {
+        let token = mint_token(&settings(), chrono::Utc::now().timestamp());
+        assert_eq!(response_headers(Some(format!("cf_csrf={}", token))), Vec::new());
+    }
+}
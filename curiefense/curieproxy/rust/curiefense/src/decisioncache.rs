@@ -0,0 +1,370 @@
+//! Decision outcome cache.
+//!
+//! Floods of identical malicious requests are expensive to analyze, as each of them goes through the
+//! content filter and the counter backend. When enabled on a security policy entry, blocking decisions are
+//! remembered for a short time, keyed on the client IP, method, URI, arguments, headers and cookies, so
+//! that repeats can be answered without running the analysis again. Flows and limits are still counted
+//! for the repeats.
+//!
+//! Decisions that depend on some state, such as challenges, rate limits, and the stages that track the client state
+//! in redis, are never cached. These stages still run for the repeats, so that their state is kept up to date.
+//!
+//! Each resolved security policy entry has its own cache, so a configuration reload drops all cached
+//! decisions.
+use sha2::{Digest, Sha224};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::globalfilter::SESSION_STAGE_TAGS;
+use crate::config::raw::{RawActionType, RawDecisionCache};
+use crate::interface::{BlockReason, Decision, Initiator, InitiatorKind, Tags};
+use crate::logs::Logs;
+use crate::utils::RequestInfo;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecisionCacheSettings {
+    pub ttl: Duration,
+    pub max_entries: usize,
+    /// a decision is cached when one of its reasons is of one of these kinds
+    pub kinds: HashSet<InitiatorKind>,
+}
+
+fn parse_kind(s: &str) -> anyhow::Result<InitiatorKind> {
+    match s {
+        "acl" => Ok(InitiatorKind::Acl),
+        "rate_limit" => Err(anyhow::anyhow!(
+            "rate limit decisions depend on counters, they can't be cached"
+        )),
+        "global_filter" => Ok(InitiatorKind::GlobalFilter),
+        "content_filter" => Ok(InitiatorKind::ContentFilter),
+        "restriction" => Ok(InitiatorKind::Restriction),
+        "schema" => Ok(InitiatorKind::Schema),
+        _ => Err(anyhow::anyhow!("invalid cacheable decision kind {:?}", s)),
+    }
+}
+
+impl DecisionCacheSettings {
+    pub fn resolve(logs: &mut Logs, raw: RawDecisionCache) -> Self {
+        let mut kinds = HashSet::new();
+        for k in raw.kinds {
+            match parse_kind(&k) {
+                Ok(kind) => {
+                    kinds.insert(kind);
+                }
+                Err(rr) => logs.error(|| rr.to_string()),
+            }
+        }
+        if kinds.is_empty() {
+            kinds.extend([
+                InitiatorKind::Acl,
+                InitiatorKind::ContentFilter,
+                InitiatorKind::Restriction,
+            ]);
+        }
+        DecisionCacheSettings {
+            ttl: Duration::from_secs(raw.ttl_seconds.unwrap_or(10).max(1)),
+            max_entries: raw.max_entries.unwrap_or(10000).max(1),
+            kinds,
+        }
+    }
+}
+
+type CacheKey = Vec<u8>;
+
+#[derive(Debug)]
+struct CacheEntry {
+    decision: Decision,
+    tags: Tags,
+    expires: Instant,
+    /// position in the recency order
+    stamp: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, CacheEntry>,
+    /// entries by last use, oldest first
+    recency: BTreeMap<u64, CacheKey>,
+    next_stamp: u64,
+}
+
+impl CacheState {
+    fn touch(&mut self, key: &[u8]) {
+        let stamp = self.next_stamp;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.recency.remove(&entry.stamp);
+            entry.stamp = stamp;
+            self.recency.insert(stamp, key.to_vec());
+            self.next_stamp += 1;
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.stamp);
+        }
+    }
+}
+
+/// challenges, counter based decisions, and ACL decisions on the tags of the client state must be evaluated for each
+/// request
+fn stateful(reason: &BlockReason) -> bool {
+    matches!(reason.action, RawActionType::Challenge | RawActionType::Ichallenge)
+        || match &reason.initiator {
+            Initiator::Limit { .. } | Initiator::Phase01Fail(_) | Initiator::Phase02 => true,
+            Initiator::Acl { tags, .. } => tags.iter().any(|t| SESSION_STAGE_TAGS.iter().any(|p| t.starts_with(p))),
+            _ => false,
+        }
+}
+
+/// a LRU cache of blocking decisions
+#[derive(Debug)]
+pub struct DecisionCache {
+    pub settings: DecisionCacheSettings,
+    state: Mutex<CacheState>,
+}
+
+impl DecisionCache {
+    pub fn new(settings: DecisionCacheSettings) -> Self {
+        DecisionCache {
+            settings,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    pub fn key(&self, reqinfo: &RequestInfo) -> CacheKey {
+        let mut hasher = Sha224::new();
+        let mut field = |s: &str| {
+            hasher.update(s.as_bytes());
+            hasher.update([0]);
+        };
+        field(&reqinfo.rinfo.geoip.ipstr);
+        field(&reqinfo.rinfo.meta.method);
+        field(&reqinfo.rinfo.meta.path);
+        // arguments include the body content
+        for fields in [&reqinfo.headers, &reqinfo.cookies, &reqinfo.rinfo.qinfo.args] {
+            let mut values: Vec<(&str, &str)> = fields.iter().collect();
+            values.sort_unstable();
+            // separates the field kinds
+            field(&values.len().to_string());
+            for (k, v) in values {
+                field(k);
+                field(v);
+            }
+        }
+        hasher.finalize().to_vec()
+    }
+
+    fn cacheable(&self, decision: &Decision) -> bool {
+        decision.is_final()
+            && !decision.reasons.iter().any(stateful)
+            && decision
+                .reasons
+                .iter()
+                .any(|r| r.initiator.to_kind().map(|k| self.settings.kinds.contains(&k)) == Some(true))
+    }
+
+    /// returns the cached decision, and the tags of the request that produced it
+    pub fn get(&self, key: &[u8]) -> Option<(Decision, Tags)> {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let expired = state.entries.get(key)?.expires <= Instant::now();
+        if expired {
+            state.remove(key);
+            return None;
+        }
+        state.touch(key);
+        state.entries.get(key).map(|e| (e.decision.clone(), e.tags.clone()))
+    }
+
+    /// stores a decision, if it is cacheable, returns true when it was stored
+    pub fn insert(&self, key: CacheKey, decision: &Decision, tags: &Tags) -> bool {
+        if !self.cacheable(decision) {
+            return false;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.remove(&key);
+        while state.entries.len() >= self.settings.max_entries {
+            match state.recency.iter().next().map(|(_, k)| k.clone()) {
+                Some(oldest) => state.remove(&oldest),
+                None => break,
+            }
+        }
+        let stamp = state.next_stamp;
+        state.next_stamp += 1;
+        state.recency.insert(stamp, key.clone());
+        state.entries.insert(
+            key,
+            CacheEntry {
+                decision: decision.clone(),
+                tags: tags.clone(),
+                expires: Instant::now() + self.settings.ttl,
+                stamp,
+            },
+        );
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entries
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// the decision cache of a request, carried through the analysis phases
+#[derive(Debug, Clone)]
+pub struct CacheLookup {
+    cache: Arc<DecisionCache>,
+    key: CacheKey,
+    /// reasons added by the stages that depend on the client state
+    stage_reasons: Vec<BlockReason>,
+    /// the cached decision, and the tags of the request that produced it
+    pub hit: Option<Box<(Decision, Tags)>>,
+}
+
+impl CacheLookup {
+    /// the key is computed before the client state stages run, as they can mask parts of the request
+    pub fn new(cache: Arc<DecisionCache>, reqinfo: &RequestInfo) -> Self {
+        let key = cache.key(reqinfo);
+        CacheLookup {
+            cache,
+            key,
+            stage_reasons: Vec::new(),
+            hit: None,
+        }
+    }
+
+    /// looks the decision up, once the client state stages ran, returns true on a hit
+    pub fn lookup(&mut self, stage_reasons: Vec<BlockReason>) -> bool {
+        self.stage_reasons = stage_reasons;
+        self.hit = self.cache.get(&self.key).map(Box::new);
+        self.hit.is_some()
+    }
+
+    /// stores the decision, unless it was served from the cache or one of its reasons comes from a client state
+    /// stage, returns true when it was stored
+    pub fn insert(&self, decision: &Decision, tags: &Tags) -> bool {
+        self.hit.is_none()
+            && !decision.reasons.iter().any(|r| self.stage_reasons.contains(r))
+            && self.cache.insert(self.key.clone(), decision, tags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::virtualtags::VirtualTags;
+    use crate::interface::{AclStage, BlockReason, Location};
+
+    fn mk_cache(max_entries: usize, kinds: &[&str]) -> DecisionCache {
+        let mut logs = Logs::default();
+        DecisionCache::new(DecisionCacheSettings::resolve(
+            &mut logs,
+            RawDecisionCache {
+                ttl_seconds: Some(60),
+                max_entries: Some(max_entries),
+                kinds: kinds.iter().map(|k| k.to_string()).collect(),
+            },
+        ))
+    }
+
+    fn acl_block() -> Decision {
+        let tags = Tags::new(&VirtualTags::default());
+        Decision::pass(vec![BlockReason::acl(
+            "acl".to_string(),
+            "acl".to_string(),
+            tags,
+            AclStage::Deny,
+        )])
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let cache = mk_cache(2, &["acl"]);
+        let tags = Tags::new(&VirtualTags::default());
+        let dec = acl_block();
+        assert!(cache.insert(b"a".to_vec(), &dec, &tags));
+        assert!(cache.insert(b"b".to_vec(), &dec, &tags));
+        // a is now the most recently used entry
+        assert!(cache.get(b"a").is_some());
+        assert!(cache.insert(b"c".to_vec(), &dec, &tags));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(b"b").is_none());
+        assert!(cache.get(b"a").is_some());
+        assert!(cache.get(b"c").is_some());
+    }
+
+    #[test]
+    fn cacheable_kinds() {
+        let mut tags = Tags::new(&VirtualTags::default());
+        tags.insert("x", Location::Request);
+        let dec = acl_block();
+        assert!(!mk_cache(10, &["content_filter"]).insert(b"a".to_vec(), &dec, &tags));
+        assert!(!mk_cache(10, &["acl"]).insert(b"a".to_vec(), &Decision::pass(Vec::new()), &tags));
+        let cache = mk_cache(10, &["acl", "invalid"]);
+        assert!(cache.insert(b"a".to_vec(), &dec, &tags));
+        let (_, cached_tags) = cache.get(b"a").unwrap();
+        assert!(cached_tags.contains("x"));
+    }
+
+    #[test]
+    fn stateful_decisions() {
+        let tags = Tags::new(&VirtualTags::default());
+        let cache = mk_cache(10, &["acl", "global_filter", "rate_limit"]);
+        let mut challenge = acl_block();
+        challenge.reasons[0].action = RawActionType::Challenge;
+        assert!(!cache.insert(b"a".to_vec(), &challenge, &tags));
+        let mut limited = acl_block();
+        limited.reasons.push(BlockReason::limit(
+            "limit".to_string(),
+            "limit".to_string(),
+            10,
+            RawActionType::Custom,
+        ));
+        assert!(!cache.insert(b"b".to_vec(), &limited, &tags));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn client_state_decisions() {
+        let cache = Arc::new(mk_cache(10, &["acl", "restriction"]));
+        let mut tags = Tags::new(&VirtualTags::default());
+        // ACL decision on a tag of the offender stage
+        tags.insert_qualified("offender", "high", Location::Ip);
+        let offender = Decision::pass(vec![BlockReason::acl(
+            "acl".to_string(),
+            "acl".to_string(),
+            tags.clone(),
+            AclStage::Deny,
+        )]);
+        assert!(!cache.insert(b"a".to_vec(), &offender, &tags));
+
+        // decision of a client state stage
+        let reqinfo = crate::testutils::request_info(&[], &[]);
+        let mut lookup = CacheLookup::new(cache.clone(), &reqinfo);
+        let replay = Decision::pass(vec![BlockReason::replay(
+            "entry".to_string(),
+            "entry".to_string(),
+            RawActionType::Custom,
+            "x-nonce".to_string(),
+            "replayed",
+        )]);
+        assert!(!lookup.lookup(replay.reasons.clone()));
+        assert!(!lookup.insert(&replay, &tags));
+        assert!(cache.is_empty());
+
+        let dec = acl_block();
+        assert!(lookup.insert(&dec, &tags));
+        let mut lookup = CacheLookup::new(cache.clone(), &reqinfo);
+        assert!(lookup.lookup(Vec::new()));
+        // a hit is not stored again
+        assert!(!lookup.insert(&dec, &tags));
+    }
+}
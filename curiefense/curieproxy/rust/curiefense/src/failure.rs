@@ -0,0 +1,118 @@
+//! Dependency failures.
+//!
+//! Redis, the challenge provider and hyperscan can be unavailable. Failures are tagged as
+//! dependency-failure:NAME, and the failure policy of the security policy entry decides what happens:
+//!
+//!  * fail_open: the check is skipped, or degraded, and the request goes on,
+//!  * monitor: same as fail_open, but a monitor reason is reported for each failure,
+//!  * fail_closed: the request is denied.
+use serde::Deserialize;
+
+use crate::config::hostmap::SecurityPolicy;
+use crate::config::raw::RawActionType;
+use crate::interface::{Action, BlockReason, Decision, Location, Tags};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailurePolicy {
+    #[default]
+    FailOpen,
+    FailClosed,
+    Monitor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dependency {
+    Redis,
+    Challenge,
+    Hyperscan,
+}
+
+const DEPENDENCIES: [Dependency; 3] = [Dependency::Redis, Dependency::Challenge, Dependency::Hyperscan];
+
+impl Dependency {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Dependency::Redis => "redis",
+            Dependency::Challenge => "challenge",
+            Dependency::Hyperscan => "hyperscan",
+        }
+    }
+
+    fn tag(&self) -> String {
+        format!("dependency-failure:{}", self.name())
+    }
+}
+
+/// tags the failure, so that outages are visible in the logs and statistics
+pub fn dependency_failed(tags: &mut Tags, dependency: Dependency) {
+    tags.insert("dependency-failure", Location::Request);
+    tags.insert(&dependency.tag(), Location::Request);
+}
+
+/// dependencies whose failure was tagged
+pub fn failed_dependencies(tags: &Tags) -> Vec<Dependency> {
+    DEPENDENCIES
+        .iter()
+        .copied()
+        .filter(|d| tags.contains(&d.tag()))
+        .collect()
+}
+
+/// the decision for failed dependencies, according to the failure policy of the security policy
+pub fn failure_decision(secpol: &SecurityPolicy, failed: &[Dependency]) -> Option<Decision> {
+    if failed.is_empty() {
+        return None;
+    }
+    let reasons = |action: RawActionType| {
+        failed
+            .iter()
+            .map(|d| {
+                BlockReason::dependency_failure(secpol.entry.id.clone(), secpol.entry.name.clone(), action, d.name())
+            })
+            .collect()
+    };
+    match secpol.failure_policy {
+        FailurePolicy::FailOpen => None,
+        FailurePolicy::Monitor => Some(Decision::pass(reasons(RawActionType::Monitor))),
+        FailurePolicy::FailClosed => Some(Decision::action(
+            Action {
+                content: "service unavailable".to_string(),
+                ..Action::default()
+            },
+            reasons(RawActionType::Custom),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::virtualtags::VirtualTags;
+
+    fn decision(policy: FailurePolicy, failed: &[Dependency]) -> Option<Decision> {
+        let mut secpol = SecurityPolicy::empty();
+        secpol.failure_policy = policy;
+        failure_decision(&secpol, failed)
+    }
+
+    #[test]
+    fn policies() {
+        let mut tags = Tags::new(&VirtualTags::default());
+        assert!(failed_dependencies(&tags).is_empty());
+        dependency_failed(&mut tags, Dependency::Hyperscan);
+        dependency_failed(&mut tags, Dependency::Redis);
+        assert!(tags.contains("dependency-failure"));
+        let failed = failed_dependencies(&tags);
+        assert_eq!(failed, vec![Dependency::Redis, Dependency::Hyperscan]);
+
+        assert!(decision(FailurePolicy::FailOpen, &failed).is_none());
+        assert!(decision(FailurePolicy::FailClosed, &[]).is_none());
+        let monitor = decision(FailurePolicy::Monitor, &failed).unwrap();
+        assert!(!monitor.is_final());
+        assert_eq!(monitor.reasons.len(), 2);
+        let closed = decision(FailurePolicy::FailClosed, &failed).unwrap();
+        assert!(closed.is_blocking());
+        assert_eq!(closed.maction.unwrap().status, 503);
+    }
+}
@@ -0,0 +1,119 @@
+//! Passive HTTP client fingerprinting.
+//!
+//! HTTP libraries and browsers send their headers in a stable order, with a stable casing. The fingerprint is
+//! a hash of the header names, in the order they were received, and of their casing. Headers that are added by
+//! proxies, or that depend on the request rather than on the client, are ignored.
+//!
+//! The proxy provides the header order with the `header-order` attribute. When it is missing, only the set of
+//! header names is hashed.
+use itertools::Itertools;
+use sha2::{Digest, Sha256};
+
+use crate::requestfields::RequestField;
+use crate::utils::RequestMeta;
+
+const VOLATILE_HEADERS: [&str; 9] = [
+    "cookie",
+    "content-length",
+    "content-type",
+    "forwarded",
+    "via",
+    "x-real-ip",
+    "x-request-id",
+    "traceparent",
+    "tracestate",
+];
+
+const VOLATILE_PREFIXES: [&str; 3] = [":", "x-forwarded-", "x-envoy-"];
+
+fn is_volatile(lname: &str) -> bool {
+    lname.is_empty() || VOLATILE_HEADERS.contains(&lname) || VOLATILE_PREFIXES.iter().any(|p| lname.starts_with(p))
+}
+
+/// l: lowercase, u: uppercase, c: capitalized words (Accept-Encoding), m: mixed
+fn casing(name: &str) -> char {
+    if !name.chars().any(|c| c.is_ascii_uppercase()) {
+        'l'
+    } else if !name.chars().any(|c| c.is_ascii_lowercase()) {
+        'u'
+    } else if name.split('-').all(|word| {
+        let mut chars = word.chars();
+        chars.next().map(|c| !c.is_ascii_lowercase()).unwrap_or(true) && chars.all(|c| !c.is_ascii_uppercase())
+    }) {
+        'c'
+    } else {
+        'm'
+    }
+}
+
+/// computes the client fingerprint of a request, as 12 hex characters
+pub fn http_fingerprint(meta: &RequestMeta, headers: &RequestField) -> String {
+    let names: Vec<String> = match &meta.header_order {
+        Some(order) => order
+            .iter()
+            .filter(|name| !is_volatile(&name.to_ascii_lowercase()))
+            .cloned()
+            .collect(),
+        None => headers
+            .fields
+            .keys()
+            .map(|name| name.to_ascii_lowercase())
+            .filter(|lname| !is_volatile(lname))
+            .sorted()
+            .collect(),
+    };
+    let order = names.iter().map(|name| name.to_ascii_lowercase()).join(",");
+    let casings: String = names.iter().map(|name| casing(name)).collect();
+    let digest = Sha256::digest(format!("{}|{}", order, casings).as_bytes());
+    digest.iter().take(6).map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::Location;
+    use std::collections::HashMap;
+
+    fn fingerprint(order: Option<&str>, headers: &[&str]) -> String {
+        let mut attrs: HashMap<String, String> = HashMap::new();
+        attrs.insert("method".to_string(), "GET".to_string());
+        attrs.insert("path".to_string(), "/".to_string());
+        if let Some(o) = order {
+            attrs.insert("header-order".to_string(), o.to_string());
+        }
+        let meta = RequestMeta::from_map(attrs).unwrap();
+        let mut fields = RequestField::new(&[]);
+        for h in headers {
+            fields.add(h.to_string(), Location::Header(h.to_string()), "x".to_string());
+        }
+        http_fingerprint(&meta, &fields)
+    }
+
+    #[test]
+    fn casings() {
+        assert_eq!(casing("accept-encoding"), 'l');
+        assert_eq!(casing("DNT"), 'u');
+        assert_eq!(casing("Accept-Encoding"), 'c');
+        assert_eq!(casing("User-agent"), 'm');
+        assert_eq!(casing("accept-Encoding"), 'm');
+    }
+
+    #[test]
+    fn order_and_casing() {
+        let curl = fingerprint(Some("Host,User-Agent,Accept"), &[]);
+        assert_eq!(curl.len(), 12);
+        assert_eq!(
+            curl,
+            fingerprint(Some("Host, User-Agent, Accept, X-Forwarded-For, Cookie"), &[])
+        );
+        assert_ne!(curl, fingerprint(Some("Host,Accept,User-Agent"), &[]));
+        assert_ne!(curl, fingerprint(Some("host,user-agent,accept"), &[]));
+    }
+
+    #[test]
+    fn unordered() {
+        let fp = fingerprint(None, &["user-agent", "accept", "host", "x-request-id"]);
+        assert_eq!(fp, fingerprint(None, &["accept", "host", "user-agent"]));
+        assert_eq!(fp, fingerprint(Some("accept,host,user-agent"), &["ignored"]));
+    }
+}
@@ -1,30 +1,40 @@
-use redis::aio::ConnectionManager;
+use crate::redis::RedisConn;
 
 use crate::interface::stats::{BStageFlow, BStageMapped, StatsCollect};
 use crate::Logs;
 
-use crate::config::flow::{FlowElement, FlowMap, SequenceKey};
-use crate::config::matchers::RequestSelector;
+use crate::config::flow::{FlowElement, FlowMap, SequenceKey, StepRoute};
+use crate::config::hostmap::CounterSettings;
 use crate::interface::{Location, Tags};
 use crate::redis::REDIS_KEY_PREFIX;
-use crate::utils::{check_selector_cond, select_string, RequestInfo};
+use crate::utils::{check_selector_cond, now_ms, select_string, RequestInfo};
 
 fn session_sequence_key(ri: &RequestInfo) -> SequenceKey {
     SequenceKey(ri.rinfo.meta.method.to_string() + &ri.rinfo.host + &ri.rinfo.qinfo.qpath)
 }
 
-fn build_redis_key(
-    reqinfo: &RequestInfo,
-    tags: &Tags,
-    key: &[RequestSelector],
-    entry_id: &str,
-    entry_name: &str,
-) -> Option<String> {
-    let mut tohash = entry_id.to_string() + entry_name;
-    for kpart in key.iter() {
+fn build_redis_key(reqinfo: &RequestInfo, tags: &Tags, elem: &FlowElement) -> Option<String> {
+    let mut tohash = elem.id.clone() + &elem.name;
+    for kpart in elem.key.iter() {
         tohash += &select_string(reqinfo, kpart, Some(tags))?;
     }
-    Some(format!("{}{:X}", *REDIS_KEY_PREFIX, md5::compute(tohash)))
+    // sequences started with other timing settings are not reused
+    if elem.step_ttl.is_some() || elem.max_duration.is_some() {
+        tohash += &format!("/{:?}/{:?}", elem.step_ttl, elem.max_duration);
+    }
+    Some(format!(
+        "{}{}{:X}",
+        *REDIS_KEY_PREFIX,
+        reqinfo.rinfo.secpolicy.counters.namespace,
+        md5::compute(tohash)
+    ))
+}
+
+fn route_match(ri: &RequestInfo, route: &StepRoute) -> bool {
+    let part_match = |expected: &Option<String>, actual: &str| expected.as_ref().map(|e| e == actual).unwrap_or(true);
+    part_match(&route.method, &ri.rinfo.meta.method)
+        && part_match(&route.host, &ri.rinfo.host)
+        && part_match(&route.path, &ri.rinfo.qinfo.qpath)
 }
 
 fn flow_match(reqinfo: &RequestInfo, tags: &Tags, elem: &FlowElement) -> bool {
@@ -34,7 +44,9 @@ fn flow_match(reqinfo: &RequestInfo, tags: &Tags, elem: &FlowElement) -> bool {
     if !(elem.include.is_empty() || elem.include.iter().any(|e| tags.contains(e))) {
         return false;
     }
-    elem.select.iter().all(|e| check_selector_cond(reqinfo, tags, e))
+    route_match(reqinfo, &elem.route)
+        && elem.step_tags.iter().all(|t| tags.contains(t))
+        && elem.select.iter().all(|e| check_selector_cond(reqinfo, tags, e))
 }
 
 #[derive(Clone)]
@@ -43,6 +55,34 @@ pub struct FlowResult {
     pub id: String,
     pub name: String,
     pub tags: Vec<String>,
+    /// set when the sequence in progress timed out, and was restarted
+    pub expired: Option<FlowExpiry>,
+}
+
+/// the reason why a sequence in progress was discarded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlowExpiry {
+    /// too much time since the previous step
+    Step,
+    /// too much time since the first step
+    Sequence,
+}
+
+impl FlowExpiry {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FlowExpiry::Step => "step",
+            FlowExpiry::Sequence => "sequence",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "step" => Some(FlowExpiry::Step),
+            "sequence" => Some(FlowExpiry::Sequence),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -61,50 +101,99 @@ pub struct FlowCheck {
     pub id: String,
     pub name: String,
     pub tags: Vec<String>,
+    /// maximum time between two consecutive steps, in seconds
+    pub step_ttl: Option<u64>,
+    /// maximum time between the first and the last step, in seconds
+    pub max_duration: Option<u64>,
+}
+
+impl FlowCheck {
+    /// the step timestamps are only queried when they are checked
+    pub fn timed(&self) -> bool {
+        self.step_ttl.is_some() || self.max_duration.is_some()
+    }
+
+    /// checks the timestamps of the latest and first steps of the sequence in progress, in milliseconds
+    pub fn expiry(&self, now: u64, latest: Option<i64>, first: Option<i64>) -> Option<FlowExpiry> {
+        let elapsed = |ts: Option<i64>| ts.map(|t| now.saturating_sub(t.max(0) as u64));
+        let too_long = |ts: Option<i64>, limit: Option<u64>| match (elapsed(ts), limit) {
+            (Some(e), Some(l)) => e > l * 1000,
+            _ => false,
+        };
+        if too_long(latest, self.step_ttl) {
+            Some(FlowExpiry::Step)
+        } else if too_long(first, self.max_duration) {
+            Some(FlowExpiry::Sequence)
+        } else {
+            None
+        }
+    }
 }
 
 pub fn flow_info(logs: &mut Logs, flows: &FlowMap, reqinfo: &RequestInfo, tags: &Tags) -> Vec<FlowCheck> {
     let sequence_key = session_sequence_key(reqinfo);
-    match flows.get(&sequence_key) {
-        None => Vec::new(),
-        Some(elems) => {
-            let mut out = Vec::new();
-            for elem in elems.iter() {
-                if !flow_match(reqinfo, tags, elem) {
-                    continue;
-                }
-                logs.debug(|| format!("Testing flow control {} (step {})", elem.name, elem.step));
-                match build_redis_key(reqinfo, tags, &elem.key, &elem.id, &elem.name) {
-                    Some(redis_key) => {
-                        out.push(FlowCheck {
-                            redis_key,
-                            step: elem.step,
-                            timeframe: elem.timeframe,
-                            is_last: elem.is_last,
-                            id: elem.id.clone(),
-                            name: elem.name.clone(),
-                            tags: elem.tags.clone(),
-                        });
-                    }
-                    None => logs.warning(|| format!("Could not fetch key in flow control {}", elem.name)),
-                }
+    // steps that are not bound to a path are tested on every request
+    let elems = flows
+        .get(&sequence_key)
+        .into_iter()
+        .chain(flows.get(&SequenceKey::any()))
+        .flatten();
+    let mut out = Vec::new();
+    for elem in elems {
+        if !flow_match(reqinfo, tags, elem) {
+            continue;
+        }
+        logs.debug(|| format!("Testing flow control {} (step {})", elem.name, elem.step));
+        match build_redis_key(reqinfo, tags, elem) {
+            Some(redis_key) => {
+                out.push(FlowCheck {
+                    redis_key,
+                    step: elem.step,
+                    timeframe: CounterSettings::scaled_ttl(
+                        elem.timeframe,
+                        reqinfo.rinfo.secpolicy.counters.flow_ttl_multiplier,
+                    ),
+                    is_last: elem.is_last,
+                    id: elem.id.clone(),
+                    name: elem.name.clone(),
+                    tags: elem.tags.clone(),
+                    step_ttl: elem.step_ttl,
+                    max_duration: elem.max_duration,
+                });
             }
-            out
+            None => logs.warning(|| format!("Could not fetch key in flow control {}", elem.name)),
         }
     }
+    out
 }
 
 pub async fn flow_resolve_query<I: Iterator<Item = Option<i64>>>(
-    redis: &mut ConnectionManager,
+    redis: &mut RedisConn,
     iter: &mut I,
     checks: Vec<FlowCheck>,
 ) -> anyhow::Result<Vec<FlowResult>> {
     let mut out = Vec::new();
+    let now = now_ms();
     for check in checks {
-        let listlen = match iter.next() {
+        let mut listlen = match iter.next() {
             None => anyhow::bail!("Empty iterator when checking {}", check.name),
             Some(l) => l.unwrap_or(0) as usize,
         };
+        let mut expired = None;
+        if check.timed() {
+            let (latest, first) = match (iter.next(), iter.next()) {
+                (Some(l), Some(f)) => (l, f),
+                _ => anyhow::bail!("Empty iterator when getting the timestamps of {}", check.name),
+            };
+            if listlen > 0 {
+                expired = check.expiry(now, latest, first);
+            }
+            if expired.is_some() {
+                // the sequence starts over
+                redis::cmd("DEL").arg(&check.redis_key).query_async(redis).await?;
+                listlen = 0;
+            }
+        }
         let tp = if check.is_last {
             if check.step as usize == listlen {
                 FlowResultType::LastOk
@@ -116,7 +205,7 @@ pub async fn flow_resolve_query<I: Iterator<Item = Option<i64>>>(
                 let (_, mexpire): ((), Option<i64>) = redis::pipe()
                     .cmd("LPUSH")
                     .arg(&check.redis_key)
-                    .arg("foo")
+                    .arg(now)
                     .cmd("TTL")
                     .arg(&check.redis_key)
                     .query_async(redis)
@@ -138,14 +227,24 @@ pub async fn flow_resolve_query<I: Iterator<Item = Option<i64>>>(
             name: check.name.clone(),
             id: check.id.clone(),
             tags: check.tags.clone(),
+            expired,
         });
     }
     Ok(out)
 }
 
+/// the lists hold the timestamps of the steps, the latest first
 pub fn flow_build_query(pipe: &mut redis::Pipeline, checks: &[FlowCheck]) {
     for check in checks {
         pipe.cmd("LLEN").arg(&check.redis_key);
+        if check.timed() {
+            pipe.cmd("LINDEX")
+                .arg(&check.redis_key)
+                .arg(0)
+                .cmd("LINDEX")
+                .arg(&check.redis_key)
+                .arg(-1);
+        }
     }
 }
 
@@ -156,6 +255,11 @@ pub fn flow_process(
     tags: &mut Tags,
 ) -> StatsCollect<BStageFlow> {
     for result in results {
+        if let Some(expiry) = result.expired {
+            tags.insert_qualified("fc-expired-id", &result.id, Location::Request);
+            tags.insert_qualified("fc-expired-name", &result.name, Location::Request);
+            tags.insert_qualified("fc-expired", expiry.name(), Location::Request);
+        }
         match result.tp {
             FlowResultType::LastOk => {
                 tags.insert_qualified("fc-id", &result.id, Location::Request);
@@ -170,3 +274,86 @@ pub fn flow_process(
     }
     stats.flow(flow_total, results.len())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::flow::flow_resolve;
+    use crate::config::hostmap::SecurityPolicy;
+    use crate::config::raw::RawFlowEntry;
+    use crate::config::virtualtags::VirtualTags;
+    use crate::testutils::{map_raw, raw_request};
+
+    fn mk_rinfo(method: &str, path: &str) -> RequestInfo {
+        let mut raw = raw_request(&[("method", method), ("path", path)], &[("host", "example.com")]);
+        raw.ipstr = "52.78.12.56".to_string();
+        map_raw(SecurityPolicy::default(), &raw)
+    }
+
+    #[test]
+    fn predicate_steps() {
+        let raw: RawFlowEntry = serde_json::from_value(serde_json::json!({
+            "id": "otp",
+            "name": "otp",
+            "include": [],
+            "exclude": [],
+            "key": [{"attrs": "ip"}],
+            "active": true,
+            "timeframe": 60,
+            "tags": [],
+            "sequence": [
+                {"method": "POST", "uri": "/otp/request", "headers": {"host": "example.com"}},
+                {"tags": ["otp-verify"]}
+            ]
+        }))
+        .unwrap();
+        let flows = flow_resolve(&mut Logs::default(), vec![raw]);
+        let mut tags = Tags::new(&VirtualTags::default());
+        let mut logs = Logs::default();
+
+        let request = flow_info(&mut logs, &flows, &mk_rinfo("POST", "/otp/request"), &tags);
+        assert_eq!(request.len(), 1);
+        assert_eq!(request[0].step, 0);
+
+        assert!(flow_info(&mut logs, &flows, &mk_rinfo("POST", "/otp/verify"), &tags).is_empty());
+        tags.insert("otp-verify", Location::Request);
+        let verify = flow_info(&mut logs, &flows, &mk_rinfo("POST", "/otp/verify"), &tags);
+        assert_eq!(verify.len(), 1);
+        assert_eq!(verify[0].step, 1);
+        assert!(verify[0].is_last);
+        assert_eq!(verify[0].redis_key, request[0].redis_key);
+    }
+
+    fn mk_check(step_ttl: Option<u64>, max_duration: Option<u64>) -> FlowCheck {
+        FlowCheck {
+            redis_key: "k".to_string(),
+            step: 1,
+            timeframe: 60,
+            is_last: false,
+            id: "otp".to_string(),
+            name: "otp".to_string(),
+            tags: Vec::new(),
+            step_ttl,
+            max_duration,
+        }
+    }
+
+    #[test]
+    fn expiry() {
+        let now = 100_000;
+        let untimed = mk_check(None, None);
+        assert!(!untimed.timed());
+        assert_eq!(untimed.expiry(now, Some(0), Some(0)), None);
+
+        let check = mk_check(Some(10), Some(30));
+        assert!(check.timed());
+        assert_eq!(check.expiry(now, Some(95_000), Some(80_000)), None);
+        assert_eq!(check.expiry(now, Some(85_000), Some(80_000)), Some(FlowExpiry::Step));
+        assert_eq!(
+            check.expiry(now, Some(95_000), Some(60_000)),
+            Some(FlowExpiry::Sequence)
+        );
+        // unreadable timestamps never expire
+        assert_eq!(check.expiry(now, None, None), None);
+    }
+}
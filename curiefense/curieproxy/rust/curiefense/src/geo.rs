@@ -3,21 +3,30 @@
 //! The intelligence is provided by through MaxMind GeoIP2 or ipinfo. By default
 //! MaxMind GeoIP2 is used with free database, but you can use ipinfo instead by
 //! setting the enviroment variable to `USE_IPINFO`.
+//!
+//! The MaxMind databases are reopened when their files change, on configuration reloads. The Anonymous-IP
+//! database is optional, and provides the VPN, Tor and hosting provider flags.
 
 use anyhow::anyhow;
+use arc_swap::ArcSwap;
 use ipnet::IpNet;
 use lazy_static::lazy_static;
 use maxminddb::{
-    geoip2::{Asn, City, Country},
+    geoip2::{AnonymousIp, Asn, City, Country},
     Reader,
 };
 use serde::Deserialize;
 
+#[cfg(not(test))]
+use maxminddb::MaxMindDBError;
 #[cfg(not(test))]
 use std::ops::Deref;
+use std::sync::Arc;
+use std::time::SystemTime;
 use std::{collections::HashMap, net::IpAddr, path::PathBuf};
 
 use crate::ipinfo::{AsnDetails, CarrierDetails, CompanyDetails, LocationDetails, PrivacyDetails};
+use crate::logs::Logs;
 
 /// From https://github.com/ipinfo/rust/blob/master/assets/countries.json
 const IPINFO_COUNTRY_NAME_RAW: &str = include_str!("../assets/ipinfo/countries.json");
@@ -26,11 +35,67 @@ const IPINFO_COUNTRY_IN_EU_RAW: &str = include_str!("../assets/ipinfo/eu.json");
 /// https://github.com/ipinfo/rust/blob/master/assets/continent.json
 const IPINFO_CONTINENT_RAW: &str = include_str!("../assets/ipinfo/continent.json");
 
+/// an opened MaxMind database, with the modification time of its file
+#[allow(dead_code)]
+struct MaxmindDb {
+    reader: Reader<Vec<u8>>,
+    modified: Option<SystemTime>,
+}
+
 #[allow(dead_code)]
+type MaxmindSlot = Result<Arc<MaxmindDb>, String>;
+
+#[allow(dead_code)]
+#[derive(Clone)]
 struct MaxmindGeo {
-    asn: Reader<Vec<u8>>,
-    country: Reader<Vec<u8>>,
-    city: Reader<Vec<u8>>,
+    asn: MaxmindSlot,
+    country: MaxmindSlot,
+    city: MaxmindSlot,
+    anonymous: MaxmindSlot,
+}
+
+#[allow(dead_code)]
+fn modification_time(path: &std::path::Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// opens a database, unless the file did not change since it was last opened
+#[allow(dead_code)]
+fn open_maxmind(path: PathBuf, current: Option<&MaxmindSlot>) -> MaxmindSlot {
+    let modified = modification_time(&path);
+    if let Some(Ok(db)) = current {
+        if modified.is_some() && db.modified == modified {
+            return Ok(db.clone());
+        }
+    }
+    Reader::open_readfile(&path)
+        .map(|reader| Arc::new(MaxmindDb { reader, modified }))
+        .map_err(|rr| format!("{}: {}", path.display(), rr))
+}
+
+#[allow(dead_code)]
+impl MaxmindGeo {
+    fn load(current: Option<&MaxmindGeo>) -> Self {
+        let maxmind_root =
+            std::env::var("MAXMIND_ROOT").unwrap_or_else(|_| "/cf-config/current/config/maxmind".to_string());
+        let path = |var: &str, default: &str| {
+            let mut p = PathBuf::from(&maxmind_root);
+            p.push(std::env::var(var).unwrap_or_else(|_| default.to_string()));
+            p
+        };
+        MaxmindGeo {
+            asn: open_maxmind(path("MAXMIND_ASN", "GeoLite2-ASN.mmdb"), current.map(|c| &c.asn)),
+            country: open_maxmind(
+                path("MAXMIND_COUNTRY", "GeoLite2-Country.mmdb"),
+                current.map(|c| &c.country),
+            ),
+            city: open_maxmind(path("MAXMIND_CITY", "GeoLite2-City.mmdb"), current.map(|c| &c.city)),
+            anonymous: open_maxmind(
+                path("MAXMIND_ANONYMOUS", "GeoIP2-Anonymous-IP.mmdb"),
+                current.map(|c| &c.anonymous),
+            ),
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -52,25 +117,7 @@ lazy_static! {
     // as they are lazy, these loads will not be triggered in test mode
     pub static ref USE_IPINFO: bool = std::env::var("USE_IPINFO").map(|s| s.parse().unwrap_or(false)).unwrap_or(false);
 
-    static ref MAXMIND: anyhow::Result<MaxmindGeo> = {
-        let maxmind_root = std::env::var("MAXMIND_ROOT").unwrap_or_else(|_| "/cf-config/current/config/maxmind".to_string());
-        let maxmind_asn = std::env::var("MAXMIND_ASN").unwrap_or_else(|_| "GeoLite2-ASN.mmdb".to_string());
-        let maxmind_country = std::env::var("MAXMIND_COUNTRY").unwrap_or_else(|_| "GeoLite2-Country.mmdb".to_string());
-        let maxmind_city = std::env::var("MAXMIND_CITY").unwrap_or_else(|_| "GeoLite2-City.mmdb".to_string());
-
-        let root_path = PathBuf::from(maxmind_root);
-        let mut asn_path = root_path.clone();
-        asn_path.push(maxmind_asn);
-        let mut country_path = root_path.clone();
-        country_path.push(maxmind_country);
-        let mut city_path = root_path;
-        city_path.push(maxmind_city);
-        Reader::open_readfile(asn_path)
-            .and_then(|asn| Reader::open_readfile(country_path)
-            .and_then(|country| Reader::open_readfile(city_path)
-            .map(|city| MaxmindGeo { asn, country, city } ))).map_err(|rr| anyhow!("{}", rr))
-    };
-
+    static ref MAXMIND: ArcSwap<MaxmindGeo> = ArcSwap::from_pointee(MaxmindGeo::load(None));
 
     static ref IPINFO: anyhow::Result<IpinfoGeo> = {
         let ipinfo_root = std::env::var("IPINFO_ROOT");
@@ -127,50 +174,106 @@ fn compute_network<T>(data: T, addr: IpAddr, prefix_len: usize) -> (T, Option<Ip
     (data, network)
 }
 
-/// Retrieves the english name of the country associated with this IP
+/// reopens the MaxMind databases whose files changed
 #[cfg(not(test))]
-pub fn get_maxmind_country(addr: IpAddr) -> Result<(Country<'static>, Option<IpNet>), String> {
+pub fn reload_maxmind(logs: &mut Logs) {
     if *USE_IPINFO {
-        return Err("Maxmind is not enabled. You can enable it by setting USE_IPINFO=false".to_string());
+        return;
     }
-
-    match MAXMIND.deref() {
-        Err(rr) => Err(format!("could not read country db: {}", rr)),
-        Ok(maxmind) => match maxmind.country.lookup_prefix(addr) {
-            Ok((country, prefix_len)) => Ok(compute_network::<Country>(country, addr, prefix_len)),
-            Err(rr) => Err(format!("{}", rr)),
-        },
+    let current = MAXMIND.load();
+    let reloaded = MaxmindGeo::load(Some(&current));
+    for (name, slot) in [
+        ("ASN", &reloaded.asn),
+        ("country", &reloaded.country),
+        ("city", &reloaded.city),
+    ] {
+        if let Err(rr) = slot {
+            logs.warning(|| format!("could not read {} db: {}", name, rr));
+        }
     }
+    MAXMIND.store(Arc::new(reloaded));
 }
 
 #[cfg(not(test))]
-pub fn get_maxmind_asn(addr: IpAddr) -> Result<(Asn<'static>, Option<IpNet>), String> {
+fn maxmind_lookup<R, S, F>(name: &str, select: S, f: F) -> Result<R, String>
+where
+    S: FnOnce(&MaxmindGeo) -> &MaxmindSlot,
+    F: FnOnce(&Reader<Vec<u8>>) -> Result<R, MaxMindDBError>,
+{
     if *USE_IPINFO {
         return Err("Maxmind is not enabled. You can enable it by setting USE_IPINFO=false".to_string());
     }
 
-    match MAXMIND.deref() {
-        Err(rr) => Err(format!("could not read ASN db: {}", rr)),
-        Ok(maxmind) => match maxmind.asn.lookup_prefix(addr) {
-            Ok((asn, prefix_len)) => Ok(compute_network::<Asn>(asn, addr, prefix_len)),
-            Err(rr) => Err(format!("{}", rr)),
-        },
+    let maxmind = MAXMIND.load();
+    match select(&maxmind) {
+        Err(rr) => Err(format!("could not read {} db: {}", name, rr)),
+        Ok(db) => f(&db.reader).map_err(|rr| format!("{}", rr)),
     }
 }
 
+/// Retrieves the english name of the country associated with this IP
 #[cfg(not(test))]
-pub fn get_maxmind_city(addr: IpAddr) -> Result<(City<'static>, Option<IpNet>), String> {
-    if *USE_IPINFO {
-        return Err("Maxmind is not enabled. You can enable it by setting USE_IPINFO=false".to_string());
-    }
+pub fn get_maxmind_country<R, F>(addr: IpAddr, f: F) -> Result<R, String>
+where
+    F: FnOnce(Country, Option<IpNet>) -> R,
+{
+    maxmind_lookup(
+        "country",
+        |m| &m.country,
+        |reader| {
+            reader
+                .lookup_prefix(addr)
+                .map(|(country, prefix_len)| f(country, IpNet::new(addr, prefix_len as u8).ok()))
+        },
+    )
+}
 
-    match MAXMIND.deref() {
-        Err(rr) => Err(format!("could not read city db: {}", rr)),
-        Ok(maxmind) => match maxmind.city.lookup_prefix(addr) {
-            Ok((city, prefix_len)) => Ok(compute_network::<City>(city, addr, prefix_len)),
-            Err(rr) => Err(format!("{}", rr)),
+#[cfg(not(test))]
+pub fn get_maxmind_asn<R, F>(addr: IpAddr, f: F) -> Result<R, String>
+where
+    F: FnOnce(Asn, Option<IpNet>) -> R,
+{
+    maxmind_lookup(
+        "ASN",
+        |m| &m.asn,
+        |reader| {
+            reader
+                .lookup_prefix(addr)
+                .map(|(asn, prefix_len)| f(asn, IpNet::new(addr, prefix_len as u8).ok()))
         },
-    }
+    )
+}
+
+#[cfg(not(test))]
+pub fn get_maxmind_city<R, F>(addr: IpAddr, f: F) -> Result<R, String>
+where
+    F: FnOnce(City, Option<IpNet>) -> R,
+{
+    maxmind_lookup(
+        "city",
+        |m| &m.city,
+        |reader| {
+            reader
+                .lookup_prefix(addr)
+                .map(|(city, prefix_len)| f(city, IpNet::new(addr, prefix_len as u8).ok()))
+        },
+    )
+}
+
+#[cfg(not(test))]
+pub fn get_maxmind_anonymous<R, F>(addr: IpAddr, f: F) -> Result<R, String>
+where
+    F: FnOnce(AnonymousIp, Option<IpNet>) -> R,
+{
+    maxmind_lookup(
+        "anonymous IP",
+        |m| &m.anonymous,
+        |reader| {
+            reader
+                .lookup_prefix(addr)
+                .map(|(anon, prefix_len)| f(anon, IpNet::new(addr, prefix_len as u8).ok()))
+        },
+    )
 }
 
 #[cfg(not(test))]
@@ -249,17 +352,37 @@ pub fn get_ipinfo_carrier(addr: IpAddr) -> Result<(CarrierDetails, Option<IpNet>
 }
 
 #[cfg(test)]
-pub fn get_maxmind_country(_addr: IpAddr) -> Result<(Country<'static>, Option<IpNet>), String> {
+pub fn reload_maxmind(_logs: &mut Logs) {}
+
+#[cfg(test)]
+pub fn get_maxmind_country<R, F>(_addr: IpAddr, _f: F) -> Result<R, String>
+where
+    F: FnOnce(Country, Option<IpNet>) -> R,
+{
+    Err("TEST".into())
+}
+
+#[cfg(test)]
+pub fn get_maxmind_asn<R, F>(_addr: IpAddr, _f: F) -> Result<R, String>
+where
+    F: FnOnce(Asn, Option<IpNet>) -> R,
+{
     Err("TEST".into())
 }
 
 #[cfg(test)]
-pub fn get_maxmind_asn(_addr: IpAddr) -> Result<(Asn<'static>, Option<IpNet>), String> {
+pub fn get_maxmind_city<R, F>(_addr: IpAddr, _f: F) -> Result<R, String>
+where
+    F: FnOnce(City, Option<IpNet>) -> R,
+{
     Err("TEST".into())
 }
 
 #[cfg(test)]
-pub fn get_maxmind_city(_addr: IpAddr) -> Result<(City<'static>, Option<IpNet>), String> {
+pub fn get_maxmind_anonymous<R, F>(_addr: IpAddr, _f: F) -> Result<R, String>
+where
+    F: FnOnce(AnonymousIp, Option<IpNet>) -> R,
+{
     Err("TEST".into())
 }
 
@@ -287,3 +410,64 @@ pub fn get_ipinfo_asn(_addr: IpAddr) -> Result<(AsnDetails, Option<IpNet>), Stri
 pub fn get_ipinfo_carrier(_addr: IpAddr) -> Result<(CarrierDetails, Option<IpNet>), String> {
     Err("TEST".into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn asn_db(dir: &std::path::Path) -> PathBuf {
+        let source = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(
+            "../../../images/confserver/bootstrap/confdb-initial-data/_internal_base/config/maxmind/GeoLite2-ASN.mmdb",
+        );
+        let path = dir.join("GeoLite2-ASN.mmdb");
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::copy(source, &path).unwrap();
+        path
+    }
+
+    #[test]
+    fn unchanged_db_is_kept() {
+        let dir = std::env::temp_dir().join(format!("cf-maxmind-kept-{}", std::process::id()));
+        let path = asn_db(&dir);
+        let first = open_maxmind(path.clone(), None);
+        let db = first.as_ref().unwrap().clone();
+        let second = open_maxmind(path, Some(&first));
+        assert!(Arc::ptr_eq(&db, second.as_ref().unwrap()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changed_db_is_reopened() {
+        let dir = std::env::temp_dir().join(format!("cf-maxmind-changed-{}", std::process::id()));
+        let path = asn_db(&dir);
+        let first = open_maxmind(path.clone(), None);
+        let db = first.as_ref().unwrap().clone();
+        let modified = db.modified.unwrap() + std::time::Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let second = open_maxmind(path, Some(&first));
+        let reopened = second.as_ref().unwrap();
+        assert!(!Arc::ptr_eq(&db, reopened));
+        assert_eq!(reopened.modified, Some(modified));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_db() {
+        let dir = std::env::temp_dir().join(format!("cf-maxmind-missing-{}", std::process::id()));
+        let path = asn_db(&dir);
+        let first = open_maxmind(path.clone(), None);
+        std::fs::remove_file(&path).unwrap();
+        // the database is gone, the slot reports it instead of serving the stale reader
+        let rr = open_maxmind(path.clone(), Some(&first)).err().unwrap();
+        assert!(rr.starts_with(&path.display().to_string()));
+        // and it is opened again once the file comes back
+        let path = asn_db(&dir);
+        assert!(open_maxmind(path, Some(&Err(rr))).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
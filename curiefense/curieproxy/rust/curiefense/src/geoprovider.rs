@@ -0,0 +1,400 @@
+//! Pluggable geographic lookup providers.
+//!
+//! The provider is selected in geo.json, and defaults to MaxMind, or ipinfo when `USE_IPINFO` is set. Deployments
+//! without a MaxMind license can use an IP2Location CSV database (DB1 to DB11, IPv4 or IPv6), or a simple CSV file
+//! mapping networks to countries:
+//!
+//! ```text
+//! # network,country_iso[,country_name]
+//! 192.0.2.0/24,FR
+//! 2001:db8::/32,DE,Germany
+//! ```
+//!
+//! The file based providers only fill the country, continent, EU membership, region and city fields.
+use arc_swap::ArcSwap;
+use ipnet::IpNet;
+use lazy_static::lazy_static;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::config::raw::RawGeoSettings;
+use crate::geo::{ipinfo_country_in_eu, ipinfo_resolve_continent, ipinfo_resolve_country_name, USE_IPINFO};
+use crate::logs::Logs;
+use crate::utils::{find_geoip_ipinfo, find_geoip_maxmind, GeoIp};
+
+/// a source of geographic information
+pub trait GeoProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// fills the fields of `geoip` known for this address
+    fn lookup(&self, logs: &mut Logs, geoip: &mut GeoIp, ip: IpAddr);
+}
+
+pub struct MaxmindProvider;
+
+impl GeoProvider for MaxmindProvider {
+    fn name(&self) -> &'static str {
+        "maxmind"
+    }
+
+    fn lookup(&self, logs: &mut Logs, geoip: &mut GeoIp, ip: IpAddr) {
+        find_geoip_maxmind(logs, geoip, ip)
+    }
+}
+
+pub struct IpinfoProvider;
+
+impl GeoProvider for IpinfoProvider {
+    fn name(&self) -> &'static str {
+        "ipinfo"
+    }
+
+    fn lookup(&self, logs: &mut Logs, geoip: &mut GeoIp, ip: IpAddr) {
+        find_geoip_ipinfo(logs, geoip, ip)
+    }
+}
+
+/// a range of addresses, as IPv6 integers, IPv4 addresses being mapped
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GeoRange {
+    start: u128,
+    end: u128,
+    /// upper case ISO code
+    country_iso: String,
+    country_name: Option<String>,
+    region: Option<String>,
+    city_name: Option<String>,
+}
+
+fn ip_key(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(v4) => u128::from(v4.to_ipv6_mapped()),
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+/// IP2Location IPv4 databases hold plain 32 bits integers
+fn ip2location_key(n: u128, ipv4: bool) -> u128 {
+    if ipv4 {
+        0xffff_0000_0000 | n
+    } else {
+        n
+    }
+}
+
+/// splits a CSV line, with optionally quoted fields
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                current.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    fields.push(current);
+    fields
+}
+
+/// IP2Location uses "-" for unknown values
+fn known(field: Option<&String>) -> Option<String> {
+    field
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty() && *s != "-")
+        .map(|s| s.to_string())
+}
+
+/// sorted, non overlapping, address ranges
+#[derive(Debug, Default)]
+pub struct RangeTable {
+    ranges: Vec<GeoRange>,
+}
+
+impl RangeTable {
+    fn build(logs: &mut Logs, source: &str, mut ranges: Vec<GeoRange>) -> Self {
+        ranges.sort_by_key(|r| (r.start, r.end));
+        let mut out: Vec<GeoRange> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match out.last() {
+                Some(previous) if previous.end >= range.start => {
+                    logs.warning(|| format!("{}: overlapping range for {}, skipped", source, range.country_iso))
+                }
+                _ => out.push(range),
+            }
+        }
+        RangeTable { ranges: out }
+    }
+
+    /// parses an IP2Location CSV database: ip_from, ip_to, country code, country name, region, city, ...
+    pub fn from_ip2location(logs: &mut Logs, source: &str, content: &str) -> Self {
+        let mut ranges = Vec::new();
+        for (lineno, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields = csv_fields(line);
+            let bounds = (
+                fields.first().and_then(|s| s.trim().parse::<u128>().ok()),
+                fields.get(1).and_then(|s| s.trim().parse::<u128>().ok()),
+            );
+            let (from, to) = match bounds {
+                (Some(from), Some(to)) if from <= to => (from, to),
+                _ => {
+                    logs.warning(|| format!("{}:{}: invalid range", source, lineno + 1));
+                    continue;
+                }
+            };
+            let country_iso = match known(fields.get(2)) {
+                Some(c) => c.to_uppercase(),
+                // unallocated or reserved ranges
+                None => continue,
+            };
+            let ipv4 = to <= u128::from(u32::MAX);
+            ranges.push(GeoRange {
+                start: ip2location_key(from, ipv4),
+                end: ip2location_key(to, ipv4),
+                country_iso,
+                country_name: known(fields.get(3)),
+                region: known(fields.get(4)),
+                city_name: known(fields.get(5)),
+            });
+        }
+        Self::build(logs, source, ranges)
+    }
+
+    /// parses a network,country_iso[,country_name] CSV file, lines starting with # are comments
+    pub fn from_cidr(logs: &mut Logs, source: &str, content: &str) -> Self {
+        let mut ranges = Vec::new();
+        for (lineno, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = csv_fields(line);
+            let network = fields.first().and_then(|s| {
+                let s = s.trim();
+                s.parse::<IpNet>()
+                    .ok()
+                    .or_else(|| s.parse::<IpAddr>().ok().map(IpNet::from))
+            });
+            let (network, country_iso) = match (network, known(fields.get(1))) {
+                (Some(n), Some(c)) => (n.trunc(), c.to_uppercase()),
+                _ => {
+                    logs.warning(|| format!("{}:{}: invalid entry", source, lineno + 1));
+                    continue;
+                }
+            };
+            ranges.push(GeoRange {
+                start: ip_key(network.network()),
+                end: ip_key(network.broadcast()),
+                country_iso,
+                country_name: known(fields.get(2)),
+                region: None,
+                city_name: None,
+            });
+        }
+        Self::build(logs, source, ranges)
+    }
+
+    fn get(&self, ip: IpAddr) -> Option<&GeoRange> {
+        let key = ip_key(ip);
+        let idx = self.ranges.partition_point(|r| r.start <= key);
+        idx.checked_sub(1).map(|i| &self.ranges[i]).filter(|r| key <= r.end)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+/// a provider backed by a file loaded in memory
+pub struct FileProvider {
+    name: &'static str,
+    table: RangeTable,
+}
+
+impl FileProvider {
+    pub fn load(logs: &mut Logs, name: &'static str, path: &Path) -> Option<Self> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(rr) => {
+                logs.error(|| format!("could not read geo database {}: {}", path.display(), rr));
+                return None;
+            }
+        };
+        let source = path.display().to_string();
+        let table = if name == "ip2location" {
+            RangeTable::from_ip2location(logs, &source, &content)
+        } else {
+            RangeTable::from_cidr(logs, &source, &content)
+        };
+        if table.is_empty() {
+            logs.error(|| format!("geo database {} has no entries", source));
+            return None;
+        }
+        logs.debug(|| format!("loaded {} ranges from {}", table.len(), source));
+        Some(FileProvider { name, table })
+    }
+}
+
+impl GeoProvider for FileProvider {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn lookup(&self, _logs: &mut Logs, geoip: &mut GeoIp, ip: IpAddr) {
+        let range = match self.table.get(ip) {
+            Some(r) => r,
+            None => return,
+        };
+        let iso = range.country_iso.as_str();
+        geoip.country_iso = Some(iso.to_lowercase());
+        geoip.country_name = range
+            .country_name
+            .clone()
+            .or_else(|| ipinfo_resolve_country_name(iso))
+            .map(|n| n.to_lowercase());
+        geoip.in_eu = Some(ipinfo_country_in_eu(iso));
+        if let Some(continent) = ipinfo_resolve_continent(iso) {
+            geoip.continent_code = Some(continent.code.to_string());
+            geoip.continent_name = Some(continent.name.to_lowercase());
+        }
+        geoip.region = range.region.clone();
+        geoip.city_name = range.city_name.as_ref().map(|c| c.to_lowercase());
+    }
+}
+
+fn default_provider() -> Box<dyn GeoProvider> {
+    if *USE_IPINFO {
+        Box::new(IpinfoProvider)
+    } else {
+        Box::new(MaxmindProvider)
+    }
+}
+
+lazy_static! {
+    static ref PROVIDER: ArcSwap<Box<dyn GeoProvider>> = ArcSwap::from_pointee(default_provider());
+}
+
+fn file_provider(logs: &mut Logs, name: &'static str, path: Option<&str>) -> Option<FileProvider> {
+    match path {
+        Some(path) => FileProvider::load(logs, name, Path::new(path)),
+        None => {
+            logs.error(|| format!("the {} geo provider requires a path", name));
+            None
+        }
+    }
+}
+
+/// selects the provider, keeping the current one when the new one can't be loaded
+pub fn configure_geo(logs: &mut Logs, raw: Option<RawGeoSettings>) {
+    let raw = match raw {
+        None => {
+            PROVIDER.store(Arc::new(default_provider()));
+            return;
+        }
+        Some(r) => r,
+    };
+    let provider: Box<dyn GeoProvider> = match raw.provider.to_lowercase().as_str() {
+        "maxmind" => Box::new(MaxmindProvider),
+        "ipinfo" => Box::new(IpinfoProvider),
+        "ip2location" => match file_provider(logs, "ip2location", raw.path.as_deref()) {
+            Some(p) => Box::new(p),
+            None => return,
+        },
+        "cidr" => match file_provider(logs, "cidr", raw.path.as_deref()) {
+            Some(p) => Box::new(p),
+            None => return,
+        },
+        unknown => {
+            logs.error(|| format!("unknown geo provider {}", unknown));
+            return;
+        }
+    };
+    PROVIDER.store(Arc::new(provider));
+}
+
+/// name of the current provider
+pub fn geo_provider_name() -> &'static str {
+    PROVIDER.load().name()
+}
+
+/// fills `geoip` with the current provider
+pub fn geo_lookup(logs: &mut Logs, geoip: &mut GeoIp, ip: IpAddr) {
+    PROVIDER.load().lookup(logs, geoip, ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(provider: &FileProvider, ip: &str) -> GeoIp {
+        let mut geoip = GeoIp::default();
+        provider.lookup(&mut Logs::default(), &mut geoip, ip.parse().unwrap());
+        geoip
+    }
+
+    #[test]
+    fn csv() {
+        assert_eq!(csv_fields("a,\"b, c\",\"d\"\"\""), vec!["a", "b, c", "d\""]);
+    }
+
+    #[test]
+    fn ip2location() {
+        let mut logs = Logs::default();
+        let content = r#""0","16777215","-","-","-","-"
+"16777216","16777471","AU","Australia","Queensland","Brisbane"
+"16777472","16778239","CN","China","Fujian","Fuzhou"
+"281470698520576","281470698520831","BQ","Bonaire, Sint Eustatius and Saba","-","-"
+"#;
+        let provider = FileProvider {
+            name: "ip2location",
+            table: RangeTable::from_ip2location(&mut logs, "test", content),
+        };
+        assert_eq!(provider.table.len(), 3);
+        let au = lookup(&provider, "1.0.0.12");
+        assert_eq!(au.country_iso.as_deref(), Some("au"));
+        assert_eq!(au.country_name.as_deref(), Some("australia"));
+        assert_eq!(au.city_name.as_deref(), Some("brisbane"));
+        assert_eq!(au.region.as_deref(), Some("Queensland"));
+        assert_eq!(lookup(&provider, "1.0.2.255").country_iso.as_deref(), Some("cn"));
+        assert_eq!(lookup(&provider, "1.0.4.0").country_iso, None);
+        assert_eq!(lookup(&provider, "0.0.0.1").country_iso, None);
+        // IPv6 databases hold IPv4 addresses as mapped addresses
+        let bq = lookup(&provider, "1.1.0.1");
+        assert_eq!(bq.country_name.as_deref(), Some("bonaire, sint eustatius and saba"));
+    }
+
+    #[test]
+    fn cidr() {
+        let mut logs = Logs::default();
+        let content = "# network,country\n192.0.2.0/24,fr\n192.0.2.128/25,DE\n2001:db8::/32,DE,Germany\n198.51.100.7,US\nbogus,XX\n";
+        let provider = FileProvider {
+            name: "cidr",
+            table: RangeTable::from_cidr(&mut logs, "test", content),
+        };
+        // the overlapping and invalid entries are skipped
+        assert_eq!(provider.table.len(), 3);
+        let fr = lookup(&provider, "192.0.2.200");
+        assert_eq!(fr.country_iso.as_deref(), Some("fr"));
+        assert_eq!(fr.in_eu, Some(true));
+        assert_eq!(
+            lookup(&provider, "2001:db8:1::1").country_name.as_deref(),
+            Some("germany")
+        );
+        assert_eq!(lookup(&provider, "198.51.100.7").country_iso.as_deref(), Some("us"));
+        assert_eq!(lookup(&provider, "198.51.100.8").country_iso, None);
+        assert_eq!(lookup(&provider, "2001:db9::1").country_iso, None);
+    }
+}
@@ -1,11 +1,19 @@
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 
+use crate::challenge::ChallengeProvider;
+use crate::failure::{failure_decision, Dependency, FailurePolicy};
 use crate::interface::BlockReason;
 use crate::logs::Logs;
 use crate::utils::RequestInfo;
 use crate::{Action, ActionType, Decision};
 use std::collections::HashMap;
 use std::ffi::{CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 #[repr(u8)]
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
@@ -29,7 +37,7 @@ impl PrecisionLevel {
 }
 
 #[repr(u8)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Copy, Clone, PartialEq, Eq)]
 pub enum GHMode {
     Passive,
     Active,
@@ -56,7 +64,7 @@ pub struct GHResponse {
     pub precision_level: PrecisionLevel,
     pub str_response: String,
     pub headers: HashMap<String, String>,
-    status_code: u32,
+    pub(crate) status_code: u32,
 }
 
 impl GHResponse {
@@ -70,14 +78,6 @@ impl GHResponse {
     }
 }
 
-pub trait Grasshopper {
-    fn is_human(&self, input: GHQuery) -> Result<PrecisionLevel, String>;
-    fn init_challenge(&self, input: GHQuery, mode: GHMode) -> Result<GHResponse, String>;
-    fn verify_challenge(&self, headers: HashMap<&str, &str>) -> Result<String, String>;
-    fn should_provide_app_sig(&self, headers: HashMap<&str, &str>) -> Result<GHResponse, String>;
-    fn handle_bio_report(&self, input: GHQuery, precision_level: PrecisionLevel) -> Result<GHResponse, String>;
-}
-
 mod imported {
     use super::{GHMode, PrecisionLevel};
     use std::os::raw::c_char;
@@ -102,7 +102,7 @@ mod imported {
 pub struct DummyGrasshopper {}
 
 // use this when grasshopper can't be used
-impl Grasshopper for DummyGrasshopper {
+impl ChallengeProvider for DummyGrasshopper {
     fn should_provide_app_sig(&self, _headers: HashMap<&str, &str>) -> Result<GHResponse, String> {
         Err("not implemented".into())
     }
@@ -127,7 +127,7 @@ impl Grasshopper for DummyGrasshopper {
 #[derive(Clone)]
 pub struct DynGrasshopper {}
 
-impl Grasshopper for DynGrasshopper {
+impl ChallengeProvider for DynGrasshopper {
     fn is_human(&self, input: GHQuery) -> Result<PrecisionLevel, String> {
         unsafe {
             let encoded_input = serde_json::to_vec(&input).map_err(|rr| rr.to_string())?;
@@ -230,6 +230,177 @@ impl Grasshopper for DynGrasshopper {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GrasshopperSettings {
+    pub timeout: Duration,
+}
+
+impl Default for GrasshopperSettings {
+    fn default() -> Self {
+        GrasshopperSettings {
+            timeout: Duration::from_millis(500),
+        }
+    }
+}
+
+/// threads running the guarded calls
+const GUARD_WORKERS: usize = 4;
+
+type GuardJob = Box<dyn FnOnce() + Send>;
+
+lazy_static! {
+    /// calls that hang keep their worker busy, no other thread is started for them
+    static ref GUARD_QUEUE: SyncSender<GuardJob> = {
+        let (tx, rx) = sync_channel::<GuardJob>(GUARD_WORKERS);
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..GUARD_WORKERS {
+            let rx = rx.clone();
+            let spawned = std::thread::Builder::new()
+                .name(format!("cf-guard-{}", i))
+                .spawn(move || loop {
+                    let job = match rx.lock() {
+                        Err(_) => return,
+                        Ok(queue) => queue.recv(),
+                    };
+                    match job {
+                        Err(_) => return,
+                        Ok(job) => job(),
+                    }
+                });
+            if let Err(rr) = spawned {
+                crate::logs::background_error(|| format!("could not start the guard workers: {}", rr));
+            }
+        }
+        tx
+    };
+}
+
+/// runs a call that can hang or panic on the guard workers, waiting for at most `timeout`
+///
+/// The caller waits on a channel, no executor is run. When all the workers are stuck, the call fails right away.
+pub fn run_guarded<T, F>(name: &'static str, timeout: Duration, f: F) -> Result<T, String>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, String> + Send + 'static,
+{
+    let (tx, rx) = sync_channel(1);
+    let job: GuardJob = Box::new(move || {
+        let res = catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| Err(format!("{} panicked", name)));
+        // the caller is gone when it timed out
+        let _ = tx.send(res);
+    });
+    GUARD_QUEUE
+        .try_send(job)
+        .map_err(|_| format!("{}: all the workers are busy", name))?;
+    rx.recv_timeout(timeout)
+        .unwrap_or_else(|_| Err(format!("{} timed out", name)))
+}
+
+/// an owned copy of a query, so that it can be sent to another thread
+struct OwnedQuery {
+    headers: HashMap<String, String>,
+    cookies: HashMap<String, String>,
+    ip: String,
+    protocol: String,
+}
+
+impl OwnedQuery {
+    fn new(input: &GHQuery) -> Self {
+        OwnedQuery {
+            headers: owned_headers(&input.headers),
+            cookies: owned_headers(&input.cookies),
+            ip: input.ip.to_string(),
+            protocol: input.protocol.to_string(),
+        }
+    }
+
+    fn query(&self) -> GHQuery<'_> {
+        GHQuery {
+            headers: borrowed_headers(&self.headers),
+            cookies: borrowed_headers(&self.cookies),
+            ip: &self.ip,
+            protocol: &self.protocol,
+        }
+    }
+}
+
+fn owned_headers(headers: &HashMap<&str, &str>) -> HashMap<String, String> {
+    headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+fn borrowed_headers(headers: &HashMap<String, String>) -> HashMap<&str, &str> {
+    headers.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect()
+}
+
+/// the grasshopper library, called on the guard workers so that calls that hang or panic can't take the request
+/// path down with them
+///
+/// Hung calls keep a worker busy, but the request goes on once the timeout expires.
+pub struct GuardedGrasshopper {
+    pub settings: GrasshopperSettings,
+    /// time spent waiting for the library, in microseconds
+    elapsed: AtomicU64,
+}
+
+impl GuardedGrasshopper {
+    pub fn new(settings: GrasshopperSettings) -> Self {
+        GuardedGrasshopper {
+            settings,
+            elapsed: AtomicU64::new(0),
+        }
+    }
+
+    fn guarded<T, F>(&self, name: &'static str, f: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&DynGrasshopper) -> Result<T, String> + Send + 'static,
+    {
+        let start = Instant::now();
+        let res = run_guarded(name, self.settings.timeout, move || f(&DynGrasshopper {}))
+            .map_err(|rr| format!("grasshopper {}", rr));
+        self.elapsed
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        res
+    }
+}
+
+impl ChallengeProvider for GuardedGrasshopper {
+    fn is_human(&self, input: GHQuery) -> Result<PrecisionLevel, String> {
+        let input = OwnedQuery::new(&input);
+        self.guarded("is_human", move |gh| gh.is_human(input.query()))
+    }
+
+    fn init_challenge(&self, input: GHQuery, mode: GHMode) -> Result<GHResponse, String> {
+        let input = OwnedQuery::new(&input);
+        self.guarded("init_challenge", move |gh| gh.init_challenge(input.query(), mode))
+    }
+
+    fn verify_challenge(&self, headers: HashMap<&str, &str>) -> Result<String, String> {
+        let headers = owned_headers(&headers);
+        self.guarded("verify_challenge", move |gh| {
+            gh.verify_challenge(borrowed_headers(&headers))
+        })
+    }
+
+    fn should_provide_app_sig(&self, headers: HashMap<&str, &str>) -> Result<GHResponse, String> {
+        let headers = owned_headers(&headers);
+        self.guarded("should_provide_app_sig", move |gh| {
+            gh.should_provide_app_sig(borrowed_headers(&headers))
+        })
+    }
+
+    fn handle_bio_report(&self, input: GHQuery, precision_level: PrecisionLevel) -> Result<GHResponse, String> {
+        let input = OwnedQuery::new(&input);
+        self.guarded("handle_bio_report", move |gh| {
+            gh.handle_bio_report(input.query(), precision_level)
+        })
+    }
+
+    fn latency(&self) -> Duration {
+        Duration::from_micros(self.elapsed.load(Ordering::Relaxed))
+    }
+}
+
 pub fn gh_fail_decision(reason: &str) -> Decision {
     Decision::action(
         Action {
@@ -239,12 +410,16 @@ pub fn gh_fail_decision(reason: &str) -> Decision {
             status: 500,
             content: "internal_error".to_string(),
             extra_tags: None,
+            response_headers: None,
+            delay: None,
+            transform: None,
+            ban: None,
         },
         vec![BlockReason::phase01_unknown(reason)],
     )
 }
 
-pub fn challenge_phase01<GH: Grasshopper>(
+pub fn challenge_phase01<GH: ChallengeProvider>(
     gh: &GH,
     logs: &mut Logs,
     rinfo: &RequestInfo,
@@ -264,7 +439,19 @@ pub fn challenge_phase01<GH: Grasshopper>(
         }
         Err(rr) => {
             logs.error(|| format!("Challenge phase01 error {}", rr));
-            return gh_fail_decision(&rr);
+            let secpol = &rinfo.rinfo.secpolicy;
+            if secpol.failure_policy == FailurePolicy::FailClosed {
+                return gh_fail_decision(&rr);
+            }
+            // the request is not challenged, monitor policies report the failure
+            let mut reasons = reasons;
+            for reason in reasons.iter_mut() {
+                reason.action.inactive();
+            }
+            if let Some(failure) = failure_decision(secpol, &[Dependency::Challenge]) {
+                reasons.extend(failure.reasons);
+            }
+            return Decision::pass(reasons);
         }
     };
     Decision::action(
@@ -275,12 +462,16 @@ pub fn challenge_phase01<GH: Grasshopper>(
             status: 247,
             content: gh_response.str_response,
             extra_tags: Some(["challenge_phase01"].iter().map(|s| s.to_string()).collect()),
+            response_headers: None,
+            delay: None,
+            transform: None,
+            ban: None,
         },
         reasons,
     )
 }
 
-pub fn challenge_phase02<GH: Grasshopper>(gh: &GH, logs: &mut Logs, reqinfo: &RequestInfo) -> Option<Decision> {
+pub fn challenge_phase02<GH: ChallengeProvider>(gh: &GH, logs: &mut Logs, reqinfo: &RequestInfo) -> Option<Decision> {
     if !reqinfo
         .rinfo
         .qinfo
@@ -316,12 +507,16 @@ pub fn challenge_phase02<GH: Grasshopper>(gh: &GH, logs: &mut Logs, reqinfo: &Re
             status: 248,
             content: "{}".to_string(),
             extra_tags: Some(["challenge_phase02"].iter().map(|s| s.to_string()).collect()),
+            response_headers: None,
+            delay: None,
+            transform: None,
+            ban: None,
         },
         vec![],
     ))
 }
 
-pub fn check_app_sig<GH: Grasshopper>(gh: &GH, logs: &mut Logs, reqinfo: &RequestInfo) -> Option<Decision> {
+pub fn check_app_sig<GH: ChallengeProvider>(gh: &GH, logs: &mut Logs, reqinfo: &RequestInfo) -> Option<Decision> {
     if !reqinfo
         .rinfo
         .qinfo
@@ -346,12 +541,16 @@ pub fn check_app_sig<GH: Grasshopper>(gh: &GH, logs: &mut Logs, reqinfo: &Reques
             status: gh_response.status_code,
             content: "{}".to_string(),
             extra_tags: Some(["check_app_sig"].iter().map(|s| s.to_string()).collect()),
+            response_headers: None,
+            delay: None,
+            transform: None,
+            ban: None,
         },
         vec![],
     ))
 }
 
-pub fn handle_bio_reports<GH: Grasshopper>(
+pub fn handle_bio_reports<GH: ChallengeProvider>(
     gh: &GH,
     logs: &mut Logs,
     reqinfo: &RequestInfo,
@@ -388,7 +587,37 @@ pub fn handle_bio_reports<GH: Grasshopper>(
             status: gh_response.status_code, //todo?
             content: gh_response.str_response,
             extra_tags: Some(["handle_bio_reports"].iter().map(|s| s.to_string()).collect()),
+            response_headers: None,
+            delay: None,
+            transform: None,
+            ban: None,
         },
         vec![],
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guarded_calls() {
+        let timeout = Duration::from_millis(200);
+        assert_eq!(run_guarded("ok", timeout, || Ok(42)), Ok(42));
+        assert_eq!(
+            run_guarded::<(), _>("failing", timeout, || Err("failed".to_string())),
+            Err("failed".to_string())
+        );
+        assert_eq!(
+            run_guarded::<(), _>("panicking", timeout, || panic!("oops")),
+            Err("panicking panicked".to_string())
+        );
+        assert_eq!(
+            run_guarded("hung", Duration::from_millis(10), || {
+                std::thread::sleep(Duration::from_millis(100));
+                Ok(())
+            }),
+            Err("hung timed out".to_string())
+        );
+    }
+}
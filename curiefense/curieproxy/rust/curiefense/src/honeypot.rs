@@ -0,0 +1,185 @@
+//! Honeypot paths and hidden form field traps.
+//!
+//! A security policy entry can list decoy paths (path templates, such as `/wp-admin/**`) and decoy fields, hidden
+//! form fields that browsers submit empty. Legitimate users never touch them, so a request hitting a decoy path,
+//! or filling a decoy field, marks its IP address, and its session when session tracking is enabled, in redis for
+//! `ttl_seconds` (one day by default).
+//!
+//! Marked clients are tagged with `honeypot` on all their requests, and the trapping request with
+//! `honeypot-trap:path` or `honeypot-trap:field`. The marks are loaded before the session stage, so that ACL
+//! profiles, and global filters matching on the `honeypot` tags, can block the subsequent traffic.
+use crate::analyze::APhase0;
+use crate::clientstate::{Pending, StateQuery, StateReplies};
+use crate::config::pathtrie::PathTemplate;
+use crate::config::raw::RawHoneypot;
+use crate::failure::{dependency_failed, Dependency};
+use crate::interface::Location;
+use crate::logs::Logs;
+use crate::redis::REDIS_KEY_PREFIX;
+use crate::session::session_id;
+use crate::utils::RequestInfo;
+
+/// tag of the clients that touched a trap, set on all their requests until the mark expires
+pub const HONEYPOT_TAG: &str = "honeypot";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HoneypotSettings {
+    pub paths: Vec<PathTemplate>,
+    pub fields: Vec<String>,
+    pub ttl: u64,
+}
+
+/// what the request touched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    Path,
+    Field,
+}
+
+impl HoneypotSettings {
+    pub fn resolve(logs: &mut Logs, raw: RawHoneypot) -> Option<Self> {
+        let mut paths = Vec::new();
+        for p in raw.paths {
+            match p.parse() {
+                Ok(tpl) => paths.push(tpl),
+                Err(rr) => logs.error(|| format!("honeypot path: {}", rr)),
+            }
+        }
+        if paths.is_empty() && raw.fields.is_empty() {
+            return None;
+        }
+        Some(HoneypotSettings {
+            paths,
+            fields: raw.fields,
+            ttl: raw.ttl_seconds.unwrap_or(86400).max(1),
+        })
+    }
+
+    /// the trap touched by the request, decoy fields only trigger when they are filled
+    pub fn trap(&self, reqinfo: &RequestInfo) -> Option<Trap> {
+        let path = &reqinfo.rinfo.qinfo.normalized.path;
+        if self.paths.iter().any(|tpl| tpl.captures(path).is_some()) {
+            return Some(Trap::Path);
+        }
+        let args = &reqinfo.rinfo.qinfo.args;
+        if self
+            .fields
+            .iter()
+            .any(|f| args.get_str(f).map(|v| !v.is_empty()).unwrap_or(false))
+        {
+            return Some(Trap::Field);
+        }
+        None
+    }
+}
+
+impl Trap {
+    fn name(&self) -> &'static str {
+        match self {
+            Trap::Path => "path",
+            Trap::Field => "field",
+        }
+    }
+}
+
+/// keys of the marks of the client, by IP and by session
+fn mark_keys(reqinfo: &RequestInfo) -> Vec<String> {
+    let secpolicy = &reqinfo.rinfo.secpolicy;
+    let prefix = format!("{}{}honeypot_", *REDIS_KEY_PREFIX, secpolicy.counters.namespace);
+    let mut out = vec![format!("{}ip_{}", prefix, reqinfo.rinfo.geoip.ipstr)];
+    if let Some(id) = secpolicy.session_tracking.as_ref().and_then(|s| session_id(s, reqinfo)) {
+        out.push(format!("{}session_{}", prefix, id));
+    }
+    out
+}
+
+/// the mark commands of a request
+#[derive(Debug)]
+pub struct HoneypotQuery {
+    pending: Pending,
+    trapped: bool,
+}
+
+/// tags the trapping request, and adds the commands that set the marks of trapped clients, or look them up
+pub fn query_honeypot(p0: &mut APhase0, query: &mut StateQuery) -> Option<HoneypotQuery> {
+    let secpolicy = p0.reqinfo.rinfo.secpolicy.clone();
+    let settings = secpolicy.honeypot.as_ref()?;
+    let trap = settings.trap(&p0.reqinfo);
+    if let Some(t) = trap {
+        p0.itags.insert_qualified("honeypot-trap", t.name(), Location::Request);
+        // the trapping request is tagged even if the mark can't be stored
+        p0.itags.insert(HONEYPOT_TAG, Location::Request);
+    }
+    let keys = mark_keys(&p0.reqinfo);
+    let pending = query.add(|pipe| {
+        if trap.is_some() {
+            for key in &keys {
+                pipe.cmd("SET").arg(key).arg(1).arg("EX").arg(settings.ttl).ignore();
+            }
+            0
+        } else {
+            pipe.cmd("EXISTS").arg(&keys);
+            1
+        }
+    });
+    Some(HoneypotQuery {
+        pending,
+        trapped: trap.is_some(),
+    })
+}
+
+/// tags the requests of marked clients
+pub fn apply_honeypot(logs: &mut Logs, p0: &mut APhase0, query: HoneypotQuery, replies: &StateReplies) {
+    let marked = if query.trapped {
+        replies.get::<()>(query.pending).map(|()| true)
+    } else {
+        replies.get::<(u64,)>(query.pending).map(|(found,)| found > 0)
+    };
+    match marked {
+        Ok(true) => p0.itags.insert(HONEYPOT_TAG, Location::Request),
+        Ok(false) => (),
+        Err(rr) => {
+            logs.error(|| format!("honeypot: {}", rr));
+            p0.itags.insert("honeypot-degraded", Location::Request);
+            dependency_failed(&mut p0.itags, Dependency::Redis);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::request_info;
+
+    fn mk_reqinfo(path: &str) -> RequestInfo {
+        request_info(&[("path", path)], &[])
+    }
+
+    #[test]
+    fn traps() {
+        let settings = HoneypotSettings::resolve(
+            &mut Logs::default(),
+            RawHoneypot {
+                paths: vec!["/wp-admin/**".to_string(), "invalid".to_string()],
+                fields: vec!["website".to_string()],
+                ttl_seconds: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(settings.paths.len(), 1);
+        assert_eq!(settings.ttl, 86400);
+        assert_eq!(settings.trap(&mk_reqinfo("/wp-admin/setup.php")), Some(Trap::Path));
+        assert_eq!(settings.trap(&mk_reqinfo("/contact?website=spam")), Some(Trap::Field));
+        // browsers submit hidden fields empty
+        assert_eq!(settings.trap(&mk_reqinfo("/contact?website=")), None);
+        assert_eq!(settings.trap(&mk_reqinfo("/admin")), None);
+        assert_eq!(mark_keys(&mk_reqinfo("/")).len(), 1);
+
+        let empty = RawHoneypot {
+            paths: Vec::new(),
+            fields: Vec::new(),
+            ttl_seconds: Some(60),
+        };
+        assert_eq!(HoneypotSettings::resolve(&mut Logs::default(), empty), None);
+    }
+}
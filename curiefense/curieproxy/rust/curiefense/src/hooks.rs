@@ -0,0 +1,213 @@
+//! Analysis hooks.
+//!
+//! Integrators can add custom logic to the analysis pipeline by registering implementations of the
+//! `AnalyzeHook` trait. The registered hooks are attached to the configuration when it is loaded, so
+//! registering a hook takes effect on the next configuration (re)load, and a request only ever sees the
+//! hooks of the configuration it was matched with.
+//!
+//! Hooks run in registration order. They can add tags, and return a decision, in which case the
+//! remaining hooks of the phase are skipped and the decision is merged with the current one. Blocking
+//! decisions end the analysis.
+use lazy_static::lazy_static;
+use std::sync::{Arc, RwLock};
+
+use crate::interface::{Decision, Tags};
+use crate::logs::Logs;
+use crate::utils::RequestInfo;
+
+lazy_static! {
+    static ref REGISTERED_HOOKS: RwLock<Vec<Arc<dyn AnalyzeHook>>> = RwLock::new(Vec::new());
+}
+
+pub trait AnalyzeHook: Send + Sync {
+    /// name of the hook, used in logs, registering a hook with the same name replaces it
+    fn name(&self) -> &str;
+
+    /// called before the security policy checks, once global filters and session tags are known
+    fn on_init(&self, _logs: &mut Logs, _reqinfo: &RequestInfo, _tags: &mut Tags) -> Option<Decision> {
+        None
+    }
+
+    /// called once flow and limit tags are set, before the ACL is checked
+    fn on_pre_acl(&self, _logs: &mut Logs, _reqinfo: &RequestInfo, _tags: &mut Tags) -> Option<Decision> {
+        None
+    }
+
+    /// called before the content filter is run
+    fn on_pre_contentfilter(&self, _logs: &mut Logs, _reqinfo: &RequestInfo, _tags: &mut Tags) -> Option<Decision> {
+        None
+    }
+
+    /// called with the final decision, when all checks were run
+    fn on_finish(
+        &self,
+        _logs: &mut Logs,
+        _reqinfo: &RequestInfo,
+        _tags: &mut Tags,
+        _decision: &Decision,
+    ) -> Option<Decision> {
+        None
+    }
+}
+
+/// registers a hook, it will be used starting with the next configuration load
+pub fn register_hook(hook: Arc<dyn AnalyzeHook>) {
+    let mut hooks = REGISTERED_HOOKS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    match hooks.iter_mut().find(|h| h.name() == hook.name()) {
+        Some(existing) => *existing = hook,
+        None => hooks.push(hook),
+    }
+}
+
+/// removes a hook, returns true if it was registered
+pub fn unregister_hook(name: &str) -> bool {
+    let mut hooks = REGISTERED_HOOKS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let before = hooks.len();
+    hooks.retain(|h| h.name() != name);
+    hooks.len() != before
+}
+
+/// the hooks attached to a configuration
+#[derive(Clone, Default)]
+pub struct Hooks(Arc<Vec<Arc<dyn AnalyzeHook>>>);
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.0.iter().map(|h| h.name())).finish()
+    }
+}
+
+impl Hooks {
+    /// snapshot of the currently registered hooks
+    pub fn registered() -> Self {
+        let hooks = REGISTERED_HOOKS.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        Hooks(Arc::new(hooks.clone()))
+    }
+
+    pub fn from_hooks(hooks: Vec<Arc<dyn AnalyzeHook>>) -> Self {
+        Hooks(Arc::new(hooks))
+    }
+
+    /// appends other hooks, that will run after these ones
+    pub fn with(&self, other: &Hooks) -> Self {
+        Hooks(Arc::new(self.0.iter().chain(other.0.iter()).cloned().collect()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn run<F>(&self, logs: &mut Logs, phase: &str, mut f: F) -> Option<Decision>
+    where
+        F: FnMut(&dyn AnalyzeHook, &mut Logs) -> Option<Decision>,
+    {
+        for hook in self.0.iter() {
+            if let Some(decision) = f(hook.as_ref(), logs) {
+                logs.debug(|| format!("hook {} returned a decision in phase {}", hook.name(), phase));
+                return Some(decision);
+            }
+        }
+        None
+    }
+
+    pub fn on_init(&self, logs: &mut Logs, reqinfo: &RequestInfo, tags: &mut Tags) -> Option<Decision> {
+        self.run(logs, "init", |h, logs| h.on_init(logs, reqinfo, tags))
+    }
+
+    pub fn on_pre_acl(&self, logs: &mut Logs, reqinfo: &RequestInfo, tags: &mut Tags) -> Option<Decision> {
+        self.run(logs, "pre_acl", |h, logs| h.on_pre_acl(logs, reqinfo, tags))
+    }
+
+    pub fn on_pre_contentfilter(&self, logs: &mut Logs, reqinfo: &RequestInfo, tags: &mut Tags) -> Option<Decision> {
+        self.run(logs, "pre_contentfilter", |h, logs| {
+            h.on_pre_contentfilter(logs, reqinfo, tags)
+        })
+    }
+
+    pub fn on_finish(
+        &self,
+        logs: &mut Logs,
+        reqinfo: &RequestInfo,
+        tags: &mut Tags,
+        decision: &Decision,
+    ) -> Option<Decision> {
+        self.run(logs, "finish", |h, logs| h.on_finish(logs, reqinfo, tags, decision))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::virtualtags::VirtualTags;
+    use crate::interface::Location;
+    use crate::testutils::request_info;
+
+    struct TagHook(&'static str, bool);
+
+    impl AnalyzeHook for TagHook {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn on_pre_acl(&self, _logs: &mut Logs, _reqinfo: &RequestInfo, tags: &mut Tags) -> Option<Decision> {
+            tags.insert(self.0, Location::Request);
+            if self.1 {
+                Some(Decision::pass(Vec::new()))
+            } else {
+                None
+            }
+        }
+    }
+
+    /// a hook that does nothing, so that registering it does not change the outcome of other tests
+    struct InertHook(&'static str);
+
+    impl AnalyzeHook for InertHook {
+        fn name(&self) -> &str {
+            self.0
+        }
+    }
+
+    #[test]
+    fn registration() {
+        register_hook(Arc::new(InertHook("hook-test-a")));
+        register_hook(Arc::new(InertHook("hook-test-b")));
+        register_hook(Arc::new(InertHook("hook-test-a")));
+        let names: Vec<String> = Hooks::registered()
+            .0
+            .iter()
+            .map(|h| h.name().to_string())
+            .filter(|n| n.starts_with("hook-test"))
+            .collect();
+        assert_eq!(names, vec!["hook-test-a", "hook-test-b"]);
+        assert!(unregister_hook("hook-test-a"));
+        assert!(unregister_hook("hook-test-b"));
+        assert!(!unregister_hook("hook-test-b"));
+    }
+
+    #[test]
+    fn run_order() {
+        let hooks = Hooks(Arc::new(vec![
+            Arc::new(TagHook("hook-a", false)),
+            Arc::new(TagHook("hook-b", true)),
+            Arc::new(TagHook("hook-c", false)),
+        ]));
+        let mut logs = Logs::default();
+        let mut tags = Tags::new(&VirtualTags::default());
+        let reqinfo = request_info(&[], &[]);
+        assert!(hooks.on_pre_acl(&mut logs, &reqinfo, &mut tags).is_some());
+        assert!(tags.contains("hook-a"));
+        assert!(tags.contains("hook-b"));
+        // skipped, as the previous hook returned a decision
+        assert!(!tags.contains("hook-c"));
+        assert!(hooks.on_init(&mut logs, &reqinfo, &mut tags).is_none());
+    }
+}
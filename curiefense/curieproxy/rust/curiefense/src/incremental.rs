@@ -6,31 +6,38 @@
    the case for envoy in its external processing mode.
 */
 
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Instant};
 
 use chrono::{DateTime, Utc};
 
 use crate::{
     analyze::{analyze, APhase0, CfRulesArg},
+    challenge::ChallengeProvider,
     challenge_verified,
+    clearance::ClearanceSettings,
+    clientip::{client_ip_configured, xff_peer},
     config::{
         contentfilter::ContentFilterRules,
         contentfilter::{ContentFilterProfile, SectionIdx},
         flow::FlowMap,
         globalfilter::GlobalFilterSection,
         hostmap::SecurityPolicy,
+        hostrules::HostMatch,
         virtualtags::VirtualTags,
         Config,
     },
-    grasshopper::{Grasshopper, PrecisionLevel},
+    contentfilter::stream::{early_verdict, BodyStream},
+    failure::{dependency_failed, Dependency},
+    grasshopper::{DynGrasshopper, PrecisionLevel},
+    hooks::Hooks,
     interface::{
         stats::{BStageSecpol, SecpolStats, StatsCollect},
         Action, ActionType, AnalyzeResult, BlockReason, Decision, Location, Tags,
     },
     logs::{LogLevel, Logs},
-    securitypolicy::match_securitypolicy,
+    securitypolicy::route_securitypolicy,
     tagging::tag_request,
-    utils::{map_request, RawRequest, RequestMeta},
+    utils::{map_request, url::normalize_uri, RawRequest, RequestMeta},
 };
 
 pub enum IPInfo {
@@ -44,17 +51,31 @@ pub struct IData {
     meta: RequestMeta,
     headers: HashMap<String, String>,
     secpol: Arc<SecurityPolicy>,
+    host_match: HostMatch,
     body: Option<Vec<u8>>,
+    /// set when the body is analyzed as it is received
+    body_stream: Option<BodyStream>,
+    /// request tags and normalized path, computed when the streamed body matches are first enough to block
+    stream_context: Option<(Tags, String)>,
+    /// content filter rules of the configuration the security policy comes from
+    hsdb: Arc<HashMap<String, ContentFilterRules>>,
     ipinfo: IPInfo,
     stats: StatsCollect<BStageSecpol>,
     container_name: Option<String>,
     plugins: HashMap<String, String>,
+    hooks: Hooks,
+    clearance: Option<Arc<ClearanceSettings>>,
 }
 
 impl IData {
     fn ip(&self) -> String {
         match &self.ipinfo {
             IPInfo::Ip(s) => s.clone(),
+            // the connecting address is the entry envoy appended to X-Forwarded-For, the chain is resolved with the
+            // trusted proxies
+            IPInfo::Hops(_) if client_ip_configured() => {
+                xff_peer(&self.headers).unwrap_or_else(|| "1.1.1.1".to_string())
+            }
             IPInfo::Hops(hops) => extract_ip(*hops, &self.headers).unwrap_or_else(|| "1.1.1.1".to_string()),
         }
     }
@@ -84,17 +105,20 @@ pub fn inspect_init(
     plugins: HashMap<String, String>,
 ) -> Result<IData, String> {
     let mut logs = Logs::new(loglevel);
-    let mr = match_securitypolicy(
+    let mr = route_securitypolicy(
         meta.authority.as_deref().unwrap_or("localhost"),
-        &meta.path,
+        meta.sni.as_deref(),
+        &normalize_uri(&meta.path),
+        Some(&meta.method),
         config,
         &mut logs,
         selected_secpol,
     );
     match mr {
         None => Err("could not find a matching security policy".to_string()),
-        Some(secpol) => {
+        Some((secpol, host_match)) => {
             let stats = StatsCollect::new(logs.start, config.revision.clone())
+                .config_hash(&config.config_hash)
                 .secpol(SecpolStats::build(&secpol, config.globalfilters.len()));
             Ok(IData {
                 start: start.unwrap_or_else(Utc::now),
@@ -102,11 +126,17 @@ pub fn inspect_init(
                 meta,
                 headers: HashMap::new(),
                 secpol,
+                host_match,
                 body: None,
+                body_stream: None,
+                stream_context: None,
+                hsdb: config.hsdb.clone(),
                 ipinfo,
                 stats,
                 container_name: config.container_name.clone(),
                 plugins,
+                hooks: config.hooks.clone(),
+                clearance: config.clearance.clone(),
             })
         }
     }
@@ -114,7 +144,7 @@ pub fn inspect_init(
 
 /// called when the content filter policy is violated
 /// no tags are returned though!
-fn early_block(idata: IData, action: Action, br: BlockReason) -> (Logs, AnalyzeResult) {
+fn early_block(idata: IData, action: Action, reasons: Vec<BlockReason>) -> (Logs, AnalyzeResult) {
     let ipstr = idata.ip();
     let mut logs = idata.logs;
     let secpolicy = idata.secpol;
@@ -135,7 +165,7 @@ fn early_block(idata: IData, action: Action, br: BlockReason) -> (Logs, AnalyzeR
     (
         logs,
         AnalyzeResult {
-            decision: Decision::action(action, vec![br]),
+            decision: Decision::action(action, reasons),
             tags: Tags::new(&VirtualTags::default()),
             rinfo: reqinfo,
             stats: idata.stats.early_exit(),
@@ -159,14 +189,6 @@ pub fn add_headers(idata: IData, new_headers: HashMap<String, String>) -> Result
 /// other properties are not checked at this point (restrict for example), this early check purely exists as an anti DOS measure
 pub fn add_header(idata: IData, key: String, value: String) -> Result<IData, (Logs, AnalyzeResult)> {
     let mut dt = idata;
-    let cf_block = || Action {
-        atype: ActionType::Block,
-        block_mode: true,
-        status: 403,
-        headers: None,
-        content: "Access denied".to_string(),
-        extra_tags: None,
-    };
     let cfid = &dt.secpol.content_filter_profile.id;
     let cfname = &dt.secpol.content_filter_profile.name;
     let action = dt.secpol.content_filter_profile.action.atype.to_raw();
@@ -182,7 +204,7 @@ pub fn add_header(idata: IData, key: String, value: String) -> Result<IData, (Lo
                 dt.headers.len() + 1,
                 hdrs.max_count,
             );
-            return Err(early_block(dt, cf_block(), br));
+            return Err(early_block(dt, cf_block(), vec![br]));
         }
         let kl = key.to_lowercase();
         if kl == "content-length" {
@@ -190,7 +212,7 @@ pub fn add_header(idata: IData, key: String, value: String) -> Result<IData, (Lo
                 let max_size = dt.secpol.content_filter_profile.max_body_size;
                 if content_length > max_size {
                     let (a, br) = body_too_large(&dt.secpol.content_filter_profile, content_length, max_size);
-                    return Err(early_block(dt, a, br));
+                    return Err(early_block(dt, a, vec![br]));
                 }
             }
         }
@@ -204,7 +226,7 @@ pub fn add_header(idata: IData, key: String, value: String) -> Result<IData, (Lo
                 value.len(),
                 hdrs.max_length,
             );
-            return Err(early_block(dt, cf_block(), br));
+            return Err(early_block(dt, cf_block(), vec![br]));
         }
         dt.headers.insert(kl, value);
     } else {
@@ -213,16 +235,25 @@ pub fn add_header(idata: IData, key: String, value: String) -> Result<IData, (Lo
     Ok(dt)
 }
 
+/// the action used when the content filter blocks a request before it is complete
+fn cf_block() -> Action {
+    Action {
+        atype: ActionType::Block,
+        block_mode: true,
+        status: 403,
+        headers: None,
+        content: "Access denied".to_string(),
+        extra_tags: None,
+        response_headers: None,
+        delay: None,
+        transform: None,
+        ban: None,
+    }
+}
+
 fn body_too_large(profile: &ContentFilterProfile, actual: usize, expected: usize) -> (Action, BlockReason) {
     (
-        Action {
-            atype: ActionType::Block,
-            block_mode: true,
-            status: 403,
-            headers: None,
-            content: "Access denied".to_string(),
-            extra_tags: None,
-        },
+        cf_block(),
         BlockReason::body_too_large(
             profile.id.clone(),
             profile.name.clone(),
@@ -246,7 +277,7 @@ pub fn add_body(idata: IData, new_body: &[u8]) -> Result<IData, (Logs, AnalyzeRe
     let max_size = dt.secpol.content_filter_profile.max_body_size;
     if dt.secpol.content_filter_active && new_size > max_size {
         let (a, br) = body_too_large(&dt.secpol.content_filter_profile, new_size, max_size);
-        return Err(early_block(dt, a, br));
+        return Err(early_block(dt, a, vec![br]));
     }
 
     match dt.body.as_mut() {
@@ -256,7 +287,163 @@ pub fn add_body(idata: IData, new_body: &[u8]) -> Result<IData, (Logs, AnalyzeRe
     Ok(dt)
 }
 
-pub async fn finalize<GH: Grasshopper>(
+/// blocks the request on the streamed body matches, unless the request tags bypass the content filter
+///
+/// the rule exclusions and the action of the profile apply, as they would once the body is complete
+#[allow(clippy::result_large_err)]
+fn stream_block(
+    idata: IData,
+    reasons: Vec<BlockReason>,
+    globalfilters: &[GlobalFilterSection],
+    vtags: &VirtualTags,
+) -> Result<IData, (Logs, AnalyzeResult)> {
+    let mut dt = idata;
+    let secpol = dt.secpol.clone();
+    let profile = &secpol.content_filter_profile;
+    let map_partial = |dt: &mut IData| {
+        let rawrequest = RawRequest {
+            ipstr: dt.ip(),
+            headers: dt.headers.clone(),
+            meta: dt.meta.clone(),
+            mbody: None,
+        };
+        map_request(
+            &mut dt.logs,
+            secpol.clone(),
+            dt.container_name.clone(),
+            &rawrequest,
+            Some(dt.start),
+            dt.plugins.clone(),
+        )
+    };
+    let mut mreqinfo = None;
+    let (tags, path) = match dt.stream_context.take() {
+        Some(ctx) => ctx,
+        None => {
+            let reqinfo = map_partial(&mut dt);
+            // the statistics of the request are kept for its result
+            let stats = StatsCollect::new(dt.logs.start, String::new())
+                .secpol(SecpolStats::build(&secpol, globalfilters.len()));
+            let (mut tags, _, _) = tag_request(stats, PrecisionLevel::Invalid, globalfilters, &reqinfo, vtags);
+            tags.insert("all", Location::Request);
+            tags.insert_qualified("host-rule", dt.host_match.name(), Location::Request);
+            let path = reqinfo.rinfo.qinfo.normalized.path.clone();
+            mreqinfo = Some(reqinfo);
+            (tags, path)
+        }
+    };
+    if tags.has_intersection(&profile.ignore) {
+        dt.logs.debug("streamed body matches ignored because of global ignore");
+        dt.stream_context = Some((tags, path));
+        return Ok(dt);
+    }
+    let mut rtags = tags.clone();
+    let reasons = match early_verdict(&mut dt.logs, profile, &path, &mut rtags, reasons) {
+        None => {
+            dt.stream_context = Some((tags, path));
+            return Ok(dt);
+        }
+        Some(r) => r,
+    };
+    let reqinfo = match mreqinfo {
+        Some(r) => r,
+        None => map_partial(&mut dt),
+    };
+    for t in &profile.tags {
+        rtags.insert(t, Location::Body);
+    }
+    let decision = profile.action.to_decision(
+        &mut dt.logs,
+        PrecisionLevel::Invalid,
+        None::<&DynGrasshopper>,
+        &reqinfo,
+        &mut rtags,
+        reasons,
+    );
+    Err((
+        dt.logs,
+        AnalyzeResult {
+            decision,
+            tags: rtags,
+            rinfo: reqinfo,
+            stats: dt.stats.early_exit(),
+        },
+    ))
+}
+
+/// incrementally analyze a body chunk
+///
+/// when the content filter profile streams bodies, the chunk is scanned right away, and the request can be blocked
+/// before the body is complete. The body is then only buffered while it is small enough to be fully analyzed.
+/// Otherwise, this is the same as `add_body`.
+///
+/// the global filters and virtual tags are those of the configuration passed to `inspect_init`, they tag the request
+/// when it is blocked early.
+pub fn analyze_body_chunk(
+    idata: IData,
+    chunk: &[u8],
+    globalfilters: &[GlobalFilterSection],
+    vtags: &VirtualTags,
+) -> Result<IData, (Logs, AnalyzeResult)> {
+    let mut dt = idata;
+    let secpol = dt.secpol.clone();
+    let profile = &secpol.content_filter_profile;
+    if !profile.stream_body || !secpol.content_filter_active || profile.ignore_body {
+        return add_body(dt, chunk);
+    }
+
+    if dt.body_stream.is_none() {
+        // the stream is opened on the first chunk, or not at all
+        if dt.body.is_some() {
+            return add_body(dt, chunk);
+        }
+        match BodyStream::open(dt.hsdb.clone(), profile) {
+            Ok(Some(stream)) => dt.body_stream = Some(stream),
+            Ok(None) => {
+                dt.logs
+                    .debug(|| format!("no streaming rules for profile {}, buffering the body", profile.id));
+                return add_body(dt, chunk);
+            }
+            Err(rr) => {
+                dt.logs.error(|| format!("could not open the body stream: {}", rr));
+                return add_body(dt, chunk);
+            }
+        }
+    }
+
+    let mut matched = None;
+    let scanned = if let Some(stream) = dt.body_stream.as_mut() {
+        let new_size = stream.scanned + chunk.len();
+        if new_size > profile.max_body_size {
+            let (a, br) = body_too_large(profile, new_size, profile.max_body_size);
+            return Err(early_block(dt, a, vec![br]));
+        }
+        match stream.scan(&mut dt.logs, profile, chunk) {
+            Ok(reasons) => matched = reasons,
+            Err(rr) => dt.logs.error(|| format!("when scanning a body chunk: {}", rr)),
+        }
+        stream.scanned
+    } else {
+        0
+    };
+    if let Some(reasons) = matched {
+        dt = stream_block(dt, reasons, globalfilters, vtags)?;
+    }
+
+    // keep the body while it can still be decoded and fully analyzed
+    if scanned <= profile.max_body_scan_size {
+        match dt.body.as_mut() {
+            None => dt.body = Some(chunk.to_vec()),
+            Some(b) => b.extend(chunk),
+        }
+    } else if dt.body.take().is_some() {
+        dt.logs
+            .debug(|| format!("body larger than {} bytes, only streamed", profile.max_body_scan_size));
+    }
+    Ok(dt)
+}
+
+pub async fn finalize<GH: ChallengeProvider>(
     idata: IData,
     mgh: Option<&GH>,
     globalfilters: &[GlobalFilterSection],
@@ -267,16 +454,28 @@ pub async fn finalize<GH: Grasshopper>(
     let ipstr = idata.ip();
     let mut logs = idata.logs;
     let secpolicy = idata.secpol;
+    let host_match = idata.host_match;
+    let streamed_body = match idata.body_stream {
+        None => Vec::new(),
+        Some(stream) => stream
+            .finish(&mut logs, &secpolicy.content_filter_profile)
+            .unwrap_or_else(|rr| {
+                logs.error(|| format!("when closing the body stream: {}", rr));
+                Vec::new()
+            }),
+    };
     let rawrequest = RawRequest {
         ipstr,
         headers: idata.headers,
         meta: idata.meta,
         mbody: idata.body.as_deref(),
     };
-    let cfrules = mcfrules
-        .map(|cfrules| CfRulesArg::Get(cfrules.get(&secpolicy.content_filter_profile.id)))
-        .unwrap_or(CfRulesArg::Global);
-    let reqinfo = map_request(
+    // the rules come from the same configuration as the security policy, unless they are provided
+    let hsdb = idata.hsdb;
+    let cfrules = CfRulesArg::Get(mcfrules.unwrap_or(&hsdb).get(&secpolicy.content_filter_profile.id));
+    let mut stats = idata.stats;
+    let mapping_start = Instant::now();
+    let mut reqinfo = map_request(
         &mut logs,
         secpolicy.clone(),
         idata.container_name,
@@ -284,16 +483,17 @@ pub async fn finalize<GH: Grasshopper>(
         Some(idata.start),
         idata.plugins,
     );
+    reqinfo.streamed_body = streamed_body;
+    stats.request_mapped(mapping_start);
 
-    let precision_level = if let Some(gh) = mgh {
-        challenge_verified(gh, &reqinfo, &mut logs)
-    } else {
-        PrecisionLevel::Invalid
-    };
-    // without grasshopper, default to being human
-    let (mut tags, globalfilter_dec, stats) =
-        tag_request(idata.stats, precision_level, globalfilters, &reqinfo, &vtags);
+    let verified = challenge_verified(mgh, idata.clearance.as_deref(), &mut reqinfo, &mut logs);
+    let precision_level = verified.unwrap_or(PrecisionLevel::Invalid);
+    let (mut tags, globalfilter_dec, stats) = tag_request(stats, precision_level, globalfilters, &reqinfo, &vtags);
     tags.insert("all", Location::Request);
+    tags.insert_qualified("host-rule", host_match.name(), Location::Request);
+    if verified.is_none() {
+        dependency_failed(&mut tags, Dependency::Challenge);
+    }
 
     let dec = analyze(
         &mut logs,
@@ -304,7 +504,10 @@ pub async fn finalize<GH: Grasshopper>(
             reqinfo,
             precision_level,
             globalfilter_dec,
+            session_filters: globalfilters.iter().filter(|s| s.session).cloned().collect(),
+            hooks: idata.hooks,
             flows: flows.clone(),
+            cache: None,
         },
         cfrules,
     )
@@ -316,7 +519,10 @@ pub async fn finalize<GH: Grasshopper>(
 mod test {
     use crate::config::{
         contentfilter::ContentFilterProfile,
-        hostmap::{HostMap, PolicyId},
+        dataleak::DataLeakRules,
+        hostmap::{CounterSettings, HostMap, PolicyId},
+        hostrules::HostRules,
+        pathtrie::PathTrie,
         raw::AclProfile,
     };
     use std::collections::HashSet;
@@ -326,12 +532,15 @@ mod test {
     fn empty_config(cf: ContentFilterProfile) -> Config {
         Config {
             revision: "dummy".to_string(),
+            config_hash: String::new(),
             securitypolicies_map: HashMap::new(),
             securitypolicies: Vec::new(),
             globalfilters: Vec::new(),
+            host_rules: HostRules::default(),
             default: Some(HostMap {
                 name: "default".to_string(),
                 entries: Vec::new(),
+                templates: PathTrie::default(),
                 default: Some(Arc::new(SecurityPolicy {
                     policy: PolicyId {
                         id: "__default__".to_string(),
@@ -348,19 +557,54 @@ mod test {
                     content_filter_profile: cf,
                     session: Vec::new(),
                     session_ids: Vec::new(),
+                    response_filter_active: false,
+                    response_filter_profile: None,
+                    counters: CounterSettings::default(),
+                    jwt: None,
+                    session_tracking: None,
+                    decision_cache: None,
+                    data_leak: None,
+                    csrf: None,
+                    protocol: None,
+                    websocket: None,
+                    bot_score: None,
+                    captcha: None,
+                    failure_policy: Default::default(),
+                    canary: None,
+                    candidate: None,
+                    access_log: None,
+                    path_template: None,
+                    methods: None,
+                    api_key: None,
+                    honeypot: None,
+                    offender_tracking: None,
+                    bypass: None,
+                    cadence: None,
+                    login_protection: None,
+                    checkout_protection: None,
+                    scraping: None,
+                    replay: None,
+                    signature: None,
                     limits: Vec::new(),
                 })),
             }),
             container_name: None,
             flows: HashMap::new(),
             content_filter_profiles: HashMap::new(),
+            response_filter_profiles: HashMap::new(),
+            data_leak_rules: Arc::new(DataLeakRules::empty()),
+            hsdb: Arc::new(HashMap::new()),
             logs: Logs::default(),
             virtual_tags: Arc::new(HashMap::new()),
+            challenge: Default::default(),
+            clearance: None,
             actions: HashMap::new(),
             limits: HashMap::new(),
             global_limits: Vec::new(),
             inactive_limits: HashSet::new(),
             acls: HashMap::new(),
+            hooks: Hooks::default(),
+            plugin_hooks: Hooks::default(),
         }
     }
 
@@ -376,6 +620,11 @@ mod test {
                 authority: Some("authority".to_string()),
                 method: "GET".to_string(),
                 protocol: None,
+                tls_fingerprint: None,
+                header_order: None,
+                proxy_source: None,
+                sni: None,
+                client_cert: None,
                 path: "/path/to/somewhere".to_string(),
                 extra: HashMap::default(),
                 requestid: None,
@@ -33,8 +33,11 @@ lazy_static! {
         .unwrap_or(8);
     static ref PLANET_NAME: String = std::env::var("CF_PLANET_NAME").ok().unwrap_or_default();
     static ref EMPTY_AGGREGATED_DATA: AggregatedCounters = AggregatedCounters::default();
+    static ref SNAPSHOT_PATH: Option<String> = std::env::var("ANOMALY_SNAPSHOT_PATH").ok();
 }
 
+static SNAPSHOT_EXPORT: std::sync::Once = std::sync::Once::new();
+
 #[derive(Debug, Default)]
 struct Arp<T> {
     active: T,
@@ -168,6 +171,8 @@ struct AggregatedCounters {
     bot: usize,
     human: usize,
     challenge: usize,
+    /// requests for which redis, the challenge provider or hyperscan failed, by dependency
+    dependency_failures: Bag<String>,
 
     // per request
     /// Processing time in microseconds
@@ -472,6 +477,10 @@ fn is_autotag_prefix(s: &str) -> bool {
         s,
         "securitypolicy"
             | "securitypolicy-entry"
+            | "securitypolicy-entry-methods"
+            | "apikey"
+            | "mtls-fingerprint"
+            | "mtls-san"
             | "aclid"
             | "aclname"
             | "contentfilterid"
@@ -488,6 +497,7 @@ fn is_autotag_prefix(s: &str) -> bool {
             | "cookies"
             | "args"
             | "host"
+            | "request-id"
             | "ip"
             | "geo-continent-name"
             | "geo-continent-code"
@@ -525,8 +535,12 @@ impl AggregatedCounters {
                     skipped = true;
                     false
                 }
-                RawActionType::Monitor => false,
-                RawActionType::Custom | RawActionType::Challenge | RawActionType::Ichallenge => {
+                RawActionType::Monitor | RawActionType::RateLimitHeaders | RawActionType::Transform => false,
+                RawActionType::Custom
+                | RawActionType::Challenge
+                | RawActionType::Ichallenge
+                | RawActionType::Redirect
+                | RawActionType::Tarpit => {
                     blocked = true;
                     true
                 }
@@ -581,7 +595,7 @@ impl AggregatedCounters {
                     self.ruleid.get_mut(cursor).inc(ruleid.clone());
                     self.risk_level.get_mut(cursor).inc(*risk_level);
                 }
-                Restriction { .. } => {
+                Restriction { .. } | Schema { .. } => {
                     if this_blocked {
                         self.requests_triggered_restriction_active += 1;
                     } else {
@@ -616,6 +630,9 @@ impl AggregatedCounters {
                     | Location::RefererPathpartValue(_, _) => aggloc.headers += 1,
                     Location::Cookies | Location::Cookie(_) | Location::CookieValue(_, _) => aggloc.headers += 1,
                     Location::Plugins | Location::Plugin(_) | Location::PluginValue(_, _) => aggloc.plugins += 1,
+                    Location::Response => (),
+                    Location::ResponseHeader(_) => aggloc.headers += 1,
+                    Location::ResponseBody => aggloc.body += 1,
                 }
             }
         }
@@ -670,6 +687,7 @@ impl AggregatedCounters {
                 tg => match tg.split_once(':') {
                     None => top_tags.inc(tg.to_string()),
                     Some(("rtc", rtc)) => self.top_rtc.get_mut(cursor).inc(rtc.to_string()),
+                    Some(("dependency-failure", dependency)) => self.dependency_failures.inc(dependency.to_string()),
                     Some((prefix, _)) => {
                         if !is_autotag_prefix(prefix) {
                             top_tags.inc(tg.to_string())
@@ -816,6 +834,7 @@ fn serialize_counters(e: &AggregatedCounters) -> Value {
     content.insert("status".into(), e.status.serialize_top());
     content.insert("status_classes".into(), e.status_classes.serialize_top());
     content.insert("methods".into(), e.methods.serialize_top());
+    content.insert("dependency_failures".into(), e.dependency_failures.serialize_top());
 
     e.top_tags.serialize(&mut content, "top_tags_");
     content.insert("top_request_per_cookies".into(), e.cookies_amount.serialize_top());
@@ -845,10 +864,15 @@ fn serialize_counters(e: &AggregatedCounters) -> Value {
     Value::Object(content)
 }
 
-fn serialize_entry(sample: i64, hdr: &AggregationKey, counters: &AggregatedCounters) -> Value {
+/// start time of a sample
+fn sample_timestamp(sample: i64) -> chrono::DateTime<chrono::Utc> {
     let naive_dt =
         chrono::NaiveDateTime::from_timestamp_opt(sample * *SAMPLE_DURATION, 0).unwrap_or(chrono::NaiveDateTime::MIN);
-    let timestamp: chrono::DateTime<chrono::Utc> = chrono::DateTime::from_utc(naive_dt, chrono::Utc);
+    chrono::DateTime::from_utc(naive_dt, chrono::Utc)
+}
+
+fn serialize_entry(sample: i64, hdr: &AggregationKey, counters: &AggregatedCounters) -> Value {
+    let timestamp = sample_timestamp(sample);
     let mut content = serde_json::Map::new();
 
     content.insert(
@@ -867,15 +891,52 @@ fn serialize_entry(sample: i64, hdr: &AggregationKey, counters: &AggregatedCount
     content.insert("branch".into(), Value::String(hdr.branch.clone()));
     content.insert("planet_name".into(), Value::String(PLANET_NAME.clone()));
     content.insert("counters".into(), serialize_counters(counters));
+    let sinks = crate::logsink::sinks_stats();
+    if !sinks.is_empty() {
+        content.insert("log_sinks".into(), serde_json::to_value(sinks).unwrap_or(Value::Null));
+    }
+    if let Some(redis) = crate::redis::redis_stats() {
+        content.insert("redis".into(), serde_json::to_value(redis).unwrap_or(Value::Null));
+    }
+    let feeds = crate::reputation::reputation_stats();
+    if !feeds.is_empty() {
+        content.insert(
+            "reputation_feeds".into(),
+            serde_json::to_value(feeds).unwrap_or(Value::Null),
+        );
+    }
+    let canaries = crate::canary::canary_stats();
+    if !canaries.is_empty() {
+        content.insert("canary".into(), serde_json::to_value(canaries).unwrap_or(Value::Null));
+    }
+    let comparisons = crate::abtest::comparison_stats();
+    if !comparisons.is_empty() {
+        content.insert(
+            "ab_test".into(),
+            serde_json::to_value(comparisons).unwrap_or(Value::Null),
+        );
+    }
+    if let Some(topn) = crate::topn::topn_stats() {
+        content.insert("topn".into(), serde_json::to_value(topn).unwrap_or(Value::Null));
+    }
+    let latencies = crate::interface::stats::stage_latency_histograms();
+    if !latencies.is_empty() {
+        content.insert(
+            "stage_latency".into(),
+            serde_json::to_value(latencies).unwrap_or(Value::Null),
+        );
+    }
     Value::Object(content)
 }
 
 fn prune_old_values<A>(amp: &mut HashMap<AggregationKey, BTreeMap<i64, A>>, cursample: i64) {
+    // the anomaly snapshot needs the two samples before the current one
+    let kept = (*SAMPLES_KEPT).max(3);
     for (_, mp) in amp.iter_mut() {
         #[allow(clippy::needless_collect)]
         let keys: Vec<i64> = mp.keys().copied().collect();
         for k in keys.into_iter() {
-            if k <= cursample - *SAMPLES_KEPT {
+            if k <= cursample - kept {
                 mp.remove(&k);
             }
         }
@@ -905,11 +966,7 @@ pub async fn aggregated_values() -> String {
         })
         .collect();
     let entries = if entries.is_empty() {
-        let proxy = crate::config::CONFIGS
-            .config
-            .read()
-            .ok()
-            .and_then(|cfg| cfg.container_name.clone());
+        let proxy = crate::config::CONFIGS.config.load().container_name.clone();
 
         timerange()
             .map(|ts| {
@@ -959,9 +1016,205 @@ pub async fn aggregate(
         secpolentryid: rinfo.rinfo.secpolicy.entry.id.to_string(),
         branch: branch_tag.to_string(),
     };
+    start_snapshot_export();
     let mut guard = AGGREGATED.lock().await;
     prune_old_values(&mut guard, sample);
     let entry_hdrs = guard.entry(key).or_default();
     let entry = entry_hdrs.entry(sample).or_default();
     entry.increment(dec, rcode, rinfo, tags, bytes_sent);
 }
+
+fn merge_top(out: &mut HashMap<String, usize>, top: &TopN<String>) {
+    for (k, v) in &top.counters {
+        *out.entry(k.clone()).or_default() += *v;
+    }
+}
+
+fn top_to_value(mp: HashMap<String, usize>) -> Value {
+    let mut v = mp.into_iter().collect::<Vec<_>>();
+    v.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    v.truncate(*TOP_AMOUNT);
+    Bag::<String>::sorted_to_value(v)
+}
+
+#[derive(Serialize)]
+struct TagDelta {
+    tag: String,
+    current: usize,
+    previous: usize,
+    delta: i64,
+}
+
+/// computes the anomaly snapshot for a given sample, compared to the previous one
+///
+/// both samples must be complete, or the deltas would be skewed by the partial one
+fn build_snapshot(amp: &HashMap<AggregationKey, BTreeMap<i64, AggregatedCounters>>, cursample: i64) -> Value {
+    let mut blocked_ips = HashMap::new();
+    let mut rules = HashMap::new();
+    let mut cur_tags = HashMap::new();
+    let mut prev_tags = HashMap::new();
+    let mut hits = 0;
+    let mut blocked = 0;
+
+    for samples in amp.values() {
+        if let Some(cur) = samples.get(&cursample) {
+            hits += cur.hits;
+            blocked += cur.requests.get(ArpCursor::Active);
+            merge_top(&mut blocked_ips, cur.ip.top.get(ArpCursor::Active));
+            merge_top(&mut rules, cur.ruleid.get(ArpCursor::Active));
+            merge_top(&mut rules, cur.ruleid.get(ArpCursor::Report));
+            for cursor in [ArpCursor::Active, ArpCursor::Report, ArpCursor::Pass] {
+                merge_top(&mut cur_tags, cur.top_tags.get(cursor));
+            }
+        }
+        if let Some(prev) = samples.get(&(cursample - 1)) {
+            for cursor in [ArpCursor::Active, ArpCursor::Report, ArpCursor::Pass] {
+                merge_top(&mut prev_tags, prev.top_tags.get(cursor));
+            }
+        }
+    }
+
+    let mut deltas: Vec<TagDelta> = cur_tags
+        .keys()
+        .chain(prev_tags.keys())
+        .collect::<std::collections::HashSet<_>>()
+        .into_iter()
+        .map(|tag| {
+            let current = cur_tags.get(tag).copied().unwrap_or(0);
+            let previous = prev_tags.get(tag).copied().unwrap_or(0);
+            TagDelta {
+                tag: tag.clone(),
+                current,
+                previous,
+                delta: current as i64 - previous as i64,
+            }
+        })
+        .filter(|d| d.delta != 0)
+        .collect();
+    deltas.sort_by(|a, b| b.delta.abs().cmp(&a.delta.abs()).then_with(|| a.tag.cmp(&b.tag)));
+    deltas.truncate(*TOP_AMOUNT);
+
+    serde_json::json!({
+        "timestamp": sample_timestamp(cursample),
+        "window": *SAMPLE_DURATION,
+        "planet_name": *PLANET_NAME,
+        "hits": hits,
+        "blocked": blocked,
+        "top_blocked_ips": top_to_value(blocked_ips),
+        "top_rules": top_to_value(rules),
+        "tag_deltas": deltas,
+    })
+}
+
+/// exports a snapshot of the last complete sample, meant for dashboards and scripts
+///
+/// it contains the top blocked IPs, the top triggered rules, and how tag frequencies changed since the previous sample
+pub async fn anomaly_snapshot() -> String {
+    let guard = AGGREGATED.lock().await;
+    // the current sample is still being filled
+    let cursample = chrono::Utc::now().timestamp() / *SAMPLE_DURATION - 1;
+    serde_json::to_string(&build_snapshot(&guard, cursample)).unwrap_or_else(|_| "{}".into())
+}
+
+/// non asynchronous version of anomaly_snapshot
+pub fn anomaly_snapshot_block() -> String {
+    async_std::task::block_on(anomaly_snapshot())
+}
+
+/// when ANOMALY_SNAPSHOT_PATH is set, writes a snapshot to this file every sample period
+fn start_snapshot_export() {
+    if let Some(path) = SNAPSHOT_PATH.as_ref() {
+        SNAPSHOT_EXPORT.call_once(|| {
+            let period = std::time::Duration::from_secs((*SAMPLE_DURATION).max(1) as u64);
+            std::thread::spawn(move || loop {
+                std::thread::sleep(period);
+                // write then rename, so that readers never see a partial file
+                let tmp = format!("{}.tmp", path);
+                if std::fs::write(&tmp, anomaly_snapshot_block()).is_ok() {
+                    let _ = std::fs::rename(&tmp, path);
+                }
+            });
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_deltas() {
+        let mut prev = AggregatedCounters::default();
+        let mut cur = AggregatedCounters::default();
+        for _ in 0..3 {
+            prev.top_tags.get_mut(ArpCursor::Pass).inc("steady".to_string());
+            cur.top_tags.get_mut(ArpCursor::Pass).inc("steady".to_string());
+        }
+        for _ in 0..5 {
+            cur.top_tags.get_mut(ArpCursor::Active).inc("scanner".to_string());
+            cur.ip.inc(&"1.2.3.4".to_string(), ArpCursor::Active);
+        }
+        cur.ruleid.get_mut(ArpCursor::Report).inc("100001".to_string());
+        prev.top_tags.get_mut(ArpCursor::Pass).inc("gone".to_string());
+
+        let mut samples = BTreeMap::new();
+        samples.insert(9, prev);
+        samples.insert(10, cur);
+        let mut amp = HashMap::new();
+        amp.insert(
+            AggregationKey {
+                proxy: None,
+                secpolid: "__default__".to_string(),
+                secpolentryid: "__default__".to_string(),
+                branch: "-".to_string(),
+            },
+            samples,
+        );
+
+        let snapshot = build_snapshot(&amp, 10);
+        assert_eq!(
+            snapshot["top_blocked_ips"],
+            serde_json::json!([{"key": "1.2.3.4", "value": 5}])
+        );
+        assert_eq!(
+            snapshot["top_rules"],
+            serde_json::json!([{"key": "100001", "value": 1}])
+        );
+        assert_eq!(
+            snapshot["tag_deltas"],
+            serde_json::json!([
+                {"tag": "scanner", "current": 5, "previous": 0, "delta": 5},
+                {"tag": "gone", "current": 0, "previous": 1, "delta": -1},
+            ])
+        );
+    }
+
+    #[test]
+    fn snapshot_capped() {
+        let mut cur = AggregatedCounters::default();
+        for i in 0..*TOP_AMOUNT + 10 {
+            cur.ip.inc(&format!("10.0.0.{}", i), ArpCursor::Active);
+            cur.top_tags.get_mut(ArpCursor::Pass).inc(format!("tag-{}", i));
+        }
+        let mut samples = BTreeMap::new();
+        samples.insert(10, cur);
+        let mut amp = HashMap::new();
+        amp.insert(
+            AggregationKey {
+                proxy: None,
+                secpolid: "__default__".to_string(),
+                secpolentryid: "__default__".to_string(),
+                branch: "-".to_string(),
+            },
+            samples,
+        );
+
+        let snapshot = build_snapshot(&amp, 10);
+        assert_eq!(snapshot["blocked"], serde_json::json!(0));
+        assert_eq!(
+            snapshot["top_blocked_ips"].as_array().map(|a| a.len()),
+            Some(*TOP_AMOUNT)
+        );
+        assert_eq!(snapshot["tag_deltas"].as_array().map(|a| a.len()), Some(*TOP_AMOUNT));
+    }
+}
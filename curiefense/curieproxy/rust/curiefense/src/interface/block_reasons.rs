@@ -1,5 +1,6 @@
 /// this file contains all the data type that are used when interfacing with a proxy
 use crate::config::{contentfilter::SectionIdx, raw::RawActionType};
+use crate::protocol::ProtocolAnomaly;
 use serde::ser::SerializeMap;
 use serde::Serialize;
 use serde_json::Value;
@@ -37,6 +38,12 @@ pub enum Initiator {
         actual: String,
         expected: String,
     },
+    /// an argument that does not match the positive security schema of the path
+    Schema {
+        path: String,
+        param: String,
+        problem: &'static str,
+    },
 
     // TODO, these two are not serialized for now
     Phase01Fail(String),
@@ -54,6 +61,7 @@ impl std::fmt::Display for Initiator {
             Phase01Fail(r) => write!(f, "grasshopper phase 1 error: {}", r),
             Phase02 => write!(f, "grasshopper phase 2"),
             Restriction { tpe, actual, expected } => write!(f, "restricted {}[{}/{}]", tpe, actual, expected),
+            Schema { path, param, problem } => write!(f, "schema {} {}: {}", path, param, problem),
         }
     }
 }
@@ -66,6 +74,7 @@ pub enum InitiatorKind {
     GlobalFilter,
     ContentFilter,
     Restriction,
+    Schema,
 }
 
 impl Initiator {
@@ -79,6 +88,7 @@ impl Initiator {
             Initiator::Phase01Fail(_) => None,
             Initiator::Phase02 => None,
             Initiator::Restriction { .. } => Some(Restriction),
+            Initiator::Schema { .. } => Some(Schema),
         }
     }
 
@@ -104,6 +114,11 @@ impl Initiator {
                 map.serialize_entry("actual", actual)?;
                 map.serialize_entry("expected", expected)?;
             }
+            Initiator::Schema { path, param, problem } => {
+                map.serialize_entry("path", path)?;
+                map.serialize_entry("param", param)?;
+                map.serialize_entry("problem", problem)?;
+            }
 
             // not serialized
             Initiator::Phase01Fail(r) => {
@@ -221,6 +236,39 @@ impl BlockReason {
             extra: Value::Null,
         }
     }
+    pub fn body_too_complex(id: String, name: String, action: RawActionType, expected: usize) -> Self {
+        BlockReason {
+            id,
+            name,
+            initiator: Initiator::Restriction {
+                tpe: "too complex",
+                actual: format!(">{}", expected),
+                expected: expected.to_string(),
+            },
+            location: Location::Body,
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
+    pub fn body_upload(
+        id: String,
+        name: String,
+        action: RawActionType,
+        tpe: &'static str,
+        actual: String,
+        expected: String,
+    ) -> Self {
+        BlockReason {
+            id,
+            name,
+            initiator: Initiator::Restriction { tpe, actual, expected },
+            location: Location::Body,
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
     pub fn body_too_large(id: String, name: String, action: RawActionType, actual: usize, expected: usize) -> Self {
         BlockReason {
             id,
@@ -236,6 +284,289 @@ impl BlockReason {
             extra: Value::Null,
         }
     }
+    pub fn memory_exceeded(id: String, name: String, action: RawActionType, expected: usize) -> Self {
+        BlockReason {
+            id,
+            name,
+            initiator: Initiator::Restriction {
+                tpe: "memory",
+                actual: format!(">{}", expected),
+                expected: expected.to_string(),
+            },
+            location: Location::Request,
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
+    fn limit_exceeded(
+        id: String,
+        name: String,
+        action: RawActionType,
+        tpe: &'static str,
+        location: Location,
+        actual: usize,
+        expected: usize,
+    ) -> Self {
+        BlockReason {
+            id,
+            name,
+            initiator: Initiator::Restriction {
+                tpe,
+                actual: actual.to_string(),
+                expected: expected.to_string(),
+            },
+            location,
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
+    pub fn too_many_args(id: String, name: String, action: RawActionType, actual: usize, expected: usize) -> Self {
+        Self::limit_exceeded(id, name, action, "args count", Location::Request, actual, expected)
+    }
+    pub fn too_many_headers(id: String, name: String, action: RawActionType, actual: usize, expected: usize) -> Self {
+        Self::limit_exceeded(id, name, action, "headers count", Location::Headers, actual, expected)
+    }
+    pub fn value_too_long(
+        id: String,
+        name: String,
+        action: RawActionType,
+        location: Location,
+        actual: usize,
+        expected: usize,
+    ) -> Self {
+        Self::limit_exceeded(id, name, action, "value length", location, actual, expected)
+    }
+    pub fn decoded_body_too_large(
+        id: String,
+        name: String,
+        action: RawActionType,
+        actual: usize,
+        expected: usize,
+    ) -> Self {
+        Self::limit_exceeded(id, name, action, "decoded body size", Location::Body, actual, expected)
+    }
+    pub fn plugin(id: String, name: String, action: RawActionType, reason: String) -> Self {
+        BlockReason {
+            id,
+            name,
+            initiator: Initiator::Restriction {
+                tpe: "plugin",
+                actual: reason,
+                expected: "plugin approval".to_string(),
+            },
+            location: Location::Request,
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
+    pub fn banned(action: RawActionType, until: String, reason: String) -> Self {
+        BlockReason {
+            id: "ban".to_string(),
+            name: "ban".to_string(),
+            initiator: Initiator::Restriction {
+                tpe: "banned",
+                actual: until,
+                expected: reason,
+            },
+            location: Location::Ip,
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
+    pub fn login_anomaly(
+        id: String,
+        name: String,
+        action: RawActionType,
+        signal: &'static str,
+        location: Location,
+        count: u64,
+        max: u64,
+    ) -> Self {
+        BlockReason {
+            id,
+            name,
+            initiator: Initiator::Restriction {
+                tpe: signal,
+                actual: count.to_string(),
+                expected: format!("at most {}", max),
+            },
+            location,
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
+    pub fn card_testing(
+        id: String,
+        name: String,
+        action: RawActionType,
+        signal: &'static str,
+        location: Location,
+        count: u64,
+        max: u64,
+    ) -> Self {
+        BlockReason {
+            id,
+            name,
+            initiator: Initiator::Restriction {
+                tpe: signal,
+                actual: count.to_string(),
+                expected: format!("at most {}", max),
+            },
+            location,
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
+    pub fn jwt(id: String, name: String, action: RawActionType, header: String, problem: &str) -> Self {
+        BlockReason {
+            id,
+            name,
+            initiator: Initiator::Restriction {
+                tpe: "jwt",
+                actual: problem.to_string(),
+                expected: "valid token".to_string(),
+            },
+            location: Location::Header(header),
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
+    pub fn protocol(id: String, name: String, action: RawActionType, anomaly: ProtocolAnomaly) -> Self {
+        BlockReason {
+            id,
+            name,
+            initiator: Initiator::Restriction {
+                tpe: anomaly.check,
+                actual: anomaly.actual,
+                expected: anomaly.expected.to_string(),
+            },
+            location: anomaly.location,
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
+    pub fn websocket(
+        id: String,
+        name: String,
+        action: RawActionType,
+        tpe: &'static str,
+        location: Location,
+        actual: String,
+        expected: String,
+    ) -> Self {
+        BlockReason {
+            id,
+            name,
+            initiator: Initiator::Restriction { tpe, actual, expected },
+            location,
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
+    pub fn bot_score(id: String, name: String, action: RawActionType, score: u8, threshold: u8) -> Self {
+        BlockReason {
+            id,
+            name,
+            initiator: Initiator::Restriction {
+                tpe: "bot score",
+                actual: score.to_string(),
+                expected: format!("<{}", threshold),
+            },
+            location: Location::Request,
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
+    pub fn csrf(id: String, name: String, action: RawActionType, header: String, problem: &str) -> Self {
+        BlockReason {
+            id,
+            name,
+            initiator: Initiator::Restriction {
+                tpe: "csrf",
+                actual: problem.to_string(),
+                expected: "valid token".to_string(),
+            },
+            location: Location::Header(header),
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
+    pub fn replay(id: String, name: String, action: RawActionType, header: String, problem: &str) -> Self {
+        BlockReason {
+            id,
+            name,
+            initiator: Initiator::Restriction {
+                tpe: "replay",
+                actual: problem.to_string(),
+                expected: "unique nonce".to_string(),
+            },
+            location: Location::Header(header),
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
+    pub fn signature(id: String, name: String, action: RawActionType, header: String, problem: &str) -> Self {
+        BlockReason {
+            id,
+            name,
+            initiator: Initiator::Restriction {
+                tpe: "signature",
+                actual: problem.to_string(),
+                expected: "valid signature".to_string(),
+            },
+            location: Location::Header(header),
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
+    pub fn dependency_failure(id: String, name: String, action: RawActionType, dependency: &str) -> Self {
+        BlockReason {
+            id,
+            name,
+            initiator: Initiator::Restriction {
+                tpe: "dependency",
+                actual: dependency.to_string(),
+                expected: "available".to_string(),
+            },
+            location: Location::Request,
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
+    pub fn schema(
+        id: String,
+        name: String,
+        action: RawActionType,
+        path: String,
+        param: String,
+        problem: &'static str,
+        locs: &HashSet<Location>,
+    ) -> Self {
+        let (location, extra_locations) = extra_locations(locs.iter());
+        BlockReason {
+            id,
+            name,
+            initiator: Initiator::Schema { path, param, problem },
+            location,
+            action,
+            extra_locations,
+            extra: Value::Null,
+        }
+    }
     pub fn body_missing(id: String, name: String, action: RawActionType) -> Self {
         BlockReason {
             id,
@@ -286,6 +617,28 @@ impl BlockReason {
             extra: Value::Null,
         }
     }
+    /// finding of the native lexical analyzers, engine is "sqli" or "xss"
+    pub fn lexical(
+        id: String,
+        name: String,
+        action: RawActionType,
+        location: Location,
+        engine: &str,
+        finding: &str,
+    ) -> Self {
+        BlockReason {
+            id,
+            name,
+            initiator: Initiator::ContentFilter {
+                ruleid: format!("lexical-{}:{}", engine, finding),
+                risk_level: 4,
+            },
+            location,
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
     pub fn xss(id: String, name: String, action: RawActionType, location: Location) -> Self {
         BlockReason {
             id,
@@ -300,6 +653,20 @@ impl BlockReason {
             extra: Value::Null,
         }
     }
+    pub fn response_signature(id: String, name: String, action: RawActionType, ruleid: &str, risk_level: u8) -> Self {
+        BlockReason {
+            id,
+            name,
+            initiator: Initiator::ContentFilter {
+                ruleid: ruleid.to_string(),
+                risk_level,
+            },
+            location: Location::ResponseBody,
+            action,
+            extra_locations: Vec::new(),
+            extra: Value::Null,
+        }
+    }
     pub fn too_many_entries(
         id: String,
         name: String,
@@ -1,15 +1,19 @@
+use crate::analyze::{analyze_response, APhaseResp, ResponseAnalyzeResult};
+use crate::challenge::ChallengeProvider;
 use crate::config::hostmap::SecurityPolicy;
 /// this file contains all the data type that are used when interfacing with a proxy
 use crate::config::matchers::RequestSelector;
 use crate::config::raw::{RawAction, RawActionType};
-use crate::grasshopper::{challenge_phase01, GHMode, Grasshopper, PrecisionLevel};
+use crate::config::responsetemplate::{ResponseFormat, ResponseTemplate};
+use crate::grasshopper::{challenge_phase01, GHMode, PrecisionLevel};
 use crate::logs::Logs;
 use crate::utils::json::NameValue;
-use crate::utils::templating::{parse_request_template, RequestTemplate, TVar, TemplatePart};
+use crate::utils::templating::{parse_request_template, RVar, RequestTemplate, TVar, TemplatePart};
 use crate::utils::{selector, GeoIp, RequestInfo, Selected};
 use serde::ser::{SerializeMap, SerializeSeq};
 use serde::{Deserialize, Serialize, Serializer};
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
 pub use self::block_reasons::*;
 pub use self::stats::*;
@@ -21,6 +25,7 @@ pub mod stats;
 pub mod tagging;
 
 #[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
 pub enum SimpleDecision {
     Pass,
     Action(SimpleAction, Vec<BlockReason>),
@@ -57,14 +62,32 @@ pub fn merge_decisions(d1: Decision, d2: Decision) -> Decision {
 
     // Merge headers if kept action is monitor
     if let Some(action) = &mut kept.maction {
-        if action.atype == ActionType::Monitor {
+        let (throw_headers, throw_response_headers, throw_transform) = match thrown.maction {
+            None => (None, None, None),
+            Some(a) => (a.headers, a.response_headers, a.transform),
+        };
+        if !action.atype.is_final() {
             // if the kept action is monitor, the thrown action is monitor or pass, so we might need to merge headers
-            let throw_headers = thrown.maction.and_then(|action| action.headers);
             if let Some(headers) = &mut action.headers {
                 headers.extend(throw_headers.unwrap_or_default())
             } else {
                 action.headers = throw_headers;
             }
+            if let Some(headers) = &mut action.response_headers {
+                headers.extend(throw_response_headers.unwrap_or_default())
+            } else {
+                action.response_headers = throw_response_headers;
+            }
+            match (&mut action.transform, throw_transform) {
+                (Some(transform), Some(other)) => transform.merge(other),
+                (None, other) => action.transform = other,
+                (Some(_), None) => (),
+            }
+        } else if action.atype.is_blocking() {
+            // rate limit headers are also sent with blocking responses
+            for (k, v) in throw_response_headers.unwrap_or_default() {
+                action.headers.get_or_insert_with(HashMap::new).entry(k).or_insert(v);
+            }
         }
     }
 
@@ -78,11 +101,24 @@ pub fn stronger_decision(d1: SimpleDecision, d2: SimpleDecision) -> SimpleDecisi
     match (d1, d2) {
         (SimpleDecision::Pass, d2) => d2,
         (d1, SimpleDecision::Pass) => d1,
-        (SimpleDecision::Action(mut s1, mut kept_reasons), SimpleDecision::Action(s2, br2)) => {
+        (SimpleDecision::Action(mut s1, mut kept_reasons), SimpleDecision::Action(mut s2, br2)) => {
             kept_reasons.extend(br2);
-            if s1.atype.priority() > s2.atype.priority() {
-                SimpleDecision::Action(s1, kept_reasons)
-            } else if s1.atype == SimpleActionT::Monitor && s2.atype == SimpleActionT::Monitor {
+            // the most restrictive limit is advertised
+            let quota = match (s1.quota, s2.quota) {
+                (Some(q1), Some(q2)) if q2.remaining < q1.remaining => Some(q2),
+                (q1, q2) => q1.or(q2),
+            };
+            s1.quota = quota;
+            s2.quota = quota;
+            if s1.atype.is_passing() && s2.atype.is_passing() {
+                match (&mut s1.atype, s2.atype) {
+                    (SimpleActionT::Transform(t1), SimpleActionT::Transform(t2)) => t1.merge(*t2),
+                    (a1, a2) => {
+                        if a2.priority() > a1.priority() {
+                            *a1 = a2;
+                        }
+                    }
+                }
                 s1.headers = match (s1.headers, s2.headers) {
                     (None, None) => None,
                     (Some(h1), None) => Some(h1),
@@ -93,6 +129,8 @@ pub fn stronger_decision(d1: SimpleDecision, d2: SimpleDecision) -> SimpleDecisi
                     }
                 };
                 SimpleDecision::Action(s1, kept_reasons)
+            } else if s1.atype.priority() > s2.atype.priority() {
+                SimpleDecision::Action(s1, kept_reasons)
             } else {
                 SimpleDecision::Action(s2, kept_reasons)
             }
@@ -108,6 +146,33 @@ pub struct AnalyzeResult {
     pub stats: Stats,
 }
 
+impl AnalyzeResult {
+    /// the request identifier, shown on the blocking pages and found in the logs
+    pub fn request_id(&self) -> Option<&str> {
+        self.rinfo.rinfo.meta.requestid.as_deref()
+    }
+
+    /// inspects the response of the upstream server, for a request that was let through
+    pub fn inspect_response(
+        &self,
+        logs: &mut Logs,
+        status: u32,
+        headers: HashMap<String, String>,
+        body: Option<Vec<u8>>,
+    ) -> ResponseAnalyzeResult {
+        analyze_response(
+            logs,
+            APhaseResp {
+                reqinfo: self.rinfo.clone(),
+                tags: self.tags.clone(),
+                status,
+                headers,
+                body,
+            },
+        )
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Decision {
     pub maction: Option<Action>,
@@ -146,6 +211,14 @@ impl Decision {
         self.maction.as_ref().map(|a| a.atype.is_blocking()).unwrap_or(false)
     }
 
+    /// changes to apply to the forwarded request, when it is not blocked
+    pub fn transform(&self) -> Option<&RequestTransform> {
+        self.maction
+            .as_ref()
+            .filter(|a| !a.block_mode)
+            .and_then(|a| a.transform.as_ref())
+    }
+
     /// is the action final (no further processing)
     pub fn is_final(&self) -> bool {
         self.maction.as_ref().map(|a| a.atype.is_final()).unwrap_or(false)
@@ -202,6 +275,8 @@ pub async fn jsonlog(
     match mrinfo {
         Some(rinfo) => {
             aggregator::aggregate(dec, status_code, rinfo, tags, bytes_sent).await;
+            crate::support::record_decision(dec, status_code, rinfo, tags);
+            stats::record_stage_durations(&stats.durations);
             match jsonlog_rinfo(dec, rinfo, status_code, tags, stats, logs, proxy, &now) {
                 Err(_) => (b"null".to_vec(), now),
                 Ok(y) => (y, now),
@@ -239,6 +314,11 @@ pub fn jsonlog_rinfo(
     map_ser.serialize_entry("curiesession_ids", &NameValue::new(&rinfo.session_ids))?;
     let request_id = proxy.get("request_id").or(rinfo.rinfo.meta.requestid.as_ref());
     map_ser.serialize_entry("request_id", &request_id)?;
+    if let Some(trace) = logs.trace.as_ref().or(rinfo.trace.as_ref()) {
+        map_ser.serialize_entry("trace_id", &trace.trace_id)?;
+        map_ser.serialize_entry("span_id", &trace.span_id)?;
+        map_ser.serialize_entry("parent_span_id", &trace.parent_id)?;
+    }
     map_ser.serialize_entry("arguments", &rinfo.rinfo.qinfo.args)?;
     map_ser.serialize_entry("path", &rinfo.rinfo.qinfo.qpath)?;
     map_ser.serialize_entry("path_parts", &rinfo.rinfo.qinfo.path_as_map)?;
@@ -251,6 +331,9 @@ pub fn jsonlog_rinfo(
     map_ser.serialize_entry("query", &rinfo.rinfo.qinfo.query)?;
     map_ser.serialize_entry("ip", &rinfo.rinfo.geoip.ip)?;
     map_ser.serialize_entry("method", &rinfo.rinfo.meta.method)?;
+    if let Some(cert) = &rinfo.rinfo.meta.client_cert {
+        map_ser.serialize_entry("client_cert", cert)?;
+    }
     map_ser.serialize_entry("response_code", &rcode)?;
     map_ser.serialize_entry("logs", logs)?;
     map_ser.serialize_entry("processing_stage", &stats.processing_stage)?;
@@ -260,6 +343,7 @@ pub fn jsonlog_rinfo(
     map_ser.serialize_entry("gf_triggers", get_trigger(&InitiatorKind::GlobalFilter))?;
     map_ser.serialize_entry("cf_triggers", get_trigger(&InitiatorKind::ContentFilter))?;
     map_ser.serialize_entry("cf_restrict_triggers", get_trigger(&InitiatorKind::Restriction))?;
+    map_ser.serialize_entry("schema_triggers", get_trigger(&InitiatorKind::Schema))?;
     map_ser.serialize_entry("reason", &block_reason_desc)?;
 
     let branch_tag = tags.inner().keys().filter_map(|t| t.strip_prefix("branch:")).next();
@@ -293,11 +377,7 @@ pub fn jsonlog_rinfo(
     // If we have a monitor action, remove the return code to prevent tag
     // addition. This could be fixed with a better Action structure, but
     // requires more changes.
-    if let Some(Action {
-        atype: ActionType::Monitor,
-        ..
-    }) = &dec.maction
-    {
+    if dec.maction.as_ref().map(|a| !a.atype.is_final()).unwrap_or(false) {
         rcode = None;
     }
 
@@ -396,9 +476,13 @@ pub fn jsonlog_rinfo(
         {
             let mut mp = serializer.serialize_map(None)?;
             mp.serialize_entry("revision", &self.0.revision)?;
+            mp.serialize_entry("config_hash", &self.0.config_hash)?;
             mp.serialize_entry("acl_active", &self.0.secpol.acl_enabled)?;
             mp.serialize_entry("cf_active", &self.0.secpol.content_filter_enabled)?;
             mp.serialize_entry("cf_rules", &self.0.content_filter_total)?;
+            if let Some(score) = self.0.content_filter_score {
+                mp.serialize_entry("cf_score", &score)?;
+            }
             mp.serialize_entry("rl_rules", &self.0.secpol.limit_amount)?;
             mp.serialize_entry("gf_rules", &self.0.secpol.globalfilters_amount)?;
             mp.serialize_entry("secpolid", &self.1.policy.id)?;
@@ -425,6 +509,7 @@ pub fn jsonlog_rinfo(
             let rate_limit = stats_counter(InitiatorKind::RateLimit);
             let content_filters = stats_counter(InitiatorKind::ContentFilter);
             let restriction = stats_counter(InitiatorKind::Restriction);
+            let schema = stats_counter(InitiatorKind::Schema);
 
             let mut mp = serializer.serialize_map(None)?;
             mp.serialize_entry("acl", &acl)?;
@@ -432,6 +517,7 @@ pub fn jsonlog_rinfo(
             mp.serialize_entry("rl", &rate_limit)?;
             mp.serialize_entry("cf", &content_filters)?;
             mp.serialize_entry("cf_restrict", &restriction)?;
+            mp.serialize_entry("schema", &schema)?;
             mp.end()
         }
     }
@@ -448,6 +534,7 @@ pub fn jsonlog_rinfo(
         }
     }
     map_ser.serialize_entry("profiling", &stats.timing)?;
+    map_ser.serialize_entry("stage_durations", &stats.durations)?;
     SerializeMap::end(map_ser)?;
     Ok(outbuffer)
 }
@@ -474,6 +561,93 @@ pub struct Action {
     pub headers: Option<HashMap<String, String>>,
     pub content: String,
     pub extra_tags: Option<HashSet<String>>,
+    /// headers added to the response of the server, for passing actions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_headers: Option<HashMap<String, String>>,
+    /// time to wait before sending a blocking response, in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delay: Option<u64>,
+    /// changes to the forwarded request, for passing actions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transform: Option<RequestTransform>,
+    /// seconds the client is banned for, when the action is blocking
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ban: Option<u64>,
+}
+
+/// how a passing request is altered before being forwarded, the headers to set are the action headers
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RequestTransform {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub remove_headers: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+}
+
+impl RequestTransform {
+    /// the first path and upstream are kept
+    pub fn merge(&mut self, other: Self) {
+        for h in other.remove_headers {
+            if !self.remove_headers.contains(&h) {
+                self.remove_headers.push(h);
+            }
+        }
+        self.path = self.path.take().or(other.path);
+        self.upstream = self.upstream.take().or(other.upstream);
+    }
+}
+
+/// a request transform, before rendering
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransformTemplate {
+    pub remove_headers: Vec<String>,
+    pub path: Option<RequestTemplate>,
+    pub upstream: Option<RequestTemplate>,
+}
+
+impl TransformTemplate {
+    fn merge(&mut self, other: Self) {
+        self.remove_headers.extend(other.remove_headers);
+        self.path = self.path.take().or(other.path);
+        self.upstream = self.upstream.take().or(other.upstream);
+    }
+
+    fn render(&self, rinfo: &RequestInfo, tags: &Tags) -> RequestTransform {
+        RequestTransform {
+            remove_headers: self.remove_headers.clone(),
+            path: self.path.as_ref().map(|p| render_template(rinfo, tags, p)),
+            upstream: self.upstream.as_ref().map(|u| render_template(rinfo, tags, u)),
+        }
+    }
+}
+
+/// the state of a rate limit, as advertised with the X-RateLimit-* headers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateQuota {
+    pub limit: u64,
+    pub remaining: u64,
+    /// seconds until the counter resets
+    pub reset: u64,
+}
+
+impl RateQuota {
+    /// Retry-After is only sent once the quota is exhausted
+    pub fn headers(&self) -> HashMap<String, String> {
+        let mut out: HashMap<String, String> = [
+            ("x-ratelimit-limit", self.limit),
+            ("x-ratelimit-remaining", self.remaining),
+            ("x-ratelimit-reset", self.reset),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        if self.remaining == 0 {
+            out.insert("retry-after".to_string(), self.reset.to_string());
+        }
+        out
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -482,6 +656,10 @@ pub enum SimpleActionT {
     Monitor,
     Custom { content: String },
     Challenge { ch_level: GHMode },
+    Redirect { location: RequestTemplate },
+    Tarpit { content: String, delay: u64 },
+    RateLimitHeaders,
+    Transform(Box<TransformTemplate>),
 }
 
 impl SimpleActionT {
@@ -489,7 +667,11 @@ impl SimpleActionT {
         use SimpleActionT::*;
         match self {
             Custom { content: _ } => 8,
+            Redirect { .. } => 8,
+            Tarpit { .. } => 7,
             Challenge { ch_level: _ } => 6,
+            Transform(_) => 3,
+            RateLimitHeaders => 2,
             Monitor => 1,
             Skip => 9,
         }
@@ -499,15 +681,27 @@ impl SimpleActionT {
         use SimpleActionT::*;
         match self {
             Custom { content: _ } => 8,
+            Redirect { .. } => 8,
+            Tarpit { .. } => 7,
             Challenge { .. } => 6,
             Monitor => 1,
+            RateLimitHeaders => 1,
+            Transform(_) => 1,
             // skip action should be ignored when using with rate limit
             Skip => 0,
         }
     }
 
     fn is_blocking(&self) -> bool {
-        !matches!(self, SimpleActionT::Monitor)
+        !self.is_passing()
+    }
+
+    /// the request is passed to the server
+    pub fn is_passing(&self) -> bool {
+        matches!(
+            self,
+            SimpleActionT::Monitor | SimpleActionT::RateLimitHeaders | SimpleActionT::Transform(_)
+        )
     }
 
     pub fn to_raw(&self) -> RawActionType {
@@ -515,6 +709,10 @@ impl SimpleActionT {
             SimpleActionT::Skip => RawActionType::Skip,
             SimpleActionT::Monitor => RawActionType::Monitor,
             SimpleActionT::Custom { .. } => RawActionType::Custom,
+            SimpleActionT::Redirect { .. } => RawActionType::Redirect,
+            SimpleActionT::Tarpit { .. } => RawActionType::Tarpit,
+            SimpleActionT::RateLimitHeaders => RawActionType::RateLimitHeaders,
+            SimpleActionT::Transform(_) => RawActionType::Transform,
             SimpleActionT::Challenge { ch_level } => {
                 if ch_level == &GHMode::Active {
                     RawActionType::Challenge
@@ -533,6 +731,12 @@ pub struct SimpleAction {
     pub headers: Option<HashMap<String, RequestTemplate>>,
     pub status: u32,
     pub extra_tags: Option<HashSet<String>>,
+    /// replaces the content of custom actions
+    pub template: Option<Arc<ResponseTemplate>>,
+    /// rate limit state, filled when the limits are checked
+    pub quota: Option<RateQuota>,
+    /// seconds the client is banned for, see the ban module
+    pub ban: Option<u64>,
 }
 
 impl Default for SimpleAction {
@@ -542,6 +746,9 @@ impl Default for SimpleAction {
             headers: None,
             status: 503,
             extra_tags: None,
+            template: None,
+            quota: None,
+            ban: None,
         }
     }
 }
@@ -560,22 +767,33 @@ pub enum ActionType {
     Skip,
     Monitor,
     Block,
+    Redirect,
+    Tarpit,
+    RateLimitHeaders,
+    Transform,
 }
 
 impl ActionType {
     /// is the action blocking (not passed to the underlying server)
     pub fn is_blocking(&self) -> bool {
-        matches!(self, ActionType::Block)
+        matches!(self, ActionType::Block | ActionType::Redirect | ActionType::Tarpit)
     }
 
     /// is the action final (no further processing)
     pub fn is_final(&self) -> bool {
-        !matches!(self, ActionType::Monitor)
+        !matches!(
+            self,
+            ActionType::Monitor | ActionType::RateLimitHeaders | ActionType::Transform
+        )
     }
 
     pub fn priority(&self) -> u32 {
         match self {
             ActionType::Block => 6,
+            ActionType::Redirect => 6,
+            ActionType::Tarpit => 6,
+            ActionType::Transform => 3,
+            ActionType::RateLimitHeaders => 2,
             ActionType::Monitor => 1,
             ActionType::Skip => 9,
         }
@@ -591,15 +809,26 @@ impl std::default::Default for Action {
             headers: None,
             content: "request denied".to_string(),
             extra_tags: None,
+            response_headers: None,
+            delay: None,
+            transform: None,
+            ban: None,
         }
     }
 }
 
+/// tarpit delay when none is configured, in milliseconds
+const DEFAULT_TARPIT_DELAY: u64 = 5000;
+
 impl SimpleAction {
-    pub fn resolve_actions(logs: &mut Logs, rawactions: Vec<RawAction>) -> HashMap<String, Self> {
+    pub fn resolve_actions(
+        logs: &mut Logs,
+        templates: &HashMap<String, Arc<ResponseTemplate>>,
+        rawactions: Vec<RawAction>,
+    ) -> HashMap<String, Self> {
         let mut out = HashMap::new();
         for raction in rawactions {
-            match Self::resolve(&raction) {
+            match Self::resolve(templates, &raction) {
                 Ok((id, action)) => {
                     out.insert(id, action);
                 }
@@ -609,7 +838,10 @@ impl SimpleAction {
         out
     }
 
-    fn resolve(rawaction: &RawAction) -> anyhow::Result<(String, SimpleAction)> {
+    fn resolve(
+        templates: &HashMap<String, Arc<ResponseTemplate>>,
+        rawaction: &RawAction,
+    ) -> anyhow::Result<(String, SimpleAction)> {
         let id = rawaction.id.clone();
         let atype = match rawaction.type_ {
             RawActionType::Skip => SimpleActionT::Skip,
@@ -623,8 +855,38 @@ impl SimpleAction {
             RawActionType::Ichallenge => SimpleActionT::Challenge {
                 ch_level: GHMode::Interactive,
             },
+            RawActionType::Redirect => SimpleActionT::Redirect {
+                location: parse_request_template(
+                    rawaction
+                        .params
+                        .location
+                        .as_deref()
+                        .ok_or_else(|| anyhow::anyhow!("redirect actions need a location"))?,
+                ),
+            },
+            RawActionType::Tarpit => SimpleActionT::Tarpit {
+                content: rawaction.params.content.clone().unwrap_or_default(),
+                delay: rawaction.params.delay.unwrap_or(DEFAULT_TARPIT_DELAY),
+            },
+            RawActionType::RateLimitHeaders => SimpleActionT::RateLimitHeaders,
+            RawActionType::Transform => SimpleActionT::Transform(Box::new(TransformTemplate {
+                remove_headers: rawaction
+                    .params
+                    .remove_headers
+                    .iter()
+                    .map(|h| h.to_ascii_lowercase())
+                    .collect(),
+                path: rawaction.params.path.as_deref().map(parse_request_template),
+                upstream: rawaction.params.upstream.as_deref().map(parse_request_template),
+            })),
+        };
+        let status = match (&atype, rawaction.params.status) {
+            (SimpleActionT::Redirect { .. }, None) => 302,
+            (SimpleActionT::Redirect { .. }, Some(s)) if !(300..400).contains(&s) => {
+                anyhow::bail!("redirect status must be 3xx, not {}", s)
+            }
+            (_, s) => s.unwrap_or(503),
         };
-        let status = rawaction.params.status.unwrap_or(503);
         let headers = rawaction.params.headers.as_ref().map(|hm| {
             hm.iter()
                 .map(|(k, v)| (k.to_string(), parse_request_template(v)))
@@ -635,6 +897,15 @@ impl SimpleAction {
         } else {
             Some(rawaction.tags.iter().cloned().collect())
         };
+        let template = match &rawaction.params.template {
+            None => None,
+            Some(tid) => Some(
+                templates
+                    .get(tid)
+                    .cloned()
+                    .ok_or_else(|| anyhow::anyhow!("unknown response template {}", tid))?,
+            ),
+        };
 
         Ok((
             id,
@@ -643,6 +914,9 @@ impl SimpleAction {
                 status,
                 headers,
                 extra_tags,
+                template,
+                quota: None,
+                ban: rawaction.params.ban.filter(|b| *b > 0),
             },
         ))
     }
@@ -659,6 +933,7 @@ impl SimpleAction {
         let mut reason = reason;
         action.block_mode = action.atype.is_blocking();
         action.status = self.status;
+        action.ban = self.ban;
         action.headers = self.headers.as_ref().map(|hm| {
             hm.iter()
                 .map(|(k, v)| (k.to_string(), render_template(rinfo, tags, v)))
@@ -669,7 +944,25 @@ impl SimpleAction {
             SimpleActionT::Monitor => action.atype = ActionType::Monitor,
             SimpleActionT::Custom { content } => {
                 action.atype = ActionType::Block;
-                action.content = content.clone();
+                self.render_content(&mut action, rinfo, tags, &reason, content);
+            }
+            SimpleActionT::Redirect { location } => {
+                action.atype = ActionType::Redirect;
+                action.content = String::new();
+                action
+                    .headers
+                    .get_or_insert_with(HashMap::new)
+                    .insert("location".to_string(), render_template(rinfo, tags, location));
+            }
+            SimpleActionT::Tarpit { content, delay } => {
+                action.atype = ActionType::Tarpit;
+                action.delay = Some(*delay);
+                self.render_content(&mut action, rinfo, tags, &reason, content);
+            }
+            SimpleActionT::RateLimitHeaders => action.atype = ActionType::RateLimitHeaders,
+            SimpleActionT::Transform(template) => {
+                action.atype = ActionType::Transform;
+                action.transform = Some(template.render(rinfo, tags));
             }
             SimpleActionT::Challenge { ch_level } => {
                 let is_human = match ch_level {
@@ -687,14 +980,47 @@ impl SimpleAction {
                 }
             }
         }
-        if action.atype == ActionType::Monitor {
+        if !action.atype.is_final() {
             action.status = 200;
             action.block_mode = false;
         }
+        if let Some(quota) = &self.quota {
+            if action.atype.is_blocking() {
+                let headers = action.headers.get_or_insert_with(HashMap::new);
+                for (k, v) in quota.headers() {
+                    headers.entry(k).or_insert(v);
+                }
+            } else {
+                action.response_headers = Some(quota.headers());
+            }
+        }
         Ok(Decision::action(action, reason))
     }
 
-    pub fn to_decision<GH: Grasshopper>(
+    /// the fixed content, or the response template matching the request
+    fn render_content(
+        &self,
+        action: &mut Action,
+        rinfo: &RequestInfo,
+        tags: &Tags,
+        reason: &[BlockReason],
+        content: &str,
+    ) {
+        let accept = rinfo.headers.get("accept").map(|s| s.as_str());
+        match self.template.as_ref().and_then(|t| t.select(accept).map(|s| (t, s))) {
+            None => action.content = content.to_string(),
+            Some((template, (format, body))) => {
+                action.content = render_response(rinfo, tags, reason, &template.support, format, body);
+                action
+                    .headers
+                    .get_or_insert_with(HashMap::new)
+                    .entry("content-type".to_string())
+                    .or_insert_with(|| format.content_type().to_string());
+            }
+        }
+    }
+
+    pub fn to_decision<GH: ChallengeProvider>(
         &self,
         logs: &mut Logs,
         precision_level: PrecisionLevel,
@@ -755,3 +1081,223 @@ fn render_template(rinfo: &RequestInfo, tags: &Tags, template: &[TemplatePart<TV
     }
     out
 }
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// renders a response template
+///
+/// In JSON templates, variables are replaced with JSON values, strings are quoted. In HTML templates, they are
+/// replaced with escaped text, lists being separated with commas.
+fn render_response(
+    rinfo: &RequestInfo,
+    tags: &Tags,
+    reasons: &[BlockReason],
+    support: &str,
+    format: ResponseFormat,
+    template: &[TemplatePart<RVar>],
+) -> String {
+    let mut out = String::new();
+    for p in template {
+        let value: serde_json::Value = match p {
+            TemplatePart::Raw(s) => {
+                out.push_str(s);
+                continue;
+            }
+            TemplatePart::Var(RVar::RequestId) => rinfo.rinfo.meta.requestid.clone().into(),
+            TemplatePart::Var(RVar::Support) => support.into(),
+            TemplatePart::Var(RVar::Reasons) => reasons
+                .iter()
+                .filter(|r| r.action.is_final())
+                .map(
+                    |r| match r.initiator.to_kind().and_then(|k| serde_json::to_value(k).ok()) {
+                        Some(serde_json::Value::String(kind)) => format!("{} {}", kind, r.name),
+                        _ => r.name.clone(),
+                    },
+                )
+                .collect::<Vec<_>>()
+                .into(),
+            TemplatePart::Var(RVar::Request(TVar::Selector(RequestSelector::Tags))) => {
+                let mut names: Vec<String> = tags.tags.keys().cloned().collect();
+                names.sort();
+                names.into()
+            }
+            TemplatePart::Var(RVar::Request(TVar::Tag(tagname))) => tags.contains(tagname).into(),
+            TemplatePart::Var(RVar::Request(TVar::Selector(sel))) => match selector(rinfo, sel, Some(tags)) {
+                None => serde_json::Value::Null,
+                Some(Selected::OStr(s)) => s.into(),
+                Some(Selected::Str(s)) => s.as_str().into(),
+                Some(Selected::U32(v)) => v.into(),
+            },
+        };
+        match format {
+            ResponseFormat::Json => out.push_str(&value.to_string()),
+            ResponseFormat::Html => match value {
+                serde_json::Value::Null => (),
+                serde_json::Value::String(s) => out.push_str(&escape_html(&s)),
+                serde_json::Value::Array(vs) => out.push_str(&escape_html(
+                    &vs.iter()
+                        .map(|v| v.as_str().map(|s| s.to_string()).unwrap_or_else(|| v.to_string()))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                )),
+                v => out.push_str(&v.to_string()),
+            },
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::virtualtags::VirtualTags;
+    use crate::interface::tagging::Location;
+    use crate::testutils::{request_info, TEST_IP};
+
+    fn transform(remove: &[&str], path: Option<&str>, upstream: Option<&str>) -> RequestTransform {
+        RequestTransform {
+            remove_headers: remove.iter().map(|h| h.to_string()).collect(),
+            path: path.map(|p| p.to_string()),
+            upstream: upstream.map(|u| u.to_string()),
+        }
+    }
+
+    fn transform_action(remove: &[&str], path: Option<&str>, upstream: Option<&str>) -> SimpleAction {
+        SimpleAction {
+            atype: SimpleActionT::Transform(Box::new(TransformTemplate {
+                remove_headers: remove.iter().map(|h| h.to_string()).collect(),
+                path: path.map(parse_request_template),
+                upstream: upstream.map(parse_request_template),
+            })),
+            headers: None,
+            status: 503,
+            extra_tags: None,
+            template: None,
+            quota: None,
+            ban: None,
+        }
+    }
+
+    fn render(action: &SimpleAction, tags: &Tags) -> Decision {
+        action
+            .build_decision(
+                &request_info(&[("path", "/api/users")], &[("host", "example.com")]),
+                tags,
+                PrecisionLevel::Invalid,
+                Vec::new(),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn transform_merge_removes_once() {
+        let mut t = transform(&["cookie", "x-debug"], None, None);
+        t.merge(transform(&["x-debug", "authorization"], None, None));
+        assert_eq!(t.remove_headers, vec!["cookie", "x-debug", "authorization"]);
+    }
+
+    #[test]
+    fn transform_merge_first_path_wins() {
+        let mut t = transform(&[], Some("/first"), None);
+        t.merge(transform(&[], Some("/second"), Some("backend")));
+        assert_eq!(t, transform(&[], Some("/first"), Some("backend")));
+    }
+
+    #[test]
+    fn transform_render() {
+        let tags = Tags::new(&VirtualTags::default());
+        let decision = render(
+            &transform_action(&["cookie"], Some("/v2${path}"), Some("pool-${headers.host}")),
+            &tags,
+        );
+        assert_eq!(
+            decision.transform(),
+            Some(&transform(&["cookie"], Some("/v2/api/users"), Some("pool-example.com")))
+        );
+        let action = decision.maction.unwrap();
+        assert_eq!(action.atype, ActionType::Transform);
+        assert_eq!(action.status, 200);
+        assert!(!action.block_mode);
+    }
+
+    #[test]
+    fn transform_render_edge_cases() {
+        let mut tags = Tags::new(&VirtualTags::default());
+        tags.insert("vip", Location::Request);
+        let decision = render(
+            &transform_action(
+                &[],
+                Some("/${headers.x-missing}/${tags.vip}/${tags.other}"),
+                Some("\\${ip}-${ip}"),
+            ),
+            &tags,
+        );
+        let expected_upstream = format!("${{ip}}-{}", TEST_IP);
+        assert_eq!(
+            decision.transform(),
+            Some(&transform(&[], Some("/nil/true/false"), Some(&expected_upstream)))
+        );
+    }
+
+    #[test]
+    fn transform_actions_combine() {
+        let tags = Tags::new(&VirtualTags::default());
+        let d1 = SimpleDecision::Action(transform_action(&["cookie"], None, Some("first")), Vec::new());
+        let d2 = SimpleDecision::Action(
+            transform_action(&["x-debug"], Some("/moved"), Some("second")),
+            Vec::new(),
+        );
+        let action = match stronger_decision(d1, d2) {
+            SimpleDecision::Action(a, _) => a,
+            SimpleDecision::Pass => panic!("expected an action"),
+        };
+        assert_eq!(
+            render(&action, &tags).transform(),
+            Some(&transform(&["cookie", "x-debug"], Some("/moved"), Some("first")))
+        );
+    }
+
+    #[test]
+    fn transform_monitor_merge() {
+        let tags = Tags::new(&VirtualTags::default());
+        let monitor = SimpleAction {
+            atype: SimpleActionT::Monitor,
+            ..transform_action(&[], None, None)
+        };
+        let merged = merge_decisions(
+            render(&monitor, &tags),
+            render(&transform_action(&["cookie"], Some("/moved"), None), &tags),
+        );
+        assert_eq!(merged.transform(), Some(&transform(&["cookie"], Some("/moved"), None)));
+    }
+
+    #[test]
+    fn transform_dropped_when_blocking() {
+        let tags = Tags::new(&VirtualTags::default());
+        let block = SimpleAction {
+            atype: SimpleActionT::Custom {
+                content: "blocked".to_string(),
+            },
+            ..transform_action(&[], None, None)
+        };
+        let merged = merge_decisions(
+            render(&block, &tags),
+            render(&transform_action(&["cookie"], Some("/moved"), None), &tags),
+        );
+        assert!(merged.is_blocking());
+        assert_eq!(merged.transform(), None);
+    }
+}
@@ -1,4 +1,7 @@
+use lazy_static::lazy_static;
 use serde::{ser::SerializeSeq, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
 use std::{marker::PhantomData, time::Instant};
 
 use crate::{config::hostmap::SecurityPolicy, utils::json::BigTableKV};
@@ -11,6 +14,8 @@ pub struct TimingInfo {
     limit: Option<u64>,
     acl: Option<u64>,
     content_filter: Option<u64>,
+    /// time spent waiting for the challenge provider
+    challenge: Option<u64>,
 }
 
 impl Serialize for TimingInfo {
@@ -43,10 +48,94 @@ impl Serialize for TimingInfo {
             name: "content_filter",
             value: &self.content_filter,
         })?;
+        mp.serialize_element(&BigTableKV {
+            name: "challenge",
+            value: &self.challenge,
+        })?;
         mp.end()
     }
 }
 
+/// wall-clock time spent in each stage, in microseconds
+#[derive(Default, Debug, Clone, Serialize)]
+pub struct StageDurations {
+    pub mapping: Option<u64>,
+    pub globalfilter: Option<u64>,
+    pub flow: Option<u64>,
+    pub limit: Option<u64>,
+    pub acl: Option<u64>,
+    pub content_filter: Option<u64>,
+    /// time spent in the Grasshopper calls
+    pub grasshopper: Option<u64>,
+}
+
+impl StageDurations {
+    fn stages(&self) -> [(&'static str, Option<u64>); 7] {
+        [
+            ("mapping", self.mapping),
+            ("globalfilter", self.globalfilter),
+            ("flow", self.flow),
+            ("limit", self.limit),
+            ("acl", self.acl),
+            ("content_filter", self.content_filter),
+            ("grasshopper", self.grasshopper),
+        ]
+    }
+}
+
+/// upper bounds of the latency histogram buckets, in microseconds, the last bucket holds everything above
+const LATENCY_BUCKETS: [u64; 11] = [50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000];
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LatencyHistogram {
+    pub count: u64,
+    /// sum of the durations, in microseconds
+    pub sum: u64,
+    pub max: u64,
+    /// counts for each bucket of LATENCY_BUCKETS, followed by the overflow bucket
+    pub buckets: Vec<u64>,
+    pub bounds: Vec<u64>,
+}
+
+impl LatencyHistogram {
+    fn record(&mut self, micros: u64) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; LATENCY_BUCKETS.len() + 1];
+            self.bounds = LATENCY_BUCKETS.to_vec();
+        }
+        let idx = LATENCY_BUCKETS
+            .iter()
+            .position(|b| micros <= *b)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[idx] += 1;
+        self.count += 1;
+        self.sum += micros;
+        self.max = self.max.max(micros);
+    }
+}
+
+lazy_static! {
+    static ref HISTOGRAMS: Mutex<BTreeMap<&'static str, LatencyHistogram>> = Mutex::new(BTreeMap::new());
+}
+
+/// adds the stage durations of a request to the latency histograms
+pub fn record_stage_durations(durations: &StageDurations) {
+    let mut histograms = HISTOGRAMS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    for (stage, duration) in durations.stages().iter() {
+        if let Some(micros) = duration {
+            histograms.entry(stage).or_default().record(*micros);
+        }
+    }
+}
+
+/// latency histograms of all the analyzed requests, by stage
+pub fn stage_latency_histograms() -> BTreeMap<&'static str, LatencyHistogram> {
+    HISTOGRAMS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+}
+
 pub struct BStageInit;
 pub struct BStageSecpol;
 #[derive(Clone)]
@@ -81,7 +170,11 @@ impl SecpolStats {
 #[derive(Debug, Clone)]
 pub struct Stats {
     start: Instant,
+    /// end of the previous stage
+    last: Instant,
     pub revision: String,
+    /// hash of the configuration files
+    pub config_hash: String,
     pub processing_stage: usize,
     pub secpol: SecpolStats,
 
@@ -104,15 +197,20 @@ pub struct Stats {
     pub content_filter_total: usize,
     content_filter_triggered: usize,
     content_filter_active: usize,
+    /// total anomaly score, for profiles in anomaly scoring mode
+    pub content_filter_score: Option<u32>,
 
     pub timing: TimingInfo,
+    pub durations: StageDurations,
 }
 
 impl Stats {
     pub fn new(start: Instant, revision: String) -> Self {
         Stats {
             start,
+            last: start,
             revision,
+            config_hash: String::new(),
             processing_stage: 0,
             secpol: SecpolStats::default(),
 
@@ -130,7 +228,27 @@ impl Stats {
             content_filter_total: 0,
             content_filter_triggered: 0,
             content_filter_active: 0,
+            content_filter_score: None,
             timing: TimingInfo::default(),
+            durations: StageDurations::default(),
+        }
+    }
+
+    /// time since the end of the previous stage, in microseconds
+    fn lap(&mut self) -> u64 {
+        let now = Instant::now();
+        let micros = now.duration_since(self.last).as_micros() as u64;
+        self.last = now;
+        micros
+    }
+}
+
+impl Stats {
+    /// records the time spent waiting for the challenge provider, in microseconds
+    pub fn challenge_latency(&mut self, micros: u64) {
+        if micros > 0 {
+            self.timing.challenge = Some(micros);
+            self.durations.grasshopper = Some(micros);
         }
     }
 }
@@ -142,6 +260,13 @@ pub struct StatsCollect<A> {
     phantom: PhantomData<A>,
 }
 
+impl<A> StatsCollect<A> {
+    /// revision of the configuration the request is analyzed with
+    pub fn revision(&self) -> &str {
+        &self.stats.revision
+    }
+}
+
 impl StatsCollect<BStageInit> {
     pub fn new(start: Instant, revision: String) -> Self {
         StatsCollect {
@@ -150,11 +275,17 @@ impl StatsCollect<BStageInit> {
         }
     }
 
+    pub fn config_hash(mut self, hash: &str) -> Self {
+        self.stats.config_hash = hash.to_string();
+        self
+    }
+
     pub fn secpol(self, secpol: SecpolStats) -> StatsCollect<BStageSecpol> {
         let mut stats = self.stats;
         stats.processing_stage = 1;
         stats.secpol = secpol;
         stats.timing.secpol = Some(stats.start.elapsed().as_micros() as u64);
+        stats.lap();
         StatsCollect {
             stats,
             phantom: PhantomData,
@@ -173,12 +304,19 @@ impl StatsCollect<BStageInit> {
 }
 
 impl StatsCollect<BStageSecpol> {
+    /// records the time spent mapping the request, that started at `since`
+    pub fn request_mapped(&mut self, since: Instant) {
+        self.stats.durations.mapping = Some(since.elapsed().as_micros() as u64);
+        self.stats.last = Instant::now();
+    }
+
     pub fn mapped(self, globalfilters_total: usize, globalfilters_active: usize) -> StatsCollect<BStageMapped> {
         let mut stats = self.stats;
         stats.processing_stage = 2;
         stats.globalfilters_total = globalfilters_total;
         stats.globalfilters_active = globalfilters_active;
         stats.timing.mapping = Some(stats.start.elapsed().as_micros() as u64);
+        stats.durations.globalfilter = Some(stats.lap());
         StatsCollect {
             stats,
             phantom: PhantomData,
@@ -198,6 +336,7 @@ impl StatsCollect<BStageMapped> {
     pub fn no_flow(self) -> StatsCollect<BStageFlow> {
         let mut stats = self.stats;
         stats.processing_stage = 3;
+        stats.lap();
         StatsCollect {
             stats,
             phantom: PhantomData,
@@ -210,6 +349,7 @@ impl StatsCollect<BStageMapped> {
         stats.flow_total = flow_total;
         stats.flow_active = flow_active;
         stats.timing.flow = Some(stats.start.elapsed().as_micros() as u64);
+        stats.durations.flow = Some(stats.lap());
         StatsCollect {
             stats,
             phantom: PhantomData,
@@ -225,6 +365,7 @@ impl StatsCollect<BStageFlow> {
     pub fn no_limit(self) -> StatsCollect<BStageLimit> {
         let mut stats = self.stats;
         stats.processing_stage = 4;
+        stats.lap();
         StatsCollect {
             stats,
             phantom: PhantomData,
@@ -237,6 +378,7 @@ impl StatsCollect<BStageFlow> {
         stats.limit_total = limit_total;
         stats.limit_active = limit_active;
         stats.timing.limit = Some(stats.start.elapsed().as_micros() as u64);
+        stats.durations.limit = Some(stats.lap());
         StatsCollect {
             stats,
             phantom: PhantomData,
@@ -254,6 +396,7 @@ impl StatsCollect<BStageLimit> {
         stats.processing_stage = 5;
         stats.acl_active = acl_active;
         stats.timing.acl = Some(stats.start.elapsed().as_micros() as u64);
+        stats.durations.acl = Some(stats.lap());
         StatsCollect {
             stats,
             phantom: PhantomData,
@@ -280,6 +423,7 @@ impl StatsCollect<BStageAcl> {
         stats.processing_stage = 6;
         stats.content_filter_total = total;
         stats.timing.content_filter = Some(stats.start.elapsed().as_micros() as u64);
+        stats.durations.content_filter = Some(stats.lap());
         StatsCollect {
             stats,
             phantom: PhantomData,
@@ -293,6 +437,7 @@ impl StatsCollect<BStageAcl> {
         stats.content_filter_active = active;
         stats.content_filter_triggered = triggered;
         stats.timing.content_filter = Some(stats.start.elapsed().as_micros() as u64);
+        stats.durations.content_filter = Some(stats.lap());
         StatsCollect {
             stats,
             phantom: PhantomData,
@@ -301,7 +446,35 @@ impl StatsCollect<BStageAcl> {
 }
 
 impl StatsCollect<BStageContentFilter> {
+    pub fn cf_score(self, score: u32) -> Self {
+        let mut stats = self.stats;
+        stats.content_filter_score = Some(score);
+        StatsCollect {
+            stats,
+            phantom: PhantomData,
+        }
+    }
+
     pub fn cf_stage_build(self) -> Stats {
         self.stats
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets() {
+        let mut h = LatencyHistogram::default();
+        h.record(10);
+        h.record(50);
+        h.record(700);
+        h.record(1_000_000);
+        assert_eq!(h.count, 4);
+        assert_eq!(h.max, 1_000_000);
+        assert_eq!(h.buckets[0], 2);
+        assert_eq!(h.buckets[4], 1);
+        assert_eq!(h.buckets[LATENCY_BUCKETS.len()], 1);
+    }
+}
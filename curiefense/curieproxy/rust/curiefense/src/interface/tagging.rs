@@ -31,6 +31,9 @@ pub enum Location {
     Plugins,
     Plugin(String),
     PluginValue(String, String),
+    Response,
+    ResponseHeader(String),
+    ResponseBody,
 }
 
 impl std::fmt::Display for Location {
@@ -62,6 +65,9 @@ impl std::fmt::Display for Location {
             Plugins => write!(f, "plugins"),
             Plugin(c) => write!(f, "plugin {}", c),
             PluginValue(c, v) => write!(f, "plugin {}={}", c, v),
+            Response => write!(f, "response"),
+            ResponseHeader(h) => write!(f, "response header {}", h),
+            ResponseBody => write!(f, "response body"),
         }
     }
 }
@@ -118,6 +124,9 @@ impl Location {
             Plugins => Some(Request),
             Plugin(_) => Some(Plugins),
             PluginValue(n, _) => Some(Plugin(n.clone())),
+            Response => None,
+            ResponseHeader(_) => Some(Response),
+            ResponseBody => Some(Response),
         }
     }
 
@@ -162,6 +171,26 @@ impl Location {
             SectionIdx::Plugins => Location::Plugins,
         }
     }
+    /// name of the argument, header, cookie or plugin entry
+    pub fn name(&self) -> Option<&str> {
+        use Location::*;
+        match self {
+            UriArgument(n)
+            | UriArgumentValue(n, _)
+            | RefererArgument(n)
+            | RefererArgumentValue(n, _)
+            | BodyArgument(n)
+            | BodyArgumentValue(n, _)
+            | Header(n)
+            | HeaderValue(n, _)
+            | Cookie(n)
+            | CookieValue(n, _)
+            | Plugin(n)
+            | PluginValue(n, _)
+            | ResponseHeader(n) => Some(n),
+            _ => None,
+        }
+    }
     pub fn serialize_with_parent<S: serde::Serializer>(
         &self,
         map: &mut <S as serde::Serializer>::SerializeMap,
@@ -240,6 +269,15 @@ impl Location {
             Location::PluginValue(_, value) => {
                 map.serialize_entry("value", value)?;
             }
+            Location::Response => {
+                map.serialize_entry("section", "response")?;
+            }
+            Location::ResponseHeader(name) => {
+                map.serialize_entry("name", name)?;
+            }
+            Location::ResponseBody => {
+                map.serialize_entry("part", "body")?;
+            }
         }
         if let Some(p) = self.parent(ParentMode::LoggingOnly) {
             p.serialize_with_parent::<S>(map)?;
@@ -341,6 +379,9 @@ impl Tags {
     }
 
     pub fn insert_locs(&mut self, value: &str, locs: HashSet<Location>) {
+        if !crate::memory::charge(value.len()) {
+            return;
+        }
         let tag = tagify(value);
         if let Some(vtags) = self.vtags.get(&tag) {
             for vtag in vtags {
@@ -576,6 +617,9 @@ mod test {
             Cookies,
             Cookie("foo".to_string()),
             CookieValue("foo".to_string(), "foo".to_string()),
+            Response,
+            ResponseHeader("foo".to_string()),
+            ResponseBody,
         ];
         for location in locations {
             let res = serde_json::to_string(location).unwrap();
@@ -0,0 +1,461 @@
+//! Bearer token (JWT) inspection.
+//!
+//! When enabled on a security policy entry, the token is decoded, its header and claims are added as
+//! arguments (jwt-header-*, jwt-claim-*) so that they are inspected like the rest of the request, and
+//! tags describe the token (jwt-alg:*, jwt-iss:*, jwt-expired ...).
+//!
+//! Signatures are checked when a JWKS endpoint is configured. Key sets are fetched and refreshed in the
+//! background, so that requests never wait on the endpoint: until the keys are available, tokens are
+//! tagged with jwt-unverified and rejected when the token is enforced. Key sets that are no longer used
+//! by the configuration stop being refreshed when it is reloaded.
+use jsonwebtoken::jwk::{AlgorithmParameters, Jwk, JwkSet};
+use jsonwebtoken::{crypto, Algorithm, DecodingKey};
+use lazy_static::lazy_static;
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::config::raw::RawJwtSettings;
+use crate::config::Config;
+use crate::interface::{Location, Tags};
+use crate::logs::Logs;
+use crate::utils::decoders::base64dec_all;
+use crate::utils::RequestInfo;
+
+lazy_static! {
+    static ref JWKS: RwLock<HashMap<String, KeySet>> = RwLock::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Default)]
+struct CachedKeys {
+    keys: Option<Arc<JwkSet>>,
+    error: Option<String>,
+}
+
+#[derive(Debug)]
+struct KeySet {
+    cached: CachedKeys,
+    /// the refresh thread stops when this is dropped
+    _stop: Sender<()>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JwtSettings {
+    pub header: String,
+    pub jwks_url: Option<String>,
+    pub jwks_refresh: Duration,
+    pub issuers: HashSet<String>,
+    pub audiences: HashSet<String>,
+    pub leeway: i64,
+    pub required: bool,
+    pub enforce: bool,
+}
+
+impl JwtSettings {
+    pub fn resolve(raw: RawJwtSettings) -> Self {
+        JwtSettings {
+            header: raw
+                .header
+                .map(|h| h.to_lowercase())
+                .unwrap_or_else(|| "authorization".to_string()),
+            jwks_url: raw.jwks_url.filter(|u| !u.is_empty()),
+            jwks_refresh: Duration::from_secs(raw.jwks_refresh_seconds.unwrap_or(300).max(1)),
+            issuers: raw.issuers.into_iter().collect(),
+            audiences: raw.audiences.into_iter().collect(),
+            leeway: raw.leeway_seconds.min(i64::MAX as u64) as i64,
+            required: raw.required,
+            enforce: raw.enforce,
+        }
+    }
+}
+
+/// a token problem, used as the block reason
+pub type JwtProblem = &'static str;
+
+pub fn decode_part(part: &str) -> Option<Map<String, Value>> {
+    let bytes = base64dec_all(part).ok()?;
+    match serde_json::from_slice(&bytes).ok()? {
+        Value::Object(o) => Some(o),
+        _ => None,
+    }
+}
+
+fn value_string(v: &Value) -> String {
+    match v {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// the token of a header value, that is either a bare token or uses the Bearer scheme
+///
+/// values using other schemes, such as Basic credentials, are not tokens
+pub fn bearer_token(value: &str) -> Option<&str> {
+    let value = value.trim();
+    match value.split_once(' ') {
+        None => Some(value),
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => Some(token.trim_start()),
+        Some(_) => None,
+    }
+}
+
+fn fetch_jwks(url: &str) -> anyhow::Result<JwkSet> {
+    let content = if url.starts_with("http://") || url.starts_with("https://") {
+        let connector = native_tls::TlsConnector::new()?;
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .tls_connector(Arc::new(connector))
+            .build();
+        agent.get(url).call()?.into_string()?
+    } else {
+        std::fs::read_to_string(url.strip_prefix("file://").unwrap_or(url))?
+    };
+    Ok(serde_json::from_str(&content)?)
+}
+
+fn store_keys(url: &str, res: anyhow::Result<JwkSet>) {
+    if let Ok(mut w) = JWKS.write() {
+        let entry = match w.get_mut(url) {
+            // the key set is no longer used
+            None => return,
+            Some(e) => &mut e.cached,
+        };
+        match res {
+            Ok(set) => {
+                entry.keys = Some(Arc::new(set));
+                entry.error = None;
+            }
+            // the previous keys are kept
+            Err(rr) => entry.error = Some(rr.to_string()),
+        }
+    }
+}
+
+/// returns the cached key set, and starts refreshing it when it is requested for the first time
+fn cached_keys(url: &str, refresh: Duration) -> CachedKeys {
+    if let Ok(r) = JWKS.read() {
+        if let Some(c) = r.get(url) {
+            return c.cached.clone();
+        }
+    }
+    let mut w = match JWKS.write() {
+        Ok(w) => w,
+        Err(_) => return CachedKeys::default(),
+    };
+    if let Some(c) = w.get(url) {
+        return c.cached.clone();
+    }
+    let (stop, stopped) = channel();
+    w.insert(
+        url.to_string(),
+        KeySet {
+            cached: CachedKeys::default(),
+            _stop: stop,
+        },
+    );
+    let url = url.to_string();
+    std::thread::spawn(move || loop {
+        let res = fetch_jwks(&url);
+        let delay = if res.is_ok() {
+            refresh
+        } else {
+            refresh.min(Duration::from_secs(30))
+        };
+        store_keys(&url, res);
+        match stopped.recv_timeout(delay) {
+            Err(RecvTimeoutError::Timeout) => (),
+            _ => break,
+        }
+    });
+    CachedKeys::default()
+}
+
+/// stops refreshing the key sets that are not used by the configuration
+pub fn retain_key_sets(config: &Config) {
+    let hostmaps = config
+        .securitypolicies
+        .iter()
+        .map(|m| &m.inner)
+        .chain(config.securitypolicies_map.values())
+        .chain(config.default.iter());
+    let used: HashSet<&str> = hostmaps
+        .flat_map(|h| {
+            h.entries
+                .iter()
+                .map(|e| &e.inner)
+                .chain(h.templates.values().flatten().map(|t| &t.policy))
+                .chain(h.default.iter())
+        })
+        .filter_map(|p| p.jwt.as_ref().and_then(|j| j.jwks_url.as_deref()))
+        .collect();
+    if let Ok(mut w) = JWKS.write() {
+        w.retain(|url, _| used.contains(url.as_str()));
+    }
+}
+
+/// checks that the key type matches the algorithm family, so that a public key is never used as a HMAC secret
+fn key_accepts(jwk: &Jwk, alg: Algorithm) -> bool {
+    use Algorithm::*;
+    let family_ok = match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => matches!(alg, RS256 | RS384 | RS512 | PS256 | PS384 | PS512),
+        AlgorithmParameters::EllipticCurve(_) => matches!(alg, ES256 | ES384),
+        AlgorithmParameters::OctetKeyPair(_) => alg == EdDSA,
+        AlgorithmParameters::OctetKey(_) => matches!(alg, HS256 | HS384 | HS512),
+    };
+    family_ok && jwk.common.algorithm.map(|a| a == alg).unwrap_or(true)
+}
+
+fn check_signature(keys: &JwkSet, kid: Option<&str>, alg: Algorithm, message: &str, signature: &str) -> bool {
+    let candidates: Vec<_> = match kid {
+        Some(k) => keys.find(k).into_iter().collect(),
+        None => keys.keys.iter().collect(),
+    };
+    candidates
+        .into_iter()
+        .filter(|jwk| key_accepts(jwk, alg))
+        .filter_map(|jwk| DecodingKey::from_jwk(jwk).ok())
+        .any(|key| crypto::verify(signature, message.as_bytes(), &key, alg).unwrap_or(false))
+}
+
+/// inspects the bearer token, adding tags and arguments to the request
+///
+/// returns the first problem that was found with the token
+pub fn inspect_jwt(
+    logs: &mut Logs,
+    settings: &JwtSettings,
+    reqinfo: &mut RequestInfo,
+    tags: &mut Tags,
+) -> Option<JwtProblem> {
+    let loc = Location::Header(settings.header.clone());
+    let token = match reqinfo.headers.get_str(&settings.header).and_then(bearer_token) {
+        Some(v) => v.to_string(),
+        None => {
+            if settings.required {
+                tags.insert("jwt-missing", loc);
+                return Some("missing");
+            }
+            return None;
+        }
+    };
+    tags.insert("jwt", loc.clone());
+
+    let parts: Vec<&str> = token.split('.').collect();
+    let (header, claims) = match parts.as_slice() {
+        [h, c, _] => match (decode_part(h), decode_part(c)) {
+            (Some(header), Some(claims)) => (header, claims),
+            _ => {
+                tags.insert("jwt-malformed", loc);
+                return Some("malformed");
+            }
+        },
+        _ => {
+            tags.insert("jwt-malformed", loc);
+            return Some("malformed");
+        }
+    };
+
+    let args = &mut reqinfo.rinfo.qinfo.args;
+    for (k, v) in &header {
+        args.add(format!("jwt-header-{}", k), loc.clone(), value_string(v));
+    }
+    for (k, v) in &claims {
+        args.add(format!("jwt-claim-{}", k), loc.clone(), value_string(v));
+    }
+
+    let mut problems: Vec<(&str, JwtProblem)> = Vec::new();
+    let alg = header.get("alg").and_then(|a| a.as_str()).unwrap_or("none");
+    tags.insert_qualified("jwt-alg", alg, loc.clone());
+    let iss = claims.get("iss").and_then(|i| i.as_str());
+    if let Some(i) = iss {
+        tags.insert_qualified("jwt-iss", i, loc.clone());
+    }
+
+    let now = reqinfo.timestamp.timestamp();
+    let claim_time = |name: &str| claims.get(name).and_then(|v| v.as_f64()).map(|f| f as i64);
+    if let Some(exp) = claim_time("exp") {
+        if exp.saturating_add(settings.leeway) < now {
+            problems.push(("jwt-expired", "expired"));
+        }
+    }
+    if let Some(nbf) = claim_time("nbf") {
+        if nbf > now.saturating_add(settings.leeway) {
+            problems.push(("jwt-not-yet-valid", "not yet valid"));
+        }
+    }
+    if !settings.issuers.is_empty() && !iss.map(|i| settings.issuers.contains(i)).unwrap_or(false) {
+        problems.push(("jwt-bad-issuer", "bad issuer"));
+    }
+    if !settings.audiences.is_empty() {
+        let audience_ok = match claims.get("aud") {
+            Some(Value::String(a)) => settings.audiences.contains(a),
+            Some(Value::Array(auds)) => auds
+                .iter()
+                .any(|a| a.as_str().map(|a| settings.audiences.contains(a)).unwrap_or(false)),
+            _ => false,
+        };
+        if !audience_ok {
+            problems.push(("jwt-bad-audience", "bad audience"));
+        }
+    }
+
+    if let Some(url) = &settings.jwks_url {
+        match Algorithm::from_str(alg) {
+            // unsigned tokens, or unknown algorithms, can't be trusted when signatures are checked
+            Err(_) => problems.push(("jwt-unsigned", "unsigned")),
+            Ok(algorithm) => {
+                let cached = cached_keys(url, settings.jwks_refresh);
+                if let Some(rr) = &cached.error {
+                    logs.warning(|| format!("could not refresh the key set {}: {}", url, rr));
+                }
+                match cached.keys {
+                    // fails closed, the token can't be trusted
+                    None => problems.push(("jwt-unverified", "unverified")),
+                    Some(keys) => {
+                        let kid = header.get("kid").and_then(|k| k.as_str());
+                        let message = format!("{}.{}", parts[0], parts[1]);
+                        if check_signature(&keys, kid, algorithm, &message, parts[2]) {
+                            tags.insert("jwt-valid-signature", loc.clone());
+                        } else {
+                            problems.push(("jwt-bad-signature", "bad signature"));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    for (tag, _) in &problems {
+        tags.insert(tag, loc.clone());
+    }
+    // the subject can identify the client once the token is known to be genuine
+    if problems.is_empty() && tags.contains("jwt-valid-signature") {
+        reqinfo.jwt_subject = claims.get("sub").and_then(|s| s.as_str()).map(|s| s.to_string());
+    }
+    problems.first().map(|(_, p)| *p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::virtualtags::VirtualTags;
+    use crate::testutils::request_info;
+
+    fn mk_reqinfo(authorization: &str) -> RequestInfo {
+        request_info(&[("authority", "x.com")], &[("authorization", authorization)])
+    }
+
+    // {"alg":"HS256","typ":"JWT"} {"sub":"1234567890","iss":"Issuer","exp":1000}
+    const EXPIRED: &str = "Bearer eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.eyJzdWIiOiIxMjM0NTY3ODkwIiwiaXNzIjoiSXNzdWVyIiwiZXhwIjoxMDAwfQ.c2ln";
+
+    #[test]
+    fn expired_token() {
+        let settings = JwtSettings::resolve(RawJwtSettings::default());
+        let mut reqinfo = mk_reqinfo(EXPIRED);
+        let mut tags = Tags::new(&VirtualTags::default());
+        let mut logs = Logs::default();
+        let problem = inspect_jwt(&mut logs, &settings, &mut reqinfo, &mut tags);
+        assert_eq!(problem, Some("expired"));
+        assert!(tags.contains("jwt"));
+        assert!(tags.contains("jwt-alg:hs256"));
+        assert!(tags.contains("jwt-iss:issuer"));
+        assert!(tags.contains("jwt-expired"));
+        assert_eq!(reqinfo.rinfo.qinfo.args.get_str("jwt-claim-sub"), Some("1234567890"));
+        assert_eq!(reqinfo.rinfo.qinfo.args.get_str("jwt-header-typ"), Some("JWT"));
+        assert_eq!(reqinfo.jwt_subject, None);
+    }
+
+    #[test]
+    fn malformed_and_missing() {
+        let mut settings = JwtSettings::resolve(RawJwtSettings::default());
+        let mut logs = Logs::default();
+        let mut tags = Tags::new(&VirtualTags::default());
+        let mut reqinfo = mk_reqinfo("Bearer not.a-token");
+        assert_eq!(
+            inspect_jwt(&mut logs, &settings, &mut reqinfo, &mut tags),
+            Some("malformed")
+        );
+        assert!(tags.contains("jwt-malformed"));
+
+        settings.header = "x-token".to_string();
+        assert_eq!(inspect_jwt(&mut logs, &settings, &mut reqinfo, &mut tags), None);
+        settings.required = true;
+        assert_eq!(
+            inspect_jwt(&mut logs, &settings, &mut reqinfo, &mut tags),
+            Some("missing")
+        );
+    }
+
+    #[test]
+    fn other_schemes() {
+        assert_eq!(bearer_token("bearer  a.b.c"), Some("a.b.c"));
+        assert_eq!(bearer_token("a.b.c"), Some("a.b.c"));
+        assert_eq!(bearer_token("Basic dXNlcjpwYXNz"), None);
+
+        let settings = JwtSettings::resolve(RawJwtSettings::default());
+        let mut logs = Logs::default();
+        let mut tags = Tags::new(&VirtualTags::default());
+        let mut reqinfo = mk_reqinfo("Basic dXNlcjpwYXNz");
+        assert_eq!(inspect_jwt(&mut logs, &settings, &mut reqinfo, &mut tags), None);
+        assert!(!tags.contains("jwt-malformed"));
+    }
+
+    #[test]
+    fn unavailable_keys() {
+        let settings = JwtSettings::resolve(RawJwtSettings {
+            jwks_url: Some("file:///nonexistent/jwks.json".to_string()),
+            ..RawJwtSettings::default()
+        });
+        let mut logs = Logs::default();
+        let mut tags = Tags::new(&VirtualTags::default());
+        // {"alg":"HS256","typ":"JWT"} {"sub":"1234567890"}
+        let mut reqinfo = mk_reqinfo("Bearer eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.eyJzdWIiOiIxMjM0NTY3ODkwIn0.c2ln");
+        assert_eq!(
+            inspect_jwt(&mut logs, &settings, &mut reqinfo, &mut tags),
+            Some("unverified")
+        );
+        assert!(tags.contains("jwt-unverified"));
+        // the claims are inspected, but do not identify the client
+        assert_eq!(reqinfo.rinfo.qinfo.args.get_str("jwt-claim-sub"), Some("1234567890"));
+        assert_eq!(reqinfo.jwt_subject, None);
+    }
+
+    fn key_set(jwk: &str) -> JwkSet {
+        serde_json::from_str(&format!("{{\"keys\":[{}]}}", jwk)).unwrap()
+    }
+
+    fn hs256(secret: &[u8], message: &str) -> String {
+        crypto::sign(
+            message.as_bytes(),
+            &jsonwebtoken::EncodingKey::from_secret(secret),
+            Algorithm::HS256,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn hmac_secret() {
+        let keys = key_set(r#"{"kty":"oct","k":"c2VjcmV0"}"#);
+        let signature = hs256(b"secret", "a.b");
+        assert!(check_signature(&keys, None, Algorithm::HS256, "a.b", &signature));
+        assert!(!check_signature(&keys, None, Algorithm::HS256, "a.c", &signature));
+    }
+
+    #[test]
+    fn public_key_as_hmac_secret() {
+        // the decoding key of this EC key holds the 0x04 || x || y bytes
+        let keys = key_set(r#"{"kty":"EC","crv":"P-256","x":"AQID","y":"BAUG"}"#);
+        let forged = hs256(&[4, 1, 2, 3, 4, 5, 6], "a.b");
+        assert!(!check_signature(&keys, None, Algorithm::HS256, "a.b", &forged));
+    }
+
+    #[test]
+    fn rsa_key_with_hmac_token() {
+        // verifying a HMAC signature with a RSA key used to panic
+        let keys = key_set(r#"{"kty":"RSA","n":"AQAB","e":"AQAB"}"#);
+        let signature = hs256(b"secret", "a.b");
+        assert!(!check_signature(&keys, None, Algorithm::HS256, "a.b", &signature));
+    }
+}
@@ -1,43 +1,130 @@
+pub mod abtest;
+pub mod accesslog;
 pub mod acl;
 pub mod analyze;
+pub mod apikey;
+pub mod ban;
 pub mod body;
+pub mod botscore;
+pub mod bypass;
+pub mod cadence;
+pub mod canary;
+pub mod captcha;
+pub mod challenge;
+pub mod checkout;
+pub mod clearance;
+pub mod clientip;
+pub mod clientstate;
 pub mod config;
 pub mod contentfilter;
+pub mod counters;
+pub mod csrf;
+pub mod dataleak;
+pub mod decisioncache;
+pub mod failure;
+pub mod fingerprint;
 pub mod flow;
 pub mod geo;
+pub mod geoprovider;
 pub mod grasshopper;
+pub mod honeypot;
+pub mod hooks;
 pub mod incremental;
 pub mod interface;
 pub mod ipinfo;
+pub mod jwt;
 pub mod limit;
+pub mod localstore;
+pub mod login;
 pub mod logs;
+pub mod logsink;
+pub mod masking;
+pub mod memcached;
+pub mod memory;
+pub mod offender;
+pub mod otel;
+pub mod overrides;
+pub mod pow;
+pub mod protocol;
+pub mod quota;
 pub mod redis;
+pub mod replay;
+pub mod reputation;
 pub mod requestfields;
+pub mod requestid;
+pub mod responsefilter;
+pub mod scraping;
 pub mod securitypolicy;
+pub mod session;
+pub mod siem;
+pub mod signature;
 pub mod simple_executor;
+pub mod support;
 pub mod tagging;
+#[cfg(test)]
+pub mod testutils;
+pub mod topn;
 pub mod utils;
+pub mod wasm;
+pub mod webhook;
+pub mod websocket;
 
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Instant;
 
 use analyze::{APhase0, CfRulesArg};
+use ban::{ban_action, banned, Ban};
+use bypass::{consume_bypass_nonce, consume_bypass_nonce_block, BypassMethod, BYPASS_TAG};
+use challenge::ChallengeProvider;
+use clearance::{clearance_cookie, request_clearance, ClearanceSettings};
 use config::virtualtags::VirtualTags;
 use config::with_config;
-use grasshopper::{GHQuery, Grasshopper, PrecisionLevel};
+use failure::{dependency_failed, Dependency};
+use grasshopper::{GHQuery, PrecisionLevel};
 use interface::stats::{SecpolStats, Stats, StatsCollect};
 use interface::{Action, ActionType, AnalyzeResult, BlockReason, Decision, Location, Tags};
 use logs::Logs;
-use securitypolicy::match_securitypolicy;
+use overrides::{check_overrides, OverrideVerdict};
+use securitypolicy::route_securitypolicy;
+use signature::{SignatureProblem, SIGNATURE_TAG};
 use simple_executor::{Executor, Progress, Task};
 use tagging::tag_request;
+use utils::url::normalize_uri;
 use utils::{map_request, RawRequest, RequestInfo};
 
+use crate::config::contentfilter::BodyAnalysisDepth;
 use crate::config::hostmap::SecurityPolicy;
-use crate::interface::SimpleAction;
+use crate::interface::{stronger_decision, SimpleAction, SimpleActionT, SimpleDecision};
 //todo should receive sdk configuration from config/raw.rs struct, and pass it to gg
-fn challenge_verified<GH: Grasshopper>(gh: &GH, reqinfo: &RequestInfo, logs: &mut Logs) -> PrecisionLevel {
-    match gh.is_human(GHQuery {
+/// the precision level of the request, from its clearance cookie, or from the challenge provider
+///
+/// without challenge provider, default to being not human, returns None when the provider failed
+fn challenge_verified<GH: ChallengeProvider>(
+    mgh: Option<&GH>,
+    clearance: Option<&ClearanceSettings>,
+    reqinfo: &mut RequestInfo,
+    logs: &mut Logs,
+) -> Option<PrecisionLevel> {
+    if let Some(level) = clearance.and_then(|settings| request_clearance(settings, reqinfo)) {
+        logs.debug("valid clearance cookie");
+        return Some(level);
+    }
+    if let Some(level) = reqinfo
+        .rinfo
+        .secpolicy
+        .captcha
+        .as_ref()
+        .and_then(|c| c.cached_level(reqinfo))
+    {
+        logs.debug("verified CAPTCHA token");
+        return Some(level);
+    }
+    let gh = match mgh {
+        None => return Some(PrecisionLevel::Invalid),
+        Some(gh) => gh,
+    };
+    let level = match gh.is_human(GHQuery {
         headers: reqinfo.headers.as_map(),
         cookies: reqinfo.cookies.as_map(),
         ip: &reqinfo.rinfo.geoip.ipstr,
@@ -46,9 +133,13 @@ fn challenge_verified<GH: Grasshopper>(gh: &GH, reqinfo: &RequestInfo, logs: &mu
         Ok(level) => level,
         Err(rr) => {
             logs.error(|| format!("Grasshopper: {}", rr));
-            PrecisionLevel::Invalid
+            return None;
         }
+    };
+    if let Some(settings) = clearance.filter(|_| level.is_human()) {
+        reqinfo.clearance = Some(clearance_cookie(settings, level, reqinfo));
     }
+    Some(level)
 }
 
 /// # Safety
@@ -71,7 +162,7 @@ pub unsafe fn inspect_async_free(ptr: *mut Executor<(Decision, Tags, Logs)>) {
     let _x = Box::from_raw(ptr);
 }
 
-pub fn inspect_generic_request_map<GH: Grasshopper>(
+pub fn inspect_generic_request_map<GH: ChallengeProvider>(
     mgh: Option<&GH>,
     raw: RawRequest,
     logs: &mut Logs,
@@ -87,14 +178,97 @@ pub fn inspect_generic_request_map<GH: Grasshopper>(
     ))
 }
 
+/// result of a request that is in the override list, the body is not decoded
+fn override_result<GH: ChallengeProvider>(
+    logs: &mut Logs,
+    mgh: Option<&GH>,
+    verdict: OverrideVerdict,
+    raw: &RawRequest,
+    mut tags: Tags,
+    start: chrono::DateTime<chrono::Utc>,
+    plugins: HashMap<String, String>,
+) -> AnalyzeResult {
+    let revision = with_config(logs, |_, cfg| cfg.revision.clone()).unwrap_or_else(|| "unknown".into());
+    let mut secpol = SecurityPolicy::default();
+    secpol.content_filter_profile.ignore_body = true;
+    let rinfo = map_request(logs, Arc::new(secpol), None, raw, Some(start), plugins);
+    let decision = match verdict {
+        OverrideVerdict::Block => {
+            logs.debug("request blocked by the override list");
+            let action = SimpleAction::default();
+            let reason = BlockReason::restricted(
+                "override-list".to_string(),
+                "override list".to_string(),
+                action.atype.to_raw(),
+                Location::Ip,
+                rinfo.rinfo.geoip.ipstr.clone(),
+                "in the override block list".to_string(),
+            );
+            tags.insert("override-block", Location::Ip);
+            action.to_decision(logs, PrecisionLevel::Invalid, mgh, &rinfo, &mut tags, vec![reason])
+        }
+        OverrideVerdict::Allow => {
+            logs.debug("request allowed by the override list");
+            tags.insert("override-allow", Location::Ip);
+            Decision::pass(Vec::new())
+        }
+    };
+    AnalyzeResult {
+        decision,
+        tags,
+        rinfo,
+        stats: Stats::new(logs.start, revision),
+    }
+}
+
+/// a mapped request, before its signed bypass is checked
+struct MappedRequest {
+    p0: APhase0,
+    signed_bypass: Option<String>,
+    jwt_problem: Option<(String, jwt::JwtProblem)>,
+}
+
 // generic entry point when the request map has already been parsed
-pub fn inspect_generic_request_map_init<GH: Grasshopper>(
+pub fn inspect_generic_request_map_init<GH: ChallengeProvider>(
     mgh: Option<&GH>,
     raw: RawRequest,
     logs: &mut Logs,
     selected_secpol: Option<&str>,
     plugins: HashMap<String, String>,
 ) -> Result<APhase0, AnalyzeResult> {
+    let mut mapped = init_mapping(mgh, &raw, logs, selected_secpol, plugins)?;
+    if let Some(nonce) = mapped.signed_bypass.take() {
+        let consumed = consume_bypass_nonce_block(&mapped.p0.reqinfo, &nonce);
+        mapped = signed_bypass_check(logs, mapped, consumed)?;
+    }
+    init_finish(logs, mgh, mapped, &raw)
+}
+
+/// same as inspect_generic_request_map_init, without blocking on the nonce of signed bypasses
+pub async fn inspect_generic_request_map_init_async<GH: ChallengeProvider>(
+    mgh: Option<&GH>,
+    raw: RawRequest<'_>,
+    logs: &mut Logs,
+    selected_secpol: Option<&str>,
+    plugins: HashMap<String, String>,
+) -> Result<APhase0, AnalyzeResult> {
+    let mut mapped = init_mapping(mgh, &raw, logs, selected_secpol, plugins)?;
+    if let Some(nonce) = mapped.signed_bypass.take() {
+        let consumed = consume_bypass_nonce(&mapped.p0.reqinfo, &nonce).await;
+        mapped = signed_bypass_check(logs, mapped, consumed)?;
+    }
+    init_finish(logs, mgh, mapped, &raw)
+}
+
+/// routes and maps the request, the early results are returned as errors
+#[allow(clippy::result_large_err)]
+fn init_mapping<GH: ChallengeProvider>(
+    mgh: Option<&GH>,
+    raw: &RawRequest,
+    logs: &mut Logs,
+    selected_secpol: Option<&str>,
+    plugins: HashMap<String, String>,
+) -> Result<MappedRequest, AnalyzeResult> {
     let start = chrono::Utc::now();
 
     // insert the all tag here, to make sure it is always present, even in the presence of early errors
@@ -102,10 +276,18 @@ pub fn inspect_generic_request_map_init<GH: Grasshopper>(
 
     logs.debug(|| format!("Inspection starts (grasshopper active: {})", mgh.is_some()));
 
+    // the emergency override list is applied before any security policy is evaluated
+    if let Some(verdict) = check_overrides(logs, raw) {
+        return Err(override_result(logs, mgh, verdict, raw, tags, start, plugins));
+    }
+
     #[allow(clippy::large_enum_variant)]
     enum RequestMappingResult<A> {
-        NoSecurityPolicy,
-        BodyTooLarge((SimpleAction, BlockReason), RequestInfo),
+        NoSecurityPolicy(String),
+        Bypass(BypassMethod, RequestInfo, String),
+        Banned(Ban, RequestInfo, String),
+        BadSignature(String, SignatureProblem, RequestInfo, String),
+        BodyTooLarge((SimpleAction, BlockReason), RequestInfo, String),
         Res(A),
     }
 
@@ -113,124 +295,400 @@ pub fn inspect_generic_request_map_init<GH: Grasshopper>(
     // there is a lot of copying taking place, to minimize the lock time
     // this decision should be backed with benchmarks
 
-    let ((mut ntags, globalfilter_dec, stats), flows, reqinfo, precision_level) =
-        match with_config(logs, |slogs, cfg| {
-            let mmapinfo = match_securitypolicy(&raw.get_host(), &raw.meta.path, cfg, slogs, selected_secpol);
-            match mmapinfo {
-                Some(secpolicy) => {
-                    // this part is where we use the configuration as much as possible, while we have a lock on it
-
-                    // check if the body is too large
-                    // if the body is too large, we store the "too large" action for later use, and set the max depth to 0
-                    let body_too_large = if let Some(body) = raw.mbody {
-                        if body.len() > secpolicy.content_filter_profile.max_body_size
-                            && !secpolicy.content_filter_profile.ignore_body
-                        {
-                            Some((
-                                secpolicy.content_filter_profile.action.clone(),
-                                BlockReason::body_too_large(
-                                    secpolicy.content_filter_profile.id.clone(),
-                                    secpolicy.content_filter_profile.name.clone(),
-                                    secpolicy.content_filter_profile.action.atype.to_raw(),
-                                    body.len(),
-                                    secpolicy.content_filter_profile.max_body_size,
-                                ),
-                            ))
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    };
+    let (
+        (mut ntags, globalfilter_dec, stats),
+        flows,
+        session_filters,
+        hooks,
+        reqinfo,
+        precision_level,
+        signed_bypass,
+        jwt_problem,
+    ) = match with_config(logs, |slogs, cfg| {
+        let mmapinfo = route_securitypolicy(
+            &raw.get_host(),
+            raw.meta.sni.as_deref(),
+            &normalize_uri(&raw.meta.path),
+            Some(&raw.meta.method),
+            cfg,
+            slogs,
+            selected_secpol,
+        );
+        match mmapinfo {
+            Some((secpolicy, host_match)) => {
+                // this part is where we use the configuration as much as possible, while we have a lock on it
 
-                    let stats = StatsCollect::new(slogs.start, cfg.revision.clone())
-                        .secpol(SecpolStats::build(&secpolicy, cfg.globalfilters.len()));
-                    // if the max depth is equal to 0, the body will not be parsed
-                    let reqinfo = map_request(
+                // verified bypasses and banned clients are handled before the body is decoded
+                let bypass = secpolicy.bypass.as_ref().and_then(|b| b.check(raw));
+                // signed bypasses are granted once their nonce is recorded, after the mapping
+                let signed_bypass = secpolicy
+                    .bypass
+                    .as_ref()
+                    .and_then(|b| b.signed_nonce(raw, start.timestamp()));
+                let map_bodyless = |slogs: &mut Logs, secpolicy| {
+                    let bodyless = RawRequest {
+                        ipstr: raw.ipstr.clone(),
+                        headers: raw.headers.clone(),
+                        meta: raw.meta.clone(),
+                        mbody: None,
+                    };
+                    map_request(
                         slogs,
                         secpolicy,
                         cfg.container_name.clone(),
-                        &raw,
+                        &bodyless,
                         Some(start),
                         plugins.clone(),
-                    );
-
-                    if let Some(action) = body_too_large {
-                        return RequestMappingResult::BodyTooLarge(action, reqinfo);
-                    }
+                    )
+                };
+                if let Some(method) = bypass {
+                    let rinfo = map_bodyless(slogs, secpolicy);
+                    return RequestMappingResult::Bypass(method, rinfo, cfg.revision.clone());
+                }
+                if let Some(ban) = banned(&raw.client_address(), start.timestamp()) {
+                    let rinfo = map_bodyless(slogs, secpolicy);
+                    return RequestMappingResult::Banned(ban, rinfo, cfg.revision.clone());
+                }
 
-                    let nflows = cfg.flows.clone();
+                // request signatures are verified on the raw body, before it is decoded
+                let signature = secpolicy
+                    .signature
+                    .as_ref()
+                    .filter(|s| s.applies(raw))
+                    .map(|s| (s.header.clone(), s.enforce, s.verify(raw, start.timestamp())));
 
-                    // without grasshopper, default to being not human
-                    let precision_level = if let Some(gh) = mgh {
-                        challenge_verified(gh, &reqinfo, slogs)
+                // check if the body is too large
+                // if the body is too large, we store the "too large" action for later use, and set the max depth to 0
+                let body_too_large = if let Some(body) = raw.mbody {
+                    if secpolicy.content_filter_profile.body_depth(body.len()) == BodyAnalysisDepth::TooLarge {
+                        Some((
+                            secpolicy.content_filter_profile.action.clone(),
+                            BlockReason::body_too_large(
+                                secpolicy.content_filter_profile.id.clone(),
+                                secpolicy.content_filter_profile.name.clone(),
+                                secpolicy.content_filter_profile.action.atype.to_raw(),
+                                body.len(),
+                                secpolicy.content_filter_profile.max_body_size,
+                            ),
+                        ))
                     } else {
-                        PrecisionLevel::Invalid
-                    };
+                        None
+                    }
+                } else {
+                    None
+                };
+
+                let mut stats = StatsCollect::new(slogs.start, cfg.revision.clone())
+                    .config_hash(&cfg.config_hash)
+                    .secpol(SecpolStats::build(&secpolicy, cfg.globalfilters.len()));
+                let mapping_start = Instant::now();
+                // if the max depth is equal to 0, the body will not be parsed
+                let mut reqinfo = map_request(
+                    slogs,
+                    secpolicy,
+                    cfg.container_name.clone(),
+                    raw,
+                    Some(start),
+                    plugins.clone(),
+                );
+                stats.request_mapped(mapping_start);
 
-                    let ntags = tag_request(stats, precision_level, &cfg.globalfilters, &reqinfo, &cfg.virtual_tags);
-                    RequestMappingResult::Res((ntags, nflows, reqinfo, precision_level))
+                if let Some(action) = body_too_large {
+                    return RequestMappingResult::BodyTooLarge(action, reqinfo, cfg.revision.clone());
                 }
-                None => RequestMappingResult::NoSecurityPolicy,
-            }
-        }) {
-            Some(RequestMappingResult::Res(x)) => x,
-            Some(RequestMappingResult::BodyTooLarge((action, br), rinfo)) => {
-                let mut tags = tags;
-                let decision = action.to_decision(logs, PrecisionLevel::Invalid, mgh, &rinfo, &mut tags, vec![br]);
-                return Err(AnalyzeResult {
-                    decision,
-                    tags,
-                    rinfo,
-                    stats: Stats::new(logs.start, "unknown".into()),
-                });
-            }
-            Some(RequestMappingResult::NoSecurityPolicy) => {
-                logs.debug("No security policy found");
-                let mut secpol = SecurityPolicy::default();
-                secpol.content_filter_profile.ignore_body = true;
-                let rinfo = map_request(logs, Arc::new(secpol), None, &raw, Some(start), plugins);
-                return Err(AnalyzeResult {
-                    decision: Decision::pass(Vec::new()),
-                    tags,
-                    rinfo,
-                    stats: Stats::new(logs.start, "unknown".into()),
-                });
-            }
-            None => {
-                logs.debug("Something went wrong during security policy searching");
-                let mut secpol = SecurityPolicy::default();
-                secpol.content_filter_profile.ignore_body = true;
-                let rinfo = map_request(logs, Arc::new(secpol), None, &raw, Some(start), plugins);
-                return Err(AnalyzeResult {
-                    decision: Decision::pass(Vec::new()),
-                    tags,
-                    rinfo,
-                    stats: Stats::new(logs.start, "unknown".into()),
+                if let Some((header, true, Err(problem))) = signature {
+                    return RequestMappingResult::BadSignature(header, problem, reqinfo, cfg.revision.clone());
+                }
+
+                let nflows = cfg.flows.clone();
+                let session_filters = cfg.globalfilters.iter().filter(|s| s.session).cloned().collect();
+
+                let verified = challenge_verified(mgh, cfg.clearance.as_deref(), &mut reqinfo, slogs);
+                let precision_level = verified.unwrap_or(PrecisionLevel::Invalid);
+
+                // the token header and claims are added as arguments before the global filters are evaluated
+                let mut jwt_tags = Tags::new(&cfg.virtual_tags);
+                let secpolicy = reqinfo.rinfo.secpolicy.clone();
+                let jwt_problem = secpolicy.jwt.as_ref().and_then(|settings| {
+                    jwt::inspect_jwt(slogs, settings, &mut reqinfo, &mut jwt_tags)
+                        .filter(|_| settings.enforce)
+                        .map(|problem| (settings.header.clone(), problem))
                 });
+
+                let mut memory = reqinfo.memory;
+                let mut ntags = memory
+                    .track(|| tag_request(stats, precision_level, &cfg.globalfilters, &reqinfo, &cfg.virtual_tags));
+                reqinfo.memory = memory;
+                ntags.0.extend(jwt_tags);
+                if verified.is_none() {
+                    dependency_failed(&mut ntags.0, Dependency::Challenge);
+                }
+                ntags
+                    .0
+                    .insert_qualified("host-rule", host_match.name(), Location::Request);
+                match signature {
+                    None => (),
+                    Some((_, _, Ok(()))) => ntags.0.insert_qualified(SIGNATURE_TAG, "valid", Location::Request),
+                    Some((header, _, Err(problem))) => {
+                        ntags
+                            .0
+                            .insert_qualified(SIGNATURE_TAG, problem, Location::Header(header.clone()));
+                        let action = SimpleAction {
+                            atype: SimpleActionT::Monitor,
+                            ..SimpleAction::default()
+                        };
+                        let entry = &reqinfo.rinfo.secpolicy.entry;
+                        let reason = BlockReason::signature(
+                            entry.id.clone(),
+                            entry.name.clone(),
+                            action.atype.to_raw(),
+                            header,
+                            problem,
+                        );
+                        ntags.1 = stronger_decision(ntags.1, SimpleDecision::Action(action, vec![reason]));
+                    }
+                }
+                RequestMappingResult::Res((
+                    ntags,
+                    nflows,
+                    session_filters,
+                    cfg.hooks.clone(),
+                    reqinfo,
+                    precision_level,
+                    signed_bypass,
+                    jwt_problem,
+                ))
             }
-        };
+            None => RequestMappingResult::NoSecurityPolicy(cfg.revision.clone()),
+        }
+    }) {
+        Some(RequestMappingResult::Res(x)) => x,
+        Some(RequestMappingResult::Bypass(method, rinfo, revision)) => {
+            logs.debug(|| format!("analysis bypassed ({})", method.name()));
+            let mut tags = tags;
+            tags.insert(BYPASS_TAG, Location::Request);
+            tags.insert_qualified(BYPASS_TAG, method.name(), Location::Request);
+            return Err(AnalyzeResult {
+                decision: Decision::pass(Vec::new()),
+                tags,
+                rinfo,
+                stats: Stats::new(logs.start, revision),
+            });
+        }
+        Some(RequestMappingResult::Banned(ban, rinfo, revision)) => {
+            let mut tags = tags;
+            tags.insert("banned", Location::Ip);
+            let (action, br) = ban_action(&ban);
+            let decision = action.to_decision(logs, PrecisionLevel::Invalid, mgh, &rinfo, &mut tags, vec![br]);
+            return Err(AnalyzeResult {
+                decision,
+                tags,
+                rinfo,
+                stats: Stats::new(logs.start, revision),
+            });
+        }
+        Some(RequestMappingResult::BadSignature(header, problem, rinfo, revision)) => {
+            logs.debug(|| format!("invalid request signature: {}", problem));
+            let mut tags = tags;
+            tags.insert_qualified(SIGNATURE_TAG, problem, Location::Header(header.clone()));
+            let action = SimpleAction::default();
+            let reason = BlockReason::signature(
+                rinfo.rinfo.secpolicy.entry.id.clone(),
+                rinfo.rinfo.secpolicy.entry.name.clone(),
+                action.atype.to_raw(),
+                header,
+                problem,
+            );
+            let decision = action.to_decision(logs, PrecisionLevel::Invalid, mgh, &rinfo, &mut tags, vec![reason]);
+            return Err(AnalyzeResult {
+                decision,
+                tags,
+                rinfo,
+                stats: Stats::new(logs.start, revision),
+            });
+        }
+        Some(RequestMappingResult::BodyTooLarge((action, br), rinfo, revision)) => {
+            let mut tags = tags;
+            let decision = action.to_decision(logs, PrecisionLevel::Invalid, mgh, &rinfo, &mut tags, vec![br]);
+            return Err(AnalyzeResult {
+                decision,
+                tags,
+                rinfo,
+                stats: Stats::new(logs.start, revision),
+            });
+        }
+        Some(RequestMappingResult::NoSecurityPolicy(revision)) => {
+            logs.debug("No security policy found");
+            let mut secpol = SecurityPolicy::default();
+            secpol.content_filter_profile.ignore_body = true;
+            let rinfo = map_request(logs, Arc::new(secpol), None, raw, Some(start), plugins);
+            return Err(AnalyzeResult {
+                decision: Decision::pass(Vec::new()),
+                tags,
+                rinfo,
+                stats: Stats::new(logs.start, revision),
+            });
+        }
+        None => {
+            logs.debug("Something went wrong during security policy searching");
+            let mut secpol = SecurityPolicy::default();
+            secpol.content_filter_profile.ignore_body = true;
+            let rinfo = map_request(logs, Arc::new(secpol), None, raw, Some(start), plugins);
+            return Err(AnalyzeResult {
+                decision: Decision::pass(Vec::new()),
+                tags,
+                rinfo,
+                stats: Stats::new(logs.start, "unknown".into()),
+            });
+        }
+    };
     ntags.extend(tags);
 
+    Ok(MappedRequest {
+        p0: APhase0 {
+            stats,
+            itags: ntags,
+            reqinfo,
+            precision_level,
+            globalfilter_dec,
+            session_filters,
+            hooks,
+            flows,
+            cache: None,
+        },
+        signed_bypass,
+        jwt_problem,
+    })
+}
+
+/// bypasses the analysis when the nonce of the signed bypass was recorded
+#[allow(clippy::result_large_err)]
+fn signed_bypass_check(
+    logs: &mut Logs,
+    mut mapped: MappedRequest,
+    consumed: anyhow::Result<bool>,
+) -> Result<MappedRequest, AnalyzeResult> {
+    match consumed {
+        Ok(true) => {
+            logs.debug("analysis bypassed (signature)");
+            let mut p0 = mapped.p0;
+            p0.itags.insert(BYPASS_TAG, Location::Request);
+            p0.itags
+                .insert_qualified(BYPASS_TAG, BypassMethod::Signature.name(), Location::Request);
+            return Err(AnalyzeResult {
+                decision: Decision::pass(Vec::new()),
+                tags: p0.itags,
+                rinfo: p0.reqinfo,
+                stats: p0.stats.mapped_stage_build(),
+            });
+        }
+        Ok(false) => {
+            logs.debug("bypass nonce replayed");
+            mapped.p0.itags.insert("bypass-replayed", Location::Request);
+        }
+        Err(rr) => {
+            logs.error(|| format!("bypass nonce: {}", rr));
+            dependency_failed(&mut mapped.p0.itags, Dependency::Redis);
+        }
+    }
+    Ok(mapped)
+}
+
+/// the checks of the mapped request that do not depend on the client state
+#[allow(clippy::result_large_err)]
+fn init_finish<GH: ChallengeProvider>(
+    logs: &mut Logs,
+    mgh: Option<&GH>,
+    mapped: MappedRequest,
+    raw: &RawRequest,
+) -> Result<APhase0, AnalyzeResult> {
+    let MappedRequest { p0, jwt_problem, .. } = mapped;
+    let APhase0 {
+        stats,
+        itags: mut ntags,
+        reqinfo,
+        precision_level,
+        globalfilter_dec,
+        session_filters,
+        hooks,
+        flows,
+        cache,
+    } = p0;
+
+    // some fields or tags were dropped, the analysis can't be trusted and is aborted
+    if reqinfo.memory.exceeded {
+        let profile = &reqinfo.rinfo.secpolicy.content_filter_profile;
+        logs.warning(|| format!("request memory budget exceeded ({} bytes)", profile.max_request_memory));
+        let action = profile.action.clone();
+        let reason = BlockReason::memory_exceeded(
+            profile.id.clone(),
+            profile.name.clone(),
+            action.atype.to_raw(),
+            profile.max_request_memory,
+        );
+        ntags.insert("memory-cap-exceeded", Location::Request);
+        let decision = action.to_decision(logs, precision_level, mgh, &reqinfo, &mut ntags, vec![reason]);
+        return Err(AnalyzeResult {
+            decision,
+            tags: ntags,
+            rinfo: reqinfo,
+            stats: stats.mapped_stage_build(),
+        });
+    }
+
+    if let Some((header, problem)) = jwt_problem {
+        logs.debug(|| format!("invalid bearer token: {}", problem));
+        let action = SimpleAction::default();
+        let reason = BlockReason::jwt(
+            reqinfo.rinfo.secpolicy.entry.id.clone(),
+            reqinfo.rinfo.secpolicy.entry.name.clone(),
+            action.atype.to_raw(),
+            header,
+            problem,
+        );
+        let decision = action.to_decision(logs, precision_level, mgh, &reqinfo, &mut ntags, vec![reason]);
+        return Err(AnalyzeResult {
+            decision,
+            tags: ntags,
+            rinfo: reqinfo,
+            stats: stats.mapped_stage_build(),
+        });
+    }
+
+    if let Some(body) = raw.mbody {
+        if reqinfo.rinfo.secpolicy.content_filter_profile.body_depth(body.len()) == BodyAnalysisDepth::MetadataOnly {
+            logs.debug(|| {
+                format!(
+                    "body too large for a full scan ({} bytes), analyzing metadata only",
+                    body.len()
+                )
+            });
+            ntags.insert("body-metadata-only", Location::Body);
+        }
+    }
+
     Ok(APhase0 {
         stats,
         itags: ntags,
         reqinfo,
         precision_level,
         globalfilter_dec,
+        session_filters,
+        hooks,
         flows,
+        cache,
     })
 }
 
 // generic entry point when the request map has already been parsed
-pub async fn inspect_generic_request_map_async<GH: Grasshopper>(
+pub async fn inspect_generic_request_map_async<GH: ChallengeProvider>(
     mgh: Option<&GH>,
     raw: RawRequest<'_>,
     logs: &mut Logs,
     selected_secpol: Option<&str>,
     plugins: HashMap<String, String>,
 ) -> AnalyzeResult {
-    match inspect_generic_request_map_init(mgh, raw, logs, selected_secpol, plugins) {
+    match inspect_generic_request_map_init_async(mgh, raw, logs, selected_secpol, plugins).await {
         Err(res) => res,
         Ok(p0) => analyze::analyze(logs, mgh, p0, CfRulesArg::Global).await,
     }
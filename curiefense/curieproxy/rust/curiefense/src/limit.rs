@@ -1,19 +1,88 @@
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use redis::Script;
+
 use crate::interface::stats::{BStageFlow, BStageLimit, StatsCollect};
 use crate::logs::Logs;
+use crate::redis::RedisConn;
 use crate::redis::REDIS_KEY_PREFIX;
-use redis::aio::ConnectionManager;
 
-use crate::config::limit::Limit;
+use crate::config::hostmap::CounterSettings;
 use crate::config::limit::LimitThreshold;
-use crate::interface::{stronger_decision, BlockReason, Location, SimpleDecision, Tags};
-use crate::utils::{select_string, RequestInfo};
+use crate::config::limit::{Limit, LimitAlgorithm, LimitUnit, QuotaPeriod};
+use crate::interface::{stronger_decision, BlockReason, Location, RateQuota, SimpleActionT, SimpleDecision, Tags};
+use crate::utils::{now_ms, select_string, RequestInfo};
+
+lazy_static! {
+    /// seconds quota counters are kept after the end of their period, so that the usage can still be reported
+    pub static ref QUOTA_RETENTION: u64 = std::env::var("QUOTA_RETENTION")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(31 * 86400);
+    /// the limit scripts are called by their hash, so that their text is only sent when redis does not know them
+    pub static ref LIMIT_SCRIPTS: LimitScripts = LimitScripts {
+        sliding_log: Script::new(SLIDING_LOG_SCRIPT),
+        sliding_counter: Script::new(SLIDING_COUNTER_SCRIPT),
+        token_bucket: Script::new(TOKEN_BUCKET_SCRIPT),
+        quota: Script::new(QUOTA_SCRIPT),
+        inflight: Script::new(INFLIGHT_SCRIPT),
+    };
+}
+
+pub struct LimitScripts {
+    pub sliding_log: Script,
+    pub sliding_counter: Script,
+    pub token_bucket: Script,
+    pub quota: Script,
+    pub inflight: Script,
+}
+
+/// loads the limit scripts in redis, after it answered that it does not know one of them
+pub async fn load_limit_scripts(redis: &mut RedisConn) -> anyhow::Result<()> {
+    let mut pipe = redis::pipe();
+    for script in [
+        SLIDING_LOG_SCRIPT,
+        SLIDING_COUNTER_SCRIPT,
+        TOKEN_BUCKET_SCRIPT,
+        QUOTA_SCRIPT,
+        INFLIGHT_SCRIPT,
+    ] {
+        pipe.cmd("SCRIPT").arg("LOAD").arg(script).ignore();
+    }
+    pipe.query_async::<_, ()>(redis).await?;
+    Ok(())
+}
+
+/// key of a limit counter, from the concatenated values of the key selectors
+pub fn counter_key(namespace: &str, limit_id: &str, parts: &str) -> String {
+    format!(
+        "{}{}{:X}",
+        *REDIS_KEY_PREFIX,
+        namespace,
+        md5::compute(format!("{}{}", limit_id, parts))
+    )
+}
+
+/// quota counters are distinct for each period
+pub fn quota_key(key: &str, period: QuotaPeriod, now: DateTime<Utc>) -> String {
+    format!("{}:{}", key, period.label(now))
+}
 
 fn build_key(reqinfo: &RequestInfo, tags: &Tags, limit: &Limit) -> Option<String> {
-    let mut key = limit.id.clone();
-    for kpart in limit.key.iter().map(|r| select_string(reqinfo, r, Some(tags))) {
-        key += &kpart?;
+    let mut parts = String::new();
+    for sel in limit.key.iter() {
+        match select_string(reqinfo, sel, Some(tags)) {
+            Some(kpart) => parts += &kpart,
+            // requests with unknown geo attributes share a counter, they can be excluded with the geo-*:nil tags
+            None if sel.is_geo() => parts += "nil",
+            None => return None,
+        }
     }
-    Some(format!("{}{:X}", *REDIS_KEY_PREFIX, md5::compute(key)))
+    let key = counter_key(&reqinfo.rinfo.secpolicy.counters.namespace, &limit.id, &parts);
+    Some(match limit.algorithm {
+        LimitAlgorithm::Quota(period) => quota_key(&key, period, reqinfo.timestamp),
+        _ => key,
+    })
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -36,6 +105,31 @@ fn limit_pure_react(tags: &mut Tags, limit: &Limit, threshold: &LimitThreshold)
     )
 }
 
+fn rate_limit_headers(
+    tags: &mut Tags,
+    result: &LimitResult,
+    threshold: &LimitThreshold,
+    exceeded: bool,
+) -> SimpleDecision {
+    let quota = RateQuota {
+        limit: threshold.limit,
+        remaining: threshold.limit.saturating_sub(result.curcount.max(0) as u64),
+        reset: result.reset,
+    };
+    let decision = if exceeded {
+        limit_pure_react(tags, &result.limit, threshold)
+    } else {
+        SimpleDecision::Action(threshold.action.clone(), Vec::new())
+    };
+    match decision {
+        SimpleDecision::Action(mut action, reasons) => {
+            action.quota = Some(quota);
+            SimpleDecision::Action(action, reasons)
+        }
+        SimpleDecision::Pass => SimpleDecision::Pass,
+    }
+}
+
 fn limit_match(tags: &Tags, elem: &Limit) -> bool {
     if elem.exclude.iter().any(|e| tags.contains(e)) {
         return false;
@@ -52,6 +146,12 @@ pub struct LimitCheck {
     pub key: String,
     pub pairwith: Option<String>,
     pub limit: Limit,
+    /// expiration of the counter, in seconds
+    pub ttl: u64,
+    /// amount added to the counter by the request, in the unit of the limit
+    pub cost: u64,
+    /// identifies the request in the in-flight counters
+    pub member: String,
 }
 
 impl LimitCheck {
@@ -63,8 +163,15 @@ impl LimitCheck {
 /// generate information that needs to be checked in redis for limit checks
 pub fn limit_info(logs: &mut Logs, reqinfo: &RequestInfo, limits: &[Limit], tags: &Tags) -> Vec<LimitCheck> {
     let mut out = Vec::new();
+    let member = format!("{}-{:x}", now_ms(), rand::random::<u64>());
     for limit in limits {
-        if !limit_match(tags, limit) {
+        if !limit_match(tags, limit)
+            || limit
+                .window
+                .as_ref()
+                .map(|w| !w.contains(reqinfo.timestamp))
+                .unwrap_or(false)
+        {
             continue;
         }
         let key = match build_key(reqinfo, tags, limit) {
@@ -85,25 +192,227 @@ pub fn limit_info(logs: &mut Logs, reqinfo: &RequestInfo, limits: &[Limit], tags
             key,
             pairwith,
             limit: limit.clone(),
+            ttl: match limit.algorithm {
+                LimitAlgorithm::Quota(period) => period.remaining(reqinfo.timestamp) + *QUOTA_RETENTION,
+                _ => {
+                    CounterSettings::scaled_ttl(limit.timeframe, reqinfo.rinfo.secpolicy.counters.limit_ttl_multiplier)
+                }
+            },
+            cost: match limit.unit {
+                LimitUnit::Requests => 1,
+                LimitUnit::BodyBytes => reqinfo.rinfo.qinfo.body_size as u64,
+            },
+            member: member.clone(),
         })
     }
     out
 }
 
+/// KEYS[1]: sorted set of request timestamps, ARGV: now (ms), window (ms), unique member, max size
+pub const SLIDING_LOG_SCRIPT: &str = r#"
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', tonumber(ARGV[1]) - tonumber(ARGV[2]))
+redis.call('ZADD', KEYS[1], ARGV[1], ARGV[3])
+local count = redis.call('ZCARD', KEYS[1])
+local cap = tonumber(ARGV[4])
+if count > cap then
+  redis.call('ZREMRANGEBYRANK', KEYS[1], 0, count - cap - 1)
+  count = cap
+end
+redis.call('PEXPIRE', KEYS[1], ARGV[2])
+return count
+"#;
+
+/// KEYS[1]: hash of window index to counter, ARGV: now (ms), window (ms), cost
+pub const SLIDING_COUNTER_SCRIPT: &str = r#"
+local now = tonumber(ARGV[1])
+local window = tonumber(ARGV[2])
+local cur = math.floor(now / window)
+local count = redis.call('HINCRBY', KEYS[1], cur, ARGV[3])
+local prev = tonumber(redis.call('HGET', KEYS[1], cur - 1) or '0')
+for _, field in ipairs(redis.call('HKEYS', KEYS[1])) do
+  if tonumber(field) < cur - 1 then
+    redis.call('HDEL', KEYS[1], field)
+  end
+end
+redis.call('PEXPIRE', KEYS[1], window * 2)
+return math.floor(prev * (1 - (now % window) / window) + count)
+"#;
+
+/// KEYS[1]: hash holding the bucket level and last update, ARGV: now (ms), refill rate (per ms), max level, cost
+pub const TOKEN_BUCKET_SCRIPT: &str = r#"
+local now = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local state = redis.call('HMGET', KEYS[1], 'level', 'ts')
+local level = tonumber(state[1]) or 0
+local ts = tonumber(state[2]) or now
+level = math.min(math.max(0, level - math.max(0, now - ts) * rate) + tonumber(ARGV[4]), tonumber(ARGV[3]))
+redis.call('HSET', KEYS[1], 'level', level, 'ts', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil(level / rate) + 1000)
+return math.ceil(level)
+"#;
+
+/// KEYS[1]: quota counter, ARGV: cost, expiration (s), only set when the counter is created
+pub const QUOTA_SCRIPT: &str = r#"
+local count = redis.call('INCRBY', KEYS[1], ARGV[1])
+if redis.call('TTL', KEYS[1]) < 0 then
+  redis.call('EXPIRE', KEYS[1], ARGV[2])
+end
+return count
+"#;
+
+/// KEYS[1]: sorted set of the in-flight requests, scored by the expiration of their lease
+/// ARGV: now (ms), lease duration (ms), request member
+///
+/// the leases of requests that were never released expire on their own
+pub const INFLIGHT_SCRIPT: &str = r#"
+local now = tonumber(ARGV[1])
+local lease = tonumber(ARGV[2])
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now)
+redis.call('ZADD', KEYS[1], now + lease, ARGV[3])
+redis.call('PEXPIRE', KEYS[1], lease)
+return redis.call('ZCARD', KEYS[1])
+"#;
+
+/// the lease a request holds on an in-flight counter, released when the request completes
+pub fn inflight_lease(key: &str, member: &str) -> String {
+    format!("{} {}", key, member)
+}
+
+/// the counter key and request member of a lease
+pub fn parse_lease(lease: &str) -> (&str, &str) {
+    lease.rsplit_once(' ').unwrap_or((lease, ""))
+}
+
+impl LimitCheck {
+    /// the algorithm used for this check, pairwith counters are always fixed windows
+    pub fn algorithm(&self) -> LimitAlgorithm {
+        if self.pairwith.is_some() {
+            LimitAlgorithm::FixedWindow
+        } else {
+            self.limit.algorithm
+        }
+    }
+
+    /// seconds until the counter resets, from the TTL of fixed windows
+    pub fn reset(&self, ttl: i64) -> u64 {
+        match self.algorithm() {
+            LimitAlgorithm::FixedWindow if ttl >= 0 => ttl as u64,
+            LimitAlgorithm::FixedWindow => self.ttl,
+            LimitAlgorithm::Quota(period) => period.remaining(Utc::now()),
+            _ => self.limit.timeframe,
+        }
+    }
+
+    pub fn result(self, curcount: i64, ttl: i64) -> LimitResult {
+        LimitResult {
+            reset: if self.zero_limits() { 0 } else { self.reset(ttl) },
+            limit: self.limit,
+            curcount,
+        }
+    }
+
+    /// counting stops one above the highest threshold, as higher values don't change the decision
+    fn max_count(&self) -> u64 {
+        self.limit.thresholds.iter().map(|t| t.limit).max().unwrap_or(0) + 1
+    }
+
+    /// refill rate of a token bucket, in requests per millisecond
+    fn refill_rate(&self) -> f64 {
+        let lowest = self
+            .limit
+            .thresholds
+            .iter()
+            .map(|t| t.limit)
+            .filter(|l| *l > 0)
+            .min()
+            .unwrap_or(1);
+        lowest as f64 / (self.limit.timeframe.max(1) * 1000) as f64
+    }
+}
+
+/// leases on the in-flight counters that are taken when the checks are queried
+pub fn inflight_leases(checks: &[LimitCheck]) -> impl Iterator<Item = String> + '_ {
+    checks
+        .iter()
+        .filter(|c| !c.zero_limits() && c.algorithm() == LimitAlgorithm::InFlight)
+        .map(|c| inflight_lease(&c.key, &c.member))
+}
+
 #[derive(Clone)]
 pub struct LimitResult {
     pub limit: Limit,
     pub curcount: i64,
+    /// seconds until the counter resets
+    pub reset: u64,
 }
 
 pub fn limit_build_query(pipe: &mut redis::Pipeline, checks: &[LimitCheck]) {
     for check in checks {
         let key = &check.key;
         if !check.zero_limits() {
+            let window = check.limit.timeframe * 1000;
+            match check.algorithm() {
+                LimitAlgorithm::FixedWindow => (),
+                LimitAlgorithm::InFlight => {
+                    pipe.cmd("EVALSHA")
+                        .arg(LIMIT_SCRIPTS.inflight.get_hash())
+                        .arg(1)
+                        .arg(key)
+                        .arg(now_ms())
+                        .arg(window)
+                        .arg(&check.member);
+                    continue;
+                }
+                LimitAlgorithm::SlidingLog => {
+                    let now = now_ms();
+                    pipe.cmd("EVALSHA")
+                        .arg(LIMIT_SCRIPTS.sliding_log.get_hash())
+                        .arg(1)
+                        .arg(key)
+                        .arg(now)
+                        .arg(window)
+                        .arg(format!("{}-{:x}", now, rand::random::<u64>()))
+                        .arg(check.max_count());
+                    continue;
+                }
+                LimitAlgorithm::SlidingCounter => {
+                    pipe.cmd("EVALSHA")
+                        .arg(LIMIT_SCRIPTS.sliding_counter.get_hash())
+                        .arg(1)
+                        .arg(key)
+                        .arg(now_ms())
+                        .arg(window)
+                        .arg(check.cost);
+                    continue;
+                }
+                LimitAlgorithm::Quota(_) => {
+                    pipe.cmd("EVALSHA")
+                        .arg(LIMIT_SCRIPTS.quota.get_hash())
+                        .arg(1)
+                        .arg(key)
+                        .arg(check.cost)
+                        .arg(check.ttl);
+                    continue;
+                }
+                LimitAlgorithm::TokenBucket { burst } => {
+                    pipe.cmd("EVALSHA")
+                        .arg(LIMIT_SCRIPTS.token_bucket.get_hash())
+                        .arg(1)
+                        .arg(key)
+                        .arg(now_ms())
+                        .arg(check.refill_rate())
+                        .arg(check.max_count() + burst)
+                        .arg(check.cost);
+                    continue;
+                }
+            }
             match &check.pairwith {
-                None => {
+                None if check.cost == 1 => {
                     pipe.cmd("INCR").arg(key).cmd("TTL").arg(key);
                 }
+                None => {
+                    pipe.cmd("INCRBY").arg(key).arg(check.cost).cmd("TTL").arg(key);
+                }
                 Some(pv) => {
                     pipe.cmd("SADD")
                         .arg(key)
@@ -121,7 +430,7 @@ pub fn limit_build_query(pipe: &mut redis::Pipeline, checks: &[LimitCheck]) {
 
 pub async fn limit_resolve_query<I: Iterator<Item = Option<i64>>>(
     logs: &mut Logs,
-    redis: &mut ConnectionManager,
+    redis: &mut RedisConn,
     iter: &mut I,
     checks: Vec<LimitCheck>,
 ) -> anyhow::Result<Vec<LimitResult>> {
@@ -131,6 +440,17 @@ pub async fn limit_resolve_query<I: Iterator<Item = Option<i64>>>(
     for check in checks {
         let (curcount, expire) = if check.zero_limits() {
             (1, 0)
+        } else if check.algorithm() != LimitAlgorithm::FixedWindow {
+            // the scripts set the expiration themselves
+            let count = match iter.next() {
+                None => anyhow::bail!("Empty iterator when getting curcount for {:?}", check.limit),
+                Some(r) => r.unwrap_or(0),
+            };
+            match check.algorithm() {
+                // the burst is the part of the bucket that doesn't count towards the thresholds
+                LimitAlgorithm::TokenBucket { burst } => ((count - burst as i64).max(0), 0),
+                _ => (count, 0),
+            }
         } else {
             let curcount = match iter.next() {
                 None => anyhow::bail!("Empty iterator when getting curcount for {:?}", check.limit),
@@ -144,13 +464,10 @@ pub async fn limit_resolve_query<I: Iterator<Item = Option<i64>>>(
         };
         logs.debug(|| format!("limit {} curcount={} expire={}", check.limit.id, curcount, expire));
         if expire < 0 {
-            pipe.cmd("EXPIRE").arg(&check.key).arg(check.limit.timeframe);
+            pipe.cmd("EXPIRE").arg(&check.key).arg(check.ttl);
         }
         pipe.query_async(redis).await?;
-        out.push(LimitResult {
-            limit: check.limit,
-            curcount,
-        })
+        out.push(check.result(curcount, expire))
     }
     Ok(out)
 }
@@ -168,7 +485,14 @@ pub fn limit_process(
             for threshold in &result.limit.thresholds {
                 // Only one action with highest limit larger than current
                 // counter will be applied, all the rest will be skipped.
-                if result.curcount > threshold.limit as i64 {
+                let exceeded = result.curcount > threshold.limit as i64;
+                if exceeded && matches!(result.limit.algorithm, LimitAlgorithm::Quota(_)) {
+                    // exhausted quotas advertise when they reset, with the Retry-After header
+                    out = stronger_decision(out, rate_limit_headers(tags, result, threshold, true));
+                } else if threshold.action.atype == SimpleActionT::RateLimitHeaders {
+                    // the headers are sent with every counted request, not only when the limit is exceeded
+                    out = stronger_decision(out, rate_limit_headers(tags, result, threshold, exceeded));
+                } else if exceeded {
                     out = stronger_decision(out, limit_pure_react(tags, &result.limit, threshold));
                 }
             }
@@ -177,3 +501,146 @@ pub fn limit_process(
 
     (out, stats.limit(nlimits, results.len()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::hostmap::SecurityPolicy;
+    use crate::config::matchers::RequestSelector;
+    use crate::config::virtualtags::VirtualTags;
+    use crate::interface::SimpleAction;
+    use crate::testutils::{map_raw, raw_request};
+    use std::collections::HashSet;
+
+    fn mk_rinfo() -> RequestInfo {
+        let mut raw = raw_request(&[("method", "POST"), ("path", "/login")], &[]);
+        raw.ipstr = "52.78.12.56".to_string();
+        map_raw(SecurityPolicy::default(), &raw)
+    }
+
+    fn mk_limit(key: Vec<RequestSelector>) -> Limit {
+        Limit {
+            id: "login".to_string(),
+            name: "login".to_string(),
+            timeframe: 60,
+            thresholds: Vec::new(),
+            exclude: HashSet::new(),
+            include: HashSet::new(),
+            pairwith: None,
+            key,
+            tags: Vec::new(),
+            algorithm: LimitAlgorithm::FixedWindow,
+            unit: LimitUnit::Requests,
+            window: None,
+        }
+    }
+
+    #[test]
+    fn geo_keys() {
+        let rinfo = mk_rinfo();
+        let tags = Tags::new(&VirtualTags::default());
+        // the geoip databases are not available in tests, all the attributes are unknown
+        let asn = build_key(
+            &rinfo,
+            &tags,
+            &mk_limit(vec![RequestSelector::Asn, RequestSelector::Path]),
+        );
+        assert!(asn.is_some());
+        let country = build_key(
+            &rinfo,
+            &tags,
+            &mk_limit(vec![RequestSelector::Country, RequestSelector::Path]),
+        );
+        assert_eq!(asn, country);
+        let ip = build_key(
+            &rinfo,
+            &tags,
+            &mk_limit(vec![RequestSelector::Ip, RequestSelector::Path]),
+        );
+        assert!(ip.is_some());
+        assert_ne!(asn, ip);
+        let header = build_key(
+            &rinfo,
+            &tags,
+            &mk_limit(vec![RequestSelector::Header("x-api-key".to_string())]),
+        );
+        assert!(header.is_none());
+    }
+
+    #[test]
+    fn rate_limit_headers_quota() {
+        let mut tags = Tags::new(&VirtualTags::default());
+        let threshold = |limit: u64, atype: SimpleActionT| LimitThreshold {
+            limit,
+            action: SimpleAction {
+                atype,
+                ..SimpleAction::default()
+            },
+        };
+        let result = |curcount: i64| LimitResult {
+            limit: mk_limit(Vec::new()),
+            curcount,
+            reset: 42,
+        };
+        let headers = threshold(10, SimpleActionT::RateLimitHeaders);
+        let block = threshold(20, SimpleActionT::default());
+
+        let below = stronger_decision(
+            rate_limit_headers(&mut tags, &result(4), &headers, false),
+            SimpleDecision::Pass,
+        );
+        match below {
+            SimpleDecision::Action(a, reasons) => {
+                assert!(reasons.is_empty());
+                assert_eq!(
+                    a.quota,
+                    Some(RateQuota {
+                        limit: 10,
+                        remaining: 6,
+                        reset: 42
+                    })
+                );
+            }
+            SimpleDecision::Pass => panic!("expected rate limit headers"),
+        }
+        assert!(!tags.contains("limit-id:login"));
+
+        // the block decision is kept, with the quota
+        let above = stronger_decision(
+            rate_limit_headers(&mut tags, &result(25), &headers, true),
+            limit_pure_react(&mut tags, &result(25).limit, &block),
+        );
+        match above {
+            SimpleDecision::Action(a, reasons) => {
+                assert_eq!(reasons.len(), 2);
+                assert!(!a.atype.is_passing());
+                assert_eq!(a.quota.map(|q| q.remaining), Some(0));
+            }
+            SimpleDecision::Pass => panic!("expected a block"),
+        }
+    }
+
+    #[test]
+    fn scripts_by_hash() {
+        let mut limit = mk_limit(Vec::new());
+        limit.algorithm = LimitAlgorithm::SlidingLog;
+        limit.thresholds = vec![LimitThreshold {
+            limit: 5,
+            action: SimpleAction::default(),
+        }];
+        let check = LimitCheck {
+            key: "key".to_string(),
+            pairwith: None,
+            limit,
+            ttl: 60,
+            cost: 1,
+            member: "member".to_string(),
+        };
+        let mut pipe = redis::pipe();
+        limit_build_query(&mut pipe, &[check]);
+        let packed = String::from_utf8(pipe.get_packed_pipeline()).unwrap();
+        assert!(packed.contains("EVALSHA"));
+        assert!(packed.contains(LIMIT_SCRIPTS.sliding_log.get_hash()));
+        assert!(!packed.contains("ZREMRANGEBYSCORE"));
+    }
+}
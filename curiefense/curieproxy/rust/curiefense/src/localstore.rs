@@ -0,0 +1,578 @@
+//! An in-process replacement for the subset of redis commands used by the flow and limit checks.
+//!
+//! It is used as a degraded-mode fallback when redis can't be reached: counters are only shared
+//! between the requests handled by the same worker, so enforcement is approximate. The limit scripts are not
+//! interpreted, they are recognized by their hash and emulated natively.
+use futures::future::FutureExt;
+use lazy_static::lazy_static;
+use redis::aio::ConnectionLike;
+use redis::{Arg, Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, RedisResult, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// expired entries are swept every SWEEP_PERIOD commands
+const SWEEP_PERIOD: u64 = 1024;
+
+lazy_static! {
+    pub static ref LOCAL_STORE: LocalStore = LocalStore::default();
+}
+
+#[derive(Debug)]
+enum LocalValue {
+    Counter(i64),
+    Set(HashSet<Vec<u8>>),
+    List(VecDeque<Vec<u8>>),
+    /// request timestamps, for sliding log limits
+    Log(VecDeque<i64>),
+    /// current window index and counter, and counter of the previous window
+    Windows {
+        index: i64,
+        count: i64,
+        prev: i64,
+    },
+    /// token bucket level, and its last update time
+    Bucket {
+        level: f64,
+        updated: i64,
+    },
+    /// in-flight requests, and the expiration of their lease (ms)
+    Leases(HashMap<Vec<u8>, i64>),
+}
+
+#[derive(Debug)]
+struct LocalEntry {
+    value: LocalValue,
+    expires: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct StoreState {
+    entries: HashMap<Vec<u8>, LocalEntry>,
+    ops: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LocalStore {
+    state: Arc<Mutex<StoreState>>,
+}
+
+fn wrong_type() -> RedisError {
+    RedisError::from((
+        ErrorKind::TypeError,
+        "WRONGTYPE Operation against a key holding the wrong kind of value",
+    ))
+}
+
+fn parse_int(arg: &[u8]) -> RedisResult<i64> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| RedisError::from((ErrorKind::TypeError, "value is not an integer")))
+}
+
+fn parse_float(arg: &[u8]) -> RedisResult<f64> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| RedisError::from((ErrorKind::TypeError, "value is not a valid float")))
+}
+
+fn script_arg<'a>(argv: &[&'a [u8]], idx: usize) -> RedisResult<&'a [u8]> {
+    argv.get(idx)
+        .copied()
+        .ok_or_else(|| RedisError::from((ErrorKind::ClientError, "missing script argument")))
+}
+
+impl StoreState {
+    /// returns the live entry for a key, evicting it if it expired
+    fn live(&mut self, key: &[u8], now: Instant) -> Option<&mut LocalEntry> {
+        let expired = matches!(self.entries.get(key), Some(LocalEntry { expires: Some(e), .. }) if *e <= now);
+        if expired {
+            self.entries.remove(key);
+        }
+        self.entries.get_mut(key)
+    }
+
+    fn entry(&mut self, key: &[u8], now: Instant, default: LocalValue) -> &mut LocalEntry {
+        if self.live(key, now).is_none() {
+            self.entries.insert(
+                key.to_vec(),
+                LocalEntry {
+                    value: default,
+                    expires: None,
+                },
+            );
+        }
+        // the entry was just inserted if it was missing
+        self.entries.get_mut(key).unwrap()
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        self.ops += 1;
+        if self.ops >= SWEEP_PERIOD {
+            self.ops = 0;
+            self.entries.retain(|_, e| e.expires.map(|x| x > now).unwrap_or(true));
+        }
+    }
+
+    /// emulates the limit scripts
+    fn evalsha(&mut self, sha: &[u8], key: &[u8], argv: &[&[u8]], now: Instant) -> RedisResult<Value> {
+        use crate::limit::LIMIT_SCRIPTS;
+        let is = |script: &redis::Script| sha == script.get_hash().as_bytes();
+        if is(&LIMIT_SCRIPTS.quota) {
+            let cost = parse_int(script_arg(argv, 0)?)?;
+            let ttl = parse_int(script_arg(argv, 1)?)?;
+            let entry = self.entry(key, now, LocalValue::Counter(0));
+            if entry.expires.is_none() {
+                entry.expires = Some(now + Duration::from_secs(ttl.max(0) as u64));
+            }
+            return match &mut entry.value {
+                LocalValue::Counter(c) => {
+                    *c += cost;
+                    Ok(Value::Int(*c))
+                }
+                _ => Err(wrong_type()),
+            };
+        }
+        let ts = parse_int(script_arg(argv, 0)?)?;
+        if is(&LIMIT_SCRIPTS.inflight) {
+            let lease = parse_int(script_arg(argv, 1)?)?;
+            let member = script_arg(argv, 2)?;
+            let entry = self.entry(key, now, LocalValue::Leases(HashMap::new()));
+            let res = match &mut entry.value {
+                LocalValue::Leases(leases) => {
+                    leases.retain(|_, expires| *expires > ts);
+                    leases.insert(member.to_vec(), ts + lease);
+                    Ok(Value::Int(leases.len() as i64))
+                }
+                _ => Err(wrong_type()),
+            };
+            entry.expires = Some(now + Duration::from_millis(lease.max(0) as u64));
+            res
+        } else if is(&LIMIT_SCRIPTS.sliding_log) {
+            let window = parse_int(script_arg(argv, 1)?)?;
+            let cap = parse_int(script_arg(argv, 3)?)?.max(0) as usize;
+            let entry = self.entry(key, now, LocalValue::Log(VecDeque::new()));
+            let res = match &mut entry.value {
+                LocalValue::Log(log) => {
+                    while log.front().map(|t| *t <= ts - window).unwrap_or(false) {
+                        log.pop_front();
+                    }
+                    log.push_back(ts);
+                    while log.len() > cap {
+                        log.pop_front();
+                    }
+                    Ok(Value::Int(log.len() as i64))
+                }
+                _ => Err(wrong_type()),
+            };
+            entry.expires = Some(now + Duration::from_millis(window.max(0) as u64));
+            res
+        } else if is(&LIMIT_SCRIPTS.sliding_counter) {
+            let window = parse_int(script_arg(argv, 1)?)?.max(1);
+            let cost = parse_int(script_arg(argv, 2)?)?;
+            let cur = ts / window;
+            let default = LocalValue::Windows {
+                index: cur,
+                count: 0,
+                prev: 0,
+            };
+            let entry = self.entry(key, now, default);
+            let res = match &mut entry.value {
+                LocalValue::Windows { index, count, prev } => {
+                    if *index == cur {
+                        *count += cost;
+                    } else {
+                        *prev = if *index + 1 == cur { *count } else { 0 };
+                        *count = cost;
+                        *index = cur;
+                    }
+                    let elapsed = (ts % window) as f64 / window as f64;
+                    Ok(Value::Int(
+                        (*prev as f64 * (1.0 - elapsed) + *count as f64).floor() as i64
+                    ))
+                }
+                _ => Err(wrong_type()),
+            };
+            entry.expires = Some(now + Duration::from_millis(window as u64 * 2));
+            res
+        } else if is(&LIMIT_SCRIPTS.token_bucket) {
+            let rate = parse_float(script_arg(argv, 1)?)?;
+            let cap = parse_float(script_arg(argv, 2)?)?;
+            let cost = parse_float(script_arg(argv, 3)?)?;
+            let default = LocalValue::Bucket {
+                level: 0.0,
+                updated: ts,
+            };
+            let entry = self.entry(key, now, default);
+            match &mut entry.value {
+                LocalValue::Bucket { level, updated } => {
+                    let drained = (ts - *updated).max(0) as f64 * rate;
+                    *level = ((*level - drained).max(0.0) + cost).min(cap);
+                    *updated = ts;
+                    let lifetime = if rate > 0.0 { (*level / rate).ceil() as u64 } else { 0 };
+                    let res = Value::Int(level.ceil() as i64);
+                    entry.expires = Some(now + Duration::from_millis(lifetime + 1000));
+                    Ok(res)
+                }
+                _ => Err(wrong_type()),
+            }
+        } else {
+            Err(RedisError::from((
+                ErrorKind::ClientError,
+                "script not supported by the local store",
+            )))
+        }
+    }
+
+    fn exec(&mut self, cmd: &Cmd, now: Instant) -> RedisResult<Value> {
+        self.sweep(now);
+        let args: Vec<&[u8]> = cmd
+            .args_iter()
+            .filter_map(|a| match a {
+                Arg::Simple(s) => Some(s),
+                Arg::Cursor => None,
+            })
+            .collect();
+        if let [name, sha, _, key, argv @ ..] = args.as_slice() {
+            if name.eq_ignore_ascii_case(b"EVALSHA") {
+                return self.evalsha(sha, key, argv, now);
+            }
+        }
+        let (name, key) = match args.as_slice() {
+            [name, key, ..] => (String::from_utf8_lossy(name).to_uppercase(), *key),
+            _ => return Err(RedisError::from((ErrorKind::ClientError, "missing command key"))),
+        };
+        match (name.as_str(), &args[2..]) {
+            ("INCR", []) => match &mut self.entry(key, now, LocalValue::Counter(0)).value {
+                LocalValue::Counter(c) => {
+                    *c += 1;
+                    Ok(Value::Int(*c))
+                }
+                _ => Err(wrong_type()),
+            },
+            ("INCRBY", [by]) => {
+                let by = parse_int(by)?;
+                match &mut self.entry(key, now, LocalValue::Counter(0)).value {
+                    LocalValue::Counter(c) => {
+                        *c += by;
+                        Ok(Value::Int(*c))
+                    }
+                    _ => Err(wrong_type()),
+                }
+            }
+            ("GET", []) => match self.live(key, now).map(|e| &e.value) {
+                None => Ok(Value::Nil),
+                Some(LocalValue::Counter(c)) => Ok(Value::Data(c.to_string().into_bytes())),
+                Some(_) => Err(wrong_type()),
+            },
+            ("SADD", [member]) => match &mut self.entry(key, now, LocalValue::Set(HashSet::new())).value {
+                LocalValue::Set(s) => Ok(Value::Int(s.insert(member.to_vec()) as i64)),
+                _ => Err(wrong_type()),
+            },
+            ("SCARD", []) => match self.live(key, now).map(|e| &e.value) {
+                None => Ok(Value::Int(0)),
+                Some(LocalValue::Set(s)) => Ok(Value::Int(s.len() as i64)),
+                Some(_) => Err(wrong_type()),
+            },
+            ("LPUSH", [item]) => match &mut self.entry(key, now, LocalValue::List(VecDeque::new())).value {
+                LocalValue::List(l) => {
+                    l.push_front(item.to_vec());
+                    Ok(Value::Int(l.len() as i64))
+                }
+                _ => Err(wrong_type()),
+            },
+            ("LLEN", []) => match self.live(key, now).map(|e| &e.value) {
+                None => Ok(Value::Int(0)),
+                Some(LocalValue::List(l)) => Ok(Value::Int(l.len() as i64)),
+                Some(_) => Err(wrong_type()),
+            },
+            ("LINDEX", [idx]) => {
+                let idx = parse_int(idx)?;
+                match self.live(key, now).map(|e| &e.value) {
+                    None => Ok(Value::Nil),
+                    Some(LocalValue::List(l)) => {
+                        let pos = if idx < 0 { l.len() as i64 + idx } else { idx };
+                        Ok(usize::try_from(pos)
+                            .ok()
+                            .and_then(|p| l.get(p))
+                            .map(|item| Value::Data(item.clone()))
+                            .unwrap_or(Value::Nil))
+                    }
+                    Some(_) => Err(wrong_type()),
+                }
+            }
+            ("ZREM", [member]) => match self.live(key, now).map(|e| &mut e.value) {
+                None => Ok(Value::Int(0)),
+                Some(LocalValue::Leases(leases)) => Ok(Value::Int(leases.remove(*member).is_some() as i64)),
+                Some(_) => Err(wrong_type()),
+            },
+            ("DEL", []) => Ok(Value::Int(match self.live(key, now) {
+                None => 0,
+                Some(_) => {
+                    self.entries.remove(key);
+                    1
+                }
+            })),
+            ("TTL", []) => Ok(Value::Int(match self.live(key, now) {
+                None => -2,
+                Some(LocalEntry { expires: None, .. }) => -1,
+                Some(LocalEntry { expires: Some(e), .. }) => e.saturating_duration_since(now).as_secs() as i64,
+            })),
+            ("EXPIRE", [secs]) => {
+                let secs = parse_int(secs)?;
+                Ok(Value::Int(match self.live(key, now) {
+                    None => 0,
+                    Some(e) => {
+                        e.expires = Some(now + Duration::from_secs(secs.max(0) as u64));
+                        1
+                    }
+                }))
+            }
+            _ => Err(RedisError::from((
+                ErrorKind::ClientError,
+                "command not supported by the local store",
+                name,
+            ))),
+        }
+    }
+}
+
+impl LocalStore {
+    fn run(&self, cmds: &[&Cmd], now: Instant) -> RedisResult<Vec<Value>> {
+        let mut state = match self.state.lock() {
+            Ok(s) => s,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        cmds.iter().map(|c| state.exec(c, now)).collect()
+    }
+}
+
+impl ConnectionLike for LocalStore {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        let res = self.run(&[cmd], Instant::now()).map(|mut v| v.remove(0));
+        futures::future::ready(res).boxed()
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipe: &'a Pipeline,
+        offset: usize,
+        _count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        let cmds: Vec<&Cmd> = pipe.cmd_iter().collect();
+        let res = self.run(&cmds, Instant::now()).map(|values| {
+            // atomic pipelines expect the results to be wrapped, as an EXEC answer
+            if offset > 0 {
+                vec![Value::Bulk(values)]
+            } else {
+                values
+            }
+        });
+        futures::future::ready(res).boxed()
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_expire() {
+        let mut state = StoreState::default();
+        let now = Instant::now();
+        let incr = redis::cmd("INCR").arg("k").clone();
+        let ttl = redis::cmd("TTL").arg("k").clone();
+        assert_eq!(state.exec(&ttl, now).unwrap(), Value::Int(-2));
+        assert_eq!(state.exec(&incr, now).unwrap(), Value::Int(1));
+        assert_eq!(state.exec(&incr, now).unwrap(), Value::Int(2));
+        assert_eq!(state.exec(&ttl, now).unwrap(), Value::Int(-1));
+        assert_eq!(
+            state.exec(redis::cmd("EXPIRE").arg("k").arg(10), now).unwrap(),
+            Value::Int(1)
+        );
+        assert_eq!(state.exec(&ttl, now).unwrap(), Value::Int(10));
+        let later = now + Duration::from_secs(11);
+        assert_eq!(state.exec(&ttl, later).unwrap(), Value::Int(-2));
+        assert_eq!(state.exec(&incr, later).unwrap(), Value::Int(1));
+        assert_eq!(
+            state.exec(redis::cmd("INCRBY").arg("k").arg(500), later).unwrap(),
+            Value::Int(501)
+        );
+    }
+
+    #[test]
+    fn sets_and_lists() {
+        let mut state = StoreState::default();
+        let now = Instant::now();
+        for m in ["a", "b", "a"] {
+            state.exec(redis::cmd("SADD").arg("s").arg(m), now).unwrap();
+        }
+        assert_eq!(state.exec(redis::cmd("SCARD").arg("s"), now).unwrap(), Value::Int(2));
+        assert_eq!(state.exec(redis::cmd("LLEN").arg("l"), now).unwrap(), Value::Int(0));
+        state.exec(redis::cmd("LPUSH").arg("l").arg("foo"), now).unwrap();
+        assert_eq!(state.exec(redis::cmd("LLEN").arg("l"), now).unwrap(), Value::Int(1));
+        state.exec(redis::cmd("LPUSH").arg("l").arg("bar"), now).unwrap();
+        assert_eq!(
+            state.exec(redis::cmd("LINDEX").arg("l").arg(0), now).unwrap(),
+            Value::Data(b"bar".to_vec())
+        );
+        assert_eq!(
+            state.exec(redis::cmd("LINDEX").arg("l").arg(-1), now).unwrap(),
+            Value::Data(b"foo".to_vec())
+        );
+        assert_eq!(
+            state.exec(redis::cmd("LINDEX").arg("l").arg(2), now).unwrap(),
+            Value::Nil
+        );
+        assert!(state.exec(redis::cmd("INCR").arg("l"), now).is_err());
+        assert!(state.exec(redis::cmd("GET").arg("l"), now).is_err());
+    }
+
+    #[test]
+    fn limit_scripts() {
+        use crate::limit::LIMIT_SCRIPTS;
+        let mut state = StoreState::default();
+        let now = Instant::now();
+        let mut log = |ts: i64| {
+            state
+                .exec(
+                    redis::cmd("EVALSHA")
+                        .arg(LIMIT_SCRIPTS.sliding_log.get_hash())
+                        .arg(1)
+                        .arg("log")
+                        .arg(ts)
+                        .arg(1000)
+                        .arg(format!("m{}", ts))
+                        .arg(3),
+                    now,
+                )
+                .unwrap()
+        };
+        assert_eq!(log(0), Value::Int(1));
+        assert_eq!(log(500), Value::Int(2));
+        assert_eq!(log(999), Value::Int(3));
+        assert_eq!(log(999), Value::Int(3));
+        assert_eq!(log(1600), Value::Int(3));
+        assert_eq!(log(2000), Value::Int(2));
+
+        let mut state = StoreState::default();
+        let mut counter = |ts: i64| {
+            state
+                .exec(
+                    redis::cmd("EVALSHA")
+                        .arg(LIMIT_SCRIPTS.sliding_counter.get_hash())
+                        .arg(1)
+                        .arg("windows")
+                        .arg(ts)
+                        .arg(1000)
+                        .arg(1),
+                    now,
+                )
+                .unwrap()
+        };
+        for ts in 0..4 {
+            assert_eq!(counter(ts * 100), Value::Int(ts + 1));
+        }
+        // previous window weighted at 75%
+        assert_eq!(counter(1250), Value::Int(4));
+        assert_eq!(counter(3000), Value::Int(1));
+
+        let mut state = StoreState::default();
+        let mut bucket = |ts: i64| {
+            state
+                .exec(
+                    redis::cmd("EVALSHA")
+                        .arg(LIMIT_SCRIPTS.token_bucket.get_hash())
+                        .arg(1)
+                        .arg("bucket")
+                        .arg(ts)
+                        .arg(0.01)
+                        .arg(5)
+                        .arg(1),
+                    now,
+                )
+                .unwrap()
+        };
+        assert_eq!(bucket(0), Value::Int(1));
+        assert_eq!(bucket(0), Value::Int(2));
+        assert_eq!(bucket(50), Value::Int(3));
+        assert_eq!(bucket(50), Value::Int(4));
+        for _ in 0..5 {
+            bucket(100);
+        }
+        assert_eq!(bucket(100), Value::Int(5));
+        assert_eq!(bucket(600), Value::Int(1));
+    }
+
+    #[test]
+    fn inflight_leases() {
+        let mut state = StoreState::default();
+        let now = Instant::now();
+        let take = |ts: i64, member: &str| {
+            redis::cmd("EVALSHA")
+                .arg(crate::limit::LIMIT_SCRIPTS.inflight.get_hash())
+                .arg(1)
+                .arg("f")
+                .arg(ts)
+                .arg(1000)
+                .arg(member)
+                .clone()
+        };
+        let release = |member: &str| redis::cmd("ZREM").arg("f").arg(member).clone();
+        assert_eq!(state.exec(&take(0, "a"), now).unwrap(), Value::Int(1));
+        assert_eq!(state.exec(&take(100, "b"), now).unwrap(), Value::Int(2));
+        assert_eq!(state.exec(&release("a"), now).unwrap(), Value::Int(1));
+        // releasing twice does not free the lease of another request
+        assert_eq!(state.exec(&release("a"), now).unwrap(), Value::Int(0));
+        assert_eq!(state.exec(&take(200, "c"), now).unwrap(), Value::Int(2));
+        // the lease of b was never released, it expires on its own
+        assert_eq!(state.exec(&take(1150, "d"), now).unwrap(), Value::Int(2));
+    }
+
+    #[test]
+    fn quota() {
+        let mut state = StoreState::default();
+        let now = Instant::now();
+        let quota = |cost: u64, ttl: u64| {
+            redis::cmd("EVALSHA")
+                .arg(crate::limit::LIMIT_SCRIPTS.quota.get_hash())
+                .arg(1)
+                .arg("q")
+                .arg(cost)
+                .arg(ttl)
+                .clone()
+        };
+        assert_eq!(state.exec(redis::cmd("GET").arg("q"), now).unwrap(), Value::Nil);
+        assert_eq!(state.exec(&quota(1, 100), now).unwrap(), Value::Int(1));
+        // the expiration is set once, at the creation of the counter
+        assert_eq!(state.exec(&quota(5, 1000), now).unwrap(), Value::Int(6));
+        assert_eq!(state.exec(redis::cmd("TTL").arg("q"), now).unwrap(), Value::Int(100));
+        assert_eq!(
+            state.exec(redis::cmd("GET").arg("q"), now).unwrap(),
+            Value::Data(b"6".to_vec())
+        );
+    }
+
+    #[test]
+    fn pipeline() {
+        let mut store = LocalStore::default();
+        let mut pipe = redis::pipe();
+        pipe.cmd("SADD")
+            .arg("p")
+            .arg("x")
+            .ignore()
+            .cmd("SCARD")
+            .arg("p")
+            .cmd("TTL")
+            .arg("p");
+        let res: Vec<Option<i64>> = async_std::task::block_on(pipe.query_async(&mut store)).unwrap();
+        assert_eq!(res, vec![Some(1), Some(-1)]);
+    }
+}
@@ -0,0 +1,295 @@
+//! Login protection, against credential stuffing and password spraying.
+//!
+//! A security policy entry can designate its login endpoints (path templates, and methods, POST by default), and the
+//! arguments holding the username and password. For each login attempt, three distinct counts are kept in redis, as
+//! HyperLogLogs over fixed windows of `window_seconds`:
+//!
+//!  * the usernames tried from the client IP, that grows fast with credential stuffing,
+//!  * the IP addresses trying the username, that grows with distributed attacks on an account,
+//!  * the usernames tried with the same password, that grows with password spraying and breached password lists.
+//!
+//! Usernames and passwords are only stored as truncated hashes. Login attempts are tagged `login-attempt`, and each
+//! threshold that is exceeded adds a `login-anomaly:<signal>` tag and a reason, with the configured action (a
+//! challenge by default).
+use sha2::{Digest, Sha256};
+
+use crate::analyze::APhase0;
+use crate::clientstate::{Pending, StateQuery, StateReplies};
+use crate::config::pathtrie::PathTemplate;
+use crate::config::raw::{RawActionType, RawLoginProtection};
+use crate::failure::{dependency_failed, Dependency};
+use crate::grasshopper::GHMode;
+use crate::interface::{stronger_decision, BlockReason, Location, SimpleAction, SimpleActionT, SimpleDecision};
+use crate::logs::Logs;
+use crate::redis::REDIS_KEY_PREFIX;
+use crate::utils::RequestInfo;
+
+/// prefix of the tags of login attempts, and of the anomalies of their counts
+pub const LOGIN_TAG_PREFIX: &str = "login-";
+
+#[derive(Debug, Clone)]
+pub struct LoginProtectionSettings {
+    pub paths: Vec<PathTemplate>,
+    /// uppercase
+    pub methods: Vec<String>,
+    pub username_field: String,
+    pub password_field: String,
+    /// seconds
+    pub window: u64,
+    pub max_usernames_per_ip: u64,
+    pub max_ips_per_username: u64,
+    pub max_usernames_per_password: u64,
+    pub action: SimpleAction,
+}
+
+/// the credentials of a login attempt, they are hashed before being stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoginAttempt {
+    pub username: String,
+    pub password: Option<String>,
+}
+
+/// distinct counts of the current window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoginCounts {
+    pub usernames_per_ip: u64,
+    pub ips_per_username: u64,
+    pub usernames_per_password: u64,
+}
+
+pub(crate) fn short_hash(namespace: &str, value: &str) -> String {
+    let digest = Sha256::digest(format!("{}{}", namespace, value).as_bytes());
+    digest.iter().take(8).map(|b| format!("{:02x}", b)).collect()
+}
+
+/// action of the abuse protections: monitor, challenge (the default), ichallenge, or custom for a block
+pub(crate) fn protection_action(logs: &mut Logs, what: &str, raw: Option<RawActionType>) -> SimpleAction {
+    let atype = match raw.unwrap_or(RawActionType::Challenge) {
+        RawActionType::Monitor => SimpleActionT::Monitor,
+        RawActionType::Challenge => SimpleActionT::Challenge {
+            ch_level: GHMode::Active,
+        },
+        RawActionType::Ichallenge => SimpleActionT::Challenge {
+            ch_level: GHMode::Interactive,
+        },
+        RawActionType::Custom => SimpleActionT::default(),
+        other => {
+            logs.error(|| format!("unsupported {} action {:?}, using a challenge", what, other));
+            SimpleActionT::Challenge {
+                ch_level: GHMode::Active,
+            }
+        }
+    };
+    SimpleAction {
+        atype,
+        status: 403,
+        ..SimpleAction::default()
+    }
+}
+
+impl LoginProtectionSettings {
+    pub fn resolve(logs: &mut Logs, raw: RawLoginProtection) -> Option<Self> {
+        let mut paths = Vec::new();
+        for p in raw.paths {
+            match p.parse() {
+                Ok(tpl) => paths.push(tpl),
+                Err(rr) => logs.error(|| format!("login protection path: {}", rr)),
+            }
+        }
+        if paths.is_empty() {
+            logs.error("login protection requires login paths, it is disabled");
+            return None;
+        }
+        let methods = if raw.methods.is_empty() {
+            vec!["POST".to_string()]
+        } else {
+            raw.methods.iter().map(|m| m.to_uppercase()).collect()
+        };
+        Some(LoginProtectionSettings {
+            paths,
+            methods,
+            username_field: raw.username_field.unwrap_or_else(|| "username".to_string()),
+            password_field: raw.password_field.unwrap_or_else(|| "password".to_string()),
+            window: raw.window_seconds.unwrap_or(600).max(1),
+            max_usernames_per_ip: raw.max_usernames_per_ip.unwrap_or(5),
+            max_ips_per_username: raw.max_ips_per_username.unwrap_or(10),
+            max_usernames_per_password: raw.max_usernames_per_password.unwrap_or(5),
+            action: protection_action(logs, "login protection", raw.action),
+        })
+    }
+
+    /// the credentials of the request, if it is a login attempt
+    pub fn attempt(&self, reqinfo: &RequestInfo) -> Option<LoginAttempt> {
+        if !self.methods.contains(&reqinfo.rinfo.meta.method.to_uppercase()) {
+            return None;
+        }
+        let path = &reqinfo.rinfo.qinfo.normalized.path;
+        if !self.paths.iter().any(|tpl| tpl.captures(path).is_some()) {
+            return None;
+        }
+        let args = &reqinfo.rinfo.qinfo.args;
+        let username = args.get_str(&self.username_field).filter(|u| !u.is_empty())?;
+        Some(LoginAttempt {
+            username: username.to_lowercase(),
+            password: args
+                .get_str(&self.password_field)
+                .filter(|p| !p.is_empty())
+                .map(|p| p.to_string()),
+        })
+    }
+
+    /// the exceeded thresholds, as (signal, count, threshold)
+    pub fn anomalies(&self, counts: &LoginCounts) -> Vec<(&'static str, u64, u64)> {
+        [
+            ("usernames-per-ip", counts.usernames_per_ip, self.max_usernames_per_ip),
+            ("ips-per-username", counts.ips_per_username, self.max_ips_per_username),
+            (
+                "usernames-per-password",
+                counts.usernames_per_password,
+                self.max_usernames_per_password,
+            ),
+        ]
+        .iter()
+        .filter(|(_, count, max)| count > max)
+        .cloned()
+        .collect()
+    }
+}
+
+/// the HyperLogLogs of the attempt, and the member it adds to each of them
+fn attempt_sets(
+    reqinfo: &RequestInfo,
+    settings: &LoginProtectionSettings,
+    attempt: &LoginAttempt,
+) -> Vec<(String, String)> {
+    let namespace = &reqinfo.rinfo.secpolicy.counters.namespace;
+    let bucket = reqinfo.timestamp.timestamp().max(0) as u64 / settings.window;
+    let prefix = format!("{}{}login_", *REDIS_KEY_PREFIX, namespace);
+    let ip = &reqinfo.rinfo.geoip.ipstr;
+    let user = short_hash(namespace, &attempt.username);
+    let mut sets = vec![
+        (format!("{}ip_{}_{}", prefix, ip, bucket), user.clone()),
+        (format!("{}user_{}_{}", prefix, user, bucket), ip.clone()),
+    ];
+    if let Some(password) = &attempt.password {
+        let pw = short_hash(namespace, password);
+        sets.push((format!("{}pw_{}_{}", prefix, pw, bucket), user));
+    }
+    sets
+}
+
+/// tags login attempts, and adds the commands that count them
+pub fn query_login(p0: &mut APhase0, query: &mut StateQuery) -> Option<Pending> {
+    let secpolicy = p0.reqinfo.rinfo.secpolicy.clone();
+    let settings = secpolicy.login_protection.as_ref()?;
+    let attempt = settings.attempt(&p0.reqinfo)?;
+    p0.itags.insert("login-attempt", Location::Request);
+    let sets = attempt_sets(&p0.reqinfo, settings, &attempt);
+    Some(query.add(|pipe| {
+        for (key, member) in &sets {
+            pipe.cmd("PFADD").arg(key).arg(member).ignore();
+            pipe.cmd("EXPIRE").arg(key).arg(settings.window).ignore();
+            pipe.cmd("PFCOUNT").arg(key);
+        }
+        sets.len()
+    }))
+}
+
+/// sets the login protection action when a threshold is exceeded
+pub fn apply_login(logs: &mut Logs, p0: &mut APhase0, pending: Pending, replies: &StateReplies) {
+    let secpolicy = p0.reqinfo.rinfo.secpolicy.clone();
+    let settings = match &secpolicy.login_protection {
+        None => return,
+        Some(s) => s,
+    };
+    let counts = match replies.get::<Vec<u64>>(pending) {
+        Ok(counts) => LoginCounts {
+            usernames_per_ip: counts.first().copied().unwrap_or(0),
+            ips_per_username: counts.get(1).copied().unwrap_or(0),
+            usernames_per_password: counts.get(2).copied().unwrap_or(0),
+        },
+        Err(rr) => {
+            logs.error(|| format!("login protection: {}", rr));
+            p0.itags.insert("login-protection-degraded", Location::Request);
+            dependency_failed(&mut p0.itags, Dependency::Redis);
+            return;
+        }
+    };
+    logs.debug(|| format!("login attempt counts: {:?}", counts));
+    let mut reasons = Vec::new();
+    for (signal, count, max) in settings.anomalies(&counts) {
+        let location = if signal == "usernames-per-ip" {
+            Location::Ip
+        } else {
+            Location::Request
+        };
+        p0.itags.insert_qualified("login-anomaly", signal, location.clone());
+        reasons.push(BlockReason::login_anomaly(
+            secpolicy.entry.id.clone(),
+            secpolicy.entry.name.clone(),
+            settings.action.atype.to_raw(),
+            signal,
+            location,
+            count,
+            max,
+        ));
+    }
+    if !reasons.is_empty() {
+        let decision = SimpleDecision::Action(settings.action.clone(), reasons);
+        let previous = std::mem::replace(&mut p0.globalfilter_dec, SimpleDecision::Pass);
+        p0.globalfilter_dec = stronger_decision(previous, decision);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::request_info;
+
+    fn mk_reqinfo(method: &str, path: &str) -> RequestInfo {
+        request_info(&[("method", method), ("path", path)], &[])
+    }
+
+    #[test]
+    fn attempts_and_anomalies() {
+        let settings = LoginProtectionSettings::resolve(
+            &mut Logs::default(),
+            RawLoginProtection {
+                paths: vec!["/login".to_string()],
+                username_field: Some("user".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            settings.action.atype,
+            SimpleActionT::Challenge {
+                ch_level: GHMode::Active
+            }
+        );
+        assert_eq!(
+            settings.attempt(&mk_reqinfo("POST", "/login?user=Bob&password=hunter2")),
+            Some(LoginAttempt {
+                username: "bob".to_string(),
+                password: Some("hunter2".to_string())
+            })
+        );
+        assert_eq!(settings.attempt(&mk_reqinfo("GET", "/login?user=bob")), None);
+        assert_eq!(settings.attempt(&mk_reqinfo("POST", "/logout?user=bob")), None);
+        assert_eq!(settings.attempt(&mk_reqinfo("POST", "/login?user=")), None);
+
+        let counts = LoginCounts {
+            usernames_per_ip: 12,
+            ips_per_username: 2,
+            usernames_per_password: 6,
+        };
+        assert_eq!(
+            settings.anomalies(&counts),
+            vec![("usernames-per-ip", 12, 5), ("usernames-per-password", 6, 5)]
+        );
+        assert_ne!(short_hash("a", "bob"), short_hash("b", "bob"));
+
+        let missing = RawLoginProtection::default();
+        assert!(LoginProtectionSettings::resolve(&mut Logs::default(), missing).is_none());
+    }
+}
@@ -1,11 +1,56 @@
+use crate::otel::TraceContext;
 use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 use std::time::Instant;
 
+/// process wide count of the warning and error messages, including those below the logging level
+static WARNING_COUNT: AtomicU64 = AtomicU64::new(0);
+static ERROR_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// errors of the background tasks, reported in the logs of the next analyzed request
+static BACKGROUND_ERRORS: Mutex<Vec<Log>> = Mutex::new(Vec::new());
+/// only the last background errors are kept when no request is analyzed
+const MAX_BACKGROUND_ERRORS: usize = 16;
+
+/// logs an error of a background task, such as a writer or exporter thread, that has no request logs
+pub fn background_error<S: CheapString>(message: S) {
+    ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
+    let mut pending = BACKGROUND_ERRORS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if pending.len() >= MAX_BACKGROUND_ERRORS {
+        pending.remove(0);
+    }
+    pending.push(Log {
+        elapsed_micros: 0,
+        level: LogLevel::Error,
+        message: message.c_to_string(),
+    });
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct LogCounters {
+    pub warnings: u64,
+    pub errors: u64,
+}
+
+pub fn log_counters() -> LogCounters {
+    LogCounters {
+        warnings: WARNING_COUNT.load(Ordering::Relaxed),
+        errors: ERROR_COUNT.load(Ordering::Relaxed),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Logs {
     pub level: LogLevel,
     pub start: Instant,
     pub logs: Vec<Log>,
+    /// trace context of the request being analyzed, so that logs can be correlated with traces
+    pub trace: Option<TraceContext>,
+    /// identifier of the request being analyzed, prepended to the entries when they are rendered
+    pub request_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -63,6 +108,8 @@ impl Default for Logs {
             start: Instant::now(),
             level: LogLevel::Debug,
             logs: Vec::new(),
+            trace: None,
+            request_id: None,
         }
     }
 }
@@ -94,10 +141,17 @@ impl Logs {
             start: Instant::now(),
             level: lvl,
             logs: Vec::new(),
+            trace: None,
+            request_id: None,
         }
     }
 
     pub fn log<S: CheapString>(&mut self, level: LogLevel, message: S) {
+        match level {
+            LogLevel::Warning => WARNING_COUNT.fetch_add(1, Ordering::Relaxed),
+            LogLevel::Error => ERROR_COUNT.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
         if level < self.level {
             return;
         }
@@ -122,13 +176,28 @@ impl Logs {
     }
 
     pub fn to_stringvec(&self) -> Vec<String> {
-        self.logs.iter().map(|l| l.to_string()).collect()
+        self.logs.iter().map(|l| self.render(l)).collect()
+    }
+
+    fn render(&self, log: &Log) -> String {
+        match &self.request_id {
+            None => log.to_string(),
+            Some(id) => format!("[{}] {}", id, log),
+        }
     }
 
     pub fn extend(&mut self, other: Logs) {
         self.logs.extend(other.logs);
     }
 
+    /// moves the pending errors of the background tasks to these logs
+    pub fn background_errors(&mut self) {
+        let mut pending = BACKGROUND_ERRORS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.logs.append(&mut pending);
+    }
+
     pub fn to_json(&self) -> serde_json::Value {
         serde_json::to_value(&self.logs).unwrap_or_else(|rr| serde_json::Value::String(rr.to_string()))
     }
@@ -139,6 +208,6 @@ impl Serialize for Logs {
     where
         S: serde::Serializer,
     {
-        serializer.collect_seq(self.logs.iter().map(|l| l.to_string()))
+        serializer.collect_seq(self.logs.iter().map(|l| self.render(l)))
     }
 }
@@ -0,0 +1,220 @@
+//! Log and alert sinks, with explicit back-pressure handling.
+//!
+//! The request path only calls `LogSink::submit`, which never waits for the destination: when it is
+//! too slow, the bounded queue fills up, and records are dropped according to the sink drop policy.
+//! Drops are counted, and exposed with the aggregated statistics.
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::time::Duration;
+
+lazy_static! {
+    static ref SINKS: Mutex<Vec<Weak<dyn LogSink>>> = Mutex::new(Vec::new());
+}
+
+/// what to do when the queue of a sink is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropPolicy {
+    /// the record being submitted is dropped
+    DropNewest,
+    /// the oldest queued record is dropped to make room
+    DropOldest,
+}
+
+impl std::str::FromStr for DropPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop_newest" | "newest" => Ok(DropPolicy::DropNewest),
+            "drop_oldest" | "oldest" => Ok(DropPolicy::DropOldest),
+            _ => Err(anyhow::anyhow!("invalid drop policy {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    pub data: Vec<u8>,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SinkStats {
+    pub submitted: u64,
+    pub dropped: u64,
+    pub queued: usize,
+    pub capacity: usize,
+}
+
+/// A destination for access logs and alerts
+pub trait LogSink: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// queues a record, without ever blocking, returns false when the record has been dropped
+    fn submit(&self, record: LogRecord) -> bool;
+
+    fn stats(&self) -> SinkStats;
+}
+
+/// a sink backed by a bounded queue, that is consumed by a dedicated task
+pub struct BoundedSink {
+    name: String,
+    capacity: usize,
+    policy: DropPolicy,
+    queue: Mutex<VecDeque<LogRecord>>,
+    ready: Condvar,
+    submitted: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl BoundedSink {
+    /// creates a new sink, and registers it so that its statistics are reported
+    pub fn new(name: &str, capacity: usize, policy: DropPolicy) -> Arc<Self> {
+        let sink = Arc::new(BoundedSink {
+            name: name.to_string(),
+            capacity: capacity.max(1),
+            policy,
+            queue: Mutex::new(VecDeque::new()),
+            ready: Condvar::new(),
+            submitted: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        });
+        let dynsink: Arc<dyn LogSink> = sink.clone();
+        register_sink(&dynsink);
+        sink
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VecDeque<LogRecord>> {
+        match self.queue.lock() {
+            Ok(q) => q,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<LogRecord> {
+        self.lock().pop_front()
+    }
+
+    /// waits for the next record, meant to be called by the consumer thread
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<LogRecord> {
+        let queue = self.lock();
+        let mut queue = match self.ready.wait_timeout_while(queue, timeout, |q| q.is_empty()) {
+            Ok((q, _)) => q,
+            Err(poisoned) => poisoned.into_inner().0,
+        };
+        queue.pop_front()
+    }
+}
+
+impl LogSink for BoundedSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn submit(&self, record: LogRecord) -> bool {
+        self.submitted.fetch_add(1, Ordering::Relaxed);
+        let mut queue = self.lock();
+        let accepted = if queue.len() < self.capacity {
+            queue.push_back(record);
+            true
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            match self.policy {
+                DropPolicy::DropNewest => false,
+                DropPolicy::DropOldest => {
+                    queue.pop_front();
+                    queue.push_back(record);
+                    true
+                }
+            }
+        };
+        drop(queue);
+        if accepted {
+            self.ready.notify_one();
+        }
+        accepted
+    }
+
+    fn stats(&self) -> SinkStats {
+        SinkStats {
+            submitted: self.submitted.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            queued: self.lock().len(),
+            capacity: self.capacity,
+        }
+    }
+}
+
+/// registers a sink, so that its statistics are reported
+pub fn register_sink(sink: &Arc<dyn LogSink>) {
+    if let Ok(mut sinks) = SINKS.lock() {
+        sinks.retain(|s| s.strong_count() > 0);
+        sinks.push(Arc::downgrade(sink));
+    }
+}
+
+/// statistics of all live sinks, by name
+pub fn sinks_stats() -> HashMap<String, SinkStats> {
+    match SINKS.lock() {
+        Ok(sinks) => sinks
+            .iter()
+            .filter_map(|s| s.upgrade())
+            .map(|s| (s.name().to_string(), s.stats()))
+            .collect(),
+        Err(_) => HashMap::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(n: u8) -> LogRecord {
+        LogRecord {
+            data: vec![n],
+            timestamp: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn drop_newest() {
+        let sink = BoundedSink::new("test_newest", 2, DropPolicy::DropNewest);
+        assert!(sink.submit(record(1)));
+        assert!(sink.submit(record(2)));
+        assert!(!sink.submit(record(3)));
+        assert_eq!(
+            sink.stats(),
+            SinkStats {
+                submitted: 3,
+                dropped: 1,
+                queued: 2,
+                capacity: 2
+            }
+        );
+        assert_eq!(sink.try_recv().map(|r| r.data), Some(vec![1]));
+        assert!(sinks_stats().contains_key("test_newest"));
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let sink = BoundedSink::new("test_oldest", 2, DropPolicy::DropOldest);
+        for n in 1..=3 {
+            assert!(sink.submit(record(n)));
+        }
+        assert_eq!(sink.stats().dropped, 1);
+        assert_eq!(
+            sink.recv_timeout(Duration::from_millis(1)).map(|r| r.data),
+            Some(vec![2])
+        );
+        assert_eq!(
+            sink.recv_timeout(Duration::from_millis(1)).map(|r| r.data),
+            Some(vec![3])
+        );
+        assert!(sink.recv_timeout(Duration::from_millis(1)).is_none());
+    }
+}
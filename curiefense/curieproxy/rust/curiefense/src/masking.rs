@@ -0,0 +1,433 @@
+//! Masking policies.
+//!
+//! On top of the mask flag of the content filter entries, a content filter profile can define a masking policy:
+//! named classes of sensitive values (the built-in email, phone and credit_card classes, and custom regexes), and
+//! rules selecting the fields to mask, by section and name. When a rule lists classes, only the parts of the value
+//! matching them are masked, otherwise the whole value is. The masking modes are:
+//!
+//!  * full: the value is replaced by MASKED,
+//!  * partial: all characters but the last four are replaced by `*`,
+//!  * hash: the value is replaced by a salted hash, as MASKED{...}, so that equal values can be matched.
+//!
+//! The policy is applied to the request information before it is returned, so that the access logs never see the
+//! raw values. Masked arguments are also replaced in the query string. The client_cert section holds the subject
+//! (named `subject`) and alternative names (named `san`) of the mutual TLS client certificate.
+//!
+//! With pseudonymize, the full and hash modes, and the mask flag of the content filter entries, replace values with
+//! an HMAC pseudonym, PSEUDO{...}, so that the same value can be followed across requests, and across profiles
+//! sharing the key. The key is set with pseudonym_key, or read from the environment variable named by
+//! pseudonym_key_env (CF_PSEUDONYM_KEY by default) when the configuration is loaded.
+use regex::Regex;
+use std::sync::Arc;
+
+use crate::config::raw::{RawMaskingPolicy, RawMaskingRule};
+use crate::logs::Logs;
+use crate::requestfields::RequestField;
+use crate::utils::{hmac_sha256, masker, ClientCert, RequestInfo};
+
+/// environment variable holding the pseudonymization key, when not set in the profile
+const DEFAULT_KEY_ENV: &str = "CF_PSEUDONYM_KEY";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaskingMode {
+    Full,
+    Partial,
+    Hash,
+}
+
+impl std::str::FromStr for MaskingMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(MaskingMode::Full),
+            "partial" => Ok(MaskingMode::Partial),
+            "hash" => Ok(MaskingMode::Hash),
+            _ => Err(anyhow::anyhow!("invalid masking mode {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaskedSection {
+    Args,
+    Headers,
+    Cookies,
+    /// the subject and subject alternative names of the client certificate
+    ClientCert,
+}
+
+/// a named class of sensitive values
+#[derive(Debug)]
+pub struct MaskingClass {
+    pub name: String,
+    pub regex: Regex,
+    /// matches are only kept when they pass the Luhn check
+    pub luhn: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct MaskingRule {
+    /// all sections when empty
+    pub sections: Vec<MaskedSection>,
+    /// all names when not set, compared case insensitively
+    pub name: Option<String>,
+    /// the whole value is masked when empty
+    pub classes: Vec<Arc<MaskingClass>>,
+    pub mode: MaskingMode,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MaskingPolicy {
+    pub rules: Vec<MaskingRule>,
+    pub salt: Vec<u8>,
+    /// masked values are replaced by pseudonyms when set
+    pub pseudonym_key: Option<Vec<u8>>,
+}
+
+/// deterministic pseudonym of a value
+pub fn pseudonym(key: &[u8], value: &str) -> String {
+    format!("PSEUDO{{{}}}", hex::encode(&hmac_sha256(key, value.as_bytes())[..8]))
+}
+
+fn builtin_class(name: &str) -> Option<MaskingClass> {
+    let (re, luhn) = match name {
+        "email" => (r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}", false),
+        "phone" => (r"\+?\d[\d .()-]{6,}\d", false),
+        "credit_card" => (r"\b\d(?:[ -]?\d){12,18}\b", true),
+        _ => return None,
+    };
+    Some(MaskingClass {
+        name: name.to_string(),
+        regex: Regex::new(re).ok()?,
+        luhn,
+    })
+}
+
+fn luhn_valid(candidate: &str) -> bool {
+    let digits: Vec<u32> = candidate.chars().filter_map(|c| c.to_digit(10)).collect();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| match (i % 2, d * 2) {
+            (0, _) => d,
+            (_, dd) if dd > 9 => dd - 9,
+            (_, dd) => dd,
+        })
+        .sum();
+    digits.len() >= 13 && sum.is_multiple_of(10)
+}
+
+impl MaskingPolicy {
+    pub fn resolve(logs: &mut Logs, raw: RawMaskingPolicy, default_salt: &str) -> anyhow::Result<Self> {
+        let mut classes: Vec<Arc<MaskingClass>> = Vec::new();
+        for rawclass in raw.classes {
+            let regex =
+                Regex::new(&rawclass.regex).map_err(|rr| anyhow::anyhow!("masking class {}: {}", rawclass.name, rr))?;
+            classes.push(Arc::new(MaskingClass {
+                name: rawclass.name,
+                regex,
+                luhn: false,
+            }));
+        }
+        let mut find_class = |name: &str| -> anyhow::Result<Arc<MaskingClass>> {
+            if let Some(c) = classes.iter().find(|c| c.name == name) {
+                return Ok(c.clone());
+            }
+            let c = Arc::new(builtin_class(name).ok_or_else(|| anyhow::anyhow!("unknown masking class {}", name))?);
+            classes.push(c.clone());
+            Ok(c)
+        };
+        let mut rules = Vec::new();
+        for RawMaskingRule {
+            sections,
+            name,
+            classes: rule_classes,
+            mode,
+        } in raw.rules
+        {
+            let sections = sections
+                .iter()
+                .map(|s| match s.as_str() {
+                    "args" => Ok(MaskedSection::Args),
+                    "headers" => Ok(MaskedSection::Headers),
+                    "cookies" => Ok(MaskedSection::Cookies),
+                    "client_cert" => Ok(MaskedSection::ClientCert),
+                    _ => Err(anyhow::anyhow!("invalid masking section {}", s)),
+                })
+                .collect::<anyhow::Result<Vec<MaskedSection>>>()?;
+            let classes = rule_classes
+                .iter()
+                .map(|c| find_class(c))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            rules.push(MaskingRule {
+                sections,
+                name,
+                classes,
+                mode: mode.as_deref().unwrap_or("full").parse()?,
+            });
+        }
+        let pseudonym_key = if raw.pseudonymize {
+            let env = raw.pseudonym_key_env.as_deref().unwrap_or(DEFAULT_KEY_ENV);
+            let key = raw
+                .pseudonym_key
+                .or_else(|| std::env::var(env).ok())
+                .filter(|k| !k.is_empty());
+            if key.is_none() {
+                logs.error(|| format!("no pseudonymization key, neither in the profile nor in {}", env));
+            }
+            key.map(String::into_bytes)
+        } else {
+            None
+        };
+        Ok(MaskingPolicy {
+            rules,
+            salt: raw.salt.unwrap_or_else(|| default_salt.to_string()).into_bytes(),
+            pseudonym_key,
+        })
+    }
+
+    /// masks a value flagged by a content filter entry
+    pub fn mask_value(&self, masking_seed: &[u8], value: &str) -> String {
+        match &self.pseudonym_key {
+            Some(key) => pseudonym(key, value),
+            None => masker(masking_seed, value),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    fn mask(&self, mode: MaskingMode, value: &str) -> String {
+        match (mode, &self.pseudonym_key) {
+            (MaskingMode::Full, Some(key)) | (MaskingMode::Hash, Some(key)) => pseudonym(key, value),
+            (MaskingMode::Full, None) => "MASKED".to_string(),
+            (MaskingMode::Partial, _) => {
+                let nchars = value.chars().count();
+                value
+                    .chars()
+                    .enumerate()
+                    .map(|(i, c)| if nchars > 4 && i + 4 >= nchars { c } else { '*' })
+                    .collect()
+            }
+            (MaskingMode::Hash, None) => masker(&self.salt, value),
+        }
+    }
+
+    /// the masked value, None when the rule does not change it
+    fn apply_rule(&self, rule: &MaskingRule, value: &str) -> Option<String> {
+        if rule.classes.is_empty() {
+            return Some(self.mask(rule.mode, value));
+        }
+        let mut out = value.to_string();
+        let mut changed = false;
+        for class in &rule.classes {
+            let masked = class.regex.replace_all(&out, |caps: &regex::Captures| {
+                let m = &caps[0];
+                if class.luhn && !luhn_valid(m) {
+                    m.to_string()
+                } else {
+                    self.mask(rule.mode, m)
+                }
+            });
+            if masked != out {
+                out = masked.into_owned();
+                changed = true;
+            }
+        }
+        if changed {
+            Some(out)
+        } else {
+            None
+        }
+    }
+
+    /// masks the fields of a section, returns the replaced values
+    fn apply_section(&self, section: MaskedSection, field: &mut RequestField) -> Vec<(String, String)> {
+        let mut replaced = Vec::new();
+        for rule in &self.rules {
+            if !rule.sections.is_empty() && !rule.sections.contains(&section) {
+                continue;
+            }
+            for (name, (value, _)) in field.fields.iter_mut() {
+                if let Some(n) = &rule.name {
+                    if !n.eq_ignore_ascii_case(name) {
+                        continue;
+                    }
+                }
+                if let Some(masked) = self.apply_rule(rule, value) {
+                    replaced.push((std::mem::replace(value, masked.clone()), masked));
+                }
+            }
+        }
+        replaced
+    }
+
+    /// masks the subject (name `subject`) and alternative names (name `san`) of a client certificate
+    fn apply_client_cert(&self, cert: &mut ClientCert) {
+        for rule in &self.rules {
+            if !rule.sections.is_empty() && !rule.sections.contains(&MaskedSection::ClientCert) {
+                continue;
+            }
+            let selected = |field: &str| {
+                rule.name
+                    .as_ref()
+                    .map(|n| n.eq_ignore_ascii_case(field))
+                    .unwrap_or(true)
+            };
+            if selected("subject") {
+                if let Some(subject) = cert.subject.as_mut() {
+                    if let Some(masked) = self.apply_rule(rule, subject) {
+                        *subject = masked;
+                    }
+                }
+            }
+            if selected("san") {
+                for san in cert.san.iter_mut() {
+                    if let Some(masked) = self.apply_rule(rule, san) {
+                        *san = masked;
+                    }
+                }
+            }
+        }
+    }
+
+    /// applies the policy to the request arguments, headers, cookies and client certificate
+    pub fn apply(&self, ri: &mut RequestInfo) {
+        if self.is_empty() {
+            return;
+        }
+        let args = self.apply_section(MaskedSection::Args, &mut ri.rinfo.qinfo.args);
+        self.apply_section(MaskedSection::Headers, &mut ri.headers);
+        self.apply_section(MaskedSection::Cookies, &mut ri.cookies);
+        if let Some(cert) = ri.rinfo.meta.client_cert.as_mut() {
+            self.apply_client_cert(cert);
+        }
+        for (original, masked) in args.iter().filter(|(o, _)| !o.is_empty()) {
+            ri.rinfo.meta.path = ri.rinfo.meta.path.replace(original, masked);
+            if let Some(q) = &ri.rinfo.qinfo.query {
+                ri.rinfo.qinfo.query = Some(q.replace(original, masked));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::raw::RawMaskingClass;
+
+    fn policy(rules: Vec<RawMaskingRule>) -> MaskingPolicy {
+        MaskingPolicy::resolve(
+            &mut Logs::default(),
+            RawMaskingPolicy {
+                classes: vec![RawMaskingClass {
+                    name: "ssn".to_string(),
+                    regex: r"\d{3}-\d{2}-\d{4}".to_string(),
+                }],
+                rules,
+                ..Default::default()
+            },
+            "seed",
+        )
+        .unwrap()
+    }
+
+    fn rule(classes: &[&str], mode: &str) -> RawMaskingRule {
+        RawMaskingRule {
+            sections: Vec::new(),
+            name: None,
+            classes: classes.iter().map(|s| s.to_string()).collect(),
+            mode: Some(mode.to_string()),
+        }
+    }
+
+    #[test]
+    fn classes() {
+        let p = policy(Vec::new());
+        let r = policy(vec![rule(&["email", "credit_card"], "partial")]);
+        let pii = &r.rules[0];
+        assert_eq!(
+            r.apply_rule(pii, "mail bob@example.com"),
+            Some("mail ***********.com".to_string())
+        );
+        assert_eq!(
+            r.apply_rule(pii, "card 4111 1111 1111 1111"),
+            Some("card ***************1111".to_string())
+        );
+        // not a valid card number
+        assert_eq!(r.apply_rule(pii, "id 4111111111111112"), None);
+        assert!(p.is_empty());
+
+        let ssn = policy(vec![rule(&["ssn"], "full")]);
+        assert_eq!(ssn.apply_rule(&ssn.rules[0], "123-45-6789"), Some("MASKED".to_string()));
+        let hashed = policy(vec![rule(&[], "hash")]);
+        assert_eq!(
+            hashed.apply_rule(&hashed.rules[0], "secret"),
+            Some(masker(b"seed", "secret"))
+        );
+    }
+
+    #[test]
+    fn client_cert() {
+        let p = policy(vec![RawMaskingRule {
+            sections: vec!["client_cert".to_string()],
+            name: Some("subject".to_string()),
+            classes: vec!["email".to_string()],
+            mode: Some("full".to_string()),
+        }]);
+        let mut cert = ClientCert {
+            subject: Some("CN=bob,emailAddress=bob@example.com".to_string()),
+            san: vec!["bob@example.com".to_string()],
+            ..Default::default()
+        };
+        p.apply_client_cert(&mut cert);
+        assert_eq!(cert.subject.as_deref(), Some("CN=bob,emailAddress=MASKED"));
+        // only the subject is selected
+        assert_eq!(cert.san, vec!["bob@example.com".to_string()]);
+    }
+
+    #[test]
+    fn invalid() {
+        let resolve = |r: RawMaskingRule| {
+            MaskingPolicy::resolve(
+                &mut Logs::default(),
+                RawMaskingPolicy {
+                    rules: vec![r],
+                    ..Default::default()
+                },
+                "",
+            )
+        };
+        assert!(resolve(rule(&["unknown"], "full")).is_err());
+        assert!(resolve(rule(&[], "scramble")).is_err());
+    }
+
+    #[test]
+    fn pseudonyms() {
+        let mut logs = Logs::default();
+        let raw = RawMaskingPolicy {
+            rules: vec![rule(&["email"], "full")],
+            pseudonymize: true,
+            pseudonym_key: Some("k".to_string()),
+            ..Default::default()
+        };
+        let p = MaskingPolicy::resolve(&mut logs, raw, "seed").unwrap();
+        let masked = p.apply_rule(&p.rules[0], "bob@example.com").unwrap();
+        assert!(masked.starts_with("PSEUDO{"));
+        assert_eq!(Some(masked), p.apply_rule(&p.rules[0], "bob@example.com"));
+        assert_ne!(pseudonym(b"k", "a"), pseudonym(b"k", "b"));
+        assert_eq!(p.mask_value(b"seed", "a"), pseudonym(b"k", "a"));
+
+        let nokey = RawMaskingPolicy {
+            pseudonymize: true,
+            pseudonym_key_env: Some("CF_PSEUDONYM_KEY_UNSET_IN_TESTS".to_string()),
+            ..Default::default()
+        };
+        let p = MaskingPolicy::resolve(&mut logs, nokey, "seed").unwrap();
+        assert_eq!(p.mask_value(b"seed", "a"), masker(b"seed", "a"));
+        assert_eq!(logs.logs.len(), 1);
+    }
+}
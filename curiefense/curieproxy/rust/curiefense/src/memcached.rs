@@ -0,0 +1,250 @@
+//! A minimal memcached client, implementing the flow and limit counters with the text protocol.
+//!
+//! Memcached has no sets, lists or TTL queries, so:
+//!  * lists are replaced with counters, so flow step timeouts are not checked,
+//!  * distinct values (pairwith limits) are counted using a marker key per value,
+//!  * the expiration is set when the counter is created, so in-flight leases are not extended,
+//!  * sliding windows and token buckets can't be implemented, these limits are counted in fixed windows and an
+//!    error is logged.
+use async_std::io::BufReader;
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+use crate::config::limit::LimitAlgorithm;
+use crate::counters::CounterBackend;
+use crate::flow::{FlowCheck, FlowResult, FlowResultType};
+use crate::limit::{parse_lease, LimitCheck, LimitResult};
+use crate::logs::Logs;
+
+/// expiration times larger than this are understood by memcached as unix timestamps
+const MAX_RELATIVE_EXPTIME: u64 = 60 * 60 * 24 * 30;
+
+type MemcachedConn = BufReader<TcpStream>;
+
+lazy_static! {
+    static ref MEMCACHED_ADDR: String = format!(
+        "{}:{}",
+        std::env::var("MEMCACHED_HOST").unwrap_or_else(|_| "memcached".to_string()),
+        std::env::var("MEMCACHED_PORT").unwrap_or_else(|_| "11211".to_string())
+    );
+    /// maximum number of idle connections kept, from MEMCACHED_POOL_SIZE
+    static ref POOL_SIZE: usize = std::env::var("MEMCACHED_POOL_SIZE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(16);
+    /// idle connections, the text protocol does not allow sharing a connection between requests
+    static ref MEMCACHED_POOL: Mutex<Vec<MemcachedConn>> = Mutex::new(Vec::new());
+}
+
+/// a backend checks out a connection from the pool on its first command, and puts it back when dropped
+pub struct MemcachedBackend {
+    conn: Option<MemcachedConn>,
+}
+
+pub fn memcached_backend() -> MemcachedBackend {
+    MemcachedBackend { conn: None }
+}
+
+fn checkout() -> Option<MemcachedConn> {
+    MEMCACHED_POOL.lock().ok().and_then(|mut pool| pool.pop())
+}
+
+impl Drop for MemcachedBackend {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            if let Ok(mut pool) = MEMCACHED_POOL.lock() {
+                if pool.len() < *POOL_SIZE {
+                    pool.push(conn);
+                }
+            }
+        }
+    }
+}
+
+fn exptime(ttl: u64, now: u64) -> u64 {
+    if ttl > MAX_RELATIVE_EXPTIME {
+        now + ttl
+    } else {
+        ttl
+    }
+}
+
+async fn exchange(conn: &mut MemcachedConn, cmd: &str) -> anyhow::Result<String> {
+    conn.get_mut().write_all(cmd.as_bytes()).await?;
+    let mut line = String::new();
+    conn.read_line(&mut line).await?;
+    if line.starts_with("VALUE ") {
+        // single key get, the data line is followed by END
+        let mut data = String::new();
+        conn.read_line(&mut data).await?;
+        let mut end = String::new();
+        conn.read_line(&mut end).await?;
+        return Ok(data.trim_end().to_string());
+    }
+    let line = line.trim_end();
+    if line.is_empty() {
+        anyhow::bail!("memcached connection closed");
+    }
+    if line.ends_with("ERROR") || line.contains("ERROR ") {
+        anyhow::bail!("memcached error: {}", line);
+    }
+    Ok(line.to_string())
+}
+
+impl MemcachedBackend {
+    async fn command(&mut self, cmd: &str) -> anyhow::Result<String> {
+        let mut conn = match self.conn.take().or_else(checkout) {
+            Some(conn) => conn,
+            None => BufReader::new(TcpStream::connect(MEMCACHED_ADDR.as_str()).await?),
+        };
+        let res = exchange(&mut conn, cmd).await;
+        // on errors, the connection state is unknown, it is dropped and a new one is used for the next command
+        if res.is_ok() {
+            self.conn = Some(conn);
+        }
+        res
+    }
+
+    /// creates a key set to value, returns false if it already exists
+    async fn add(&mut self, key: &str, value: u64, ttl: u64) -> anyhow::Result<bool> {
+        let now = chrono::Utc::now().timestamp() as u64;
+        let value = value.to_string();
+        let res = self
+            .command(&format!(
+                "add {} 0 {} {}\r\n{}\r\n",
+                key,
+                exptime(ttl, now),
+                value.len(),
+                value
+            ))
+            .await?;
+        Ok(res == "STORED")
+    }
+
+    /// increments a counter, creating it with the given ttl if it does not exist
+    async fn incr(&mut self, key: &str, by: u64, ttl: u64) -> anyhow::Result<i64> {
+        // two attempts, in case the key is created or expires between the commands
+        for _ in 0..2 {
+            let res = self.command(&format!("incr {} {}\r\n", key, by)).await?;
+            if res != "NOT_FOUND" {
+                return Ok(res.parse()?);
+            }
+            if self.add(key, by, ttl).await? {
+                return Ok(by as i64);
+            }
+        }
+        anyhow::bail!("could not increment {}", key)
+    }
+
+    /// decrements a counter, memcached does not go below zero
+    async fn decr(&mut self, key: &str) -> anyhow::Result<()> {
+        self.command(&format!("decr {} 1\r\n", key)).await?;
+        Ok(())
+    }
+
+    async fn get(&mut self, key: &str) -> anyhow::Result<i64> {
+        let res = self.command(&format!("get {}\r\n", key)).await?;
+        if res == "END" {
+            Ok(0)
+        } else {
+            Ok(res.parse()?)
+        }
+    }
+}
+
+#[async_trait]
+impl CounterBackend for MemcachedBackend {
+    async fn resolve_limits(&mut self, logs: &mut Logs, checks: Vec<LimitCheck>) -> anyhow::Result<Vec<LimitResult>> {
+        let mut out = Vec::new();
+        for check in checks {
+            if let LimitAlgorithm::SlidingLog | LimitAlgorithm::SlidingCounter | LimitAlgorithm::TokenBucket { .. } =
+                check.algorithm()
+            {
+                logs.error(|| {
+                    format!(
+                        "limit {}: the {:?} algorithm is not supported by the memcached backend, counting in a fixed window",
+                        check.limit.id,
+                        check.algorithm()
+                    )
+                });
+            }
+            let timeframe = check.ttl;
+            let curcount = if check.zero_limits() {
+                1
+            } else {
+                match &check.pairwith {
+                    None => self.incr(&check.key, check.cost, timeframe).await?,
+                    Some(pv) => {
+                        let marker = format!("{}_{:X}", check.key, md5::compute(pv));
+                        if self.add(&marker, 1, timeframe).await? {
+                            self.incr(&check.key, 1, timeframe).await?
+                        } else {
+                            self.get(&check.key).await?
+                        }
+                    }
+                }
+            };
+            logs.debug(|| format!("limit {} curcount={}", check.limit.id, curcount));
+            // memcached does not report expiration times
+            out.push(check.result(curcount, -1));
+        }
+        Ok(out)
+    }
+
+    async fn resolve_flows(&mut self, _logs: &mut Logs, checks: Vec<FlowCheck>) -> anyhow::Result<Vec<FlowResult>> {
+        let mut out = Vec::new();
+        for check in checks {
+            let listlen = self.get(&check.redis_key).await? as usize;
+            let tp = if check.is_last {
+                if check.step as usize == listlen {
+                    FlowResultType::LastOk
+                } else {
+                    FlowResultType::LastBlock
+                }
+            } else {
+                if check.step as usize == listlen {
+                    self.incr(&check.redis_key, 1, check.timeframe).await?;
+                }
+                // never block if not the last step!
+                FlowResultType::NonLast
+            };
+            out.push(FlowResult {
+                tp,
+                name: check.name,
+                id: check.id,
+                tags: check.tags,
+                expired: None,
+            });
+        }
+        Ok(out)
+    }
+
+    /// memcached has no sorted sets, in-flight requests are plain counters
+    async fn release_inflight(&mut self, leases: &[String]) -> anyhow::Result<()> {
+        for lease in leases {
+            self.decr(parse_lease(lease).0).await?;
+        }
+        Ok(())
+    }
+
+    async fn counter_value(&mut self, key: &str) -> anyhow::Result<i64> {
+        self.get(key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_exptime() {
+        assert_eq!(exptime(60, 1_000_000_000), 60);
+        assert_eq!(
+            exptime(MAX_RELATIVE_EXPTIME + 1, 1_000_000_000),
+            1_000_000_000 + MAX_RELATIVE_EXPTIME + 1
+        );
+    }
+}
@@ -0,0 +1,91 @@
+//! Per-request memory accounting.
+//!
+//! The analysis steps that grow with the request content (body decoding, tagging, content filter
+//! scratch buffers) run synchronously, so the budget of the request being analyzed is kept in a thread
+//! local variable. The data structures charge it as they grow, and stop growing once it is exhausted,
+//! at which point the analysis is aborted.
+use std::cell::Cell;
+
+thread_local! {
+    static CURRENT: Cell<Option<MemoryUsage>> = const { Cell::new(None) };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub used: usize,
+    pub limit: usize,
+    pub exceeded: bool,
+}
+
+impl Default for MemoryUsage {
+    fn default() -> Self {
+        MemoryUsage::new(usize::MAX)
+    }
+}
+
+impl MemoryUsage {
+    pub fn new(limit: usize) -> Self {
+        MemoryUsage {
+            used: 0,
+            limit,
+            exceeded: false,
+        }
+    }
+
+    /// runs f with this budget active on the current thread, and accounts for what it charged
+    pub fn track<R, F: FnOnce() -> R>(&mut self, f: F) -> R {
+        let previous = CURRENT.with(|c| c.replace(Some(*self)));
+        let out = f();
+        if let Some(usage) = CURRENT.with(|c| c.replace(previous)) {
+            *self = usage;
+        }
+        out
+    }
+}
+
+/// charges the active budget, returns false when it is exhausted and the allocation should not happen
+pub fn charge(bytes: usize) -> bool {
+    CURRENT.with(|c| match c.get() {
+        None => true,
+        Some(mut usage) => {
+            if !usage.exceeded {
+                usage.used = usage.used.saturating_add(bytes);
+                usage.exceeded = usage.used > usage.limit;
+                c.set(Some(usage));
+            }
+            !usage.exceeded
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget_tracking() {
+        // no active budget
+        assert!(charge(usize::MAX));
+
+        let mut usage = MemoryUsage::new(10);
+        let inner = usage.track(|| {
+            assert!(charge(6));
+            let mut nested = MemoryUsage::new(100);
+            nested.track(|| assert!(charge(50)));
+            assert!(!charge(6));
+            // once exceeded, nothing can be charged
+            assert!(!charge(0));
+            nested
+        });
+        assert_eq!(inner.used, 50);
+        assert_eq!(
+            usage,
+            MemoryUsage {
+                used: 12,
+                limit: 10,
+                exceeded: true
+            }
+        );
+        assert!(charge(1));
+    }
+}
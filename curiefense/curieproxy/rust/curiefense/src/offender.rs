@@ -0,0 +1,206 @@
+//! Cross-request offender tracking.
+//!
+//! When enabled on a security policy entry, each blocking decision adds `block_weight` to the offender score of the
+//! IP address, and of the session when session tracking is enabled, and each monitored decision with reasons adds
+//! `suspicious_weight`. Scores are kept in redis and decay exponentially, halving every `half_life_seconds`.
+//!
+//! The score is loaded at the start of the analysis, before the session stage, and exposed as a bucket tag:
+//! `offender:low`, `offender:medium` or `offender:high`, so that ACL profiles, global filters and limits can escalate
+//! repeat attackers to challenges or blocks faster.
+use crate::analyze::APhase0;
+use crate::clientstate::{Pending, StateQuery, StateReplies};
+use crate::config::raw::RawOffenderTracking;
+use crate::failure::{dependency_failed, Dependency};
+use crate::interface::{AnalyzeResult, Location};
+use crate::logs::Logs;
+use crate::redis::{redis_async_conn, REDIS_KEY_PREFIX};
+use crate::session::session_id;
+use crate::utils::RequestInfo;
+
+/// tag of the offender score bucket of the client, qualified with low, medium or high
+pub const OFFENDER_TAG: &str = "offender";
+
+/// KEYS: score hashes, ARGV: now (s), half life (s), increment, expiration (s)
+pub const OFFENDER_SCRIPT: &str = r#"
+local now = tonumber(ARGV[1])
+for _, key in ipairs(KEYS) do
+  local state = redis.call('HMGET', key, 'score', 'ts')
+  local score = tonumber(state[1]) or 0
+  local ts = tonumber(state[2]) or now
+  score = score * 0.5 ^ (math.max(0, now - ts) / tonumber(ARGV[2])) + tonumber(ARGV[3])
+  redis.call('HSET', key, 'score', tostring(score), 'ts', now)
+  redis.call('EXPIRE', key, ARGV[4])
+end
+return 0
+"#;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OffenderSettings {
+    /// seconds
+    pub half_life: u64,
+    pub block_weight: f64,
+    pub suspicious_weight: f64,
+    /// lowest scores of the low, medium and high buckets
+    pub buckets: [f64; 3],
+}
+
+impl OffenderSettings {
+    pub fn resolve(logs: &mut Logs, raw: RawOffenderTracking) -> Self {
+        let mut buckets = [
+            raw.low.unwrap_or(1.0),
+            raw.medium.unwrap_or(3.0),
+            raw.high.unwrap_or(10.0),
+        ];
+        if !(buckets[0] > 0.0 && buckets[0] <= buckets[1] && buckets[1] <= buckets[2]) {
+            logs.error(|| format!("invalid offender buckets {:?}, using the defaults", buckets));
+            buckets = [1.0, 3.0, 10.0];
+        }
+        OffenderSettings {
+            half_life: raw.half_life_seconds.unwrap_or(3600).max(1),
+            block_weight: raw.block_weight.unwrap_or(1.0).max(0.0),
+            suspicious_weight: raw.suspicious_weight.unwrap_or(0.2).max(0.0),
+            buckets,
+        }
+    }
+
+    /// scores are forgotten once they have decayed below a thousandth of their value
+    fn ttl(&self) -> u64 {
+        self.half_life * 10
+    }
+
+    pub fn bucket(&self, score: f64) -> Option<&'static str> {
+        ["high", "medium", "low"]
+            .iter()
+            .zip(self.buckets.iter().rev())
+            .find(|(_, min)| score >= **min)
+            .map(|(name, _)| *name)
+    }
+
+    /// increment of the score for this result
+    pub fn weight(&self, result: &AnalyzeResult) -> f64 {
+        if result.decision.is_blocking() {
+            self.block_weight
+        } else if !result.decision.reasons.is_empty() {
+            self.suspicious_weight
+        } else {
+            0.0
+        }
+    }
+}
+
+/// score after `elapsed` seconds
+pub fn decayed(score: f64, elapsed: i64, half_life: u64) -> f64 {
+    score * 0.5_f64.powf(elapsed.max(0) as f64 / half_life as f64)
+}
+
+/// keys of the scores of the client, by IP and by session
+fn score_keys(reqinfo: &RequestInfo) -> Vec<String> {
+    let secpolicy = &reqinfo.rinfo.secpolicy;
+    let prefix = format!("{}{}offender_", *REDIS_KEY_PREFIX, secpolicy.counters.namespace);
+    let mut out = vec![format!("{}ip_{}", prefix, reqinfo.rinfo.geoip.ipstr)];
+    if let Some(id) = secpolicy.session_tracking.as_ref().and_then(|s| session_id(s, reqinfo)) {
+        out.push(format!("{}session_{}", prefix, id));
+    }
+    out
+}
+
+/// adds the commands that read the scores of the client
+pub fn query_offender(p0: &APhase0, query: &mut StateQuery) -> Option<Pending> {
+    p0.reqinfo.rinfo.secpolicy.offender_tracking.as_ref()?;
+    let keys = score_keys(&p0.reqinfo);
+    Some(query.add(|pipe| {
+        for key in &keys {
+            pipe.cmd("HMGET").arg(key).arg("score").arg("ts");
+        }
+        keys.len()
+    }))
+}
+
+/// tags the request with the offender bucket of the highest score of the client
+pub fn apply_offender(logs: &mut Logs, p0: &mut APhase0, pending: Pending, replies: &StateReplies) {
+    let secpolicy = p0.reqinfo.rinfo.secpolicy.clone();
+    let settings = match &secpolicy.offender_tracking {
+        None => return,
+        Some(s) => s,
+    };
+    let now = p0.reqinfo.timestamp.timestamp();
+    match replies.get::<Vec<(Option<f64>, Option<i64>)>>(pending) {
+        Ok(states) => {
+            let score = states
+                .into_iter()
+                .filter_map(|(score, ts)| Some(decayed(score?, now - ts?, settings.half_life)))
+                .fold(0.0, f64::max);
+            if let Some(bucket) = settings.bucket(score) {
+                p0.itags.insert_qualified(OFFENDER_TAG, bucket, Location::Request);
+            }
+        }
+        Err(rr) => {
+            logs.error(|| format!("offender tracking: {}", rr));
+            p0.itags.insert("offender-degraded", Location::Request);
+            dependency_failed(&mut p0.itags, Dependency::Redis);
+        }
+    }
+}
+
+/// adds the weight of the decision to the scores of the client
+pub async fn record_offender(logs: &mut Logs, result: &AnalyzeResult) {
+    let settings = match &result.rinfo.rinfo.secpolicy.offender_tracking {
+        None => return,
+        Some(s) => s,
+    };
+    let weight = settings.weight(result);
+    if weight <= 0.0 {
+        return;
+    }
+    let keys = score_keys(&result.rinfo);
+    let res: anyhow::Result<()> = async {
+        let mut conn = redis_async_conn().await?;
+        redis::cmd("EVAL")
+            .arg(OFFENDER_SCRIPT)
+            .arg(keys.len())
+            .arg(&keys)
+            .arg(result.rinfo.timestamp.timestamp())
+            .arg(settings.half_life)
+            .arg(weight)
+            .arg(settings.ttl())
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+    .await;
+    if let Err(rr) = res {
+        logs.error(|| format!("offender tracking: {}", rr));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_and_decay() {
+        let settings = OffenderSettings::resolve(
+            &mut Logs::default(),
+            RawOffenderTracking {
+                half_life_seconds: Some(60),
+                ..Default::default()
+            },
+        );
+        assert_eq!(settings.bucket(0.5), None);
+        assert_eq!(settings.bucket(1.0), Some("low"));
+        assert_eq!(settings.bucket(4.0), Some("medium"));
+        assert_eq!(settings.bucket(25.0), Some("high"));
+        assert!((decayed(8.0, 120, 60) - 2.0).abs() < 1e-9);
+        assert_eq!(decayed(8.0, -5, 60), 8.0);
+
+        let mut logs = Logs::default();
+        let invalid = OffenderSettings::resolve(
+            &mut logs,
+            RawOffenderTracking {
+                medium: Some(50.0),
+                ..Default::default()
+            },
+        );
+        assert_eq!(invalid.buckets, [1.0, 3.0, 10.0]);
+    }
+}
@@ -0,0 +1,298 @@
+//! OpenTelemetry trace propagation.
+//!
+//! The W3C trace context (traceparent and tracestate headers) of incoming requests is parsed into the
+//! request information, and attached to the logs, so that access logs can be correlated with traces.
+//!
+//! When an OTLP endpoint is configured (OTEL_EXPORTER_OTLP_TRACES_ENDPOINT, or OTEL_EXPORTER_OTLP_ENDPOINT
+//! to which /v1/traces is appended), the analysis stages of sampled requests are exported as spans, using
+//! the OTLP/HTTP JSON encoding. Spans are queued in a bounded sink, and sent in batches by a dedicated
+//! thread, so that a slow collector never delays requests.
+//!
+//! Each request gets its own span id, that is logged along with the trace id, and the analysis spans are its
+//! children.
+use lazy_static::lazy_static;
+use rand::Rng;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::logs::background_error;
+use crate::logsink::{BoundedSink, DropPolicy, LogRecord, LogSink};
+use crate::requestfields::RequestField;
+
+/// maximum number of spans sent in a single export request
+const EXPORT_BATCH: usize = 256;
+
+lazy_static! {
+    static ref EXPORTER: Option<Arc<BoundedSink>> = otlp_endpoint().map(start_exporter);
+}
+
+fn otlp_endpoint() -> Option<String> {
+    if let Ok(url) = std::env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT") {
+        return Some(url).filter(|u| !u.is_empty());
+    }
+    std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+        .ok()
+        .filter(|u| !u.is_empty())
+        .map(|u| format!("{}/v1/traces", u.trim_end_matches('/')))
+}
+
+fn start_exporter(url: String) -> Arc<BoundedSink> {
+    let capacity = std::env::var("OTEL_SPAN_QUEUE_SIZE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(4096);
+    let sink = BoundedSink::new("otlp", capacity, DropPolicy::DropOldest);
+    let consumer = sink.clone();
+    std::thread::spawn(move || loop {
+        let mut spans: Vec<Value> = Vec::new();
+        if let Some(first) = consumer.recv_timeout(Duration::from_secs(1)) {
+            spans.extend(serde_json::from_slice(&first.data).ok());
+            while spans.len() < EXPORT_BATCH {
+                match consumer.try_recv() {
+                    Some(r) => spans.extend(serde_json::from_slice(&r.data).ok()),
+                    None => break,
+                }
+            }
+        }
+        if spans.is_empty() {
+            continue;
+        }
+        let body = json!({
+            "resourceSpans": [{
+                "resource": { "attributes": [attribute("service.name", &json!("curiefense"))] },
+                "scopeSpans": [{
+                    "scope": { "name": "curiefense", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": spans,
+                }],
+            }]
+        });
+        if let Err(rr) = ureq::post(&url)
+            .timeout(Duration::from_secs(5))
+            .set("content-type", "application/json")
+            .send_string(&body.to_string())
+        {
+            background_error(|| format!("could not export spans to {}: {}", url, rr));
+        }
+    });
+    sink
+}
+
+fn attribute(key: &str, value: &Value) -> Value {
+    let v = match value {
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::Number(n) if n.is_i64() || n.is_u64() => json!({ "intValue": n.to_string() }),
+        Value::Number(n) => json!({ "doubleValue": n }),
+        Value::String(s) => json!({ "stringValue": s }),
+        other => json!({ "stringValue": other.to_string() }),
+    };
+    json!({ "key": key, "value": v })
+}
+
+fn is_lower_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c))
+}
+
+/// identifiers can't be all zeros
+fn is_hex_id(s: &str, len: usize) -> bool {
+    is_lower_hex(s, len) && s.bytes().any(|c| c != b'0')
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..bytes).map(|_| format!("{:02x}", rng.gen::<u8>())).collect()
+}
+
+/// a W3C trace context
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: String,
+    /// the span this request, or span, is a child of
+    pub parent_id: String,
+    /// the span of this request, or span
+    pub span_id: String,
+    pub sampled: bool,
+    pub tracestate: Option<String>,
+}
+
+impl TraceContext {
+    /// parses a traceparent header, returns None if it is invalid
+    pub fn parse(traceparent: &str, tracestate: Option<&str>) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let parent_id = parts.next()?;
+        let flags = parts.next()?;
+        // future versions may add fields, but the first ones keep their meaning
+        if !is_lower_hex(version, 2) || version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+        if !is_hex_id(trace_id, 32) || !is_hex_id(parent_id, 16) || !is_lower_hex(flags, 2) {
+            return None;
+        }
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        Some(TraceContext {
+            trace_id: trace_id.to_string(),
+            parent_id: parent_id.to_string(),
+            span_id: random_hex(8),
+            sampled: flags & 1 == 1,
+            tracestate: tracestate.map(|s| s.to_string()).filter(|s| !s.is_empty()),
+        })
+    }
+
+    pub fn from_headers(headers: &RequestField) -> Option<Self> {
+        TraceContext::parse(headers.get_str("traceparent")?, headers.get_str("tracestate"))
+    }
+
+    /// context of a new child span
+    pub fn child(&self) -> Self {
+        TraceContext {
+            parent_id: self.span_id.clone(),
+            span_id: random_hex(8),
+            ..self.clone()
+        }
+    }
+
+    /// the traceparent header value, for the children of this span
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            self.trace_id,
+            self.span_id,
+            if self.sampled { 1 } else { 0 }
+        )
+    }
+}
+
+struct ActiveSpan {
+    name: &'static str,
+    context: TraceContext,
+    start: SystemTime,
+    attributes: Vec<Value>,
+}
+
+/// a span, that is exported when ended, if the trace is sampled and an exporter is configured
+pub struct Span(Option<ActiveSpan>);
+
+impl Span {
+    fn new(context: Option<TraceContext>, name: &'static str, start: SystemTime) -> Self {
+        let exporting = EXPORTER.is_some();
+        Span(context.filter(|c| c.sampled && exporting).map(|context| ActiveSpan {
+            name,
+            context,
+            start,
+            attributes: Vec::new(),
+        }))
+    }
+
+    /// starts a child of the given span
+    pub fn start(parent: Option<&TraceContext>, name: &'static str) -> Self {
+        Span::new(parent.map(TraceContext::child), name, SystemTime::now())
+    }
+
+    /// the span of the request itself, that started when the request was received
+    pub fn request(context: Option<&TraceContext>, name: &'static str, start: SystemTime) -> Self {
+        Span::new(context.cloned(), name, start)
+    }
+
+    /// context of this span, to start its children
+    pub fn context(&self) -> Option<TraceContext> {
+        self.0.as_ref().map(|s| s.context.clone())
+    }
+
+    pub fn set_attribute(&mut self, key: &str, value: Value) {
+        if let Some(s) = self.0.as_mut() {
+            s.attributes.push(attribute(key, &value));
+        }
+    }
+
+    pub fn end(self) {
+        let s = match self.0 {
+            None => return,
+            Some(s) => s,
+        };
+        let nanos = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string();
+        let span = json!({
+            "traceId": s.context.trace_id,
+            "spanId": s.context.span_id,
+            "parentSpanId": s.context.parent_id,
+            "traceState": s.context.tracestate.unwrap_or_default(),
+            "name": s.name,
+            // internal
+            "kind": 1,
+            "startTimeUnixNano": nanos(s.start),
+            "endTimeUnixNano": nanos(SystemTime::now()),
+            "attributes": s.attributes,
+        });
+        if let (Some(exporter), Ok(data)) = (EXPORTER.as_ref(), serde_json::to_vec(&span)) {
+            exporter.submit(LogRecord {
+                data,
+                timestamp: chrono::Utc::now(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traceparent_parsing() {
+        let ctx = TraceContext::parse(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            Some("congo=t61rcWkgMzE"),
+        )
+        .unwrap();
+        assert_eq!(ctx.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(ctx.parent_id, "00f067aa0ba902b7");
+        assert!(is_hex_id(&ctx.span_id, 16));
+        assert_ne!(ctx.span_id, ctx.parent_id);
+        assert!(ctx.sampled);
+        assert_eq!(ctx.tracestate.as_deref(), Some("congo=t61rcWkgMzE"));
+        assert_eq!(
+            ctx.traceparent(),
+            format!("00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01", ctx.span_id)
+        );
+
+        let unsampled = TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00", None);
+        assert_eq!(unsampled.map(|c| c.sampled), Some(false));
+        // future versions can have extra fields
+        assert!(TraceContext::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-xx", None).is_some());
+
+        for invalid in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-xx",
+        ] {
+            assert_eq!(TraceContext::parse(invalid, None), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn child_spans() {
+        let ctx = TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01", None).unwrap();
+        let child = ctx.child();
+        assert_eq!(child.trace_id, ctx.trace_id);
+        assert_eq!(child.parent_id, ctx.span_id);
+        assert!(is_hex_id(&child.span_id, 16));
+        assert_ne!(child.span_id, ctx.span_id);
+        assert_ne!(ctx.child().span_id, child.span_id);
+    }
+
+    #[test]
+    fn otlp_attributes() {
+        assert_eq!(
+            attribute("a", &json!(3)),
+            json!({"key": "a", "value": {"intValue": "3"}})
+        );
+        assert_eq!(
+            attribute("b", &json!("x")),
+            json!({"key": "b", "value": {"stringValue": "x"}})
+        );
+    }
+}
@@ -0,0 +1,307 @@
+//! Emergency override list, for incident response.
+//!
+//! This is a small list of IPs, networks and fingerprints that are blocked or allowed right away,
+//! before any security policy is evaluated. It does not go through the configuration reload
+//! machinery: it is polled every few seconds from a redis key (OVERRIDE_LIST_KEY), or from a file
+//! (OVERRIDE_LIST_PATH), so that changes propagate in seconds even when full configuration pushes
+//! take minutes.
+//!
+//! The list is a JSON document:
+//! ```json
+//! {
+//!   "block": { "ips": ["1.2.3.4", "10.0.0.0/8"], "fingerprints": ["..."] },
+//!   "allow": { "ips": ["192.168.1.1"] },
+//!   "fingerprint_header": "x-ja3"
+//! }
+//! ```
+//!
+//! A missing redis key or file, or an empty document, is an empty list, so deleting the source lifts all the
+//! overrides.
+use ipnet::IpNet;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::logs::Logs;
+use crate::redis::redis_async_conn;
+use crate::utils::RawRequest;
+
+lazy_static! {
+    static ref OVERRIDE_LIST_PATH: Option<String> = std::env::var("OVERRIDE_LIST_PATH").ok();
+    static ref OVERRIDE_LIST_KEY: Option<String> = std::env::var("OVERRIDE_LIST_KEY").ok();
+    static ref OVERRIDE_POLL_PERIOD: Duration = Duration::from_secs(
+        std::env::var("OVERRIDE_POLL_SECONDS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(5)
+    );
+    static ref OVERRIDES: RwLock<Arc<OverrideList>> = RwLock::new(Arc::new(OverrideList::default()));
+}
+
+static OVERRIDE_POLLER: std::sync::Once = std::sync::Once::new();
+
+#[derive(Debug, Deserialize, Default)]
+struct RawOverrideSet {
+    #[serde(default)]
+    ips: Vec<String>,
+    #[serde(default)]
+    fingerprints: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct RawOverrideList {
+    #[serde(default)]
+    block: RawOverrideSet,
+    #[serde(default)]
+    allow: RawOverrideSet,
+    fingerprint_header: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OverrideSet {
+    networks: Vec<IpNet>,
+    fingerprints: HashSet<String>,
+}
+
+impl OverrideSet {
+    fn resolve(logs: &mut Logs, raw: RawOverrideSet) -> Self {
+        let mut networks = Vec::new();
+        for s in raw.ips {
+            let parsed = s.parse::<IpNet>().or_else(|_| s.parse::<IpAddr>().map(IpNet::from));
+            match parsed {
+                Ok(n) => networks.push(n),
+                Err(rr) => logs.error(|| format!("invalid override list entry {}: {}", s, rr)),
+            }
+        }
+        OverrideSet {
+            networks,
+            fingerprints: raw.fingerprints.into_iter().collect(),
+        }
+    }
+
+    fn matches(&self, ip: Option<IpAddr>, fingerprint: Option<&str>) -> bool {
+        ip.map(|ip| self.networks.iter().any(|n| n.contains(&ip)))
+            .unwrap_or(false)
+            || fingerprint.map(|f| self.fingerprints.contains(f)).unwrap_or(false)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.networks.is_empty() && self.fingerprints.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OverrideList {
+    block: OverrideSet,
+    allow: OverrideSet,
+    fingerprint_header: Option<String>,
+    /// errors encountered when loading the list, replayed in the request logs
+    logs: Logs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverrideVerdict {
+    Block,
+    Allow,
+}
+
+impl OverrideList {
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        if content.trim().is_empty() {
+            return Ok(OverrideList::default());
+        }
+        let raw: RawOverrideList = serde_json::from_str(content)?;
+        let mut logs = Logs::default();
+        Ok(OverrideList {
+            block: OverrideSet::resolve(&mut logs, raw.block),
+            allow: OverrideSet::resolve(&mut logs, raw.allow),
+            fingerprint_header: raw.fingerprint_header.map(|h| h.to_lowercase()),
+            logs,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.block.is_empty() && self.allow.is_empty()
+    }
+
+    /// block entries take precedence over allow entries
+    pub fn check(&self, ip: Option<IpAddr>, fingerprint: Option<&str>) -> Option<OverrideVerdict> {
+        if self.block.matches(ip, fingerprint) {
+            Some(OverrideVerdict::Block)
+        } else if self.allow.matches(ip, fingerprint) {
+            Some(OverrideVerdict::Allow)
+        } else {
+            None
+        }
+    }
+}
+
+/// the current override list
+pub fn current_overrides() -> Arc<OverrideList> {
+    start_override_poller();
+    match OVERRIDES.read() {
+        Ok(o) => o.clone(),
+        Err(_) => Arc::new(OverrideList::default()),
+    }
+}
+
+/// checks a request against the override list, with its resolved client address
+pub fn check_overrides(logs: &mut Logs, raw: &RawRequest) -> Option<OverrideVerdict> {
+    let overrides = current_overrides();
+    logs.extend(overrides.logs.clone());
+    if overrides.is_empty() {
+        return None;
+    }
+    let fingerprint = overrides.fingerprint_header.as_deref().and_then(|h| {
+        raw.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(h))
+            .map(|(_, v)| v.as_str())
+    });
+    overrides.check(raw.client_address().parse().ok(), fingerprint)
+}
+
+fn store_overrides(list: OverrideList) {
+    if let Ok(mut w) = OVERRIDES.write() {
+        *w = Arc::new(list);
+    }
+}
+
+/// keeps the current list, but reports the polling error
+fn report_poll_error(msg: String) {
+    let mut list = current_overrides().as_ref().clone();
+    list.logs = Logs::default();
+    list.logs.error(|| msg);
+    store_overrides(list);
+}
+
+fn file_mtime(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+async fn redis_overrides(key: &str) -> anyhow::Result<Option<String>> {
+    let mut conn = redis_async_conn().await?;
+    Ok(redis::cmd("GET").arg(key).query_async(&mut conn).await?)
+}
+
+/// starts the thread polling the override sources, if any is configured
+fn start_override_poller() {
+    if OVERRIDE_LIST_PATH.is_none() && OVERRIDE_LIST_KEY.is_none() {
+        return;
+    }
+    OVERRIDE_POLLER.call_once(|| {
+        std::thread::spawn(|| {
+            let mut last_mtime = None;
+            let mut last_content = None;
+            loop {
+                // the redis key takes precedence over the file, when both are set
+                let content = match (OVERRIDE_LIST_KEY.as_ref(), OVERRIDE_LIST_PATH.as_ref()) {
+                    (Some(key), _) => match async_std::task::block_on(redis_overrides(key)) {
+                        // the key was deleted
+                        Ok(c) => Some(c.unwrap_or_default()),
+                        Err(rr) => {
+                            report_poll_error(format!("could not fetch the override list: {}", rr));
+                            // reload the list on the next successful poll, to clear the error
+                            last_content = None;
+                            None
+                        }
+                    },
+                    (None, Some(path)) => {
+                        let mtime = file_mtime(path);
+                        if mtime != last_mtime {
+                            last_mtime = mtime;
+                            match std::fs::read_to_string(path) {
+                                Ok(c) => Some(c),
+                                // the file was deleted
+                                Err(rr) if rr.kind() == std::io::ErrorKind::NotFound => Some(String::new()),
+                                Err(rr) => {
+                                    report_poll_error(format!("could not read the override list: {}", rr));
+                                    last_mtime = None;
+                                    last_content = None;
+                                    None
+                                }
+                            }
+                        } else {
+                            None
+                        }
+                    }
+                    (None, None) => None,
+                };
+                if let Some(c) = content {
+                    if last_content.as_ref() != Some(&c) {
+                        match OverrideList::parse(&c) {
+                            Ok(list) => store_overrides(list),
+                            Err(rr) => report_poll_error(format!("could not parse the override list: {}", rr)),
+                        }
+                        last_content = Some(c);
+                    }
+                }
+                std::thread::sleep(*OVERRIDE_POLL_PERIOD);
+            }
+        });
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grasshopper::DummyGrasshopper;
+    use crate::inspect_generic_request_map_init;
+    use crate::testutils::raw_request;
+
+    #[test]
+    fn override_precedence() {
+        let list = OverrideList::parse(
+            r#"{"block": {"ips": ["10.0.0.0/8", "1.2.3.4", "garbage"], "fingerprints": ["bad"]},
+                "allow": {"ips": ["10.1.2.3", "5.6.7.8"]},
+                "fingerprint_header": "X-JA3"}"#,
+        )
+        .unwrap();
+        assert_eq!(list.logs.logs.len(), 1);
+        assert_eq!(list.fingerprint_header.as_deref(), Some("x-ja3"));
+        let ip = |s: &str| s.parse().ok();
+        assert_eq!(list.check(ip("1.2.3.4"), None), Some(OverrideVerdict::Block));
+        assert_eq!(list.check(ip("10.1.2.3"), None), Some(OverrideVerdict::Block));
+        assert_eq!(list.check(ip("5.6.7.8"), None), Some(OverrideVerdict::Allow));
+        assert_eq!(list.check(ip("5.6.7.8"), Some("bad")), Some(OverrideVerdict::Block));
+        assert_eq!(list.check(ip("9.9.9.9"), Some("good")), None);
+        assert_eq!(list.check(None, None), None);
+
+        // a deleted source lifts the overrides
+        assert!(OverrideList::parse("").unwrap().is_empty());
+        assert!(OverrideList::parse(" \n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn override_without_policy() {
+        // no security policy is loaded, the override list still applies
+        store_overrides(
+            OverrideList::parse(r#"{"block": {"ips": ["203.0.113.7"]}, "allow": {"ips": ["203.0.113.8"]}}"#).unwrap(),
+        );
+        let inspect = |ip: &str| {
+            let mut raw = raw_request(&[("authority", "nopolicy.example.com")], &[]);
+            raw.ipstr = ip.to_string();
+            let res = inspect_generic_request_map_init::<DummyGrasshopper>(
+                None,
+                raw,
+                &mut Logs::default(),
+                None,
+                std::collections::HashMap::new(),
+            );
+            match res {
+                Err(res) => res,
+                Ok(_) => panic!("the override list was not applied"),
+            }
+        };
+        let blocked = inspect("203.0.113.7");
+        assert!(blocked.decision.is_blocking());
+        assert!(blocked.tags.contains("override-block"));
+        let allowed = inspect("203.0.113.8");
+        assert!(!allowed.decision.is_blocking());
+        assert!(allowed.tags.contains("override-allow"));
+        store_overrides(OverrideList::default());
+    }
+}
@@ -0,0 +1,282 @@
+//! Proof-of-work challenge.
+//!
+//! A native alternative to the grasshopper challenge, for deployments without the library. The challenge page
+//! holds a puzzle made of its expiration timestamp and a random salt, signed with HMAC-SHA256 along with the
+//! client IP. The browser looks for a nonce such that the SHA-256 hash of `puzzle.nonce` starts with `difficulty`
+//! zero bits, and stores this solution in a cookie.
+//!
+//! The solution cookie is verified when computing the precision level of a request, requests holding a valid
+//! solution being considered as coming from a browser that passed an active challenge.
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+
+use crate::challenge::ChallengeProvider;
+use crate::config::raw::RawChallengeSettings;
+use crate::grasshopper::{GHMode, GHQuery, GHResponse, PrecisionLevel};
+use crate::logs::Logs;
+use crate::utils::hmac_sha256;
+
+/// difficulties above this would take browsers far too long to solve
+const MAX_DIFFICULTY: u8 = 32;
+/// longest accepted nonce, solutions are decimal counters
+const MAX_NONCE_LENGTH: usize = 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PowSettings {
+    pub secret: Vec<u8>,
+    pub difficulty: u8,
+    /// in seconds
+    pub ttl: i64,
+    pub cookie: String,
+}
+
+impl PowSettings {
+    pub fn resolve(logs: &mut Logs, raw: RawChallengeSettings) -> Option<Self> {
+        if raw.secret.is_empty() {
+            logs.error("the proof-of-work challenge requires a secret, it is disabled");
+            return None;
+        }
+        let cookie = match raw.cookie {
+            Some(c) if !c.is_empty() && c.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') => c,
+            Some(c) => {
+                logs.warning(|| format!("invalid proof-of-work cookie name {:?}, using cf_pow", c));
+                "cf_pow".to_string()
+            }
+            None => "cf_pow".to_string(),
+        };
+        Some(PowSettings {
+            secret: raw.secret.into_bytes(),
+            difficulty: raw.difficulty.unwrap_or(16).clamp(1, MAX_DIFFICULTY),
+            ttl: raw.ttl_seconds.unwrap_or(3600).clamp(1, i64::MAX as u64) as i64,
+            cookie,
+        })
+    }
+}
+
+/// a puzzle problem, logged when a solution is rejected
+pub type PowProblem = &'static str;
+
+fn sign(settings: &PowSettings, payload: &str, ip: &str) -> String {
+    hex::encode(hmac_sha256(&settings.secret, format!("{}|{}", payload, ip).as_bytes()))
+}
+
+/// mints a puzzle for the client IP, valid until now + ttl
+pub fn mint_puzzle(settings: &PowSettings, ip: &str, now: i64) -> String {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let payload = format!("{}.{}", now.saturating_add(settings.ttl), hex::encode(salt));
+    let signature = sign(settings, &payload, ip);
+    format!("{}.{}", payload, signature)
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut out = 0;
+    for b in hash {
+        out += b.leading_zeros();
+        if *b != 0 {
+            break;
+        }
+    }
+    out
+}
+
+/// checks a solution, made of a puzzle minted for this IP and a nonce
+pub fn check_solution(settings: &PowSettings, ip: &str, solution: &str, now: i64) -> Result<(), PowProblem> {
+    let (puzzle, nonce) = solution.rsplit_once('.').ok_or("malformed solution")?;
+    if nonce.is_empty() || nonce.len() > MAX_NONCE_LENGTH || !nonce.bytes().all(|b| b.is_ascii_digit()) {
+        return Err("malformed nonce");
+    }
+    let (payload, signature) = puzzle.rsplit_once('.').ok_or("malformed solution")?;
+    if !bool::from(sign(settings, payload, ip).as_bytes().ct_eq(signature.as_bytes())) {
+        return Err("invalid signature");
+    }
+    let expires: i64 = payload
+        .split('.')
+        .next()
+        .and_then(|e| e.parse().ok())
+        .ok_or("malformed solution")?;
+    if expires < now {
+        return Err("expired puzzle");
+    }
+    if leading_zero_bits(&Sha256::digest(solution.as_bytes())) < settings.difficulty as u32 {
+        return Err("insufficient work");
+    }
+    Ok(())
+}
+
+/// the challenge page, solving the puzzle in the browser before reloading the page with the solution cookie
+fn challenge_page(settings: &PowSettings, puzzle: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>Checking your browser</title></head>
+<body><p>Checking your browser...</p>
+<script>
+(async function () {{
+  const puzzle = "{puzzle}";
+  const difficulty = {difficulty};
+  const encoder = new TextEncoder();
+  function zeros(hash) {{
+    let out = 0;
+    for (const b of new Uint8Array(hash)) {{
+      out += Math.clz32(b) - 24;
+      if (b !== 0) break;
+    }}
+    return out;
+  }}
+  for (let nonce = 0; ; nonce++) {{
+    const solution = puzzle + "." + nonce;
+    const hash = await crypto.subtle.digest("SHA-256", encoder.encode(solution));
+    if (zeros(hash) >= difficulty) {{
+      document.cookie = "{cookie}=" + solution + "; path=/; max-age={ttl}; SameSite=Lax";
+      location.reload();
+      return;
+    }}
+  }}
+}})();
+</script>
+<noscript>Please enable JavaScript to continue.</noscript>
+</body></html>
+"#,
+        puzzle = puzzle,
+        difficulty = settings.difficulty,
+        cookie = settings.cookie,
+        ttl = settings.ttl
+    )
+}
+
+/// the proof-of-work challenge provider, used in place of grasshopper
+#[derive(Debug, Clone)]
+pub struct ProofOfWork {
+    pub settings: Arc<PowSettings>,
+}
+
+impl ChallengeProvider for ProofOfWork {
+    fn is_human(&self, input: GHQuery) -> Result<PrecisionLevel, String> {
+        let solution = match input.cookies.get(self.settings.cookie.as_str()) {
+            None => return Ok(PrecisionLevel::Invalid),
+            Some(s) => s,
+        };
+        let now = chrono::Utc::now().timestamp();
+        Ok(match check_solution(&self.settings, input.ip, solution, now) {
+            Ok(()) => PrecisionLevel::Active,
+            Err(_) => PrecisionLevel::Invalid,
+        })
+    }
+
+    fn init_challenge(&self, input: GHQuery, _mode: GHMode) -> Result<GHResponse, String> {
+        let puzzle = mint_puzzle(&self.settings, input.ip, chrono::Utc::now().timestamp());
+        let mut headers = HashMap::new();
+        headers.insert("content-type".to_string(), "text/html; charset=utf-8".to_string());
+        headers.insert("cache-control".to_string(), "no-store".to_string());
+        Ok(GHResponse {
+            precision_level: PrecisionLevel::Invalid,
+            str_response: challenge_page(&self.settings, &puzzle),
+            headers,
+            status_code: 247,
+        })
+    }
+
+    fn verify_challenge(&self, _headers: HashMap<&str, &str>) -> Result<String, String> {
+        Err("not supported by the proof-of-work challenge".into())
+    }
+
+    fn should_provide_app_sig(&self, _headers: HashMap<&str, &str>) -> Result<GHResponse, String> {
+        Err("not supported by the proof-of-work challenge".into())
+    }
+
+    fn handle_bio_report(&self, _input: GHQuery, _precision_level: PrecisionLevel) -> Result<GHResponse, String> {
+        Err("not supported by the proof-of-work challenge".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> PowSettings {
+        PowSettings::resolve(
+            &mut Logs::default(),
+            RawChallengeSettings {
+                provider: "pow".to_string(),
+                secret: "s3cr3t".to_string(),
+                difficulty: Some(8),
+                ttl_seconds: Some(60),
+                cookie: None,
+                url: None,
+                timeout_ms: None,
+                clearance: None,
+            },
+        )
+        .unwrap()
+    }
+
+    fn solve(settings: &PowSettings, puzzle: &str) -> String {
+        (0u64..)
+            .map(|nonce| format!("{}.{}", puzzle, nonce))
+            .find(|s| leading_zero_bits(&Sha256::digest(s.as_bytes())) >= settings.difficulty as u32)
+            .unwrap()
+    }
+
+    #[test]
+    fn zero_bits() {
+        assert_eq!(leading_zero_bits(&[0, 0, 0x10, 0xff]), 19);
+        assert_eq!(leading_zero_bits(&[0x80, 0]), 0);
+        assert_eq!(leading_zero_bits(&[0, 0]), 16);
+    }
+
+    #[test]
+    fn solutions() {
+        let settings = settings();
+        assert_eq!(settings.cookie, "cf_pow");
+        let puzzle = mint_puzzle(&settings, "1.2.3.4", 1000);
+        let solution = solve(&settings, &puzzle);
+        assert_eq!(check_solution(&settings, "1.2.3.4", &solution, 1000), Ok(()));
+        assert_eq!(
+            check_solution(&settings, "5.6.7.8", &solution, 1000),
+            Err("invalid signature")
+        );
+        assert_eq!(
+            check_solution(&settings, "1.2.3.4", &solution, 1061),
+            Err("expired puzzle")
+        );
+        assert_eq!(
+            check_solution(&settings, "1.2.3.4", &puzzle, 1000),
+            Err("malformed nonce")
+        );
+        // the solution is only accepted when the work was done
+        let unsolved = (0u64..)
+            .map(|nonce| format!("{}.{}", puzzle, nonce))
+            .find(|s| leading_zero_bits(&Sha256::digest(s.as_bytes())) < settings.difficulty as u32)
+            .unwrap();
+        assert_eq!(
+            check_solution(&settings, "1.2.3.4", &unsolved, 1000),
+            Err("insufficient work")
+        );
+    }
+
+    fn query<'t>(cookies: HashMap<&'t str, &'t str>) -> GHQuery<'t> {
+        GHQuery {
+            headers: HashMap::new(),
+            cookies,
+            ip: "1.2.3.4",
+            protocol: "https",
+        }
+    }
+
+    #[test]
+    fn provider() {
+        let pow = ProofOfWork {
+            settings: Arc::new(settings()),
+        };
+        let page = pow.init_challenge(query(HashMap::new()), GHMode::Active).unwrap();
+        assert!(page.str_response.contains("crypto.subtle.digest"));
+        assert_eq!(pow.is_human(query(HashMap::new())), Ok(PrecisionLevel::Invalid));
+        let puzzle = mint_puzzle(&pow.settings, "1.2.3.4", chrono::Utc::now().timestamp());
+        let solution = solve(&pow.settings, &puzzle);
+        let cookies = std::iter::once(("cf_pow", solution.as_str())).collect();
+        assert_eq!(pow.is_human(query(cookies)), Ok(PrecisionLevel::Active));
+    }
+}
@@ -0,0 +1,450 @@
+//! Protocol anomaly checks.
+//!
+//! These checks run before the ACL, and look for requests that are not valid HTTP, or that could be
+//! understood differently by the proxy and the upstream server: duplicate Content-Length headers,
+//! Transfer-Encoding headers that conflict with the Content-Length or are obfuscated, invalid characters in
+//! the request line, NUL bytes in arguments and overlong paths.
+//!
+//! HTTP/2 and HTTP/3 requests, as reported in the protocol attribute, are also checked for invalid pseudo headers
+//! and connection specific headers, rapid reset abuse (the stream rate and reset count of the connection are passed
+//! by the proxy in the stream-rate and stream-resets attributes), and header compression bombs: header lists that
+//! are too large once decoded, or too large compared to their compressed size (header-wire-size attribute).
+//!
+//! Each check can be disabled. Anomalies are tagged, and only block the request when the settings are
+//! enforced.
+use crate::config::raw::RawProtocolSettings;
+use crate::interface::Location;
+use crate::utils::RequestInfo;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtocolSettings {
+    pub duplicate_content_length: bool,
+    pub transfer_encoding: bool,
+    pub request_line: bool,
+    pub nul_bytes: bool,
+    pub max_path_length: Option<usize>,
+    pub pseudo_headers: bool,
+    pub max_stream_rate: Option<f64>,
+    pub max_stream_resets: Option<u64>,
+    pub max_header_list_size: Option<usize>,
+    pub max_header_compression_ratio: Option<f64>,
+    pub enforce: bool,
+}
+
+impl ProtocolSettings {
+    pub fn resolve(raw: RawProtocolSettings) -> Self {
+        ProtocolSettings {
+            duplicate_content_length: raw.duplicate_content_length,
+            transfer_encoding: raw.transfer_encoding,
+            request_line: raw.request_line,
+            nul_bytes: raw.nul_bytes,
+            max_path_length: raw.max_path_length.filter(|l| *l > 0),
+            pseudo_headers: raw.pseudo_headers,
+            max_stream_rate: raw.max_stream_rate.filter(|r| *r > 0.0),
+            max_stream_resets: raw.max_stream_resets,
+            max_header_list_size: raw.max_header_list_size.filter(|s| *s > 0),
+            max_header_compression_ratio: raw.max_header_compression_ratio.filter(|r| *r > 0.0),
+            enforce: raw.enforce,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolAnomaly {
+    /// name of the check, used in the tags and block reasons
+    pub check: &'static str,
+    pub expected: &'static str,
+    pub actual: String,
+    pub location: Location,
+}
+
+impl ProtocolAnomaly {
+    fn new(check: &'static str, expected: &'static str, actual: String, location: Location) -> Self {
+        ProtocolAnomaly {
+            check,
+            expected,
+            actual,
+            location,
+        }
+    }
+}
+
+/// HTTP/2 and HTTP/3 requests have pseudo headers and compressed header lists
+fn is_multiplexed(rinfo: &RequestInfo) -> bool {
+    rinfo
+        .rinfo
+        .meta
+        .protocol
+        .as_deref()
+        .map(|p| {
+            let p = p.to_ascii_uppercase();
+            p.starts_with("HTTP/2") || p.starts_with("HTTP/3") || p == "H2" || p == "H3"
+        })
+        .unwrap_or(false)
+}
+
+/// a numeric attribute passed by the proxy
+fn attribute<T: std::str::FromStr>(rinfo: &RequestInfo, name: &str) -> Option<T> {
+    rinfo.rinfo.meta.extra.get(name).and_then(|v| v.trim().parse().ok())
+}
+
+/// RFC 9110 token characters, used in methods and header names
+fn is_tchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+fn check_content_length(rinfo: &RequestInfo, out: &mut Vec<ProtocolAnomaly>) {
+    if let Some(cl) = rinfo.headers.get_str("content-length") {
+        // repeated headers are joined by the proxies
+        let values: Vec<&str> = cl
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|v| !v.is_empty())
+            .collect();
+        if values.len() > 1 {
+            out.push(ProtocolAnomaly::new(
+                "duplicate-content-length",
+                "a single content-length",
+                cl.to_string(),
+                Location::Header("content-length".to_string()),
+            ));
+        } else if !values.iter().all(|v| v.chars().all(|c| c.is_ascii_digit())) {
+            out.push(ProtocolAnomaly::new(
+                "invalid-content-length",
+                "a decimal content-length",
+                cl.to_string(),
+                Location::Header("content-length".to_string()),
+            ));
+        }
+    }
+}
+
+fn check_transfer_encoding(rinfo: &RequestInfo, out: &mut Vec<ProtocolAnomaly>) {
+    let te = match rinfo.headers.get_str("transfer-encoding") {
+        None => return,
+        Some(te) => te,
+    };
+    let location = Location::Header("transfer-encoding".to_string());
+    if rinfo.headers.get_str("content-length").is_some() {
+        out.push(ProtocolAnomaly::new(
+            "conflicting-transfer-encoding",
+            "either content-length or transfer-encoding",
+            te.to_string(),
+            location.clone(),
+        ));
+    }
+    // the last coding must be chunked, and codings must be plain tokens
+    let codings: Vec<&str> = te.split(',').map(|c| c.trim()).collect();
+    let obfuscated = codings.iter().any(|c| c.is_empty() || !c.chars().all(is_tchar))
+        || !codings
+            .last()
+            .map(|c| c.eq_ignore_ascii_case("chunked"))
+            .unwrap_or(false);
+    if obfuscated {
+        out.push(ProtocolAnomaly::new(
+            "invalid-transfer-encoding",
+            "a transfer-encoding ending with chunked",
+            te.to_string(),
+            location,
+        ));
+    }
+}
+
+fn check_request_line(rinfo: &RequestInfo, out: &mut Vec<ProtocolAnomaly>) {
+    let method = &rinfo.rinfo.meta.method;
+    if method.is_empty() || !method.chars().all(is_tchar) {
+        out.push(ProtocolAnomaly::new(
+            "invalid-method",
+            "a token",
+            method.to_string(),
+            Location::Request,
+        ));
+    }
+    let path = &rinfo.rinfo.meta.path;
+    if path.chars().any(|c| c.is_ascii_control() || c.is_whitespace()) {
+        out.push(ProtocolAnomaly::new(
+            "invalid-path",
+            "no control or whitespace characters",
+            path.escape_default().to_string(),
+            Location::Uri,
+        ));
+    }
+    for (name, _) in rinfo.headers.iter() {
+        // decoded copies and pseudo headers are added by the proxy and the decoding chain
+        if name.ends_with(":decoded") || name.starts_with(':') {
+            continue;
+        }
+        if name.is_empty() || !name.chars().all(is_tchar) {
+            out.push(ProtocolAnomaly::new(
+                "invalid-header-name",
+                "a token",
+                name.escape_default().to_string(),
+                Location::Header(name.to_string()),
+            ));
+        }
+    }
+}
+
+fn check_nul_bytes(rinfo: &RequestInfo, out: &mut Vec<ProtocolAnomaly>) {
+    for (name, value, locations) in rinfo.rinfo.qinfo.args.iter_locations() {
+        if name.contains('\0') || value.contains('\0') {
+            let location = locations
+                .iter()
+                .next()
+                .cloned()
+                .unwrap_or_else(|| Location::UriArgument(name.to_string()));
+            out.push(ProtocolAnomaly::new(
+                "nul-byte",
+                "no NUL bytes",
+                name.escape_default().to_string(),
+                location,
+            ));
+        }
+    }
+}
+
+const PSEUDO_HEADERS: [&str; 5] = [":method", ":scheme", ":authority", ":path", ":protocol"];
+
+/// headers that are forbidden in HTTP/2 and HTTP/3, RFC 9113 section 8.2.2
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+fn check_pseudo_headers(rinfo: &RequestInfo, out: &mut Vec<ProtocolAnomaly>) {
+    for (name, value) in rinfo.headers.iter() {
+        if name.starts_with(':') {
+            if !PSEUDO_HEADERS.contains(&name) {
+                out.push(ProtocolAnomaly::new(
+                    "invalid-pseudo-header",
+                    "a request pseudo header",
+                    name.to_string(),
+                    Location::Header(name.to_string()),
+                ));
+            } else if name == ":path" && !(value.starts_with('/') || value == "*") {
+                out.push(ProtocolAnomaly::new(
+                    "invalid-pseudo-header",
+                    "an absolute path",
+                    value.escape_default().to_string(),
+                    Location::Header(name.to_string()),
+                ));
+            }
+        } else if CONNECTION_HEADERS.contains(&name) || (name == "te" && !value.eq_ignore_ascii_case("trailers")) {
+            out.push(ProtocolAnomaly::new(
+                "connection-specific-header",
+                "no connection specific headers",
+                name.to_string(),
+                Location::Header(name.to_string()),
+            ));
+        }
+    }
+}
+
+fn check_streams(settings: &ProtocolSettings, rinfo: &RequestInfo, out: &mut Vec<ProtocolAnomaly>) {
+    if let (Some(max), Some(rate)) = (settings.max_stream_rate, attribute::<f64>(rinfo, "stream-rate")) {
+        if rate > max {
+            out.push(ProtocolAnomaly::new(
+                "stream-rate",
+                "a lower stream rate",
+                rate.to_string(),
+                Location::Request,
+            ));
+        }
+    }
+    if let (Some(max), Some(resets)) = (settings.max_stream_resets, attribute::<u64>(rinfo, "stream-resets")) {
+        if resets > max {
+            out.push(ProtocolAnomaly::new(
+                "rapid-reset",
+                "fewer stream resets",
+                resets.to_string(),
+                Location::Request,
+            ));
+        }
+    }
+}
+
+fn check_header_compression(settings: &ProtocolSettings, rinfo: &RequestInfo, out: &mut Vec<ProtocolAnomaly>) {
+    // RFC 9113 section 6.5.2, each header costs 32 bytes on top of its name and value
+    let decoded: usize = rinfo
+        .headers
+        .iter()
+        .filter(|(name, _)| !name.ends_with(":decoded"))
+        .map(|(name, value)| name.len() + value.len() + 32)
+        .sum();
+    if let Some(max) = settings.max_header_list_size {
+        if decoded > max {
+            out.push(ProtocolAnomaly::new(
+                "header-list-size",
+                "a smaller header list",
+                decoded.to_string(),
+                Location::Headers,
+            ));
+        }
+    }
+    if let (Some(max), Some(wire)) = (
+        settings.max_header_compression_ratio,
+        attribute::<usize>(rinfo, "header-wire-size"),
+    ) {
+        let ratio = decoded as f64 / wire.max(1) as f64;
+        if ratio > max {
+            out.push(ProtocolAnomaly::new(
+                "header-compression-bomb",
+                "a lower header compression ratio",
+                format!("{:.1}", ratio),
+                Location::Headers,
+            ));
+        }
+    }
+}
+
+/// runs the enabled checks, returning all the anomalies that were found
+pub fn check_protocol(settings: &ProtocolSettings, rinfo: &RequestInfo) -> Vec<ProtocolAnomaly> {
+    let mut out = Vec::new();
+    if settings.duplicate_content_length {
+        check_content_length(rinfo, &mut out);
+    }
+    if settings.transfer_encoding {
+        check_transfer_encoding(rinfo, &mut out);
+    }
+    if settings.request_line {
+        check_request_line(rinfo, &mut out);
+    }
+    if settings.nul_bytes {
+        check_nul_bytes(rinfo, &mut out);
+    }
+    if let Some(max) = settings.max_path_length {
+        let len = rinfo.rinfo.qinfo.qpath.len();
+        if len > max {
+            out.push(ProtocolAnomaly::new(
+                "overlong-path",
+                "a shorter path",
+                len.to_string(),
+                Location::Uri,
+            ));
+        }
+    }
+    if is_multiplexed(rinfo) {
+        if settings.pseudo_headers {
+            check_pseudo_headers(rinfo, &mut out);
+        }
+        check_streams(settings, rinfo, &mut out);
+        check_header_compression(settings, rinfo, &mut out);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::hostmap::SecurityPolicy;
+    use crate::testutils::{map_raw, raw_request};
+
+    fn settings() -> ProtocolSettings {
+        ProtocolSettings {
+            duplicate_content_length: true,
+            transfer_encoding: true,
+            request_line: true,
+            nul_bytes: true,
+            max_path_length: Some(32),
+            pseudo_headers: true,
+            max_stream_rate: Some(100.0),
+            max_stream_resets: Some(50),
+            max_header_list_size: Some(4096),
+            max_header_compression_ratio: Some(20.0),
+            enforce: true,
+        }
+    }
+
+    fn anomalies(settings: &ProtocolSettings, method: &str, path: &str, headers: &[(&str, &str)]) -> Vec<&'static str> {
+        anomalies_with(settings, None, &[], method, path, headers)
+    }
+
+    fn anomalies_with(
+        settings: &ProtocolSettings,
+        protocol: Option<&str>,
+        attrs: &[(&str, &str)],
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> Vec<&'static str> {
+        let mut attrs = attrs.to_vec();
+        attrs.extend([("authority", "example.com"), ("method", method), ("path", path)]);
+        attrs.extend(protocol.map(|p| ("protocol", p)));
+        let rinfo = map_raw(SecurityPolicy::empty(), &raw_request(&attrs, headers));
+        check_protocol(settings, &rinfo).into_iter().map(|a| a.check).collect()
+    }
+
+    #[test]
+    fn valid_request() {
+        assert!(anomalies(&settings(), "POST", "/a?b=c", &[("content-length", "12")]).is_empty());
+        assert!(anomalies(&settings(), "POST", "/a", &[("transfer-encoding", "gzip, chunked")]).is_empty());
+    }
+
+    #[test]
+    fn smuggling() {
+        assert_eq!(
+            anomalies(&settings(), "POST", "/", &[("content-length", "12, 14")]),
+            vec!["duplicate-content-length"]
+        );
+        assert_eq!(
+            anomalies(
+                &settings(),
+                "POST",
+                "/",
+                &[("content-length", "12"), ("transfer-encoding", "chunked")]
+            ),
+            vec!["conflicting-transfer-encoding"]
+        );
+        assert_eq!(
+            anomalies(&settings(), "POST", "/", &[("transfer-encoding", "chunked, identity")]),
+            vec!["invalid-transfer-encoding"]
+        );
+    }
+
+    #[test]
+    fn request_line() {
+        assert_eq!(anomalies(&settings(), "GE T", "/", &[]), vec!["invalid-method"]);
+        assert_eq!(anomalies(&settings(), "GET", "/a\tb", &[]), vec!["invalid-path"]);
+        assert_eq!(anomalies(&settings(), "GET", "/a?b=%00", &[]), vec!["nul-byte"]);
+        assert_eq!(
+            anomalies(&settings(), "GET", "/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", &[]),
+            vec!["overlong-path"]
+        );
+        let mut disabled = settings();
+        disabled.request_line = false;
+        disabled.max_path_length = None;
+        assert!(anomalies(&disabled, "GE T", "/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", &[]).is_empty());
+    }
+
+    #[test]
+    fn multiplexed() {
+        let h2 = |attrs: &[(&str, &str)], headers: &[(&str, &str)]| {
+            anomalies_with(&settings(), Some("HTTP/2"), attrs, "GET", "/", headers)
+        };
+        assert!(h2(&[], &[(":path", "/"), (":method", "GET"), ("te", "trailers")]).is_empty());
+        assert_eq!(h2(&[], &[(":status", "200")]), vec!["invalid-pseudo-header"]);
+        assert_eq!(h2(&[], &[(":path", "x")]), vec!["invalid-pseudo-header"]);
+        assert_eq!(h2(&[], &[("connection", "close")]), vec!["connection-specific-header"]);
+        assert_eq!(h2(&[("stream-resets", "500")], &[]), vec!["rapid-reset"]);
+        assert_eq!(h2(&[("stream-rate", "1000")], &[]), vec!["stream-rate"]);
+        let big = "a".repeat(5000);
+        assert_eq!(h2(&[], &[("x-big", &big)]), vec!["header-list-size"]);
+        let medium = "a".repeat(2000);
+        assert_eq!(
+            h2(&[("header-wire-size", "50")], &[("x-medium", &medium)]),
+            vec!["header-compression-bomb"]
+        );
+        // HTTP/1 requests are not checked
+        let h1 = anomalies_with(
+            &settings(),
+            Some("HTTP/1.1"),
+            &[],
+            "GET",
+            "/",
+            &[("connection", "close")],
+        );
+        assert!(h1.is_empty());
+    }
+}
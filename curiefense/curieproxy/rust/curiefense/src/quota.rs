@@ -0,0 +1,74 @@
+//! Usage reporting of quotas.
+//!
+//! Quotas are limits using the `daily-quota` or `monthly-quota` algorithm, whose counters are aligned on UTC calendar
+//! periods. Their usage can be queried, for billing or reporting, with the counter namespace of the security policy
+//! and the values of the limit key selectors, concatenated in the order they are configured (usually the API key).
+//! Counters are kept for QUOTA_RETENTION seconds after the end of their period, so that the previous period can
+//! still be reported.
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+
+use crate::config::limit::LimitAlgorithm;
+use crate::config::CONFIGS;
+use crate::counters::counter_backend;
+use crate::limit::{counter_key, quota_key};
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub limit_id: String,
+    /// the day or month, such as 2024-11
+    pub period: String,
+    pub used: u64,
+    /// highest threshold of the limit
+    pub limit: u64,
+    pub remaining: u64,
+    /// seconds until the end of the period, 0 for past periods
+    pub reset: u64,
+}
+
+/// usage of a quota during the period containing `at`, the current one by default
+pub async fn quota_usage(
+    namespace: &str,
+    limit_id: &str,
+    key: &str,
+    at: Option<DateTime<Utc>>,
+) -> anyhow::Result<QuotaUsage> {
+    let limit = match CONFIGS.config.load().limits.get(limit_id) {
+        None => anyhow::bail!("unknown limit {}", limit_id),
+        Some(l) => l.clone(),
+    };
+    let period = match limit.algorithm {
+        LimitAlgorithm::Quota(period) => period,
+        other => anyhow::bail!("limit {} is not a quota, but uses the {:?} algorithm", limit_id, other),
+    };
+    let namespace = if namespace.is_empty() {
+        String::new()
+    } else {
+        format!("{}_", namespace)
+    };
+    let now = Utc::now();
+    let at = at.unwrap_or(now);
+    let ckey = quota_key(&counter_key(&namespace, limit_id, key), period, at);
+    let used = counter_backend().await?.counter_value(&ckey).await?.max(0) as u64;
+    let highest = limit.thresholds.iter().map(|t| t.limit).max().unwrap_or(0);
+    Ok(QuotaUsage {
+        limit_id: limit.id,
+        period: period.label(at),
+        used,
+        limit: highest,
+        remaining: highest.saturating_sub(used),
+        reset: (period.end(at) - now).num_seconds().max(0) as u64,
+    })
+}
+
+/// same as quota_usage, the date being a unix timestamp
+pub fn quota_usage_block(namespace: &str, limit_id: &str, key: &str, at: Option<i64>) -> anyhow::Result<QuotaUsage> {
+    let at = match at {
+        None => None,
+        Some(ts) => match Utc.timestamp_opt(ts, 0).single() {
+            None => anyhow::bail!("invalid timestamp {}", ts),
+            d => d,
+        },
+    };
+    async_std::task::block_on(quota_usage(namespace, limit_id, key, at))
+}
@@ -1,8 +1,30 @@
+use futures::future::{join_all, FutureExt};
 use lazy_static::lazy_static;
-use redis::{ConnectionAddr, ConnectionInfo, RedisConnectionInfo};
+use redis::aio::{ConnectionLike, ConnectionManager};
+use redis::{
+    Arg, Cmd, ConnectionAddr, ConnectionInfo, ErrorKind, Pipeline, RedisConnectionInfo, RedisError, RedisFuture,
+    RedisResult, Value,
+};
+use serde::Serialize;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::localstore::{LocalStore, LOCAL_STORE};
 
 lazy_static! {
-    static ref RPOOL: anyhow::Result<redis::aio::ConnectionManager> = async_std::task::block_on(build_pool());
+    static ref HEALTH: Mutex<RedisHealth> = Mutex::new(RedisHealth {
+        breaker: Breaker::new(BreakerSettings::from_env()),
+        pool: Vec::new(),
+        next: 0,
+    });
+    /// number of connections to the redis server(s), from REDIS_POOL_SIZE
+    static ref POOL_SIZE: usize = std::env::var("REDIS_POOL_SIZE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1usize)
+        .max(1);
+    /// held while connecting, so that a single connection is attempted at a time
+    static ref CONNECTING: async_std::sync::Mutex<()> = async_std::sync::Mutex::new(());
     pub static ref REDIS_KEY_PREFIX: String = std::env::var("REDIS_KEY_PREFIX")
         .map(|mut prefix| {
             prefix.push('_');
@@ -11,29 +33,1070 @@ lazy_static! {
         .unwrap_or_default();
 }
 
-/// creates an async connection to a redis server
-pub async fn build_pool() -> anyhow::Result<redis::aio::ConnectionManager> {
-    let server = std::env::var("REDIS_HOST").unwrap_or_else(|_| "redis".to_string());
-    let port = std::env::var("REDIS_PORT").unwrap_or_else(|_| "6379".to_string());
-    let db = std::env::var("REDIS_DB").unwrap_or_else(|_| "0".to_string());
+const CLUSTER_SLOTS: u16 = 16384;
+
+/// redirections followed for a single command, before giving up
+const MAX_REDIRECTIONS: usize = 5;
+
+/// host and port of a redis node
+type NodeAddr = (String, u16);
+
+/// how the redis server(s) are reached
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RedisMode {
+    /// a single redis server
+    Single { host: String, port: u16, db: i64 },
+    /// a redis cluster, the nodes are only used to discover the cluster topology
+    Cluster { nodes: Vec<NodeAddr> },
+    /// a master that is monitored by a set of sentinels
+    Sentinel {
+        sentinels: Vec<NodeAddr>,
+        master: String,
+        db: i64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedisSettings {
+    pub mode: RedisMode,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+fn parse_hosts(hosts: &str, default_port: u16) -> anyhow::Result<Vec<NodeAddr>> {
+    let mut out = Vec::new();
+    for h in hosts.split(',').filter(|h| !h.is_empty()) {
+        match h.rsplit_once(':') {
+            Some((host, port)) => out.push((host.to_string(), port.parse()?)),
+            None => out.push((h.to_string(), default_port)),
+        }
+    }
+    if out.is_empty() {
+        anyhow::bail!("no host in redis url");
+    }
+    Ok(out)
+}
+
+/// parses redis urls, with support for the following schemes:
+///
+///  * redis://[user:password@]host[:port][/db]
+///  * redis+cluster://[user:password@]host1[:port1],host2[:port2]
+///  * redis+sentinel://[user:password@]host1[:port1],host2[:port2]/master[/db]
+pub fn parse_redis_url(url: &str) -> anyhow::Result<RedisSettings> {
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| anyhow::anyhow!("invalid redis url {}", url))?;
+    let (creds, rest) = match rest.rsplit_once('@') {
+        Some((c, r)) => (Some(c), r),
+        None => (None, rest),
+    };
+    let (username, password) = match creds.map(|c| c.split_once(':')) {
+        None => (None, None),
+        Some(None) => (None, creds.map(|s| s.to_string())),
+        Some(Some((u, p))) => (
+            Some(u.to_string()).filter(|s| !s.is_empty()),
+            Some(p.to_string()).filter(|s| !s.is_empty()),
+        ),
+    };
+    let (hosts, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let parse_db = |s: &str| -> anyhow::Result<i64> {
+        if s.is_empty() {
+            Ok(0)
+        } else {
+            Ok(s.parse()?)
+        }
+    };
+    let mode = match scheme {
+        "redis" => {
+            let mut hs = parse_hosts(hosts, 6379)?;
+            if hs.len() != 1 {
+                anyhow::bail!("a single host is expected in {}", url);
+            }
+            let (host, port) = hs.remove(0);
+            RedisMode::Single {
+                host,
+                port,
+                db: parse_db(path)?,
+            }
+        }
+        "redis+cluster" => RedisMode::Cluster {
+            nodes: parse_hosts(hosts, 6379)?,
+        },
+        "redis+sentinel" => {
+            let (master, db) = path.split_once('/').unwrap_or((path, ""));
+            if master.is_empty() {
+                anyhow::bail!("missing master name in {}", url);
+            }
+            RedisMode::Sentinel {
+                sentinels: parse_hosts(hosts, 26379)?,
+                master: master.to_string(),
+                db: parse_db(db)?,
+            }
+        }
+        _ => anyhow::bail!("unsupported redis url scheme {}", scheme),
+    };
+    Ok(RedisSettings {
+        mode,
+        username,
+        password,
+    })
+}
+
+/// reads the redis settings from the environment
+///
+/// REDIS_URL takes precedence over the REDIS_HOST, REDIS_PORT and REDIS_DB variables
+pub fn redis_settings() -> anyhow::Result<RedisSettings> {
     let username = std::env::var("REDIS_USERNAME").ok();
     let password = std::env::var("REDIS_PASSWORD").ok();
-    let addr = ConnectionAddr::Tcp(server, port.parse()?);
-    let redis = RedisConnectionInfo {
-        db: db.parse()?,
+    if let Ok(url) = std::env::var("REDIS_URL") {
+        let mut settings = parse_redis_url(&url)?;
+        settings.username = settings.username.or(username);
+        settings.password = settings.password.or(password);
+        return Ok(settings);
+    }
+    let host = std::env::var("REDIS_HOST").unwrap_or_else(|_| "redis".to_string());
+    let port = std::env::var("REDIS_PORT").unwrap_or_else(|_| "6379".to_string());
+    let db = std::env::var("REDIS_DB").unwrap_or_else(|_| "0".to_string());
+    Ok(RedisSettings {
+        mode: RedisMode::Single {
+            host,
+            port: port.parse()?,
+            db: db.parse()?,
+        },
         username,
         password,
+    })
+}
+
+fn client(settings: &RedisSettings, host: &str, port: u16, db: i64) -> RedisResult<redis::Client> {
+    let addr = ConnectionAddr::Tcp(host.to_string(), port);
+    let redis = RedisConnectionInfo {
+        db,
+        username: settings.username.clone(),
+        password: settings.password.clone(),
     };
-    let cinfo = ConnectionInfo { addr, redis };
-    let client = redis::Client::open(cinfo)?;
-    let o = redis::aio::ConnectionManager::new(client).await?;
-    Ok(o)
+    redis::Client::open(ConnectionInfo { addr, redis })
+}
+
+async fn connect(settings: &RedisSettings, host: &str, port: u16, db: i64) -> RedisResult<ConnectionManager> {
+    ConnectionManager::new(client(settings, host, port, db)?).await
+}
+
+/// asks the sentinels for the address of the current master
+async fn sentinel_master(sentinels: &[NodeAddr], master: &str) -> anyhow::Result<(String, u16)> {
+    let mut last_error = anyhow::anyhow!("no sentinel could be reached");
+    for (host, port) in sentinels {
+        let info = ConnectionInfo {
+            addr: ConnectionAddr::Tcp(host.clone(), *port),
+            redis: RedisConnectionInfo::default(),
+        };
+        let res: anyhow::Result<Option<(String, u16)>> = async {
+            let mut con = redis::Client::open(info)?.get_async_connection().await?;
+            Ok(redis::cmd("SENTINEL")
+                .arg("get-master-addr-by-name")
+                .arg(master)
+                .query_async(&mut con)
+                .await?)
+        }
+        .await;
+        match res {
+            Ok(Some(addr)) => return Ok(addr),
+            Ok(None) => last_error = anyhow::anyhow!("sentinel {}:{} does not know master {}", host, port, master),
+            Err(rr) => last_error = rr,
+        }
+    }
+    Err(last_error)
+}
+
+/// parses the output of the CLUSTER SLOTS command, returning (start, end, master address) triples
+fn parse_cluster_slots(v: Value) -> anyhow::Result<Vec<(u16, u16, NodeAddr)>> {
+    let entries = match v {
+        Value::Bulk(b) => b,
+        _ => anyhow::bail!("unexpected CLUSTER SLOTS answer {:?}", v),
+    };
+    let mut out = Vec::new();
+    for entry in entries {
+        let (start, end, master): (u16, u16, Vec<Value>) = match entry {
+            Value::Bulk(items) if items.len() >= 3 => {
+                let mut it = items.into_iter();
+                let start = redis::from_redis_value(&it.next().unwrap_or(Value::Nil))?;
+                let end = redis::from_redis_value(&it.next().unwrap_or(Value::Nil))?;
+                let master = redis::from_redis_value(&it.next().unwrap_or(Value::Nil))?;
+                (start, end, master)
+            }
+            e => anyhow::bail!("unexpected CLUSTER SLOTS entry {:?}", e),
+        };
+        if master.len() < 2 {
+            anyhow::bail!("unexpected CLUSTER SLOTS node {:?}", master);
+        }
+        let host: String = redis::from_redis_value(&master[0])?;
+        let port: u16 = redis::from_redis_value(&master[1])?;
+        out.push((start, end, (host, port)));
+    }
+    out.sort_by_key(|(start, _, _)| *start);
+    Ok(out)
+}
+
+/// fetches the cluster topology from the first node that answers, reusing the connections of the `known` nodes
+async fn cluster_topology(settings: &RedisSettings, seeds: &[NodeAddr], known: &Topology) -> anyhow::Result<Topology> {
+    let mut mslots = None;
+    for (host, port) in seeds {
+        let res: anyhow::Result<Value> = async {
+            let mut con = client(settings, host, *port, 0)?.get_async_connection().await?;
+            Ok(redis::cmd("CLUSTER").arg("SLOTS").query_async(&mut con).await?)
+        }
+        .await;
+        if let Ok(v) = res {
+            mslots = Some(parse_cluster_slots(v)?);
+            break;
+        }
+    }
+    let slots = mslots.ok_or_else(|| anyhow::anyhow!("could not get the cluster topology from any node"))?;
+
+    let mut addresses: Vec<NodeAddr> = Vec::new();
+    let mut ranges = Vec::new();
+    for (start, end, addr) in slots {
+        let idx = match addresses.iter().position(|a| a == &addr) {
+            Some(i) => i,
+            None => {
+                addresses.push(addr);
+                addresses.len() - 1
+            }
+        };
+        ranges.push((start, end, idx));
+    }
+    let mut nodes = Vec::new();
+    for addr in addresses {
+        let con = match known.connection(&addr) {
+            Some(c) => c,
+            None => connect(settings, &addr.0, addr.1, 0).await?,
+        };
+        nodes.push((addr, con));
+    }
+    Ok(Topology { ranges, nodes })
+}
+
+async fn build_cluster(settings: &RedisSettings, seeds: &[NodeAddr]) -> anyhow::Result<ClusterConn> {
+    let topology = cluster_topology(settings, seeds, &Topology::default()).await?;
+    Ok(ClusterConn {
+        settings: Arc::new(settings.clone()),
+        seeds: Arc::new(seeds.to_vec()),
+        topology: Arc::new(RwLock::new(Arc::new(topology))),
+    })
 }
 
 /// creates an async connection to a redis server
-pub async fn redis_async_conn() -> anyhow::Result<redis::aio::ConnectionManager> {
-    match &*RPOOL {
-        Ok(c) => Ok(c.clone()),
-        Err(rr) => Err(anyhow::anyhow!("{}", rr)),
+async fn build_conn(settings: &RedisSettings) -> anyhow::Result<RedisConn> {
+    match &settings.mode {
+        RedisMode::Single { host, port, db } => Ok(RedisConn::Single(TrackedConn::new(
+            connect(settings, host, *port, *db).await?,
+            false,
+        ))),
+        // the master is resolved again when the pool is rebuilt, see TrackedConn
+        RedisMode::Sentinel { sentinels, master, db } => {
+            let (host, port) = sentinel_master(sentinels, master).await?;
+            Ok(RedisConn::Single(TrackedConn::new(
+                connect(settings, &host, port, *db).await?,
+                true,
+            )))
+        }
+        RedisMode::Cluster { nodes } => Ok(RedisConn::Cluster(build_cluster(settings, nodes).await?)),
+    }
+}
+
+/// creates REDIS_POOL_SIZE connections to the redis server(s)
+pub async fn build_pool() -> anyhow::Result<Vec<RedisConn>> {
+    let settings = redis_settings()?;
+    let mut pool = Vec::with_capacity(*POOL_SIZE);
+    for _ in 0..*POOL_SIZE {
+        pool.push(build_conn(&settings).await?);
+    }
+    Ok(pool)
+}
+
+/// a multiplexed connection, shared by concurrent requests
+///
+/// the pipelines of the requests are sent as they are, and not merged, so that an error in a pipeline does not fail
+/// the pipelines of other requests
+///
+/// when the server was found through the sentinels, a read-only answer or a connection error means that the master
+/// may have changed, and the pool is dropped so that the next request asks the sentinels again
+#[derive(Clone)]
+pub struct TrackedConn {
+    conn: ConnectionManager,
+    sentinel: bool,
+}
+
+impl TrackedConn {
+    fn new(conn: ConnectionManager, sentinel: bool) -> Self {
+        TrackedConn { conn, sentinel }
+    }
+
+    fn track<'a, T: Send + 'a>(sentinel: bool, fut: RedisFuture<'a, T>) -> RedisFuture<'a, T> {
+        (async move {
+            let res = tracked(fut).await;
+            if let Err(rr) = &res {
+                if sentinel && (rr.kind() == ErrorKind::ReadOnly || is_connection_error(rr)) {
+                    drop_pool();
+                }
+            }
+            res
+        })
+        .boxed()
+    }
+}
+
+impl ConnectionLike for TrackedConn {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        Self::track(self.sentinel, self.conn.req_packed_command(cmd))
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipe: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        Self::track(self.sentinel, self.conn.req_packed_commands(pipe, offset, count))
+    }
+
+    fn get_db(&self) -> i64 {
+        self.conn.get_db()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakerSettings {
+    /// consecutive failures opening the breaker
+    pub threshold: u32,
+    /// time before the first probe, doubled each time the probe fails
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    pub connect_timeout: Duration,
+}
+
+impl BreakerSettings {
+    /// reads the REDIS_BREAKER_THRESHOLD, REDIS_BACKOFF_MS, REDIS_MAX_BACKOFF_MS and REDIS_CONNECT_TIMEOUT_MS variables
+    fn from_env() -> Self {
+        let var = |name: &str, default: u64| std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+        BreakerSettings {
+            threshold: var("REDIS_BREAKER_THRESHOLD", 3).clamp(1, u32::MAX as u64) as u32,
+            backoff: Duration::from_millis(var("REDIS_BACKOFF_MS", 500).max(1)),
+            max_backoff: Duration::from_millis(var("REDIS_MAX_BACKOFF_MS", 30_000).max(1)),
+            connect_timeout: Duration::from_millis(var("REDIS_CONNECT_TIMEOUT_MS", 1000).max(1)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// redis is used
+    Closed,
+    /// redis is skipped until the next probe
+    Open,
+    /// a connection is being probed
+    HalfOpen,
+}
+
+/// circuit breaker, so that requests do not pay a connection timeout each when redis is down
+#[derive(Debug)]
+struct Breaker {
+    settings: BreakerSettings,
+    state: BreakerState,
+    consecutive_failures: u32,
+    /// times the breaker opened since the last success, for the exponential backoff
+    trips: u32,
+    /// when the next probe is allowed, for the open and half-open states
+    retry_at: Option<Instant>,
+    attempts: u64,
+    opened: u64,
+    last_error: Option<String>,
+}
+
+impl Breaker {
+    fn new(settings: BreakerSettings) -> Self {
+        Breaker {
+            settings,
+            state: BreakerState::Closed,
+            consecutive_failures: 0,
+            trips: 0,
+            retry_at: None,
+            attempts: 0,
+            opened: 0,
+            last_error: None,
+        }
+    }
+
+    fn allows_attempt(&self, now: Instant) -> bool {
+        match self.state {
+            BreakerState::Closed => true,
+            BreakerState::Open | BreakerState::HalfOpen => self.retry_at.map(|t| now >= t).unwrap_or(true),
+        }
+    }
+
+    /// a connection attempt is starting, it is a probe when the breaker is not closed
+    fn attempt(&mut self, now: Instant) {
+        self.attempts += 1;
+        if self.state != BreakerState::Closed {
+            self.state = BreakerState::HalfOpen;
+            // in case the probe never completes
+            self.retry_at = Some(now + self.settings.connect_timeout);
+        }
+    }
+
+    fn backoff(&self) -> Duration {
+        let factor = 1u32.checked_shl(self.trips.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.settings
+            .backoff
+            .checked_mul(factor)
+            .unwrap_or(self.settings.max_backoff)
+            .min(self.settings.max_backoff)
+    }
+
+    fn success(&mut self) {
+        self.state = BreakerState::Closed;
+        self.consecutive_failures = 0;
+        self.trips = 0;
+        self.retry_at = None;
+    }
+
+    fn failure(&mut self, now: Instant, error: String) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        self.last_error = Some(error);
+        if self.state == BreakerState::HalfOpen || self.consecutive_failures >= self.settings.threshold {
+            self.state = BreakerState::Open;
+            self.trips = self.trips.saturating_add(1);
+            self.opened += 1;
+            self.retry_at = Some(now + self.backoff());
+        }
+    }
+}
+
+struct RedisHealth {
+    breaker: Breaker,
+    /// dropped when the breaker opens, to be rebuilt by the next probe
+    pool: Vec<RedisConn>,
+    /// index of the next connection of the pool
+    next: usize,
+}
+
+impl RedisHealth {
+    fn conn(&mut self) -> Option<RedisConn> {
+        if self.pool.is_empty() {
+            return None;
+        }
+        self.next = (self.next + 1) % self.pool.len();
+        Some(self.pool[self.next].clone())
+    }
+}
+
+fn health() -> MutexGuard<'static, RedisHealth> {
+    HEALTH.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// state of the redis connection, as exposed in the statistics
+#[derive(Debug, Clone, Serialize)]
+pub struct RedisStats {
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    /// connection attempts, including probes
+    pub attempts: u64,
+    /// times the breaker opened
+    pub opened: u64,
+    /// time until the next probe, in milliseconds
+    pub retry_in_ms: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+/// the redis connection statistics, once redis has been used
+pub fn redis_stats() -> Option<RedisStats> {
+    let health = health();
+    let breaker = &health.breaker;
+    if breaker.attempts == 0 {
+        return None;
+    }
+    let now = Instant::now();
+    Some(RedisStats {
+        state: breaker.state,
+        consecutive_failures: breaker.consecutive_failures,
+        attempts: breaker.attempts,
+        opened: breaker.opened,
+        retry_in_ms: breaker
+            .retry_at
+            .map(|t| t.saturating_duration_since(now).as_millis() as u64),
+        last_error: breaker.last_error.clone(),
+    })
+}
+
+fn record_success() {
+    let mut health = health();
+    if health.breaker.consecutive_failures > 0 || health.breaker.state != BreakerState::Closed {
+        health.breaker.success();
+    }
+}
+
+fn record_failure(error: String) {
+    let mut health = health();
+    health.breaker.failure(Instant::now(), error);
+    if health.breaker.state == BreakerState::Open {
+        health.pool.clear();
+    }
+}
+
+/// the connections are rebuilt by the next request, without waiting for the breaker
+fn drop_pool() {
+    health().pool.clear();
+}
+
+fn is_connection_error(rr: &RedisError) -> bool {
+    rr.is_io_error() || rr.is_timeout() || rr.is_connection_dropped()
+}
+
+/// the current connection, or None when a connection must be attempted
+fn current_conn() -> anyhow::Result<Option<RedisConn>> {
+    let mut health = health();
+    if let Some(c) = health.conn() {
+        return Ok(Some(c));
+    }
+    if !health.breaker.allows_attempt(Instant::now()) {
+        anyhow::bail!(
+            "redis circuit breaker is open, last error: {}",
+            health.breaker.last_error.as_deref().unwrap_or("none")
+        );
+    }
+    Ok(None)
+}
+
+/// connects, and checks that the servers answer
+async fn probe() -> anyhow::Result<Vec<RedisConn>> {
+    let mut pool = build_pool().await?;
+    for conn in pool.iter_mut() {
+        let _: String = redis::cmd("PING").query_async(conn).await?;
+    }
+    Ok(pool)
+}
+
+/// returns the shared connection to the redis server(s), connecting when required
+///
+/// fails immediately when the circuit breaker is open
+pub async fn redis_async_conn() -> anyhow::Result<RedisConn> {
+    if let Some(conn) = current_conn()? {
+        return Ok(conn);
+    }
+    let _connecting = match CONNECTING.try_lock() {
+        Some(guard) => guard,
+        // requests wait for the first connection, but are not delayed while redis is probed
+        None if health().breaker.trips == 0 => CONNECTING.lock().await,
+        None => anyhow::bail!("redis is being probed"),
+    };
+    if let Some(conn) = current_conn()? {
+        return Ok(conn);
+    }
+    let timeout = {
+        let mut health = health();
+        health.breaker.attempt(Instant::now());
+        health.breaker.settings.connect_timeout
+    };
+    let res = match async_std::future::timeout(timeout, probe()).await {
+        Ok(r) => r,
+        Err(_) => Err(anyhow::anyhow!("timeout while connecting to redis")),
+    };
+    let mut health = health();
+    match res {
+        Ok(pool) => {
+            health.breaker.success();
+            health.pool = pool;
+            health
+                .conn()
+                .ok_or_else(|| anyhow::anyhow!("empty redis connection pool"))
+        }
+        Err(rr) => {
+            health.breaker.failure(Instant::now(), rr.to_string());
+            Err(rr)
+        }
+    }
+}
+
+/// records the outcome of commands sent to redis, connection problems counting as breaker failures
+fn tracked<'a, T: Send + 'a>(fut: RedisFuture<'a, T>) -> RedisFuture<'a, T> {
+    (async move {
+        let res = fut.await;
+        match &res {
+            Ok(_) => record_success(),
+            Err(rr) if is_connection_error(rr) => record_failure(rr.to_string()),
+            Err(_) => (),
+        }
+        res
+    })
+    .boxed()
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for b in data {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// computes the cluster slot of a key, taking hash tags into account
+pub fn key_slot(key: &[u8]) -> u16 {
+    let hashed = match key.iter().position(|c| *c == b'{') {
+        Some(open) => match key[open + 1..].iter().position(|c| *c == b'}') {
+            Some(close) if close > 0 => &key[open + 1..open + 1 + close],
+            _ => key,
+        },
+        None => key,
+    };
+    crc16(hashed) % CLUSTER_SLOTS
+}
+
+fn cmd_key(cmd: &Cmd) -> Option<&[u8]> {
+    // scripts are called as EVAL script numkeys key...
+    let pos = match cmd.args_iter().next() {
+        Some(Arg::Simple(name)) if name.eq_ignore_ascii_case(b"EVAL") || name.eq_ignore_ascii_case(b"EVALSHA") => 3,
+        _ => 1,
+    };
+    match cmd.args_iter().nth(pos) {
+        Some(Arg::Simple(k)) => Some(k),
+        _ => None,
+    }
+}
+
+/// the masters of a redis cluster, and the slots they own
+#[derive(Default)]
+struct Topology {
+    /// (first slot, last slot, node index), sorted by first slot
+    ranges: Vec<(u16, u16, usize)>,
+    nodes: Vec<(NodeAddr, ConnectionManager)>,
+}
+
+impl Topology {
+    fn connection(&self, addr: &NodeAddr) -> Option<ConnectionManager> {
+        self.nodes.iter().find(|(a, _)| a == addr).map(|(_, c)| c.clone())
+    }
+
+    fn node_connection(&self, node: usize) -> RedisResult<ConnectionManager> {
+        self.nodes
+            .get(node)
+            .map(|(_, c)| c.clone())
+            .ok_or_else(|| RedisError::from((ErrorKind::ClusterDown, "no known cluster node")))
+    }
+
+    /// the connection to the master owning the key of the command, the first node for commands without keys
+    fn connection_for_cmd(&self, cmd: &Cmd) -> RedisResult<ConnectionManager> {
+        self.node_connection(cmd_node(&self.ranges, cmd))
+    }
+}
+
+/// the node owning the key of the command, the first node for commands without keys
+fn cmd_node(ranges: &[(u16, u16, usize)], cmd: &Cmd) -> usize {
+    cmd_key(cmd).and_then(|k| slot_node(ranges, key_slot(k))).unwrap_or(0)
+}
+
+/// the indices of the commands sent to each node, in the order the nodes are first used
+fn node_groups(ranges: &[(u16, u16, usize)], cmds: &[&Cmd]) -> Vec<(usize, Vec<usize>)> {
+    let mut groups: Vec<(usize, Vec<usize>)> = Vec::new();
+    for (i, cmd) in cmds.iter().enumerate() {
+        let node = cmd_node(ranges, cmd);
+        match groups.iter_mut().find(|(n, _)| *n == node) {
+            Some((_, idxs)) => idxs.push(i),
+            None => groups.push((node, vec![i])),
+        }
+    }
+    groups
+}
+
+/// the slot of the keys of a transaction, that must all belong to the same one
+fn transaction_slot(pipe: &Pipeline) -> RedisResult<Option<u16>> {
+    let mut slot = None;
+    for key in pipe.cmd_iter().filter_map(cmd_key) {
+        let kslot = key_slot(key);
+        match slot {
+            Some(s) if s != kslot => {
+                return Err(RedisError::from((
+                    ErrorKind::CrossSlot,
+                    "the keys of the transaction belong to different slots",
+                )))
+            }
+            _ => slot = Some(kslot),
+        }
+    }
+    Ok(slot)
+}
+
+fn slot_node(ranges: &[(u16, u16, usize)], slot: u16) -> Option<usize> {
+    let pos = ranges.partition_point(|(start, _, _)| *start <= slot);
+    match pos.checked_sub(1).and_then(|p| ranges.get(p)) {
+        Some((_, end, node)) if slot <= *end => Some(*node),
+        _ => None,
+    }
+}
+
+/// the node a MOVED or ASK error redirects to
+fn redirection(rr: &RedisError) -> Option<NodeAddr> {
+    let (addr, _) = rr.redirect_node()?;
+    let (host, port) = addr.rsplit_once(':')?;
+    Some((host.to_string(), port.parse().ok()?))
+}
+
+/// a connection to a redis cluster, routing each command to the master owning its key
+///
+/// a MOVED answer refreshes the topology before the command is sent again, and an ASK answer sends the command
+/// to the importing node, after ASKING
+#[derive(Clone)]
+pub struct ClusterConn {
+    settings: Arc<RedisSettings>,
+    seeds: Arc<Vec<NodeAddr>>,
+    topology: Arc<RwLock<Arc<Topology>>>,
+}
+
+impl ClusterConn {
+    fn topology(&self) -> Arc<Topology> {
+        match self.topology.read() {
+            Ok(t) => t.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// asks the known nodes, then the seeds, for the current topology
+    async fn refresh(&self) -> anyhow::Result<()> {
+        let known = self.topology();
+        let mut candidates: Vec<NodeAddr> = known.nodes.iter().map(|(addr, _)| addr.clone()).collect();
+        candidates.extend(self.seeds.iter().cloned());
+        let topology = cluster_topology(&self.settings, &candidates, &known).await?;
+        match self.topology.write() {
+            Ok(mut t) => *t = Arc::new(topology),
+            Err(poisoned) => *poisoned.into_inner() = Arc::new(topology),
+        }
+        Ok(())
+    }
+
+    async fn node_connection(&self, addr: &NodeAddr) -> RedisResult<ConnectionManager> {
+        match self.topology().connection(addr) {
+            Some(c) => Ok(c),
+            None => connect(&self.settings, &addr.0, addr.1, 0).await,
+        }
+    }
+
+    /// sends a command, following the cluster redirections
+    async fn send(&self, cmd: &Cmd) -> RedisResult<Value> {
+        let mut asking = None;
+        let mut redirections = 0;
+        loop {
+            let res = match asking.take() {
+                None => self.topology().connection_for_cmd(cmd)?.req_packed_command(cmd).await,
+                Some(addr) => {
+                    let mut con = self.node_connection(&addr).await?;
+                    let mut pipe = redis::pipe();
+                    pipe.cmd("ASKING").add_command(cmd.clone());
+                    con.req_packed_commands(&pipe, 0, 2)
+                        .await
+                        .map(|mut values| values.pop().unwrap_or(Value::Nil))
+                }
+            };
+            let rr = match res {
+                Ok(v) => return Ok(v),
+                Err(rr) if redirections >= MAX_REDIRECTIONS => return Err(rr),
+                Err(rr) => rr,
+            };
+            redirections += 1;
+            match rr.kind() {
+                ErrorKind::Moved => {
+                    if self.refresh().await.is_err() {
+                        return Err(rr);
+                    }
+                }
+                ErrorKind::Ask => asking = Some(redirection(&rr).ok_or(rr)?),
+                ErrorKind::TryAgain => async_std::task::sleep(Duration::from_millis(10)).await,
+                _ => return Err(rr),
+            }
+        }
+    }
+
+    /// sends the commands of a node as a single pipeline
+    ///
+    /// when the node redirects one of them, its slot is migrating, and the commands are sent again one by one,
+    /// following the redirections, so that the commands that already ran are run a second time
+    async fn send_group(&self, node: usize, cmds: &[&Cmd]) -> RedisResult<Vec<Value>> {
+        let mut pipe = redis::pipe();
+        for cmd in cmds {
+            pipe.add_command((*cmd).clone());
+        }
+        let mut con = self.topology().node_connection(node)?;
+        match con.req_packed_commands(&pipe, 0, cmds.len()).await {
+            Err(rr) if matches!(rr.kind(), ErrorKind::Moved | ErrorKind::Ask | ErrorKind::TryAgain) => {
+                let mut values = Vec::with_capacity(cmds.len());
+                for cmd in cmds {
+                    values.push(self.send(cmd).await?);
+                }
+                Ok(values)
+            }
+            res => res,
+        }
+    }
+
+    /// transactions are sent to the node owning their keys, that must all belong to the same slot
+    ///
+    /// a MOVED answer aborts the whole transaction, that can then be sent again
+    async fn send_transaction(&self, pipe: &Pipeline, offset: usize, count: usize) -> RedisResult<Vec<Value>> {
+        let slot = transaction_slot(pipe)?;
+        let mut redirections = 0;
+        loop {
+            let topology = self.topology();
+            let node = slot.and_then(|s| slot_node(&topology.ranges, s)).unwrap_or(0);
+            let mut con = topology.node_connection(node)?;
+            match con.req_packed_commands(pipe, offset, count).await {
+                Err(rr) if rr.kind() == ErrorKind::Moved && redirections < MAX_REDIRECTIONS => {
+                    redirections += 1;
+                    if self.refresh().await.is_err() {
+                        return Err(rr);
+                    }
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl ConnectionLike for ClusterConn {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        (async move { self.send(cmd).await }).boxed()
+    }
+
+    /// pipelines are split by node, each node receiving its commands as a single pipeline, and the answers are
+    /// reassembled in the original order
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipe: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        (async move {
+            if offset > 0 {
+                return self.send_transaction(pipe, offset, count).await;
+            }
+            let this = &*self;
+            let cmds: Vec<&Cmd> = pipe.cmd_iter().collect();
+            let groups = node_groups(&this.topology().ranges, &cmds);
+            let answers = join_all(groups.iter().map(|(node, idxs)| {
+                let group: Vec<&Cmd> = idxs.iter().map(|i| cmds[*i]).collect();
+                async move { this.send_group(*node, &group).await }
+            }))
+            .await;
+            let mut out = vec![Value::Nil; cmds.len()];
+            // the groups are ordered by their first command, the error of the first failed group is returned
+            for ((_, idxs), answer) in groups.iter().zip(answers) {
+                for (i, v) in idxs.iter().zip(answer?) {
+                    out[*i] = v;
+                }
+            }
+            Ok(out)
+        })
+        .boxed()
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
+
+/// a connection to the in-process store, used when redis is not available
+pub fn local_fallback_conn() -> RedisConn {
+    RedisConn::Local(LOCAL_STORE.clone())
+}
+
+/// a redis connection, to either a single server, a cluster, or the local fallback store
+#[derive(Clone)]
+pub enum RedisConn {
+    Single(TrackedConn),
+    Cluster(ClusterConn),
+    Local(LocalStore),
+}
+
+impl ConnectionLike for RedisConn {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            RedisConn::Single(c) => c.req_packed_command(cmd),
+            RedisConn::Cluster(c) => tracked(c.req_packed_command(cmd)),
+            RedisConn::Local(c) => c.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipe: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            RedisConn::Single(c) => c.req_packed_commands(pipe, offset, count),
+            RedisConn::Cluster(c) => tracked(c.req_packed_commands(pipe, offset, count)),
+            RedisConn::Local(c) => c.req_packed_commands(pipe, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            RedisConn::Single(c) => c.get_db(),
+            RedisConn::Cluster(c) => c.get_db(),
+            RedisConn::Local(c) => c.get_db(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn breaker() {
+        let mut breaker = Breaker::new(BreakerSettings {
+            threshold: 2,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+            connect_timeout: Duration::from_millis(50),
+        });
+        let now = Instant::now();
+        let ms = |n| now + Duration::from_millis(n);
+        breaker.failure(now, "refused".to_string());
+        assert_eq!(breaker.state, BreakerState::Closed);
+        breaker.failure(now, "refused".to_string());
+        assert_eq!(breaker.state, BreakerState::Open);
+        assert!(!breaker.allows_attempt(ms(99)));
+        assert!(breaker.allows_attempt(ms(100)));
+        // failed probes reopen the breaker, with an exponential backoff
+        breaker.attempt(ms(100));
+        assert_eq!(breaker.state, BreakerState::HalfOpen);
+        assert!(!breaker.allows_attempt(ms(120)));
+        breaker.failure(ms(110), "refused".to_string());
+        assert_eq!(breaker.state, BreakerState::Open);
+        assert!(!breaker.allows_attempt(ms(309)));
+        assert!(breaker.allows_attempt(ms(310)));
+        breaker.attempt(ms(310));
+        breaker.failure(ms(310), "refused".to_string());
+        breaker.attempt(ms(710));
+        breaker.failure(ms(710), "refused".to_string());
+        assert_eq!(breaker.backoff(), Duration::from_millis(300));
+        assert_eq!(breaker.opened, 4);
+        breaker.success();
+        assert_eq!(breaker.state, BreakerState::Closed);
+        assert_eq!(breaker.backoff(), Duration::from_millis(100));
+        assert!(breaker.allows_attempt(ms(710)));
+    }
+
+    #[test]
+    fn slots() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        // an empty hash tag, the whole key is hashed
+        assert_eq!(key_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % CLUSTER_SLOTS);
+        assert_ne!(key_slot(b"foo{}{bar}"), key_slot(b"bar"));
+        // only the first hash tag is used
+        assert_eq!(key_slot(b"foo{bar}{zap}"), key_slot(b"bar"));
+        assert_eq!(key_slot(b"foo{{bar}}zap"), key_slot(b"{bar"));
+    }
+
+    #[test]
+    fn cluster_routing() {
+        let ranges = vec![(0, 5460, 0), (5461, 10922, 1), (10923, 16383, 2)];
+        assert_eq!(slot_node(&ranges, 0), Some(0));
+        assert_eq!(slot_node(&ranges, 5461), Some(1));
+        assert_eq!(slot_node(&ranges, 16383), Some(2));
+        assert_eq!(slot_node(&ranges[1..], 100), None);
+        assert_eq!(slot_node(&[(0, 100, 0), (200, 300, 1)], 150), None);
+
+        let moved = RedisError::from((ErrorKind::Moved, "key moved", "3999 10.0.0.2:6381".to_string()));
+        assert_eq!(redirection(&moved), Some(("10.0.0.2".to_string(), 6381)));
+        let ask = RedisError::from((ErrorKind::Ask, "key moved (ask)", "3999 redis-b:7000".to_string()));
+        assert_eq!(redirection(&ask), Some(("redis-b".to_string(), 7000)));
+        let other = RedisError::from((ErrorKind::ReadOnly, "read-only", "3999 10.0.0.2:6381".to_string()));
+        assert_eq!(redirection(&other), None);
+    }
+
+    #[test]
+    fn command_keys() {
+        assert_eq!(cmd_key(redis::cmd("INCR").arg("k1")), Some(&b"k1"[..]));
+        assert_eq!(
+            cmd_key(redis::cmd("EVAL").arg("return 1").arg(1).arg("k2").arg(5)),
+            Some(&b"k2"[..])
+        );
+        assert_eq!(cmd_key(&redis::cmd("PING")), None);
+    }
+
+    #[test]
+    fn cluster_pipelines() {
+        let ranges = vec![(0, 8191, 0), (8192, 16383, 1)];
+        let mut pipe = redis::pipe();
+        // foo is in slot 12182, bar in slot 5061
+        pipe.cmd("INCR")
+            .arg("foo")
+            .cmd("PING")
+            .cmd("INCR")
+            .arg("bar")
+            .cmd("GET")
+            .arg("{foo}x");
+        let cmds: Vec<&Cmd> = pipe.cmd_iter().collect();
+        assert_eq!(node_groups(&ranges, &cmds), vec![(1, vec![0, 3]), (0, vec![1, 2])]);
+
+        let mut same_slot = redis::pipe();
+        same_slot.atomic().cmd("INCR").arg("{foo}a").cmd("INCR").arg("{foo}b");
+        assert_eq!(transaction_slot(&same_slot).unwrap(), Some(key_slot(b"foo")));
+        let mut cross_slot = redis::pipe();
+        cross_slot.atomic().cmd("INCR").arg("foo").cmd("INCR").arg("bar");
+        assert_eq!(transaction_slot(&cross_slot).unwrap_err().kind(), ErrorKind::CrossSlot);
+    }
+
+    #[test]
+    fn url_single() {
+        assert_eq!(
+            parse_redis_url("redis://:secret@myredis:6380/2").unwrap(),
+            RedisSettings {
+                mode: RedisMode::Single {
+                    host: "myredis".to_string(),
+                    port: 6380,
+                    db: 2
+                },
+                username: None,
+                password: Some("secret".to_string())
+            }
+        );
+    }
+
+    #[test]
+    fn url_cluster() {
+        assert_eq!(
+            parse_redis_url("redis+cluster://a:7000,b").unwrap().mode,
+            RedisMode::Cluster {
+                nodes: vec![("a".to_string(), 7000), ("b".to_string(), 6379)]
+            }
+        );
+    }
+
+    #[test]
+    fn url_sentinel() {
+        assert_eq!(
+            parse_redis_url("redis+sentinel://user:pass@s1,s2:5000/mymaster/3").unwrap(),
+            RedisSettings {
+                mode: RedisMode::Sentinel {
+                    sentinels: vec![("s1".to_string(), 26379), ("s2".to_string(), 5000)],
+                    master: "mymaster".to_string(),
+                    db: 3
+                },
+                username: Some("user".to_string()),
+                password: Some("pass".to_string())
+            }
+        );
+        assert!(parse_redis_url("redis+sentinel://s1").is_err());
+        assert!(parse_redis_url("http://s1").is_err());
     }
 }
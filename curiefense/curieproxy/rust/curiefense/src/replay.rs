@@ -0,0 +1,297 @@
+//! Replay protection.
+//!
+//! A security policy entry can require its clients to send a unique value with each request, a nonce or a request
+//! signature, in one of the configured headers (`x-nonce` by default, the first one present is used). The values
+//! seen on the protected endpoints are kept in redis for `ttl_seconds`, and a request reusing one of them is a
+//! replay. Requests without a nonce are also rejected, unless `allow_missing` is set.
+//!
+//! Nonces are only remembered for a while, so the requests must also carry a timestamp, signed as configured in the
+//! `signature` settings of the same entry (its `timestamp_header`). Requests whose timestamp is further from the
+//! current time than the signature tolerance, or the nonce TTL if it is shorter, are rejected, and the timestamp is
+//! part of the recorded value. The replay protection is disabled when the entry has no signed timestamp.
+//!
+//! Failed requests are tagged `replay:missing-nonce`, `replay:invalid-nonce` (too long), `replay:replayed-nonce`,
+//! `replay:missing-timestamp`, `replay:invalid-timestamp` or `replay:stale-timestamp`, and are blocked when
+//! `enforce` is set, or only get a monitor reason otherwise. When redis is not available, requests are let
+//! through.
+use std::collections::HashSet;
+
+use crate::analyze::APhase0;
+use crate::config::pathtrie::PathTemplate;
+use crate::config::raw::RawReplay;
+use crate::failure::{dependency_failed, Dependency};
+use crate::interface::{stronger_decision, BlockReason, Location, SimpleAction, SimpleActionT, SimpleDecision};
+use crate::login::short_hash;
+use crate::logs::Logs;
+use crate::redis::{redis_async_conn, REDIS_KEY_PREFIX};
+use crate::signature::SignatureSettings;
+use crate::utils::RequestInfo;
+
+/// nonces longer than this are rejected, so that the redis keys stay small
+const MAX_NONCE_LENGTH: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct ReplaySettings {
+    /// all the paths of the entry when empty
+    pub paths: Vec<PathTemplate>,
+    /// uppercase
+    pub methods: HashSet<String>,
+    /// lowercase
+    pub headers: Vec<String>,
+    pub ttl: u64,
+    /// lowercase, the signed timestamp header
+    pub timestamp_header: String,
+    /// seconds, never more than the ttl
+    pub tolerance: i64,
+    pub allow_missing: bool,
+    pub enforce: bool,
+}
+
+/// a nonce problem, used as the block reason
+pub type ReplayProblem = &'static str;
+
+impl ReplaySettings {
+    pub fn resolve(logs: &mut Logs, raw: RawReplay, signature: Option<&SignatureSettings>) -> Option<Self> {
+        let (timestamp_header, sig_tolerance) =
+            match signature.and_then(|s| Some((s.timestamp_header.clone()?, s.tolerance))) {
+                Some(ts) => ts,
+                None => {
+                    logs.error("replay protection requires a signature with a timestamp header, it is disabled");
+                    return None;
+                }
+            };
+        let mut paths = Vec::new();
+        for p in raw.paths {
+            match p.parse() {
+                Ok(tpl) => paths.push(tpl),
+                Err(rr) => logs.error(|| format!("replay protection path: {}", rr)),
+            }
+        }
+        let methods = if raw.methods.is_empty() {
+            ["POST", "PUT", "PATCH", "DELETE"]
+                .iter()
+                .map(|m| m.to_string())
+                .collect()
+        } else {
+            raw.methods.iter().map(|m| m.to_uppercase()).collect()
+        };
+        let headers = if raw.headers.is_empty() {
+            vec!["x-nonce".to_string()]
+        } else {
+            raw.headers.iter().map(|h| h.to_lowercase()).collect()
+        };
+        let ttl = raw.ttl_seconds.unwrap_or(300).clamp(1, i64::MAX as u64);
+        Some(ReplaySettings {
+            paths,
+            methods,
+            headers,
+            ttl,
+            timestamp_header,
+            tolerance: sig_tolerance.min(ttl as i64),
+            allow_missing: raw.allow_missing,
+            enforce: raw.enforce,
+        })
+    }
+
+    pub fn protects(&self, reqinfo: &RequestInfo) -> bool {
+        if !self.methods.contains(&reqinfo.rinfo.meta.method.to_uppercase()) {
+            return false;
+        }
+        let path = &reqinfo.rinfo.qinfo.normalized.path;
+        self.paths.is_empty() || self.paths.iter().any(|tpl| tpl.captures(path).is_some())
+    }
+
+    /// the header carrying the nonce, and its value
+    pub fn nonce<'a>(&'a self, reqinfo: &'a RequestInfo) -> Option<(&'a str, &'a str)> {
+        self.headers.iter().find_map(|h| {
+            reqinfo
+                .headers
+                .get_str(h)
+                .map(|v| v.trim())
+                .filter(|v| !v.is_empty())
+                .map(|v| (h.as_str(), v))
+        })
+    }
+
+    /// the signed timestamp of the request, if it is recent enough, `now` being the current unix time
+    pub fn timestamp<'a>(&self, reqinfo: &'a RequestInfo, now: i64) -> Result<&'a str, ReplayProblem> {
+        let ts = reqinfo
+            .headers
+            .get_str(&self.timestamp_header)
+            .map(|v| v.trim())
+            .ok_or("missing-timestamp")?;
+        let parsed: i64 = ts.parse().map_err(|_| "invalid-timestamp")?;
+        if now.abs_diff(parsed) > self.tolerance as u64 {
+            return Err("stale-timestamp");
+        }
+        Ok(ts)
+    }
+}
+
+/// records the nonce, returns false if it was already seen
+async fn record_nonce(
+    reqinfo: &RequestInfo,
+    settings: &ReplaySettings,
+    header: &str,
+    nonce: &str,
+    timestamp: &str,
+) -> anyhow::Result<bool> {
+    let namespace = &reqinfo.rinfo.secpolicy.counters.namespace;
+    let key = format!(
+        "{}{}replay_{}",
+        *REDIS_KEY_PREFIX,
+        namespace,
+        short_hash(namespace, &format!("{}:{}:{}", header, timestamp, nonce))
+    );
+    let mut conn = redis_async_conn().await?;
+    let stored: Option<String> = redis::cmd("SET")
+        .arg(key)
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(settings.ttl)
+        .query_async(&mut conn)
+        .await?;
+    Ok(stored.is_some())
+}
+
+/// checks the nonce of the request, and sets the replay protection action when it is missing or already seen
+pub async fn analyze_replay(logs: &mut Logs, mut p0: APhase0) -> APhase0 {
+    let secpolicy = p0.reqinfo.rinfo.secpolicy.clone();
+    let settings = match &secpolicy.replay {
+        None => return p0,
+        Some(s) => s,
+    };
+    if !settings.protects(&p0.reqinfo) {
+        return p0;
+    }
+    let timestamp = settings.timestamp(&p0.reqinfo, p0.reqinfo.timestamp.timestamp());
+    let (header, problem): (String, ReplayProblem) = match (settings.nonce(&p0.reqinfo), timestamp) {
+        (None, _) if settings.allow_missing => return p0,
+        (None, _) => (settings.headers[0].clone(), "missing-nonce"),
+        (Some((header, nonce)), _) if nonce.len() > MAX_NONCE_LENGTH => (header.to_string(), "invalid-nonce"),
+        (Some(_), Err(problem)) => (settings.timestamp_header.clone(), problem),
+        (Some((header, nonce)), Ok(ts)) => match record_nonce(&p0.reqinfo, settings, header, nonce, ts).await {
+            Ok(true) => return p0,
+            Ok(false) => (header.to_string(), "replayed-nonce"),
+            Err(rr) => {
+                logs.error(|| format!("replay protection: {}", rr));
+                p0.itags.insert("replay-protection-degraded", Location::Request);
+                dependency_failed(&mut p0.itags, Dependency::Redis);
+                return p0;
+            }
+        },
+    };
+    logs.debug(|| format!("replay check failed: {}", problem));
+    p0.itags
+        .insert_qualified("replay", problem, Location::Header(header.clone()));
+    let action = if settings.enforce {
+        SimpleAction::default()
+    } else {
+        SimpleAction {
+            atype: SimpleActionT::Monitor,
+            ..SimpleAction::default()
+        }
+    };
+    let reason = BlockReason::replay(
+        secpolicy.entry.id.clone(),
+        secpolicy.entry.name.clone(),
+        action.atype.to_raw(),
+        header,
+        problem,
+    );
+    p0.globalfilter_dec = stronger_decision(p0.globalfilter_dec, SimpleDecision::Action(action, vec![reason]));
+    p0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::raw::RawSignature;
+    use crate::testutils::request_info;
+
+    fn mk_reqinfo(method: &str, path: &str, headers: &[(&str, &str)]) -> RequestInfo {
+        request_info(&[("method", method), ("path", path)], headers)
+    }
+
+    fn signature(tolerance: u64) -> SignatureSettings {
+        SignatureSettings::resolve(
+            &mut Logs::default(),
+            RawSignature {
+                secret: Some("whsec".to_string()),
+                timestamp_header: Some("X-Timestamp".to_string()),
+                tolerance_seconds: Some(tolerance),
+                ..Default::default()
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn protected_requests() {
+        let settings = ReplaySettings::resolve(
+            &mut Logs::default(),
+            RawReplay {
+                paths: vec!["/hooks/*".to_string()],
+                headers: vec!["X-Nonce".to_string(), "X-Signature".to_string()],
+                ..Default::default()
+            },
+            Some(&signature(300)),
+        )
+        .unwrap();
+        assert!(settings.protects(&mk_reqinfo("POST", "/hooks/github", &[])));
+        assert!(!settings.protects(&mk_reqinfo("GET", "/hooks/github", &[])));
+        assert!(!settings.protects(&mk_reqinfo("POST", "/api/items", &[])));
+
+        let signed = mk_reqinfo("POST", "/hooks/github", &[("x-signature", "sha256=abcd")]);
+        assert_eq!(settings.nonce(&signed), Some(("x-signature", "sha256=abcd")));
+        let both = mk_reqinfo(
+            "POST",
+            "/hooks/github",
+            &[("x-signature", "sha256=abcd"), ("x-nonce", " 42 ")],
+        );
+        assert_eq!(settings.nonce(&both), Some(("x-nonce", "42")));
+        let empty = mk_reqinfo("POST", "/hooks/github", &[("x-nonce", "")]);
+        assert_eq!(settings.nonce(&empty), None);
+
+        let all = ReplaySettings::resolve(&mut Logs::default(), RawReplay::default(), Some(&signature(300))).unwrap();
+        assert!(all.protects(&mk_reqinfo("DELETE", "/anything", &[])));
+        assert_eq!(all.headers, vec!["x-nonce".to_string()]);
+    }
+
+    #[test]
+    fn signed_timestamps() {
+        let raw = RawReplay {
+            ttl_seconds: Some(60),
+            ..Default::default()
+        };
+        // the tolerance never exceeds the nonce ttl
+        let settings = ReplaySettings::resolve(&mut Logs::default(), raw.clone(), Some(&signature(300))).unwrap();
+        assert_eq!(settings.tolerance, 60);
+        let now = 1700000000;
+        let req = mk_reqinfo("POST", "/", &[("x-nonce", "42"), ("x-timestamp", "1700000000")]);
+        assert_eq!(settings.timestamp(&req, now), Ok("1700000000"));
+        assert_eq!(settings.timestamp(&req, now + 60), Ok("1700000000"));
+        assert_eq!(settings.timestamp(&req, now + 61), Err("stale-timestamp"));
+        assert_eq!(settings.timestamp(&req, now - 61), Err("stale-timestamp"));
+        let req = mk_reqinfo(
+            "POST",
+            "/",
+            &[("x-nonce", "42"), ("x-timestamp", "-9223372036854775808")],
+        );
+        assert_eq!(settings.timestamp(&req, now), Err("stale-timestamp"));
+        let req = mk_reqinfo("POST", "/", &[("x-nonce", "42"), ("x-timestamp", "yesterday")]);
+        assert_eq!(settings.timestamp(&req, now), Err("invalid-timestamp"));
+        let req = mk_reqinfo("POST", "/", &[("x-nonce", "42")]);
+        assert_eq!(settings.timestamp(&req, now), Err("missing-timestamp"));
+
+        let shorter = ReplaySettings::resolve(&mut Logs::default(), raw.clone(), Some(&signature(10))).unwrap();
+        assert_eq!(shorter.tolerance, 10);
+
+        // without a signed timestamp, the replay protection is disabled
+        assert!(ReplaySettings::resolve(&mut Logs::default(), raw.clone(), None).is_none());
+        let mut unsigned = signature(300);
+        unsigned.timestamp_header = None;
+        assert!(ReplaySettings::resolve(&mut Logs::default(), raw, Some(&unsigned)).is_none());
+    }
+}
@@ -0,0 +1,388 @@
+//! IP reputation feeds.
+//!
+//! Reputation lists, from reputation-feeds.json, contain one IP address or network per line, and can be
+//! read from a file, an http(s) URL, or a Redis set (`redis:key`). They are refreshed in the background, so
+//! that requests never wait on a feed, and merged in a single prefix trie that is swapped in once built.
+//!
+//! Requests coming from a listed address are tagged with `reputation:<category>`, and with the feed tags.
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::raw::RawReputationFeed;
+use crate::logs::Logs;
+use crate::redis::redis_async_conn;
+
+lazy_static! {
+    static ref FEEDS: Mutex<HashMap<String, FeedState>> = Mutex::new(HashMap::new());
+    static ref REPUTATION: ArcSwap<ReputationDb> = ArcSwap::from_pointee(ReputationDb::default());
+}
+
+static REFRESHER: std::sync::Once = std::sync::Once::new();
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReputationFeed {
+    pub id: String,
+    pub category: String,
+    pub tags: Vec<String>,
+    pub source: String,
+    pub refresh: Duration,
+}
+
+impl ReputationFeed {
+    pub fn resolve(raw: Vec<RawReputationFeed>) -> Vec<Self> {
+        raw.into_iter()
+            .filter(|f| f.active)
+            .map(|f| ReputationFeed {
+                id: f.id,
+                category: f.category,
+                tags: f.tags,
+                source: f.source,
+                refresh: Duration::from_secs(f.refresh_seconds.unwrap_or(3600).max(10)),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FeedStats {
+    pub category: String,
+    pub source: String,
+    pub entries: usize,
+    pub invalid_entries: usize,
+    pub refreshes: u64,
+    pub failures: u64,
+    pub last_refresh: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+struct FeedState {
+    feed: ReputationFeed,
+    networks: Arc<Vec<IpNet>>,
+    next_refresh: Instant,
+    stats: FeedStats,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Node {
+    /// indices of the children, 0 when absent (the root is never a child)
+    children: [u32; 2],
+    /// feeds listing the prefix ending at this node
+    feeds: Vec<u16>,
+}
+
+/// a binary trie of network prefixes
+#[derive(Debug, Clone)]
+pub struct PrefixTrie {
+    width: u8,
+    nodes: Vec<Node>,
+}
+
+impl PrefixTrie {
+    fn new(width: u8) -> Self {
+        PrefixTrie {
+            width,
+            nodes: vec![Node::default()],
+        }
+    }
+
+    fn bit(&self, bits: u128, idx: u8) -> usize {
+        ((bits >> (self.width - 1 - idx)) & 1) as usize
+    }
+
+    fn insert(&mut self, bits: u128, prefix_len: u8, feed: u16) {
+        let mut cur = 0;
+        for idx in 0..prefix_len.min(self.width) {
+            let bit = self.bit(bits, idx);
+            let next = self.nodes[cur].children[bit] as usize;
+            cur = if next == 0 {
+                self.nodes.push(Node::default());
+                let created = self.nodes.len() - 1;
+                self.nodes[cur].children[bit] = created as u32;
+                created
+            } else {
+                next
+            };
+        }
+        if !self.nodes[cur].feeds.contains(&feed) {
+            self.nodes[cur].feeds.push(feed);
+        }
+    }
+
+    /// feeds of all the prefixes containing the address
+    fn lookup(&self, bits: u128, out: &mut Vec<u16>) {
+        let mut cur = 0;
+        out.extend(&self.nodes[cur].feeds);
+        for idx in 0..self.width {
+            let next = self.nodes[cur].children[self.bit(bits, idx)] as usize;
+            if next == 0 {
+                break;
+            }
+            cur = next;
+            out.extend(&self.nodes[cur].feeds);
+        }
+    }
+}
+
+/// the merged reputation lists
+#[derive(Debug, Clone)]
+pub struct ReputationDb {
+    v4: PrefixTrie,
+    v6: PrefixTrie,
+    feeds: Vec<ReputationFeed>,
+}
+
+impl Default for ReputationDb {
+    fn default() -> Self {
+        ReputationDb {
+            v4: PrefixTrie::new(32),
+            v6: PrefixTrie::new(128),
+            feeds: Vec::new(),
+        }
+    }
+}
+
+impl ReputationDb {
+    pub fn build(lists: &[(&ReputationFeed, &[IpNet])]) -> Self {
+        let mut db = ReputationDb::default();
+        for (idx, (feed, networks)) in lists.iter().enumerate() {
+            db.feeds.push((*feed).clone());
+            for net in networks.iter() {
+                match net {
+                    IpNet::V4(n) => db.v4.insert(u32::from(n.network()) as u128, n.prefix_len(), idx as u16),
+                    IpNet::V6(n) => db.v6.insert(u128::from(n.network()), n.prefix_len(), idx as u16),
+                }
+            }
+        }
+        db
+    }
+
+    /// feeds listing the address
+    pub fn lookup(&self, addr: IpAddr) -> Vec<&ReputationFeed> {
+        let mut found = Vec::new();
+        match addr {
+            IpAddr::V4(a) => self.v4.lookup(u32::from(a) as u128, &mut found),
+            IpAddr::V6(a) => self.v6.lookup(u128::from(a), &mut found),
+        }
+        found.sort_unstable();
+        found.dedup();
+        found
+            .into_iter()
+            .filter_map(|idx| self.feeds.get(idx as usize))
+            .collect()
+    }
+}
+
+/// parses a list, one address or network per line, ignoring comments, returns the number of invalid entries
+pub fn parse_entries<'a, I: Iterator<Item = &'a str>>(lines: I) -> (Vec<IpNet>, usize) {
+    let mut networks = Vec::new();
+    let mut invalid = 0;
+    for line in lines {
+        let entry = line.split('#').next().unwrap_or("").trim();
+        if entry.is_empty() {
+            continue;
+        }
+        match entry.parse::<IpNet>() {
+            Ok(net) => networks.push(net.trunc()),
+            Err(_) => match entry.parse::<IpAddr>() {
+                Ok(addr) => networks.push(IpNet::from(addr)),
+                Err(_) => invalid += 1,
+            },
+        }
+    }
+    (networks, invalid)
+}
+
+fn fetch(source: &str) -> anyhow::Result<Vec<String>> {
+    if let Some(key) = source.strip_prefix("redis:") {
+        return async_std::task::block_on(async {
+            let mut conn = redis_async_conn().await?;
+            let members: Vec<String> = redis::cmd("SMEMBERS").arg(key).query_async(&mut conn).await?;
+            Ok(members)
+        });
+    }
+    let content = if source.starts_with("http://") || source.starts_with("https://") {
+        let connector = native_tls::TlsConnector::new()?;
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(30))
+            .tls_connector(Arc::new(connector))
+            .build();
+        agent.get(source).call()?.into_string()?
+    } else {
+        std::fs::read_to_string(source.strip_prefix("file://").unwrap_or(source))?
+    };
+    Ok(content.lines().map(|l| l.to_string()).collect())
+}
+
+fn rebuild(feeds: &HashMap<String, FeedState>) {
+    let mut states: Vec<&FeedState> = feeds.values().collect();
+    states.sort_by(|a, b| a.feed.id.cmp(&b.feed.id));
+    let lists: Vec<(&ReputationFeed, &[IpNet])> = states.iter().map(|s| (&s.feed, s.networks.as_slice())).collect();
+    REPUTATION.store(Arc::new(ReputationDb::build(&lists)));
+}
+
+/// refreshes the feeds that are due
+fn refresh_feeds() {
+    let now = Instant::now();
+    let due: Vec<ReputationFeed> = match FEEDS.lock() {
+        Ok(feeds) => feeds
+            .values()
+            .filter(|s| s.next_refresh <= now)
+            .map(|s| s.feed.clone())
+            .collect(),
+        Err(_) => return,
+    };
+    if due.is_empty() {
+        return;
+    }
+    // the feeds are fetched without holding the lock
+    let results: Vec<(ReputationFeed, anyhow::Result<Vec<String>>)> =
+        due.into_iter().map(|f| (f.clone(), fetch(&f.source))).collect();
+    let mut feeds = match FEEDS.lock() {
+        Ok(feeds) => feeds,
+        Err(_) => return,
+    };
+    let mut changed = false;
+    for (feed, res) in results {
+        // the feed was removed or changed by a configuration reload in the meantime
+        let state = match feeds.get_mut(&feed.id) {
+            Some(s) if s.feed.source == feed.source => s,
+            _ => continue,
+        };
+        state.stats.refreshes += 1;
+        state.stats.last_refresh = Some(Utc::now());
+        match res {
+            Ok(lines) => {
+                let (networks, invalid) = parse_entries(lines.iter().map(|l| l.as_str()));
+                state.stats.entries = networks.len();
+                state.stats.invalid_entries = invalid;
+                state.stats.last_error = None;
+                state.networks = Arc::new(networks);
+                state.next_refresh = Instant::now() + state.feed.refresh;
+                changed = true;
+            }
+            Err(rr) => {
+                // the previous list is kept, and the feed is retried sooner
+                state.stats.failures += 1;
+                state.stats.last_error = Some(rr.to_string());
+                state.next_refresh = Instant::now() + state.feed.refresh.min(Duration::from_secs(30));
+            }
+        }
+    }
+    if changed {
+        rebuild(&feeds);
+    }
+}
+
+/// replaces the feed definitions, keeping the lists of the feeds whose source did not change
+pub fn configure_feeds(logs: &mut Logs, new_feeds: Vec<ReputationFeed>) {
+    let mut feeds = match FEEDS.lock() {
+        Ok(feeds) => feeds,
+        Err(_) => {
+            logs.error("reputation feeds lock is poisoned");
+            return;
+        }
+    };
+    let mut previous = std::mem::take(&mut *feeds);
+    for feed in new_feeds {
+        let state = match previous.remove(&feed.id) {
+            Some(mut state) if state.feed.source == feed.source => {
+                state.stats.category = feed.category.clone();
+                state.feed = feed.clone();
+                state
+            }
+            _ => FeedState {
+                stats: FeedStats {
+                    category: feed.category.clone(),
+                    source: feed.source.clone(),
+                    ..FeedStats::default()
+                },
+                feed: feed.clone(),
+                networks: Arc::new(Vec::new()),
+                next_refresh: Instant::now(),
+            },
+        };
+        feeds.insert(feed.id, state);
+    }
+    rebuild(&feeds);
+    if !feeds.is_empty() {
+        REFRESHER.call_once(|| {
+            std::thread::spawn(|| loop {
+                refresh_feeds();
+                std::thread::sleep(Duration::from_secs(1));
+            });
+        });
+    }
+}
+
+/// feeds listing the address
+pub fn reputation_lookup(addr: IpAddr) -> Vec<ReputationFeed> {
+    REPUTATION.load().lookup(addr).into_iter().cloned().collect()
+}
+
+/// refresh status of the feeds, by id
+pub fn reputation_stats() -> HashMap<String, FeedStats> {
+    match FEEDS.lock() {
+        Ok(feeds) => feeds.iter().map(|(id, s)| (id.clone(), s.stats.clone())).collect(),
+        Err(_) => HashMap::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(category: &str) -> ReputationFeed {
+        ReputationFeed {
+            id: category.to_string(),
+            category: category.to_string(),
+            tags: Vec::new(),
+            source: format!("/tmp/{}.txt", category),
+            refresh: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn entries() {
+        let (networks, invalid) =
+            parse_entries("# botnet list\n1.2.3.4\n10.0.0.1/8 # whole network\n\n2001:db8::/32\nnot an ip\n".lines());
+        assert_eq!(invalid, 1);
+        assert_eq!(
+            networks,
+            vec![
+                "1.2.3.4/32".parse::<IpNet>().unwrap(),
+                "10.0.0.0/8".parse().unwrap(),
+                "2001:db8::/32".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn lookups() {
+        let botnet = feed("botnet");
+        let scanner = feed("scanner");
+        let (bnets, _) = parse_entries(["1.2.3.4", "10.0.0.0/8", "2001:db8::/32"].iter().copied());
+        let (snets, _) = parse_entries(["10.1.0.0/16", "0.0.0.0/0"].iter().copied());
+        let db = ReputationDb::build(&[(&botnet, &bnets), (&scanner, &snets[..1])]);
+        let categories = |ip: &str| -> Vec<String> {
+            db.lookup(ip.parse().unwrap())
+                .into_iter()
+                .map(|f| f.category.clone())
+                .collect()
+        };
+        assert_eq!(categories("1.2.3.4"), vec!["botnet"]);
+        assert!(categories("1.2.3.5").is_empty());
+        assert_eq!(categories("10.1.2.3"), vec!["botnet", "scanner"]);
+        assert_eq!(categories("10.2.2.3"), vec!["botnet"]);
+        assert_eq!(categories("2001:db8::1"), vec!["botnet"]);
+        assert!(categories("2001:db9::1").is_empty());
+        let db = ReputationDb::build(&[(&scanner, &snets)]);
+        assert_eq!(db.lookup("8.8.8.8".parse().unwrap()).len(), 1);
+    }
+}
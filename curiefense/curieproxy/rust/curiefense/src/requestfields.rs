@@ -3,19 +3,61 @@ use crate::interface::Location;
 use crate::utils::decoders::DecodingResult;
 use crate::utils::json::BigTableKV;
 use crate::utils::masker;
+use std::borrow::Cow;
 use std::collections::HashSet;
 use std::collections::{hash_map, HashMap};
 
+/// normalizes a field name, for case insensitive matching
+pub fn normalize_name(name: &str) -> Cow<'_, str> {
+    if name.chars().any(char::is_uppercase) {
+        Cow::Owned(name.to_lowercase())
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+/// applies a single decoding, None when it does not apply
+fn decode_step(tr: Transformation, v: &str) -> Option<String> {
+    use crate::utils::decoders::{base64dec_all_str, gunzip_base64, htmlentities, parse_unicode, urldecode_str};
+    let changed = |r: DecodingResult<String>| match r {
+        DecodingResult::Changed(ns) => Some(ns),
+        DecodingResult::NoChange => None,
+    };
+    match tr {
+        Transformation::Base64Decode => base64dec_all_str(v).ok(),
+        Transformation::UrlDecode => changed(urldecode_str(v)),
+        Transformation::DoubleUrlDecode => changed(urldecode_str(v)).map(|once| match urldecode_str(&once) {
+            DecodingResult::Changed(twice) => twice,
+            DecodingResult::NoChange => once,
+        }),
+        // this code is not robust enough, as it fails on the first entity error, and will not decode anything
+        // ie. "foo &gt&gt;" will not be decoded, but it should return "foo &gt>"
+        Transformation::HtmlEntitiesDecode => changed(htmlentities(v)),
+        Transformation::UnicodeDecode => changed(parse_unicode(v)),
+        Transformation::GzipDecode => changed(gunzip_base64(v)),
+    }
+}
+
 /// a newtype for user supplied data that can collide
 /// more or less like a HashMap, but concatenates entries with a separator on insert
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RequestField {
     pub decoding: Vec<Transformation>,
+    /// maximum number of times the decoding chain is applied
+    pub decoding_depth: usize,
+    /// the decodings that changed values, and where
+    pub decoded: HashMap<Transformation, HashSet<Location>>,
     pub fields: HashMap<String, (String, HashSet<Location>)>,
+    /// names are matched case insensitively, the raw names are kept as keys
+    pub case_insensitive: bool,
 }
 
 impl RequestField {
     fn base_add(&mut self, key: String, ds: Location, value: String) {
+        // values are dropped once the request memory budget is exhausted, the analysis is then aborted
+        if !crate::memory::charge(key.len() + value.len()) {
+            return;
+        }
         self.fields
             .entry(key)
             .and_modify(|(v, pds)| {
@@ -42,36 +84,23 @@ impl RequestField {
             None
         } else {
             let mut changed = false;
-            for tr in self.decoding.iter() {
-                match tr {
-                    Transformation::Base64Decode => {
-                        if let Ok(n) = crate::utils::decoders::base64dec_all_str(&v) {
-                            v = n;
-                            changed = true;
+            // the chain is applied again until nothing changes, to defeat nested encodings
+            for _ in 0..self.decoding_depth {
+                let mut round_changed = false;
+                for tr in self.decoding.iter() {
+                    if let Some(n) = decode_step(*tr, &v) {
+                        v = n;
+                        round_changed = true;
+                        if matches!(tr, Transformation::Base64Decode | Transformation::GzipDecode) {
                             replace_parameter = false;
                         }
-                    }
-                    Transformation::UrlDecode => {
-                        if let DecodingResult::Changed(ns) = crate::utils::decoders::urldecode_str(&v) {
-                            v = ns;
-                            changed = true;
-                        }
-                    }
-                    Transformation::HtmlEntitiesDecode => {
-                        // this code is not robust enough, as it fails on the first entity error, and will not decode anything
-                        // ie. "foo &gt&gt;" will not be decoded, but it should return "foo &gt>"
-                        if let DecodingResult::Changed(ns) = crate::utils::decoders::htmlentities(&v) {
-                            v = ns;
-                            changed = true;
-                        }
-                    }
-                    Transformation::UnicodeDecode => {
-                        if let DecodingResult::Changed(ns) = crate::utils::decoders::parse_unicode(&v) {
-                            v = ns;
-                            changed = true;
-                        }
+                        self.decoded.entry(*tr).or_default().insert(ds.clone());
                     }
                 }
+                if !round_changed {
+                    break;
+                }
+                changed = true;
             }
             if changed {
                 Some(v)
@@ -90,10 +119,18 @@ impl RequestField {
     }
 
     pub fn mask(&mut self, masking_seed: &[u8], key: &str) -> HashSet<Location> {
+        self.mask_with(key, |v| masker(masking_seed, v))
+    }
+
+    /// replaces the value of the key with the result of the masking function
+    pub fn mask_with<F>(&mut self, key: &str, f: F) -> HashSet<Location>
+    where
+        F: FnOnce(&str) -> String,
+    {
         self.fields
             .get_mut(key)
             .map(|(v, ds)| {
-                *v = masker(masking_seed, v);
+                *v = f(v);
                 ds.clone()
             })
             .unwrap_or_default()
@@ -108,12 +145,35 @@ impl RequestField {
         }
     }
 
+    fn get_entry(&self, k: &str) -> Option<&(String, HashSet<Location>)> {
+        match self.fields.get(k) {
+            Some(e) => Some(e),
+            None if self.case_insensitive => {
+                let nk = normalize_name(k);
+                self.fields
+                    .iter()
+                    .find(|(name, _)| normalize_name(name) == nk)
+                    .map(|(_, e)| e)
+            }
+            None => None,
+        }
+    }
+
     pub fn get(&self, k: &str) -> Option<&String> {
-        self.fields.get(k).map(|(v, _)| v)
+        self.get_entry(k).map(|(v, _)| v)
     }
 
     pub fn get_str(&self, k: &str) -> Option<&str> {
-        self.fields.get(k).map(|(s, _)| s.as_str())
+        self.get_entry(k).map(|(s, _)| s.as_str())
+    }
+
+    /// the form of a field name that should be used to look it up in the configuration
+    pub fn lookup_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        if self.case_insensitive {
+            normalize_name(name)
+        } else {
+            Cow::Borrowed(name)
+        }
     }
 
     pub fn len(&self) -> usize {
@@ -128,10 +188,23 @@ impl RequestField {
         self.fields.iter().map(|(k, (v, _))| (k.as_str(), v.as_str()))
     }
 
+    /// iterates over the fields, with the locations they were found in
+    pub fn iter_locations(&self) -> impl Iterator<Item = (&str, &str, &HashSet<Location>)> + '_ {
+        self.fields.iter().map(|(k, (v, l))| (k.as_str(), v.as_str(), l))
+    }
+
     pub fn new(decoding: &[Transformation]) -> Self {
+        RequestField::with_decoding(decoding, 1)
+    }
+
+    /// the decoding chain is applied up to depth times
+    pub fn with_decoding(decoding: &[Transformation], depth: usize) -> Self {
         RequestField {
             decoding: decoding.to_vec(),
+            decoding_depth: depth,
+            decoded: HashMap::new(),
             fields: HashMap::default(),
+            case_insensitive: false,
         }
     }
 
@@ -158,6 +231,8 @@ impl RequestField {
     pub fn raw_create(decoding: &[Transformation], content: &[(&str, &Location, &str)]) -> Self {
         RequestField {
             decoding: decoding.to_vec(),
+            decoding_depth: 1,
+            decoded: HashMap::new(),
             fields: content
                 .iter()
                 .map(|(k, ds, v)| {
@@ -166,6 +241,7 @@ impl RequestField {
                     (k.to_string(), (v.to_string(), hs))
                 })
                 .collect(),
+            case_insensitive: false,
         }
     }
 }
@@ -181,3 +257,38 @@ impl serde::Serialize for RequestField {
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recursive_decoding() {
+        let chain = [Transformation::UrlDecode, Transformation::HtmlEntitiesDecode];
+        // %2526lt%253B... -> %26lt%3B... -> &lt;script&gt; -> <script>
+        let value = "%2526lt%253Bscript%2526gt%253B".to_string();
+
+        let mut once = RequestField::new(&chain);
+        once.add("a".to_string(), Location::Body, value.clone());
+        assert_eq!(once.get_str("a"), Some("%26lt%3Bscript%26gt%3B"));
+
+        let mut deep = RequestField::with_decoding(&chain, 4);
+        deep.add("a".to_string(), Location::Body, value);
+        assert_eq!(deep.get_str("a"), Some("<script>"));
+        assert!(deep.decoded.contains_key(&Transformation::HtmlEntitiesDecode));
+        assert!(deep.decoded.contains_key(&Transformation::UrlDecode));
+    }
+
+    #[test]
+    fn case_insensitive_lookup() {
+        let mut field = RequestField::new(&[]);
+        field.add("SessionId".to_string(), Location::Cookies, "abc".to_string());
+        assert_eq!(field.get("sessionid"), None);
+        field.case_insensitive = true;
+        assert_eq!(field.get_str("sessionid"), Some("abc"));
+        assert_eq!(field.get_str("SESSIONID"), Some("abc"));
+        assert_eq!(field.lookup_name("SessionId"), "sessionid");
+        // the raw form is preserved
+        assert!(field.fields.contains_key("SessionId"));
+    }
+}
@@ -0,0 +1,64 @@
+//! Request identifiers.
+//!
+//! Every request gets an identifier, so that a blocking page can show a reference that is found in the logs. The
+//! identifier given by the proxy (the x-request-id attribute) is used first, then the value of the header named by
+//! CF_REQUEST_ID_HEADER (x-request-id by default). Identifiers that are too long, or that hold characters other
+//! than alphanumerics and `-_.:`, are replaced by a generated one, so that they can't be used to forge log lines.
+use lazy_static::lazy_static;
+use rand::Rng;
+use std::collections::HashMap;
+
+const MAX_LENGTH: usize = 128;
+
+lazy_static! {
+    static ref HEADER: String = std::env::var("CF_REQUEST_ID_HEADER")
+        .map(|h| h.to_lowercase())
+        .unwrap_or_else(|_| "x-request-id".to_string());
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+/// a new random identifier, 32 hexadecimal characters
+pub fn generate_request_id() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 16]>())
+}
+
+fn select_request_id(header: &str, attribute: Option<&str>, headers: &HashMap<String, String>) -> String {
+    attribute
+        .or_else(|| headers.get(header).map(|s| s.trim()))
+        .filter(|id| is_valid(id))
+        .map(|id| id.to_string())
+        .unwrap_or_else(generate_request_id)
+}
+
+/// the identifier of a request, from the proxy attribute, the request id header, or generated
+pub fn resolve_request_id(attribute: Option<&str>, headers: &HashMap<String, String>) -> String {
+    select_request_id(&HEADER, attribute, headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selection() {
+        let mut headers = HashMap::new();
+        headers.insert("x-correlation-id".to_string(), " abc-123 ".to_string());
+        assert_eq!(select_request_id("x-correlation-id", None, &headers), "abc-123");
+        assert_eq!(
+            select_request_id("x-correlation-id", Some("proxy.1"), &headers),
+            "proxy.1"
+        );
+
+        headers.insert("x-correlation-id".to_string(), "abc\nW 0µs forged".to_string());
+        let generated = select_request_id("x-correlation-id", None, &headers);
+        assert_eq!(generated.len(), 32);
+        assert_ne!(generated, select_request_id("x-correlation-id", None, &headers));
+    }
+}
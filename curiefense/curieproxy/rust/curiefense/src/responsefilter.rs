@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+
+use crate::config::contentfilter::ContentFilterEntryMatch;
+use crate::config::raw::RawActionType;
+use crate::config::responsefilter::ResponseFilterProfile;
+use crate::interface::{BlockReason, Location, Tags};
+use crate::logs::Logs;
+use crate::utils::masker;
+
+/// result of the response filter checks
+#[derive(Debug, Default)]
+pub struct RfResult {
+    /// the response should not be forwarded as is
+    pub blocking: bool,
+    pub reasons: Vec<BlockReason>,
+    /// response headers that have been masked
+    pub headers: HashMap<String, String>,
+    /// the masked body, only set when a masking signature matched
+    pub body: Option<Vec<u8>>,
+}
+
+fn header_entry<'a>(profile: &'a ResponseFilterProfile, name: &str) -> Option<&'a ContentFilterEntryMatch> {
+    profile.headers.names.get(name).or_else(|| {
+        profile
+            .headers
+            .regex
+            .iter()
+            .find(|(re, _)| re.is_match(name))
+            .map(|(_, e)| e)
+    })
+}
+
+/// Runs the response filter checks, on the status code, headers and body of an upstream response
+pub fn response_filter_check(
+    logs: &mut Logs,
+    tags: &mut Tags,
+    profile: &ResponseFilterProfile,
+    status: u32,
+    headers: &HashMap<String, String>,
+    mbody: Option<&[u8]>,
+) -> RfResult {
+    let mut out = RfResult::default();
+    let action = profile.action.atype.to_raw();
+
+    if profile.status.contains(&status) {
+        tags.insert_qualified("rf-status", &status.to_string(), Location::Response);
+        let mut denied: Vec<u32> = profile.status.iter().copied().collect();
+        denied.sort_unstable();
+        let denied: Vec<String> = denied.iter().map(|s| s.to_string()).collect();
+        out.reasons.push(BlockReason::restricted(
+            profile.id.clone(),
+            profile.name.clone(),
+            action,
+            Location::Response,
+            status.to_string(),
+            format!("status not in {}", denied.join(", ")),
+        ));
+        out.blocking = true;
+    }
+
+    if headers.len() > profile.headers.max_count {
+        out.reasons.push(BlockReason::restricted(
+            profile.id.clone(),
+            profile.name.clone(),
+            action,
+            Location::Response,
+            headers.len().to_string(),
+            profile.headers.max_count.to_string(),
+        ));
+        out.blocking = true;
+    }
+
+    for (k, v) in headers {
+        let name = k.to_lowercase();
+        if v.len() > profile.headers.max_length {
+            out.reasons.push(BlockReason::restricted(
+                profile.id.clone(),
+                profile.name.clone(),
+                action,
+                Location::ResponseHeader(name.clone()),
+                v.len().to_string(),
+                profile.headers.max_length.to_string(),
+            ));
+            out.blocking = true;
+        }
+        if let Some(entry) = header_entry(profile, &name) {
+            if let Some(re) = &entry.reg {
+                if entry.restrict && !re.matches(v) {
+                    out.reasons.push(BlockReason::restricted(
+                        profile.id.clone(),
+                        profile.name.clone(),
+                        action,
+                        Location::ResponseHeader(name.clone()),
+                        v.to_string(),
+                        re.inner.as_str().to_string(),
+                    ));
+                    out.blocking = true;
+                }
+            }
+            if entry.mask {
+                out.headers.insert(k.clone(), masker(&profile.masking_seed, v));
+            }
+        }
+    }
+
+    if let Some(body) = mbody {
+        let scanned = if body.len() > profile.max_body_size {
+            logs.debug(|| {
+                format!(
+                    "response body too large ({} bytes), only scanning {} bytes",
+                    body.len(),
+                    profile.max_body_size
+                )
+            });
+            &body[..profile.max_body_size]
+        } else {
+            body
+        };
+        // signatures work on bytes, so that binary bodies are left intact
+        let mut masked: Option<Vec<u8>> = None;
+        for sig in &profile.signatures {
+            let current = masked.as_deref().unwrap_or(scanned);
+            if !sig.re.is_match(current) {
+                continue;
+            }
+            logs.debug(|| format!("response signature matched {}", sig.id));
+            tags.insert_qualified("rf-rule-id", &sig.id, Location::ResponseBody);
+            tags.insert_qualified("rf-rule-risk", &sig.risk.to_string(), Location::ResponseBody);
+            let sigaction = if sig.mask { RawActionType::Monitor } else { action };
+            out.reasons.push(BlockReason::response_signature(
+                profile.id.clone(),
+                profile.name.clone(),
+                sigaction,
+                &sig.id,
+                sig.risk,
+            ));
+            if sig.mask {
+                let replaced = sig
+                    .re
+                    .replace_all(current, |caps: &regex::bytes::Captures| {
+                        masker(&profile.masking_seed, &String::from_utf8_lossy(&caps[0]))
+                    })
+                    .into_owned();
+                masked = Some(replaced);
+            } else {
+                out.blocking = true;
+            }
+        }
+        out.body = masked.map(|mut b| {
+            // keep the part of the body that was not scanned untouched
+            b.extend_from_slice(&body[scanned.len()..]);
+            b
+        });
+    }
+
+    if !out.reasons.is_empty() {
+        for t in &profile.tags {
+            tags.insert(t, Location::Response);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::contentfilter::ContentFilterSection;
+    use crate::config::responsefilter::ResponseSignature;
+    use crate::config::virtualtags::VirtualTags;
+    use crate::interface::SimpleAction;
+
+    fn profile() -> ResponseFilterProfile {
+        ResponseFilterProfile {
+            id: "rf".to_string(),
+            name: "response filter".to_string(),
+            status: std::iter::once(500).collect(),
+            headers: ContentFilterSection {
+                max_count: 42,
+                max_length: 1024,
+                names: HashMap::new(),
+                regex: Vec::new(),
+            },
+            signatures: vec![
+                ResponseSignature {
+                    id: "stacktrace".to_string(),
+                    risk: 4,
+                    mask: false,
+                    re: regex::bytes::Regex::new("Traceback \\(most recent call last\\)").unwrap(),
+                },
+                ResponseSignature {
+                    id: "ccn".to_string(),
+                    risk: 5,
+                    mask: true,
+                    re: regex::bytes::Regex::new("\\b4[0-9]{15}\\b").unwrap(),
+                },
+            ],
+            masking_seed: b"seed".to_vec(),
+            max_body_size: usize::MAX,
+            action: SimpleAction::default(),
+            tags: std::iter::once("data-leak".to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn clean_response() {
+        let mut logs = Logs::default();
+        let mut tags = Tags::new(&VirtualTags::default());
+        let res = response_filter_check(
+            &mut logs,
+            &mut tags,
+            &profile(),
+            200,
+            &HashMap::new(),
+            Some(b"hello world"),
+        );
+        assert!(!res.blocking);
+        assert!(res.reasons.is_empty());
+        assert!(res.body.is_none());
+        assert!(!tags.contains("data-leak"));
+    }
+
+    #[test]
+    fn blocked_status() {
+        let mut logs = Logs::default();
+        let mut tags = Tags::new(&VirtualTags::default());
+        let res = response_filter_check(&mut logs, &mut tags, &profile(), 500, &HashMap::new(), None);
+        assert!(res.blocking);
+        assert!(tags.contains("rf-status:500"));
+        assert!(tags.contains("data-leak"));
+        assert_eq!(
+            res.reasons[0].initiator.to_string(),
+            "restricted restricted[500/status not in 500]"
+        );
+    }
+
+    #[test]
+    fn masked_body() {
+        let mut logs = Logs::default();
+        let mut tags = Tags::new(&VirtualTags::default());
+        let res = response_filter_check(
+            &mut logs,
+            &mut tags,
+            &profile(),
+            200,
+            &HashMap::new(),
+            Some(b"card: 4111111111111111."),
+        );
+        assert!(!res.blocking);
+        assert!(tags.contains("rf-rule-id:ccn"));
+        let body = String::from_utf8(res.body.unwrap()).unwrap();
+        assert!(!body.contains("4111111111111111"));
+        assert!(body.starts_with("card: MASKED{"));
+        assert!(body.ends_with('.'));
+    }
+
+    #[test]
+    fn masked_binary_body() {
+        let mut logs = Logs::default();
+        let mut tags = Tags::new(&VirtualTags::default());
+        let res = response_filter_check(
+            &mut logs,
+            &mut tags,
+            &profile(),
+            200,
+            &HashMap::new(),
+            Some(b"\xff\x00 4111111111111111 \xfe\x80"),
+        );
+        let body = res.body.unwrap();
+        // the bytes around the masked card number are not altered
+        assert!(body.starts_with(b"\xff\x00 MASKED{"));
+        assert!(body.ends_with(b"} \xfe\x80"));
+    }
+
+    #[test]
+    fn leaking_body() {
+        let mut logs = Logs::default();
+        let mut tags = Tags::new(&VirtualTags::default());
+        let res = response_filter_check(
+            &mut logs,
+            &mut tags,
+            &profile(),
+            200,
+            &HashMap::new(),
+            Some(b"Traceback (most recent call last):\n  File \"x.py\""),
+        );
+        assert!(res.blocking);
+        assert_eq!(res.reasons.len(), 1);
+        assert_eq!(res.reasons[0].location, Location::ResponseBody);
+    }
+}
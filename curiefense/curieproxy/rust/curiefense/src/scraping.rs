@@ -0,0 +1,247 @@
+//! Scraping detection, from the diversity of the content accessed by a session.
+//!
+//! When enabled on a security policy entry, each request of a session updates, in redis:
+//!
+//!  * its request count, and the number of static resources (stylesheets, scripts, images, fonts) it requested,
+//!  * a HyperLogLog of the distinct paths it requested,
+//!  * the deepest page it reached, from the pagination arguments (`page` and `p` by default).
+//!
+//! Counts are kept per half window, the statistics covering the current and previous halves, so that they slide
+//! with time. Once a session has sent `min_requests` requests, three signals are computed:
+//!
+//!  * path-diversity, when the ratio of distinct paths to requests exceeds `max_path_diversity`,
+//!  * pagination, when the deepest page exceeds `max_pagination_depth`,
+//!  * no-static, when the ratio of static resources falls below `min_static_ratio`, as scrapers rarely load them.
+//!
+//! Each signal is tagged `scraper:<signal>`, and the session is tagged `scraper:likely` when at least `min_signals`
+//! are present. These tags are set before the session stage, so that global filters and limits can challenge or
+//! throttle scrapers.
+use crate::analyze::APhase0;
+use crate::clientstate::{Pending, StateQuery, StateReplies};
+use crate::config::raw::RawScraping;
+use crate::failure::{dependency_failed, Dependency};
+use crate::interface::Location;
+use crate::logs::Logs;
+use crate::redis::REDIS_KEY_PREFIX;
+use crate::utils::RequestInfo;
+
+/// tag of the scraping signals of the session, qualified with the signal name, or with likely
+pub const SCRAPER_TAG: &str = "scraper";
+pub const LIKELY_SCRAPER_TAG: &str = "scraper:likely";
+
+/// KEYS: current counts, current paths, previous counts, previous paths
+/// ARGV: path, static (0 or 1), page, expiration (s)
+pub const SCRAPING_SCRIPT: &str = r#"
+redis.call('HINCRBY', KEYS[1], 'requests', 1)
+redis.call('HINCRBY', KEYS[1], 'static', tonumber(ARGV[2]))
+local page = tonumber(ARGV[3])
+local deepest = tonumber(redis.call('HGET', KEYS[1], 'page')) or 0
+if page > deepest then
+  redis.call('HSET', KEYS[1], 'page', page)
+end
+redis.call('PFADD', KEYS[2], ARGV[1])
+redis.call('EXPIRE', KEYS[1], ARGV[4])
+redis.call('EXPIRE', KEYS[2], ARGV[4])
+local cur = redis.call('HMGET', KEYS[1], 'requests', 'static', 'page')
+local prev = redis.call('HMGET', KEYS[3], 'requests', 'static', 'page')
+local paths = redis.call('PFCOUNT', KEYS[2], KEYS[4])
+return {
+  tonumber(cur[1]) + (tonumber(prev[1]) or 0),
+  tonumber(cur[2]) + (tonumber(prev[2]) or 0),
+  paths,
+  math.max(tonumber(cur[3]) or 0, tonumber(prev[3]) or 0)
+}
+"#;
+
+const DEFAULT_STATIC_EXTENSIONS: [&str; 12] = [
+    "css", "js", "png", "jpg", "jpeg", "gif", "svg", "ico", "webp", "woff", "woff2", "ttf",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScrapingSettings {
+    /// seconds
+    pub window: u64,
+    pub min_requests: u64,
+    pub max_path_diversity: f64,
+    pub max_pagination_depth: u64,
+    pub min_static_ratio: f64,
+    pub min_signals: usize,
+    pub page_args: Vec<String>,
+    /// lowercase, without the dot
+    pub static_extensions: Vec<String>,
+}
+
+/// statistics of a session over the window
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ScrapingStats {
+    pub requests: u64,
+    pub static_requests: u64,
+    pub distinct_paths: u64,
+    pub deepest_page: u64,
+}
+
+impl ScrapingSettings {
+    pub fn resolve(raw: RawScraping) -> Self {
+        let or_default = |v: Vec<String>, default: &[&str]| {
+            if v.is_empty() {
+                default.iter().map(|s| s.to_string()).collect()
+            } else {
+                v
+            }
+        };
+        ScrapingSettings {
+            window: raw.window_seconds.unwrap_or(600).max(2),
+            min_requests: raw.min_requests.unwrap_or(20).max(1),
+            max_path_diversity: raw.max_path_diversity.unwrap_or(0.8),
+            max_pagination_depth: raw.max_pagination_depth.unwrap_or(20),
+            min_static_ratio: raw.min_static_ratio.unwrap_or(0.05),
+            min_signals: raw.min_signals.unwrap_or(2).max(1),
+            page_args: or_default(raw.page_args, &["page", "p"]),
+            static_extensions: or_default(raw.static_extensions, &DEFAULT_STATIC_EXTENSIONS)
+                .iter()
+                .map(|e| e.trim_start_matches('.').to_lowercase())
+                .collect(),
+        }
+    }
+
+    pub fn is_static(&self, path: &str) -> bool {
+        let last = path.rsplit('/').next().unwrap_or("");
+        match last.rsplit_once('.') {
+            None => false,
+            Some((_, ext)) => self.static_extensions.contains(&ext.to_lowercase()),
+        }
+    }
+
+    /// the page number of the request, 0 when it has no pagination argument
+    pub fn page(&self, reqinfo: &RequestInfo) -> u64 {
+        self.page_args
+            .iter()
+            .filter_map(|a| reqinfo.rinfo.qinfo.args.get_str(a))
+            .filter_map(|v| v.trim().parse::<u64>().ok())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// the scraping signals of a session
+    pub fn signals(&self, stats: &ScrapingStats) -> Vec<&'static str> {
+        let mut out = Vec::new();
+        if stats.requests < self.min_requests {
+            return out;
+        }
+        let requests = stats.requests as f64;
+        if stats.distinct_paths as f64 / requests > self.max_path_diversity {
+            out.push("path-diversity");
+        }
+        if stats.deepest_page > self.max_pagination_depth {
+            out.push("pagination");
+        }
+        if (stats.static_requests as f64) / requests < self.min_static_ratio {
+            out.push("no-static");
+        }
+        out
+    }
+}
+
+/// adds the script that updates the session content access statistics
+pub fn query_scraping(p0: &APhase0, query: &mut StateQuery) -> Option<Pending> {
+    let reqinfo = &p0.reqinfo;
+    let settings = reqinfo.rinfo.secpolicy.scraping.as_ref()?;
+    let is_static = settings.is_static(&reqinfo.rinfo.qinfo.normalized.path);
+    let page = settings.page(reqinfo);
+    let half = settings.window / 2;
+    let bucket = reqinfo.timestamp.timestamp().max(0) as u64 / half;
+    let prefix = format!(
+        "{}{}scraping_{:X}",
+        *REDIS_KEY_PREFIX,
+        reqinfo.rinfo.secpolicy.counters.namespace,
+        md5::compute(&reqinfo.session)
+    );
+    Some(query.add(|pipe| {
+        pipe.cmd("EVAL")
+            .arg(SCRAPING_SCRIPT)
+            .arg(4)
+            .arg(format!("{}_{}", prefix, bucket))
+            .arg(format!("{}_{}_paths", prefix, bucket))
+            .arg(format!("{}_{}", prefix, bucket.saturating_sub(1)))
+            .arg(format!("{}_{}_paths", prefix, bucket.saturating_sub(1)))
+            .arg(&reqinfo.rinfo.qinfo.normalized.path)
+            .arg(if is_static { 1 } else { 0 })
+            .arg(page)
+            .arg(settings.window);
+        1
+    }))
+}
+
+fn scraping_stats(replies: &StateReplies, pending: Pending) -> anyhow::Result<ScrapingStats> {
+    let ((requests, static_requests, distinct_paths, deepest_page),): ((u64, u64, u64, u64),) = replies.get(pending)?;
+    Ok(ScrapingStats {
+        requests,
+        static_requests,
+        distinct_paths,
+        deepest_page,
+    })
+}
+
+/// tags the scraping signals of the session, and likely scrapers
+pub fn apply_scraping(logs: &mut Logs, p0: &mut APhase0, pending: Pending, replies: &StateReplies) {
+    let secpolicy = p0.reqinfo.rinfo.secpolicy.clone();
+    let settings = match &secpolicy.scraping {
+        None => return,
+        Some(s) => s,
+    };
+    match scraping_stats(replies, pending) {
+        Ok(stats) => {
+            logs.debug(|| format!("scraping statistics: {:?}", stats));
+            let signals = settings.signals(&stats);
+            for signal in &signals {
+                p0.itags.insert_qualified(SCRAPER_TAG, signal, Location::Request);
+            }
+            if signals.len() >= settings.min_signals {
+                p0.itags.insert(LIKELY_SCRAPER_TAG, Location::Request);
+            }
+        }
+        Err(rr) => {
+            logs.error(|| format!("scraping detection: {}", rr));
+            p0.itags.insert("scraping-degraded", Location::Request);
+            dependency_failed(&mut p0.itags, Dependency::Redis);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signals() {
+        let settings = ScrapingSettings::resolve(RawScraping {
+            static_extensions: vec![".CSS".to_string(), "png".to_string()],
+            ..Default::default()
+        });
+        assert!(settings.is_static("/assets/site.css"));
+        assert!(settings.is_static("/img/logo.PNG"));
+        assert!(!settings.is_static("/products.v2/list"));
+        assert!(!settings.is_static("/app.js"));
+
+        let browsing = ScrapingStats {
+            requests: 40,
+            static_requests: 25,
+            distinct_paths: 20,
+            deepest_page: 3,
+        };
+        assert!(settings.signals(&browsing).is_empty());
+        let scraper = ScrapingStats {
+            requests: 40,
+            static_requests: 0,
+            distinct_paths: 38,
+            deepest_page: 37,
+        };
+        assert_eq!(
+            settings.signals(&scraper),
+            vec!["path-diversity", "pagination", "no-static"]
+        );
+        // not enough requests
+        let young = ScrapingStats { requests: 5, ..scraper };
+        assert!(settings.signals(&young).is_empty());
+    }
+}
@@ -1,13 +1,15 @@
 use std::sync::Arc;
 
 use crate::config::hostmap::{HostMap, SecurityPolicy};
+use crate::config::hostrules::HostMatch;
 use crate::config::Config;
 use crate::logs::Logs;
 
 /// finds the securitypolicy matching a given request, based on the configuration
 /// there are cases where default values do not exist (even though the UI should prevent that)
 ///
-/// note that the url is matched using the url-decoded path!
+/// note that the url should be normalized with `utils::url::normalize_uri`, so that encoded dot segments can't
+/// be used to select another entry
 ///
 /// returns the matching security policy, along with the name and id of the selected host map
 pub fn match_securitypolicy<'a>(
@@ -17,31 +19,74 @@ pub fn match_securitypolicy<'a>(
     logs: &mut Logs,
     selected_secpol: Option<&str>,
 ) -> Option<Arc<SecurityPolicy>> {
-    // find the first matching hostmap, or use the default, if it exists
-    let get_hostmap = || {
-        cfg.securitypolicies
-            .iter()
-            .find(|e| e.matches(host))
-            .map(|m| &m.inner)
-            .or(cfg.default.as_ref())
+    route_securitypolicy(host, None, path, None, cfg, logs, selected_secpol).map(|(secpol, _)| secpol)
+}
+
+/// finds the hostmap of a host: the host rules and the match patterns of the security policies are evaluated for the
+/// host header first, then the host rules for the SNI, the fallback policy, and the default one
+///
+/// the SNI is only used when the host header matches nothing, so that a client can't select a lenient policy with
+/// the SNI while the request is routed by its host header
+fn route_host<'a>(host: &str, sni: Option<&str>, cfg: &'a Config) -> Option<(&'a HostMap, HostMatch)> {
+    let by_rule = |name: &str| {
+        cfg.host_rules
+            .route(name)
+            .and_then(|(id, kind)| cfg.securitypolicies_map.get(id).map(|h| (h, kind)))
     };
-    let hostmap: &HostMap = match selected_secpol {
-        None => get_hostmap()?,
+    by_rule(host)
+        .or_else(|| {
+            cfg.securitypolicies
+                .iter()
+                .find(|e| e.matches(host))
+                .map(|m| (&m.inner, HostMatch::Pattern))
+        })
+        .or_else(|| sni.and_then(by_rule))
+        .or_else(|| {
+            cfg.host_rules
+                .fallback
+                .as_ref()
+                .and_then(|id| cfg.securitypolicies_map.get(id))
+                .map(|h| (h, HostMatch::Fallback))
+        })
+        .or_else(|| cfg.default.as_ref().map(|h| (h, HostMatch::Default)))
+}
+
+/// same as match_securitypolicy, also returning how the hostmap was selected, using the SNI for routing, and the
+/// method to select method scoped entries
+pub fn route_securitypolicy(
+    host: &str,
+    sni: Option<&str>,
+    path: &str,
+    method: Option<&str>,
+    cfg: &Config,
+    logs: &mut Logs,
+    selected_secpol: Option<&str>,
+) -> Option<(Arc<SecurityPolicy>, HostMatch)> {
+    let (hostmap, host_match): (&HostMap, HostMatch) = match selected_secpol {
+        None => route_host(host, sni, cfg)?,
         Some(secpolid) => match cfg.securitypolicies_map.get(secpolid) {
-            Some(p) => p,
+            Some(p) => (p, HostMatch::Selected),
             None => {
                 logs.error(|| format!("Can't find secpol id {}", secpolid));
-                get_hostmap()?
+                route_host(host, sni, cfg)?
             }
         },
     };
     logs.debug(|| format!("Selected hostmap {}", hostmap.name));
     // find the first matching securitypolicy, or use the default, if it exists
-    let securitypolicy: Arc<SecurityPolicy> = match hostmap
-        .entries
+    // the most specific path template whose entry accepts the method is only selected when no regular expression entry
+    // coming before it matches
+    let template = hostmap
+        .templates
+        .get_all(path)
+        .flat_map(|shared| shared.iter())
+        .find(|t| t.policy.accepts_method(method));
+    let before = template.map(|t| t.rank).unwrap_or(hostmap.entries.len());
+    let securitypolicy: Arc<SecurityPolicy> = match hostmap.entries[..before]
         .iter()
-        .find(|e| e.matches(path))
+        .find(|e| e.matches(path) && e.inner.accepts_method(method))
         .map(|m| &m.inner)
+        .or_else(|| template.map(|t| &t.policy))
         .or(hostmap.default.as_ref())
     {
         None => {
@@ -51,5 +96,149 @@ pub fn match_securitypolicy<'a>(
         Some(x) => x.clone(),
     };
     logs.debug(|| format!("Selected hostmap entry {}", securitypolicy.entry.id));
-    Some(securitypolicy)
+    Some((securitypolicy, host_match))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::hostmap::{PolicyId, TemplateEntry};
+    use crate::config::matchers::Matching;
+
+    fn hostmap(name: &str) -> HostMap {
+        HostMap {
+            name: name.to_string(),
+            entries: Vec::new(),
+            templates: Default::default(),
+            default: Some(Arc::new(SecurityPolicy::default())),
+        }
+    }
+
+    #[test]
+    fn sni_routing() {
+        let mut cfg = Config::empty();
+        cfg.securitypolicies_map
+            .insert("lenient".to_string(), hostmap("lenient"));
+        cfg.host_rules.add("lenient.example.com", "lenient").unwrap();
+        cfg.securitypolicies
+            .push(Matching::from_str("^strict\\.example\\.com$", hostmap("strict")).unwrap());
+        let route = |host: &str, sni: Option<&str>| route_host(host, sni, &cfg).map(|(h, m)| (h.name.clone(), m));
+        // domain fronting, the host header selects the policy
+        assert_eq!(
+            route("strict.example.com", Some("lenient.example.com")),
+            Some(("strict".to_string(), HostMatch::Pattern))
+        );
+        assert_eq!(
+            route("10.0.0.1", Some("lenient.example.com")),
+            Some(("lenient".to_string(), HostMatch::Exact))
+        );
+        assert_eq!(route("10.0.0.1", None), None);
+    }
+
+    fn entry(id: &str) -> Arc<SecurityPolicy> {
+        Arc::new(SecurityPolicy {
+            entry: PolicyId {
+                id: id.to_string(),
+                name: id.to_string(),
+            },
+            ..SecurityPolicy::default()
+        })
+    }
+
+    #[test]
+    fn templates_entry_order() {
+        let mut map = hostmap("site");
+        // the regular expression entry comes before the template, the second one after it
+        map.entries
+            .push(Matching::from_str("^/api/v1/users$", entry("users")).unwrap());
+        map.entries
+            .push(Matching::from_str("^/static/", entry("static")).unwrap());
+        map.templates
+            .get_or_insert_with(&"/**".parse().unwrap(), Vec::new)
+            .push(TemplateEntry {
+                rank: 1,
+                policy: entry("all"),
+            });
+        let mut cfg = Config::empty();
+        cfg.securitypolicies_map.insert("site".to_string(), map);
+        let route = |path: &str| {
+            match_securitypolicy("example.com", path, &cfg, &mut Logs::default(), Some("site"))
+                .map(|p| p.entry.id.clone())
+        };
+        assert_eq!(route("/api/v1/users"), Some("users".to_string()));
+        assert_eq!(route("/api/v1/groups"), Some("all".to_string()));
+        assert_eq!(route("/static/logo.png"), Some("all".to_string()));
+    }
+
+    /// a host map with template entries, the ids of the entries being their templates, optionally scoped to a method
+    fn template_map(templates: &[(&str, Option<&str>)]) -> Config {
+        let mut map = hostmap("site");
+        for (tpl, method) in templates {
+            let policy = SecurityPolicy {
+                entry: PolicyId {
+                    id: tpl.to_string(),
+                    name: tpl.to_string(),
+                },
+                methods: method.map(|m| vec![m.to_string()]),
+                ..SecurityPolicy::default()
+            };
+            map.templates
+                .get_or_insert_with(&tpl.parse().unwrap(), Vec::new)
+                .push(TemplateEntry {
+                    rank: 0,
+                    policy: Arc::new(policy),
+                });
+        }
+        let mut cfg = Config::empty();
+        cfg.securitypolicies_map.insert("site".to_string(), map);
+        cfg
+    }
+
+    #[test]
+    fn templates_method_fallback() {
+        let cfg = template_map(&[("/api/x", Some("POST")), ("/api/**", None)]);
+        let route = |path: &str, method: &str| {
+            route_securitypolicy(
+                "example.com",
+                None,
+                path,
+                Some(method),
+                &cfg,
+                &mut Logs::default(),
+                Some("site"),
+            )
+            .map(|(p, _)| p.entry.id.clone())
+        };
+        assert_eq!(route("/api/x", "POST"), Some("/api/x".to_string()));
+        // the most specific template does not accept GET, the next one is selected
+        assert_eq!(route("/api/x", "GET"), Some("/api/**".to_string()));
+        assert_eq!(route("/api/y", "POST"), Some("/api/**".to_string()));
+    }
+
+    #[test]
+    fn method_scoped_templates_order() {
+        let scoped = ("/api/x", Some("POST"));
+        let unscoped = ("/api/**", None);
+        // the configured order of the entries does not change the selection
+        for templates in [[scoped, unscoped], [unscoped, scoped]] {
+            let cfg = template_map(&templates);
+            let route = |method: Option<&str>| {
+                route_securitypolicy(
+                    "example.com",
+                    None,
+                    "/api/x",
+                    method,
+                    &cfg,
+                    &mut Logs::default(),
+                    Some("site"),
+                )
+                .map(|(p, _)| p.entry.id.clone())
+            };
+            assert_eq!(route(Some("post")), Some("/api/x".to_string()));
+            assert_eq!(route(Some("GET")), Some("/api/**".to_string()));
+            assert_eq!(route(Some("DELETE")), Some("/api/**".to_string()));
+            // without a method, all the entries accept the request
+            assert_eq!(route(None), Some("/api/x".to_string()));
+        }
+    }
 }
@@ -0,0 +1,351 @@
+//! Session tracking.
+//!
+//! When enabled on a security policy entry, requests are grouped into sessions, identified by the first
+//! available configured source (a cookie, a header, the sub claim of a verified JWT, or the IP and user agent). The
+//! session state (first seen date, request count, content filter hits and recently seen tags) is kept in
+//! redis, and exposed to the rest of the analysis as tags:
+//!
+//!  * session-new, for the first request of a session,
+//!  * session-age-over-N, session-requests-over-N and session-cf-hits-over-N, for the configured thresholds,
+//!  * session-seen:TAG, for the tracked tags that were seen in a previous request of the session.
+//!
+//! The session state is loaded before flows and limits are computed, so they can use these tags. Global
+//! filters are evaluated earlier, so the ones referring to session tags are deferred to the session stage.
+use sha2::{Digest, Sha224};
+
+use crate::analyze::APhase0;
+use crate::config::raw::RawSessionTracking;
+use crate::failure::{dependency_failed, Dependency};
+use crate::interface::{stronger_decision, tagify, AnalyzeResult, Initiator, Location, Tags};
+use crate::logs::Logs;
+use crate::redis::{redis_async_conn, REDIS_KEY_PREFIX};
+use crate::tagging::apply_globalfilters;
+use crate::utils::RequestInfo;
+
+/// prefix of the tags describing the session state, its age, request count, content filter hits and seen tags
+pub const SESSION_TAG_PREFIX: &str = "session-";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionSource {
+    Cookie(String),
+    Header(String),
+    JwtSub,
+    IpUserAgent,
+}
+
+impl SessionSource {
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        match s.split_once(':') {
+            Some(("cookie", name)) if !name.is_empty() => Ok(SessionSource::Cookie(name.to_string())),
+            Some(("header", name)) if !name.is_empty() => Ok(SessionSource::Header(name.to_lowercase())),
+            None if s == "jwt-sub" => Ok(SessionSource::JwtSub),
+            None if s == "ip-ua" => Ok(SessionSource::IpUserAgent),
+            _ => Err(anyhow::anyhow!("invalid session source {:?}", s)),
+        }
+    }
+
+    fn select(&self, reqinfo: &RequestInfo) -> Option<String> {
+        let nonempty = |s: Option<&str>| s.filter(|v| !v.is_empty()).map(|v| v.to_string());
+        match self {
+            SessionSource::Cookie(name) => nonempty(reqinfo.cookies.get_str(name)).map(|v| format!("cookie:{}", v)),
+            SessionSource::Header(name) => nonempty(reqinfo.headers.get_str(name)).map(|v| format!("header:{}", v)),
+            // the jwt-claim-sub argument is set even when the token is forged
+            SessionSource::JwtSub => nonempty(reqinfo.jwt_subject.as_deref()).map(|v| format!("jwt:{}", v)),
+            SessionSource::IpUserAgent => Some(format!(
+                "ip-ua:{}|{}",
+                reqinfo.rinfo.geoip.ipstr,
+                reqinfo.headers.get_str("user-agent").unwrap_or_default()
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionSettings {
+    pub sources: Vec<SessionSource>,
+    pub ttl: u64,
+    pub tracked_tags: Vec<String>,
+    pub max_recent_tags: usize,
+    pub age_thresholds: Vec<u64>,
+    pub request_thresholds: Vec<u64>,
+    pub cf_hit_thresholds: Vec<u64>,
+}
+
+impl SessionSettings {
+    pub fn resolve(logs: &mut Logs, raw: RawSessionTracking) -> Self {
+        let mut sources = Vec::new();
+        for s in raw.sources {
+            match SessionSource::parse(&s) {
+                Ok(source) => sources.push(source),
+                Err(rr) => logs.error(|| rr.to_string()),
+            }
+        }
+        if sources.is_empty() {
+            sources.push(SessionSource::IpUserAgent);
+        }
+        SessionSettings {
+            sources,
+            ttl: raw.ttl_seconds.unwrap_or(1800).max(1),
+            tracked_tags: raw.tracked_tags.iter().map(|t| tagify(t)).collect(),
+            max_recent_tags: raw.max_recent_tags.unwrap_or(16).max(1),
+            age_thresholds: raw.age_thresholds,
+            request_thresholds: raw.request_thresholds,
+            cf_hit_thresholds: raw.cf_hit_thresholds,
+        }
+    }
+}
+
+/// state of a session, as stored before the current request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionState {
+    /// age in seconds
+    pub age: u64,
+    /// number of requests, including the current one
+    pub requests: u64,
+    pub cf_hits: u64,
+    pub recent_tags: Vec<String>,
+}
+
+impl SessionState {
+    pub fn tag(&self, settings: &SessionSettings, tags: &mut Tags) {
+        if self.requests <= 1 {
+            tags.insert("session-new", Location::Request);
+        }
+        let thresholds = [
+            ("session-age-over", self.age, &settings.age_thresholds),
+            ("session-requests-over", self.requests, &settings.request_thresholds),
+            ("session-cf-hits-over", self.cf_hits, &settings.cf_hit_thresholds),
+        ];
+        for (name, value, limits) in thresholds.iter() {
+            for limit in limits.iter().filter(|l| *value > **l) {
+                tags.insert(&format!("{}-{}", name, limit), Location::Request);
+            }
+        }
+        for t in &self.recent_tags {
+            tags.insert_qualified("session-seen", t, Location::Request);
+        }
+    }
+}
+
+/// computes the session identifier, hashed so that it can't be traced back to the request content
+pub fn session_id(settings: &SessionSettings, reqinfo: &RequestInfo) -> Option<String> {
+    let raw = settings.sources.iter().find_map(|s| s.select(reqinfo))?;
+    let mut hasher = Sha224::new();
+    hasher.update(&reqinfo.rinfo.secpolicy.content_filter_profile.masking_seed);
+    hasher.update(raw.as_bytes());
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+/// the keys share a hash tag, as they are updated in the same transactions
+fn session_keys(namespace: &str, id: &str) -> (String, String) {
+    let key = format!("{}{}session_{{{}}}", *REDIS_KEY_PREFIX, namespace, id);
+    let tkey = format!("{}_tags", key);
+    (key, tkey)
+}
+
+/// registers the request in its session, and returns the session state
+async fn query_session(
+    settings: &SessionSettings,
+    namespace: &str,
+    id: &str,
+    now: i64,
+) -> anyhow::Result<SessionState> {
+    let (key, tkey) = session_keys(namespace, id);
+    let mut conn = redis_async_conn().await?;
+    let (requests, first_seen, cf_hits, recent_tags): (u64, i64, Option<u64>, Vec<String>) = redis::pipe()
+        .atomic()
+        .cmd("HSETNX")
+        .arg(&key)
+        .arg("first_seen")
+        .arg(now)
+        .ignore()
+        .cmd("HINCRBY")
+        .arg(&key)
+        .arg("requests")
+        .arg(1)
+        .cmd("HGET")
+        .arg(&key)
+        .arg("first_seen")
+        .cmd("HGET")
+        .arg(&key)
+        .arg("cf_hits")
+        .cmd("EXPIRE")
+        .arg(&key)
+        .arg(settings.ttl)
+        .ignore()
+        .cmd("LRANGE")
+        .arg(&tkey)
+        .arg(0)
+        .arg(settings.max_recent_tags as i64 - 1)
+        .query_async(&mut conn)
+        .await?;
+    Ok(SessionState {
+        age: now.saturating_sub(first_seen).max(0) as u64,
+        requests,
+        cf_hits: cf_hits.unwrap_or(0),
+        recent_tags,
+    })
+}
+
+/// loads the session state, tags the request accordingly, and evaluates the deferred global filters
+pub async fn analyze_session(logs: &mut Logs, mut p0: APhase0) -> APhase0 {
+    let secpolicy = p0.reqinfo.rinfo.secpolicy.clone();
+    if let Some(settings) = &secpolicy.session_tracking {
+        if let Some(id) = session_id(settings, &p0.reqinfo) {
+            let now = p0.reqinfo.timestamp.timestamp();
+            match query_session(settings, &secpolicy.counters.namespace, &id, now).await {
+                Ok(state) => state.tag(settings, &mut p0.itags),
+                Err(rr) => {
+                    logs.error(|| format!("session tracking: {}", rr));
+                    p0.itags.insert("session-degraded", Location::Request);
+                    dependency_failed(&mut p0.itags, Dependency::Redis);
+                }
+            }
+        }
+    }
+
+    if !p0.session_filters.is_empty() {
+        let (decision, _) = apply_globalfilters(&p0.reqinfo, &mut p0.itags, p0.session_filters.iter());
+        p0.globalfilter_dec = stronger_decision(p0.globalfilter_dec, decision);
+    }
+    p0
+}
+
+/// updates the session state with the outcome of the analysis
+pub async fn record_session(logs: &mut Logs, result: &AnalyzeResult) {
+    let secpolicy = &result.rinfo.rinfo.secpolicy;
+    let settings = match &secpolicy.session_tracking {
+        None => return,
+        Some(s) => s,
+    };
+    let id = match session_id(settings, &result.rinfo) {
+        None => return,
+        Some(id) => id,
+    };
+    let cf_hit = result
+        .decision
+        .reasons
+        .iter()
+        .any(|r| matches!(r.initiator, Initiator::ContentFilter { .. }));
+    let seen: Vec<&String> = settings
+        .tracked_tags
+        .iter()
+        .filter(|t| result.tags.contains(t))
+        .collect();
+    if !cf_hit && seen.is_empty() {
+        return;
+    }
+
+    let (key, tkey) = session_keys(&secpolicy.counters.namespace, &id);
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    if cf_hit {
+        pipe.cmd("HINCRBY").arg(&key).arg("cf_hits").arg(1).ignore();
+    }
+    if !seen.is_empty() {
+        for t in seen {
+            pipe.cmd("LREM").arg(&tkey).arg(0).arg(t).ignore();
+            pipe.cmd("LPUSH").arg(&tkey).arg(t).ignore();
+        }
+        pipe.cmd("LTRIM")
+            .arg(&tkey)
+            .arg(0)
+            .arg(settings.max_recent_tags as i64 - 1)
+            .ignore();
+        pipe.cmd("EXPIRE").arg(&tkey).arg(settings.ttl).ignore();
+    }
+    let res: anyhow::Result<()> = async {
+        let mut conn = redis_async_conn().await?;
+        pipe.query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+    .await;
+    if let Err(rr) = res {
+        logs.error(|| format!("session tracking: {}", rr));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::virtualtags::VirtualTags;
+    use crate::testutils::request_info;
+
+    fn mk_reqinfo(headers: &[(&str, &str)]) -> RequestInfo {
+        request_info(&[("authority", "x.com")], headers)
+    }
+
+    fn mk_settings(sources: &[&str]) -> SessionSettings {
+        let mut logs = Logs::default();
+        SessionSettings::resolve(
+            &mut logs,
+            RawSessionTracking {
+                sources: sources.iter().map(|s| s.to_string()).collect(),
+                age_thresholds: vec![60, 3600],
+                request_thresholds: vec![10],
+                cf_hit_thresholds: vec![0],
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn identifier_sources() {
+        let settings = mk_settings(&["cookie:sid", "header:X-Session", "bogus"]);
+        assert_eq!(
+            settings.sources,
+            vec![
+                SessionSource::Cookie("sid".to_string()),
+                SessionSource::Header("x-session".to_string())
+            ]
+        );
+        let by_cookie = mk_reqinfo(&[("cookie", "sid=abc"), ("x-session", "abc")]);
+        let by_header = mk_reqinfo(&[("x-session", "abc")]);
+        let anonymous = mk_reqinfo(&[]);
+        let id = session_id(&settings, &by_cookie).unwrap();
+        assert_eq!(id.len(), 56);
+        // the source is part of the identifier
+        assert_ne!(Some(id), session_id(&settings, &by_header));
+        assert_eq!(session_id(&settings, &anonymous), None);
+
+        let fallback = mk_settings(&[]);
+        assert_eq!(fallback.sources, vec![SessionSource::IpUserAgent]);
+        assert!(session_id(&fallback, &anonymous).is_some());
+    }
+
+    #[test]
+    fn verified_subjects() {
+        let settings = mk_settings(&["jwt-sub"]);
+        assert_eq!(settings.sources, vec![SessionSource::JwtSub]);
+        // the claim of a token whose signature was not verified
+        let mut forged = mk_reqinfo(&[]);
+        forged
+            .rinfo
+            .qinfo
+            .args
+            .add("jwt-claim-sub".to_string(), Location::Request, "alice".to_string());
+        assert_eq!(session_id(&settings, &forged), None);
+        let mut verified = mk_reqinfo(&[]);
+        verified.jwt_subject = Some("alice".to_string());
+        assert!(session_id(&settings, &verified).is_some());
+    }
+
+    #[test]
+    fn state_tags() {
+        let settings = mk_settings(&[]);
+        let state = SessionState {
+            age: 120,
+            requests: 1,
+            cf_hits: 2,
+            recent_tags: vec!["cf-rule-id:100".to_string()],
+        };
+        let mut tags = Tags::new(&VirtualTags::default());
+        state.tag(&settings, &mut tags);
+        assert!(tags.contains("session-new"));
+        assert!(tags.contains("session-age-over-60"));
+        assert!(!tags.contains("session-age-over-3600"));
+        assert!(!tags.contains("session-requests-over-10"));
+        assert!(tags.contains("session-cf-hits-over-0"));
+        assert!(tags.contains("session-seen:cf-rule-id:100"));
+    }
+}
@@ -0,0 +1,244 @@
+//! SIEM export formats.
+//!
+//! Analysis results can be serialized as syslog CEF lines (ArcSight, QRadar) or as Elastic Common Schema documents,
+//! so that they can be ingested without a translation layer. The format is selected per log sink, the default
+//! being the native curiefense log format, produced by `interface::jsonlog`.
+use chrono::SecondsFormat;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::config::raw::RawActionType;
+use crate::interface::{BlockReason, Decision, Tags};
+use crate::utils::RequestInfo;
+
+const VENDOR: &str = "Curiefense";
+const PRODUCT: &str = "Curiefense";
+const ECS_VERSION: &str = "8.11.0";
+
+/// output format of a log sink
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// the native curiefense log
+    #[default]
+    Curiefense,
+    /// ArcSight Common Event Format
+    Cef,
+    /// Elastic Common Schema
+    Ecs,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "curiefense" | "native" => Ok(LogFormat::Curiefense),
+            "cef" => Ok(LogFormat::Cef),
+            "ecs" => Ok(LogFormat::Ecs),
+            _ => Err(anyhow::anyhow!("invalid log format {}", s)),
+        }
+    }
+}
+
+fn action_name(decision: &Decision) -> &'static str {
+    if decision.is_blocking() {
+        "block"
+    } else if decision.reasons.iter().any(|r| r.action == RawActionType::Monitor) {
+        "monitor"
+    } else {
+        "pass"
+    }
+}
+
+/// the reason reported as the rule of the event, the first final one, or the first one
+fn main_reason(decision: &Decision) -> Option<&BlockReason> {
+    decision
+        .reasons
+        .iter()
+        .find(|r| r.action.is_final())
+        .or_else(|| decision.reasons.first())
+}
+
+pub(crate) fn sorted_tags(tags: &Tags) -> Vec<&str> {
+    let mut names: Vec<&str> = tags.inner().keys().map(|s| s.as_str()).collect();
+    names.sort_unstable();
+    names
+}
+
+/// escapes a CEF header field
+fn cef_header(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('|', "\\|").replace(['\r', '\n'], " ")
+}
+
+/// escapes a CEF extension value
+fn cef_value(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('=', "\\=")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+}
+
+/// the analysis result, as a CEF line
+pub fn cef_record(decision: &Decision, rinfo: &RequestInfo, tags: &Tags, status: Option<u32>) -> String {
+    let reason = main_reason(decision);
+    let action = action_name(decision);
+    let (signature, name) = match reason {
+        Some(r) => (r.id.clone(), r.to_string()),
+        None => ("pass".to_string(), "request passed".to_string()),
+    };
+    let severity = match action {
+        "block" => 8,
+        "monitor" => 5,
+        _ => 1,
+    };
+    let secpol = &rinfo.rinfo.secpolicy;
+    let mut ext: Vec<(&str, String)> = vec![
+        ("rt", rinfo.timestamp.timestamp_millis().to_string()),
+        ("src", rinfo.rinfo.geoip.ipstr.clone()),
+        ("requestMethod", rinfo.rinfo.meta.method.clone()),
+        ("dhost", rinfo.rinfo.host.clone()),
+        ("request", rinfo.rinfo.qinfo.uri.clone()),
+        ("act", action.to_string()),
+    ];
+    if let Some(ua) = rinfo.headers.get("user-agent") {
+        ext.push(("requestClientApplication", ua.clone()));
+    }
+    if let Some(id) = &rinfo.rinfo.meta.requestid {
+        ext.push(("externalId", id.clone()));
+    }
+    if let Some(code) = status.or_else(|| decision.maction.as_ref().map(|a| a.status)) {
+        ext.push(("cn1Label", "status".to_string()));
+        ext.push(("cn1", code.to_string()));
+    }
+    ext.push(("cs1Label", "securitypolicy".to_string()));
+    ext.push(("cs1", secpol.policy.name.clone()));
+    ext.push(("cs2Label", "securitypolicyentry".to_string()));
+    ext.push(("cs2", secpol.entry.name.clone()));
+    ext.push(("cs3Label", "tags".to_string()));
+    ext.push(("cs3", sorted_tags(tags).join(",")));
+    if let Some(country) = &rinfo.rinfo.geoip.country_iso {
+        ext.push(("cs4Label", "country".to_string()));
+        ext.push(("cs4", country.clone()));
+    }
+    let extension: Vec<String> = ext.iter().map(|(k, v)| format!("{}={}", k, cef_value(v))).collect();
+    format!(
+        "CEF:0|{}|{}|{}|{}|{}|{}|{}",
+        VENDOR,
+        PRODUCT,
+        env!("CARGO_PKG_VERSION"),
+        cef_header(&signature),
+        cef_header(&name),
+        severity,
+        extension.join(" ")
+    )
+}
+
+/// the analysis result, as an Elastic Common Schema document
+pub fn ecs_document(decision: &Decision, rinfo: &RequestInfo, tags: &Tags, status: Option<u32>) -> Value {
+    let action = action_name(decision);
+    let geoip = &rinfo.rinfo.geoip;
+    let secpol = &rinfo.rinfo.secpolicy;
+
+    let mut geo = Map::new();
+    if let Some(c) = &geoip.country_iso {
+        geo.insert("country_iso_code".into(), json!(c));
+    }
+    if let Some(c) = &geoip.country_name {
+        geo.insert("country_name".into(), json!(c));
+    }
+    if let Some(c) = &geoip.continent_code {
+        geo.insert("continent_code".into(), json!(c));
+    }
+    if let Some(r) = &geoip.region {
+        geo.insert("region_name".into(), json!(r));
+    }
+    if let Some(c) = &geoip.city_name {
+        geo.insert("city_name".into(), json!(c));
+    }
+    if let Some((lat, lon)) = geoip.location {
+        geo.insert("location".into(), json!({"lat": lat, "lon": lon}));
+    }
+    let mut source = Map::new();
+    source.insert("ip".into(), json!(geoip.ipstr));
+    if !geo.is_empty() {
+        source.insert("geo".into(), Value::Object(geo));
+    }
+    if let Some(asn) = geoip.asn {
+        source.insert(
+            "as".into(),
+            json!({"number": asn, "organization": {"name": geoip.as_name}}),
+        );
+    }
+
+    let mut doc = json!({
+        "@timestamp": rinfo.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+        "ecs": {"version": ECS_VERSION},
+        "event": {
+            "kind": if decision.reasons.is_empty() { "event" } else { "alert" },
+            "category": ["web"],
+            "type": if decision.is_blocking() { vec!["access", "denied"] } else { vec!["access", "allowed"] },
+            "action": action,
+            "outcome": if decision.is_blocking() { "failure" } else { "success" },
+            "dataset": "curiefense.access",
+        },
+        "observer": {"vendor": VENDOR, "product": PRODUCT, "type": "waf", "version": env!("CARGO_PKG_VERSION")},
+        "http": {
+            "request": {"method": rinfo.rinfo.meta.method, "id": rinfo.rinfo.meta.requestid},
+            "response": {"status_code": status.or_else(|| decision.maction.as_ref().map(|a| a.status))},
+        },
+        "url": {
+            "domain": rinfo.rinfo.host,
+            "path": rinfo.rinfo.qinfo.qpath,
+            "query": rinfo.rinfo.qinfo.query,
+            "original": rinfo.rinfo.qinfo.uri,
+        },
+        "source": Value::Object(source),
+        "user_agent": {"original": rinfo.headers.get("user-agent")},
+        "tags": sorted_tags(tags),
+        "labels": {
+            "securitypolicy": secpol.policy.name,
+            "securitypolicy_entry": secpol.entry.name,
+        },
+    });
+    if let Some(reason) = main_reason(decision) {
+        doc["rule"] = json!({
+            "id": reason.id,
+            "name": reason.name,
+            "description": reason.to_string(),
+            "ruleset": reason.initiator.to_kind(),
+        });
+        doc["curiefense"] = json!({ "reasons": decision.reasons });
+    }
+    doc
+}
+
+/// serializes the analysis result in a SIEM format, returns None for the native format
+pub fn siem_record(
+    format: LogFormat,
+    decision: &Decision,
+    rinfo: &RequestInfo,
+    tags: &Tags,
+    status: Option<u32>,
+) -> Option<Vec<u8>> {
+    match format {
+        LogFormat::Curiefense => None,
+        LogFormat::Cef => Some(cef_record(decision, rinfo, tags, status).into_bytes()),
+        LogFormat::Ecs => {
+            Some(serde_json::to_vec(&ecs_document(decision, rinfo, tags, status)).unwrap_or_else(|_| b"{}".to_vec()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cef_escaping() {
+        assert_eq!(cef_header("a|b\\c"), "a\\|b\\\\c");
+        assert_eq!(cef_value("k=v\nx"), "k\\=v\\nx");
+        assert_eq!("ecs".parse::<LogFormat>().unwrap(), LogFormat::Ecs);
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
@@ -0,0 +1,256 @@
+//! HMAC request signatures, as sent by webhook providers.
+//!
+//! A security policy entry can require the requests to some of its paths (all of them by default) to be signed
+//! with a shared secret. The signature is read from a header (`x-signature` by default), after an optional prefix
+//! such as `sha256=`, and is the HMAC of the raw body, hex or base64 encoded, with SHA-256, SHA-384 or SHA-512.
+//!
+//! When `timestamp_header` is set, the signed message is `<timestamp>.<body>`, and requests whose timestamp is more
+//! than `tolerance_seconds` away from the current time are rejected. The secret is set with `secret`, or read from
+//! the environment variable named by `secret_env` when the configuration is loaded.
+//!
+//! Signatures are verified on the raw body, before it is decoded. Valid requests are tagged `signature:valid`,
+//! invalid ones `signature:<problem>`, and are blocked when `enforce` is set, or only get a monitor reason otherwise.
+use hmac::digest::KeyInit;
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Sha384, Sha512};
+use subtle::ConstantTimeEq;
+
+use crate::config::pathtrie::PathTemplate;
+use crate::config::raw::RawSignature;
+use crate::logs::Logs;
+use crate::utils::decoders::base64dec_all;
+use crate::utils::url::normalize_path;
+use crate::utils::RawRequest;
+
+pub const SIGNATURE_TAG: &str = "signature";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl std::str::FromStr for SignatureAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sha256" | "hmac-sha256" => Ok(SignatureAlgorithm::Sha256),
+            "sha384" | "hmac-sha384" => Ok(SignatureAlgorithm::Sha384),
+            "sha512" | "hmac-sha512" => Ok(SignatureAlgorithm::Sha512),
+            _ => Err(anyhow::anyhow!("unsupported signature algorithm {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureEncoding {
+    Hex,
+    Base64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SignatureSettings {
+    /// all the paths of the entry when empty
+    pub paths: Vec<PathTemplate>,
+    /// lowercase
+    pub header: String,
+    /// stripped from the header value
+    pub prefix: String,
+    pub algorithm: SignatureAlgorithm,
+    pub encoding: SignatureEncoding,
+    pub secret: Vec<u8>,
+    /// lowercase
+    pub timestamp_header: Option<String>,
+    pub tolerance: i64,
+    pub enforce: bool,
+}
+
+/// a signature problem, used as the block reason
+pub type SignatureProblem = &'static str;
+
+fn keyed_mac<M: Mac + KeyInit>(key: &[u8], message: &[&[u8]]) -> Vec<u8> {
+    let mut mac = <M as KeyInit>::new_from_slice(key).expect("HMAC keys can have any length");
+    for part in message {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().to_vec()
+}
+
+impl SignatureSettings {
+    pub fn resolve(logs: &mut Logs, raw: RawSignature) -> Option<Self> {
+        let secret_env = raw.secret_env;
+        let secret = raw
+            .secret
+            .filter(|s| !s.is_empty())
+            .or_else(|| secret_env.and_then(|env| std::env::var(env).ok()))
+            .filter(|s| !s.is_empty());
+        let secret = match secret {
+            None => {
+                logs.error("request signatures require a secret, the verification is disabled");
+                return None;
+            }
+            Some(s) => s.into_bytes(),
+        };
+        let algorithm = match raw.algorithm.as_deref().unwrap_or("sha256").parse() {
+            Ok(a) => a,
+            Err(rr) => {
+                logs.error(|| format!("{}, the verification is disabled", rr));
+                return None;
+            }
+        };
+        let encoding = match raw.encoding.as_deref().unwrap_or("hex") {
+            "hex" => SignatureEncoding::Hex,
+            "base64" => SignatureEncoding::Base64,
+            other => {
+                logs.error(|| format!("unsupported signature encoding {}, using hex", other));
+                SignatureEncoding::Hex
+            }
+        };
+        let mut paths = Vec::new();
+        for p in raw.paths {
+            match p.parse() {
+                Ok(tpl) => paths.push(tpl),
+                Err(rr) => logs.error(|| format!("signature path: {}", rr)),
+            }
+        }
+        Some(SignatureSettings {
+            paths,
+            header: raw
+                .header
+                .map(|h| h.to_lowercase())
+                .unwrap_or_else(|| "x-signature".to_string()),
+            prefix: raw.prefix.unwrap_or_default(),
+            algorithm,
+            encoding,
+            secret,
+            timestamp_header: raw.timestamp_header.map(|h| h.to_lowercase()),
+            tolerance: raw.tolerance_seconds.unwrap_or(300).clamp(1, i64::MAX as u64) as i64,
+            enforce: raw.enforce,
+        })
+    }
+
+    /// true if the request must be signed, the path being normalized as it is forwarded
+    pub fn applies(&self, raw: &RawRequest) -> bool {
+        let path = normalize_path(raw.meta.path.split('?').next().unwrap_or_default()).path;
+        self.paths.is_empty() || self.paths.iter().any(|tpl| tpl.captures(&path).is_some())
+    }
+
+    pub fn sign(&self, timestamp: Option<&str>, body: &[u8]) -> Vec<u8> {
+        let message: Vec<&[u8]> = match timestamp {
+            None => vec![body],
+            Some(ts) => vec![ts.as_bytes(), b".", body],
+        };
+        match self.algorithm {
+            SignatureAlgorithm::Sha256 => keyed_mac::<Hmac<Sha256>>(&self.secret, &message),
+            SignatureAlgorithm::Sha384 => keyed_mac::<Hmac<Sha384>>(&self.secret, &message),
+            SignatureAlgorithm::Sha512 => keyed_mac::<Hmac<Sha512>>(&self.secret, &message),
+        }
+    }
+
+    fn header_value<'a>(raw: &'a RawRequest, name: &str) -> Option<&'a str> {
+        raw.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.trim())
+    }
+
+    /// verifies the signature of the request, `now` being the current unix time
+    pub fn verify(&self, raw: &RawRequest, now: i64) -> Result<(), SignatureProblem> {
+        let value = Self::header_value(raw, &self.header).ok_or("missing-signature")?;
+        let encoded = value.strip_prefix(self.prefix.as_str()).ok_or("malformed-signature")?;
+        let signature = match self.encoding {
+            SignatureEncoding::Hex => hex::decode(encoded).ok(),
+            SignatureEncoding::Base64 => base64dec_all(encoded).ok(),
+        }
+        .ok_or("malformed-signature")?;
+        let timestamp = match &self.timestamp_header {
+            None => None,
+            Some(h) => {
+                let ts = Self::header_value(raw, h).ok_or("missing-timestamp")?;
+                let parsed: i64 = ts.parse().map_err(|_| "invalid-timestamp")?;
+                if now.saturating_sub(parsed).saturating_abs() > self.tolerance {
+                    return Err("stale-timestamp");
+                }
+                Some(ts)
+            }
+        };
+        let expected = self.sign(timestamp, raw.mbody.unwrap_or_default());
+        if bool::from(expected.ct_eq(&signature)) {
+            Ok(())
+        } else {
+            Err("mismatch")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::raw_request;
+    use crate::utils::hmac_sha256;
+
+    fn mk_raw<'a>(headers: &[(&str, &str)], body: &'a [u8]) -> RawRequest<'a> {
+        let mut raw = raw_request(&[("method", "POST"), ("path", "/hooks/stripe?x=1")], headers);
+        raw.mbody = Some(body);
+        raw
+    }
+
+    #[test]
+    fn hmac_vectors() {
+        // RFC 4231, test case 2
+        let msg: &[&[u8]] = &[b"what do ya want ", b"for nothing?"];
+        assert_eq!(
+            hex::encode(keyed_mac::<Hmac<Sha256>>(b"Jefe", msg)),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex::encode(keyed_mac::<Hmac<Sha512>>(b"Jefe", msg)),
+            "164b7a7bfcf819e2e395fbe73b56e0a387bd64222e831fd610270cd7ea2505549758bf75c05a994a6d034f65f8f0e6fdcaeab1a34d4a6b4b636e070a38bce737"
+        );
+        assert_eq!(
+            keyed_mac::<Hmac<Sha256>>(&[7; 100], &[b"body"]),
+            hmac_sha256(&[7; 100], b"body").to_vec()
+        );
+    }
+
+    #[test]
+    fn verification() {
+        let settings = SignatureSettings::resolve(
+            &mut Logs::default(),
+            RawSignature {
+                paths: vec!["/hooks/*".to_string()],
+                header: Some("X-Hub-Signature-256".to_string()),
+                prefix: Some("sha256=".to_string()),
+                secret: Some("whsec".to_string()),
+                timestamp_header: Some("X-Timestamp".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let body = b"{\"event\":\"paid\"}";
+        let now = 1700000000;
+        let sig = format!("sha256={}", hex::encode(settings.sign(Some("1700000000"), body)));
+        let ts = ("x-timestamp", "1700000000");
+        let good = mk_raw(&[("x-hub-signature-256", &sig), ts], body);
+        assert!(settings.applies(&good));
+        let mut traversal = mk_raw(&[ts], body);
+        traversal.meta.path = "/x/../hooks/stripe".to_string();
+        assert!(settings.applies(&traversal));
+        traversal.meta.path = "/api/items".to_string();
+        assert!(!settings.applies(&traversal));
+        assert_eq!(settings.verify(&good, now), Ok(()));
+        assert_eq!(settings.verify(&good, now + 600), Err("stale-timestamp"));
+        let tampered = mk_raw(&[("x-hub-signature-256", &sig), ts], b"{\"event\":\"refund\"}");
+        assert_eq!(settings.verify(&tampered, now), Err("mismatch"));
+        let unsigned = mk_raw(&[ts], body);
+        assert_eq!(settings.verify(&unsigned, now), Err("missing-signature"));
+        let unprefixed = mk_raw(&[("x-hub-signature-256", &sig[7..]), ts], body);
+        assert_eq!(settings.verify(&unprefixed, now), Err("malformed-signature"));
+        let no_ts = mk_raw(&[("x-hub-signature-256", &sig)], body);
+        assert_eq!(settings.verify(&no_ts, now), Err("missing-timestamp"));
+
+        assert!(SignatureSettings::resolve(&mut Logs::default(), RawSignature::default()).is_none());
+    }
+}
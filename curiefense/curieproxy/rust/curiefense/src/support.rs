@@ -0,0 +1,269 @@
+//! Support bundles.
+//!
+//! A support bundle is a single JSON document describing the state of the engine: version, configuration
+//! summary, error counters, the last decisions and the health of the dependencies. It is meant to be
+//! attached to bug reports, so it does not contain request content: IPs, paths, arguments and headers
+//! are left out, and identifying tags are redacted.
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::challenge::ChallengeProvider;
+use crate::config::watcher::reload_stats;
+use crate::config::CONFIGS;
+use crate::grasshopper::GHQuery;
+use crate::interface::{Decision, Tags};
+use crate::logs::{log_counters, LogLevel};
+use crate::redis::redis_async_conn;
+use crate::utils::RequestInfo;
+
+lazy_static! {
+    static ref RECENT_DECISIONS_SIZE: usize = std::env::var("SUPPORT_BUNDLE_DECISIONS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(20);
+    static ref RECENT_DECISIONS: Mutex<VecDeque<Value>> = Mutex::new(VecDeque::new());
+}
+
+/// qualified tags whose values come from the configuration or a fixed vocabulary, the values of the other
+/// qualified tags (IPs, hosts, locations, keys, fingerprints, token claims...) are redacted
+const EXPORTED_TAGS: &[&str] = &[
+    "aclid",
+    "aclname",
+    "args",
+    "bot-signal",
+    "botscore",
+    "bypass",
+    "cadence",
+    "cf-excluded",
+    "cf-rule-category",
+    "cf-rule-id",
+    "cf-rule-risk",
+    "cf-rule-subcategory",
+    "cf-score",
+    "checkout-anomaly",
+    "config-version",
+    "contentfilterid",
+    "contentfiltername",
+    "cookies",
+    "dataleak-rule-id",
+    "dependency-failure",
+    "expiring-rule",
+    "fc-expired",
+    "fc-expired-id",
+    "fc-expired-name",
+    "fc-id",
+    "fc-name",
+    "geo-continent-code",
+    "geo-continent-name",
+    "geo-country",
+    "geo-privacy-service",
+    "headers",
+    "honeypot-trap",
+    "host-rule",
+    "limit-id",
+    "limit-name",
+    "login-anomaly",
+    "offender",
+    "protocol-anomaly",
+    "replay",
+    "reputation",
+    "responsefilterid",
+    "responsefiltername",
+    "rf-rule-id",
+    "rf-rule-risk",
+    "rf-status",
+    "scraper",
+    "securitypolicy",
+    "securitypolicy-entry",
+    "securitypolicy-entry-methods",
+    "signature",
+];
+
+/// maximum number of configuration loading messages in the bundle
+const MAX_CONFIG_MESSAGES: usize = 20;
+
+fn redact_tag(tag: &str) -> String {
+    match tag.split_once(':') {
+        Some((prefix, _)) if !EXPORTED_TAGS.contains(&prefix) => format!("{}:redacted", prefix),
+        _ => tag.to_string(),
+    }
+}
+
+fn decision_record(dec: &Decision, rcode: Option<u32>, rinfo: &RequestInfo, tags: &Tags) -> Value {
+    let mut tags: Vec<String> = tags.inner().keys().map(|t| redact_tag(t)).collect();
+    tags.sort();
+    let reasons: Vec<Value> = dec
+        .reasons
+        .iter()
+        .map(|r| {
+            json!({
+                "id": r.id,
+                "name": r.name,
+                "initiator": r.initiator.to_kind(),
+                "action": r.action,
+                "location": r.location.to_string(),
+            })
+        })
+        .collect();
+    json!({
+        "timestamp": rinfo.timestamp,
+        "security_policy": rinfo.rinfo.secpolicy.policy.id,
+        "security_policy_entry": rinfo.rinfo.secpolicy.entry.id,
+        "method": rinfo.rinfo.meta.method,
+        "status": rcode,
+        "action": dec.maction.as_ref().map(|a| a.atype),
+        "reasons": reasons,
+        "tags": tags,
+        "memory_used": rinfo.memory.used,
+    })
+}
+
+/// keeps a redacted copy of the decision, for the next support bundles
+pub fn record_decision(dec: &Decision, rcode: Option<u32>, rinfo: &RequestInfo, tags: &Tags) {
+    if *RECENT_DECISIONS_SIZE == 0 {
+        return;
+    }
+    let record = decision_record(dec, rcode, rinfo, tags);
+    if let Ok(mut recent) = RECENT_DECISIONS.lock() {
+        if recent.len() >= *RECENT_DECISIONS_SIZE {
+            recent.pop_front();
+        }
+        recent.push_back(record);
+    }
+}
+
+fn config_summary() -> Value {
+    let cfg = CONFIGS.config.load();
+    let messages: Vec<String> = cfg
+        .logs
+        .logs
+        .iter()
+        .filter(|l| l.level >= LogLevel::Warning)
+        .take(MAX_CONFIG_MESSAGES)
+        .map(|l| l.to_string())
+        .collect();
+    json!({
+        "revision": cfg.revision,
+        "config_hash": cfg.config_hash,
+        "container_name": cfg.container_name,
+        "security_policies": cfg.securitypolicies_map.len(),
+        "security_policy_entries": cfg.securitypolicies_map.values().map(|h| h.entries.len() + h.templates.len()).sum::<usize>(),
+        "global_filters": cfg.globalfilters.len(),
+        "flows": cfg.flows.len(),
+        "acl_profiles": cfg.acls.len(),
+        "content_filter_profiles": cfg.content_filter_profiles.len(),
+        "response_filter_profiles": cfg.response_filter_profiles.len(),
+        "data_leak_rules": cfg.data_leak_rules.rules.len(),
+        "limits": cfg.limits.len(),
+        "global_limits": cfg.global_limits.len(),
+        "actions": cfg.actions.len(),
+        "hooks": cfg.hooks.len(),
+        "reloads": reload_stats(),
+        "messages": messages,
+    })
+}
+
+fn hsdb_health() -> Value {
+    let cfg = CONFIGS.config.load();
+    let hsdb = &cfg.hsdb;
+    let mut missing: Vec<&String> = cfg
+        .content_filter_profiles
+        .keys()
+        .filter(|p| !hsdb.contains_key(*p))
+        .collect();
+    missing.sort();
+    json!({
+        "ok": missing.is_empty(),
+        "databases": hsdb.len(),
+        "rules": hsdb.values().map(|r| r.ids.len()).sum::<usize>(),
+        "missing_profiles": missing,
+    })
+}
+
+async fn redis_health() -> Value {
+    let start = Instant::now();
+    let res: anyhow::Result<String> = async {
+        let mut conn = redis_async_conn().await?;
+        Ok(redis::cmd("PING").query_async(&mut conn).await?)
+    }
+    .await;
+    let latency_micros = start.elapsed().as_micros() as u64;
+    match res {
+        Ok(_) => json!({ "ok": true, "latency_micros": latency_micros }),
+        Err(rr) => json!({ "ok": false, "latency_micros": latency_micros, "error": rr.to_string() }),
+    }
+}
+
+fn grasshopper_health<GH: ChallengeProvider>(mgh: Option<&GH>) -> Value {
+    let gh = match mgh {
+        None => return json!({ "ok": false, "error": "not available" }),
+        Some(gh) => gh,
+    };
+    let start = Instant::now();
+    let res = gh.is_human(GHQuery {
+        headers: Default::default(),
+        cookies: Default::default(),
+        ip: "127.0.0.1",
+        protocol: "https",
+    });
+    let latency_micros = start.elapsed().as_micros() as u64;
+    match res {
+        Ok(_) => json!({ "ok": true, "latency_micros": latency_micros }),
+        Err(rr) => json!({ "ok": false, "latency_micros": latency_micros, "error": rr }),
+    }
+}
+
+/// builds the support bundle
+pub async fn support_bundle<GH: ChallengeProvider>(mgh: Option<&GH>) -> Value {
+    let decisions: Vec<Value> = RECENT_DECISIONS
+        .lock()
+        .map(|recent| recent.iter().cloned().collect())
+        .unwrap_or_default();
+    json!({
+        "generated": chrono::Utc::now(),
+        "version": env!("CARGO_PKG_VERSION"),
+        "config": config_summary(),
+        "log_counters": log_counters(),
+        "log_sinks": crate::logsink::sinks_stats(),
+        "reputation_feeds": crate::reputation::reputation_stats(),
+        "recent_decisions": decisions,
+        "dependencies": {
+            "redis": redis_health().await,
+            "grasshopper": grasshopper_health(mgh),
+            "hsdb": hsdb_health(),
+        },
+    })
+}
+
+pub fn support_bundle_block<GH: ChallengeProvider>(mgh: Option<&GH>) -> String {
+    let bundle = async_std::task::block_on(support_bundle(mgh));
+    serde_json::to_string_pretty(&bundle).unwrap_or_else(|rr| format!("{{\"error\": \"{}\"}}", rr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tag_redaction() {
+        assert_eq!(redact_tag("ip:1-2-3-4"), "ip:redacted");
+        assert_eq!(redact_tag("network:1-2-3-0-24"), "network:redacted");
+        assert_eq!(redact_tag("geo-country:france"), "geo-country:france");
+        assert_eq!(redact_tag("iphone"), "iphone");
+        assert_eq!(redact_tag("cf-rule-id:100045"), "cf-rule-id:100045");
+        // identifying values, and tags that are not known to be safe
+        assert_eq!(redact_tag("request-id:4f2a9c"), "request-id:redacted");
+        assert_eq!(redact_tag("apikey:f00dfeed"), "apikey:redacted");
+        assert_eq!(redact_tag("host:www-example-com"), "host:redacted");
+        assert_eq!(redact_tag("geo-asn:64496"), "geo-asn:redacted");
+        assert_eq!(redact_tag("geo-org:example-corp"), "geo-org:redacted");
+        assert_eq!(redact_tag("geo-region:bavaria"), "geo-region:redacted");
+        assert_eq!(redact_tag("ja3:e7d705a3286e19ea42f587b344ee6865"), "ja3:redacted");
+        assert_eq!(redact_tag("http-fp:abcd"), "http-fp:redacted");
+        assert_eq!(redact_tag("jwt-iss:https-idp-example-com"), "jwt-iss:redacted");
+        assert_eq!(redact_tag("some-future-tag:value"), "some-future-tag:redacted");
+    }
+}
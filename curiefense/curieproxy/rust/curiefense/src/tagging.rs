@@ -1,11 +1,15 @@
+use crate::apikey::fingerprint;
+use crate::config::expiry::{expires_soon, is_expired, EXPIRING_TAG};
 use crate::config::globalfilter::{
     GlobalFilterEntry, GlobalFilterEntryE, GlobalFilterRule, GlobalFilterSection, PairEntry, SingleEntry,
 };
 use crate::config::raw::Relation;
 use crate::config::virtualtags::VirtualTags;
+use crate::fingerprint::http_fingerprint;
 use crate::grasshopper::PrecisionLevel;
 use crate::interface::stats::{BStageMapped, BStageSecpol, StatsCollect};
 use crate::interface::{stronger_decision, BlockReason, Location, SimpleActionT, SimpleDecision, Tags};
+use crate::reputation::reputation_lookup;
 use crate::requestfields::RequestField;
 use crate::utils::RequestInfo;
 use std::collections::HashSet;
@@ -143,6 +147,25 @@ fn check_entry(rinfo: &RequestInfo, tags: &Tags, sub: &GlobalFilterEntry) -> Mat
         GlobalFilterEntryE::Cookies(arg) => check_pair(arg, &rinfo.cookies, |c| {
             Location::CookieValue(arg.key.clone(), c.to_string())
         }),
+        GlobalFilterEntryE::HeaderRegex(hr) => {
+            let locations: HashSet<Location> = rinfo
+                .headers
+                .iter()
+                .filter(|(k, v)| hr.key.is_match(k) && hr.value.is_match(v))
+                .map(|(k, v)| Location::HeaderValue(k.to_string(), v.to_string()))
+                .collect();
+            Some(locations).filter(|l| !l.is_empty())
+        }
+        GlobalFilterEntryE::ArgExists(name) => rinfo
+            .rinfo
+            .qinfo
+            .args
+            .get(name)
+            .map(|_| std::iter::once(Location::UriArgument(name.clone())).collect()),
+        GlobalFilterEntryE::CookieExists(name) => rinfo
+            .cookies
+            .get(name)
+            .map(|_| std::iter::once(Location::Cookie(name.clone())).collect()),
         GlobalFilterEntryE::Asn(asn) => mbool(Location::Ip, rinfo.rinfo.geoip.asn.map(|casn| casn == *asn)),
         GlobalFilterEntryE::Company(cmp) => rinfo
             .rinfo
@@ -151,6 +174,29 @@ fn check_entry(rinfo: &RequestInfo, tags: &Tags, sub: &GlobalFilterEntry) -> Mat
             .as_ref()
             .and_then(|ccmp| check_single(cmp, ccmp.as_str(), Location::Ip)),
         GlobalFilterEntryE::Authority(at) => check_single(at, &rinfo.rinfo.host, Location::Request),
+        GlobalFilterEntryE::ApiKey(key) => rinfo
+            .api_key
+            .as_ref()
+            .and_then(|k| check_single(key, k, Location::Request)),
+        GlobalFilterEntryE::ClientCertSubject(subject) => rinfo
+            .rinfo
+            .meta
+            .client_cert
+            .as_ref()
+            .and_then(|c| c.subject.as_ref())
+            .and_then(|s| check_single(subject, s, Location::Request)),
+        GlobalFilterEntryE::ClientCertSan(san) => rinfo
+            .rinfo
+            .meta
+            .client_cert
+            .as_ref()
+            .and_then(|c| c.san.iter().find_map(|s| check_single(san, s, Location::Request))),
+        GlobalFilterEntryE::TlsFingerprint(fp) => rinfo
+            .rinfo
+            .meta
+            .tls_fingerprint
+            .as_ref()
+            .and_then(|tfp| check_single(fp, &tfp.hash, Location::Request)),
         GlobalFilterEntryE::Tag(tg) => tags.get(&tg.exact).cloned(),
         GlobalFilterEntryE::SecurityPolicyId(id) => {
             if &rinfo.rinfo.secpolicy.policy.id == id {
@@ -209,11 +255,30 @@ pub fn tag_request(
             tags.insert("bot", Location::Request);
         }
     }
+    tags.insert_qualified("config-version", stats.revision(), Location::Request);
     tags.insert_qualified("headers", &rinfo.headers.len().to_string(), Location::Headers);
     tags.insert_qualified("cookies", &rinfo.cookies.len().to_string(), Location::Cookies);
     tags.insert_qualified("args", &rinfo.rinfo.qinfo.args.len().to_string(), Location::Request);
     tags.insert_qualified("host", &rinfo.rinfo.host, Location::Request);
+    if let Some(request_id) = &rinfo.rinfo.meta.requestid {
+        tags.insert_qualified("request-id", request_id, Location::Request);
+    }
+    if rinfo.rinfo.qinfo.normalized.rewritten {
+        tags.insert("path-normalized", Location::Uri);
+    }
+    if let Some(key) = &rinfo.api_key {
+        tags.insert_qualified("apikey", &fingerprint(key), Location::Request);
+    }
     tags.insert_qualified("ip", &rinfo.rinfo.geoip.ipstr, Location::Ip);
+    if let Some(client) = &rinfo.rinfo.client_ip {
+        tags.insert_qualified("client-ip-hop", &client.hop.to_string(), Location::Ip);
+        if client.spoofed {
+            tags.insert("client-ip-spoofed", Location::Ip);
+        }
+        if let Some(proto) = &client.proto {
+            tags.insert_qualified("forwarded-proto", proto, Location::Request);
+        }
+    }
     tags.insert_qualified(
         "geo-continent-name",
         rinfo.rinfo.geoip.continent_name.as_deref().unwrap_or("nil"),
@@ -288,17 +353,71 @@ pub fn tag_request(
     if rinfo.rinfo.geoip.is_mobile.unwrap_or(false) {
         tags.insert("geo-mobile", Location::Ip);
     }
+    if let Some(fp) = &rinfo.rinfo.meta.tls_fingerprint {
+        tags.insert_qualified(fp.kind.name(), &fp.hash, Location::Request);
+    }
+    if let Some(cert) = &rinfo.rinfo.meta.client_cert {
+        tags.insert("mtls", Location::Request);
+        if let Some(fp) = &cert.fingerprint {
+            tags.insert_qualified("mtls-fingerprint", fp, Location::Request);
+        }
+        for san in &cert.san {
+            tags.insert_qualified("mtls-san", san, Location::Request);
+        }
+        if let Some(problem) = cert.validity(rinfo.timestamp.timestamp()) {
+            tags.insert_qualified("mtls", problem, Location::Request);
+        }
+    }
+    tags.insert_qualified(
+        "http-fp",
+        &http_fingerprint(&rinfo.rinfo.meta, &rinfo.headers),
+        Location::Headers,
+    );
+
+    if let Some(ip) = rinfo.rinfo.geoip.ip {
+        for feed in reputation_lookup(ip) {
+            tags.insert_qualified("reputation", &feed.category, Location::Ip);
+            for tag in &feed.tags {
+                tags.insert(tag, Location::Ip);
+            }
+        }
+    }
 
     for tag in rinfo.rinfo.secpolicy.tags.iter() {
         tags.insert(tag, Location::Request)
     }
 
+    // filters depending on the session state are evaluated later, by the session stage
+    let (decision, matched) = apply_globalfilters(rinfo, &mut tags, globalfilters.iter().filter(|s| !s.session));
+
+    (tags, decision, stats.mapped(globalfilters.len(), matched))
+}
+
+/// evaluates global filter sections, adding their tags, and returns the resulting decision and number of matches
+pub fn apply_globalfilters<'t, I: Iterator<Item = &'t GlobalFilterSection>>(
+    rinfo: &RequestInfo,
+    tags: &mut Tags,
+    globalfilters: I,
+) -> (SimpleDecision, usize) {
     let mut matched = 0;
     let mut decision = SimpleDecision::Pass;
     for psection in globalfilters {
-        let mtch = check_rule(rinfo, &tags, &psection.rule);
+        if psection
+            .window
+            .as_ref()
+            .map(|w| !w.contains(rinfo.timestamp))
+            .unwrap_or(false)
+            || is_expired(psection.expires, rinfo.timestamp)
+        {
+            continue;
+        }
+        let mtch = check_rule(rinfo, tags, &psection.rule);
         if mtch.matching {
             matched += 1;
+            if expires_soon(psection.expires, rinfo.timestamp) {
+                tags.insert(EXPIRING_TAG, Location::Request);
+                tags.insert_qualified(EXPIRING_TAG, &psection.id, Location::Request);
+            }
             let rtags = tags
                 .new_with_vtags()
                 .with_raw_tags_locs(psection.tags.clone(), &mtch.matched);
@@ -318,8 +437,7 @@ pub fn tag_request(
             }
         }
     }
-
-    (tags, decision, stats.mapped(globalfilters.len(), matched))
+    (decision, matched)
 }
 
 #[cfg(test)]
@@ -328,6 +446,7 @@ mod tests {
     use crate::config::globalfilter::optimize_ipranges;
     use crate::config::globalfilter::GlobalFilterRelation;
     use crate::config::hostmap::SecurityPolicy;
+    use crate::interface::stats::SecpolStats;
     use crate::logs::Logs;
     use crate::utils::map_request;
     use crate::utils::RawRequest;
@@ -348,6 +467,7 @@ mod tests {
             ("accept", "*/*"),
             ("user-agent", "curl/7.58.0"),
             ("x-envoy-internal", "true"),
+            (":ja3", "771,4865-4866-4867,0-23-65281,29-23-24,0"),
         ];
         let mut headers = HashMap::<String, String>::new();
         let mut attrs = HashMap::<String, String>::new();
@@ -403,6 +523,61 @@ mod tests {
         }
     }
 
+    #[test]
+    fn check_entry_new_primitives() {
+        let re = |s: &str| RegexBuilder::new(s).case_insensitive(true).build().unwrap();
+        let hdr = |k: &str, v: &str| {
+            GlobalFilterEntryE::HeaderRegex(crate::config::globalfilter::RegexPairEntry {
+                key: re(k),
+                value: re(v),
+            })
+        };
+        assert!(t_check_entry(false, hdr("^x-.*-proto$", "^http$")).matching);
+        assert!(!t_check_entry(false, hdr("^x-.*-proto$", "^https$")).matching);
+        assert!(t_check_entry(false, GlobalFilterEntryE::ArgExists("lol".to_string())).matching);
+        assert!(t_check_entry(true, GlobalFilterEntryE::ArgExists("missing".to_string())).matching);
+        assert!(!t_check_entry(false, GlobalFilterEntryE::CookieExists("session".to_string())).matching);
+    }
+
+    #[test]
+    fn ip_list_expansion() {
+        use crate::config::globalfilter::expand_ip_lists;
+        use crate::config::raw::{
+            GlobalFilterEntryType, RawGlobalFilterEntry, RawGlobalFilterRule, RawGlobalFilterSection, RawIpList,
+        };
+
+        let section = |list: &str| RawGlobalFilterSection {
+            id: list.to_string(),
+            name: list.to_string(),
+            active: true,
+            tags: vec!["listed".to_string()],
+            action: None,
+            window: None,
+            expires: None,
+            rule: RawGlobalFilterRule::Entry(RawGlobalFilterEntry {
+                tp: GlobalFilterEntryType::IpList,
+                vl: serde_json::Value::String(list.to_string()),
+                comment: None,
+            }),
+        };
+        let lists = vec![RawIpList {
+            id: "partners".to_string(),
+            name: "partners".to_string(),
+            entries: vec!["52.78.0.0/16".to_string(), "10.0.0.1".to_string()],
+        }];
+        let raw = expand_ip_lists(
+            vec![section("partners"), section("!partners"), section("unknown")],
+            &lists,
+        );
+        let mut logs = Logs::default();
+        let sections = GlobalFilterSection::resolve(&mut logs, &HashMap::new(), raw);
+        assert_eq!(sections.len(), 2);
+        let rinfo = mk_rinfo();
+        let tags = Tags::new(&VirtualTags::default());
+        assert!(check_rule(&rinfo, &tags, &sections[0].rule).matching);
+        assert!(!check_rule(&rinfo, &tags, &sections[1].rule).matching);
+    }
+
     #[test]
     fn check_entry_ip_in() {
         let r = t_check_entry(false, GlobalFilterEntryE::Ip("52.78.12.56".parse().unwrap()));
@@ -449,6 +624,26 @@ mod tests {
         assert!(r.matching);
     }
 
+    #[test]
+    fn check_tls_fingerprint() {
+        let fp = "650293d7a2ffb5335422221c5d75a9c9";
+        let r = t_check_entry(false, GlobalFilterEntryE::TlsFingerprint(single_re(fp)));
+        assert!(r.matching);
+        let r = t_check_entry(
+            false,
+            GlobalFilterEntryE::TlsFingerprint(single_re("^00000000000000000000000000000000$")),
+        );
+        assert!(!r.matching);
+        let (tags, _, _) = tag_request(
+            StatsCollect::new(std::time::Instant::now(), "dummy".to_string()).secpol(SecpolStats::default()),
+            PrecisionLevel::Invalid,
+            &[],
+            &mk_rinfo(),
+            &VirtualTags::default(),
+        );
+        assert!(tags.contains(&format!("ja3:{}", fp)));
+    }
+
     fn mk_globalfilterentries(lst: &[&str]) -> Vec<GlobalFilterRule> {
         lst.iter()
             .map(|e| match e.strip_prefix('!') {
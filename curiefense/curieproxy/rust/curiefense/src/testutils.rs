@@ -0,0 +1,43 @@
+//! Fixtures shared by the unit tests.
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::config::hostmap::SecurityPolicy;
+use crate::logs::Logs;
+use crate::utils::{map_request, RawRequest, RequestInfo, RequestMeta};
+
+/// address of the client of the test requests
+pub const TEST_IP: &str = "1.2.3.4";
+
+/// a request from TEST_IP, without body
+///
+/// `attrs` are the request attributes sent by the proxy, such as the authority. The method and path default to GET
+/// and /.
+pub fn raw_request<'a>(attrs: &[(&str, &str)], headers: &[(&str, &str)]) -> RawRequest<'a> {
+    let mut attrs: HashMap<String, String> = attrs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    attrs.entry("method".to_string()).or_insert_with(|| "GET".to_string());
+    attrs.entry("path".to_string()).or_insert_with(|| "/".to_string());
+    RawRequest {
+        ipstr: TEST_IP.to_string(),
+        headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        meta: RequestMeta::from_map(attrs).unwrap(),
+        mbody: None,
+    }
+}
+
+/// maps a raw request, with the given security policy
+pub fn map_raw(secpolicy: SecurityPolicy, raw: &RawRequest) -> RequestInfo {
+    map_request(
+        &mut Logs::default(),
+        Arc::new(secpolicy),
+        None,
+        raw,
+        None,
+        HashMap::new(),
+    )
+}
+
+/// maps a request from TEST_IP, with the default security policy
+pub fn request_info(attrs: &[(&str, &str)], headers: &[(&str, &str)]) -> RequestInfo {
+    map_raw(SecurityPolicy::default(), &raw_request(attrs, headers))
+}
@@ -0,0 +1,183 @@
+//! Top-N reporting in redis.
+//!
+//! When TOPN_REPORTING is set, every analyzed request increments per-minute redis sorted sets, that dashboards
+//! (such as Grafana, with its redis data source) can query directly, without a log pipeline:
+//!
+//!  * PREFIXtopn:ip:MINUTE, blocked IPs,
+//!  * PREFIXtopn:rule:MINUTE, triggered rules, by block reason id,
+//!  * PREFIXtopn:path:MINUTE, paths of the requests that triggered a rule,
+//!  * PREFIXtopn:decision:MINUTE, decision counts (pass, monitor, block, ...),
+//!
+//! MINUTE being the unix timestamp divided by 60. Increments are batched in memory, and written by a dedicated
+//! thread every TOPN_FLUSH_SECONDS (5 by default), so that requests never wait for redis. Keys expire after
+//! TOPN_TTL seconds (1 day by default), and only the TOPN_MAX_MEMBERS (1000 by default) highest scoring members
+//! of each set are kept.
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::raw::RawActionType;
+use crate::interface::AnalyzeResult;
+use crate::logs::background_error;
+use crate::redis::{redis_async_conn, REDIS_KEY_PREFIX};
+
+#[derive(Debug, Clone)]
+struct TopNSettings {
+    ttl: u64,
+    max_members: usize,
+    flush: Duration,
+}
+
+/// increments waiting to be written, by redis key and member
+type Pending = HashMap<String, HashMap<String, u64>>;
+
+lazy_static! {
+    static ref SETTINGS: Option<TopNSettings> = settings().map(start_writer);
+    static ref PENDING: Mutex<Pending> = Mutex::new(HashMap::new());
+    static ref DROPPED: AtomicU64 = AtomicU64::new(0);
+    static ref ERRORS: AtomicU64 = AtomicU64::new(0);
+}
+
+fn settings() -> Option<TopNSettings> {
+    std::env::var("TOPN_REPORTING")
+        .ok()
+        .filter(|v| !v.is_empty() && v != "0")?;
+    let var = |name: &str, default: u64| std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+    Some(TopNSettings {
+        ttl: var("TOPN_TTL", 86400),
+        max_members: var("TOPN_MAX_MEMBERS", 1000) as usize,
+        flush: Duration::from_secs(var("TOPN_FLUSH_SECONDS", 5).max(1)),
+    })
+}
+
+fn start_writer(settings: TopNSettings) -> TopNSettings {
+    let writer = settings.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(writer.flush);
+        let pending = std::mem::take(&mut *PENDING.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+        if pending.is_empty() {
+            continue;
+        }
+        if let Err(rr) = async_std::task::block_on(flush(&writer, pending)) {
+            ERRORS.fetch_add(1, Ordering::Relaxed);
+            background_error(|| format!("could not write the top-N sets: {}", rr));
+        }
+    });
+    settings
+}
+
+async fn flush(settings: &TopNSettings, pending: Pending) -> anyhow::Result<()> {
+    let mut pipe = redis::pipe();
+    for (key, members) in pending {
+        for (member, count) in members {
+            pipe.cmd("ZINCRBY").arg(&key).arg(count).arg(member).ignore();
+        }
+        pipe.cmd("ZREMRANGEBYRANK")
+            .arg(&key)
+            .arg(0)
+            .arg(-(settings.max_members as i64) - 1)
+            .ignore();
+        pipe.cmd("EXPIRE").arg(&key).arg(settings.ttl).ignore();
+    }
+    let mut conn = redis_async_conn().await?;
+    pipe.query_async::<_, ()>(&mut conn).await?;
+    Ok(())
+}
+
+fn key(set: &str, minute: i64) -> String {
+    format!("{}topn:{}:{}", *REDIS_KEY_PREFIX, set, minute)
+}
+
+/// the name of the decision, as counted in the decision set
+fn decision_name(result: &AnalyzeResult) -> String {
+    match &result.decision.maction {
+        Some(action) => serde_json::to_value(action.atype)
+            .ok()
+            .and_then(|v| v.as_str().map(|s| s.to_string()))
+            .unwrap_or_else(|| "unknown".to_string()),
+        None if result
+            .decision
+            .reasons
+            .iter()
+            .any(|r| r.action == RawActionType::Monitor) =>
+        {
+            "monitor".to_string()
+        }
+        None => "pass".to_string(),
+    }
+}
+
+/// the increments of an analysis result, as (set, member) pairs
+fn increments(result: &AnalyzeResult) -> Vec<(&'static str, String)> {
+    let mut out = vec![("decision", decision_name(result))];
+    if result.decision.is_blocking() {
+        out.push(("ip", result.rinfo.rinfo.geoip.ipstr.clone()));
+    }
+    for reason in &result.decision.reasons {
+        out.push(("rule", reason.id.clone()));
+    }
+    if !result.decision.reasons.is_empty() {
+        out.push(("path", result.rinfo.rinfo.qinfo.qpath.clone()));
+    }
+    out
+}
+
+fn add_increments(pending: &mut Pending, max_members: usize, minute: i64, incs: Vec<(&'static str, String)>) {
+    for (set, member) in incs {
+        let members = pending.entry(key(set, minute)).or_default();
+        if let Some(count) = members.get_mut(&member) {
+            *count += 1;
+        } else if members.len() < max_members {
+            members.insert(member, 1);
+        } else {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// queues the top-N increments of an analysis result, when the reporting is enabled
+pub fn record_topn(result: &AnalyzeResult) {
+    let settings = match SETTINGS.as_ref() {
+        None => return,
+        Some(s) => s,
+    };
+    let minute = result.rinfo.timestamp.timestamp() / 60;
+    let incs = increments(result);
+    let mut pending = PENDING.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    add_increments(&mut pending, settings.max_members, minute, incs);
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopNStats {
+    /// increments dropped because of the cardinality cap
+    pub dropped: u64,
+    pub write_errors: u64,
+}
+
+/// top-N reporting statistics, None when it is disabled
+pub fn topn_stats() -> Option<TopNStats> {
+    SETTINGS.as_ref()?;
+    Some(TopNStats {
+        dropped: DROPPED.load(Ordering::Relaxed),
+        write_errors: ERRORS.load(Ordering::Relaxed),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cardinality_cap() {
+        let mut pending = HashMap::new();
+        let incs = |ips: &[&str]| ips.iter().map(|ip| ("ip", ip.to_string())).collect();
+        add_increments(&mut pending, 2, 10, incs(&["a", "b", "a", "c"]));
+        let members = &pending[&key("ip", 10)];
+        assert_eq!(members.len(), 2);
+        assert_eq!(members["a"], 2);
+        assert!(!members.contains_key("c"));
+    }
+}
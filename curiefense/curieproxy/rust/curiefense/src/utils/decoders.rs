@@ -1,6 +1,7 @@
 use crate::interface::Location;
 use crate::requestfields::RequestField;
 
+use flate2::read::GzDecoder;
 use itertools::Itertools;
 use nom::branch::alt;
 use nom::bytes::complete::{is_a, tag, take_while, take_while_m_n};
@@ -102,7 +103,7 @@ fn urldecode_bytes(input: &[u8]) -> DecodingResult<Vec<u8>> {
     }
 }
 
-fn base64dec_all(input: &str) -> Result<Vec<u8>, &str> {
+pub fn base64dec_all(input: &str) -> Result<Vec<u8>, &str> {
     const BAD_PADDING_MESSAGE: &str = "bad padding";
     if input.len() % 4 == 1 {
         return Err(BAD_PADDING_MESSAGE);
@@ -165,6 +166,26 @@ pub fn base64dec_all_str(input: &str) -> Result<String, &str> {
     }
 }
 
+/// upper bound on the size of an inflated value, to defuse compression bombs
+const MAX_INFLATED_SIZE: u64 = 1 << 20;
+
+/// decodes a base64 encoded gzip stream into a string
+pub fn gunzip_base64(input: &str) -> DecodingResult<String> {
+    use std::io::Read;
+    let compressed = match base64dec_all(input) {
+        Ok(c) if c.starts_with(b"\x1f\x8b") => c,
+        _ => return DecodingResult::NoChange,
+    };
+    let mut out = Vec::new();
+    match GzDecoder::new(compressed.as_slice())
+        .take(MAX_INFLATED_SIZE)
+        .read_to_end(&mut out)
+    {
+        Ok(_) => DecodingResult::Changed(String::from_utf8_lossy(&out).into_owned()),
+        Err(_) => DecodingResult::NoChange,
+    }
+}
+
 /// decodes an url encoded string into a string, which can contain REPLACEMENT CHARACTER on decoding failure
 /// no changes if the source string did not contain '+' or '%'
 pub fn urldecode_str(input: &str) -> DecodingResult<String> {
@@ -355,6 +376,16 @@ pub fn htmlentities(input: &str) -> DecodingResult<String> {
 mod test_lib {
     use super::*;
 
+    #[test]
+    fn test_gunzip() {
+        assert_eq!(
+            gunzip_base64("H4sIAAAAAAACA7MpTi7KLCixS8xJLSrRMNS00YcKAAB27ib+GQAAAA=="),
+            DecodingResult::Changed("<script>alert(1)</script>".to_string())
+        );
+        assert_eq!(gunzip_base64("YXJndW1lbnQ="), DecodingResult::NoChange);
+        assert_eq!(gunzip_base64("not base64!"), DecodingResult::NoChange);
+    }
+
     #[test]
     fn test_urldecode_normal() {
         assert!(urldecode_str("ABCD") == DecodingResult::NoChange);
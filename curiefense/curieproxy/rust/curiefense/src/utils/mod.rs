@@ -1,9 +1,11 @@
 use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
 use ipnet::IpNet;
 use itertools::Itertools;
 use maxminddb::geoip2::country;
+use serde::Serialize;
 use serde_json::json;
-use sha2::{Digest, Sha224};
+use sha2::{Digest, Sha224, Sha256};
 use std::collections::HashMap;
 use std::net::IpAddr;
 use std::sync::Arc;
@@ -13,22 +15,28 @@ pub mod json;
 pub mod templating;
 pub mod url;
 
-use crate::body::parse_body;
-use crate::config::contentfilter::Transformation;
+use crate::body::{parse_body, ProtoContext, ProtobufSchema, UploadPolicy};
+use crate::clientip::{node_address, resolve_client_ip, ClientIp};
+use crate::config::contentfilter::{BodyAnalysisDepth, Transformation};
 use crate::config::hostmap::SecurityPolicy;
 use crate::config::matchers::{RequestSelector, RequestSelectorCondition};
 use crate::config::raw::ContentType;
 use crate::config::virtualtags::VirtualTags;
 use crate::geo::{
-    get_ipinfo_asn, get_ipinfo_carrier, get_ipinfo_company, get_ipinfo_location, get_ipinfo_privacy, get_maxmind_asn,
-    get_maxmind_city, get_maxmind_country, ipinfo_country_in_eu, ipinfo_resolve_continent, ipinfo_resolve_country_name,
-    USE_IPINFO,
+    get_ipinfo_asn, get_ipinfo_carrier, get_ipinfo_company, get_ipinfo_location, get_ipinfo_privacy,
+    get_maxmind_anonymous, get_maxmind_asn, get_maxmind_city, get_maxmind_country, ipinfo_country_in_eu,
+    ipinfo_resolve_continent, ipinfo_resolve_country_name,
 };
+use crate::geoprovider::geo_lookup;
 use crate::interface::stats::Stats;
-use crate::interface::{AnalyzeResult, Decision, Location, Tags};
+use crate::interface::{AnalyzeResult, BlockReason, Decision, Location, Tags};
 use crate::logs::Logs;
+use crate::memory::MemoryUsage;
+use crate::otel::TraceContext;
 use crate::requestfields::RequestField;
+use crate::requestid::resolve_request_id;
 use crate::utils::decoders::{parse_urlencoded_params, urldecode_str, DecodingResult};
+use crate::utils::url::{normalize_path, NormalizedPath};
 
 pub fn cookie_map(cookies: &mut RequestField, cookie: &str) {
     // tries to split the cookie around "="
@@ -49,9 +57,13 @@ pub fn cookie_map(cookies: &mut RequestField, cookie: &str) {
 /// * extract cookies
 ///
 /// Returns (headers, cookies)
-pub fn map_headers(dec: &[Transformation], rawheaders: &HashMap<String, String>) -> (RequestField, RequestField) {
-    let mut cookies = RequestField::new(dec);
-    let mut headers = RequestField::new(dec);
+pub fn map_headers(
+    dec: &[Transformation],
+    decoding_depth: usize,
+    rawheaders: &HashMap<String, String>,
+) -> (RequestField, RequestField) {
+    let mut cookies = RequestField::with_decoding(dec, decoding_depth);
+    let mut headers = RequestField::with_decoding(dec, decoding_depth);
     for (k, v) in rawheaders {
         let lk = k.to_lowercase();
         if lk == "cookie" {
@@ -102,6 +114,10 @@ fn parse_query_params(rf: &mut RequestField, query: &str, mode: ParseUriMode) {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum BodyProblem {
     TooDeep,
+    /// the graphql document has more fields than the allowed maximum
+    TooComplex(usize),
+    /// an uploaded file was rejected by the upload policy: reason, actual, expected
+    ForbiddenUpload(&'static str, String, String),
     DecodingError(String, Option<String>),
 }
 
@@ -109,6 +125,10 @@ impl std::fmt::Display for BodyProblem {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             BodyProblem::TooDeep => "too deep".fmt(f),
+            BodyProblem::TooComplex(max) => write!(f, "too complex, more than {} fields", max),
+            BodyProblem::ForbiddenUpload(reason, actual, expected) => {
+                write!(f, "{}, actual:{} expected:{}", reason, actual, expected)
+            }
             BodyProblem::DecodingError(actual, expected) => match expected {
                 Some(e) => write!(f, "actual:{} expected:{}", actual, e),
                 None => actual.fmt(f),
@@ -160,28 +180,51 @@ fn parse_uri(
 
 /// parses the request uri, storing the path and query parts (if possible)
 /// returns the hashmap of arguments
+#[allow(clippy::too_many_arguments)]
 fn map_args(
     logs: &mut Logs,
     dec: &[Transformation],
+    decoding_depth: usize,
     path: &str,
     mcontent_type: Option<&str>,
     accepted_types: &[ContentType],
     mbody: Option<&[u8]>,
     max_depth: usize,
+    max_complexity: usize,
+    mproto: Option<&ProtobufSchema>,
+    uploads: &UploadPolicy,
 ) -> QueryInfo {
     // this is necessary to do this in this convoluted way so at not to borrow attrs
     let uri = match urldecode_str(path) {
         DecodingResult::NoChange => path.to_string(),
         DecodingResult::Changed(nuri) => nuri,
     };
-    let mut args = RequestField::new(dec);
-    let mut path_as_map = RequestField::new(dec);
+    let mut args = RequestField::with_decoding(dec, decoding_depth);
+    let mut path_as_map = RequestField::with_decoding(dec, decoding_depth);
     let (qpath, query) = parse_uri(&mut args, &mut path_as_map, path, ParseUriMode::Uri);
+    let normalized = normalize_path(&qpath);
+    if normalized.rewritten {
+        // the content filter also sees the path the upstream server will probably serve
+        path_as_map.add("path:normalized".to_string(), Location::Uri, normalized.path.clone());
+    }
     logs.debug("uri parsed");
 
     let body_decoding = if let Some(body) = mbody {
         logs.debug("body parsing start");
-        if let Err(rr) = parse_body(logs, &mut args, max_depth, mcontent_type, accepted_types, body) {
+        if let Err(rr) = parse_body(
+            logs,
+            &mut args,
+            max_depth,
+            max_complexity,
+            ProtoContext {
+                schema: mproto,
+                path: &qpath,
+            },
+            uploads,
+            mcontent_type,
+            accepted_types,
+            body,
+        ) {
             // if the body could not be parsed, store it in an argument, as if it was text
             args.add(
                 "RAW_BODY".to_string(),
@@ -201,11 +244,13 @@ fn map_args(
 
     QueryInfo {
         qpath,
+        normalized,
         query,
         uri,
         args,
         path_as_map,
         body_decoding,
+        body_size: mbody.map(|b| b.len()).unwrap_or(0),
     }
 }
 
@@ -214,6 +259,8 @@ fn map_args(
 pub struct QueryInfo {
     /// the "path" portion of the raw query path
     pub qpath: String,
+    /// the path after decoding and dot segment removal, used to select the security policy entry
+    pub normalized: NormalizedPath,
     /// the "query" portion of the raw query path
     pub query: Option<String>,
     /// URL decoded path, if decoding worked
@@ -221,9 +268,11 @@ pub struct QueryInfo {
     pub args: RequestField,
     pub path_as_map: RequestField,
     pub body_decoding: BodyDecodingResult,
+    /// size of the raw body, in bytes
+    pub body_size: usize,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct GeoIp {
     // IP informations
     pub ipstr: String,
@@ -329,6 +378,16 @@ pub struct RequestMeta {
     pub path: String,
     pub requestid: Option<String>,
     pub protocol: Option<String>,
+    /// TLS client fingerprint, computed by the proxy
+    pub tls_fingerprint: Option<TlsFingerprint>,
+    /// header names, in the order and with the casing they were received, when the proxy provides them
+    pub header_order: Option<Vec<String>>,
+    /// source address of the PROXY protocol header, when the proxy received one
+    pub proxy_source: Option<String>,
+    /// server name of the TLS handshake, when the proxy provides it
+    pub sni: Option<String>,
+    /// client certificate of a mutual TLS connection, when the proxy provides it
+    pub client_cert: Option<ClientCert>,
     /// this field only exists for gradual Lua interop
     /// TODO: remove when complete
     pub extra: HashMap<String, String>,
@@ -342,6 +401,22 @@ impl RequestMeta {
         let protocol = mattrs.remove("protocol");
         let method = mattrs.remove("method").ok_or("missing method field")?;
         let path = mattrs.remove("path").ok_or("missing path field")?;
+        let ja3 = mattrs.remove("ja3");
+        let ja4 = mattrs.remove("ja4");
+        let tls_fingerprint = mattrs
+            .remove("tls-fingerprint")
+            .or(ja4)
+            .or(ja3)
+            .and_then(|raw| TlsFingerprint::parse(&raw));
+        let header_order = mattrs
+            .remove("header-order")
+            .map(|order| order.split(',').map(|h| h.trim().to_string()).collect());
+        let proxy_source = mattrs
+            .remove("proxy-protocol-source")
+            .map(|source| node_address(&source).to_string())
+            .filter(|source| !source.is_empty());
+        let sni = mattrs.remove("sni").filter(|sni| !sni.is_empty());
+        let client_cert = ClientCert::from_attributes(&mut mattrs);
         Ok(RequestMeta {
             authority,
             method,
@@ -349,10 +424,127 @@ impl RequestMeta {
             extra: mattrs,
             requestid,
             protocol,
+            tls_fingerprint,
+            header_order,
+            proxy_source,
+            sni,
+            client_cert,
         })
     }
 }
 
+/// client certificate details, passed by the proxy in the client-cert-* attributes
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, arbitrary::Arbitrary)]
+pub struct ClientCert {
+    /// distinguished name
+    pub subject: Option<String>,
+    /// subject alternative names
+    pub san: Vec<String>,
+    /// lowercase, without colons
+    pub fingerprint: Option<String>,
+    /// unix timestamps
+    pub not_before: Option<i64>,
+    pub not_after: Option<i64>,
+}
+
+/// validity dates are RFC 3339 dates or unix timestamps
+fn parse_cert_date(raw: &str) -> Option<i64> {
+    let raw = raw.trim();
+    raw.parse()
+        .ok()
+        .or_else(|| DateTime::parse_from_rfc3339(raw).ok().map(|d| d.timestamp()))
+}
+
+pub fn normalize_cert_fingerprint(fp: &str) -> String {
+    fp.trim()
+        .chars()
+        .filter(|c| *c != ':')
+        .collect::<String>()
+        .to_lowercase()
+}
+
+impl ClientCert {
+    fn from_attributes(attrs: &mut HashMap<String, String>) -> Option<Self> {
+        let mut take = |name: &str| attrs.remove(name).filter(|v| !v.trim().is_empty());
+        let cert = ClientCert {
+            subject: take("client-cert-subject").map(|s| s.trim().to_string()),
+            san: take("client-cert-san")
+                .map(|sans| {
+                    sans.split(',')
+                        .map(|s| s.trim().to_string())
+                        .filter(|s| !s.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            fingerprint: take("client-cert-fingerprint").map(|fp| normalize_cert_fingerprint(&fp)),
+            not_before: take("client-cert-not-before").and_then(|d| parse_cert_date(&d)),
+            not_after: take("client-cert-not-after").and_then(|d| parse_cert_date(&d)),
+        };
+        if cert == ClientCert::default() {
+            None
+        } else {
+            Some(cert)
+        }
+    }
+
+    /// the problem with the validity dates, if any
+    pub fn validity(&self, now: i64) -> Option<&'static str> {
+        if self.not_before.map(|nb| now < nb).unwrap_or(false) {
+            Some("not-yet-valid")
+        } else if self.not_after.map(|na| now > na).unwrap_or(false) {
+            Some("expired")
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, arbitrary::Arbitrary)]
+pub enum TlsFingerprintKind {
+    Ja3,
+    Ja4,
+}
+
+impl TlsFingerprintKind {
+    /// the tag prefix
+    pub fn name(&self) -> &'static str {
+        match self {
+            TlsFingerprintKind::Ja3 => "ja3",
+            TlsFingerprintKind::Ja4 => "ja4",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, arbitrary::Arbitrary)]
+pub struct TlsFingerprint {
+    pub kind: TlsFingerprintKind,
+    /// the JA3 md5 hash, or the JA4 fingerprint, lowercase
+    pub hash: String,
+}
+
+impl TlsFingerprint {
+    /// accepts JA4 fingerprints, JA3 hashes, and full JA3 strings, that are hashed
+    pub fn parse(raw: &str) -> Option<Self> {
+        let raw = raw.trim();
+        if raw.is_empty() {
+            return None;
+        }
+        let parts: Vec<&str> = raw.split('_').collect();
+        let is_ja4 = parts.len() == 3
+            && parts[0].len() == 10
+            && raw.starts_with(['t', 'q', 'd'])
+            && parts.iter().all(|p| p.chars().all(|c| c.is_ascii_alphanumeric()));
+        let (kind, hash) = if is_ja4 {
+            (TlsFingerprintKind::Ja4, raw.to_ascii_lowercase())
+        } else if raw.len() == 32 && raw.chars().all(|c| c.is_ascii_hexdigit()) {
+            (TlsFingerprintKind::Ja3, raw.to_ascii_lowercase())
+        } else {
+            (TlsFingerprintKind::Ja3, format!("{:x}", md5::compute(raw)))
+        };
+        Some(TlsFingerprint { kind, hash })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RInfo {
     pub meta: RequestMeta,
@@ -361,6 +553,8 @@ pub struct RInfo {
     pub host: String,
     pub secpolicy: Arc<SecurityPolicy>,
     pub container_name: Option<String>,
+    /// the client address, when resolved from the forwarding headers
+    pub client_ip: Option<ClientIp>,
 }
 
 #[derive(Debug, Clone)]
@@ -372,6 +566,20 @@ pub struct RequestInfo {
     pub session: String,
     pub session_ids: HashMap<String, String>,
     pub plugins: RequestField,
+    /// memory charged while analyzing the request
+    pub memory: MemoryUsage,
+    /// incoming trace context, from the traceparent and tracestate headers
+    pub trace: Option<TraceContext>,
+    /// leases on the in-flight limit counters taken by the request, to be released when it completes
+    pub inflight: Vec<String>,
+    /// content filter matches found while the body was streamed
+    pub streamed_body: Vec<BlockReason>,
+    /// Set-Cookie value of the clearance issued to the client, once recognized as human
+    pub clearance: Option<String>,
+    /// API key extracted with the settings of the security policy entry
+    pub api_key: Option<String>,
+    /// sub claim of the JWT, only set once its signature and claims were verified
+    pub jwt_subject: Option<String>,
 }
 
 impl RequestInfo {
@@ -444,6 +652,65 @@ impl InspectionResult {
         async_std::task::block_on(self.log_json(proxy))
     }
 
+    /// false when the request is sampled out of the logs, see the accesslog module
+    pub fn should_log(&self) -> bool {
+        let dtags = Tags::new(&VirtualTags::default());
+        match &self.rinfo {
+            None => true,
+            Some(rinfo) => crate::accesslog::should_log(&self.decision, rinfo, self.tags.as_ref().unwrap_or(&dtags)),
+        }
+    }
+
+    /// the structured access log record, see the accesslog module
+    pub fn access_log_json(&self, status: Option<u32>) -> Vec<u8> {
+        let dtags = Tags::new(&VirtualTags::default());
+        match &self.rinfo {
+            None => b"{}".to_vec(),
+            Some(rinfo) => crate::accesslog::access_log_json(
+                &self.decision,
+                rinfo,
+                self.tags.as_ref().unwrap_or(&dtags),
+                &self.stats,
+                status,
+            ),
+        }
+    }
+
+    /// the result in a SIEM format, see the siem module, None for the native format
+    pub fn siem_record(&self, format: crate::siem::LogFormat, status: Option<u32>) -> Option<Vec<u8>> {
+        let dtags = Tags::new(&VirtualTags::default());
+        let rinfo = self.rinfo.as_ref()?;
+        crate::siem::siem_record(
+            format,
+            &self.decision,
+            rinfo,
+            self.tags.as_ref().unwrap_or(&dtags),
+            status,
+        )
+    }
+
+    /// inspects the response of the upstream server, None when the request could not be mapped
+    pub fn inspect_response(
+        &self,
+        logs: &mut Logs,
+        status: u32,
+        headers: HashMap<String, String>,
+        body: Option<Vec<u8>>,
+    ) -> Option<crate::analyze::ResponseAnalyzeResult> {
+        let reqinfo = self.rinfo.clone()?;
+        let tags = self.tags.clone().unwrap_or_else(|| Tags::new(&VirtualTags::default()));
+        Some(crate::analyze::analyze_response(
+            logs,
+            crate::analyze::APhaseResp {
+                reqinfo,
+                tags,
+                status,
+                headers,
+                body,
+            },
+        ))
+    }
+
     pub fn from_analyze(logs: Logs, dec: AnalyzeResult) -> Self {
         InspectionResult {
             decision: dec.decision,
@@ -461,10 +728,10 @@ pub fn find_geoip_maxmind(logs: &mut Logs, geoip: &mut GeoIp, ip: IpAddr) {
         mmap.as_ref().and_then(|mp| mp.get("en")).map(|s| s.to_lowercase())
     };
 
-    if let Ok((asninfo, _)) = get_maxmind_asn(ip) {
+    let _ = get_maxmind_asn(ip, |asninfo, _| {
         geoip.asn = asninfo.autonomous_system_number;
         geoip.company = asninfo.autonomous_system_organization.map(|s| s.to_string());
-    }
+    });
 
     let extract_continent = |g: &mut GeoIp, mcnt: Option<country::Continent>| {
         if let Some(continent) = mcnt {
@@ -489,14 +756,14 @@ pub fn find_geoip_maxmind(logs: &mut Logs, geoip: &mut GeoIp, ip: IpAddr) {
         }
     };
 
-    if let Ok((cnty, network)) = get_maxmind_country(ip) {
+    let _ = get_maxmind_country(ip, |cnty, network| {
         extract_continent(geoip, cnty.continent);
         extract_country(geoip, cnty.country);
         extract_network(geoip, network);
         extract_mm_traits(geoip, cnty.traits);
-    }
+    });
 
-    if let Ok((cty, network)) = get_maxmind_city(ip) {
+    let _ = get_maxmind_city(ip, |cty, network| {
         extract_continent(geoip, cty.continent);
         extract_country(geoip, cty.country);
         extract_network(geoip, network);
@@ -517,7 +784,19 @@ pub fn find_geoip_maxmind(logs: &mut Logs, geoip: &mut GeoIp, ip: IpAddr) {
             }
         }
         geoip.city_name = cty.city.as_ref().and_then(|c| get_name(&c.names));
-    }
+    });
+
+    // the anonymous IP database is optional
+    let _ = get_maxmind_anonymous(ip, |anon, _| {
+        geoip.is_vpn = Some(anon.is_anonymous_vpn.unwrap_or(false));
+        geoip.is_tor = Some(anon.is_tor_exit_node.unwrap_or(false));
+        geoip.is_hosting = Some(anon.is_hosting_provider.unwrap_or(false));
+        geoip.is_proxy = Some(
+            geoip.is_proxy.unwrap_or(false)
+                || anon.is_public_proxy.unwrap_or(false)
+                || anon.is_residential_proxy.unwrap_or(false),
+        );
+    });
 }
 
 // Network field priority: ASN > Carrier > Company > Location
@@ -645,11 +924,7 @@ pub fn find_geoip(logs: &mut Logs, ipstr: String) -> GeoIp {
 
     geoip.ip = Some(ip);
 
-    if *USE_IPINFO {
-        find_geoip_ipinfo(logs, &mut geoip, ip);
-    } else {
-        find_geoip_maxmind(logs, &mut geoip, ip);
-    }
+    geo_lookup(logs, &mut geoip, ip);
 
     geoip
 }
@@ -668,6 +943,16 @@ impl<'a> RawRequest<'a> {
             None => "unknown".to_string(),
         }
     }
+
+    /// the resolved client address, see the clientip module
+    pub fn client_address(&self) -> String {
+        // the PROXY protocol source is the address of the client connecting to the load balancer
+        let peer = self.meta.proxy_source.as_deref().unwrap_or(&self.ipstr);
+        match resolve_client_ip(peer, &self.headers) {
+            Some(c) => c.ip,
+            None => peer.to_string(),
+        }
+    }
 }
 
 pub fn map_request(
@@ -677,26 +962,54 @@ pub fn map_request(
     raw: &RawRequest,
     ts: Option<DateTime<Utc>>,
     plugins: HashMap<String, String>,
+) -> RequestInfo {
+    let mut memory = MemoryUsage::new(secpolicy.content_filter_profile.max_request_memory);
+    let mut reqinfo = memory.track(|| map_request_fields(logs, secpolicy, container_name, raw, ts, plugins));
+    reqinfo.memory = memory;
+    reqinfo
+}
+
+fn map_request_fields(
+    logs: &mut Logs,
+    secpolicy: Arc<SecurityPolicy>,
+    container_name: Option<String>,
+    raw: &RawRequest,
+    ts: Option<DateTime<Utc>>,
+    plugins: HashMap<String, String>,
 ) -> RequestInfo {
     let host = raw.get_host();
 
     logs.debug("map_request starts");
-    let (headers, cookies) = map_headers(&secpolicy.content_filter_profile.decoding, &raw.headers);
+    let case_insensitive = secpolicy.content_filter_profile.case_insensitive_names;
+    let (headers, mut cookies) = map_headers(
+        &secpolicy.content_filter_profile.decoding,
+        secpolicy.content_filter_profile.decoding_depth,
+        &raw.headers,
+    );
+    cookies.case_insensitive = case_insensitive;
     logs.debug("headers mapped");
-    let geoip = find_geoip(logs, raw.ipstr.clone());
+    // the PROXY protocol source is the address of the client connecting to the load balancer
+    let peer = raw.meta.proxy_source.as_deref().unwrap_or(&raw.ipstr);
+    let client_ip = resolve_client_ip(peer, &raw.headers);
+    let ipstr = client_ip
+        .as_ref()
+        .map(|c| c.ip.clone())
+        .unwrap_or_else(|| peer.to_string());
+    let geoip = find_geoip(logs, ipstr);
     logs.debug("geoip computed");
     let mut qinfo = map_args(
         logs,
         &secpolicy.content_filter_profile.decoding,
+        secpolicy.content_filter_profile.decoding_depth,
         &raw.meta.path,
         headers.get_str("content-type"),
         &secpolicy.content_filter_profile.content_type,
-        if secpolicy.content_filter_profile.ignore_body {
-            None
-        } else {
-            raw.mbody
-        },
+        raw.mbody
+            .filter(|body| secpolicy.content_filter_profile.body_depth(body.len()) == BodyAnalysisDepth::Full),
         secpolicy.content_filter_profile.max_body_depth,
+        secpolicy.content_filter_profile.max_graphql_complexity,
+        secpolicy.content_filter_profile.protobuf.as_deref(),
+        &secpolicy.content_filter_profile.uploads,
     );
     if secpolicy.content_filter_profile.referer_as_uri {
         if let Some(rf) = headers.get("referer") {
@@ -708,23 +1021,47 @@ pub fn map_request(
             );
         }
     }
+    if let Some(captures) = secpolicy
+        .path_template
+        .as_ref()
+        .and_then(|tpl| tpl.captures(&qinfo.qpath))
+    {
+        for (name, value) in captures {
+            let loc = Location::UriArgumentValue(name.clone(), value.clone());
+            qinfo.args.add(name, loc, value);
+        }
+    }
+    qinfo.args.case_insensitive = case_insensitive;
     logs.debug("args mapped");
 
+    let mut meta = raw.meta.clone();
+    let request_id = resolve_request_id(meta.requestid.as_deref(), &raw.headers);
+    logs.request_id = Some(request_id.clone());
+    meta.requestid = Some(request_id);
     let rinfo = RInfo {
-        meta: raw.meta.clone(),
+        meta,
         geoip,
         qinfo,
         host,
         secpolicy: secpolicy.clone(),
         container_name,
+        client_ip,
     };
 
     let mut plugins_field = RequestField::new(&[]);
+    plugins_field.case_insensitive = case_insensitive;
     for (k, v) in plugins {
         let l = Location::PluginValue(k.clone(), v.clone());
         plugins_field.add(k, l, v);
     }
 
+    let api_key = secpolicy
+        .api_key
+        .as_ref()
+        .and_then(|settings| settings.extract(&headers, &rinfo.qinfo.args));
+
+    let trace = TraceContext::from_headers(&headers);
+    logs.trace = trace.clone();
     let dummy_reqinfo = RequestInfo {
         timestamp: ts.unwrap_or_else(Utc::now),
         cookies,
@@ -733,6 +1070,13 @@ pub fn map_request(
         session: String::new(),
         session_ids: HashMap::new(),
         plugins: plugins_field,
+        memory: MemoryUsage::default(),
+        trace,
+        inflight: Vec::new(),
+        streamed_body: Vec::new(),
+        clearance: None,
+        api_key,
+        jwt_subject: None,
     };
 
     let raw_session = (if secpolicy.session.is_empty() {
@@ -768,6 +1112,13 @@ pub fn map_request(
         session,
         session_ids,
         plugins: dummy_reqinfo.plugins,
+        memory: dummy_reqinfo.memory,
+        trace: dummy_reqinfo.trace,
+        inflight: Vec::new(),
+        streamed_body: Vec::new(),
+        clearance: None,
+        api_key: dummy_reqinfo.api_key,
+        jwt_subject: None,
     }
 }
 
@@ -794,6 +1145,7 @@ pub fn selector<'a>(reqinfo: &'a RequestInfo, sel: &RequestSelector, tags: Optio
         RequestSelector::Query => reqinfo.rinfo.qinfo.query.as_ref().map(Selected::Str),
         RequestSelector::Method => Some(&reqinfo.rinfo.meta.method).map(Selected::Str),
         RequestSelector::Country => reqinfo.rinfo.geoip.country_iso.as_ref().map(Selected::Str),
+        RequestSelector::Continent => reqinfo.rinfo.geoip.continent_code.as_ref().map(Selected::Str),
         RequestSelector::Authority => Some(Selected::Str(&reqinfo.rinfo.host)),
         RequestSelector::Company => reqinfo.rinfo.geoip.company.as_ref().map(Selected::Str),
         RequestSelector::Asn => reqinfo.rinfo.geoip.asn.map(Selected::U32),
@@ -803,6 +1155,7 @@ pub fn selector<'a>(reqinfo: &'a RequestInfo, sel: &RequestSelector, tags: Optio
         RequestSelector::Region => reqinfo.rinfo.geoip.region.as_ref().map(Selected::Str),
         RequestSelector::SubRegion => reqinfo.rinfo.geoip.subregion.as_ref().map(Selected::Str),
         RequestSelector::Session => Some(Selected::Str(&reqinfo.session)),
+        RequestSelector::ApiKey => reqinfo.api_key.as_ref().map(Selected::Str),
     }
 }
 
@@ -835,6 +1188,21 @@ pub fn masker(seed: &[u8], value: &str) -> String {
     format!("MASKED{{{}}}", &hash_str[0..8])
 }
 
+/// signs the message with the key, as done by the signed tokens and cookies
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC keys can have any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// current unix time, in milliseconds, as stored in the counters
+pub fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
 pub fn eat_errors<T: Default, R: std::fmt::Display>(logs: &mut Logs, rv: Result<T, R>) -> T {
     match rv {
         Err(rr) => {
@@ -849,17 +1217,66 @@ pub fn eat_errors<T: Default, R: std::fmt::Display>(logs: &mut Logs, rv: Result<
 mod tests {
     use super::*;
 
+    #[test]
+    fn hmac_test_vector() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn tls_fingerprints() {
+        let ja4 = TlsFingerprint::parse("t13d1516h2_8daaf6152771_B186095E22B6").unwrap();
+        assert_eq!(ja4.kind, TlsFingerprintKind::Ja4);
+        assert_eq!(ja4.hash, "t13d1516h2_8daaf6152771_b186095e22b6");
+        let ja3 = TlsFingerprint::parse("650293D7A2FFB5335422221C5D75A9C9").unwrap();
+        assert_eq!(ja3.kind, TlsFingerprintKind::Ja3);
+        assert_eq!(ja3.hash, "650293d7a2ffb5335422221c5d75a9c9");
+        let full = TlsFingerprint::parse("771,4865-4866-4867,0-23-65281,29-23-24,0").unwrap();
+        assert_eq!(full, ja3);
+        assert_eq!(TlsFingerprint::parse(" "), None);
+    }
+
+    #[test]
+    fn client_certs() {
+        let mut attrs: HashMap<String, String> = [
+            ("client-cert-subject", "CN=svc-a,O=Acme"),
+            ("client-cert-san", "svc-a.internal, spiffe://acme/svc-a"),
+            ("client-cert-fingerprint", "AB:CD:01"),
+            ("client-cert-not-before", "2024-01-01T00:00:00Z"),
+            ("client-cert-not-after", "1767225600"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let cert = ClientCert::from_attributes(&mut attrs).unwrap();
+        assert!(attrs.is_empty());
+        assert_eq!(cert.san, vec!["svc-a.internal", "spiffe://acme/svc-a"]);
+        assert_eq!(cert.fingerprint.as_deref(), Some("abcd01"));
+        assert_eq!(cert.not_before, Some(1704067200));
+        assert_eq!(cert.validity(1700000000), Some("not-yet-valid"));
+        assert_eq!(cert.validity(1750000000), None);
+        assert_eq!(cert.validity(1800000000), Some("expired"));
+        assert_eq!(ClientCert::from_attributes(&mut HashMap::new()), None);
+    }
+
     #[test]
     fn test_map_args_full() {
         let mut logs = Logs::default();
         let qinfo = map_args(
             &mut logs,
             &[Transformation::Base64Decode],
+            1,
             "/a/b/%20c?xa%20=12&bbbb=12%28&cccc&b64=YXJndW1lbnQ%3D",
             None,
             &[],
             None,
             500,
+            usize::MAX,
+            None,
+            &UploadPolicy::default(),
         );
 
         assert_eq!(qinfo.qpath, "/a/b/%20c");
@@ -908,7 +1325,19 @@ mod tests {
     #[test]
     fn test_map_args_simple() {
         let mut logs = Logs::default();
-        let qinfo = map_args(&mut logs, &[], "/a/b", None, &[], None, 500);
+        let qinfo = map_args(
+            &mut logs,
+            &[],
+            1,
+            "/a/b",
+            None,
+            &[],
+            None,
+            500,
+            usize::MAX,
+            None,
+            &UploadPolicy::default(),
+        );
 
         assert_eq!(qinfo.qpath, "/a/b");
         assert_eq!(qinfo.uri, "/a/b");
@@ -932,6 +1361,11 @@ mod tests {
                 path: "/this/is/the/path?arg1=x&arg2=y".to_string(),
                 requestid: None,
                 protocol: None,
+                tls_fingerprint: None,
+                header_order: None,
+                proxy_source: None,
+                sni: None,
+                client_cert: None,
                 extra: HashMap::new(),
             },
             mbody: None,
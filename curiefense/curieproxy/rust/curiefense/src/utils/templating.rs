@@ -97,6 +97,30 @@ pub fn parse_request_template(i: &str) -> RequestTemplate {
     parse_template(parse_tvar, i).into_iter().map(|p| p.owned()).collect()
 }
 
+/// variables of the response templates, that also know about the decision
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum RVar {
+    Request(TVar),
+    RequestId,
+    Reasons,
+    Support,
+}
+
+fn parse_rvar(input: &str) -> IResult<&str, RVar> {
+    alt((
+        map(tag("request_id"), |_| RVar::RequestId),
+        map(tag("reasons"), |_| RVar::Reasons),
+        map(tag("support"), |_| RVar::Support),
+        map(parse_tvar, RVar::Request),
+    ))(input)
+}
+
+pub type ResponseBodyTemplate = Vec<TemplatePart<RVar>>;
+
+pub fn parse_response_template(i: &str) -> ResponseBodyTemplate {
+    parse_template(parse_rvar, i).into_iter().map(|p| p.owned()).collect()
+}
+
 #[cfg(test)]
 mod test {
     use nom::bytes::complete::take_till1;
@@ -162,4 +186,22 @@ mod test {
             ]
         )
     }
+
+    #[test]
+    fn response_variables() {
+        use TemplatePart::*;
+        assert_eq!(
+            parse_response_template("${request_id} ${reasons}${support} ${tags.blocked} ${supports}"),
+            vec![
+                Var(RVar::RequestId),
+                Raw(" ".to_string()),
+                Var(RVar::Reasons),
+                Var(RVar::Support),
+                Raw(" ".to_string()),
+                Var(RVar::Request(TVar::Tag("blocked".to_string()))),
+                Raw(" ".to_string()),
+                Raw("${supports}".to_string()),
+            ]
+        )
+    }
 }
@@ -1,3 +1,5 @@
+use unicode_normalization::UnicodeNormalization;
+
 use nom::{
     bytes::complete::{tag, take_while},
     combinator::opt,
@@ -65,10 +67,124 @@ pub fn drop_scheme(uri: &str) -> &str {
     }
 }
 
+/// number of times percent decoding is applied, to undo double encodings such as %252e
+const MAX_PERCENT_DECODING: usize = 3;
+
+fn percent_decode_once(input: &str) -> Option<String> {
+    if !input.contains('%') {
+        return None;
+    }
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    let mut changed = false;
+    while i < bytes.len() {
+        let hex = |b: u8| (b as char).to_digit(16);
+        match (
+            bytes[i],
+            bytes.get(i + 1).and_then(|b| hex(*b)),
+            bytes.get(i + 2).and_then(|b| hex(*b)),
+        ) {
+            (b'%', Some(h), Some(l)) => {
+                out.push((h * 16 + l) as u8);
+                i += 3;
+                changed = true;
+            }
+            (b, _, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    if changed {
+        Some(String::from_utf8_lossy(&out).into_owned())
+    } else {
+        None
+    }
+}
+
+/// a request path, as it is understood by most servers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NormalizedPath {
+    pub path: String,
+    /// dot segments, backslashes or empty segments were removed, the decoded path does not look like the raw one
+    pub rewritten: bool,
+}
+
+/// percent decodes a path, converts backslashes to slashes, applies unicode NFC normalization, collapses
+/// repeated slashes and removes the dot segments
+pub fn normalize_path(path: &str) -> NormalizedPath {
+    let mut decoded = path.to_string();
+    for _ in 0..MAX_PERCENT_DECODING {
+        match percent_decode_once(&decoded) {
+            Some(d) => decoded = d,
+            None => break,
+        }
+    }
+    let decoded: String = decoded.replace('\\', "/").nfc().collect();
+    if !decoded.starts_with('/') {
+        return NormalizedPath {
+            path: decoded,
+            rewritten: false,
+        };
+    }
+    let mut segments: Vec<&str> = Vec::new();
+    let mut rewritten = false;
+    let raw_segments: Vec<&str> = decoded[1..].split('/').collect();
+    let last = raw_segments.len() - 1;
+    let mut trailing_slash = false;
+    for (i, segment) in raw_segments.into_iter().enumerate() {
+        match segment {
+            "." | ".." => {
+                rewritten = true;
+                if segment == ".." {
+                    segments.pop();
+                }
+                trailing_slash = i == last;
+            }
+            "" if i == last => trailing_slash = true,
+            "" => rewritten = true,
+            s => segments.push(s),
+        }
+    }
+    let mut out = String::with_capacity(decoded.len());
+    out.push('/');
+    out.push_str(&segments.join("/"));
+    if trailing_slash && !segments.is_empty() {
+        out.push('/');
+    }
+    rewritten |= path.contains('\\');
+    NormalizedPath { path: out, rewritten }
+}
+
+/// normalizes the path part of an uri, keeping the query string untouched
+pub fn normalize_uri(uri: &str) -> String {
+    match uri.split_once('?') {
+        Some((path, query)) => format!("{}?{}", normalize_path(path).path, query),
+        None => normalize_path(uri).path,
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn path_normalization() {
+        let norm = |p: &str| normalize_path(p).path;
+        assert_eq!(norm("/admin/%2e%2e/secret"), "/secret");
+        assert_eq!(norm("/a/./b/../../../c"), "/c");
+        assert_eq!(norm("/a//b\\..\\c/"), "/a/c/");
+        assert_eq!(norm("/static/%252e%252e/%252e%252e/etc/passwd"), "/etc/passwd");
+        assert_eq!(norm("/caf\u{65}\u{301}"), "/caf\u{e9}");
+        assert_eq!(norm("/a/b/.."), "/a/");
+        assert_eq!(norm("/"), "/");
+        assert_eq!(norm("*"), "*");
+        assert!(!normalize_path("/a/b%20c/").rewritten);
+        assert!(normalize_path("/a/%2e/b").rewritten);
+        assert_eq!(normalize_uri("/a/../b?x=/../y"), "/b?x=/../y");
+    }
+
     fn check(uri: &str, scheme: &str, userinfo: Option<&str>, hostport: &str, rm: &str) {
         let expected = Uri {
             _scheme: scheme,
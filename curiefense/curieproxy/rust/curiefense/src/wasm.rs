@@ -0,0 +1,397 @@
+//! WASM plugins.
+//!
+//! Plugins are listed in wasm-plugins.json, and run as analysis hooks in the phases they are configured
+//! for. They are sandboxed: modules can't import anything, and each call runs in a fresh instance, with
+//! bounded fuel and memory. Plugin failures are logged and tagged (wasm-plugin-error:ID), but never
+//! change the decision.
+//!
+//! A module must export:
+//!
+//!  * `memory`,
+//!  * `alloc(len: i32) -> i32`, returning a buffer where the input is written,
+//!  * `on_init`, `on_pre_acl`, `on_pre_contentfilter` and/or `on_finish`, of type `(ptr: i32, len: i32) -> i64`.
+//!
+//! The input is a JSON document with the `phase`, the `request` (as in the logs, without tags), its current
+//! `tags` and, for the finish phase, the current `decision`. Phase functions return 0 when they have
+//! nothing to say, or a pointer and length, packed as `ptr << 32 | len`, to a JSON answer:
+//!
+//! `{"tags": ["..."], "decision": {"action": "block", "status": 403, "content": "...", "reason": "..."}}`
+//!
+//! This requires the `wasm` feature, without it plugins are rejected when loading the configuration.
+#[cfg(feature = "wasm")]
+use serde::Deserialize;
+#[cfg(feature = "wasm")]
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use crate::config::raw::RawWasmPlugin;
+use crate::hooks::{AnalyzeHook, Hooks};
+use crate::logs::Logs;
+
+/// default execution budget of a call, roughly a number of instructions
+const DEFAULT_FUEL: u64 = 10_000_000;
+/// default maximum size of the linear memory
+const DEFAULT_MAX_MEMORY: usize = 16 * 1024 * 1024;
+
+const PHASES: [&str; 4] = ["init", "pre_acl", "pre_contentfilter", "finish"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WasmPluginSettings {
+    pub id: String,
+    pub name: String,
+    pub path: String,
+    pub phases: HashSet<&'static str>,
+    pub fuel: u64,
+    pub max_memory: usize,
+}
+
+impl WasmPluginSettings {
+    fn resolve(raw: RawWasmPlugin) -> anyhow::Result<Self> {
+        let mut phases = HashSet::new();
+        for p in &raw.phases {
+            match PHASES.iter().find(|known| *known == p) {
+                Some(known) => phases.insert(*known),
+                None => anyhow::bail!("unknown phase {} in plugin {}", p, raw.id),
+            };
+        }
+        Ok(WasmPluginSettings {
+            id: raw.id,
+            name: raw.name,
+            path: raw.path,
+            phases,
+            fuel: raw.fuel.unwrap_or(DEFAULT_FUEL),
+            max_memory: raw.max_memory.unwrap_or(DEFAULT_MAX_MEMORY),
+        })
+    }
+}
+
+#[cfg(feature = "wasm")]
+#[derive(Debug, Deserialize, Default)]
+struct PluginOutput {
+    #[serde(default)]
+    tags: Vec<String>,
+    decision: Option<PluginDecision>,
+}
+
+#[cfg(feature = "wasm")]
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum PluginAction {
+    Block,
+    Monitor,
+}
+
+#[cfg(feature = "wasm")]
+fn default_status() -> u32 {
+    403
+}
+
+#[cfg(feature = "wasm")]
+#[derive(Debug, Deserialize)]
+struct PluginDecision {
+    action: PluginAction,
+    #[serde(default = "default_status")]
+    status: u32,
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    #[serde(default)]
+    reason: String,
+}
+
+/// loads the active plugins, as hooks
+pub fn load_plugins(logs: &mut Logs, raw: Vec<RawWasmPlugin>) -> Hooks {
+    let mut out = Vec::new();
+    for rplugin in raw.into_iter().filter(|p| p.active) {
+        let id = rplugin.id.clone();
+        match WasmPluginSettings::resolve(rplugin).and_then(runtime::WasmHook::load) {
+            Ok(hook) => out.push(Arc::new(hook) as Arc<dyn AnalyzeHook>),
+            Err(rr) => logs.error(|| format!("could not load WASM plugin {}: {}", id, rr)),
+        }
+    }
+    Hooks::from_hooks(out)
+}
+
+#[cfg(feature = "wasm")]
+mod runtime {
+    use std::convert::TryFrom;
+    use wasmtime::{Config as WConfig, Engine, Instance, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+    use super::{PluginAction, PluginOutput, WasmPluginSettings};
+    use crate::config::raw::RawActionType;
+    use crate::hooks::AnalyzeHook;
+    use crate::interface::{Action, ActionType, BlockReason, Decision, Location, Tags};
+    use crate::logs::Logs;
+    use crate::utils::RequestInfo;
+
+    pub struct WasmHook {
+        settings: WasmPluginSettings,
+        engine: Engine,
+        module: Module,
+    }
+
+    impl WasmHook {
+        pub fn load(settings: WasmPluginSettings) -> anyhow::Result<Self> {
+            let bytes = std::fs::read(&settings.path)?;
+            Self::from_bytes(settings, &bytes)
+        }
+
+        pub fn from_bytes(settings: WasmPluginSettings, bytes: &[u8]) -> anyhow::Result<Self> {
+            let mut cfg = WConfig::new();
+            cfg.consume_fuel(true);
+            let engine = Engine::new(&cfg)?;
+            let module = Module::new(&engine, bytes)?;
+            if module.imports().len() > 0 {
+                anyhow::bail!("plugins can't import functions");
+            }
+            Ok(WasmHook {
+                settings,
+                engine,
+                module,
+            })
+        }
+
+        /// runs a phase function in a fresh instance
+        fn call(&self, export: &str, input: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+            let limits = StoreLimitsBuilder::new()
+                .memory_size(self.settings.max_memory)
+                .instances(1)
+                .build();
+            let mut store: Store<StoreLimits> = Store::new(&self.engine, limits);
+            store.limiter(|l| l);
+            store.set_fuel(self.settings.fuel)?;
+            let instance = Instance::new(&mut store, &self.module, &[])?;
+            let func = match instance.get_func(&mut store, export) {
+                None => return Ok(None),
+                Some(f) => f.typed::<(i32, i32), i64>(&store)?,
+            };
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .ok_or_else(|| anyhow::anyhow!("the module does not export its memory"))?;
+            let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+            let len = i32::try_from(input.len())?;
+            let ptr = alloc.call(&mut store, len)?;
+            memory.write(&mut store, ptr as u32 as usize, input)?;
+            let packed = func.call(&mut store, (ptr, len))? as u64;
+            if packed == 0 {
+                return Ok(None);
+            }
+            let (out_ptr, out_len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+            // the output must be in the guest memory, so that its length can't make the host allocate more
+            if !matches!(out_ptr.checked_add(out_len), Some(end) if end <= memory.data_size(&store)) {
+                anyhow::bail!(
+                    "the plugin output ({} bytes at {}) is out of its memory",
+                    out_len,
+                    out_ptr
+                );
+            }
+            let mut out = vec![0; out_len];
+            memory.read(&store, out_ptr, &mut out)?;
+            Ok(Some(out))
+        }
+
+        fn decision(&self, output: PluginOutput) -> Option<Decision> {
+            let pdec = output.decision?;
+            let raw_action = match pdec.action {
+                PluginAction::Block => RawActionType::Custom,
+                PluginAction::Monitor => RawActionType::Monitor,
+            };
+            let reason = BlockReason::plugin(
+                self.settings.id.clone(),
+                self.settings.name.clone(),
+                raw_action,
+                pdec.reason,
+            );
+            Some(match pdec.action {
+                PluginAction::Monitor => Decision::pass(vec![reason]),
+                PluginAction::Block => Decision::action(
+                    Action {
+                        atype: ActionType::Block,
+                        block_mode: true,
+                        status: pdec.status,
+                        headers: Some(pdec.headers).filter(|h| !h.is_empty()),
+                        content: pdec.content,
+                        extra_tags: None,
+                        response_headers: None,
+                        delay: None,
+                        transform: None,
+                        ban: None,
+                    },
+                    vec![reason],
+                ),
+            })
+        }
+
+        fn run(
+            &self,
+            logs: &mut Logs,
+            phase: &'static str,
+            reqinfo: &RequestInfo,
+            tags: &mut Tags,
+            decision: Option<&Decision>,
+        ) -> Option<Decision> {
+            if !self.settings.phases.contains(phase) {
+                return None;
+            }
+            let mut input = serde_json::json!({
+                "phase": phase,
+                "request": reqinfo.clone().into_json_notags(),
+                "tags": tags.inner().keys().collect::<Vec<_>>(),
+            });
+            if let Some(dec) = decision {
+                input["decision"] = serde_json::json!({
+                    "blocking": dec.is_blocking(),
+                    "action": dec.maction,
+                    "reasons": dec.reasons.len(),
+                });
+            }
+            let res = serde_json::to_vec(&input)
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| self.call(&format!("on_{}", phase), &bytes))
+                .and_then(|mout| match mout {
+                    None => Ok(PluginOutput::default()),
+                    Some(out) => Ok(serde_json::from_slice(&out)?),
+                });
+            match res {
+                Ok(output) => {
+                    for t in &output.tags {
+                        tags.insert(t, Location::Request);
+                    }
+                    self.decision(output)
+                }
+                Err(rr) => {
+                    logs.error(|| format!("WASM plugin {} failed in phase {}: {}", self.settings.id, phase, rr));
+                    tags.insert_qualified("wasm-plugin-error", &self.settings.id, Location::Request);
+                    None
+                }
+            }
+        }
+    }
+
+    impl AnalyzeHook for WasmHook {
+        fn name(&self) -> &str {
+            // ids are unique, names are not
+            self.settings.id.as_str()
+        }
+
+        fn on_init(&self, logs: &mut Logs, reqinfo: &RequestInfo, tags: &mut Tags) -> Option<Decision> {
+            self.run(logs, "init", reqinfo, tags, None)
+        }
+
+        fn on_pre_acl(&self, logs: &mut Logs, reqinfo: &RequestInfo, tags: &mut Tags) -> Option<Decision> {
+            self.run(logs, "pre_acl", reqinfo, tags, None)
+        }
+
+        fn on_pre_contentfilter(&self, logs: &mut Logs, reqinfo: &RequestInfo, tags: &mut Tags) -> Option<Decision> {
+            self.run(logs, "pre_contentfilter", reqinfo, tags, None)
+        }
+
+        fn on_finish(
+            &self,
+            logs: &mut Logs,
+            reqinfo: &RequestInfo,
+            tags: &mut Tags,
+            decision: &Decision,
+        ) -> Option<Decision> {
+            self.run(logs, "finish", reqinfo, tags, Some(decision))
+        }
+    }
+}
+
+#[cfg(not(feature = "wasm"))]
+mod runtime {
+    use super::WasmPluginSettings;
+    use crate::hooks::AnalyzeHook;
+
+    pub struct WasmHook;
+
+    impl WasmHook {
+        pub fn load(_settings: WasmPluginSettings) -> anyhow::Result<Self> {
+            anyhow::bail!("WASM support is not enabled, curiefense must be built with the wasm feature")
+        }
+    }
+
+    impl AnalyzeHook for WasmHook {
+        fn name(&self) -> &str {
+            "wasm"
+        }
+    }
+}
+
+#[cfg(all(test, feature = "wasm"))]
+mod tests {
+    use super::runtime::WasmHook;
+    use super::*;
+    use crate::config::virtualtags::VirtualTags;
+    use crate::interface::Tags;
+    use crate::testutils::request_info;
+
+    // tags requests in the init phase, blocks them in the pre_acl phase, loops forever in the finish phase
+    const PLUGIN: &str = r#"(module
+      (memory (export "memory") 1)
+      (global $next (mut i32) (i32.const 1024))
+      (func (export "alloc") (param $len i32) (result i32)
+        (local $p i32)
+        (local.set $p (global.get $next))
+        (global.set $next (i32.add (global.get $next) (local.get $len)))
+        (local.get $p))
+      (data (i32.const 16) "{\"tags\":[\"wasm-seen\"]}")
+      (data (i32.const 64) "{\"decision\":{\"action\":\"block\",\"status\":418,\"reason\":\"teapot\"}}")
+      (func (export "on_init") (param i32 i32) (result i64)
+        (i64.or (i64.shl (i64.const 16) (i64.const 32)) (i64.const 22)))
+      (func (export "on_pre_acl") (param i32 i32) (result i64)
+        (i64.or (i64.shl (i64.const 64) (i64.const 32)) (i64.const 62)))
+      (func (export "on_finish") (param i32 i32) (result i64)
+        (loop $l (br $l))
+        (i64.const 0)))"#;
+
+    #[test]
+    fn plugin_phases() {
+        let settings = WasmPluginSettings {
+            id: "test".to_string(),
+            name: "test".to_string(),
+            path: String::new(),
+            phases: PHASES.iter().copied().collect(),
+            fuel: 100_000,
+            max_memory: 1 << 20,
+        };
+        let hook = WasmHook::from_bytes(settings, PLUGIN.as_bytes()).unwrap();
+        let mut logs = Logs::default();
+        let mut tags = Tags::new(&VirtualTags::default());
+        let reqinfo = request_info(&[], &[]);
+
+        assert!(hook.on_init(&mut logs, &reqinfo, &mut tags).is_none());
+        assert!(tags.contains("wasm-seen"));
+
+        let decision = hook.on_pre_acl(&mut logs, &reqinfo, &mut tags).unwrap();
+        assert!(decision.is_blocking());
+        assert_eq!(decision.maction.as_ref().map(|a| a.status), Some(418));
+
+        // no function for this phase
+        assert!(hook.on_pre_contentfilter(&mut logs, &reqinfo, &mut tags).is_none());
+
+        // runs out of fuel
+        assert!(hook.on_finish(&mut logs, &reqinfo, &mut tags, &decision).is_none());
+        assert!(tags.contains("wasm-plugin-error:test"));
+
+        // claims a 4GiB output, in a 64KiB memory
+        let oversized = r#"(module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+          (func (export "on_init") (param i32 i32) (result i64)
+            (i64.or (i64.shl (i64.const 16) (i64.const 32)) (i64.const 0xffffffff))))"#;
+        let settings = WasmPluginSettings {
+            id: "oversized".to_string(),
+            name: "oversized".to_string(),
+            path: String::new(),
+            phases: PHASES.iter().copied().collect(),
+            fuel: 100_000,
+            max_memory: 1 << 20,
+        };
+        let hook = WasmHook::from_bytes(settings, oversized.as_bytes()).unwrap();
+        assert!(hook.on_init(&mut logs, &reqinfo, &mut tags).is_none());
+        assert!(tags.contains("wasm-plugin-error:oversized"));
+    }
+}
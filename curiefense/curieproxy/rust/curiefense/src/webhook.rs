@@ -0,0 +1,241 @@
+//! Outbound webhook notifications.
+//!
+//! webhooks.json lists endpoints that are notified of the blocking and challenge decisions, for chat, paging or
+//! SOAR integrations. A webhook can be restricted to the requests having one of its `tags`. Events are JSON
+//! documents holding the decision, tags, reasons and a summary of the (masked) request. They are queued in a
+//! bounded sink, and POSTed by a dedicated thread, as JSON arrays of up to `batch_size` events, at least every
+//! `flush_seconds`.
+//!
+//! When a secret is set, each batch is signed with HMAC-SHA256, in the `x-curiefense-signature` header, as
+//! `t=<timestamp>,v1=<hex signature of "<timestamp>.<body>">`. Failed deliveries are retried `retries` times, with
+//! an exponential backoff, before the batch is dropped.
+use arc_swap::ArcSwap;
+use chrono::{SecondsFormat, Utc};
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::raw::{RawActionType, RawWebhook};
+use crate::interface::{tagify, AnalyzeResult, Decision, Tags};
+use crate::logs::{background_error, Logs};
+use crate::logsink::{BoundedSink, DropPolicy, LogRecord, LogSink};
+use crate::siem::sorted_tags;
+use crate::utils::{hmac_sha256, RequestInfo};
+
+pub const SIGNATURE_HEADER: &str = "x-curiefense-signature";
+
+lazy_static! {
+    static ref WEBHOOKS: ArcSwap<Vec<Webhook>> = ArcSwap::from_pointee(Vec::new());
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookSettings {
+    pub id: String,
+    pub url: String,
+    pub secret: Option<Vec<u8>>,
+    /// all blocking decisions are notified when empty
+    pub tags: Vec<String>,
+    pub batch_size: usize,
+    pub flush: Duration,
+    pub retries: u32,
+    pub timeout: Duration,
+    pub queue_size: usize,
+}
+
+/// a configured webhook, and the queue of its delivery thread
+struct Webhook {
+    settings: WebhookSettings,
+    sink: Arc<BoundedSink>,
+}
+
+impl WebhookSettings {
+    pub fn resolve(logs: &mut Logs, raw: RawWebhook) -> Option<Self> {
+        if !raw.url.starts_with("http://") && !raw.url.starts_with("https://") {
+            logs.error(|| format!("webhook {}: invalid url {}", raw.id, raw.url));
+            return None;
+        }
+        let secret_env = raw.secret_env;
+        let secret = raw
+            .secret
+            .filter(|s| !s.is_empty())
+            .or_else(|| secret_env.and_then(|env| std::env::var(env).ok()))
+            .filter(|s| !s.is_empty())
+            .map(String::into_bytes);
+        Some(WebhookSettings {
+            id: raw.id,
+            url: raw.url,
+            secret,
+            tags: raw.tags.iter().map(|t| tagify(t)).collect(),
+            batch_size: raw.batch_size.unwrap_or(20).max(1),
+            flush: Duration::from_secs(raw.flush_seconds.unwrap_or(5).max(1)),
+            retries: raw.retries.unwrap_or(3),
+            timeout: Duration::from_secs(raw.timeout_seconds.unwrap_or(5).max(1)),
+            queue_size: raw.queue_size.unwrap_or(1024),
+        })
+    }
+
+    /// true if the request tags select this webhook
+    pub fn matches(&self, tags: &Tags) -> bool {
+        self.tags.is_empty() || self.tags.iter().any(|t| tags.contains(t))
+    }
+}
+
+/// the signature header value of a batch
+pub fn sign_batch(secret: &[u8], timestamp: i64, body: &str) -> String {
+    let signature = hmac_sha256(secret, format!("{}.{}", timestamp, body).as_bytes());
+    format!("t={},v1={}", timestamp, hex::encode(signature))
+}
+
+fn deliver(settings: &WebhookSettings, body: &str) {
+    let agent = ureq::AgentBuilder::new().timeout(settings.timeout).build();
+    for attempt in 0..=settings.retries {
+        let mut request = agent.post(&settings.url).set("content-type", "application/json");
+        if let Some(secret) = &settings.secret {
+            request = request.set(SIGNATURE_HEADER, &sign_batch(secret, Utc::now().timestamp(), body));
+        }
+        match request.send_string(body) {
+            Ok(_) => return,
+            Err(rr) if attempt == settings.retries => {
+                background_error(|| format!("could not notify webhook {}, batch dropped: {}", settings.id, rr))
+            }
+            Err(_) => std::thread::sleep(Duration::from_millis(500 << attempt.min(6))),
+        }
+    }
+}
+
+fn start_webhook(settings: WebhookSettings) -> Webhook {
+    let sink = BoundedSink::new(
+        &format!("webhook-{}", settings.id),
+        settings.queue_size,
+        DropPolicy::DropOldest,
+    );
+    let consumer = sink.clone();
+    let delivery = settings.clone();
+    std::thread::spawn(move || loop {
+        // the webhook was removed from the configuration, and its queue is empty
+        if Arc::strong_count(&consumer) == 1 && consumer.stats().queued == 0 {
+            return;
+        }
+        let mut events = Vec::new();
+        let deadline = Instant::now() + delivery.flush;
+        while events.len() < delivery.batch_size {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            match consumer.recv_timeout(left) {
+                Some(r) => events.extend(serde_json::from_slice::<Value>(&r.data).ok()),
+                None => break,
+            }
+        }
+        if !events.is_empty() {
+            deliver(&delivery, &Value::Array(events).to_string());
+        }
+    });
+    Webhook { settings, sink }
+}
+
+/// replaces the configured webhooks, the delivery threads of unchanged webhooks are kept
+pub fn configure_webhooks(settings: Vec<WebhookSettings>) {
+    let current = WEBHOOKS.load();
+    let webhooks = settings
+        .into_iter()
+        .map(|s| match current.iter().find(|w| w.settings == s) {
+            Some(w) => Webhook {
+                settings: s,
+                sink: w.sink.clone(),
+            },
+            None => start_webhook(s),
+        })
+        .collect();
+    WEBHOOKS.store(Arc::new(webhooks));
+}
+
+/// the event sent to the webhooks
+pub fn webhook_event(decision: &Decision, rinfo: &RequestInfo, tags: &Tags) -> Value {
+    let challenge = decision
+        .reasons
+        .iter()
+        .any(|r| matches!(r.action, RawActionType::Challenge | RawActionType::Ichallenge));
+    let secpol = &rinfo.rinfo.secpolicy;
+    json!({
+        "timestamp": rinfo.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+        "action": if challenge { "challenge" } else { "block" },
+        "status": decision.maction.as_ref().map(|a| a.status),
+        "tags": sorted_tags(tags),
+        "reasons": decision.reasons,
+        "securitypolicy": secpol.policy.name,
+        "securitypolicy_entry": secpol.entry.name,
+        "request": {
+            "id": rinfo.rinfo.meta.requestid,
+            "ip": rinfo.rinfo.geoip.ipstr,
+            "country": rinfo.rinfo.geoip.country_iso,
+            "method": rinfo.rinfo.meta.method,
+            "host": rinfo.rinfo.host,
+            "path": rinfo.rinfo.qinfo.qpath,
+            "user_agent": rinfo.headers.get("user-agent"),
+        },
+    })
+}
+
+/// queues the event of a blocking decision, for the webhooks selecting it
+pub fn notify_webhooks(result: &AnalyzeResult) {
+    let webhooks = WEBHOOKS.load();
+    if webhooks.is_empty() || !result.decision.is_blocking() {
+        return;
+    }
+    let mut event: Option<Vec<u8>> = None;
+    for webhook in webhooks.iter().filter(|w| w.settings.matches(&result.tags)) {
+        let data = event
+            .get_or_insert_with(|| {
+                serde_json::to_vec(&webhook_event(&result.decision, &result.rinfo, &result.tags)).unwrap_or_default()
+            })
+            .clone();
+        webhook.sink.submit(LogRecord {
+            data,
+            timestamp: Utc::now(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::virtualtags::VirtualTags;
+    use crate::interface::Location;
+
+    #[test]
+    fn settings_and_signature() {
+        let settings = WebhookSettings::resolve(
+            &mut Logs::default(),
+            RawWebhook {
+                id: "soar".to_string(),
+                url: "https://soar.example.com/events".to_string(),
+                secret: Some("k".to_string()),
+                tags: vec!["Login Anomaly".to_string()],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(settings.tags, vec!["login-anomaly".to_string()]);
+        assert_eq!(settings.batch_size, 20);
+        let mut tags = Tags::new(&VirtualTags::default());
+        assert!(!settings.matches(&tags));
+        tags.insert("login-anomaly", Location::Request);
+        assert!(settings.matches(&tags));
+
+        let signature = sign_batch(b"k", 1700000000, "[]");
+        assert_eq!(
+            signature,
+            format!("t=1700000000,v1={}", hex::encode(hmac_sha256(b"k", b"1700000000.[]")))
+        );
+
+        let invalid = RawWebhook {
+            id: "bad".to_string(),
+            url: "ftp://example.com".to_string(),
+            ..Default::default()
+        };
+        assert!(WebhookSettings::resolve(&mut Logs::default(), invalid).is_none());
+    }
+}
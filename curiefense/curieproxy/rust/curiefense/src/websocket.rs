@@ -0,0 +1,328 @@
+//! WebSocket inspection.
+//!
+//! Once an upgrade request passed the analysis, the proxy opens a `WsConnection` from its result, and feeds the
+//! messages sent by the client to `analyze_ws_message`. Messages are parsed like JSON bodies, or kept as raw text,
+//! and analyzed with the content filter profile of the security policy. The number of messages per connection is
+//! also limited.
+use crate::analyze::CfRulesArg;
+use crate::body::{parse_body, ProtoContext};
+use crate::config::contentfilter::ContentFilterProfile;
+use crate::config::raw::{ContentType, RawWebSocketSettings};
+use crate::config::CONFIGS;
+use crate::contentfilter::{content_filter_check, masking};
+use crate::grasshopper::{DummyGrasshopper, PrecisionLevel};
+use crate::interface::stats::StatsCollect;
+use crate::interface::{AnalyzeResult, BlockReason, Decision, Location, SimpleAction, Tags};
+use crate::logs::Logs;
+use crate::requestfields::RequestField;
+use crate::utils::{now_ms, RequestInfo};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSocketSettings {
+    pub inspect: bool,
+    pub max_messages: Option<u64>,
+    /// in milliseconds
+    pub period: u64,
+    pub max_message_size: Option<usize>,
+}
+
+impl WebSocketSettings {
+    pub fn resolve(raw: RawWebSocketSettings) -> Self {
+        WebSocketSettings {
+            inspect: raw.inspect,
+            max_messages: raw.max_messages.filter(|m| *m > 0),
+            period: raw.period.filter(|p| *p > 0).unwrap_or(1) * 1000,
+            max_message_size: raw.max_message_size.filter(|s| *s > 0),
+        }
+    }
+}
+
+/// true for requests asking for a WebSocket upgrade
+pub fn is_upgrade(reqinfo: &RequestInfo) -> bool {
+    let upgrade = reqinfo
+        .headers
+        .get_str("upgrade")
+        .map(|u| u.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false);
+    let connection = reqinfo
+        .headers
+        .get_str("connection")
+        .map(|c| c.split(',').any(|t| t.trim().eq_ignore_ascii_case("upgrade")))
+        .unwrap_or(false);
+    upgrade && connection
+}
+
+/// an upgraded connection, messages are analyzed in the context of the handshake request
+pub struct WsConnection {
+    settings: WebSocketSettings,
+    handshake: RequestInfo,
+    tags: Tags,
+    revision: String,
+    config_hash: String,
+    window_start: u64,
+    window_count: u64,
+    /// number of messages received on the connection
+    pub messages: u64,
+}
+
+impl WsConnection {
+    /// opens a connection when the handshake was a WebSocket upgrade that was not blocked, and the security policy
+    /// inspects WebSocket connections
+    pub fn open(handshake: &AnalyzeResult) -> Option<Self> {
+        if handshake.decision.is_blocking() || !is_upgrade(&handshake.rinfo) {
+            return None;
+        }
+        let settings = handshake.rinfo.rinfo.secpolicy.websocket.clone()?;
+        Some(WsConnection {
+            settings,
+            handshake: handshake.rinfo.clone(),
+            tags: handshake.tags.clone(),
+            revision: handshake.stats.revision.clone(),
+            config_hash: handshake.stats.config_hash.clone(),
+            window_start: 0,
+            window_count: 0,
+            messages: 0,
+        })
+    }
+
+    /// counts a message, returning the number of messages in the current period
+    fn record_message(&mut self, now: u64) -> u64 {
+        self.messages += 1;
+        if now.saturating_sub(self.window_start) >= self.settings.period {
+            self.window_start = now;
+            self.window_count = 0;
+        }
+        self.window_count += 1;
+        self.window_count
+    }
+
+    /// the request info of a message, holding its content as arguments
+    fn message_info(&self, logs: &mut Logs, profile: &ContentFilterProfile, message: &[u8]) -> RequestInfo {
+        let mut reqinfo = self.handshake.clone();
+        // the handshake headers, cookies and path were already analyzed
+        reqinfo.headers = RequestField::with_decoding(&profile.decoding, profile.decoding_depth);
+        reqinfo.cookies = RequestField::with_decoding(&profile.decoding, profile.decoding_depth);
+        reqinfo.rinfo.qinfo.path_as_map = RequestField::with_decoding(&profile.decoding, profile.decoding_depth);
+        let mut args = RequestField::with_decoding(&profile.decoding, profile.decoding_depth);
+        args.case_insensitive = profile.case_insensitive_names;
+        if let Err(rr) = parse_body(
+            logs,
+            &mut args,
+            profile.max_body_depth,
+            profile.max_graphql_complexity,
+            ProtoContext::default(),
+            &profile.uploads,
+            Some("application/json"),
+            &[ContentType::Json],
+            message,
+        ) {
+            logs.debug(|| format!("websocket message is not JSON: {}", rr));
+            args.add(
+                "RAW_MESSAGE".to_string(),
+                Location::Body,
+                String::from_utf8_lossy(message).to_string(),
+            );
+        }
+        reqinfo.rinfo.qinfo.args = args;
+        reqinfo.rinfo.qinfo.body_size = message.len();
+        reqinfo
+    }
+}
+
+fn restriction(
+    conn: &WsConnection,
+    tpe: &'static str,
+    location: Location,
+    actual: String,
+    expected: String,
+) -> BlockReason {
+    let secpol = &conn.handshake.rinfo.secpolicy;
+    BlockReason::websocket(
+        secpol.entry.id.clone(),
+        secpol.entry.name.clone(),
+        SimpleAction::default().atype.to_raw(),
+        tpe,
+        location,
+        actual,
+        expected,
+    )
+}
+
+/// analyzes a message sent by the client on an upgraded connection
+pub fn analyze_ws_message(
+    logs: &mut Logs,
+    conn: &mut WsConnection,
+    message: &[u8],
+    cfrules: CfRulesArg<'_>,
+) -> AnalyzeResult {
+    let secpol = conn.handshake.rinfo.secpolicy.clone();
+    let profile = &secpol.content_filter_profile;
+    let mut tags = conn.tags.clone();
+    tags.insert("websocket-message", Location::Request);
+    let stats = StatsCollect::new(logs.start, conn.revision.clone())
+        .config_hash(&conn.config_hash)
+        .content_filter_only();
+    let count = conn.record_message(now_ms());
+
+    let mut problem = None;
+    if let Some(max) = conn.settings.max_message_size {
+        if message.len() > max {
+            problem = Some(restriction(
+                conn,
+                "websocket message size",
+                Location::Body,
+                message.len().to_string(),
+                max.to_string(),
+            ));
+        }
+    }
+    if let Some(max) = conn.settings.max_messages {
+        if count > max {
+            tags.insert("websocket-rate-limited", Location::Request);
+            problem = Some(restriction(
+                conn,
+                "websocket messages",
+                Location::Request,
+                count.to_string(),
+                max.to_string(),
+            ));
+        }
+    }
+    if let Some(reason) = problem {
+        let rinfo = conn.message_info(logs, profile, &[]);
+        let decision = SimpleAction::default().to_decision::<DummyGrasshopper>(
+            logs,
+            PrecisionLevel::Invalid,
+            None,
+            &rinfo,
+            &mut tags,
+            vec![reason],
+        );
+        return AnalyzeResult {
+            decision,
+            tags,
+            rinfo: masking(rinfo),
+            stats: stats.no_content_filter().cf_stage_build(),
+        };
+    }
+
+    let rinfo = conn.message_info(logs, profile, message);
+    if !conn.settings.inspect {
+        return AnalyzeResult {
+            decision: Decision::pass(Vec::new()),
+            tags,
+            rinfo: masking(rinfo),
+            stats: stats.no_content_filter().cf_stage_build(),
+        };
+    }
+
+    let (result, stats) = match cfrules {
+        CfRulesArg::Global => {
+            let cfg = CONFIGS.config.load();
+            content_filter_check(logs, stats, &mut tags, &rinfo, profile, cfg.hsdb.get(&profile.id))
+        }
+        CfRulesArg::Get(r) => content_filter_check(logs, stats, &mut tags, &rinfo, profile, r),
+    };
+    let decision = match result {
+        Ok(()) => Decision::pass(Vec::new()),
+        Err(cfblock) => {
+            let mut reasons = cfblock.reasons;
+            if !secpol.content_filter_active || profile.mode.is_passive() {
+                for reason in reasons.iter_mut() {
+                    reason.action.inactive();
+                }
+            }
+            if cfblock.blocking && secpol.content_filter_active && !profile.mode.is_passive() {
+                profile.action.to_decision::<DummyGrasshopper>(
+                    logs,
+                    PrecisionLevel::Invalid,
+                    None,
+                    &rinfo,
+                    &mut tags,
+                    reasons,
+                )
+            } else {
+                Decision::pass(reasons)
+            }
+        }
+    };
+    AnalyzeResult {
+        decision,
+        tags,
+        rinfo: masking(rinfo),
+        stats: stats.cf_stage_build(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::contentfilter::ContentFilterRules;
+    use crate::config::hostmap::SecurityPolicy;
+    use crate::config::virtualtags::VirtualTags;
+    use crate::testutils::{map_raw, raw_request};
+
+    fn handshake(headers: &[(&str, &str)], settings: Option<WebSocketSettings>) -> AnalyzeResult {
+        let logs = Logs::default();
+        let mut secpol = SecurityPolicy::empty();
+        secpol.websocket = settings;
+        let raw = raw_request(&[("authority", "example.com"), ("path", "/ws?token=abc")], headers);
+        let rinfo = map_raw(secpol, &raw);
+        AnalyzeResult {
+            decision: Decision::pass(Vec::new()),
+            tags: Tags::new(&VirtualTags::default()),
+            rinfo,
+            stats: StatsCollect::new(logs.start, "rev".to_string())
+                .content_filter_only()
+                .no_content_filter()
+                .cf_stage_build(),
+        }
+    }
+
+    fn settings() -> WebSocketSettings {
+        WebSocketSettings {
+            inspect: true,
+            max_messages: Some(2),
+            period: 1000,
+            max_message_size: Some(16),
+        }
+    }
+
+    const UPGRADE: [(&str, &str); 2] = [("upgrade", "websocket"), ("connection", "keep-alive, Upgrade")];
+
+    #[test]
+    fn open_connection() {
+        assert!(WsConnection::open(&handshake(&UPGRADE, Some(settings()))).is_some());
+        assert!(WsConnection::open(&handshake(&UPGRADE, None)).is_none());
+        assert!(WsConnection::open(&handshake(&[("upgrade", "websocket")], Some(settings()))).is_none());
+    }
+
+    #[test]
+    fn message_rate() {
+        let mut conn = WsConnection::open(&handshake(&UPGRADE, Some(settings()))).unwrap();
+        assert_eq!(conn.record_message(10_000), 1);
+        assert_eq!(conn.record_message(10_500), 2);
+        assert_eq!(conn.record_message(10_999), 3);
+        assert_eq!(conn.record_message(11_000), 1);
+        assert_eq!(conn.messages, 4);
+    }
+
+    #[test]
+    fn message_limits() {
+        let mut logs = Logs::default();
+        let rules = ContentFilterRules::empty();
+        let mut conn = WsConnection::open(&handshake(&UPGRADE, Some(settings()))).unwrap();
+        let res = analyze_ws_message(&mut logs, &mut conn, b"{\"a\": \"b\"}", CfRulesArg::Get(Some(&rules)));
+        assert!(!res.decision.is_blocking());
+        assert_eq!(res.rinfo.rinfo.qinfo.args.get_str("a"), Some("b"));
+        // the handshake arguments are not analyzed again
+        assert_eq!(res.rinfo.rinfo.qinfo.args.get_str("token"), None);
+        let res = analyze_ws_message(
+            &mut logs,
+            &mut conn,
+            b"a message that is too large",
+            CfRulesArg::Get(Some(&rules)),
+        );
+        assert!(res.decision.is_blocking());
+    }
+}
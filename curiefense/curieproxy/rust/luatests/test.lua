@@ -47,7 +47,7 @@ local function load_json_file(path)
 end
 
 local function should_skip_tag(tag)
-  local prefixes = {"container:", "geo-", "network:"}
+  local prefixes = {"container:", "geo-", "network:", "config-version:", "http-fp:"}
   for _, prefix in ipairs(prefixes) do
     if startswith(tag, prefix) then
       return true
@@ -405,7 +405,8 @@ local function test_raw_request(request_path, mode)
       "rl_triggers",
       "gf_triggers",
       "cf_triggers",
-      "cf_restrict_triggers"
+      "cf_restrict_triggers",
+      "schema_triggers"
     }
     for _, trigger_name in pairs(triggers) do
       good = test_trigger(expected, request_map, trigger_name) and good